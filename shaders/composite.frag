@@ -0,0 +1,37 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+
+layout(location = 0) out vec4 colour;
+
+layout(set = 0, binding = 0) uniform texture2D u_texture;
+layout(set = 0, binding = 1) uniform sampler u_sampler;
+layout(set = 1, binding = 0) uniform CompositeUniforms {
+    float exposure;
+    float vignette_strength;
+    int tone_mapping;
+};
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve.
+vec3 aces_filmic(vec3 x) {
+    float a = 2.51;
+    float b = 0.03;
+    float c = 2.43;
+    float d = 0.59;
+    float e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), 0.0, 1.0);
+}
+
+void main() {
+    vec3 sampled = texture(sampler2D(u_texture, u_sampler), uv).rgb;
+
+    if (tone_mapping == 1) {
+        sampled = aces_filmic(sampled * exposure);
+    }
+
+    // Darken towards the corners, where the distance from the centre is ~0.7.
+    vec2 from_centre = uv - 0.5;
+    float vignette = 1.0 - dot(from_centre, from_centre) * 2.0 * vignette_strength;
+
+    colour = vec4(sampled * vignette, 1.0);
+}
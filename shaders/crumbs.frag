@@ -0,0 +1,27 @@
+#version 450
+
+layout(location = 0) in vec2 uv;
+layout(location = 1) in float shade;
+
+layout(location = 0) out vec4 colour;
+
+layout(set = 0, binding = 2) uniform Sun {
+    vec3 sun_direction;
+};
+
+layout(set = 0, binding = 3) uniform sampler u_sampler;
+
+layout(set = 1, binding = 1) uniform texture2D u_texture;
+
+void main() {
+    vec4 sampled = texture(sampler2D(u_texture, u_sampler), uv);
+
+    // Cut out instead of blended, so that they don't need sorting.
+    if (sampled.a < 0.5) {
+        discard;
+    }
+
+    // Lit as if they were pointing straight up, the same as the flat ground around them.
+    float brightness = max(0.0, normalize(sun_direction).y);
+    colour = vec4(sampled.rgb * shade * (brightness + 0.5), 1.0);
+}
@@ -0,0 +1,55 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in float scale;
+layout(location = 2) in float sway;
+layout(location = 3) in float phase;
+layout(location = 4) in float atlas_cell;
+layout(location = 5) in float shade;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out float out_shade;
+
+layout(set = 0, binding = 0) uniform Perspective {
+    mat4 perspective;
+};
+
+layout(set = 0, binding = 1) uniform View {
+    mat4 view;
+};
+
+layout(set = 1, binding = 0) uniform Time {
+    float time;
+};
+
+// Two triangles, from the bottom left corner going counter-clockwise.
+const vec2 CORNERS[6] = vec2[](
+    vec2(-0.5, 0.0), vec2(0.5, 0.0), vec2(0.5, 1.0),
+    vec2(-0.5, 0.0), vec2(0.5, 1.0), vec2(-0.5, 1.0)
+);
+
+const float ATLAS_CELLS = 2.0;
+
+const vec2 WIND_DIRECTION = vec2(0.8, 0.6);
+const float WIND_SPEED = 1.5;
+const float WIND_STRENGTH = 0.25;
+// How far apart the gusts are across the map.
+const float GUST_SPACING = 0.2;
+
+void main() {
+    vec2 corner = CORNERS[gl_VertexIndex];
+
+    // Turn to face the camera, but stay upright.
+    vec3 right = normalize(vec3(view[0][0], 0.0, view[2][0]));
+
+    float gust = sin(time * WIND_SPEED + dot(position.xz, WIND_DIRECTION) * GUST_SPACING + phase);
+    // Only the tips bend, and they bend the most.
+    vec2 bend = WIND_DIRECTION * (gust * 0.5 + 0.5) * WIND_STRENGTH * sway * corner.y * corner.y;
+
+    vec3 offset = right * corner.x + vec3(bend.x, corner.y, bend.y);
+
+    out_uv = vec2((corner.x + 0.5 + atlas_cell) / ATLAS_CELLS, 1.0 - corner.y);
+    out_shade = shade;
+
+    gl_Position = perspective * view * vec4(position + offset * scale, 1.0);
+}
@@ -0,0 +1,115 @@
+// The lighting shared by material.frag and material_team_coloured.frag: normal mapping, then
+// Blinn-Phong shading from the sun and the point lights, with the sun's shadows. Compile the shaders
+// that include this with glslc, which understands `#include` out of the box.
+
+const float SHININESS = 32.0;
+const float SPECULAR_STRENGTH = 0.25;
+
+struct PointLight {
+    // The w is how far the light reaches.
+    vec4 position;
+    vec4 colour;
+};
+
+layout(set = 0, binding = 2) uniform Sun {
+    vec3 sun_direction;
+};
+
+layout(set = 0, binding = 3) uniform sampler u_sampler;
+layout(set = 0, binding = 4) uniform sampler u_depth_sampler;
+layout(set = 0, binding = 5) uniform texture2D shadow_map;
+
+layout(set = 0, binding = 6) uniform Lighting {
+    vec4 sun_colour;
+    vec4 ambient;
+    vec4 camera_position;
+    uint num_point_lights;
+    float night_glow;
+};
+
+// Only the lights near the camera are in here, see `cull_point_lights` in renderer.rs.
+layout(set = 0, binding = 7) readonly buffer PointLights {
+    PointLight point_lights[];
+};
+
+layout(set = 1, binding = 0) uniform texture2D u_albedo;
+layout(set = 1, binding = 1) uniform texture2D u_normal_map;
+layout(set = 1, binding = 2) uniform texture2D u_emissive;
+
+float shadow_calculation(vec4 light_space, vec3 normal) {
+    vec3 coords = light_space.xyz / light_space.w;
+    vec2 uv = vec2(
+        (coords.x + 1.0) / 2.0,
+        (1.0 - coords.y) / 2.0
+    );
+
+    if (uv.x > 1.0 || uv.y > 1.0 || uv.x < 0.0 || uv.y < 0.0) {
+        return 0.0;
+    }
+
+    float current_depth = coords.z;
+    float max_bias = 0.01;
+    float min_bias = 0.001;
+    float bias = max(max_bias * (1.0 - dot(normal, normalize(sun_direction))), min_bias);
+
+    float shadow = 0.0;
+    vec2 texel_size = 1.0 / vec2(textureSize(sampler2D(shadow_map, u_depth_sampler), 0));
+    for (int x =  -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            float depth = texture(sampler2D(shadow_map, u_depth_sampler), uv + vec2(x, y) * texel_size).r;
+            shadow += current_depth - bias > depth ? 1.0 : 0.0;
+        }
+    }
+
+    return shadow / 9.0;
+}
+
+// The tangent's w is which way the bitangent points, for mirrored uvs.
+vec3 mapped_normal(vec2 uv, vec3 normal, vec4 tangent) {
+    vec3 n = normalize(normal);
+    vec3 t = normalize(tangent.xyz - n * dot(n, tangent.xyz));
+    vec3 b = cross(n, t) * tangent.w;
+
+    vec3 mapped = texture(sampler2D(u_normal_map, u_sampler), uv).xyz * 2.0 - 1.0;
+    return normalize(mat3(t, b, n) * mapped);
+}
+
+vec3 blinn_phong(vec3 albedo, vec3 normal, vec3 light_direction, vec3 light_colour, vec3 to_camera) {
+    float diffuse = max(dot(normal, light_direction), 0.0);
+    vec3 halfway = normalize(light_direction + to_camera);
+    float specular = diffuse > 0.0
+        ? pow(max(dot(normal, halfway), 0.0), SHININESS) * SPECULAR_STRENGTH
+        : 0.0;
+
+    return light_colour * (albedo * diffuse + specular);
+}
+
+vec3 light(vec3 albedo, vec2 uv, vec3 normal, vec4 tangent, vec3 world_position, vec4 light_space) {
+    normal = mapped_normal(uv, normal, tangent);
+    vec3 to_camera = normalize(camera_position.xyz - world_position);
+
+    float shadow = shadow_calculation(light_space, normal);
+    vec3 sun = blinn_phong(albedo, normal, normalize(sun_direction), sun_colour.rgb, to_camera);
+    vec3 colour = (albedo * ambient.rgb + sun) * (1.0 - shadow * 0.75);
+
+    for (uint i = 0; i < num_point_lights; i++) {
+        vec3 offset = point_lights[i].position.xyz - world_position;
+        float radius = point_lights[i].position.w;
+
+        // Most lights won't reach most of the screen.
+        if (dot(offset, offset) >= radius * radius) {
+            continue;
+        }
+
+        float distance = length(offset);
+        float falloff = 1.0 - distance / radius;
+
+        colour += blinn_phong(albedo, normal, offset / distance, point_lights[i].colour.rgb, to_camera)
+            * falloff * falloff;
+    }
+
+    // The emissive map's alpha is how much it glows during the day, so that windows can be lit up
+    // only at night.
+    vec4 emissive = texture(sampler2D(u_emissive, u_sampler), uv);
+    return colour + emissive.rgb * max(emissive.a, night_glow);
+}
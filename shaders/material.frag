@@ -0,0 +1,21 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// textured.frag, but lit properly with the normal and emissive maps of the material, the colour of the
+// sun and the point lights. See lighting.glsl.
+
+layout(location = 0) in vec2 uv;
+layout(location = 2) in vec3 normal;
+layout(location = 3) in vec4 light_space;
+layout(location = 4) in vec3 world_position;
+layout(location = 5) in vec4 tangent;
+
+layout(location = 0) out vec4 colour;
+
+#include "lighting.glsl"
+
+void main() {
+    vec4 sampled = texture(sampler2D(u_albedo, u_sampler), uv);
+
+    colour = vec4(light(sampled.rgb, uv, normal, tangent, world_position, light_space), sampled.a);
+}
@@ -0,0 +1,49 @@
+#version 450
+
+// The same as static_model.vert, but it also passes on the world position and tangent for
+// material.frag to light the model with.
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+layout(location = 8) in vec4 tangent;
+
+layout(location = 3) in vec4 flat_colour;
+// The instance transform, one column per attribute.
+layout(location = 4) in vec4 transform_0;
+layout(location = 5) in vec4 transform_1;
+layout(location = 6) in vec4 transform_2;
+layout(location = 7) in vec4 transform_3;
+
+layout(location = 0) out vec2 out_uv;
+layout(location = 1) out vec4 out_flat_colour;
+layout(location = 2) out vec3 out_normal;
+layout(location = 3) out vec4 out_light_space;
+layout(location = 4) out vec3 out_world_position;
+layout(location = 5) out vec4 out_tangent;
+
+layout(set = 0, binding = 0) uniform Perspective {
+    mat4 perspective;
+};
+
+layout(set = 0, binding = 1) uniform View {
+    mat4 view;
+};
+
+layout(set = 2, binding = 0) uniform ShadowUniforms {
+    mat4 light_projection_view;
+};
+
+void main() {
+    mat4 transform = mat4(transform_0, transform_1, transform_2, transform_3);
+    vec4 world_position = transform * vec4(position, 1.0);
+
+    out_uv = uv;
+    out_flat_colour = flat_colour;
+    out_normal = mat3(transpose(inverse(transform))) * normal;
+    out_light_space = light_projection_view * world_position;
+    out_world_position = world_position.xyz;
+    out_tangent = vec4(mat3(transform) * tangent.xyz, tangent.w);
+
+    gl_Position = perspective * view * world_position;
+}
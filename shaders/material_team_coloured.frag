@@ -0,0 +1,26 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+// team_coloured.frag with the lighting from material.frag.
+
+layout(location = 0) in vec2 uv;
+layout(location = 1) in vec4 flat_colour;
+layout(location = 2) in vec3 normal;
+layout(location = 3) in vec4 light_space;
+layout(location = 4) in vec3 world_position;
+layout(location = 5) in vec4 tangent;
+
+layout(location = 0) out vec4 colour;
+
+#include "lighting.glsl"
+
+void main() {
+    vec4 sampled = texture(sampler2D(u_albedo, u_sampler), uv);
+
+    // The same team colouring as team_coloured.frag.
+    float mask = 1.0 - sampled.a;
+    float luminance = dot(sampled.rgb, vec3(0.299, 0.587, 0.114));
+    vec3 albedo = mix(sampled.rgb, flat_colour.rgb * (luminance + 0.4), mask);
+
+    colour = vec4(light(albedo, uv, normal, tangent, world_position, light_space), 1.0);
+}
@@ -0,0 +1,56 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec4 clip_position;
+layout(location = 2) in vec3 to_camera;
+
+layout(location = 0) out vec4 colour;
+
+layout(set = 0, binding = 2) uniform Sun {
+    vec3 sun_direction;
+};
+
+layout(set = 1, binding = 0) uniform Time {
+    float time;
+};
+layout(set = 1, binding = 1) uniform sampler water_sampler;
+layout(set = 1, binding = 2) uniform texture2D normal_map;
+layout(set = 1, binding = 3) uniform texture2D reflection;
+
+const vec3 WATER_COLOUR = vec3(0.05, 0.2, 0.3);
+// How far across the map one copy of the normal map stretches.
+const float NORMAL_MAP_SCALE = 12.0;
+// How much the ripples wobble the reflection.
+const float DISTORTION = 0.02;
+
+vec3 sample_normal(vec2 uv) {
+    vec3 normal = texture(sampler2D(normal_map, water_sampler), uv).rgb * 2.0 - 1.0;
+    // The normal map has z pointing up.
+    return normal.xzy;
+}
+
+void main() {
+    vec2 uv = position.xz / NORMAL_MAP_SCALE;
+    vec3 normal = normalize(
+        sample_normal(uv + vec2(0.03, 0.01) * time) +
+        sample_normal(uv * 1.7 - vec2(0.02, -0.035) * time)
+    );
+
+    // The reflection is rendered mirrored horizontally (see `reflection_view` in
+    // src/renderer/water_pipeline.rs), so flip it back.
+    vec2 screen_position = clip_position.xy / clip_position.w;
+    vec2 reflection_uv = vec2(1.0 - screen_position.x, 1.0 - screen_position.y) / 2.0;
+    reflection_uv = clamp(reflection_uv + normal.xz * DISTORTION, 0.0, 1.0);
+    vec4 reflected = texture(sampler2D(reflection, water_sampler), reflection_uv);
+
+    vec3 camera_direction = normalize(to_camera);
+    vec3 sun = normalize(sun_direction);
+
+    // The camera mostly looks straight down, so don't let the reflections fade out completely.
+    float fresnel = mix(0.4, 1.0, pow(1.0 - max(dot(camera_direction, normal), 0.0), 3.0));
+    float brightness = max(0.0, dot(normal, sun));
+    float specular = pow(max(0.0, dot(normal, normalize(sun + camera_direction))), 64.0);
+
+    vec3 water = WATER_COLOUR * (brightness + 0.5);
+    colour = vec4(mix(water, reflected.rgb, reflected.a * fresnel) + specular, 0.85);
+}
@@ -0,0 +1,25 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+
+layout(location = 0) out vec3 out_position;
+layout(location = 1) out vec4 out_clip_position;
+layout(location = 2) out vec3 out_to_camera;
+
+layout(set = 0, binding = 0) uniform Perspective {
+    mat4 perspective;
+};
+
+layout(set = 0, binding = 1) uniform View {
+    mat4 view;
+};
+
+void main() {
+    vec3 camera_position = inverse(view)[3].xyz;
+
+    out_position = position;
+    out_clip_position = perspective * view * vec4(position, 1.0);
+    out_to_camera = camera_position - position;
+
+    gl_Position = out_clip_position;
+}
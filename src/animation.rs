@@ -1,6 +1,17 @@
+// Posing skins on the cpu goes unused when the joints are computed on the gpu instead.
+#![cfg_attr(feature = "gpu-skinning", allow(dead_code))]
+
 mod animation;
+#[cfg(feature = "gpu-skinning")]
+mod gpu;
 mod node;
 mod skin;
+mod state;
 
 pub use animation::{load_animations, Animation};
+#[cfg(feature = "gpu-skinning")]
+pub use gpu::{PackedAnimations, SkinningInstance, SkinningUniforms};
 pub use skin::Skin;
+#[cfg(not(feature = "gpu-skinning"))]
+pub use state::pose_all;
+pub use state::{AnimationState, CROSSFADE_FRAMES};
@@ -52,7 +52,7 @@ pub fn slerp(left: Quaternion<f32>, right: Quaternion<f32>, amount: f32) -> Quat
     )
 }
 
-trait Interpolate: Copy {
+pub(super) trait Interpolate: Copy {
     fn linear(self, other: Self, amount: f32) -> Self;
 
     fn cubic_spline(
@@ -117,11 +117,11 @@ impl Interpolate for Quaternion<f32> {
 }
 
 #[derive(Debug)]
-struct Channel<T> {
-    interpolation: Interpolation,
-    times: Vec<f32>,
-    values: Vec<T>,
-    node_index: usize,
+pub(super) struct Channel<T> {
+    pub(super) interpolation: Interpolation,
+    pub(super) times: Vec<f32>,
+    pub(super) values: Vec<T>,
+    pub(super) node_index: usize,
 }
 
 impl<T> Channel<T> {
@@ -188,25 +188,27 @@ impl<T: Interpolate> Channel<T> {
 
 #[derive(Debug)]
 pub struct Animation {
+    pub name: String,
     pub total_time: f32,
-    translation_channels: Vec<Channel<Vec3>>,
-    rotation_channels: Vec<Channel<Quaternion<f32>>>,
+    pub(super) translation_channels: Vec<Channel<Vec3>>,
+    pub(super) rotation_channels: Vec<Channel<Quaternion<f32>>>,
 }
 
 impl Animation {
-    /// Update nodes' transforms from animation data.
+    /// Update nodes' transforms from animation data, blending with their current transforms by
+    /// `weight`.
     ///
-    /// Returns true if any nodes was updated.
-    pub fn animate(&self, skin: &mut Skin, time: f32) {
+    /// `Skin::update` needs to be called afterwards to compute the joint matrices.
+    pub fn apply(&self, skin: &mut Skin, time: f32, weight: f32) {
         let (translations, rotations) = self.sample(time);
         translations.for_each(|(node_index, translation)| {
-            skin.nodes.nodes_mut()[node_index].local_translation = translation;
+            let node = &mut skin.nodes.nodes_mut()[node_index];
+            node.local_translation = node.local_translation.linear(translation, weight);
         });
         rotations.for_each(|(node_index, rotation)| {
-            skin.nodes.nodes_mut()[node_index].local_rotation = rotation;
+            let node = &mut skin.nodes.nodes_mut()[node_index];
+            node.local_rotation = node.local_rotation.linear(rotation, weight);
         });
-
-        skin.update();
     }
 
     fn sample(
@@ -254,6 +256,7 @@ fn map_animation(gltf_animation: &GltfAnimation, data: &[Vec<u8>]) -> Animation
         .unwrap_or(&0.0);
 
     Animation {
+        name: gltf_animation.name().unwrap_or_default().to_string(),
         total_time,
         translation_channels,
         rotation_channels,
@@ -0,0 +1,234 @@
+use super::animation::Channel;
+use super::{Animation, AnimationState, Skin};
+use gltf::animation::Interpolation;
+use ultraviolet::Vec4;
+
+// Has to match the constants in shaders/skinning.comp.
+const MAX_NODES: usize = 32;
+const PROPERTY_TRANSLATION: f32 = 0.0;
+const PROPERTY_ROTATION: f32 = 1.0;
+
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy, Default)]
+pub struct SkinningUniforms {
+    pub num_instances: u32,
+    pub num_nodes: u32,
+    pub num_joints: u32,
+    pub num_clips: u32,
+    pub joints_offset: u32,
+    pub clips_offset: u32,
+}
+
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy)]
+pub struct SkinningInstance {
+    clip: u32,
+    time: f32,
+    fade_clip: u32,
+    fade_time: f32,
+    fade_weight: f32,
+}
+
+// A skeleton and all of its clips flattened into a list of vec4s for the skinning compute shader:
+//
+// * Nodes (3 each, parents before children): translation + parent index (-1 for roots), rotation,
+//   scale.
+// * Joints (5 each): node index, then the columns of the inverse bind matrix.
+// * Clips (1 each): first channel, number of channels.
+// * Channels (2 each): node, property, interpolation, number of keyframes; first time, first value.
+// * Keyframe times (1 each) and values (1 each, or 3 for cubic splines).
+pub struct PackedAnimations {
+    pub data: Vec<Vec4>,
+    pub uniforms: SkinningUniforms,
+}
+
+impl PackedAnimations {
+    pub fn new(skin: &Skin, animations: &[Animation]) -> Self {
+        let traversal = &skin.nodes.depth_first_taversal_indices;
+        assert!(
+            traversal.len() <= MAX_NODES,
+            "Skins can have at most {} nodes for gpu skinning, this one has {}",
+            MAX_NODES,
+            traversal.len()
+        );
+
+        // Where each node ends up in the packed list.
+        let mut packed_indices = vec![None; skin.nodes.nodes().len()];
+        for (packed_index, (node_index, _)) in traversal.iter().enumerate() {
+            packed_indices[*node_index] = Some(packed_index);
+        }
+
+        let mut data = Vec::new();
+
+        for (node_index, parent_index) in traversal {
+            let node = &skin.nodes.nodes()[*node_index];
+            let parent = parent_index
+                .and_then(|parent_index| packed_indices[parent_index])
+                .map(|parent| parent as f32)
+                .unwrap_or(-1.0);
+
+            let translation = node.local_translation;
+            let rotation = node.local_rotation;
+            let scale = node.local_scale;
+
+            data.push(Vec4::new(
+                translation.x,
+                translation.y,
+                translation.z,
+                parent,
+            ));
+            data.push(Vec4::new(
+                rotation.v.x,
+                rotation.v.y,
+                rotation.v.z,
+                rotation.s,
+            ));
+            data.push(Vec4::new(scale.x, scale.y, scale.z, 0.0));
+        }
+
+        let joints_offset = data.len();
+
+        for joint in &skin.joints {
+            let node = packed_indices[joint.node_id].unwrap_or(0);
+            data.push(Vec4::new(node as f32, 0.0, 0.0, 0.0));
+            data.extend_from_slice(&joint.inverse_bind_matrix.cols);
+        }
+
+        // We don't know where the keyframes are going to start until we've counted all the
+        // channels, so keep their offsets relative for now.
+        let mut clips = Vec::new();
+        let mut channels = Vec::new();
+        let mut keyframes = Vec::new();
+
+        for animation in animations {
+            let first_channel = channels.len();
+
+            let translation_channels = animation.translation_channels.iter().map(|channel| {
+                let values = channel
+                    .values
+                    .iter()
+                    .map(|value| Vec4::new(value.x, value.y, value.z, 0.0))
+                    .collect::<Vec<_>>();
+                (
+                    channel_header(channel, PROPERTY_TRANSLATION),
+                    &channel.times,
+                    channel.node_index,
+                    values,
+                )
+            });
+
+            let rotation_channels = animation.rotation_channels.iter().map(|channel| {
+                let values = channel
+                    .values
+                    .iter()
+                    .map(|value| Vec4::new(value.v.x, value.v.y, value.v.z, value.s))
+                    .collect();
+                (
+                    channel_header(channel, PROPERTY_ROTATION),
+                    &channel.times,
+                    channel.node_index,
+                    values,
+                )
+            });
+
+            for (header, times, node_index, values) in translation_channels.chain(rotation_channels)
+            {
+                let node = match packed_indices[node_index] {
+                    Some(node) => node,
+                    None => continue,
+                };
+
+                let first_time = keyframes.len();
+                keyframes.extend(times.iter().map(|time| Vec4::new(*time, 0.0, 0.0, 0.0)));
+                let first_value = keyframes.len();
+                keyframes.extend_from_slice(&values);
+
+                channels.push((
+                    Vec4::new(node as f32, header.y, header.z, header.w),
+                    first_time,
+                    first_value,
+                ));
+            }
+
+            clips.push((first_channel, channels.len() - first_channel));
+        }
+
+        let clips_offset = data.len();
+        let channels_offset = clips_offset + clips.len();
+        let keyframes_offset = channels_offset + channels.len() * 2;
+
+        for (first_channel, num_channels) in &clips {
+            data.push(Vec4::new(
+                (channels_offset + first_channel * 2) as f32,
+                *num_channels as f32,
+                0.0,
+                0.0,
+            ));
+        }
+
+        for (header, first_time, first_value) in &channels {
+            data.push(*header);
+            data.push(Vec4::new(
+                (keyframes_offset + first_time) as f32,
+                (keyframes_offset + first_value) as f32,
+                0.0,
+                0.0,
+            ));
+        }
+
+        data.extend_from_slice(&keyframes);
+
+        Self {
+            data,
+            uniforms: SkinningUniforms {
+                num_instances: 0,
+                num_nodes: traversal.len() as u32,
+                num_joints: skin.joints.len() as u32,
+                num_clips: clips.len() as u32,
+                joints_offset: joints_offset as u32,
+                clips_offset: clips_offset as u32,
+            },
+        }
+    }
+}
+
+// Everything but the node, which gets remapped.
+fn channel_header<T>(channel: &Channel<T>, property: f32) -> Vec4 {
+    let interpolation = match channel.interpolation {
+        Interpolation::Step => 0.0,
+        Interpolation::Linear => 1.0,
+        Interpolation::CubicSpline => 2.0,
+    };
+
+    Vec4::new(0.0, property, interpolation, channel.times.len() as f32)
+}
+
+impl AnimationState {
+    pub fn skinning_instance(&self, animations: &[Animation]) -> SkinningInstance {
+        // Clips that the model doesn't have get an out of range index, which the shader skips.
+        let index = |clip: &str| {
+            animations
+                .iter()
+                .position(|animation| animation.name.eq_ignore_ascii_case(clip))
+                .map(|index| index as u32)
+                .unwrap_or(u32::MAX)
+        };
+
+        match &self.crossfade {
+            Some(crossfade) => SkinningInstance {
+                clip: index(self.clip),
+                time: self.time,
+                fade_clip: index(crossfade.clip),
+                fade_time: crossfade.time,
+                fade_weight: crossfade.frame as f32 / crossfade.frames as f32,
+            },
+            None => SkinningInstance {
+                clip: index(self.clip),
+                time: self.time,
+                fade_clip: u32::MAX,
+                fade_time: 0.0,
+                fade_weight: 1.0,
+            },
+        }
+    }
+}
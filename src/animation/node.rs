@@ -8,7 +8,7 @@ use ultraviolet::{Mat4, Vec3};
 pub struct Nodes {
     nodes: Vec<Node>,
     roots_indices: Vec<usize>,
-    depth_first_taversal_indices: Vec<(usize, Option<usize>)>,
+    pub(super) depth_first_taversal_indices: Vec<(usize, Option<usize>)>,
 }
 
 impl Nodes {
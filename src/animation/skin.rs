@@ -53,8 +53,8 @@ impl Skin {
 #[derive(Copy, Clone, Debug)]
 pub struct Joint {
     pub matrix: Mat4,
-    inverse_bind_matrix: Mat4,
-    node_id: usize,
+    pub(super) inverse_bind_matrix: Mat4,
+    pub(super) node_id: usize,
 }
 
 impl Joint {
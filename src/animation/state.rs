@@ -0,0 +1,253 @@
+use super::{Animation, Skin};
+
+// How many ticks it takes to fade from one clip into another by default.
+pub const CROSSFADE_FRAMES: u32 = 8;
+
+// Which clip an entity is playing and how far through it is. Clips are referred to by name
+// (case-insensitively) so that models don't all have to export their animations in the same order.
+#[derive(Clone, Debug)]
+pub struct AnimationState {
+    pub(super) clip: &'static str,
+    pub(super) time: f32,
+    pub speed: f32,
+    // Clips that don't loop stop on their last frame.
+    pub(super) looping: bool,
+    pub(super) crossfade: Option<Crossfade>,
+}
+
+// The clip being faded out of.
+#[derive(Clone, Debug)]
+pub(super) struct Crossfade {
+    pub(super) clip: &'static str,
+    pub(super) time: f32,
+    pub(super) frame: u32,
+    pub(super) frames: u32,
+}
+
+impl AnimationState {
+    pub fn new(clip: &'static str) -> Self {
+        Self {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+            crossfade: None,
+        }
+    }
+
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time;
+    }
+
+    // Start playing a clip from the beginning, fading out the current one over `frames` ticks.
+    pub fn play(&mut self, clip: &'static str, frames: u32) {
+        if clip.eq_ignore_ascii_case(self.clip) {
+            return;
+        }
+
+        self.crossfade = if frames > 0 {
+            Some(Crossfade {
+                clip: self.clip,
+                time: self.time,
+                frame: 0,
+                frames,
+            })
+        } else {
+            None
+        };
+
+        self.clip = clip;
+        self.time = 0.0;
+        self.looping = true;
+    }
+
+    // Like `play`, but for clips like dying that should only happen once.
+    pub fn play_once(&mut self, clip: &'static str, frames: u32) {
+        self.play(clip, frames);
+        self.looping = false;
+    }
+
+    pub fn advance(&mut self, delta_time: f32, animations: &[Animation]) {
+        let delta_time = delta_time * self.speed;
+
+        let animation = find(animations, self.clip);
+
+        self.time = if self.looping {
+            wrap(self.time + delta_time, animation)
+        } else {
+            clamp(self.time + delta_time, animation)
+        };
+
+        if let Some(crossfade) = self.crossfade.as_mut() {
+            crossfade.time = wrap(
+                crossfade.time + delta_time,
+                find(animations, crossfade.clip),
+            );
+            crossfade.frame += 1;
+
+            if crossfade.frame >= crossfade.frames {
+                self.crossfade = None;
+            }
+        }
+    }
+
+    // Pose a skin and compute its joint matrices. Clips that the model doesn't have (e.g. a 'die'
+    // animation) just leave the skin in whatever pose it was in before.
+    pub fn pose(&self, animations: &[Animation], skin: &mut Skin) {
+        match &self.crossfade {
+            Some(crossfade) => {
+                if let Some(animation) = find(animations, crossfade.clip) {
+                    animation.apply(skin, crossfade.time, 1.0);
+                }
+
+                if let Some(animation) = find(animations, self.clip) {
+                    let weight = crossfade.frame as f32 / crossfade.frames as f32;
+                    animation.apply(skin, self.time, weight);
+                }
+            }
+            None => {
+                if let Some(animation) = find(animations, self.clip) {
+                    animation.apply(skin, self.time, 1.0);
+                }
+            }
+        }
+
+        skin.update();
+    }
+}
+
+// Each skin can be posed independently of the others, so with lots of units on screen it's worth
+// spreading them across threads.
+pub fn pose_all(skins: &mut [(&AnimationState, &mut Skin)], animations: &[Animation]) {
+    #[cfg(feature = "multithreading")]
+    {
+        use rayon::prelude::*;
+
+        skins
+            .par_iter_mut()
+            .for_each(|(state, skin)| state.pose(animations, skin));
+    }
+
+    #[cfg(not(feature = "multithreading"))]
+    for (state, skin) in skins {
+        state.pose(animations, skin);
+    }
+}
+
+fn find<'a>(animations: &'a [Animation], clip: &str) -> Option<&'a Animation> {
+    animations
+        .iter()
+        .find(|animation| animation.name.eq_ignore_ascii_case(clip))
+}
+
+fn wrap(time: f32, animation: Option<&Animation>) -> f32 {
+    match animation {
+        Some(animation) if animation.total_time > 0.0 => time % animation.total_time,
+        _ => 0.0,
+    }
+}
+
+fn clamp(time: f32, animation: Option<&Animation>) -> f32 {
+    match animation {
+        Some(animation) => time.min(animation.total_time),
+        None => 0.0,
+    }
+}
+
+#[test]
+fn crossfades_finish_after_the_given_number_of_frames() {
+    let mut state = AnimationState::new("idle");
+    state.play("walk", 3);
+
+    for _ in 0..2 {
+        state.advance(1.0 / 60.0, &[]);
+        assert!(state.crossfade.is_some());
+    }
+
+    state.advance(1.0 / 60.0, &[]);
+    assert!(state.crossfade.is_none());
+
+    // Playing the clip that's already playing shouldn't restart it.
+    state.play("Walk", 3);
+    assert!(state.crossfade.is_none());
+}
+
+#[test]
+fn clips_played_once_stop_on_the_last_frame() {
+    let animations = [Animation {
+        name: "Die".to_string(),
+        total_time: 1.0,
+        translation_channels: Vec::new(),
+        rotation_channels: Vec::new(),
+    }];
+
+    let mut state = AnimationState::new("idle");
+    state.play_once("die", 0);
+
+    state.advance(0.75, &animations);
+    assert_eq!(state.time, 0.75);
+    state.advance(0.75, &animations);
+    assert_eq!(state.time, 1.0);
+}
+
+// More of a benchmark than a test. Run it with
+// `cargo test --release posing_scales_across_threads -- --ignored --nocapture`.
+#[cfg(all(feature = "multithreading", not(feature = "gpu-skinning")))]
+#[test]
+#[ignore]
+fn posing_scales_across_threads() {
+    use std::time::{Duration, Instant};
+
+    let info =
+        crate::assets::AnimationInfo::load_gltf(include_bytes!("../../models/mouse.gltf")).unwrap();
+    let max_threads = rayon::current_num_threads();
+
+    for &count in &[1000, 2000, 4000] {
+        // A mix of clips at different points through them, like a real army.
+        let states: Vec<AnimationState> = (0..count)
+            .map(|i| {
+                let mut state = AnimationState::new(if i % 2 == 0 { "walk" } else { "shoot" });
+                state.set_time(i as f32 * 0.01);
+                state
+            })
+            .collect();
+
+        let pose = |threads: usize| -> (Duration, Vec<Skin>) {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let mut skins = vec![info.skin.clone(); count];
+            let mut pairs: Vec<_> = states.iter().zip(skins.iter_mut()).collect();
+
+            let start = Instant::now();
+            pool.install(|| pose_all(&mut pairs, &info.animations));
+            (start.elapsed(), skins)
+        };
+
+        // The first run is slower while everything gets loaded into the cache.
+        pose(1);
+        let (single_threaded, expected) = pose(1);
+        println!("{} units, 1 thread: {:?}", count, single_threaded);
+
+        let mut threads = 2;
+        while threads <= max_threads {
+            let (duration, skins) = pose(threads);
+            println!(
+                "{} units, {} threads: {:?} ({:.1}x)",
+                count,
+                threads,
+                duration,
+                single_threaded.as_secs_f32() / duration.as_secs_f32()
+            );
+
+            for (skin, expected) in skins.iter().zip(&expected) {
+                for (joint, expected) in skin.joints.iter().zip(&expected.joints) {
+                    assert_eq!(joint.matrix, expected.matrix);
+                }
+            }
+
+            threads *= 2;
+        }
+    }
+}
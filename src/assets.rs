@@ -1,9 +1,19 @@
 use crate::animation::{Animation, Skin};
-use crate::renderer::{AnimatedVertex, Vertex, TEXTURE_FORMAT};
+use crate::hot_reload::asset;
+use crate::renderer::{Vertex, TEXTURE_FORMAT};
+use crate::terrain::Terrain;
+use atlas::AtlasRegion;
+use image::RgbaImage;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use ultraviolet::{Vec2, Vec3, Vec4};
 use wgpu::util::DeviceExt;
 
+mod atlas;
+mod import;
+
 pub struct Assets {
-    pub surface_model: Model,
+    pub terrain_model: Model,
     pub bullet_model: Model,
     pub mouse_model: AnimatedModel,
     pub mouse_helmet_model: AnimatedModel,
@@ -14,22 +24,28 @@ pub struct Assets {
     pub cheese_moon_model: Model,
     pub billboard_model: Model,
     pub cheese_droplet_model: Model,
+    pub cheese_wheel_model: Model,
+    pub grass_tuft_model: Model,
     pub pump_model: AnimatedModel,
     pub pump_static_model: Model,
     pub wrench_model: AnimatedModel,
     pub blaster_model: AnimatedModel,
     pub explosion_model: Model,
 
-    pub texture_bind_group_layout: wgpu::BindGroupLayout,
+    // Every material bind group (the `_texture`s below) uses this, see `Material`.
+    pub material_bind_group_layout: wgpu::BindGroupLayout,
+    // The starry sky, laid out as a strip of cubemap faces. See `renderer::SkyPipeline`.
+    pub skybox: RgbaImage,
 
     pub surface_texture: wgpu::BindGroup,
+    pub terrain_texture: wgpu::BindGroup,
     pub mouse_texture: wgpu::BindGroup,
     pub misc_texture: wgpu::BindGroup,
-    pub armoury_texture: wgpu::BindGroup,
-    pub pump_texture: wgpu::BindGroup,
-    pub blaster_texture: wgpu::BindGroup,
+    // The armoury, pump, blaster, wrench, cheese droplet and scenery textures, packed into one atlas.
+    pub props_texture: wgpu::BindGroup,
     pub buttons_texture: wgpu::BindGroup,
     pub explosion_texture: wgpu::BindGroup,
+    pub decals_texture: wgpu::BindGroup,
 }
 
 #[derive(Default)]
@@ -38,55 +54,94 @@ pub struct ModelAnimations {
     pub pump: AnimationInfo,
 }
 
+impl ModelAnimations {
+    // The same animations that `Assets::new` loads, for running the game without a window.
+    pub fn load() -> anyhow::Result<Self> {
+        Ok(Self {
+            mouse: AnimationInfo::load_gltf(include_bytes!("../models/mouse.gltf"))?,
+            pump: AnimationInfo::load_gltf(include_bytes!("../models/pump.gltf"))?,
+        })
+    }
+}
+
 impl Assets {
     pub fn new(
         device: &wgpu::Device,
+        terrain: &Terrain,
     ) -> anyhow::Result<(Self, ModelAnimations, wgpu::CommandBuffer)> {
         let mut init_encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("Cheese init_encoder"),
         });
 
-        let texture_bind_group_layout =
+        let material_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("Cheese texture bind group layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::SampledTexture {
-                        multisampled: false,
-                        dimension: wgpu::TextureViewDimension::D2,
-                        component_type: wgpu::TextureComponentType::Float,
-                    },
-                    count: None,
-                }],
+                label: Some("Cheese material bind group layout"),
+                entries: &[
+                    material_bind_group_layout_entry(0),
+                    material_bind_group_layout_entry(1),
+                    material_bind_group_layout_entry(2),
+                ],
             });
 
-        let (mouse_model, mouse_animation_info) = AnimatedModel::load_gltf(
-            include_bytes!("../models/mouse.gltf"),
-            "Cheese mouse model",
-            device,
-        )?;
+        // The props are small and their uvs all stay within the texture, so they can share one. The
+        // wrench has always used the armoury texture.
+        let (props_material, props_regions) = Material::pack(&[
+            // The armoury's windows light up at night.
+            Material {
+                emissive: Some(decode_png(asset!("textures/armoury_windows.png"))?),
+                ..Material::load(
+                    decode_png(asset!("textures/armoury.png"))?,
+                    asset!("models/armoury.gltf"),
+                )?
+            },
+            Material::load(
+                decode_png(asset!("textures/blaster.png"))?,
+                asset!("models/blaster.gltf"),
+            )?,
+            Material::load(
+                decode_png(asset!("textures/pump.png"))?,
+                asset!("models/pump.gltf"),
+            )?,
+            Material::load(
+                decode_png(asset!("textures/surface.png"))?,
+                asset!("models/cheese_droplet.gltf"),
+            )?,
+            Material::new(decode_png(asset!("textures/grass.png"))?),
+        ]);
+        let [armoury_region, blaster_region, pump_region, droplet_region, grass_region] = [
+            props_regions[0],
+            props_regions[1],
+            props_regions[2],
+            props_regions[3],
+            props_regions[4],
+        ];
+
+        let (mouse_model, mouse_animation_info) =
+            AnimatedModel::load_gltf(asset!("models/mouse.gltf"), "Cheese mouse model", device)?;
 
         let (mouse_helmet_model, _) = AnimatedModel::load_gltf(
-            include_bytes!("../models/mouse_helmet.gltf"),
+            asset!("models/mouse_helmet.gltf"),
             "Cheese mouse helmet model",
             device,
         )?;
 
-        let (wrench_model, _) = AnimatedModel::load_gltf(
-            include_bytes!("../models/wrench.gltf"),
+        let (wrench_model, _) = AnimatedModel::load_gltf_in_atlas(
+            asset!("models/wrench.gltf"),
+            armoury_region,
             "Cheese wrench model",
             device,
         )?;
 
-        let (blaster_model, _) = AnimatedModel::load_gltf(
-            include_bytes!("../models/blaster.gltf"),
+        let (blaster_model, _) = AnimatedModel::load_gltf_in_atlas(
+            asset!("models/blaster.gltf"),
+            blaster_region,
             "Cheese blaster model",
             device,
         )?;
 
-        let (pump_model, pump_animation_info) = AnimatedModel::load_gltf(
-            include_bytes!("../models/pump.gltf"),
+        let (pump_model, pump_animation_info) = AnimatedModel::load_gltf_in_atlas(
+            asset!("models/pump.gltf"),
+            pump_region,
             "Cheese pump model",
             device,
         )?;
@@ -97,120 +152,137 @@ impl Assets {
             pump_model,
             wrench_model,
             blaster_model,
-            surface_model: Model::load_gltf(
-                include_bytes!("../models/surface.gltf"),
-                "Cheese surface model",
-                device,
-            )?,
+            terrain_model: Model::from_terrain(terrain, "Cheese terrain model", device),
             bullet_model: Model::load_gltf(
-                include_bytes!("../models/bullet.gltf"),
+                asset!("models/bullet.gltf"),
                 "Cheese bullet model",
                 device,
             )?,
             torus_model: Model::load_gltf(
-                include_bytes!("../models/torus.gltf"),
+                asset!("models/torus.gltf"),
                 "Cheese torus model",
                 device,
             )?,
             command_indicator_model: Model::load_gltf(
-                include_bytes!("../models/command_indicator.gltf"),
+                asset!("models/command_indicator.gltf"),
                 "Cheese command indicator model",
                 device,
             )?,
             command_path_model: Model::load_gltf(
-                include_bytes!("../models/command_path.gltf"),
+                asset!("models/command_path.gltf"),
                 "Cheese command path model",
                 device,
             )?,
-            armoury_model: Model::load_gltf(
-                include_bytes!("../models/armoury.gltf"),
+            armoury_model: Model::load_gltf_in_atlas(
+                asset!("models/armoury.gltf"),
+                armoury_region,
                 "Cheese armoury model",
                 device,
             )?,
             cheese_moon_model: Model::load_gltf(
-                include_bytes!("../models/cheese_moon.gltf"),
+                asset!("models/cheese_moon.gltf"),
                 "Cheese cheese moon model",
                 device,
             )?,
             billboard_model: Model::load_gltf(
-                include_bytes!("../models/billboard.gltf"),
+                asset!("models/billboard.gltf"),
                 "Cheese billboard model",
                 device,
             )?,
-            cheese_droplet_model: Model::load_gltf(
-                include_bytes!("../models/cheese_droplet.gltf"),
+            cheese_droplet_model: Model::load_gltf_in_atlas(
+                asset!("models/cheese_droplet.gltf"),
+                droplet_region,
                 "Cheese cheese droplet model",
                 device,
             )?,
-            pump_static_model: Model::load_gltf(
-                include_bytes!("../models/pump.gltf"),
+            // The cheese wheels are made of the same cheese as the droplets.
+            cheese_wheel_model: Model::load_gltf_in_atlas(
+                asset!("models/cheese_wheel.gltf"),
+                droplet_region,
+                "Cheese cheese wheel model",
+                device,
+            )?,
+            grass_tuft_model: Model::load_gltf_in_atlas(
+                asset!("models/grass_tuft.gltf"),
+                grass_region,
+                "Cheese grass tuft model",
+                device,
+            )?,
+            pump_static_model: Model::load_gltf_in_atlas(
+                asset!("models/pump.gltf"),
+                pump_region,
                 "Cheese static pump model",
                 device,
             )?,
             explosion_model: Model::load_gltf(
-                include_bytes!("../models/explosion.gltf"),
+                asset!("models/explosion.gltf"),
                 "Cheese explosion model",
                 device,
             )?,
 
-            surface_texture: load_texture(
-                include_bytes!("../textures/surface.png"),
-                "Cheese surface texture",
-                &texture_bind_group_layout,
+            surface_texture: Material::new(decode_png(asset!("textures/surface.png"))?)
+                .create_bind_group(
+                    "Cheese surface texture",
+                    &material_bind_group_layout,
+                    device,
+                    &mut init_encoder,
+                ),
+            terrain_texture: Material::new(
+                terrain.splat_texture(&decode_png(asset!("textures/surface.png"))?),
+            )
+            .create_bind_group(
+                "Cheese terrain texture",
+                &material_bind_group_layout,
                 device,
                 &mut init_encoder,
-            )?,
-            misc_texture: load_texture(
-                include_bytes!("../textures/misc.png"),
-                "Cheese misc texture",
-                &texture_bind_group_layout,
-                device,
-                &mut init_encoder,
-            )?,
-            mouse_texture: load_texture(
-                include_bytes!("../textures/mouse.png"),
+            ),
+            misc_texture: Material::new(decode_png(asset!("textures/misc.png"))?)
+                .create_bind_group(
+                    "Cheese misc texture",
+                    &material_bind_group_layout,
+                    device,
+                    &mut init_encoder,
+                ),
+            mouse_texture: Material::load(
+                add_team_colour_mask(decode_png(asset!("textures/mouse.png"))?),
+                asset!("models/mouse.gltf"),
+            )?
+            .create_bind_group(
                 "Cheese mouse texture",
-                &texture_bind_group_layout,
+                &material_bind_group_layout,
                 device,
                 &mut init_encoder,
-            )?,
-            armoury_texture: load_texture(
-                include_bytes!("../textures/armoury.png"),
-                "Cheese armoury texture",
-                &texture_bind_group_layout,
+            ),
+            props_texture: props_material.create_bind_group(
+                "Cheese props texture",
+                &material_bind_group_layout,
                 device,
                 &mut init_encoder,
-            )?,
-            pump_texture: load_texture(
-                include_bytes!("../textures/pump.png"),
-                "Cheese pump texture",
-                &texture_bind_group_layout,
+            ),
+            buttons_texture: Material::new(decode_png(asset!("textures/buttons.png"))?)
+                .create_bind_group(
+                    "Cheese buttons texture",
+                    &material_bind_group_layout,
+                    device,
+                    &mut init_encoder,
+                ),
+            explosion_texture: Material::new(decode_png(asset!("textures/explosion.png"))?)
+                .create_bind_group(
+                    "Cheese explosion texture",
+                    &material_bind_group_layout,
+                    device,
+                    &mut init_encoder,
+                ),
+            decals_texture: Material::new(crate::renderer::decal_atlas()).create_bind_group(
+                "Cheese decals texture",
+                &material_bind_group_layout,
                 device,
                 &mut init_encoder,
-            )?,
-            blaster_texture: load_texture(
-                include_bytes!("../textures/blaster.png"),
-                "Cheese blaster texture",
-                &texture_bind_group_layout,
-                device,
-                &mut init_encoder,
-            )?,
-            buttons_texture: load_texture(
-                include_bytes!("../textures/buttons.png"),
-                "Cheese buttons texture",
-                &texture_bind_group_layout,
-                device,
-                &mut init_encoder,
-            )?,
-            explosion_texture: load_texture(
-                include_bytes!("../textures/explosion.png"),
-                "Cheese explosion texture",
-                &texture_bind_group_layout,
-                device,
-                &mut init_encoder,
-            )?,
+            ),
+
+            skybox: decode_png(asset!("textures/skybox.png"))?,
 
-            texture_bind_group_layout,
+            material_bind_group_layout,
         };
 
         let animations = ModelAnimations {
@@ -222,15 +294,176 @@ impl Assets {
     }
 }
 
-fn load_texture(
-    bytes: &[u8],
+fn decode_png(bytes: &[u8]) -> anyhow::Result<image::RgbaImage> {
+    Ok(image::load_from_memory_with_format(bytes, image::ImageFormat::Png)?.into_rgba())
+}
+
+// The team colour mask goes in the alpha channel of a texture, where 0 is fully team coloured (so that
+// a texture without one isn't coloured at all). The pink bits of the mice (their ears, paws and
+// tails) are what gets coloured, so rather than painting the mask by hand it's worked out from how
+// much pinker than the fur each pixel is.
+fn add_team_colour_mask(mut image: image::RgbaImage) -> image::RgbaImage {
+    for pixel in image.pixels_mut() {
+        let [r, g, b, _] = pixel.0;
+        let pinkness = (r as f32 - g as f32) - (g as f32 - b as f32);
+        let mask = (pinkness / 30.0).clamp(0.0, 1.0);
+        pixel.0[3] = ((1.0 - mask) * 255.0).round() as u8;
+    }
+
+    image
+}
+
+fn material_bind_group_layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStage::FRAGMENT,
+        ty: wgpu::BindingType::SampledTexture {
+            multisampled: false,
+            dimension: wgpu::TextureViewDimension::D2,
+            component_type: wgpu::TextureComponentType::Float,
+        },
+        count: None,
+    }
+}
+
+// What a model looks like: its colours (the albedo), plus optional normal and emissive maps. The
+// bind groups all have the same layout (`Assets::material_bind_group_layout`), with the albedo at
+// binding 0, the normal map at 1 and the emissive map at 2, so any shader that only wants the colours
+// can ignore the rest. Missing maps are filled in with flat normals and no glow. The emissive map's
+// alpha is how much it glows during the day, as opposed to at night.
+//
+// Only `material.frag` (with the `material-maps` feature) uses the normal and emissive maps.
+pub struct Material {
+    pub albedo: RgbaImage,
+    pub normal_map: Option<RgbaImage>,
+    pub emissive: Option<RgbaImage>,
+}
+
+const FLAT_NORMAL: image::Rgba<u8> = image::Rgba([128, 128, 255, 255]);
+const NO_EMISSION: image::Rgba<u8> = image::Rgba([0, 0, 0, 255]);
+
+impl Material {
+    pub fn new(albedo: RgbaImage) -> Self {
+        Self {
+            albedo,
+            normal_map: None,
+            emissive: None,
+        }
+    }
+
+    // The colours still come from the textures in `textures/`, but any normal or emissive maps that
+    // are in the model's first material are used too.
+    pub fn load(albedo: RgbaImage, gltf_bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut materials = import::import_materials(gltf_bytes)?;
+        let normal_map = materials
+            .iter_mut()
+            .find_map(|material| material.normal_map.take());
+        let emissive = materials
+            .iter_mut()
+            .find_map(|material| material.emissive.take());
+
+        Ok(Self {
+            albedo,
+            normal_map,
+            emissive,
+        })
+    }
+
+    // Puts the materials into one atlas, giving back where each one went. The maps all get packed
+    // the same way, so the regions work for all three.
+    pub fn pack(materials: &[Material]) -> (Self, Vec<AtlasRegion>) {
+        let albedos: Vec<&RgbaImage> = materials.iter().map(|material| &material.albedo).collect();
+        let (albedo, regions) = atlas::pack(&albedos);
+
+        let pack_maps = |map: fn(&Material) -> &Option<RgbaImage>, default| {
+            if materials.iter().all(|material| map(material).is_none()) {
+                return None;
+            }
+
+            let maps: Vec<RgbaImage> = materials
+                .iter()
+                .map(|material| {
+                    let (width, height) = material.albedo.dimensions();
+                    match map(material) {
+                        Some(image) if image.dimensions() == (width, height) => image.clone(),
+                        Some(image) => image::imageops::resize(
+                            image,
+                            width,
+                            height,
+                            image::imageops::FilterType::Triangle,
+                        ),
+                        None => RgbaImage::from_pixel(width, height, default),
+                    }
+                })
+                .collect();
+
+            Some(atlas::pack(&maps.iter().collect::<Vec<_>>()).0)
+        };
+
+        let material = Self {
+            normal_map: pack_maps(|material| &material.normal_map, FLAT_NORMAL),
+            emissive: pack_maps(|material| &material.emissive, NO_EMISSION),
+            albedo,
+        };
+
+        (material, regions)
+    }
+
+    pub fn create_bind_group(
+        &self,
+        label: &str,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> wgpu::BindGroup {
+        let flat_normal = RgbaImage::from_pixel(1, 1, FLAT_NORMAL);
+        let no_emission = RgbaImage::from_pixel(1, 1, NO_EMISSION);
+
+        let albedo = upload_texture(&self.albedo, TEXTURE_FORMAT, label, device, encoder);
+        // Normals aren't colours, so they shouldn't be converted from srgb.
+        let normal_map = upload_texture(
+            self.normal_map.as_ref().unwrap_or(&flat_normal),
+            wgpu::TextureFormat::Rgba8Unorm,
+            label,
+            device,
+            encoder,
+        );
+        let emissive = upload_texture(
+            self.emissive.as_ref().unwrap_or(&no_emission),
+            TEXTURE_FORMAT,
+            label,
+            device,
+            encoder,
+        );
+
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Cheese material bind group"),
+            layout: bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&albedo),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&normal_map),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&emissive),
+                },
+            ],
+        })
+    }
+}
+
+fn upload_texture(
+    image: &RgbaImage,
+    format: wgpu::TextureFormat,
     label: &str,
-    bind_group_layout: &wgpu::BindGroupLayout,
     device: &wgpu::Device,
     encoder: &mut wgpu::CommandEncoder,
-) -> anyhow::Result<wgpu::BindGroup> {
-    let image = image::load_from_memory_with_format(bytes, image::ImageFormat::Png)?.into_rgba();
-
+) -> wgpu::TextureView {
     let temp_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("Cheese texture staging buffer"),
         contents: &*image,
@@ -248,7 +481,7 @@ fn load_texture(
         mip_level_count: 1,
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
-        format: TEXTURE_FORMAT,
+        format,
         usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
         label: Some(label),
     });
@@ -270,86 +503,52 @@ fn load_texture(
         texture_extent,
     );
 
-    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-
-    Ok(device.create_bind_group(&wgpu::BindGroupDescriptor {
-        label: Some("Cheese texture bind group"),
-        layout: bind_group_layout,
-        entries: &[wgpu::BindGroupEntry {
-            binding: 0,
-            resource: wgpu::BindingResource::TextureView(&view),
-        }],
-    }))
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
 }
 
 pub struct Model {
     pub vertices: wgpu::Buffer,
     pub indices: wgpu::Buffer,
     pub num_indices: u32,
+    // The simplified levels of detail, as ranges of the index buffer after the full model's
+    // `num_indices`. See `append_lods`.
+    pub lods: Vec<Range<u32>>,
 }
 
 impl Model {
-    pub fn load_gltf(
-        gltf_bytes: &[u8],
-        label: &str,
-        device: &wgpu::Device,
-    ) -> anyhow::Result<Self> {
-        let gltf = gltf::Gltf::from_slice(gltf_bytes)?;
-
-        let buffers = load_buffers(&gltf)?;
-
-        let mut vertices = Vec::new();
-        let mut indices = Vec::new();
-
-        for mesh in gltf.meshes() {
-            for primitive in mesh.primitives() {
-                if primitive.mode() != gltf::mesh::Mode::Triangles {
-                    return Err(anyhow::anyhow!(
-                        "Primitives with {:?} are not allowed. Triangles only.",
-                        primitive.mode()
-                    ));
-                }
+    fn from_terrain(terrain: &Terrain, label: &str, device: &wgpu::Device) -> Self {
+        let (vertices, indices) = terrain.mesh();
 
-                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-
-                let positions = reader.read_positions().unwrap();
-                let tex_coordinates = reader.read_tex_coords(0).unwrap().into_f32();
-                let normals = reader.read_normals().unwrap();
-
-                let num_vertices = vertices.len() as u32;
-
-                indices.extend(
-                    reader
-                        .read_indices()
-                        .unwrap()
-                        .into_u32()
-                        .map(|i| i + num_vertices),
-                );
-
-                positions
-                    .zip(tex_coordinates)
-                    .zip(normals)
-                    .for_each(|((p, uv), n)| {
-                        vertices.push(Vertex {
-                            position: p.into(),
-                            normal: n.into(),
-                            uv: uv.into(),
-                        });
-                    });
-            }
+        Self {
+            vertices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::cast_slice(&vertices),
+                usage: wgpu::BufferUsage::VERTEX,
+            }),
+            indices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::cast_slice(&indices),
+                usage: wgpu::BufferUsage::INDEX,
+            }),
+            num_indices: indices.len() as u32,
+            lods: Vec::new(),
         }
+    }
 
-        log::debug!(
-            "Gltf model {} loaded. Vertices: {}. Indices: {}.",
-            label,
-            vertices.len(),
-            indices.len(),
-        );
+    fn with_lods(
+        vertices: &[Vertex],
+        mut indices: Vec<u32>,
+        label: &str,
+        device: &wgpu::Device,
+    ) -> Self {
+        let num_indices = indices.len() as u32;
+        let positions: Vec<Vec3> = vertices.iter().map(|vertex| vertex.position).collect();
+        let lods = append_lods(&positions, &mut indices);
 
-        Ok(Self {
+        Self {
             vertices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some(label),
-                contents: bytemuck::cast_slice(&vertices),
+                contents: bytemuck::cast_slice(vertices),
                 usage: wgpu::BufferUsage::VERTEX,
             }),
             indices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -357,8 +556,59 @@ impl Model {
                 contents: bytemuck::cast_slice(&indices),
                 usage: wgpu::BufferUsage::INDEX,
             }),
-            num_indices: indices.len() as u32,
-        })
+            num_indices,
+            lods,
+        }
+    }
+
+    // The part of the index buffer to draw for a level of detail, where 0 is the full model. Asking
+    // for more detail levels than there are just gives the simplest one.
+    pub fn lod_indices(&self, lod: usize) -> Range<u32> {
+        match (lod.checked_sub(1), self.lods.last()) {
+            (Some(i), Some(simplest)) => self.lods.get(i).unwrap_or(simplest).clone(),
+            _ => 0..self.num_indices,
+        }
+    }
+
+    pub fn load_gltf(
+        gltf_bytes: &[u8],
+        label: &str,
+        device: &wgpu::Device,
+    ) -> anyhow::Result<Self> {
+        Self::from_imported(import::import(gltf_bytes)?, label, device)
+    }
+
+    // For models whose texture is in an atlas (see `Material::pack`).
+    pub fn load_gltf_in_atlas(
+        gltf_bytes: &[u8],
+        region: AtlasRegion,
+        label: &str,
+        device: &wgpu::Device,
+    ) -> anyhow::Result<Self> {
+        let mut model = import::import(gltf_bytes)?;
+        model.remap_uvs(region);
+        Self::from_imported(model, label, device)
+    }
+
+    fn from_imported(
+        model: import::ImportedModel,
+        label: &str,
+        device: &wgpu::Device,
+    ) -> anyhow::Result<Self> {
+        log::debug!(
+            "Gltf model {} loaded. Vertices: {}. Indices: {}. Materials: {}.",
+            label,
+            model.vertices.len(),
+            model.indices.len(),
+            model.materials.len(),
+        );
+
+        Ok(Self::with_lods(
+            &model.static_vertices(),
+            model.indices,
+            label,
+            device,
+        ))
     }
 }
 
@@ -367,6 +617,9 @@ pub struct AnimatedModel {
     pub indices: wgpu::Buffer,
     pub num_indices: u32,
     pub joint_uniforms: wgpu::Buffer,
+    // The model in its bind pose, for drawing far away copies of it without having to skin them.
+    // This has the levels of detail.
+    pub static_model: Model,
 }
 
 #[derive(Default)]
@@ -375,6 +628,16 @@ pub struct AnimationInfo {
     pub animations: Vec<Animation>,
 }
 
+impl AnimationInfo {
+    // Loads just the skin and animations of a model, without needing a device to upload the
+    // vertices to.
+    pub fn load_gltf(gltf_bytes: &[u8]) -> anyhow::Result<Self> {
+        import::import(gltf_bytes)?
+            .animation_info
+            .ok_or_else(|| anyhow::anyhow!("The model doesn't have a skin"))
+    }
+}
+
 #[derive(bytemuck::Pod, bytemuck::Zeroable, Copy, Clone)]
 #[repr(C)]
 struct JointUniforms {
@@ -383,69 +646,34 @@ struct JointUniforms {
 
 impl AnimatedModel {
     pub fn load_gltf(
-        gltf_bytes: &'static [u8],
+        gltf_bytes: &[u8],
         label: &str,
         device: &wgpu::Device,
     ) -> anyhow::Result<(Self, AnimationInfo)> {
-        let gltf = gltf::Gltf::from_slice(gltf_bytes)?;
-
-        let buffers = load_buffers(&gltf)?;
-
-        let mut vertices = Vec::new();
-        let mut indices = Vec::new();
-
-        for mesh in gltf.meshes() {
-            for primitive in mesh.primitives() {
-                if primitive.mode() != gltf::mesh::Mode::Triangles {
-                    return Err(anyhow::anyhow!(
-                        "Primitives with {:?} are not allowed. Triangles only.",
-                        primitive.mode()
-                    ));
-                }
-
-                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-
-                let positions = reader.read_positions().unwrap();
-                let tex_coordinates = reader.read_tex_coords(0).unwrap().into_f32();
-                let normals = reader.read_normals().unwrap();
-                let joints = reader.read_joints(0).unwrap().into_u16();
-                let weights = reader.read_weights(0).unwrap().into_f32();
-
-                let num_vertices = vertices.len() as u32;
-
-                indices.extend(
-                    reader
-                        .read_indices()
-                        .unwrap()
-                        .into_u32()
-                        .map(|i| i + num_vertices),
-                );
-
-                positions
-                    .zip(tex_coordinates)
-                    .zip(normals)
-                    .zip(joints)
-                    .zip(weights)
-                    .for_each(|((((p, uv), n), j), w)| {
-                        vertices.push(AnimatedVertex {
-                            position: p.into(),
-                            normal: n.into(),
-                            uv: uv.into(),
-                            joints: [j[0] as f32, j[1] as f32, j[2] as f32, j[3] as f32].into(),
-                            joint_weights: w.into(),
-                        });
-                    });
-            }
-        }
+        Self::from_imported(import::import(gltf_bytes)?, label, device)
+    }
 
-        let skin = Skin::load(
-            &gltf.skins().next().unwrap(),
-            gltf.nodes(),
-            &gltf.scenes().next().unwrap(),
-            &buffers,
-        );
+    pub fn load_gltf_in_atlas(
+        gltf_bytes: &[u8],
+        region: AtlasRegion,
+        label: &str,
+        device: &wgpu::Device,
+    ) -> anyhow::Result<(Self, AnimationInfo)> {
+        let mut model = import::import(gltf_bytes)?;
+        model.remap_uvs(region);
+        Self::from_imported(model, label, device)
+    }
 
-        let animations = crate::animation::load_animations(gltf.animations(), &buffers);
+    fn from_imported(
+        model: import::ImportedModel,
+        label: &str,
+        device: &wgpu::Device,
+    ) -> anyhow::Result<(Self, AnimationInfo)> {
+        let static_vertices = model.static_vertices();
+        let AnimationInfo { skin, animations } = model
+            .animation_info
+            .ok_or_else(|| anyhow::anyhow!("{} doesn't have a skin", label))?;
+        let (vertices, indices) = (model.vertices, model.indices);
 
         log::debug!(
             "Gltf model {} loaded. Vertices: {}. Indices: {}. Joints: {}, Animations: {}.",
@@ -458,6 +686,7 @@ impl AnimatedModel {
 
         Ok((
             Self {
+                static_model: Model::with_lods(&static_vertices, indices.clone(), label, device),
                 vertices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                     label: Some(label),
                     contents: bytemuck::cast_slice(&vertices),
@@ -482,6 +711,139 @@ impl AnimatedModel {
     }
 }
 
+// Works out the tangents of a mesh from how its uvs are laid out, for models that don't come with
+// their own. Each triangle adds its tangent and bitangent onto its corners, and then they're
+// straightened out against the normal at each vertex.
+pub fn generate_tangents(
+    positions: &[Vec3],
+    normals: &[Vec3],
+    uvs: &[Vec2],
+    indices: &[u32],
+) -> Vec<Vec4> {
+    let mut tangents = vec![Vec3::zero(); positions.len()];
+    let mut bitangents = vec![Vec3::zero(); positions.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [
+            triangle[0] as usize,
+            triangle[1] as usize,
+            triangle[2] as usize,
+        ];
+
+        let (edge_1, edge_2) = (positions[b] - positions[a], positions[c] - positions[a]);
+        let (uv_1, uv_2) = (uvs[b] - uvs[a], uvs[c] - uvs[a]);
+
+        let determinant = uv_1.x * uv_2.y - uv_2.x * uv_1.y;
+        // The uvs are squashed flat, so there's no way they go.
+        if determinant.abs() < f32::EPSILON {
+            continue;
+        }
+
+        let tangent = (edge_1 * uv_2.y - edge_2 * uv_1.y) / determinant;
+        let bitangent = (edge_2 * uv_1.x - edge_1 * uv_2.x) / determinant;
+
+        for &i in &[a, b, c] {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+
+    normals
+        .iter()
+        .zip(tangents)
+        .zip(bitangents)
+        .map(|((normal, tangent), bitangent)| {
+            let mut tangent = tangent - *normal * normal.dot(tangent);
+
+            // Any direction along the surface will do when the uvs don't go anywhere.
+            if tangent.mag_sq() < f32::EPSILON {
+                tangent = normal.cross(Vec3::unit_y());
+                if tangent.mag_sq() < f32::EPSILON {
+                    tangent = normal.cross(Vec3::unit_x());
+                }
+            }
+
+            let handedness = if normal.cross(tangent).dot(bitangent) < 0.0 {
+                -1.0
+            } else {
+                1.0
+            };
+
+            Vec4::from(tangent.normalized()) + Vec4::unit_w() * handedness
+        })
+        .collect()
+}
+
+// How big the grid cells are for each simplified level of detail, relative to the size of the model.
+pub const LOD_CELL_SIZES: [f32; 2] = [1.0 / 16.0, 1.0 / 8.0];
+
+// Adds simplified versions of a mesh onto the end of its indices, returning where they are. None of
+// the models come with their own low poly versions, so they're made by snapping the vertices to a
+// grid and throwing away any triangles that get squashed flat. It's crude, but it doesn't need any
+// new vertices, so the levels of detail all share the same vertex buffer.
+fn append_lods(positions: &[Vec3], indices: &mut Vec<u32>) -> Vec<Range<u32>> {
+    let (min, max) = positions.iter().fold(
+        (Vec3::one() * f32::MAX, Vec3::one() * f32::MIN),
+        |(min, max), position| {
+            (
+                min.min_by_component(*position),
+                max.max_by_component(*position),
+            )
+        },
+    );
+    let size = (max - min).component_max();
+
+    if positions.is_empty() || size <= 0.0 {
+        return Vec::new();
+    }
+
+    let full_indices = indices.clone();
+
+    LOD_CELL_SIZES
+        .iter()
+        .map(|cell_size| {
+            let start = indices.len() as u32;
+            indices.extend(simplify(positions, &full_indices, size * cell_size));
+            start..indices.len() as u32
+        })
+        .collect()
+}
+
+fn simplify(positions: &[Vec3], indices: &[u32], cell_size: f32) -> Vec<u32> {
+    // Every vertex in a cell gets merged into the first one that was found in it.
+    let mut cells = HashMap::new();
+    let merged_into: Vec<u32> = positions
+        .iter()
+        .enumerate()
+        .map(|(i, position)| {
+            let cell = (*position / cell_size).map(f32::floor);
+            let cell = (cell.x as i32, cell.y as i32, cell.z as i32);
+            *cells.entry(cell).or_insert(i as u32)
+        })
+        .collect();
+
+    let mut triangles = HashSet::new();
+
+    indices
+        .chunks_exact(3)
+        .map(|triangle| {
+            [
+                merged_into[triangle[0] as usize],
+                merged_into[triangle[1] as usize],
+                merged_into[triangle[2] as usize],
+            ]
+        })
+        .filter(|&[a, b, c]| a != b && b != c && a != c)
+        .filter(|triangle| {
+            // Triangles that have been merged into the same one only need to be drawn once.
+            let mut sorted = *triangle;
+            sorted.sort_unstable();
+            triangles.insert(sorted)
+        })
+        .flat_map(|triangle| triangle.to_vec())
+        .collect()
+}
+
 // Load the buffers from a gltf document into a vector of byte vectors.
 // I mostly copied what bevy does for this because it's a little confusing at first.
 // https://github.com/bevyengine/bevy/blob/master/crates/bevy_gltf/src/loader.rs
@@ -513,3 +875,73 @@ fn load_buffers(gltf: &gltf::Gltf) -> anyhow::Result<Vec<Vec<u8>>> {
 
     Ok(buffers)
 }
+
+#[test]
+fn only_the_pink_bits_of_the_mice_are_team_coloured() {
+    let image = add_team_colour_mask(decode_png(include_bytes!("../textures/mouse.png")).unwrap());
+
+    // Ear
+    assert!(image.get_pixel(40, 1).0[3] < 64);
+    // Fur
+    assert_eq!(image.get_pixel(30, 30).0[3], 255);
+    assert_eq!(image.get_pixel(5, 4).0[3], 255);
+}
+
+#[test]
+fn tangents_follow_the_uvs() {
+    // A quad on the ground, with the uvs going along x and z.
+    let positions = [
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(1.0, 0.0, 0.0),
+        Vec3::new(1.0, 0.0, 1.0),
+        Vec3::new(0.0, 0.0, 1.0),
+    ];
+    let uvs: Vec<Vec2> = positions.iter().map(|p| Vec2::new(p.x, p.z)).collect();
+    let normals = [Vec3::unit_y(); 4];
+
+    let tangents = generate_tangents(&positions, &normals, &uvs, &[0, 2, 1, 0, 3, 2]);
+    assert!(tangents
+        .iter()
+        .all(|&tangent| (tangent - Vec4::new(1.0, 0.0, 0.0, -1.0)).mag() < 0.001));
+
+    // Mirroring the uvs flips the handedness.
+    let uvs: Vec<Vec2> = positions.iter().map(|p| Vec2::new(p.x, -p.z)).collect();
+    let tangents = generate_tangents(&positions, &normals, &uvs, &[0, 2, 1, 0, 3, 2]);
+    assert!(tangents.iter().all(|tangent| tangent.w == 1.0));
+}
+
+#[test]
+fn lods_have_fewer_triangles_than_the_full_model() {
+    let gltf = gltf::Gltf::from_slice(include_bytes!("../models/mouse.gltf")).unwrap();
+    let buffers = load_buffers(&gltf).unwrap();
+
+    let mut positions = Vec::new();
+    let mut indices = Vec::new();
+
+    for primitive in gltf.meshes().flat_map(|mesh| mesh.primitives()) {
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let num_vertices = positions.len() as u32;
+        indices.extend(
+            reader
+                .read_indices()
+                .unwrap()
+                .into_u32()
+                .map(|i| i + num_vertices),
+        );
+        positions.extend(reader.read_positions().unwrap().map(Vec3::from));
+    }
+
+    let num_indices = indices.len() as u32;
+    let lods = append_lods(&positions, &mut indices);
+
+    assert_eq!(lods.len(), LOD_CELL_SIZES.len());
+    assert_eq!(lods[0].start, num_indices);
+
+    let mut previous = num_indices;
+    for lod in lods {
+        let len = lod.end - lod.start;
+        assert!(len > 0 && len < previous, "{} vs {}", len, previous);
+        assert_eq!(len % 3, 0);
+        previous = len;
+    }
+}
@@ -0,0 +1,154 @@
+// Packs a bunch of small textures into one big one, so that the models using them can all share the
+// same material and be drawn one after the other without switching bind groups. The textures are
+// put onto shelves, tallest first, which wastes a bit of space but is plenty for a handful of 64x64
+// prop textures.
+//
+// This only works for models whose uvs stay within 0 to 1. Anything that relies on the texture
+// wrapping around (like the cheese moon) needs its own texture.
+
+use image::RgbaImage;
+use ultraviolet::Vec2;
+
+// Around each texture, so that linear filtering at the edges doesn't pick up its neighbours. The
+// padding is filled in with the texture's edge pixels.
+const PADDING: u32 = 2;
+
+// Where a texture ended up in the atlas, in uv space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtlasRegion {
+    pub offset: Vec2,
+    pub scale: Vec2,
+}
+
+impl AtlasRegion {
+    // Moves a uv from the original texture into the atlas.
+    pub fn remap(&self, uv: Vec2) -> Vec2 {
+        self.offset + uv * self.scale
+    }
+}
+
+pub fn pack(images: &[&RgbaImage]) -> (RgbaImage, Vec<AtlasRegion>) {
+    let padded = |size: u32| size + PADDING * 2;
+
+    let mut order: Vec<usize> = (0..images.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(images[i].height()));
+
+    let area: u32 = images
+        .iter()
+        .map(|image| padded(image.width()) * padded(image.height()))
+        .sum();
+    let widest = images.iter().map(|image| padded(image.width())).max();
+
+    let mut width = ((area as f32).sqrt().ceil() as u32)
+        .max(widest.unwrap_or(1))
+        .next_power_of_two();
+
+    // Positions of the padded textures, in pixels.
+    let positions = loop {
+        if let Some(positions) = shelve(images, &order, width) {
+            break positions;
+        }
+        width *= 2;
+    };
+
+    let height = positions
+        .iter()
+        .zip(images)
+        .map(|(&(_, y), image)| y + padded(image.height()))
+        .max()
+        .unwrap_or(1)
+        .next_power_of_two();
+
+    let mut atlas = RgbaImage::new(width, height);
+    let size = Vec2::new(width as f32, height as f32);
+
+    let regions = positions
+        .iter()
+        .zip(images)
+        .map(|(&(x, y), image)| {
+            copy_with_padding(&mut atlas, image, x, y);
+
+            AtlasRegion {
+                offset: Vec2::new((x + PADDING) as f32, (y + PADDING) as f32) / size,
+                scale: Vec2::new(image.width() as f32, image.height() as f32) / size,
+            }
+        })
+        .collect();
+
+    (atlas, regions)
+}
+
+// Puts the textures on shelves that are `width` wide, or gives up if it's too narrow for the atlas to
+// stay square-ish.
+fn shelve(images: &[&RgbaImage], order: &[usize], width: u32) -> Option<Vec<(u32, u32)>> {
+    let mut positions = vec![(0, 0); images.len()];
+    let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+
+    for &i in order {
+        let image_width = images[i].width() + PADDING * 2;
+        let image_height = images[i].height() + PADDING * 2;
+
+        if x + image_width > width {
+            x = 0;
+            y += shelf_height;
+            shelf_height = 0;
+        }
+
+        positions[i] = (x, y);
+        x += image_width;
+        shelf_height = shelf_height.max(image_height);
+    }
+
+    if y + shelf_height > width * 2 {
+        None
+    } else {
+        Some(positions)
+    }
+}
+
+fn copy_with_padding(atlas: &mut RgbaImage, image: &RgbaImage, x: u32, y: u32) {
+    let (width, height) = image.dimensions();
+
+    for atlas_y in 0..height + PADDING * 2 {
+        for atlas_x in 0..width + PADDING * 2 {
+            let source_x = (atlas_x as i32 - PADDING as i32)
+                .max(0)
+                .min(width as i32 - 1);
+            let source_y = (atlas_y as i32 - PADDING as i32)
+                .max(0)
+                .min(height as i32 - 1);
+
+            atlas.put_pixel(
+                x + atlas_x,
+                y + atlas_y,
+                *image.get_pixel(source_x as u32, source_y as u32),
+            );
+        }
+    }
+}
+
+#[test]
+fn textures_are_packed_without_overlapping() {
+    let images: Vec<RgbaImage> = (0..5)
+        .map(|i| RgbaImage::from_pixel(16 + i * 8, 64 - i * 8, image::Rgba([i as u8, 0, 0, 255])))
+        .collect();
+    let (atlas, regions) = pack(&images.iter().collect::<Vec<_>>());
+
+    let size = Vec2::new(atlas.width() as f32, atlas.height() as f32);
+
+    for (i, (image, region)) in images.iter().zip(&regions).enumerate() {
+        // Every pixel of the texture (plus the padding) comes out where the region says it is.
+        let corner = region.remap(Vec2::zero()) * size;
+        let other_corner = region.remap(Vec2::one()) * size;
+        assert_eq!(
+            other_corner - corner,
+            Vec2::new(image.width() as f32, image.height() as f32)
+        );
+
+        for y in corner.y as u32 - PADDING..other_corner.y as u32 + PADDING {
+            for x in corner.x as u32 - PADDING..other_corner.x as u32 + PADDING {
+                assert_eq!(atlas.get_pixel(x, y).0[0], i as u8);
+            }
+        }
+    }
+}
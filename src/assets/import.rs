@@ -0,0 +1,329 @@
+// Pulls everything we care about out of a glTF file in one go: the meshes (with or without a skin),
+// the materials they use (with any textures that are embedded in the file), and the skeleton and
+// animation clips. New models only need exporting from blender as glTF with the textures embedded
+// to be loaded with this, no converting needed.
+
+use super::{generate_tangents, load_buffers, AnimationInfo, AtlasRegion};
+use crate::renderer::{AnimatedVertex, Vertex};
+use std::ops::Range;
+use ultraviolet::{Mat3, Mat4, Vec3, Vec4};
+
+pub struct ImportedModel {
+    // Meshes without a skin are moved into place by the nodes they're attached to, while skinned
+    // meshes stay in their bind pose. Vertices that aren't skinned have all their joint weights set
+    // to 0.
+    pub vertices: Vec<AnimatedVertex>,
+    pub indices: Vec<u32>,
+    // Which part of `indices` uses which material.
+    pub primitives: Vec<ImportedPrimitive>,
+    pub materials: Vec<ImportedMaterial>,
+    // Only if the model has a skin.
+    pub animation_info: Option<AnimationInfo>,
+}
+
+// Like the materials, these aren't used for drawing yet.
+#[allow(dead_code)]
+pub struct ImportedPrimitive {
+    pub indices: Range<u32>,
+    // An index into `ImportedModel::materials`, if the primitive has one.
+    pub material: Option<usize>,
+}
+
+// The models still get their colours from the textures in `textures/`, so only the normal and
+// emissive maps are used (see `Material::load`).
+#[allow(dead_code)]
+pub struct ImportedMaterial {
+    pub name: Option<String>,
+    pub colour: Vec4,
+    // Blender exports shadeless textures as emissive ones, so this falls back to the emissive texture
+    // if there isn't a base colour texture.
+    pub texture: Option<image::RgbaImage>,
+    pub normal_map: Option<image::RgbaImage>,
+    // Only when there's a base colour texture as well, for the same reason.
+    pub emissive: Option<image::RgbaImage>,
+}
+
+impl ImportedModel {
+    // For models whose texture has been packed into an atlas.
+    pub fn remap_uvs(&mut self, region: AtlasRegion) {
+        for vertex in &mut self.vertices {
+            vertex.uv = region.remap(vertex.uv);
+        }
+    }
+
+    // Just the vertices, without the joints.
+    pub fn static_vertices(&self) -> Vec<Vertex> {
+        self.vertices
+            .iter()
+            .map(|vertex| Vertex {
+                position: vertex.position,
+                normal: vertex.normal,
+                uv: vertex.uv,
+                tangent: vertex.tangent,
+            })
+            .collect()
+    }
+}
+
+pub fn import(gltf_bytes: &[u8]) -> anyhow::Result<ImportedModel> {
+    let gltf = gltf::Gltf::from_slice(gltf_bytes)?;
+    let buffers = load_buffers(&gltf)?;
+
+    let scene = gltf
+        .default_scene()
+        .or_else(|| gltf.scenes().next())
+        .ok_or_else(|| anyhow::anyhow!("There's no scene"))?;
+
+    let mut model = ImportedModel {
+        vertices: Vec::new(),
+        indices: Vec::new(),
+        primitives: Vec::new(),
+        materials: gltf
+            .materials()
+            .map(|material| load_material(&material, &buffers))
+            .collect::<anyhow::Result<_>>()?,
+        animation_info: gltf.skins().next().map(|skin| AnimationInfo {
+            skin: crate::animation::Skin::load(&skin, gltf.nodes(), &scene, &buffers),
+            animations: crate::animation::load_animations(gltf.animations(), &buffers),
+        }),
+    };
+
+    for node in scene.nodes() {
+        add_node(&mut model, &node, Mat4::identity(), &buffers)?;
+    }
+
+    Ok(model)
+}
+
+// Just the materials, without all the vertices and animations.
+pub fn import_materials(gltf_bytes: &[u8]) -> anyhow::Result<Vec<ImportedMaterial>> {
+    let gltf = gltf::Gltf::from_slice(gltf_bytes)?;
+    let buffers = load_buffers(&gltf)?;
+
+    gltf.materials()
+        .map(|material| load_material(&material, &buffers))
+        .collect()
+}
+
+fn add_node(
+    model: &mut ImportedModel,
+    node: &gltf::Node,
+    parent_transform: Mat4,
+    buffers: &[Vec<u8>],
+) -> anyhow::Result<()> {
+    let transform = parent_transform * Mat4::from(node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        // Skinned meshes get moved around by their joints instead.
+        let transform = match node.skin() {
+            Some(_) => Mat4::identity(),
+            None => transform,
+        };
+
+        for primitive in mesh.primitives() {
+            add_primitive(model, &primitive, transform, buffers)?;
+        }
+    }
+
+    for child in node.children() {
+        add_node(model, &child, transform, buffers)?;
+    }
+
+    Ok(())
+}
+
+fn add_primitive(
+    model: &mut ImportedModel,
+    primitive: &gltf::Primitive,
+    transform: Mat4,
+    buffers: &[Vec<u8>],
+) -> anyhow::Result<()> {
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        return Err(anyhow::anyhow!(
+            "Primitives with {:?} are not allowed. Triangles only.",
+            primitive.mode()
+        ));
+    }
+
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+    let positions = reader
+        .read_positions()
+        .ok_or_else(|| anyhow::anyhow!("A primitive is missing its positions"))?;
+    let normals = reader
+        .read_normals()
+        .ok_or_else(|| anyhow::anyhow!("A primitive is missing its normals"))?;
+    let mut tex_coordinates = reader.read_tex_coords(0).map(|uvs| uvs.into_f32());
+    let mut joints = reader.read_joints(0).map(|joints| joints.into_u16());
+    let mut weights = reader.read_weights(0).map(|weights| weights.into_f32());
+    let mut tangents = reader.read_tangents();
+
+    let normal_transform = Mat3::new(
+        transform.cols[0].truncated(),
+        transform.cols[1].truncated(),
+        transform.cols[2].truncated(),
+    )
+    .inversed()
+    .transposed();
+
+    let num_vertices = model.vertices.len() as u32;
+
+    for (position, normal) in positions.zip(normals) {
+        let uv = tex_coordinates
+            .as_mut()
+            .and_then(|uvs| uvs.next())
+            .unwrap_or_default();
+        let joints = joints
+            .as_mut()
+            .and_then(|joints| joints.next())
+            .unwrap_or_default();
+        let weights = weights
+            .as_mut()
+            .and_then(|weights| weights.next())
+            .unwrap_or_default();
+        // Filled in below if the file doesn't have them.
+        let tangent = tangents
+            .as_mut()
+            .and_then(|tangents| tangents.next())
+            .map(|[x, y, z, w]| {
+                let tangent = transform.transform_vec3(Vec3::new(x, y, z)).normalized();
+                Vec4::new(tangent.x, tangent.y, tangent.z, w)
+            })
+            .unwrap_or_else(Vec4::zero);
+
+        model.vertices.push(AnimatedVertex {
+            position: transform.transform_point3(position.into()),
+            normal: (normal_transform * Vec3::from(normal)).normalized(),
+            uv: uv.into(),
+            joints: Vec4::new(
+                joints[0] as f32,
+                joints[1] as f32,
+                joints[2] as f32,
+                joints[3] as f32,
+            ),
+            joint_weights: weights.into(),
+            tangent,
+        });
+    }
+
+    let start = model.indices.len() as u32;
+
+    match reader.read_indices() {
+        Some(indices) => model
+            .indices
+            .extend(indices.into_u32().map(|i| i + num_vertices)),
+        // Without indices, every three vertices are a triangle.
+        None => model
+            .indices
+            .extend(num_vertices..model.vertices.len() as u32),
+    }
+
+    if reader.read_tangents().is_none() {
+        let vertices = &mut model.vertices[num_vertices as usize..];
+        let indices: Vec<u32> = model.indices[start as usize..]
+            .iter()
+            .map(|i| i - num_vertices)
+            .collect();
+
+        let tangents = generate_tangents(
+            &vertices.iter().map(|v| v.position).collect::<Vec<_>>(),
+            &vertices.iter().map(|v| v.normal).collect::<Vec<_>>(),
+            &vertices.iter().map(|v| v.uv).collect::<Vec<_>>(),
+            &indices,
+        );
+
+        for (vertex, tangent) in vertices.iter_mut().zip(tangents) {
+            vertex.tangent = tangent;
+        }
+    }
+
+    model.primitives.push(ImportedPrimitive {
+        indices: start..model.indices.len() as u32,
+        material: primitive.material().index(),
+    });
+
+    Ok(())
+}
+
+fn load_material(
+    material: &gltf::Material,
+    buffers: &[Vec<u8>],
+) -> anyhow::Result<ImportedMaterial> {
+    let pbr = material.pbr_metallic_roughness();
+
+    let texture = pbr
+        .base_color_texture()
+        .or_else(|| material.emissive_texture())
+        .map(|info| load_image(&info.texture().source(), buffers))
+        .transpose()?;
+
+    let normal_map = material
+        .normal_texture()
+        .map(|normal| load_image(&normal.texture().source(), buffers))
+        .transpose()?;
+
+    let emissive = match pbr.base_color_texture() {
+        Some(_) => material
+            .emissive_texture()
+            .map(|info| load_image(&info.texture().source(), buffers))
+            .transpose()?,
+        None => None,
+    };
+
+    Ok(ImportedMaterial {
+        name: material.name().map(|name| name.to_string()),
+        colour: pbr.base_color_factor().into(),
+        texture,
+        normal_map,
+        emissive,
+    })
+}
+
+fn load_image(image: &gltf::Image, buffers: &[Vec<u8>]) -> anyhow::Result<image::RgbaImage> {
+    const PNG_URI: &str = "data:image/png;base64,";
+
+    let bytes = match image.source() {
+        gltf::image::Source::View { view, mime_type } => {
+            if mime_type != "image/png" {
+                return Err(anyhow::anyhow!("Only png textures are supported"));
+            }
+
+            let buffer = &buffers[view.buffer().index()];
+            buffer[view.offset()..view.offset() + view.length()].to_vec()
+        }
+        gltf::image::Source::Uri { uri, .. } => {
+            if uri.starts_with(PNG_URI) {
+                base64::decode(&uri[PNG_URI.len()..])?
+            } else {
+                return Err(anyhow::anyhow!(
+                    "Textures need to be embedded in the file as pngs"
+                ));
+            }
+        }
+    };
+
+    super::decode_png(&bytes)
+}
+
+#[test]
+fn models_are_imported_with_their_materials() {
+    let pump = import(include_bytes!("../../models/pump.gltf")).unwrap();
+
+    assert!(pump.animation_info.is_some());
+    assert_eq!(pump.primitives.len(), 2);
+    assert_eq!(pump.materials.len(), 2);
+    assert!(pump.materials[1].texture.is_some());
+    assert_eq!(
+        pump.primitives.last().unwrap().indices.end,
+        pump.indices.len() as u32
+    );
+
+    let torus = import(include_bytes!("../../models/torus.gltf")).unwrap();
+
+    assert!(torus.animation_info.is_none());
+    assert!(torus.materials.is_empty());
+    assert!(torus
+        .vertices
+        .iter()
+        .all(|vertex| vertex.joint_weights == Vec4::zero()));
+}
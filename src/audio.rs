@@ -0,0 +1,393 @@
+#[cfg(feature = "audio")]
+mod synth;
+
+use crate::camera::Camera;
+use crate::ecs::Unit;
+use crate::resources::Settings;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::time::Instant;
+use ultraviolet::Vec2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sound {
+    Gunshot,
+    Acknowledgement,
+    Squeak,
+    Placement,
+    // The player's things are being attacked somewhere they can't see.
+    Alert,
+}
+
+// What a unit can say. Each kind of unit has a few variations of each line, see `synth`. They're
+// ordered by priority, so a unit reporting that it's dying can talk over one that's just been
+// selected, but not the other way around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VoiceLine {
+    Select,
+    Move,
+    Attack,
+    Death,
+}
+
+impl VoiceLine {
+    // In seconds.
+    pub fn length(self) -> f32 {
+        match self {
+            Self::Select => 0.25,
+            Self::Move | Self::Attack => 0.35,
+            Self::Death => 0.6,
+        }
+    }
+}
+
+// How many different takes there are of each line.
+const VOICE_VARIATIONS: usize = 3;
+
+// Systems push the sounds they want to make in here and they all get played (or just thrown away
+// if the game was built without the `audio` feature) at the end of the frame.
+#[derive(Default)]
+pub struct SoundEvents {
+    sounds: Vec<(Sound, Option<Vec2>)>,
+    // Only one unit talks at a time, so only the most important thing said in a frame is kept.
+    voice: Option<(Unit, VoiceLine)>,
+}
+
+impl SoundEvents {
+    // For sounds that don't come from anywhere in particular, like the HUD.
+    pub fn play(&mut self, sound: Sound) {
+        self.sounds.push((sound, None));
+    }
+
+    pub fn play_at(&mut self, sound: Sound, position: Vec2) {
+        self.sounds.push((sound, Some(position)));
+    }
+
+    // Voices aren't positioned, as they're the units talking to the player over the radio.
+    pub fn say(&mut self, unit: Unit, line: VoiceLine) {
+        let more_important = match self.voice {
+            Some((_, current)) => line > current,
+            None => true,
+        };
+
+        if more_important {
+            self.voice = Some((unit, line));
+        }
+    }
+
+    // For when nothing is listening, like in a headless `Simulation`.
+    pub fn clear(&mut self) {
+        self.sounds.clear();
+        self.voice = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Speech {
+    unit: Unit,
+    line: VoiceLine,
+    variation: usize,
+}
+
+// Selecting the same units over and over again gets a reply this many times before they go quiet,
+// until the player leaves them alone for a bit.
+const MAX_SELECT_STREAK: u32 = 3;
+const SELECT_STREAK_GAP: f32 = 1.0;
+
+// Decides which of the lines that units want to say actually get said, so that they don't talk
+// over each other and don't get annoying.
+struct Voices {
+    rng: SmallRng,
+    // The line that's being said and when it'll finish.
+    speaking: Option<(VoiceLine, f32)>,
+    last_select: f32,
+    select_streak: u32,
+    // So the same take isn't played twice in a row.
+    last_speech: Option<Speech>,
+}
+
+impl Voices {
+    fn new(seed: u64) -> Self {
+        Self {
+            rng: SmallRng::seed_from_u64(seed),
+            speaking: None,
+            last_select: f32::NEG_INFINITY,
+            select_streak: 0,
+            last_speech: None,
+        }
+    }
+
+    // `now` is in seconds, from whenever.
+    fn choose(&mut self, unit: Unit, line: VoiceLine, now: f32) -> Option<Speech> {
+        if line == VoiceLine::Select {
+            let quick = now - self.last_select < SELECT_STREAK_GAP;
+            self.last_select = now;
+            self.select_streak = if quick { self.select_streak + 1 } else { 1 };
+
+            if self.select_streak > MAX_SELECT_STREAK {
+                return None;
+            }
+        }
+
+        // Only something more important can interrupt a line.
+        if let Some((speaking, until)) = self.speaking {
+            if now < until && line <= speaking {
+                return None;
+            }
+        }
+
+        let mut variation = self.rng.gen_range(0, VOICE_VARIATIONS);
+        if let Some(last) = self.last_speech {
+            if last.unit == unit && last.line == line && last.variation == variation {
+                variation = (variation + 1) % VOICE_VARIATIONS;
+            }
+        }
+
+        let speech = Speech {
+            unit,
+            line,
+            variation,
+        };
+        self.speaking = Some((line, now + line.length()));
+        self.last_speech = Some(speech);
+        Some(speech)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Audible {
+    sound: Sound,
+    volume: f32,
+    // -1.0 is fully to the left and 1.0 is fully to the right.
+    pan: f32,
+}
+
+// Sounds at this distance from the camera (or closer) play at full volume.
+const REFERENCE_DISTANCE: f32 = 20.0;
+// Below this it's not worth playing the sound at all.
+const MIN_VOLUME: f32 = 0.05;
+// A big fight can easily have dozens of units firing on the same frame, which just sounds like
+// noise, so only the loudest few of each sound get played.
+const MAX_PER_SOUND: usize = 4;
+
+fn drain_audible(events: &mut SoundEvents, camera: &Camera) -> Vec<Audible> {
+    let mut audible: Vec<Audible> = events
+        .sounds
+        .drain(..)
+        .filter_map(|(sound, position)| {
+            let (volume, pan) = match position {
+                Some(position) => {
+                    let offset = position - camera.looking_at;
+                    // The camera hovers above the point it's looking at, so even sounds right in
+                    // the middle of the screen are quieter when zoomed out.
+                    let distance = (offset.mag_sq() + camera.distance * camera.distance).sqrt();
+                    let volume = (REFERENCE_DISTANCE / distance).min(1.0);
+                    let pan = (offset.dot(camera.right()) / distance).clamp(-1.0, 1.0);
+                    (volume, pan)
+                }
+                None => (1.0, 0.0),
+            };
+
+            if volume < MIN_VOLUME {
+                None
+            } else {
+                Some(Audible { sound, volume, pan })
+            }
+        })
+        .collect();
+
+    audible.sort_by(|a, b| b.volume.partial_cmp(&a.volume).unwrap());
+
+    let mut counts = [0; 5];
+    audible.retain(|audible| {
+        let count = &mut counts[audible.sound as usize];
+        *count += 1;
+        *count <= MAX_PER_SOUND
+    });
+
+    audible
+}
+
+pub struct Audio {
+    #[cfg(feature = "audio")]
+    output: Option<synth::Output>,
+    voices: Voices,
+    start: Instant,
+}
+
+impl Audio {
+    #[cfg(feature = "audio")]
+    pub fn new(settings: &Settings) -> Self {
+        let output = synth::Output::new(settings);
+
+        if output.is_none() {
+            log::warn!("No audio output device found. The game will be silent.");
+        }
+
+        Self {
+            output,
+            voices: Voices::new(voice_seed()),
+            start: Instant::now(),
+        }
+    }
+
+    #[cfg(not(feature = "audio"))]
+    pub fn new(_settings: &Settings) -> Self {
+        Self {
+            voices: Voices::new(voice_seed()),
+            start: Instant::now(),
+        }
+    }
+
+    #[cfg(feature = "audio")]
+    pub fn set_volumes(&mut self, settings: &Settings) {
+        if let Some(output) = &mut self.output {
+            output.set_volumes(settings);
+        }
+    }
+
+    #[cfg(not(feature = "audio"))]
+    pub fn set_volumes(&mut self, _settings: &Settings) {}
+
+    pub fn play(&mut self, events: &mut SoundEvents, camera: &Camera) {
+        let audible = drain_audible(events, camera);
+
+        let now = self.start.elapsed().as_secs_f32();
+        let voices = &mut self.voices;
+        let speech = events
+            .voice
+            .take()
+            .and_then(|(unit, line)| voices.choose(unit, line, now));
+
+        #[cfg(feature = "audio")]
+        {
+            if let Some(output) = &mut self.output {
+                for audible in audible {
+                    output.play(audible.sound, audible.volume, audible.pan);
+                }
+
+                if let Some(speech) = speech {
+                    output.speak(speech);
+                }
+            }
+        }
+
+        #[cfg(not(feature = "audio"))]
+        drop((audible, speech));
+    }
+}
+
+// The variations don't need to be unpredictable, just different each time the game is played.
+fn voice_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64)
+}
+
+#[test]
+fn sounds_are_attenuated_and_panned() {
+    let camera = Camera::new(Vec2::zero(), 10.0);
+    let mut events = SoundEvents::default();
+
+    events.play(Sound::Acknowledgement);
+    events.play_at(Sound::Squeak, Vec2::new(10.0, 0.0));
+    events.play_at(Sound::Placement, Vec2::new(-40.0, 0.0));
+    events.play_at(Sound::Placement, Vec2::new(1000.0, 0.0));
+    for _ in 0..10 {
+        events.play_at(Sound::Gunshot, Vec2::zero());
+    }
+
+    let audible = drain_audible(&mut events, &camera);
+    assert!(events.sounds.is_empty());
+
+    let find = |sound| {
+        audible
+            .iter()
+            .find(|audible| audible.sound == sound)
+            .unwrap()
+    };
+
+    assert_eq!(
+        find(Sound::Acknowledgement),
+        &Audible {
+            sound: Sound::Acknowledgement,
+            volume: 1.0,
+            pan: 0.0
+        }
+    );
+
+    let squeak = find(Sound::Squeak);
+    assert_eq!(squeak.volume, 1.0);
+    assert!(squeak.pan > 0.5);
+
+    // The far away placement is dropped entirely.
+    let placements: Vec<_> = audible
+        .iter()
+        .filter(|audible| audible.sound == Sound::Placement)
+        .collect();
+    assert_eq!(placements.len(), 1);
+    assert!(placements[0].volume < 0.5 && placements[0].pan < -0.5);
+
+    let gunshots = audible
+        .iter()
+        .filter(|audible| audible.sound == Sound::Gunshot)
+        .count();
+    assert_eq!(gunshots, MAX_PER_SOUND);
+}
+
+#[test]
+fn voices_take_turns_by_priority() {
+    let mut voices = Voices::new(0);
+
+    assert!(voices
+        .choose(Unit::Engineer, VoiceLine::Move, 0.0)
+        .is_some());
+    // Still acknowledging the first order.
+    assert!(voices
+        .choose(Unit::Engineer, VoiceLine::Move, 0.1)
+        .is_none());
+    assert!(voices.choose(Unit::Medic, VoiceLine::Select, 0.2).is_none());
+    // But dying is more important.
+    assert!(voices
+        .choose(Unit::MouseMarine, VoiceLine::Death, 0.2)
+        .is_some());
+    assert!(voices
+        .choose(Unit::Engineer, VoiceLine::Attack, 0.3)
+        .is_none());
+    assert!(voices
+        .choose(Unit::Engineer, VoiceLine::Attack, 1.0)
+        .is_some());
+
+    // The same take is never played twice in a row.
+    let mut last = None;
+    for i in 0..20 {
+        let speech = voices.choose(Unit::Medic, VoiceLine::Attack, 2.0 + i as f32);
+        assert!(speech.is_some());
+        assert_ne!(speech, last);
+        last = speech;
+    }
+}
+
+#[test]
+fn selecting_over_and_over_goes_quiet() {
+    let mut voices = Voices::new(0);
+
+    let replies = (0..10)
+        .filter(|i| {
+            voices
+                .choose(Unit::MouseMarine, VoiceLine::Select, *i as f32 * 0.5)
+                .is_some()
+        })
+        .count();
+    assert_eq!(replies, MAX_SELECT_STREAK as usize);
+
+    // Leaving them alone for a bit resets it.
+    assert!(voices
+        .choose(Unit::MouseMarine, VoiceLine::Select, 10.0)
+        .is_some());
+
+    // An order given on the same frame as a selection is what gets acknowledged.
+    let mut events = SoundEvents::default();
+    events.say(Unit::MouseMarine, VoiceLine::Move);
+    events.say(Unit::Engineer, VoiceLine::Select);
+    assert_eq!(events.voice, Some((Unit::MouseMarine, VoiceLine::Move)));
+}
@@ -0,0 +1,204 @@
+// There aren't any sound files in the assets yet, so all the sounds (and the music) are generated
+// when the game starts. They're tiny, so this is quick.
+
+use super::{Sound, Speech, VoiceLine, VOICE_VARIATIONS};
+use crate::ecs::Unit;
+use crate::resources::Settings;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rodio::buffer::SamplesBuffer;
+use rodio::{Device, Sink, Source};
+use std::collections::BTreeMap;
+use std::f32::consts::PI;
+use std::iter;
+
+const SAMPLE_RATE: u32 = 44100;
+
+pub struct Output {
+    device: Device,
+    // Mono samples for each `Sound`, in the same order as the enum.
+    sounds: [Vec<f32>; 5],
+    // Every take of every line, for each kind of unit.
+    voices: BTreeMap<(Unit, VoiceLine), Vec<Vec<f32>>>,
+    effects_volume: f32,
+    // Dropping the sink would stop the music.
+    music: Sink,
+    // The same goes for whatever's being said, which is how lines get interrupted.
+    voice: Option<Sink>,
+}
+
+impl Output {
+    pub fn new(settings: &Settings) -> Option<Self> {
+        let device = rodio::default_output_device()?;
+
+        let music = Sink::new(&device);
+        music.append(SamplesBuffer::new(1, SAMPLE_RATE, music_loop()).repeat_infinite());
+
+        let mut output = Self {
+            sounds: [gunshot(), acknowledgement(), squeak(), placement(), alert()],
+            voices: voice_banks(),
+            effects_volume: 1.0,
+            device,
+            music,
+            voice: None,
+        };
+        output.set_volumes(settings);
+        Some(output)
+    }
+
+    pub fn set_volumes(&mut self, settings: &Settings) {
+        self.effects_volume = settings.master_volume * settings.effects_volume;
+        self.music
+            .set_volume(settings.master_volume * settings.music_volume);
+    }
+
+    pub fn play(&self, sound: Sound, volume: f32, pan: f32) {
+        let volume = volume * self.effects_volume;
+        // Constant power panning, so sounds don't get louder as they move to the middle.
+        let angle = (pan + 1.0) * PI / 4.0;
+        let (left, right) = (angle.cos() * volume, angle.sin() * volume);
+
+        let samples = self.sounds[sound as usize]
+            .iter()
+            .flat_map(|&sample| iter::once(sample * left).chain(iter::once(sample * right)))
+            .collect::<Vec<_>>();
+
+        rodio::play_raw(&self.device, SamplesBuffer::new(2, SAMPLE_RATE, samples));
+    }
+
+    pub fn speak(&mut self, speech: Speech) {
+        let samples = self.voices[&(speech.unit, speech.line)][speech.variation].clone();
+
+        let sink = Sink::new(&self.device);
+        sink.set_volume(self.effects_volume);
+        sink.append(SamplesBuffer::new(1, SAMPLE_RATE, samples));
+        self.voice = Some(sink);
+    }
+}
+
+fn samples(seconds: f32, mut function: impl FnMut(f32) -> f32) -> Vec<f32> {
+    let count = (seconds * SAMPLE_RATE as f32) as usize;
+    (0..count)
+        .map(|i| function(i as f32 / SAMPLE_RATE as f32))
+        .collect()
+}
+
+fn sine(frequency: f32, time: f32) -> f32 {
+    (time * frequency * 2.0 * PI).sin()
+}
+
+// A sharp crack of decaying noise.
+fn gunshot() -> Vec<f32> {
+    let mut rng = SmallRng::seed_from_u64(0);
+    samples(0.15, |time| {
+        rng.gen_range(-1.0, 1.0) * (-time * 40.0).exp() * 0.6
+    })
+}
+
+// Two quick rising blips.
+fn acknowledgement() -> Vec<f32> {
+    samples(0.16, |time| {
+        let frequency = if time < 0.08 { 880.0 } else { 1320.0 };
+        let blip_time = time % 0.08;
+        sine(frequency, time) * (1.0 - blip_time / 0.08) * 0.3
+    })
+}
+
+// A mouse's dying squeak, sweeping upwards in pitch.
+fn squeak() -> Vec<f32> {
+    let length = 0.25;
+    samples(length, |time| {
+        let progress = time / length;
+        // Integrate the frequency sweep to get the phase, otherwise it'd sweep twice as far.
+        let phase = time * (1800.0 + 400.0 * progress);
+        (phase * 2.0 * PI).sin() * (progress * PI).sin() * 0.4
+    })
+}
+
+// A low thud for when a building is put down.
+fn placement() -> Vec<f32> {
+    samples(0.4, |time| sine(90.0, time) * (-time * 10.0).exp() * 0.8)
+}
+
+// A klaxon warbling between two notes.
+fn alert() -> Vec<f32> {
+    samples(0.8, |time| {
+        let frequency = if (time * 5.0) as i32 % 2 == 0 {
+            660.0
+        } else {
+            520.0
+        };
+        let fade = (1.0 - time / 0.8).min(time * 20.0).min(1.0);
+        // A square wave so it cuts through the gunfire.
+        sine(frequency, time).signum() * fade * 0.15
+    })
+}
+
+fn voice_banks() -> BTreeMap<(Unit, VoiceLine), Vec<Vec<f32>>> {
+    let mut rng = SmallRng::seed_from_u64(1);
+    let mut banks = BTreeMap::new();
+
+    for &unit in &[Unit::MouseMarine, Unit::Engineer, Unit::Medic, Unit::Glider] {
+        for &line in &[
+            VoiceLine::Select,
+            VoiceLine::Move,
+            VoiceLine::Attack,
+            VoiceLine::Death,
+        ] {
+            let takes = (0..VOICE_VARIATIONS)
+                .map(|_| voice_line(unit, line, rng.gen_range(0.9, 1.1)))
+                .collect();
+            banks.insert((unit, line), takes);
+        }
+    }
+
+    banks
+}
+
+// Mice chattering, as a wobbly tone (with an overtone to make it a bit nasal) following a pitch
+// contour for each line. Each kind of unit has its own pitch and each take is pitched a little
+// differently.
+fn voice_line(unit: Unit, line: VoiceLine, variation: f32) -> Vec<f32> {
+    let pitch = variation
+        * match unit {
+            Unit::MouseMarine => 520.0,
+            Unit::Engineer => 700.0,
+            Unit::Medic => 900.0,
+            Unit::Glider => 620.0,
+        };
+    let length = line.length();
+    // How many syllables and how the pitch moves across the whole line.
+    let (syllables, contour): (f32, fn(f32) -> f32) = match line {
+        // A questioning "yes?".
+        VoiceLine::Select => (1.0, |progress| 1.0 + progress * 0.5),
+        // A falling "on it".
+        VoiceLine::Move => (2.0, |progress| 1.2 - progress * 0.3),
+        // A quick, urgent "go go go".
+        VoiceLine::Attack => (3.0, |_| 1.3),
+        // A long falling wail.
+        VoiceLine::Death => (1.0, |progress| 1.5 - progress),
+    };
+
+    let mut phase = 0.0;
+    samples(length, |time| {
+        let progress = time / length;
+        let vibrato = 1.0 + sine(7.0 * variation, time) * 0.03;
+        // Integrate the frequency to get the phase, as it keeps changing.
+        phase += pitch * contour(progress) * vibrato / SAMPLE_RATE as f32;
+        let syllable = (progress * syllables * PI).sin().abs();
+        let tone = (phase * 2.0 * PI).sin() + (phase * 4.0 * PI).sin() * 0.3;
+        tone * syllable * 0.25
+    })
+}
+
+// A gentle arpeggio that loops forever.
+fn music_loop() -> Vec<f32> {
+    const NOTES: [f32; 8] = [220.0, 261.63, 329.63, 392.0, 440.0, 392.0, 329.63, 261.63];
+    const NOTE_LENGTH: f32 = 0.3;
+
+    samples(NOTES.len() as f32 * NOTE_LENGTH, |time| {
+        let note = (time / NOTE_LENGTH) as usize % NOTES.len();
+        let note_time = time % NOTE_LENGTH;
+        let envelope = (-note_time * 4.0).exp();
+        (sine(NOTES[note], time) + sine(NOTES[note] * 0.5, time) * 0.5) * envelope * 0.2
+    })
+}
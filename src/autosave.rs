@@ -0,0 +1,109 @@
+// Every few minutes (`autosave_interval` in `settings.toml`) the game gets saved to one of a few
+// autosave files in turn, so that there's still an older one to go back to if the newest is no
+// good. The snapshot of the world has to be taken on the main thread, but writing it out happens on
+// another one so that the game doesn't hitch. If the game crashes, the last snapshot gets written
+// to `crash_save.json` on the way down.
+
+use crate::ecs::{self, SaveSnapshot};
+use crate::resources::Settings;
+use legion::{Resources, World};
+use once_cell::sync::Lazy;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+pub const CRASH_SAVE_PATH: &str = "crash_save.json";
+
+// How often (in game time) a snapshot is taken for the crash save, in between autosaves.
+const SNAPSHOT_INTERVAL: f32 = 30.0;
+
+// The panic hook can't get at the world (which might be half way through being updated when it
+// runs), so it writes out whatever was last put in here instead.
+static LATEST_SNAPSHOT: Lazy<Mutex<Option<Arc<SaveSnapshot>>>> = Lazy::new(Default::default);
+
+pub fn autosave_path(slot: u32) -> PathBuf {
+    PathBuf::from(format!("autosave_{}.json", slot))
+}
+
+#[derive(Default)]
+pub struct Autosaver {
+    // In game time, so that nothing gets saved while the game is paused.
+    last_snapshot: f32,
+    last_autosave: f32,
+    next_slot: u32,
+    writing: Option<JoinHandle<()>>,
+}
+
+impl Autosaver {
+    // Called whenever a game is started or loaded, with its `TotalTime`.
+    pub fn reset(&mut self, total_time: f32) {
+        self.last_snapshot = total_time;
+        self.last_autosave = total_time;
+    }
+
+    // Called once a frame while playing.
+    pub fn update(
+        &mut self,
+        world: &World,
+        resources: &Resources,
+        total_time: f32,
+        settings: &Settings,
+    ) {
+        // If the last autosave is still being written then the disk must be struggling, so this one
+        // can wait.
+        let still_writing = self
+            .writing
+            .as_ref()
+            .map(|writing| !writing.is_finished())
+            .unwrap_or(false);
+
+        let autosave_due = settings.autosave_interval > 0.0
+            && settings.autosave_slots > 0
+            && total_time - self.last_autosave >= settings.autosave_interval * 60.0
+            && !still_writing;
+
+        if !autosave_due && total_time - self.last_snapshot < SNAPSHOT_INTERVAL {
+            return;
+        }
+
+        let snapshot = Arc::new(ecs::snapshot_game(world, resources));
+        self.last_snapshot = total_time;
+        *LATEST_SNAPSHOT.lock().unwrap() = Some(snapshot.clone());
+
+        if !autosave_due {
+            return;
+        }
+
+        self.last_autosave = total_time;
+        let path = autosave_path(self.next_slot % settings.autosave_slots);
+        self.next_slot = (self.next_slot + 1) % settings.autosave_slots;
+
+        self.writing = Some(std::thread::spawn(move || {
+            if let Err(error) = snapshot.write(&path) {
+                log::error!("Failed to autosave to {}: {}", path.display(), error);
+            }
+        }));
+    }
+}
+
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+
+        // If the panic happened while the snapshot was being swapped out, trying to lock it again
+        // would just deadlock.
+        let latest = match LATEST_SNAPSHOT.try_lock() {
+            Ok(latest) => latest,
+            Err(_) => return,
+        };
+
+        if let Some(snapshot) = latest.as_ref() {
+            match snapshot.write(Path::new(CRASH_SAVE_PATH)) {
+                Ok(()) => eprintln!("Wrote an emergency save to {}", CRASH_SAVE_PATH),
+                Err(error) => eprintln!("Failed to write {}: {}", CRASH_SAVE_PATH, error),
+            }
+        }
+    }));
+}
@@ -0,0 +1,228 @@
+// Run with `--bench [units] [ticks] [csv path]` to have two armies of mouse marines (1000 by
+// default) attack-move into each other for a fixed number of ticks (2000 by default), then print
+// out how long the frames took and write every frame's timings to a csv file. Each frame runs one
+// tick of `net::TICK_LENGTH`, no matter how long it took, so runs can be compared with each other.
+// Add `--headless` anywhere after `--bench` to time just the simulation, without a window.
+
+use crate::pathfinding::Map;
+use crate::profiler::Profiler;
+use crate::scenarios;
+use crate::simulation::Simulation;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const DEFAULT_UNITS: u32 = 1000;
+const DEFAULT_TICKS: u32 = 2000;
+const DEFAULT_CSV_PATH: &str = "bench.csv";
+
+pub struct BenchOptions {
+    // In total, split between the two sides.
+    pub units: u32,
+    pub ticks: u32,
+    pub csv_path: PathBuf,
+    pub headless: bool,
+}
+
+pub fn options() -> Option<BenchOptions> {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.get(1).map(|arg| arg.as_str()) != Some("--bench") {
+        return None;
+    }
+
+    let headless = args.iter().any(|arg| arg == "--headless");
+    let args: Vec<&String> = args.iter().filter(|arg| *arg != "--headless").collect();
+
+    Some(BenchOptions {
+        units: args
+            .get(2)
+            .and_then(|arg| arg.parse().ok())
+            .unwrap_or(DEFAULT_UNITS),
+        ticks: args
+            .get(3)
+            .and_then(|arg| arg.parse().ok())
+            .unwrap_or(DEFAULT_TICKS),
+        csv_path: args
+            .get(4)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| DEFAULT_CSV_PATH.into()),
+        headless,
+    })
+}
+
+// One row of the csv.
+struct Frame {
+    frame_time: f32,
+    path_queries: usize,
+    // In the same order as `Bench::sections`.
+    sections: Vec<f32>,
+}
+
+pub struct Bench {
+    pub options: BenchOptions,
+    frames: Vec<Frame>,
+    // The names of the profiler sections, in the order they were first seen.
+    sections: Vec<String>,
+}
+
+impl Bench {
+    pub fn new(options: BenchOptions) -> Self {
+        Self {
+            options,
+            frames: Vec::new(),
+            sections: Vec::new(),
+        }
+    }
+
+    // Returns true once enough ticks have been run.
+    pub fn record_frame(
+        &mut self,
+        frame_time: Duration,
+        profiler: &Profiler,
+        path_queries: usize,
+    ) -> bool {
+        let mut sections = vec![0.0; self.sections.len()];
+
+        for (name, ms) in profiler.latest_timings() {
+            match self.sections.iter().position(|section| *section == name) {
+                Some(index) => sections[index] = ms,
+                None => {
+                    self.sections.push(name);
+                    sections.push(ms);
+                }
+            }
+        }
+
+        self.frames.push(Frame {
+            frame_time: frame_time.as_secs_f32() * 1000.0,
+            path_queries,
+            sections,
+        });
+
+        self.frames.len() >= self.options.ticks as usize
+    }
+
+    pub fn report(&self) -> String {
+        let mut frame_times: Vec<f32> = self.frames.iter().map(|frame| frame.frame_time).collect();
+        frame_times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let path_queries: usize = self.frames.iter().map(|frame| frame.path_queries).sum();
+
+        let mut report = format!(
+            "{} units, {} ticks\nFrame times: p50 {:.2} ms, p90 {:.2} ms, p99 {:.2} ms, max {:.2} ms\nPathfinding queries: {}\n",
+            self.options.units,
+            self.frames.len(),
+            percentile(&frame_times, 0.5),
+            percentile(&frame_times, 0.9),
+            percentile(&frame_times, 0.99),
+            frame_times.last().copied().unwrap_or(0.0),
+            path_queries,
+        );
+
+        report.push_str("Average timings:\n");
+        for (index, section) in self.sections.iter().enumerate() {
+            let total: f32 = self
+                .frames
+                .iter()
+                .filter_map(|frame| frame.sections.get(index))
+                .sum();
+            let _ = writeln!(
+                report,
+                "  {}: {:.2} ms",
+                section,
+                total / self.frames.len().max(1) as f32
+            );
+        }
+
+        report
+    }
+
+    pub fn write_csv(&self) -> anyhow::Result<()> {
+        let mut csv = String::from("frame,frame_time_ms,path_queries");
+        for section in &self.sections {
+            let _ = write!(csv, ",{} (ms)", section);
+        }
+        csv.push('\n');
+
+        for (index, frame) in self.frames.iter().enumerate() {
+            let _ = write!(csv, "{},{},{}", index, frame.frame_time, frame.path_queries);
+            // Sections that were first seen part way through are left empty before then.
+            for section in 0..self.sections.len() {
+                match frame.sections.get(section) {
+                    Some(ms) => {
+                        let _ = write!(csv, ",{}", ms);
+                    }
+                    None => csv.push(','),
+                }
+            }
+            csv.push('\n');
+        }
+
+        std::fs::write(&self.options.csv_path, csv)?;
+        Ok(())
+    }
+
+    // Prints out the report and writes the csv.
+    pub fn finish(&self) {
+        println!("{}", self.report());
+        match self.write_csv() {
+            Ok(()) => println!("Wrote {}", self.options.csv_path.display()),
+            Err(error) => log::error!(
+                "Failed to write {}: {}",
+                self.options.csv_path.display(),
+                error
+            ),
+        }
+    }
+}
+
+// The frame times are just how long each tick took to run here, as there's nothing to render.
+pub fn run_headless(mut bench: Bench) -> anyhow::Result<()> {
+    let mut simulation = Simulation::new(0)?;
+    simulation.start_scenario(scenarios::BENCH_SCENARIO, bench.options.units);
+
+    loop {
+        let start = Instant::now();
+        simulation.tick();
+        let frame_time = start.elapsed();
+
+        let finished = bench.record_frame(
+            frame_time,
+            &simulation.resources.get::<Profiler>().unwrap(),
+            simulation
+                .resources
+                .get::<Map>()
+                .unwrap()
+                .take_query_count(),
+        );
+
+        if finished {
+            break;
+        }
+    }
+
+    bench.finish();
+    Ok(())
+}
+
+// `sorted` needs to be in ascending order.
+fn percentile(sorted: &[f32], fraction: f32) -> f32 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let index = ((sorted.len() - 1) as f32 * fraction).round() as usize;
+    sorted[index]
+}
+
+#[test]
+fn percentiles_pick_from_sorted_frame_times() {
+    let frame_times: Vec<f32> = (1..=100).map(|ms| ms as f32).collect();
+
+    assert_eq!(percentile(&frame_times, 0.0), 1.0);
+    assert_eq!(percentile(&frame_times, 0.5), 51.0);
+    assert_eq!(percentile(&frame_times, 0.99), 99.0);
+    assert_eq!(percentile(&frame_times, 1.0), 100.0);
+    assert_eq!(percentile(&[], 0.5), 0.0);
+}
@@ -0,0 +1,321 @@
+use crate::pathfinding::MAP_SIZE;
+use crate::resources::ScreenDimensions;
+use crate::terrain::Terrain;
+use ultraviolet::{Mat4, Vec2, Vec3, Vec4};
+
+#[derive(Default)]
+pub struct CameraControls {
+    pub up: bool,
+    pub down: bool,
+    pub left: bool,
+    pub right: bool,
+    pub rotate_left: bool,
+    pub rotate_right: bool,
+    pub zoom_delta: f32,
+    // Holding down the middle mouse button drags the view around. `drag_delta` is how far the mouse
+    // has moved (in pixels) while it's been held since the last frame.
+    pub middle_held: bool,
+    pub drag_delta: Vec2,
+}
+
+// The camera keeps a target for everything the player controls and eases towards it each frame,
+// so that scrolling, zooming and rotating are all smooth.
+pub struct Camera {
+    pub distance: f32,
+    pub looking_at: Vec2,
+    // The height of the ground being looked at, eased towards as the camera moves over hills.
+    pub height: f32,
+    // Rotation around the vertical axis, in radians.
+    pub rotation: f32,
+    target_distance: f32,
+    target_looking_at: Vec2,
+    target_rotation: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::new(Vec2::zero(), Self::ANGLE.mag())
+    }
+}
+
+impl Camera {
+    pub const ANGLE: Vec3 = Vec3::new(0.0, 20.0, 10.0);
+    pub const MIN_DISTANCE: f32 = 5.0;
+    pub const MAX_DISTANCE: f32 = 90.0;
+
+    const SMOOTHING: f32 = 12.0;
+
+    pub fn new(looking_at: Vec2, distance: f32) -> Self {
+        Self {
+            distance,
+            looking_at,
+            height: 0.0,
+            rotation: 0.0,
+            target_distance: distance,
+            target_looking_at: looking_at,
+            target_rotation: 0.0,
+        }
+    }
+
+    // The directions that are right and up on the screen, on the ground.
+    pub fn right(&self) -> Vec2 {
+        rotate(Vec2::unit_x(), self.rotation)
+    }
+
+    pub fn forwards(&self) -> Vec2 {
+        -rotate(Vec2::unit_y(), self.rotation)
+    }
+
+    pub fn pan(&mut self, offset: Vec2) {
+        self.target_looking_at += offset;
+    }
+
+    // Move the camera without easing, e.g. when dragging the view around so that the ground stays
+    // under the cursor.
+    pub fn shift(&mut self, offset: Vec2) {
+        self.looking_at += offset;
+        self.target_looking_at += offset;
+    }
+
+    pub fn jump_to(&mut self, point: Vec2) {
+        self.looking_at = point;
+        self.target_looking_at = point;
+    }
+
+    pub fn zoom(&mut self, amount: f32) {
+        self.target_distance =
+            (self.target_distance - amount).clamp(Self::MIN_DISTANCE, Self::MAX_DISTANCE);
+    }
+
+    pub fn zoom_out_fully(&mut self) {
+        self.target_distance = Self::MAX_DISTANCE;
+    }
+
+    pub fn rotate(&mut self, angle: f32) {
+        self.target_rotation += angle;
+    }
+
+    pub fn update(&mut self, delta_time: f32, terrain: &Terrain) {
+        let half_size = MAP_SIZE / 2.0;
+        self.target_looking_at = self
+            .target_looking_at
+            .clamped(Vec2::broadcast(-half_size), Vec2::broadcast(half_size));
+
+        let factor = (Self::SMOOTHING * delta_time).min(1.0);
+        self.looking_at += (self.target_looking_at - self.looking_at) * factor;
+        self.distance += (self.target_distance - self.distance) * factor;
+        self.rotation += (self.target_rotation - self.rotation) * factor;
+
+        // Ease towards the height of the ground instead of snapping to it, so that panning over
+        // bumpy terrain doesn't make the view jitter up and down.
+        let ground_height = terrain.height_at(self.looking_at);
+        self.height += (ground_height - self.height) * (10.0 * delta_time).min(1.0);
+    }
+
+    pub fn looking_at_3(&self) -> Vec3 {
+        Vec3::new(self.looking_at.x, self.height, self.looking_at.y)
+    }
+
+    pub fn position(&self) -> Vec3 {
+        let angle = Self::ANGLE.normalized();
+        let horizontal = rotate(Vec2::new(angle.x, angle.z), self.rotation);
+        self.looking_at_3() + Vec3::new(horizontal.x, angle.y, horizontal.y) * self.distance
+    }
+
+    pub fn to_matrix(&self) -> Mat4 {
+        Mat4::look_at(self.position(), self.looking_at_3(), Vec3::unit_y())
+    }
+
+    // Where the mouse is pointing on a flat plane at the height the camera is looking at. This is
+    // good enough for working out the edges of the screen.
+    pub fn cast_ray(&self, mouse_position: Vec2, screen_dimensions: &ScreenDimensions) -> Vec2 {
+        self.cast_ray_at_height(mouse_position, screen_dimensions, self.height)
+    }
+
+    // Where the mouse is pointing on the terrain itself. Starting from the plane at the camera's
+    // height, we keep moving the plane to the height of the ground where the ray last hit it, which
+    // converges quickly as long as the hills aren't too steep.
+    pub fn cast_ray_onto_terrain(
+        &self,
+        mouse_position: Vec2,
+        screen_dimensions: &ScreenDimensions,
+        terrain: &Terrain,
+    ) -> Vec2 {
+        let mut hit = self.cast_ray(mouse_position, screen_dimensions);
+
+        for _ in 0..4 {
+            hit =
+                self.cast_ray_at_height(mouse_position, screen_dimensions, terrain.height_at(hit));
+        }
+
+        hit
+    }
+
+    fn cast_ray_at_height(
+        &self,
+        mouse_position: Vec2,
+        screen_dimensions: &ScreenDimensions,
+        plane_height: f32,
+    ) -> Vec2 {
+        let &ScreenDimensions { width, height } = screen_dimensions;
+
+        let x = (mouse_position.x / width as f32 * 2.0) - 1.0;
+        let y = 1.0 - (mouse_position.y / height as f32 * 2.0);
+
+        let clip = Vec4::new(x, y, -1.0, 1.0);
+
+        let eye = crate::renderer::create_perspective_mat4(width, height).inversed() * clip;
+        let eye = Vec4::new(eye.x, eye.y, -1.0, 0.0);
+        let direction = (self.to_matrix().inversed() * eye).truncated().normalized() * 1.0;
+
+        let position = self.position();
+        let ray = ncollide3d::query::Ray::new(
+            ncollide3d::math::Point::new(position.x, position.y, position.z),
+            ncollide3d::math::Vector::new(direction.x, direction.y, direction.z),
+        );
+
+        let toi = ncollide3d::query::ray_toi_with_plane(
+            &ncollide3d::math::Point::new(0.0, plane_height, 0.0),
+            &ncollide3d::math::Vector::new(0.0, 1.0, 0.0),
+            &ray,
+        );
+
+        match toi {
+            Some(toi) => {
+                let contact = self.position() + direction * toi;
+                Vec2::new(contact.x, contact.z)
+            }
+            // The above ray cast can fail in odd cases such as where the window is minimized,
+            // So let's just return the point the camera is centered on.
+            None => self.looking_at,
+        }
+    }
+}
+
+// Explosions and big things dying near the camera shake it for a moment. The simulation just
+// records where each impact was (the same way as `audio::SoundEvents`), and how much it shakes the
+// camera is worked out each frame from how far away it was.
+#[derive(Default)]
+pub struct CameraShake {
+    impacts: Vec<(Vec2, f32)>,
+    // From 0 to 1. The camera shakes by the square of this, so small knocks barely register but big
+    // ones in a row build up.
+    trauma: f32,
+    time: f32,
+}
+
+impl CameraShake {
+    // Impacts at this distance from the camera (or closer) shake it at full strength.
+    const REFERENCE_DISTANCE: f32 = 20.0;
+    // How much trauma wears off each second.
+    const DECAY: f32 = 1.5;
+    const MAX_OFFSET: f32 = 0.5;
+    // In radians.
+    const MAX_ROLL: f32 = 0.03;
+
+    pub fn impact(&mut self, position: Vec2, strength: f32) {
+        self.impacts.push((position, strength));
+    }
+
+    // For when nothing is watching, like in a headless `Simulation`.
+    pub fn clear(&mut self) {
+        self.impacts.clear();
+    }
+
+    pub fn update(&mut self, camera: &Camera, delta_time: f32, enabled: bool) {
+        for (position, strength) in self.impacts.drain(..) {
+            // The same as how far away sounds are, see `audio::drain_audible`.
+            let offset = position - camera.looking_at;
+            let distance = (offset.mag_sq() + camera.distance * camera.distance).sqrt();
+            self.trauma += strength * (Self::REFERENCE_DISTANCE / distance).min(1.0);
+        }
+
+        self.trauma = if enabled {
+            (self.trauma.min(1.0) - Self::DECAY * delta_time).max(0.0)
+        } else {
+            0.0
+        };
+        self.time += delta_time;
+    }
+
+    // Nudges the view around on the screen. A few sine waves at unrelated frequencies are jumbled
+    // enough that it doesn't look like it's going back and forth.
+    pub fn apply(&self, view: Mat4) -> Mat4 {
+        let shake = self.trauma * self.trauma;
+
+        if shake == 0.0 {
+            return view;
+        }
+
+        let wobble = |frequency: f32, phase: f32| {
+            ((self.time * frequency + phase).sin() + (self.time * frequency * 2.3).sin()) / 2.0
+        };
+
+        let offset = Vec3::new(wobble(31.0, 0.0), wobble(37.0, 1.3), 0.0) * Self::MAX_OFFSET;
+        let roll = wobble(23.0, 2.9) * Self::MAX_ROLL;
+
+        Mat4::from_rotation_z(roll * shake) * Mat4::from_translation(offset * shake) * view
+    }
+}
+
+fn rotate(vector: Vec2, angle: f32) -> Vec2 {
+    let (sin, cos) = angle.sin_cos();
+    Vec2::new(
+        vector.x * cos - vector.y * sin,
+        vector.x * sin + vector.y * cos,
+    )
+}
+
+#[test]
+fn camera_eases_towards_clamped_targets() {
+    let terrain = Terrain::default();
+    let mut camera = Camera::new(Vec2::zero(), 20.0);
+
+    camera.zoom(1000.0);
+    camera.pan(Vec2::new(500.0, 0.0));
+    camera.rotate(1.0);
+
+    camera.update(1.0 / 60.0, &terrain);
+    assert!(camera.distance > Camera::MIN_DISTANCE && camera.distance < 20.0);
+    assert!(camera.rotation > 0.0 && camera.rotation < 1.0);
+
+    for _ in 0..120 {
+        camera.update(1.0 / 60.0, &terrain);
+    }
+
+    assert!((camera.distance - Camera::MIN_DISTANCE).abs() < 0.01);
+    assert!((camera.looking_at.x - MAP_SIZE / 2.0).abs() < 0.01);
+    assert!((camera.rotation - 1.0).abs() < 0.01);
+
+    // Panning forwards always moves towards the top of the screen, whichever way the camera faces.
+    let screen_dimensions = ScreenDimensions {
+        width: 1000,
+        height: 1000,
+    };
+    let centre = camera.cast_ray(Vec2::new(500.0, 500.0), &screen_dimensions);
+    let top = camera.cast_ray(Vec2::new(500.0, 0.0), &screen_dimensions);
+    assert!((top - centre).normalized().dot(camera.forwards()) > 0.99);
+}
+
+#[test]
+fn nearby_impacts_shake_the_camera_more() {
+    let camera = Camera::new(Vec2::zero(), 20.0);
+    let mut near = CameraShake::default();
+    let mut far = CameraShake::default();
+
+    near.impact(Vec2::new(5.0, 0.0), 0.5);
+    far.impact(Vec2::new(80.0, 0.0), 0.5);
+    near.update(&camera, 0.0, true);
+    far.update(&camera, 0.0, true);
+    assert!(near.trauma > far.trauma && far.trauma > 0.0);
+
+    // It wears off over time.
+    near.update(&camera, 10.0, true);
+    assert_eq!(near.trauma, 0.0);
+    assert_eq!(near.apply(camera.to_matrix()), camera.to_matrix());
+
+    // And doesn't happen at all with the setting off.
+    far.impact(Vec2::zero(), 1.0);
+    far.update(&camera, 0.0, false);
+    assert_eq!(far.trauma, 0.0);
+}
@@ -0,0 +1,162 @@
+// The settings live in `settings.toml` in the directory the game is run from. The file is checked
+// for changes every so often while the game is running, so it can be tweaked in a text editor
+// without having to restart.
+
+use crate::audio::Audio;
+use crate::renderer::RenderContext;
+use crate::resources::Settings;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+pub const SETTINGS_PATH: &str = "settings.toml";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn load(path: &Path) -> anyhow::Result<Settings> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(toml::from_str(&contents)?)
+}
+
+pub fn save(settings: &Settings, path: &Path) -> anyhow::Result<()> {
+    std::fs::write(path, toml::to_string(settings)?)?;
+    Ok(())
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_checked: Instant,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> (Self, Settings) {
+        let settings = if path.exists() {
+            load(path).unwrap_or_else(|error| {
+                log::error!("Failed to load {}: {}", path.display(), error);
+                Settings::default()
+            })
+        } else {
+            // Write out the defaults so that there's something to edit.
+            let settings = Settings::default();
+            if let Err(error) = save(&settings, path) {
+                log::warn!("Failed to write {}: {}", path.display(), error);
+            }
+            settings
+        };
+
+        let watcher = Self {
+            path: path.to_path_buf(),
+            modified: modified_time(path),
+            last_checked: Instant::now(),
+        };
+
+        (watcher, settings)
+    }
+
+    // Returns the new settings if the file has changed since we last looked at it.
+    pub fn poll(&mut self) -> Option<Settings> {
+        if self.last_checked.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.last_checked = Instant::now();
+
+        let modified = modified_time(&self.path);
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+
+        match load(&self.path) {
+            Ok(settings) => {
+                log::info!("Reloaded {}", self.path.display());
+                Some(settings)
+            }
+            // Editors often save files in a few steps, so this might just be a half-written file.
+            // Either way, it's better to keep going with the old settings.
+            Err(error) => {
+                log::error!("Failed to reload {}: {}", self.path.display(), error);
+                None
+            }
+        }
+    }
+
+    pub fn save(&mut self, settings: &Settings) {
+        if let Err(error) = save(settings, &self.path) {
+            log::warn!("Failed to write {}: {}", self.path.display(), error);
+        }
+        // Don't reload the file we just wrote.
+        self.modified = modified_time(&self.path);
+    }
+}
+
+// Most settings are read each frame from the `Settings` resource, but a few need to be pushed to
+// the window or the audio output when they change.
+pub fn apply(
+    old: &Settings,
+    new: &Settings,
+    render_context: &mut RenderContext,
+    audio: &mut Audio,
+) {
+    // The resolution is only the size of the window when it isn't fullscreen.
+    if new.fullscreen != old.fullscreen {
+        render_context.set_fullscreen(new.fullscreen);
+    }
+
+    if !new.fullscreen && (new.resolution != old.resolution || new.fullscreen != old.fullscreen) {
+        render_context.set_window_size(new.resolution[0], new.resolution[1]);
+    }
+
+    if new.vsync != old.vsync {
+        render_context.set_vsync(new.vsync);
+    }
+
+    // The main pass pipelines are recreated in `main` to match.
+    if new.msaa != old.msaa {
+        render_context.set_msaa(new.msaa);
+    }
+
+    if new.render_scale != old.render_scale {
+        render_context.set_render_scale(new.render_scale);
+    }
+
+    if new.master_volume != old.master_volume
+        || new.effects_volume != old.effects_volume
+        || new.music_volume != old.music_volume
+    {
+        audio.set_volumes(new);
+    }
+}
+
+#[test]
+fn settings_round_trip_and_missing_fields_use_defaults() {
+    use crate::input::Action;
+    use winit::event::VirtualKeyCode;
+
+    let settings = Settings::default();
+    let serialized = toml::to_string(&settings).unwrap();
+    assert_eq!(toml::from_str::<Settings>(&serialized).unwrap(), settings);
+
+    let partial: Settings = toml::from_str(
+        "camera_speed = 90.0\n\
+         [input_map]\n\
+         camera_up = [\"W\"]\n",
+    )
+    .unwrap();
+
+    assert_eq!(partial.camera_speed, 90.0);
+    assert_eq!(
+        partial.input_map.key(Action::CameraUp),
+        Some(VirtualKeyCode::W)
+    );
+    assert_eq!(
+        partial.input_map.key(Action::CameraDown),
+        Some(VirtualKeyCode::Down)
+    );
+    assert_eq!(partial.resolution, Settings::default().resolution);
+}
@@ -0,0 +1,178 @@
+// Tiny blades of grass and crumbs of cheese scattered all over the ground. There are tens of
+// thousands of them, so they're scattered once when the game starts (the terrain is the same in
+// every match) and drawn as camera-facing billboards in a single instanced draw, swaying in the
+// wind in the vertex shader. Only the `crumbs` feature draws them, see `renderer::CrumbPipeline`.
+#![cfg_attr(not(feature = "crumbs"), allow(dead_code))]
+
+use crate::pathfinding::MAP_SIZE;
+use crate::terrain::{smoothstep, Terrain};
+use crate::water::LAKES;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use ultraviolet::{Vec2, Vec3};
+
+const SEED: u64 = 0xC2B5;
+
+// One crumb is tried in each cell of a grid this size, jittered so that they don't line up.
+const SPACING: f32 = 0.8;
+
+// The ground is too steep for anything to settle on past this.
+const MIN_FLATNESS: f32 = 0.85;
+const FULL_FLATNESS: f32 = 0.95;
+
+// How far from the edge of a lake the grass starts.
+const SHORE_GAP: f32 = 2.0;
+
+// Roughly one in every four is a crumb of cheese instead of a blade of grass.
+const CRUMB_CHANCE: f32 = 0.25;
+
+// The texture is split into two square cells, grass on the left and a crumb on the right.
+pub const ATLAS_CELL_SIZE: u32 = 32;
+
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy, Debug, PartialEq)]
+pub struct CrumbInstance {
+    pub position: Vec3,
+    pub scale: f32,
+    // How far the top of it moves in the wind. Crumbs don't move at all.
+    pub sway: f32,
+    // So that neighbouring blades don't all sway in step.
+    pub phase: f32,
+    // Which cell of the texture to use.
+    pub atlas_cell: f32,
+    // A little variation in how dark each one is.
+    pub shade: f32,
+}
+
+// How thickly covered the ground is at a point, between 0 and 1. It's made up of a few masks
+// multiplied together: nothing grows in or right next to the lakes or on steep slopes, and the rest
+// of the map is covered in patches.
+pub fn density_at(terrain: &Terrain, point: Vec2) -> f32 {
+    let shore = LAKES
+        .iter()
+        .map(|lake| smoothstep(0.0, SHORE_GAP, lake.signed_distance(point) - SHORE_GAP))
+        .fold(1.0, f32::min);
+
+    let flatness = smoothstep(MIN_FLATNESS, FULL_FLATNESS, terrain.normal_at(point).y);
+
+    shore * flatness * patchiness(point)
+}
+
+// A few sine waves at odd angles added together, which is blobby enough to look like clumps
+// without needing a noise texture.
+fn patchiness(point: Vec2) -> f32 {
+    let waves = (point.x * 0.13).sin()
+        + (point.y * 0.11 + 1.7).sin()
+        + ((point.x + point.y) * 0.07 + 0.4).sin();
+
+    smoothstep(-1.5, 1.0, waves)
+}
+
+// The same crumbs in the same places every time.
+pub fn scatter(terrain: &Terrain) -> Vec<CrumbInstance> {
+    let mut rng = SmallRng::seed_from_u64(SEED);
+    let cells = (MAP_SIZE / SPACING) as usize;
+    let mut crumbs = Vec::new();
+
+    for z in 0..cells {
+        for x in 0..cells {
+            let jitter = Vec2::new(rng.gen_range(0.0, 1.0), rng.gen_range(0.0, 1.0));
+            let point = (Vec2::new(x as f32, z as f32) + jitter) * SPACING
+                - Vec2::broadcast(MAP_SIZE / 2.0);
+
+            // Always roll both, so that the rest of the map stays the same if the density changes.
+            let keep = rng.gen_range(0.0, 1.0);
+            let variation = rng.gen_range(0.0, 1.0);
+
+            if keep >= density_at(terrain, point) {
+                continue;
+            }
+
+            let is_crumb = variation < CRUMB_CHANCE;
+
+            crumbs.push(CrumbInstance {
+                position: terrain.ground(point),
+                scale: if is_crumb {
+                    0.15 + variation * 0.4
+                } else {
+                    0.3 + variation * 0.3
+                },
+                sway: if is_crumb { 0.0 } else { 1.0 },
+                phase: variation * std::f32::consts::TAU,
+                atlas_cell: if is_crumb { 1.0 } else { 0.0 },
+                shade: 0.8 + variation * 0.2,
+            });
+        }
+    }
+
+    crumbs
+}
+
+// Drawn on the cpu rather than loaded from a file, as it's only a few blades and a blob. Anything
+// with an alpha below a half is cut out by the shader.
+pub fn texture() -> image::RgbaImage {
+    let size = ATLAS_CELL_SIZE as f32;
+
+    image::RgbaImage::from_fn(ATLAS_CELL_SIZE * 2, ATLAS_CELL_SIZE, |x, y| {
+        let cell = x / ATLAS_CELL_SIZE;
+        // 0 at the bottom of the cell and 1 at the top, with x going from -1 to 1.
+        let point = Vec2::new(
+            ((x % ATLAS_CELL_SIZE) as f32 + 0.5) / size * 2.0 - 1.0,
+            1.0 - (y as f32 + 0.5) / size,
+        );
+
+        let covered = if cell == 0 {
+            // Three blades leaning out from the middle, each getting thinner towards its tip.
+            [-0.5_f32, 0.0, 0.5].iter().any(|&lean| {
+                let center = lean * point.y * 0.8;
+                let half_width = 0.15 * (1.0 - point.y);
+                (point.x - center).abs() < half_width
+            })
+        } else {
+            // A lumpy blob sitting on the ground.
+            let offset = point - Vec2::new(0.0, 0.35);
+            offset.mag() < 0.3 + 0.05 * (point.x * 9.0).sin()
+        };
+
+        let colour = match (cell, covered) {
+            (_, false) => [0, 0, 0, 0],
+            (0, true) => {
+                // Darker at the roots.
+                let light = 0.6 + point.y * 0.4;
+                [
+                    (90.0 * light) as u8,
+                    (150.0 * light) as u8,
+                    (60.0 * light) as u8,
+                    255,
+                ]
+            }
+            (_, true) => [240, 200, 80, 255],
+        };
+
+        image::Rgba(colour)
+    })
+}
+
+#[test]
+fn crumbs_are_scattered_the_same_way_every_time() {
+    let terrain = Terrain::generate();
+
+    let crumbs = scatter(&terrain);
+    assert!(crumbs.len() > 1000);
+    assert_eq!(crumbs, scatter(&terrain));
+}
+
+#[test]
+fn nothing_grows_in_the_lakes() {
+    let terrain = Terrain::generate();
+
+    for lake in &LAKES {
+        assert_eq!(density_at(&terrain, lake.center), 0.0);
+    }
+
+    assert!(scatter(&terrain).iter().all(|crumb| {
+        let point = Vec2::new(crumb.position.x, crumb.position.z);
+        LAKES
+            .iter()
+            .all(|lake| lake.signed_distance(point) > SHORE_GAP)
+    }));
+}
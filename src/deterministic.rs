@@ -0,0 +1,71 @@
+// For lockstep multiplayer (and replays) to work, every machine has to come up with exactly the
+// same floats on every tick. Adding, multiplying, dividing and square roots are all rounded the same
+// way everywhere, but the trig functions in std call out to the platform's maths library, which is
+// allowed to round differently between operating systems and cpus. With the `deterministic`
+// feature, the simulation uses the pure rust versions from the `libm` crate instead, which give the
+// same answers everywhere (at the cost of being a bit slower).
+//
+// Anything that only affects how things look (particles, animations, the camera) can keep on using
+// the std functions.
+//
+// This only covers the maths. Legion doesn't go through entities in the same order on every machine
+// either, so anything in the simulation that depends on the order (picking the first match, adding
+// floats up) has to sort by `NetworkId` first, like `checksum_world` does.
+
+use ultraviolet::Vec2;
+
+#[cfg(feature = "deterministic")]
+pub fn sin(x: f32) -> f32 {
+    libm::sinf(x)
+}
+
+#[cfg(feature = "deterministic")]
+pub fn cos(x: f32) -> f32 {
+    libm::cosf(x)
+}
+
+#[cfg(feature = "deterministic")]
+pub fn atan2(y: f32, x: f32) -> f32 {
+    libm::atan2f(y, x)
+}
+
+#[cfg(not(feature = "deterministic"))]
+pub fn sin(x: f32) -> f32 {
+    x.sin()
+}
+
+#[cfg(not(feature = "deterministic"))]
+pub fn cos(x: f32) -> f32 {
+    x.cos()
+}
+
+#[cfg(not(feature = "deterministic"))]
+pub fn atan2(y: f32, x: f32) -> f32 {
+    y.atan2(x)
+}
+
+// A vector of length 1 pointing in the direction of an angle (in radians).
+pub fn direction(angle: f32) -> Vec2 {
+    Vec2::new(cos(angle), sin(angle))
+}
+
+// The angle a vector is pointing in, like a `Facing`.
+pub fn angle(vector: Vec2) -> f32 {
+    atan2(vector.y, vector.x)
+}
+
+// NaNs spread through everything they touch and compare unequal to themselves, so one sneaking in
+// tends to show up as a desync much later on. This is for catching them where they happen instead.
+pub fn is_finite(vector: Vec2) -> bool {
+    vector.x.is_finite() && vector.y.is_finite()
+}
+
+#[test]
+fn directions_and_angles_round_trip() {
+    for &angle in &[0.0, 1.0, -2.5, 3.0] {
+        assert!((self::angle(direction(angle)) - angle).abs() < 0.0001);
+    }
+
+    assert!((direction(std::f32::consts::FRAC_PI_2) - Vec2::new(0.0, 1.0)).mag() < 0.0001);
+    assert!(!is_finite(Vec2::new(0.0, f32::NAN)));
+}
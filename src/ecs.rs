@@ -1,85 +1,175 @@
 use crate::assets::ModelAnimations;
+use crate::camera::{Camera, CameraControls, CameraShake};
+use crate::deterministic;
 use crate::pathfinding::{Map, MapHandle};
 use crate::renderer::Image;
-use crate::resources::{
-    Camera, CameraControls, DeltaTime, MouseState, PlayerSide, RtsControls, ScreenDimensions,
-};
+use crate::resources::{MouseState, PlayerSide, RtsControls, ScreenDimensions, UnscaledDeltaTime};
+use crate::terrain::Terrain;
 use legion::systems::CommandBuffer;
 use legion::world::SubWorld;
 use legion::*;
+use once_cell::sync::Lazy;
 use rand::rngs::SmallRng;
 use rand::Rng;
-use std::collections::VecDeque;
-use ultraviolet::{Mat4, Vec2, Vec3};
+use std::collections::{BTreeMap, VecDeque};
+use ultraviolet::{Mat4, Vec2, Vec3, Vec4};
 use winit::event::VirtualKeyCode;
 
+mod abilities;
 mod ai;
+mod airborne;
+mod alerts;
 mod animation;
 mod buildings;
+mod burrowing;
+mod capture;
 mod combat;
+mod console;
 mod controls;
+mod day_night;
 mod debugging;
 mod effects;
+mod fog_of_war;
+mod healing;
+mod hud;
+mod idle;
+mod inspector;
+mod lockstep;
+mod minimap;
 mod movement;
 mod playing_menu;
 mod rendering;
+mod save;
+mod shields;
+mod spatial_hash;
+mod stealth;
+mod superweapon;
+mod tech;
+mod triggers;
+mod upgrades;
+mod walls;
 
 use crate::resources::DebugControls;
+use abilities::{cast_abilities_system, recharge_abilities_system, wear_off_speed_boosts_system};
 use ai::follow_ai_build_orders_system;
+use airborne::render_flying_shadows_system;
+pub use airborne::{Airborne, AntiAir};
+use alerts::update_alerts_system;
+pub use alerts::Alerts;
 use animation::{progress_animations_system, progress_building_animations_system};
 use buildings::{
     build_buildings_system, free_up_cheese_guysers_system, generate_cheese_coins_system,
     progress_recruitment_queue_system,
 };
+use burrowing::progress_burrowing_system;
+pub use burrowing::Burrow;
+pub use capture::Structure;
+use capture::{capture_structures_system, generate_captured_income_system, Capturable};
 use combat::{
     agro_units_system, apply_bullets_system, firing_system, handle_damaged_system,
-    propagate_agro_system, reduce_cooldowns_system, stop_actions_on_dead_entities_system,
-    update_argoed_this_tick_system,
+    leash_defensive_units_system, leash_guards_system, propagate_agro_system,
+    reduce_cooldowns_system, stop_actions_on_dead_entities_system, update_argoed_this_tick_system,
 };
+pub use console::Console;
+use console::{render_console_system, run_console_commands_system};
 use controls::{
     cast_ray_system, control_camera_system, handle_control_groups_system,
-    handle_drag_selection_system, handle_keypresses_system, handle_left_click_system,
-    handle_right_click_system, handle_stop_command_system,
-    remove_dead_entities_from_control_groups_system, update_playing_state_system,
-    update_selected_units_abilities_system,
+    handle_drag_selection_system, handle_hold_position_command_system, handle_keypresses_system,
+    handle_left_click_system, handle_right_click_system, handle_stance_command_system,
+    handle_stop_command_system, remove_dead_entities_from_control_groups_system,
+    update_playing_state_system, update_selected_units_abilities_system,
 };
+use day_night::update_time_of_day_system;
 use debugging::{
     debug_select_box_system, debug_specific_path_system, render_building_grid_system,
-    render_debug_unit_pathfinding_system, render_firing_ranges_system,
-    render_pathfinding_map_system, render_unit_paths_system, set_debug_pathfinding_start_system,
-    spawn_debug_building_system,
+    render_debug_unit_pathfinding_system, render_firing_ranges_system, render_flow_field_system,
+    render_pathfinding_map_system, render_profiler_system, render_unit_paths_system,
+    set_debug_pathfinding_start_system, spawn_debug_building_system,
 };
 use effects::{
-    apply_gravity_system, expand_explosions_system, move_cheese_droplets_system,
-    render_cheese_droplets_system, render_explosions_system, spawn_cheese_droplets_system,
+    apply_gravity_system, decay_corpses_system, emit_particles_system, expand_explosions_system,
+    fade_scorch_marks_system, float_text_upwards_system, move_cheese_droplets_system,
+    move_particles_system, render_cheese_droplets_system, render_explosions_system,
+    render_particles_system, render_scorch_marks_system, spawn_cheese_droplets_system,
+};
+#[cfg(feature = "material-maps")]
+use effects::{fade_light_flashes_system, render_light_flashes_system};
+pub use fog_of_war::FogOfWar;
+use fog_of_war::{render_fog_of_war_system, update_visibility_system};
+use healing::heal_nearby_system;
+pub use hud::ui_scale;
+use hud::{
+    handle_command_card_clicks_system, handle_game_speed_clicks_system,
+    handle_idle_workers_click_system, hud_contains, render_command_card_system,
+    render_game_speed_controls_system, render_idle_workers_button_system,
+    render_selection_panel_system,
+};
+pub use idle::IdleUnits;
+use idle::{cycle_idle_units_system, track_idle_units_system};
+pub use inspector::Inspector;
+use inspector::{
+    edit_inspected_entity_system, pick_inspected_entity_system, render_inspector_system,
 };
+pub use lockstep::WorldChecksum;
+use lockstep::{apply_player_commands_system, assign_network_ids_system, checksum_world_system};
+use minimap::{render_minimap_system, MinimapArea};
+pub use movement::PathRequests;
 use movement::{
     apply_steering_system, avoidance_system, move_bullets_system, move_units_system,
-    reset_map_updated_system, set_movement_paths_system, Avoidable, Avoids,
+    repath_invalidated_paths_system, set_movement_paths_system, solve_path_requests_system,
+    Avoidable, Avoids,
 };
 use playing_menu::{
     handle_playing_menu_controls_system, render_playing_menu_click_regions_system,
     render_playing_menu_system,
 };
 use rendering::{
-    render_abilities_system, render_building_plan_system, render_buildings_system,
-    render_bullets_system, render_command_paths_system, render_drag_box_system,
-    render_health_bars_system, render_recruitment_waypoints_system, render_selections_system,
-    render_ui_system, render_under_select_box_system, render_unit_under_cursor_system,
-    render_units_system,
+    render_ability_targeting_system, render_building_plan_system, render_buildings_system,
+    render_bullets_system, render_capturable_structures_system, render_command_paths_system,
+    render_corpses_system, render_drag_box_system, render_floating_text_system,
+    render_healing_beams_system, render_health_bars_system, render_incoming_strikes_system,
+    render_obstacles_system, render_rank_chevrons_system, render_recruitment_waypoints_system,
+    render_selections_system, render_ui_system, render_under_select_box_system,
+    render_unit_under_cursor_system, render_units_system,
 };
+pub use save::{load_game, save_game, snapshot_game, SaveSnapshot, QUICKSAVE_PATH};
+use shields::{intercept_bullets_system, regenerate_shields_system, render_shields_system};
+pub use shields::{Shield, ShieldStats};
+use spatial_hash::update_spatial_hash_system;
+pub use spatial_hash::SpatialHash;
+use stealth::{
+    lose_track_of_cloaked_targets_system, render_cloak_shimmer_system, update_detection_system,
+    wear_off_cloaks_system,
+};
+pub use stealth::{Cloaked, Detector};
+use superweapon::{charge_superweapons_system, progress_strikes_system, Strike, Superweapon};
+pub use triggers::ScriptTag;
+use triggers::{render_mission_dialogue_system, run_mission_triggers_system};
+use upgrades::progress_research_system;
+pub use upgrades::{Research, Upgrade, Upgrades};
+use walls::{
+    handle_wall_placement_system, share_wall_health_system, update_gates_system,
+    update_walls_system, Gate,
+};
+
+pub use crate::animation::AnimationState;
 
 #[legion::system]
 fn cleanup_controls(
     #[resource] mouse_state: &mut MouseState,
     #[resource] rts_controls: &mut RtsControls,
     #[resource] debug_controls: &mut DebugControls,
-    #[resource] delta_time: &DeltaTime,
+    #[resource] delta_time: &UnscaledDeltaTime,
 ) {
     mouse_state.left_state.update(delta_time.0);
     mouse_state.right_state.update(delta_time.0);
 
     rts_controls.stop_pressed = false;
+    rts_controls.hold_position_pressed = false;
+    rts_controls.cycle_stance_pressed = false;
+    rts_controls.select_idle_worker_pressed = false;
+    rts_controls.select_idle_army_pressed = false;
+    rts_controls.jump_to_alert_pressed = false;
 
     for i in 0..10 {
         rts_controls.control_group_key_pressed[i] = false;
@@ -92,79 +182,189 @@ pub fn add_gameplay_systems(builder: &mut legion::systems::Builder) {
     builder
         // Agro propagation and updating
         .add_system(update_argoed_this_tick_system())
+        .add_system(assign_network_ids_system())
+        .add_system(update_spatial_hash_system())
+        .add_system(update_detection_system())
         .flush()
         .add_system(propagate_agro_system())
         .flush()
         //
+        .add_system(handle_command_card_clicks_system())
+        .add_system(handle_game_speed_clicks_system())
+        .add_system(handle_idle_workers_click_system())
         .add_system(handle_keypresses_system())
+        .add_system(pick_inspected_entity_system())
+        .add_system(edit_inspected_entity_system())
+        .add_system(run_console_commands_system())
         .add_system(generate_cheese_coins_system())
+        .add_system(generate_captured_income_system())
         .add_system(progress_recruitment_queue_system())
-        .add_system(reset_map_updated_system())
+        .add_system(progress_research_system())
+        .add_system(charge_superweapons_system())
         .add_system(cast_ray_system())
         .add_system(free_up_cheese_guysers_system())
+        .add_system(update_walls_system())
+        .add_system(update_gates_system())
         .add_system(remove_dead_entities_from_control_groups_system())
         .add_system(stop_actions_on_dead_entities_system())
+        .add_system(lose_track_of_cloaked_targets_system())
         .add_system(control_camera_system())
         .add_system(handle_left_click_system())
+        .add_system(handle_wall_placement_system())
         .add_system(handle_right_click_system())
         .add_system(handle_stop_command_system())
+        .add_system(handle_hold_position_command_system())
+        .add_system(handle_stance_command_system())
         .add_system(handle_drag_selection_system())
         .add_system(handle_control_groups_system())
+        .add_system(track_idle_units_system())
+        .add_system(cycle_idle_units_system())
         .add_system(avoidance_system())
         .add_system(agro_units_system())
+        .add_system(leash_defensive_units_system())
+        .add_system(leash_guards_system())
         .add_system(update_selected_units_abilities_system())
         .add_system(follow_ai_build_orders_system())
+        // Player commands go through the lockstep queue, even when not playing over the network.
+        .add_system(apply_player_commands_system())
         // Needed because a command could place a building using a command buffer, but the entity
         // reference wouldn't be valid until the commands in the buffer have been executed.
         .flush()
+        .add_system(repath_invalidated_paths_system())
         .add_system(set_movement_paths_system())
+        .add_system(solve_path_requests_system())
+        .add_system(cast_abilities_system())
         .add_system(reduce_cooldowns_system())
+        .add_system(recharge_abilities_system())
+        .add_system(wear_off_speed_boosts_system())
+        .add_system(wear_off_cloaks_system())
+        .add_system(progress_strikes_system())
         .add_system(set_debug_pathfinding_start_system())
         // Cheese droplets.
         .add_system(spawn_cheese_droplets_system())
         .flush()
-        .add_system(expand_explosions_system())
+        .add_system(expand_explosions_system());
+
+    // The flashes only light anything up with the `material-maps` shaders.
+    #[cfg(feature = "material-maps")]
+    builder.add_system(fade_light_flashes_system());
+
+    builder
+        .add_system(fade_scorch_marks_system())
+        .add_system(decay_corpses_system())
         .add_system(apply_gravity_system())
         .add_system(move_cheese_droplets_system())
+        .add_system(progress_burrowing_system())
         .add_system(move_units_system())
         .add_system(move_bullets_system())
         .add_system(apply_steering_system())
         .add_system(build_buildings_system())
+        .add_system(heal_nearby_system())
+        .add_system(capture_structures_system())
+        .add_system(regenerate_shields_system())
         .add_system(firing_system())
+        .add_system(intercept_bullets_system())
+        // So that intercepted bullets are gone before they can hit anything.
+        .flush()
         .add_system(apply_bullets_system())
         .flush()
+        .add_system(share_wall_health_system())
         .add_system(handle_damaged_system())
+        .add_system(update_alerts_system())
+        .add_system(emit_particles_system())
+        .add_system(move_particles_system())
+        .add_system(float_text_upwards_system())
+        .add_system(run_mission_triggers_system())
         .add_system(update_playing_state_system())
+        .add_system(update_time_of_day_system())
+        .flush()
+        .add_system(update_visibility_system())
         // Animations.
         .add_system(progress_animations_system())
-        .add_system(progress_building_animations_system());
+        .add_system(progress_building_animations_system())
+        .add_system(checksum_world_system());
 }
 
 pub fn add_rendering_systems(builder: &mut legion::systems::Builder) {
     builder
         .add_system(render_bullets_system())
+        .add_system(render_healing_beams_system())
         .add_system(render_units_system())
+        .add_system(render_corpses_system())
+        .add_system(render_fog_of_war_system())
         .add_system(render_selections_system())
+        .add_system(render_flying_shadows_system())
         //.add_system(render_firing_ranges_system())
         .add_system(render_under_select_box_system())
         .add_system(render_drag_box_system())
         .add_system(render_command_paths_system())
         .add_system(render_ui_system())
+        .add_system(render_minimap_system())
         .add_system(render_health_bars_system())
+        .add_system(render_rank_chevrons_system())
+        .add_system(render_floating_text_system())
         .add_system(render_unit_under_cursor_system())
-        //.add_system(render_pathfinding_map_system())
-        //.add_system(render_unit_paths_system())
+        .add_system(render_pathfinding_map_system())
+        .add_system(render_unit_paths_system())
         .add_system(render_debug_unit_pathfinding_system())
+        .add_system(render_flow_field_system())
         .add_system(render_buildings_system())
+        .add_system(render_obstacles_system())
+        .add_system(render_capturable_structures_system())
         .add_system(render_building_plan_system())
+        .add_system(render_ability_targeting_system())
+        .add_system(render_incoming_strikes_system())
         .add_system(render_cheese_droplets_system())
-        .add_system(render_explosions_system())
-        .add_system(render_abilities_system())
-        .add_system(render_recruitment_waypoints_system());
+        .add_system(render_explosions_system());
+
+    #[cfg(feature = "material-maps")]
+    builder.add_system(render_light_flashes_system());
+
+    builder
+        .add_system(render_scorch_marks_system())
+        .add_system(render_particles_system())
+        .add_system(render_shields_system())
+        .add_system(render_cloak_shimmer_system())
+        .add_system(render_command_card_system())
+        .add_system(render_game_speed_controls_system())
+        .add_system(render_idle_workers_button_system())
+        .add_system(render_selection_panel_system())
+        .add_system(render_recruitment_waypoints_system())
+        .add_system(render_mission_dialogue_system())
+        .add_system(render_profiler_system())
+        .add_system(render_inspector_system())
+        .add_system(render_console_system());
     //.add_system(debug_select_box_system())
     //.add_system(debug_specific_path_system())
 }
 
+// While a single player game is paused, none of the gameplay systems run, but the camera still
+// moves and units can still be selected and given orders. The orders are carried out once the game
+// is unpaused.
+pub fn add_paused_systems(builder: &mut legion::systems::Builder) {
+    builder
+        .add_system(handle_command_card_clicks_system())
+        .add_system(handle_game_speed_clicks_system())
+        .add_system(handle_idle_workers_click_system())
+        .add_system(handle_keypresses_system())
+        .add_system(cast_ray_system())
+        .add_system(control_camera_system())
+        .add_system(handle_left_click_system())
+        .add_system(handle_wall_placement_system())
+        .add_system(handle_right_click_system())
+        .add_system(handle_stop_command_system())
+        .add_system(handle_hold_position_command_system())
+        .add_system(handle_stance_command_system())
+        .add_system(handle_drag_selection_system())
+        .add_system(handle_control_groups_system())
+        .add_system(track_idle_units_system())
+        .add_system(cycle_idle_units_system())
+        .add_system(update_alerts_system())
+        .add_system(update_selected_units_abilities_system())
+        // Needed so that the selection shows up straight away.
+        .flush();
+}
+
 pub fn add_cleanup_systems(builder: &mut legion::systems::Builder) {
     builder.flush().add_system(cleanup_controls_system());
 }
@@ -198,22 +398,122 @@ impl Ability {
         hotkey: VirtualKeyCode::E,
     };
 
+    const RECRUIT_MEDIC: Self = Self {
+        ability_type: AbilityType::Recruit(Unit::Medic),
+        hotkey: VirtualKeyCode::D,
+    };
+
     const RECRUIT_MOUSE_MARINE: Self = Self {
         ability_type: AbilityType::Recruit(Unit::MouseMarine),
         hotkey: VirtualKeyCode::F,
     };
 
+    const RECRUIT_GLIDER: Self = Self {
+        ability_type: AbilityType::Recruit(Unit::Glider),
+        hotkey: VirtualKeyCode::R,
+    };
+
+    const BUILD_WALL: Self = Self {
+        ability_type: AbilityType::Build(Building::Wall),
+        hotkey: VirtualKeyCode::B,
+    };
+
+    const BUILD_GATE: Self = Self {
+        ability_type: AbilityType::Build(Building::Gate),
+        hotkey: VirtualKeyCode::N,
+    };
+
+    const BUILD_SILO: Self = Self {
+        ability_type: AbilityType::Build(Building::Silo),
+        hotkey: VirtualKeyCode::J,
+    };
+
+    const LOCK_GATE: Self = Self {
+        ability_type: AbilityType::LockGate,
+        hotkey: VirtualKeyCode::L,
+    };
+
+    const LAUNCH_STRIKE: Self = Self {
+        ability_type: AbilityType::LaunchStrike,
+        hotkey: VirtualKeyCode::K,
+    };
+
     const SET_RECRUITMENT_WAYPOINT: Self = Self {
         ability_type: AbilityType::SetRecruitmentWaypoint,
         hotkey: VirtualKeyCode::W,
     };
 
-    fn image(&self) -> Image {
+    const THROW_GRENADE: Self = Self {
+        ability_type: AbilityType::Active(ActiveAbility::Grenade),
+        hotkey: VirtualKeyCode::Z,
+    };
+
+    const SPEED_BOOST: Self = Self {
+        ability_type: AbilityType::Active(ActiveAbility::SpeedBoost),
+        hotkey: VirtualKeyCode::X,
+    };
+
+    const CLOAK: Self = Self {
+        ability_type: AbilityType::Active(ActiveAbility::Cloak),
+        hotkey: VirtualKeyCode::T,
+    };
+
+    // D is also for recruiting medics, but armouries and units are never selected together.
+    const BURROW: Self = Self {
+        ability_type: AbilityType::Burrow,
+        hotkey: VirtualKeyCode::D,
+    };
+
+    const HEAL: Self = Self {
+        ability_type: AbilityType::Active(ActiveAbility::Heal),
+        hotkey: VirtualKeyCode::C,
+    };
+
+    const RESEARCH_ATTACK: Self = Self {
+        ability_type: AbilityType::Research(Upgrade::Attack),
+        hotkey: VirtualKeyCode::Y,
+    };
+
+    const RESEARCH_HARVESTING: Self = Self {
+        ability_type: AbilityType::Research(Upgrade::Harvesting),
+        hotkey: VirtualKeyCode::U,
+    };
+
+    const RESEARCH_GRENADES: Self = Self {
+        ability_type: AbilityType::Research(Upgrade::Grenades),
+        hotkey: VirtualKeyCode::I,
+    };
+
+    const RESEARCH_FIELD_MEDICINE: Self = Self {
+        ability_type: AbilityType::Research(Upgrade::FieldMedicine),
+        hotkey: VirtualKeyCode::O,
+    };
+
+    const ARMOURY: [&'static Self; 9] = [
+        &Self::RECRUIT_MOUSE_MARINE,
+        &Self::RECRUIT_ENGINEER,
+        &Self::RECRUIT_MEDIC,
+        &Self::RECRUIT_GLIDER,
+        &Self::SET_RECRUITMENT_WAYPOINT,
+        &Self::RESEARCH_ATTACK,
+        &Self::RESEARCH_HARVESTING,
+        &Self::RESEARCH_GRENADES,
+        &Self::RESEARCH_FIELD_MEDICINE,
+    ];
+
+    // There aren't any pictures for the active abilities, walls, gates or silos yet, so their
+    // buttons just get a label.
+    fn image(&self) -> Option<Image> {
         match self.ability_type {
-            AbilityType::Build(building) => building.stats().image,
-            AbilityType::Recruit(Unit::Engineer) => Image::RecruitEngineer,
-            AbilityType::Recruit(Unit::MouseMarine) => Image::RecruitMouseMarine,
-            AbilityType::SetRecruitmentWaypoint => Image::SetRecruitmentWaypoint,
+            AbilityType::Build(Building::Wall | Building::Gate | Building::Silo)
+            | AbilityType::LockGate
+            | AbilityType::Burrow
+            | AbilityType::LaunchStrike
+            | AbilityType::Research(_) => None,
+            AbilityType::Build(building) => Some(building.stats().image),
+            AbilityType::Recruit(unit) => Some(unit.stats().image),
+            AbilityType::SetRecruitmentWaypoint => Some(Image::SetRecruitmentWaypoint),
+            AbilityType::Active(_) => None,
         }
     }
 }
@@ -223,26 +523,154 @@ pub enum AbilityType {
     Build(Building),
     Recruit(Unit),
     SetRecruitmentWaypoint,
+    Active(ActiveAbility),
+    LockGate,
+    Research(Upgrade),
+    LaunchStrike,
+    Burrow,
 }
 
 pub struct Abilities(pub Vec<&'static Ability>);
 
+// Abilities that units use in the middle of a fight. They cost energy and have to cool down before
+// they can be used again.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum ActiveAbility {
+    Grenade,
+    Heal,
+    SpeedBoost,
+    Cloak,
+}
+
+// What an ability needs to be aimed at.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Targeting {
+    // Used on the caster straight away, without needing to click anywhere.
+    Instant,
+    // A friendly unit.
+    Unit,
+    // A point on the ground. Everything within the radius around it is affected.
+    Area(f32),
+}
+
+pub struct ActiveAbilityStats {
+    pub label: &'static str,
+    pub cooldown: f32,
+    pub energy_cost: f32,
+    // How close the caster has to walk to the target first.
+    pub range: f32,
+    pub targeting: Targeting,
+}
+
+impl ActiveAbility {
+    pub fn stats(self) -> ActiveAbilityStats {
+        match self {
+            Self::Grenade => ActiveAbilityStats {
+                label: "Grenade",
+                cooldown: 10.0,
+                energy_cost: 25.0,
+                range: 8.0,
+                targeting: Targeting::Area(4.0),
+            },
+            Self::Heal => ActiveAbilityStats {
+                label: "Heal",
+                cooldown: 4.0,
+                energy_cost: 20.0,
+                range: 6.0,
+                targeting: Targeting::Unit,
+            },
+            Self::SpeedBoost => ActiveAbilityStats {
+                label: "Boost",
+                cooldown: 15.0,
+                energy_cost: 15.0,
+                range: 0.0,
+                targeting: Targeting::Instant,
+            },
+            Self::Cloak => ActiveAbilityStats {
+                label: "Cloak",
+                cooldown: 30.0,
+                energy_cost: 50.0,
+                range: 0.0,
+                targeting: Targeting::Instant,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum AbilityTarget {
+    Caster,
+    Point(Vec2),
+    Unit(Entity),
+}
+
+impl AbilityTarget {
+    // None if the targeted unit has died.
+    fn position(self, caster_position: Vec2, world: &SubWorld) -> Option<Vec2> {
+        match self {
+            Self::Caster => Some(caster_position),
+            Self::Point(point) => Some(point),
+            Self::Unit(entity) => <&Position>::query()
+                .get(world, entity)
+                .ok()
+                .map(|position| position.0),
+        }
+    }
+}
+
+// Used up by active abilities and slowly recharged. Only units with abilities that need it have any.
+pub struct Energy(pub f32);
+
+// How long is left until each of a unit's active abilities can be used again. Abilities that are
+// ready to go aren't in here.
+#[derive(Default)]
+pub struct AbilityCooldowns(BTreeMap<ActiveAbility, f32>);
+
+impl AbilityCooldowns {
+    pub fn remaining(&self, ability: ActiveAbility) -> f32 {
+        self.0.get(&ability).copied().unwrap_or(0.0)
+    }
+}
+
+// Makes a unit move faster until it runs out.
+pub struct SpeedBoost {
+    remaining: f32,
+}
+
 pub struct CheeseGuyser;
 pub struct CheeseGuyserBuiltOn {
     pub pump: Entity,
 }
 
+// Rocks and other scenery that block the way, e.g. on generated maps. They're a part of the map
+// forever, so unlike buildings they don't need to hold onto their `MapHandle`.
+pub struct Obstacle {
+    pub dimensions: Vec2,
+}
+
+impl Obstacle {
+    pub fn add_to_world(
+        buffer: &mut CommandBuffer,
+        position: Vec2,
+        dimensions: Vec2,
+        map: &mut Map,
+    ) -> Option<Entity> {
+        map.insert(position, dimensions)?;
+        Some(buffer.push((Position(position), Obstacle { dimensions })))
+    }
+}
+
 #[derive(Debug)]
 pub struct Position(pub Vec2);
 pub struct Facing(pub f32);
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
 pub enum Side {
     Green,
     Purple,
 }
 
 impl Side {
-    fn flip(&self) -> Self {
+    pub fn flip(&self) -> Self {
         match self {
             Self::Green => Self::Purple,
             Self::Purple => Self::Green,
@@ -284,6 +712,36 @@ pub enum Command {
         target: Entity,
         state: ActionState,
     },
+    // Walk back and forth between two points, attacking anything that comes near. The path leads to
+    // `b`, and the two points get swapped around whenever it's reached.
+    Patrol {
+        a: Vec2,
+        b: Vec2,
+        path: Vec<Vec2>,
+    },
+    // Stay put and shoot at anything in range, but never chase after it. This never finishes by
+    // itself, so anything queued up after it won't happen.
+    HoldPosition,
+    // Walk within range of the target and then use an active ability on it.
+    Cast {
+        ability: ActiveAbility,
+        target: AbilityTarget,
+        state: ActionState,
+    },
+    // Keep up with a friendly unit, staying a little way behind it. Like holding position, this
+    // never finishes by itself, but it's dropped when the unit being followed dies.
+    Follow {
+        target: Entity,
+        path: Vec<Vec2>,
+    },
+    // Stand at `center` and go after any enemy that comes within `radius` of it, giving up on
+    // anything that gets back out and walking back afterwards. This never finishes by itself
+    // either. The path leads back to `center`.
+    Guard {
+        center: Vec2,
+        radius: f32,
+        path: Vec<Vec2>,
+    },
 }
 
 impl Command {
@@ -303,8 +761,52 @@ impl Command {
         }
     }
 
+    // An attack that's given up on as soon as the target is out of range, instead of chasing it.
+    fn new_attack_without_chasing(target: Entity) -> Self {
+        Self::Attack {
+            target,
+            explicit: false,
+            first_out_of_range: false,
+            state: ActionState::InRange,
+        }
+    }
+
+    fn new_cast(ability: ActiveAbility, target: AbilityTarget) -> Self {
+        Self::Cast {
+            ability,
+            target,
+            state: ActionState::InRange,
+        }
+    }
+
+    fn new_patrol(a: Vec2, b: Vec2) -> Self {
+        Self::Patrol {
+            a,
+            b,
+            path: Vec::new(),
+        }
+    }
+
+    fn new_follow(target: Entity) -> Self {
+        Self::Follow {
+            target,
+            path: Vec::new(),
+        }
+    }
+
+    fn new_guard(center: Vec2, radius: f32) -> Self {
+        Self::Guard {
+            center,
+            radius,
+            path: Vec::new(),
+        }
+    }
+
     fn path(&self) -> Option<&Vec<Vec2>> {
         if let &Command::MoveTo { ref path, .. }
+        | &Command::Patrol { ref path, .. }
+        | &Command::Follow { ref path, .. }
+        | &Command::Guard { ref path, .. }
         | &Command::Attack {
             state: ActionState::OutOfRange { ref path },
             ..
@@ -312,6 +814,10 @@ impl Command {
         | &Command::Build {
             state: ActionState::OutOfRange { ref path },
             ..
+        }
+        | &Command::Cast {
+            state: ActionState::OutOfRange { ref path },
+            ..
         } = self
         {
             Some(path)
@@ -322,6 +828,9 @@ impl Command {
 
     fn path_mut(&mut self) -> Option<&mut Vec<Vec2>> {
         if let &mut Command::MoveTo { ref mut path, .. }
+        | &mut Command::Patrol { ref mut path, .. }
+        | &mut Command::Follow { ref mut path, .. }
+        | &mut Command::Guard { ref mut path, .. }
         | &mut Command::Attack {
             state: ActionState::OutOfRange { ref mut path },
             ..
@@ -329,6 +838,10 @@ impl Command {
         | &mut Command::Build {
             state: ActionState::OutOfRange { ref mut path },
             ..
+        }
+        | &mut Command::Cast {
+            state: ActionState::OutOfRange { ref mut path },
+            ..
         } = self
         {
             Some(path)
@@ -353,34 +866,197 @@ impl ActionState {
 #[derive(Default)]
 pub struct CommandQueue(VecDeque<Command>);
 
+impl CommandQueue {
+    // Where the unit will be once it's carried out all of its queued move commands, so that
+    // shift-queued moves can be worked out from there instead of from where the unit is now.
+    fn last_waypoint(&self) -> Option<Vec2> {
+        self.0.iter().rev().find_map(|command| match command {
+            Command::MoveTo { target, .. } => Some(*target),
+            _ => None,
+        })
+    }
+}
+
 pub struct Health(pub f32);
+
+// Units get promoted as they rack up kills, which makes them tougher and hit harder.
+#[derive(Clone, Copy, Default, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Experience {
+    pub kills: u32,
+}
+
+impl Experience {
+    // How many kills it takes to get to each rank after the first.
+    const RANK_THRESHOLDS: [u32; 3] = [2, 5, 10];
+    const RANK_NAMES: [&'static str; 4] = ["Rookie", "Veteran", "Elite", "Heroic"];
+    const RANK_MULTIPLIERS: [f32; 4] = [1.0, 1.1, 1.25, 1.5];
+
+    pub fn rank(self) -> usize {
+        Self::RANK_THRESHOLDS
+            .iter()
+            .filter(|threshold| self.kills >= **threshold)
+            .count()
+    }
+
+    pub fn rank_name(self) -> &'static str {
+        Self::RANK_NAMES[self.rank()]
+    }
+
+    // Both damage and max health get boosted by the same amount.
+    pub fn multiplier(self) -> f32 {
+        Self::RANK_MULTIPLIERS[self.rank()]
+    }
+
+    // The killer isn't necessarily something the system doing the killing has access to, so this
+    // goes through the command buffer. A unit that gets promoted gets the extra health straight away.
+    pub fn credit_kill(buffer: &mut CommandBuffer, killer: Entity) {
+        buffer.exec_mut(move |world| {
+            let mut entry = match world.entry(killer) {
+                Some(entry) => entry,
+                None => return,
+            };
+
+            let (before, after) = match entry.get_component_mut::<Experience>() {
+                Ok(experience) => {
+                    let before = *experience;
+                    experience.kills += 1;
+                    (before, *experience)
+                }
+                Err(_) => return,
+            };
+
+            let max_health = match entry.get_component::<Unit>() {
+                Ok(unit) => unit.stats().max_health,
+                Err(_) => return,
+            };
+
+            if let Ok(health) = entry.get_component_mut::<Health>() {
+                health.0 += max_health * (after.multiplier() - before.multiplier());
+            }
+        });
+    }
+}
 pub struct BuildingCompleteness(pub f32);
 
 pub struct FiringRange(pub f32);
-pub struct MoveSpeed(pub f32);
+// The chance of a shot landing where it was aimed, from 0 to 1.
+pub struct Accuracy(pub f32);
+pub struct ReloadTime(pub f32);
+// Weapons with this damage everything nearby where their bullets land, not just their target.
+pub struct AreaOfEffect(pub f32);
+pub struct SightRange(pub f32);
+// How fast something goes when it's going flat out.
+pub struct MaxSpeed(pub f32);
+// How quickly a unit speeds up and slows down, in distance per second per second. Things without
+// one are always going at their max speed.
+pub struct Acceleration(pub f32);
+// Which way and how fast a unit is actually moving.
+pub struct Velocity(pub Vec2);
+// In radians per second.
+pub struct TurnRate(pub f32);
 pub struct Radius(pub f32);
+// The version of the map that a unit's current path was checked against.
+pub struct PathVersion(pub u32);
+// Entity ids differ between peers, so commands sent over the network use these instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct NetworkId(pub u32);
+
+// Every hit an entity took this tick, which all get added up in `handle_damaged`.
+pub struct DamagedThisTick(pub Vec<Damage>);
+
+impl DamagedThisTick {
+    // Several bullets can land on the same thing in one tick, and adding the component through the
+    // command buffer would just keep the last one, so this appends to the hits instead.
+    pub fn add(buffer: &mut CommandBuffer, entity: Entity, damage: Damage) {
+        buffer.exec_mut(move |world| {
+            let mut entry = match world.entry(entity) {
+                Some(entry) => entry,
+                None => return,
+            };
+
+            match entry.get_component_mut::<DamagedThisTick>() {
+                Ok(damaged) => damaged.0.push(damage),
+                Err(_) => entry.add_component(DamagedThisTick(vec![damage])),
+            }
+        });
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Damage {
+    pub source: Entity,
+    pub damage: f32,
+    pub kind: DamageKind,
+}
 
-pub struct DamagedThisTick(pub Entity);
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DamageKind {
+    // A direct hit from a bullet.
+    Bullet,
+    // Caught in the area of effect of an explosion.
+    Splash,
+}
 
-pub struct AnimationState {
-    pub animation: usize,
-    pub time: f32,
-    pub total_time: f32,
+impl DamageKind {
+    // The colour of the damage numbers.
+    fn colour(self) -> Vec3 {
+        match self {
+            Self::Bullet => Vec3::new(1.0, 0.9, 0.5),
+            Self::Splash => Vec3::new(1.0, 0.5, 0.1),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Bullet {
     source: Entity,
     target: Entity,
-    target_position: Vec2,
+    side: Side,
+    damage: f32,
+    area_of_effect: Option<f32>,
+    start: Vec2,
+    // Where the bullet is going to land. This is decided when it's fired, so bullets can miss.
+    impact: Vec2,
+}
+
+impl Bullet {
+    const GUN_HEIGHT: f32 = 1.8;
+    // How high the arc peaks, relative to the distance travelled.
+    const ARC: f32 = 0.05;
+
+    // How far along the trajectory a point is, from 0 to 1.
+    fn progress(&self, position: Vec2) -> f32 {
+        let length = (self.impact - self.start).mag();
+
+        if length == 0.0 {
+            1.0
+        } else {
+            ((position - self.start).mag() / length).min(1.0)
+        }
+    }
+
+    fn point_at(&self, progress: f32) -> Vec3 {
+        let length = (self.impact - self.start).mag();
+        let position = self.start + (self.impact - self.start) * progress;
+        let height = Self::GUN_HEIGHT + 4.0 * Self::ARC * length * progress * (1.0 - progress);
+        Vec3::new(position.x, height, position.y)
+    }
 }
 
 pub struct Cooldown(pub f32);
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+// The only clip in the pump model.
+const PUMP_ANIMATION: &str = "pump";
+
+#[derive(
+    Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, serde::Serialize, serde::Deserialize,
+)]
 pub enum Building {
     Armoury,
     Pump,
+    Wall,
+    Gate,
+    Silo,
 }
 
 pub struct BuildingStats {
@@ -389,6 +1065,9 @@ pub struct BuildingStats {
     pub max_health: f32,
     pub cost: u32,
     pub image: Image,
+    pub sight_range: f32,
+    // The footprint of the rubble left behind when it's destroyed, if there is any.
+    pub wreckage: Option<Vec2>,
 }
 
 impl Building {
@@ -400,6 +1079,12 @@ impl Building {
             Self::Pump => "Pump",
             Self::Armoury if plural => "Armouries",
             Self::Armoury => "Armoury",
+            Self::Wall if plural => "Walls",
+            Self::Wall => "Wall",
+            Self::Gate if plural => "Gates",
+            Self::Gate => "Gate",
+            Self::Silo if plural => "Silos",
+            Self::Silo => "Silo",
         }
     }
 
@@ -411,6 +1096,8 @@ impl Building {
                 max_health: 500.0,
                 cost: 200,
                 image: Image::BuildArmoury,
+                sight_range: 12.0,
+                wreckage: Some(Vec2::new(4.0, 8.0)),
             },
             Self::Pump => BuildingStats {
                 radius: 3.0,
@@ -418,6 +1105,38 @@ impl Building {
                 max_health: 200.0,
                 cost: 50,
                 image: Image::BuildPump,
+                sight_range: 8.0,
+                // Pumps sit on top of guysers, which need to stay free to build on again.
+                wreckage: None,
+            },
+            // These are per segment. The portraits reuse the armoury's picture for now.
+            Self::Wall => BuildingStats {
+                radius: walls::WALL_GRID / 2.0,
+                dimensions: Vec2::broadcast(walls::WALL_GRID),
+                max_health: 150.0,
+                cost: 10,
+                image: Image::BuildArmoury,
+                sight_range: 4.0,
+                wreckage: None,
+            },
+            Self::Gate => BuildingStats {
+                radius: walls::WALL_GRID / 2.0,
+                dimensions: Vec2::broadcast(walls::WALL_GRID),
+                max_health: 300.0,
+                cost: 25,
+                image: Image::BuildArmoury,
+                sight_range: 4.0,
+                wreckage: None,
+            },
+            // The superweapon. There isn't a model for it yet, so it's drawn as a stretched armoury.
+            Self::Silo => BuildingStats {
+                radius: 4.0,
+                dimensions: Vec2::new(6.0, 6.0),
+                max_health: 800.0,
+                cost: 400,
+                image: Image::BuildArmoury,
+                sight_range: 10.0,
+                wreckage: Some(Vec2::new(4.0, 4.0)),
             },
         }
     }
@@ -436,6 +1155,7 @@ impl Building {
         Side,
         Health,
         BuildingCompleteness,
+        SightRange,
     )> {
         let BuildingStats {
             radius,
@@ -443,9 +1163,16 @@ impl Building {
             max_health: _,
             cost: _,
             image: _,
+            sight_range,
+            wreckage: _,
         } = self.stats();
 
-        let handle = map.insert(position, dimensions)?;
+        let footprint = match self {
+            Self::Wall | Self::Gate => walls::footprint(dimensions),
+            Self::Armoury | Self::Pump | Self::Silo => dimensions,
+        };
+
+        let handle = map.insert(position, footprint)?;
 
         Some((
             Position(position),
@@ -456,6 +1183,7 @@ impl Building {
             side,
             Health(1.0),
             BuildingCompleteness(1.0),
+            SightRange(sight_range),
         ))
     }
 
@@ -479,21 +1207,24 @@ impl Building {
         match self {
             Building::Pump => {
                 entry.add_component(animations.pump.skin.clone());
-                entry.add_component(AnimationState {
-                    animation: 0,
-                    time: 0.0,
-                    total_time: animations.pump.animations[0].total_time,
-                });
+                entry.add_component(AnimationState::new(PUMP_ANIMATION));
                 entry.add_component(Cooldown(0.0));
             }
             Building::Armoury => {
-                entry.add_component(Abilities(vec![
-                    &Ability::RECRUIT_MOUSE_MARINE,
-                    &Ability::RECRUIT_ENGINEER,
-                    &Ability::SET_RECRUITMENT_WAYPOINT,
-                ]));
+                entry.add_component(Abilities(Ability::ARMOURY.to_vec()));
                 entry.add_component(RecruitmentQueue::new(position, self.stats().dimensions));
+                entry.add_component(Research::default());
+                entry.add_component(Detector(stealth::ARMOURY_DETECTION_RANGE));
             }
+            Building::Gate => {
+                entry.add_component(Gate::default());
+                entry.add_component(Abilities(vec![&Ability::LOCK_GATE]));
+            }
+            Building::Silo => {
+                entry.add_component(Superweapon::default());
+                entry.add_component(Abilities(vec![&Ability::LAUNCH_STRIKE]));
+            }
+            Building::Wall => {}
         }
 
         Some(entity)
@@ -513,30 +1244,27 @@ impl Building {
         match self {
             Building::Pump => {
                 buffer.add_component(entity, animations.pump.skin.clone());
-                buffer.add_component(
-                    entity,
-                    AnimationState {
-                        animation: 0,
-                        time: 0.0,
-                        total_time: animations.pump.animations[0].total_time,
-                    },
-                );
+                buffer.add_component(entity, AnimationState::new(PUMP_ANIMATION));
                 buffer.add_component(entity, Cooldown(0.0));
             }
             Building::Armoury => {
-                buffer.add_component(
-                    entity,
-                    Abilities(vec![
-                        &Ability::RECRUIT_MOUSE_MARINE,
-                        &Ability::RECRUIT_ENGINEER,
-                        &Ability::SET_RECRUITMENT_WAYPOINT,
-                    ]),
-                );
+                buffer.add_component(entity, Abilities(Ability::ARMOURY.to_vec()));
                 buffer.add_component(
                     entity,
                     RecruitmentQueue::new(position, self.stats().dimensions),
                 );
+                buffer.add_component(entity, Research::default());
+                buffer.add_component(entity, Detector(stealth::ARMOURY_DETECTION_RANGE));
             }
+            Building::Gate => {
+                buffer.add_component(entity, Gate::default());
+                buffer.add_component(entity, Abilities(vec![&Ability::LOCK_GATE]));
+            }
+            Building::Silo => {
+                buffer.add_component(entity, Superweapon::default());
+                buffer.add_component(entity, Abilities(vec![&Ability::LAUNCH_STRIKE]));
+            }
+            Building::Wall => {}
         }
 
         Some(entity)
@@ -546,7 +1274,30 @@ impl Building {
 pub struct RecruitmentQueue {
     percentage_progress: f32,
     pub queue: VecDeque<Unit>,
-    waypoint: Vec2,
+    waypoint: RallyPoint,
+}
+
+// Where newly recruited units head to.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RallyPoint {
+    Position(Vec2),
+    // Engineers help finish the pump on it, if there's one going up.
+    Guyser(Entity),
+    // A friendly unit for new recruits to follow.
+    Unit(Entity),
+}
+
+impl RallyPoint {
+    // `None` if whatever it was on has gone. Needs to read `Position`.
+    pub(crate) fn position(self, world: &impl EntityStore) -> Option<Vec2> {
+        match self {
+            Self::Position(position) => Some(position),
+            Self::Guyser(entity) | Self::Unit(entity) => <&Position>::query()
+                .get(world, entity)
+                .ok()
+                .map(|position| position.0),
+        }
+    }
 }
 
 impl RecruitmentQueue {
@@ -554,7 +1305,9 @@ impl RecruitmentQueue {
         Self {
             percentage_progress: 0.0,
             queue: VecDeque::new(),
-            waypoint: building_position + Vec2::new(0.0, building_dims.y / 2.0 + 2.0),
+            waypoint: RallyPoint::Position(
+                building_position + Vec2::new(0.0, building_dims.y / 2.0 + 2.0),
+            ),
         }
     }
 
@@ -567,51 +1320,113 @@ impl RecruitmentQueue {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(
+    Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, serde::Serialize, serde::Deserialize,
+)]
 pub enum Unit {
     MouseMarine,
     Engineer,
+    Medic,
+    Glider,
+}
+
+// Which set of model buffers a unit is drawn with.
+#[derive(Clone, Copy, serde::Deserialize)]
+pub enum UnitModel {
+    MouseMarine,
+    Engineer,
 }
 
+#[derive(Clone, Copy, serde::Deserialize)]
 pub struct UnitStats {
+    pub model: UnitModel,
     pub max_health: f32,
-    pub move_speed: f32,
+    pub max_speed: f32,
+    pub acceleration: f32,
+    pub turn_rate: f32,
     pub radius: f32,
     // None if the unit can't attack
     pub firing_range: Option<f32>,
+    // How long to wait between shots.
+    #[serde(default)]
+    pub reload_time: f32,
+    #[serde(default)]
+    pub accuracy: f32,
+    pub area_of_effect: Option<f32>,
     pub health_bar_height: f32,
     pub cost: u32,
+    // Used for both the recruit button and the unit's portrait.
+    pub image: Image,
     pub recruitment_time: f32,
+    pub sight_range: f32,
+    // Units without any active abilities don't need energy.
+    #[serde(default)]
+    pub max_energy: f32,
+    #[serde(default)]
+    pub healer: Option<Healer>,
+    #[serde(default)]
+    pub targeting: TargetPriorities,
+    #[serde(default)]
+    pub shield: Option<ShieldStats>,
+    // How close cloaked enemies have to be for this unit to see them.
+    #[serde(default)]
+    pub detection_range: Option<f32>,
+    // Flies over everything, see `Airborne`.
+    #[serde(default)]
+    pub airborne: bool,
+    // Can shoot at flying units.
+    #[serde(default)]
+    pub anti_air: bool,
 }
 
-enum MouseAnimation {
-    Build = 0,
-    Idle = 1,
-    Shoot = 2,
-    Walking = 3,
+// The stats for every kind of unit, from `units.toml`.
+#[derive(serde::Deserialize)]
+struct UnitKinds {
+    mouse_marine: UnitStats,
+    engineer: UnitStats,
+    medic: UnitStats,
+    glider: UnitStats,
+}
+
+static UNIT_KINDS: Lazy<UnitKinds> = Lazy::new(|| {
+    toml::from_str(include_str!("../units.toml")).expect("units.toml should be valid")
+});
+
+// The names of the clips in the mouse model.
+struct MouseAnimation;
+
+impl MouseAnimation {
+    const BUILD: &'static str = "build";
+    // The model doesn't have these either, so burrowing units just sink into the ground.
+    const BURROW: &'static str = "burrow";
+    const UNBURROW: &'static str = "unburrow";
+    // The mouse model doesn't have this one yet, so corpses just keep whatever pose they died in.
+    const DIE: &'static str = "die";
+    const IDLE: &'static str = "idle";
+    const SHOOT: &'static str = "shoot";
+    const WALK: &'static str = "walk";
+}
+
+// The simple way of adding a unit, for when it doesn't need to face a particular way or be given
+// something to do straight away.
+pub fn spawn_unit(
+    buffer: &mut CommandBuffer,
+    // This is only `None` when being run in a test
+    animations: Option<&ModelAnimations>,
+    unit: Unit,
+    side: Side,
+    position: Vec2,
+) -> Entity {
+    unit.add_to_world(buffer, animations, position, Facing(0.0), side, None)
 }
 
 impl Unit {
-    pub fn stats(self) -> UnitStats {
+    pub fn stats(self) -> &'static UnitStats {
         match self {
-            Self::MouseMarine => UnitStats {
-                max_health: 50.0,
-                firing_range: Some(10.0),
-                move_speed: 6.0,
-                radius: 1.0,
-                health_bar_height: 3.0,
-                cost: 100,
-                recruitment_time: 10.0,
-            },
-            Self::Engineer => UnitStats {
-                max_health: 40.0,
-                firing_range: None,
-                move_speed: 6.0,
-                radius: 1.0,
-                health_bar_height: 3.0,
-                cost: 50,
-                recruitment_time: 5.0,
-            },
+            Self::MouseMarine => &UNIT_KINDS.mouse_marine,
+            Self::Engineer => &UNIT_KINDS.engineer,
+            Self::Medic => &UNIT_KINDS.medic,
+            Self::Glider => &UNIT_KINDS.glider,
         }
     }
 
@@ -626,14 +1441,29 @@ impl Unit {
         starting_command: Option<Command>,
     ) -> Entity {
         let UnitStats {
+            model: _,
             max_health,
-            move_speed,
+            max_speed,
+            acceleration,
+            turn_rate,
             radius,
             firing_range,
+            reload_time,
+            accuracy,
+            area_of_effect,
             health_bar_height: _,
             cost: _,
+            image: _,
             recruitment_time: _,
-        } = self.stats();
+            sight_range,
+            max_energy,
+            healer,
+            targeting: _,
+            shield,
+            detection_range,
+            airborne,
+            anti_air,
+        } = *self.stats();
 
         let mut command_queue = CommandQueue::default();
         if let Some(starting_command) = starting_command {
@@ -650,9 +1480,15 @@ impl Unit {
             Avoidable,
             Selectable,
             Health(max_health),
+            Experience::default(),
             Cooldown(0.0),
-            MoveSpeed(move_speed),
+            MaxSpeed(max_speed),
+            Acceleration(acceleration),
+            TurnRate(turn_rate.to_radians()),
+            Velocity(Vec2::zero()),
             Radius(radius),
+            SightRange(sight_range),
+            PathVersion(0),
             // Uncomment to debug movement.
             // MovementDebugging::default(),
         ));
@@ -662,28 +1498,72 @@ impl Unit {
                 buffer.add_component(entity, CanBuild);
                 buffer.add_component(
                     entity,
-                    Abilities(vec![&Ability::BUILD_PUMP, &Ability::BUILD_ARMOURY]),
+                    Abilities(vec![
+                        &Ability::BUILD_PUMP,
+                        &Ability::BUILD_ARMOURY,
+                        &Ability::BUILD_WALL,
+                        &Ability::BUILD_GATE,
+                        &Ability::BUILD_SILO,
+                    ]),
+                );
+            }
+            Unit::Medic => {
+                buffer.add_component(entity, Abilities(vec![&Ability::HEAL]));
+            }
+            Unit::MouseMarine => {
+                buffer.add_component(
+                    entity,
+                    Abilities(vec![
+                        &Ability::THROW_GRENADE,
+                        &Ability::SPEED_BOOST,
+                        &Ability::CLOAK,
+                        &Ability::BURROW,
+                    ]),
                 );
             }
-            Unit::MouseMarine => {}
+            Unit::Glider => {}
+        }
+
+        if airborne {
+            buffer.add_component(entity, Airborne);
+        }
+
+        if anti_air {
+            buffer.add_component(entity, AntiAir);
+        }
+
+        if let Some(healer) = healer {
+            buffer.add_component(entity, healer);
+        }
+
+        if let Some(shield) = shield {
+            buffer.add_component(entity, Shield::new(shield));
+        }
+
+        if let Some(detection_range) = detection_range {
+            buffer.add_component(entity, Detector(detection_range));
+        }
+
+        if max_energy > 0.0 {
+            buffer.add_component(entity, Energy(max_energy));
+            buffer.add_component(entity, AbilityCooldowns::default());
         }
 
         if let Some(firing_range) = firing_range {
             buffer.add_component(entity, FiringRange(firing_range));
+            buffer.add_component(entity, Accuracy(accuracy));
+            buffer.add_component(entity, ReloadTime(reload_time));
             buffer.add_component(entity, CanAttack);
+            buffer.add_component(entity, Stance::default());
+        }
+
+        if let Some(area_of_effect) = area_of_effect {
+            buffer.add_component(entity, AreaOfEffect(area_of_effect));
         }
 
         if let Some(animations) = animations {
             buffer.add_component(entity, animations.mouse.skin.clone());
-            buffer.add_component(
-                entity,
-                AnimationState {
-                    animation: MouseAnimation::Idle as usize,
-                    time: 0.0,
-                    total_time: animations.mouse.animations[MouseAnimation::Idle as usize]
-                        .total_time,
-                },
-            );
+            buffer.add_component(entity, AnimationState::new(MouseAnimation::IDLE));
         }
 
         entity
@@ -749,8 +1629,121 @@ fn vec2_to_ncollide_point(point: Vec2) -> ncollide2d::math::Point<f32> {
 
 pub struct CheeseDropletPosition(Vec3);
 pub struct CheeseDropletVelocity(Vec3);
+
+#[derive(Clone, Copy, Debug)]
+pub enum ParticleEmitterKind {
+    MuzzleFlash,
+    BloodPuff,
+    BuildingDust,
+}
+
+// Emits a single burst of particles and then gets removed.
+pub struct ParticleEmitter {
+    kind: ParticleEmitterKind,
+    // Relative to the ground.
+    position: Vec3,
+    direction: Vec2,
+}
+
+impl ParticleEmitter {
+    pub fn new(kind: ParticleEmitterKind, position: Vec3, direction: Vec2) -> Self {
+        Self {
+            kind,
+            position,
+            direction,
+        }
+    }
+}
+
+pub struct Particle {
+    position: Vec3,
+    velocity: Vec3,
+    colour: Vec4,
+    size: f32,
+    gravity: f32,
+    age: f32,
+    lifetime: f32,
+}
 pub struct CanBuild;
 pub struct CanAttack;
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Deserialize)]
+pub enum HealTarget {
+    Units,
+    Buildings,
+}
+
+// Slowly patches up nearby friendly units (or buildings, for engineers) while standing around.
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+pub struct Healer {
+    pub target: HealTarget,
+    // Health per second.
+    pub rate: f32,
+    pub range: f32,
+}
+
+// How much a unit cares about each thing when picking something to shoot at by itself (i.e. when
+// idle, attack moving or patrolling), see `combat::target_score`. Distances are in map units and
+// everything else is weighed against them, so e.g. `attackers = 4.0` means that a unit that can shoot
+// back is worth walking 4 units further for.
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+#[serde(default)]
+pub struct TargetPriorities {
+    pub distance: f32,
+    // Multiplied by how much of its health the target has lost, from 0 to 1.
+    pub low_health: f32,
+    pub attackers: f32,
+    // Multiplied by the target's max health / 100, for going after the toughest things first.
+    pub armour: f32,
+    // Usually negative, so that buildings are left until everything else has been dealt with.
+    pub buildings: f32,
+}
+
+impl Default for TargetPriorities {
+    // Just go for the closest unit, and only then buildings.
+    fn default() -> Self {
+        Self {
+            distance: 1.0,
+            low_health: 0.0,
+            attackers: 0.0,
+            armour: 0.0,
+            buildings: -1000.0,
+        }
+    }
+}
+
+// What a healer is working on right now, so that the beam between them can be drawn.
+pub struct Healing(pub Entity);
+// How a unit reacts to enemies that it hasn't been told to attack.
+#[derive(PartialEq, Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Stance {
+    // Go after anything that comes near.
+    Aggressive,
+    // Go after anything that comes near, but walk back to where it was once the fight is over or
+    // it's chased too far.
+    Defensive,
+    // Never start a fight, not even when shot at.
+    Passive,
+}
+
+impl Stance {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Aggressive => Self::Defensive,
+            Self::Defensive => Self::Passive,
+            Self::Passive => Self::Aggressive,
+        }
+    }
+}
+
+impl Default for Stance {
+    fn default() -> Self {
+        Self::Aggressive
+    }
+}
+
+// Where a defensive unit was standing when it started a fight.
+pub struct Leash(pub Vec2);
 pub struct FullyBuilt;
 #[derive(Copy, Clone)]
 pub enum Agroed {
@@ -786,10 +1779,67 @@ fn nearest_point_within_building(
     building_pos + Vec2::new(x, y)
 }
 
+// What's left of a unit after it dies. It's a separate entity without any of the unit's components
+// so that nothing can select, target or count it by mistake.
+pub struct Corpse {
+    position: Vec2,
+    facing: f32,
+    side: Side,
+    age: f32,
+}
+
+impl Corpse {
+    // How long it takes to topple over.
+    const FALL_TIME: f32 = 0.5;
+    // How long it sticks around for, fading away, before being removed.
+    const LIFETIME: f32 = 5.0;
+
+    pub fn add_to_world(
+        buffer: &mut CommandBuffer,
+        unit: Entity,
+        position: Vec2,
+        side: Side,
+        world: &SubWorld,
+    ) {
+        // Units spawned in tests don't have skins, so there's nothing to leave behind.
+        if let Ok((facing, skin, animation_state)) =
+            <(&Facing, &crate::animation::Skin, &AnimationState)>::query().get(world, unit)
+        {
+            let mut animation_state = animation_state.clone();
+            animation_state.play_once(MouseAnimation::DIE, crate::animation::CROSSFADE_FRAMES);
+
+            buffer.push((
+                Corpse {
+                    position,
+                    facing: facing.0,
+                    side,
+                    age: 0.0,
+                },
+                skin.clone(),
+                animation_state,
+            ));
+        }
+    }
+
+    // From 0 (upright) to 1 (lying on the ground).
+    fn fallen(&self) -> f32 {
+        (self.age / Self::FALL_TIME).min(1.0)
+    }
+
+    // From 0 (just died) to 1 (gone).
+    fn faded(&self) -> f32 {
+        (self.age / Self::LIFETIME).min(1.0)
+    }
+}
+
 pub struct Explosion {
+    position: Vec2,
+    // Relative to the ground.
     translation_rotation: Mat4,
     progress: f32,
     max_size: f32,
+    // For the scorch mark that's left behind.
+    scorch_rotation: f32,
 }
 
 impl Explosion {
@@ -806,9 +1856,11 @@ impl Explosion {
             .into_homogeneous();
 
         Self {
+            position,
             translation_rotation: translation * rotation,
             progress: 0.0,
             max_size,
+            scorch_rotation: rng.gen_range(0.0, std::f32::consts::TAU),
         }
     }
 
@@ -825,6 +1877,104 @@ impl Explosion {
     }
 }
 
+// Left on the ground after an explosion. They stick around for a good while, but do fade away
+// eventually so that a long game doesn't pile up thousands of them.
+pub struct ScorchMark {
+    position: Vec2,
+    size: f32,
+    rotation: f32,
+    age: f32,
+}
+
+impl ScorchMark {
+    const LIFETIME: f32 = 120.0;
+    const FADE_TIME: f32 = 20.0;
+
+    fn from_explosion(explosion: &Explosion) -> Self {
+        Self {
+            position: explosion.position,
+            size: explosion.max_size * 1.5,
+            rotation: explosion.scorch_rotation,
+            age: 0.0,
+        }
+    }
+
+    fn alpha(&self) -> f32 {
+        ((Self::LIFETIME - self.age) / Self::FADE_TIME).clamp(0.0, 1.0)
+    }
+}
+
+// A short-lived point light from a gunshot or an explosion, that fades out over its lifetime.
+#[cfg(feature = "material-maps")]
+pub struct LightFlash {
+    // Relative to the ground.
+    position: Vec3,
+    colour: Vec3,
+    radius: f32,
+    age: f32,
+    lifetime: f32,
+}
+
+#[cfg(feature = "material-maps")]
+impl LightFlash {
+    pub fn muzzle_flash(position: Vec3) -> Self {
+        Self {
+            position,
+            colour: Vec3::new(1.0, 0.8, 0.4),
+            radius: 3.0,
+            age: 0.0,
+            lifetime: 0.1,
+        }
+    }
+
+    pub fn explosion(position: Vec2, size: f32) -> Self {
+        Self {
+            position: Vec3::new(position.x, 1.0, position.y),
+            colour: Vec3::new(1.0, 0.6, 0.2) * 2.0,
+            radius: size * 4.0,
+            age: 0.0,
+            lifetime: 0.5,
+        }
+    }
+
+    fn brightness(&self) -> f32 {
+        (1.0 - self.age / self.lifetime).max(0.0)
+    }
+}
+
+// Text that floats upwards from a point in the world while fading out, such as damage numbers.
+pub struct FloatingText {
+    text: String,
+    colour: Vec3,
+    position: Vec2,
+    // Relative to the ground.
+    height: f32,
+    // So that the text is hidden under the fog of war along with whatever it's floating above.
+    side: Side,
+    progress: f32,
+}
+
+impl FloatingText {
+    const DURATION: f32 = 1.0;
+    const RISE_SPEED: f32 = 1.5;
+
+    pub fn new(text: String, colour: Vec3, position: Vec2, height: f32, side: Side) -> Self {
+        Self {
+            text,
+            colour,
+            position,
+            height,
+            side,
+            progress: 0.0,
+        }
+    }
+
+    // Stay solid for most of the duration and fade out quickly at the end.
+    fn alpha(&self) -> f32 {
+        1.0 - self.progress.powi(3)
+    }
+}
+
 fn mix(a: f32, b: f32, factor: f32) -> f32 {
     a * (1.0 - factor) + b * factor
 }
@@ -847,3 +1997,14 @@ fn unit_under_building(building_position: Vec2, building_dims: Vec2, world: &Sub
             pos.x > left && pos.x < right && pos.y > top && pos.y < bottom
         })
 }
+
+#[test]
+fn unit_kinds_load_from_the_data_file() {
+    let marine = Unit::MouseMarine.stats();
+    assert_eq!(marine.firing_range, Some(10.0));
+    assert!(marine.reload_time > 0.0);
+
+    let engineer = Unit::Engineer.stats();
+    assert_eq!(engineer.firing_range, None);
+    assert_eq!(engineer.area_of_effect, None);
+}
@@ -0,0 +1,257 @@
+use super::*;
+use crate::resources::{DeltaTime, Settings};
+
+// How much energy units get back each second.
+const ENERGY_REGEN: f32 = 2.0;
+
+const GRENADE_DAMAGE: f32 = 20.0;
+// Slower than a bullet, so that there's a chance to get out of the way.
+const GRENADE_SPEED: f32 = 12.0;
+const HEAL_AMOUNT: f32 = 20.0;
+const SPEED_BOOST_DURATION: f32 = 5.0;
+pub(super) const SPEED_BOOST_MULTIPLIER: f32 = 1.5;
+const CLOAK_DURATION: f32 = 10.0;
+
+// Whether a unit has an ability and is able to use it right now. Burrowed units can't use
+// anything. Needs to read `Burrow` as well as the components in the query.
+pub(super) fn can_cast(ability: ActiveAbility, caster: Entity, world: &SubWorld) -> bool {
+    <(&Abilities, &Energy, &AbilityCooldowns)>::query()
+        .filter(!component::<Burrow>())
+        .get(world, caster)
+        .map(|(abilities, energy, cooldowns)| {
+            abilities
+                .0
+                .iter()
+                .any(|other| other.ability_type == AbilityType::Active(ability))
+                && energy.0 >= ability.stats().energy_cost
+                && cooldowns.remaining(ability) == 0.0
+        })
+        .unwrap_or(false)
+}
+
+// How much of the cooldown the readiest of the casters has left, from 0 (ready) to 1 (just used).
+pub(super) fn cooldown_fraction(
+    ability: ActiveAbility,
+    casters: &[Entity],
+    world: &SubWorld,
+) -> f32 {
+    casters
+        .iter()
+        .filter_map(|caster| <&AbilityCooldowns>::query().get(world, *caster).ok())
+        .map(|cooldowns| cooldowns.remaining(ability) / ability.stats().cooldown)
+        .fold(None, |min: Option<f32>, fraction| {
+            Some(min.map_or(fraction, |min| min.min(fraction)))
+        })
+        .unwrap_or(0.0)
+}
+
+#[legion::system(for_each)]
+pub fn recharge_abilities(
+    unit: &Unit,
+    energy: &mut Energy,
+    cooldowns: &mut AbilityCooldowns,
+    #[resource] delta_time: &DeltaTime,
+) {
+    energy.0 = (energy.0 + ENERGY_REGEN * delta_time.0).min(unit.stats().max_energy);
+
+    for remaining in cooldowns.0.values_mut() {
+        *remaining = (*remaining - delta_time.0).max(0.0);
+    }
+
+    cooldowns.0.retain(|_, remaining| *remaining > 0.0);
+}
+
+#[legion::system(for_each)]
+pub fn wear_off_speed_boosts(
+    entity: &Entity,
+    speed_boost: &mut SpeedBoost,
+    #[resource] delta_time: &DeltaTime,
+    buffer: &mut CommandBuffer,
+) {
+    speed_boost.remaining -= delta_time.0;
+
+    if speed_boost.remaining <= 0.0 {
+        buffer.remove_component::<SpeedBoost>(*entity);
+    }
+}
+
+// Units walk into range of their target in `set_movement_paths`, and this is where the ability
+// actually gets used once they're there. The command is used up either way, so a unit that's run
+// out of energy by the time it gets there just moves on to whatever's next.
+#[legion::system(for_each)]
+#[filter(component::<Position>() & component::<Side>())]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Experience)]
+#[write_component(Health)]
+pub fn cast_abilities(
+    entity: &Entity,
+    facing: &mut Facing,
+    energy: &mut Energy,
+    cooldowns: &mut AbilityCooldowns,
+    command_queue: &mut CommandQueue,
+    #[resource] settings: &Settings,
+    world: &mut SubWorld,
+    buffer: &mut CommandBuffer,
+) {
+    let (ability, target) = match command_queue.0.front() {
+        Some(&Command::Cast {
+            ability,
+            target,
+            state: ActionState::InRange,
+        }) => (ability, target),
+        _ => return,
+    };
+
+    command_queue.0.pop_front();
+
+    let stats = ability.stats();
+
+    if energy.0 < stats.energy_cost || cooldowns.remaining(ability) > 0.0 {
+        return;
+    }
+
+    let (position, side) = <(&Position, &Side)>::query()
+        .get(world, *entity)
+        .map(|(position, side)| (position.0, *side))
+        .expect("We've applied a filter to this system for Position");
+
+    let target_position = match target.position(position, world) {
+        Some(target_position) => target_position,
+        None => return,
+    };
+
+    let vector = target_position - position;
+    if vector.mag_sq() > 0.0 {
+        facing.0 = deterministic::angle(vector);
+    }
+
+    match (ability, target) {
+        (ActiveAbility::Grenade, _) => {
+            let multiplier = <&Experience>::query()
+                .get(world, *entity)
+                .map(|experience| experience.multiplier())
+                .unwrap_or(1.0);
+
+            let area_of_effect = match stats.targeting {
+                Targeting::Area(radius) => radius,
+                _ => 0.0,
+            };
+
+            // Grenades hit everything around where they land, so `target` is never used.
+            buffer.push((
+                Position(position),
+                Bullet {
+                    source: *entity,
+                    target: *entity,
+                    side,
+                    damage: GRENADE_DAMAGE * multiplier,
+                    area_of_effect: Some(area_of_effect),
+                    start: position,
+                    impact: target_position,
+                },
+                Facing(facing.0),
+                MaxSpeed(GRENADE_SPEED),
+            ));
+        }
+        (ActiveAbility::Heal, AbilityTarget::Unit(target)) => {
+            let max_health = match <(&Unit, Option<&Experience>, &Side)>::query().get(world, target)
+            {
+                Ok((unit, experience, target_side)) if *target_side == side => {
+                    unit.stats().max_health
+                        * experience
+                            .map(|experience| experience.multiplier())
+                            .unwrap_or(1.0)
+                }
+                _ => return,
+            };
+
+            if let Ok(health) = <&mut Health>::query().get_mut(world, target) {
+                let healed = (max_health - health.0).min(HEAL_AMOUNT);
+                health.0 += healed;
+
+                if settings.damage_numbers && healed > 0.0 {
+                    buffer.push((FloatingText::new(
+                        format!("+{}", healed.round()),
+                        Vec3::new(0.3, 1.0, 0.3),
+                        target_position,
+                        3.0,
+                        side,
+                    ),));
+                }
+            }
+        }
+        (ActiveAbility::Heal, _) => return,
+        (ActiveAbility::SpeedBoost, _) => {
+            buffer.add_component(
+                *entity,
+                SpeedBoost {
+                    remaining: SPEED_BOOST_DURATION,
+                },
+            );
+        }
+        (ActiveAbility::Cloak, _) => {
+            buffer.add_component(*entity, Cloaked::new(CLOAK_DURATION));
+        }
+    }
+
+    energy.0 -= stats.energy_cost;
+    cooldowns.0.insert(ability, stats.cooldown);
+}
+
+#[test]
+fn healing_uses_energy_and_waits_for_the_cooldown() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    resources.insert(Settings::default());
+
+    let mut buffer = CommandBuffer::new(&world);
+    let medic = spawn_unit(&mut buffer, None, Unit::Medic, Side::Green, Vec2::zero());
+    let marine = spawn_unit(
+        &mut buffer,
+        None,
+        Unit::MouseMarine,
+        Side::Green,
+        Vec2::new(3.0, 0.0),
+    );
+    buffer.add_component(marine, Health(10.0));
+    buffer.flush(&mut world);
+
+    let mut schedule = Schedule::builder()
+        .add_system(cast_abilities_system())
+        .build();
+
+    let heal = || Command::new_cast(ActiveAbility::Heal, AbilityTarget::Unit(marine));
+    let health = |world: &World| <&Health>::query().get(world, marine).unwrap().0;
+
+    <&mut CommandQueue>::query()
+        .get_mut(&mut world, medic)
+        .unwrap()
+        .0
+        .push_back(heal());
+    schedule.execute(&mut world, &mut resources);
+
+    assert_eq!(health(&world), 10.0 + HEAL_AMOUNT);
+    let (energy, cooldowns) = <(&Energy, &AbilityCooldowns)>::query()
+        .get(&world, medic)
+        .unwrap();
+    assert_eq!(
+        energy.0,
+        Unit::Medic.stats().max_energy - ActiveAbility::Heal.stats().energy_cost
+    );
+    assert_eq!(
+        cooldowns.remaining(ActiveAbility::Heal),
+        ActiveAbility::Heal.stats().cooldown
+    );
+
+    // Still cooling down, so this one doesn't do anything.
+    <&mut CommandQueue>::query()
+        .get_mut(&mut world, medic)
+        .unwrap()
+        .0
+        .push_back(heal());
+    schedule.execute(&mut world, &mut resources);
+
+    assert_eq!(health(&world), 10.0 + HEAL_AMOUNT);
+}
@@ -105,7 +105,7 @@ pub fn follow_ai_build_orders(
                         .iter_mut(world)
                         .filter(|(_, side)| **side != player_side.0)
                         .for_each(|(queue, _)| {
-                            queue.waypoint = *position;
+                            queue.waypoint = RallyPoint::Position(*position);
                         })
                 }
                 AiBuildOrderItem::AttackMove(position) => {
@@ -0,0 +1,79 @@
+use super::*;
+use crate::renderer::{DecalBuffer, DecalKind};
+use rendering::decal_instance;
+
+// Flying units go over everything in a straight line instead of pathfinding around it, and only
+// bump into other flying units. They can only be shot at by units with anti-air weapons, splash
+// damage doesn't reach them, and they can't capture anything.
+pub struct Airborne;
+
+// Can shoot at flying units as well as things on the ground.
+pub struct AntiAir;
+
+const FLYING_HEIGHT: f32 = 5.0;
+
+// How far above the ground a kind of unit is drawn.
+pub(super) fn height(unit: Unit) -> f32 {
+    if unit.stats().airborne {
+        FLYING_HEIGHT
+    } else {
+        0.0
+    }
+}
+
+// Whether `attacker` has a weapon that can reach `target`. Needs to read `Airborne` and `AntiAir`.
+pub(super) fn can_hit(attacker: Entity, target: Entity, world: &SubWorld) -> bool {
+    <&Airborne>::query().get(world, target).is_err()
+        || <&AntiAir>::query().get(world, attacker).is_ok()
+}
+
+// Whether two units are on the same layer, so that they need to move around each other.
+pub(super) fn same_layer(a: Option<&Airborne>, b: Option<&Airborne>) -> bool {
+    a.is_some() == b.is_some()
+}
+
+// Flying units are clicked on at the spot on the ground underneath them, which isn't obvious from
+// up in the air, so that spot gets a shadow.
+#[legion::system(for_each)]
+#[filter(component::<Airborne>())]
+pub fn render_flying_shadows(
+    position: &Position,
+    side: &Side,
+    radius: &Radius,
+    cloaked: Option<&Cloaked>,
+    #[resource] decal_buffer: &mut DecalBuffer,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] terrain: &Terrain,
+) {
+    if !fog_of_war.can_see(player_side.0, *side, position.0)
+        || stealth::is_hidden(cloaked, player_side.0, *side)
+    {
+        return;
+    }
+
+    decal_buffer.push(
+        DecalKind::Scorch,
+        decal_instance(
+            terrain,
+            position.0,
+            radius.0 * 2.0,
+            0.0,
+            Vec4::new(0.0, 0.0, 0.0, 0.5),
+        ),
+    );
+}
+
+#[test]
+fn only_anti_air_can_hit_flying_units() {
+    let mut world = World::default();
+    let marine = world.push((Side::Green, AntiAir));
+    let engineer = world.push((Side::Green,));
+    let glider = world.push((Side::Purple, Airborne));
+    let (subworld, _) = world.split::<(&Airborne, &AntiAir)>();
+
+    assert!(can_hit(marine, glider, &subworld));
+    assert!(!can_hit(engineer, glider, &subworld));
+    assert!(can_hit(glider, marine, &subworld));
+    assert!(can_hit(engineer, marine, &subworld));
+}
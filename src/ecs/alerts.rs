@@ -0,0 +1,152 @@
+use super::*;
+use crate::audio::{Sound, SoundEvents};
+use crate::resources::TotalTime;
+
+// Warnings about the player's units and buildings being attacked somewhere off screen. Each alert
+// plays a warning sound and flares up on the minimap, and space (by default) jumps the camera to
+// the most recent one.
+#[derive(Default)]
+pub struct Alerts {
+    // Where the player's things were damaged this tick, pushed by `handle_damaged`.
+    damaged: Vec<Vec2>,
+    // Newest last.
+    recent: Vec<Alert>,
+    last_sound: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Alert {
+    position: Vec2,
+    time: f32,
+}
+
+// A fight keeps on doing damage, so further damage this close to an alert doesn't make a new one
+// until the old one has been around for a while.
+const ALERT_RADIUS: f32 = 15.0;
+const ALERT_REPEAT_TIME: f32 = 20.0;
+// Alerts are forgotten after this long, so the camera doesn't jump to a fight that's long over.
+const ALERT_LIFETIME: f32 = 30.0;
+// Even when lots of different places are attacked, the warning doesn't play more often than this.
+const SOUND_COOLDOWN: f32 = 5.0;
+// How long an alert flares on the minimap for.
+const FLARE_DURATION: f32 = 3.0;
+
+impl Alerts {
+    pub fn damaged(&mut self, position: Vec2) {
+        self.damaged.push(position);
+    }
+
+    // Flares up straight away without any of the throttling, for things the player needs to know
+    // about even if they're on screen, such as an incoming strike. The sound is up to the caller.
+    pub fn ping(&mut self, position: Vec2, time: f32) {
+        self.recent.push(Alert { position, time });
+    }
+
+    // The alerts that are still flaring on the minimap, and how far through the flare they are.
+    pub fn flares(&self, time: f32) -> impl Iterator<Item = (Vec2, f32)> + '_ {
+        self.recent
+            .iter()
+            .map(move |alert| (alert.position, (time - alert.time) / FLARE_DURATION))
+            .filter(|(_, progress)| *progress < 1.0)
+    }
+
+    // Returns whether the warning sound should be played.
+    fn update(&mut self, time: f32, on_screen: impl Fn(Vec2) -> bool) -> bool {
+        self.recent
+            .retain(|alert| time - alert.time < ALERT_LIFETIME);
+
+        let mut any_new = false;
+
+        for position in self.damaged.drain(..) {
+            let already_alerted = self.recent.iter().any(|alert| {
+                (alert.position - position).mag_sq() < ALERT_RADIUS * ALERT_RADIUS
+                    && time - alert.time < ALERT_REPEAT_TIME
+            });
+
+            if already_alerted || on_screen(position) {
+                continue;
+            }
+
+            self.recent.push(Alert { position, time });
+            any_new = true;
+        }
+
+        let sound_ready = match self.last_sound {
+            Some(last) => time - last >= SOUND_COOLDOWN,
+            None => true,
+        };
+
+        if any_new && sound_ready {
+            self.last_sound = Some(time);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn latest(&self) -> Option<Vec2> {
+        self.recent.last().map(|alert| alert.position)
+    }
+}
+
+#[legion::system]
+pub fn update_alerts(
+    #[resource] alerts: &mut Alerts,
+    #[resource] camera: &mut Camera,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] rts_controls: &RtsControls,
+    #[resource] total_time: &TotalTime,
+    #[resource] sound_events: &mut SoundEvents,
+) {
+    let screen = SelectBox::new(
+        camera,
+        screen_dimensions,
+        Vec2::zero(),
+        screen_dimensions.as_vec(),
+    );
+
+    if alerts.update(total_time.0, |position| screen.contains(position)) {
+        sound_events.play(Sound::Alert);
+    }
+
+    if rts_controls.jump_to_alert_pressed {
+        if let Some(position) = alerts.latest() {
+            camera.jump_to(position);
+        }
+    }
+}
+
+#[test]
+fn alerts_are_throttled() {
+    let mut alerts = Alerts::default();
+    let off_screen = |_| false;
+
+    alerts.damaged(Vec2::new(0.0, 0.0));
+    alerts.damaged(Vec2::new(5.0, 0.0));
+    assert!(alerts.update(0.0, off_screen));
+    assert_eq!(alerts.recent.len(), 1);
+
+    // Somewhere else, but too soon for the sound to play again.
+    alerts.damaged(Vec2::new(50.0, 0.0));
+    assert!(!alerts.update(1.0, off_screen));
+    assert_eq!(alerts.latest(), Some(Vec2::new(50.0, 0.0)));
+    assert_eq!(alerts.flares(2.0).count(), 2);
+    assert_eq!(alerts.flares(3.5).count(), 1);
+
+    // The same fight still going on.
+    alerts.damaged(Vec2::new(2.0, 0.0));
+    assert!(!alerts.update(10.0, off_screen));
+    assert_eq!(alerts.recent.len(), 2);
+
+    // Nothing happens for things the player can already see.
+    alerts.damaged(Vec2::new(-50.0, 0.0));
+    assert!(!alerts.update(11.0, |_| true));
+    assert_eq!(alerts.recent.len(), 2);
+
+    alerts.damaged(Vec2::new(-50.0, 0.0));
+    assert!(alerts.update(12.0, off_screen));
+
+    // Long enough for them all to be forgotten.
+    alerts.update(60.0, off_screen);
+    assert_eq!(alerts.latest(), None);
+}
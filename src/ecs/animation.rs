@@ -1,65 +1,80 @@
-use super::{AnimationState, Building, Command, CommandQueue, FullyBuilt, MouseAnimation};
-use crate::animation::Skin;
+use super::{Building, Burrow, Command, CommandQueue, FullyBuilt, MouseAnimation, Velocity};
+use crate::animation::{AnimationState, CROSSFADE_FRAMES};
 use crate::assets::ModelAnimations;
 use crate::resources::DeltaTime;
 use legion::component;
 
+// The speed that the walk animation looks right at.
+const WALK_ANIMATION_SPEED: f32 = 6.0;
+
 #[legion::system(for_each)]
 pub fn progress_animations(
-    skin: &mut Skin,
     animation_state: &mut AnimationState,
     commands: &CommandQueue,
+    velocity: &Velocity,
+    burrow: Option<&Burrow>,
     #[resource] animations: &ModelAnimations,
     #[resource] delta_time: &DeltaTime,
 ) {
-    let animation = match commands.0.front() {
-        Some(&Command::MoveTo { .. }) => MouseAnimation::Walking,
+    if let Some(burrow) = burrow {
+        animation_state.play_once(burrow.animation(), CROSSFADE_FRAMES);
+        animation_state.speed = 1.0;
+        animation_state.advance(delta_time.0, &animations.mouse.animations);
+        return;
+    }
+
+    let clip = match commands.0.front() {
+        Some(&Command::MoveTo { .. }) | Some(&Command::Patrol { .. }) => MouseAnimation::WALK,
         Some(&Command::Attack { ref state, .. }) => {
             if state.is_out_of_range() {
-                MouseAnimation::Walking
+                MouseAnimation::WALK
             } else {
-                MouseAnimation::Shoot
+                MouseAnimation::SHOOT
             }
         }
         Some(&Command::Build { ref state, .. }) => {
             if state.is_out_of_range() {
-                MouseAnimation::Walking
+                MouseAnimation::WALK
             } else {
-                MouseAnimation::Build
+                MouseAnimation::BUILD
             }
         }
-        None => MouseAnimation::Idle,
-    } as usize;
+        Some(&Command::Cast { ref state, .. }) if state.is_out_of_range() => MouseAnimation::WALK,
+        Some(Command::Follow { path, .. }) | Some(Command::Guard { path, .. })
+            if !path.is_empty() =>
+        {
+            MouseAnimation::WALK
+        }
+        Some(&Command::Cast { .. })
+        | Some(&Command::Follow { .. })
+        | Some(&Command::Guard { .. })
+        | Some(&Command::HoldPosition)
+        | None => MouseAnimation::IDLE,
+    };
 
-    if animation != animation_state.animation {
-        animation_state.animation = animation;
-        animation_state.time = 0.0;
-        animation_state.total_time = animations.mouse.animations[animation].total_time;
+    animation_state.play(clip, CROSSFADE_FRAMES);
+
+    animation_state.speed = if clip == MouseAnimation::WALK {
+        velocity.0.mag() / WALK_ANIMATION_SPEED
     } else {
-        animation_state.time += delta_time.0;
-        animation_state.time %= animation_state.total_time;
-    }
+        1.0
+    };
 
-    animations.mouse.animations[animation_state.animation].animate(skin, animation_state.time);
+    animation_state.advance(delta_time.0, &animations.mouse.animations);
 }
 
 #[legion::system(for_each)]
 #[filter(component::<FullyBuilt>())]
 pub fn progress_building_animations(
     building: &Building,
-    skin: &mut Skin,
     animation_state: &mut AnimationState,
     #[resource] animations: &ModelAnimations,
     #[resource] delta_time: &DeltaTime,
 ) {
-    animation_state.time += delta_time.0;
-    animation_state.time %= animation_state.total_time;
-
     match building {
         Building::Pump => {
-            animations.pump.animations[animation_state.animation]
-                .animate(skin, animation_state.time);
+            animation_state.advance(delta_time.0, &animations.pump.animations);
         }
-        Building::Armoury => {}
+        Building::Armoury | Building::Wall | Building::Gate | Building::Silo => {}
     }
 }
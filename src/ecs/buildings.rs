@@ -1,11 +1,16 @@
 use super::{
     nearest_point_within_building, ActionState, Building, BuildingCompleteness,
-    CheeseGuyserBuiltOn, Command, CommandQueue, Cooldown, Facing, FullyBuilt, Health, Position,
-    RecruitmentQueue, Side,
+    CheeseGuyserBuiltOn, Command, CommandQueue, Cooldown, Facing, FullyBuilt, Health,
+    ParticleEmitter, ParticleEmitterKind, Position, RallyPoint, RecruitmentQueue, Side, Unit,
+    Upgrades,
 };
 use crate::assets::ModelAnimations;
+use crate::deterministic;
+use crate::pathfinding::Map;
 use crate::resources::{CheeseCoins, DeltaTime, GameStats, PlayerSide};
 use legion::{component, systems::CommandBuffer, world::SubWorld, Entity, EntityStore, IntoQuery};
+use rand::Rng;
+use ultraviolet::Vec3;
 
 #[legion::system(for_each)]
 #[filter(component::<Position>())]
@@ -19,8 +24,8 @@ pub fn build_buildings(
     command_queue: &mut CommandQueue,
     facing: &mut Facing,
     #[resource] delta_time: &DeltaTime,
-    #[resource] player_side: &PlayerSide,
     #[resource] stats: &mut GameStats,
+    #[resource] rng: &mut rand::rngs::SmallRng,
     world: &mut SubWorld,
     buffer: &mut CommandBuffer,
 ) {
@@ -51,20 +56,28 @@ pub fn build_buildings(
         let max = building.stats().max_health;
 
         let vector = building_pos.0 - position;
-        facing.0 = vector.y.atan2(vector.x);
+        facing.0 = deterministic::angle(vector);
 
         health.0 = (health.0 + health_increase_this_tick).min(max);
         completeness.0 = (completeness.0 + health_increase_this_tick).min(max);
 
+        // Kick up some dust in between the builder and the building every now and then.
+        if completeness.0 < max && rng.gen_range(0.0, 1.0) < 0.25 {
+            let dust_position = position + vector.normalized() * 1.5;
+            buffer.push((ParticleEmitter::new(
+                ParticleEmitterKind::BuildingDust,
+                Vec3::new(dust_position.x, 0.2, dust_position.y),
+                vector.normalized(),
+            ),));
+        }
+
         if health.0 == max {
             pop_front = true;
         }
 
         if completeness.0 == max {
             buffer.add_component(*target, FullyBuilt);
-            if *side == player_side.0 {
-                stats.buildings_built += 1;
-            }
+            stats.side_mut(*side).buildings_built += 1;
         }
     }
 
@@ -81,25 +94,41 @@ pub fn generate_cheese_coins(
     cooldown: &mut Cooldown,
     #[resource] player_side: &PlayerSide,
     #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] stats: &mut GameStats,
+    #[resource] upgrades: &Upgrades,
 ) {
-    if cooldown.0 == 0.0 && building == &Building::Pump && side == &player_side.0 {
+    if cooldown.0 == 0.0 && building == &Building::Pump {
+        let cheese = 2 + upgrades.modifiers(*side).cheese_per_pump;
         // Reminder: no delta time stuff needed here because that's done in the cooldown code.
-        cheese_coins.0 += 2;
+        if side == &player_side.0 {
+            cheese_coins.0 += cheese;
+        }
+        stats.side_mut(*side).cheese_gathered += cheese;
         cooldown.0 = 0.5;
     }
 }
 
 #[legion::system(for_each)]
-#[filter(component::<FullyBuilt>())]
+#[filter(component::<FullyBuilt>() & component::<Position>())]
+#[read_component(Position)]
+#[read_component(CheeseGuyserBuiltOn)]
+#[read_component(FullyBuilt)]
 pub fn progress_recruitment_queue(
-    building_position: &Position,
+    entity: &Entity,
     building: &Building,
     recruitment_queue: &mut RecruitmentQueue,
     side: &Side,
     #[resource] animations: &ModelAnimations,
     #[resource] delta_time: &DeltaTime,
+    #[resource] stats: &mut GameStats,
+    #[resource] map: &Map,
+    world: &SubWorld,
     buffer: &mut CommandBuffer,
 ) {
+    let building_position = <&Position>::query()
+        .get(world, *entity)
+        .expect("We've applied a filter to this system for Position");
+
     if let Some(unit) = recruitment_queue.queue.front().cloned() {
         let recruitment_time = unit.stats().recruitment_time;
         recruitment_queue.percentage_progress += delta_time.0 / recruitment_time;
@@ -107,26 +136,28 @@ pub fn progress_recruitment_queue(
             recruitment_queue.percentage_progress -= 1.0;
             recruitment_queue.queue.pop_front();
 
+            let rally_position = recruitment_queue.waypoint.position(world);
+
             let start_point = nearest_point_within_building(
-                recruitment_queue.waypoint,
+                rally_position.unwrap_or(building_position.0),
                 unit.stats().radius,
                 building_position.0,
                 building.stats().dimensions,
             );
+            // Something else could have been built right up against the armoury.
+            let start_point = map.nearest_passable_point(start_point, unit.stats().radius);
 
-            let command = Command::MoveTo {
-                target: recruitment_queue.waypoint,
-                attack_move: true,
-                path: Vec::new(),
-            };
+            let command = rally_command(recruitment_queue.waypoint, unit, map, world);
             unit.add_to_world(
                 buffer,
                 Some(animations),
                 start_point,
                 Facing(0.0),
                 *side,
-                Some(command),
+                command,
             );
+
+            stats.side_mut(*side).units_recruited += 1;
         }
     } else {
         // If a unit just finished off the queue and there are no more units in the queue,
@@ -135,6 +166,48 @@ pub fn progress_recruitment_queue(
     }
 }
 
+// What a new recruit does when it comes out of the building. Whatever the rally point was on might
+// have gone, in which case they just stay by the building.
+fn rally_command(
+    rally_point: RallyPoint,
+    unit: Unit,
+    map: &Map,
+    world: &SubWorld,
+) -> Option<Command> {
+    let radius = unit.stats().radius;
+
+    if let RallyPoint::Unit(target) = rally_point {
+        return <&Position>::query()
+            .get(world, target)
+            .ok()
+            .map(|_| Command::new_follow(target));
+    }
+
+    if let RallyPoint::Guyser(guyser) = rally_point {
+        let unfinished_pump = <&CheeseGuyserBuiltOn>::query()
+            .get(world, guyser)
+            .ok()
+            .map(|built_on| built_on.pump)
+            .filter(|pump| {
+                <&Position>::query()
+                    .filter(!component::<FullyBuilt>())
+                    .get(world, *pump)
+                    .is_ok()
+            });
+
+        if let (Some(pump), Unit::Engineer) = (unfinished_pump, unit) {
+            return Some(Command::new_build(pump));
+        }
+    }
+
+    rally_point.position(world).map(|position| Command::MoveTo {
+        // Guysers and pumps are in the way.
+        target: map.nearest_passable_point(position, radius),
+        attack_move: true,
+        path: Vec::new(),
+    })
+}
+
 #[legion::system(for_each)]
 // I think we need this :^(
 #[read_component(Position)]
@@ -148,3 +221,45 @@ pub fn free_up_cheese_guysers(
         buffer.remove_component::<CheeseGuyserBuiltOn>(*entity);
     }
 }
+
+#[test]
+fn engineers_rally_to_unfinished_pumps() {
+    use legion::World;
+    use ultraviolet::Vec2;
+
+    let mut world = World::default();
+    let map = Map::new();
+    let position = Vec2::new(10.0, 10.0);
+
+    let pump = world.push((Position(position), Building::Pump));
+    let guyser = world.push((Position(position), CheeseGuyserBuiltOn { pump }));
+    let marine = world.push((Position(Vec2::new(-10.0, 0.0)), Unit::MouseMarine));
+
+    let rally = |world: &mut World, rally_point, unit| {
+        let (subworld, _) = world.split::<(&Position, &CheeseGuyserBuiltOn, &FullyBuilt)>();
+        rally_command(rally_point, unit, &map, &subworld)
+    };
+
+    assert!(matches!(
+        rally(&mut world, RallyPoint::Guyser(guyser), Unit::Engineer),
+        Some(Command::Build { target, .. }) if target == pump
+    ));
+    // Everyone else just goes over to it.
+    assert!(matches!(
+        rally(&mut world, RallyPoint::Guyser(guyser), Unit::MouseMarine),
+        Some(Command::MoveTo { .. })
+    ));
+
+    world.entry(pump).unwrap().add_component(FullyBuilt);
+    assert!(matches!(
+        rally(&mut world, RallyPoint::Guyser(guyser), Unit::Engineer),
+        Some(Command::MoveTo { .. })
+    ));
+
+    assert!(matches!(
+        rally(&mut world, RallyPoint::Unit(marine), Unit::MouseMarine),
+        Some(Command::Follow { target, .. }) if target == marine
+    ));
+    world.remove(marine);
+    assert!(rally(&mut world, RallyPoint::Unit(marine), Unit::MouseMarine).is_none());
+}
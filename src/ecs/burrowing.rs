@@ -0,0 +1,154 @@
+use super::*;
+use crate::resources::DeltaTime;
+
+// Burrowed units dig themselves into the ground where they can't move, fight or be seen by the
+// enemy. Hiding works the same way as a cloak that never wears off, so detectors still reveal them.
+// Unburrowing is quicker than burrowing so that they can spring an ambush.
+const BURROW_TIME: f32 = 1.5;
+const UNBURROW_TIME: f32 = 0.5;
+// How far into the ground a fully burrowed unit sinks. Their owner can still see the tops of their
+// heads.
+pub(super) const BURROW_DEPTH: f32 = 1.8;
+
+#[derive(Copy, Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Burrow {
+    // Digging down, with how much time is left.
+    Burrowing(f32),
+    Burrowed,
+    // Climbing back out, with how much time is left.
+    Unburrowing(f32),
+}
+
+impl Burrow {
+    // How far down the unit is, from 0 to 1.
+    pub fn depth(self) -> f32 {
+        match self {
+            Self::Burrowing(remaining) => 1.0 - remaining / BURROW_TIME,
+            Self::Burrowed => 1.0,
+            Self::Unburrowing(remaining) => remaining / UNBURROW_TIME,
+        }
+    }
+
+    pub(super) fn animation(self) -> &'static str {
+        match self {
+            Self::Burrowing(_) | Self::Burrowed => MouseAnimation::BURROW,
+            Self::Unburrowing(_) => MouseAnimation::UNBURROW,
+        }
+    }
+}
+
+// Burrows all of the units that can, unless they're all burrowed already, in which case they come
+// back up. Units that are in the middle of coming up have to finish first.
+pub fn toggle_burrow(units: &[Entity], world: &mut SubWorld, buffer: &mut CommandBuffer) {
+    let units: Vec<Entity> = units
+        .iter()
+        .copied()
+        .filter(|unit| {
+            <&Abilities>::query()
+                .get(world, *unit)
+                .map(|abilities| {
+                    abilities
+                        .0
+                        .iter()
+                        .any(|ability| ability.ability_type == AbilityType::Burrow)
+                })
+                .unwrap_or(false)
+        })
+        .collect();
+
+    let burrow = units.iter().any(|unit| {
+        !matches!(
+            <&Burrow>::query().get(world, *unit),
+            Ok(Burrow::Burrowing(_) | Burrow::Burrowed)
+        )
+    });
+
+    for unit in units {
+        match <&mut Burrow>::query().get_mut(world, unit) {
+            Ok(state @ (Burrow::Burrowing(_) | Burrow::Burrowed)) if !burrow => {
+                *state = Burrow::Unburrowing(UNBURROW_TIME * state.depth());
+                buffer.remove_component::<Cloaked>(unit);
+            }
+            Err(_) if burrow => {
+                if let Ok(commands) = <&mut CommandQueue>::query().get_mut(world, unit) {
+                    commands.0.clear();
+                }
+                // Otherwise defensive units would try to walk back to where they were.
+                buffer.remove_component::<Leash>(unit);
+                buffer.add_component(unit, Burrow::Burrowing(BURROW_TIME));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[legion::system(for_each)]
+pub fn progress_burrowing(
+    entity: &Entity,
+    burrow: &mut Burrow,
+    velocity: &mut Velocity,
+    #[resource] delta_time: &DeltaTime,
+    buffer: &mut CommandBuffer,
+) {
+    // Units don't slide around while they're in the ground.
+    velocity.0 = Vec2::zero();
+
+    match burrow {
+        Burrow::Burrowing(remaining) => {
+            *remaining -= delta_time.0;
+            if *remaining <= 0.0 {
+                *burrow = Burrow::Burrowed;
+                buffer.add_component(*entity, Cloaked::permanent());
+            }
+        }
+        Burrow::Unburrowing(remaining) => {
+            *remaining -= delta_time.0;
+            if *remaining <= 0.0 {
+                buffer.remove_component::<Burrow>(*entity);
+            }
+        }
+        Burrow::Burrowed => {}
+    }
+}
+
+#[test]
+fn burrowing_toggles_and_hides() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    resources.insert(DeltaTime(0.5));
+    let mut schedule = Schedule::builder()
+        .add_system(progress_burrowing_system())
+        .build();
+
+    let unit = world.push((
+        Side::Green,
+        Velocity(Vec2::new(1.0, 0.0)),
+        CommandQueue::default(),
+        Abilities(vec![&Ability::BURROW]),
+    ));
+    let state = |world: &World| <&Burrow>::query().get(world, unit).ok().copied();
+
+    let toggle = |world: &mut World| {
+        let mut buffer = CommandBuffer::new(world);
+        let (mut subworld, _) = world.split::<(&mut Burrow, &mut CommandQueue, &Abilities)>();
+        toggle_burrow(&[unit], &mut subworld, &mut buffer);
+        buffer.flush(world);
+    };
+
+    toggle(&mut world);
+    assert_eq!(state(&world), Some(Burrow::Burrowing(BURROW_TIME)));
+
+    for _ in 0..3 {
+        schedule.execute(&mut world, &mut resources);
+    }
+    assert_eq!(state(&world), Some(Burrow::Burrowed));
+    let cloaked = <&Cloaked>::query().get(&world, unit).ok();
+    assert!(stealth::is_hidden(cloaked, Side::Purple, Side::Green));
+
+    // Coming back up is quicker, and the cloak goes straight away.
+    toggle(&mut world);
+    assert_eq!(state(&world), Some(Burrow::Unburrowing(UNBURROW_TIME)));
+    assert!(<&Cloaked>::query().get(&world, unit).is_err());
+    schedule.execute(&mut world, &mut resources);
+    assert_eq!(state(&world), None);
+}
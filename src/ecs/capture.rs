@@ -0,0 +1,233 @@
+use super::*;
+use crate::audio::{Sound, SoundEvents};
+use crate::resources::{CheeseCoins, DeltaTime, GameStats, TotalTime};
+
+// Neutral structures out on the map that either side can take over by keeping a unit next to one
+// for a while. Nobody makes progress while both sides have units there, and a structure that
+// someone else owns has to be knocked back to neutral before it can be taken.
+pub(super) const CAPTURE_RADIUS: f32 = 6.0;
+const CAPTURE_TIME: f32 = 10.0;
+const NEUTRALISE_TIME: f32 = 5.0;
+// Cheese stores pay out like a pump does, but they're further away from home.
+const STORE_INCOME: u32 = 2;
+const STORE_INCOME_INTERVAL: f32 = 0.5;
+// A watchtower sees further than anything else.
+pub(super) const WATCHTOWER_SIGHT: f32 = 30.0;
+
+#[derive(Copy, Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub enum Structure {
+    // Pays out cheese to whoever owns it.
+    CheeseStore,
+    // Lets whoever owns it see everything around it.
+    Watchtower,
+}
+
+impl Structure {
+    pub fn dimensions(self) -> Vec2 {
+        match self {
+            Self::CheeseStore => Vec2::new(4.0, 4.0),
+            Self::Watchtower => Vec2::new(2.0, 2.0),
+        }
+    }
+
+    // Stretches a wall segment into the shape of the structure.
+    pub(super) fn model_transform(self) -> Mat4 {
+        match self {
+            Self::CheeseStore => Mat4::from_nonuniform_scale(Vec3::new(2.2, 2.0, 2.2)),
+            Self::Watchtower => Mat4::from_nonuniform_scale(Vec3::new(1.2, 8.0, 1.2)),
+        }
+    }
+
+    // Like obstacles, structures are a part of the map forever.
+    pub fn add_to_world(
+        self,
+        buffer: &mut CommandBuffer,
+        position: Vec2,
+        map: &mut Map,
+    ) -> Option<Entity> {
+        map.insert(position, self.dimensions())?;
+        Some(buffer.push((Position(position), Capturable::new(self), Cooldown(0.0))))
+    }
+}
+
+pub struct Capturable {
+    pub structure: Structure,
+    pub owner: Option<Side>,
+    // Whoever is currently taking it over, and how far along they are from 0 to 1.
+    pub capturing: Option<Side>,
+    pub progress: f32,
+}
+
+impl Capturable {
+    pub fn new(structure: Structure) -> Self {
+        Self {
+            structure,
+            owner: None,
+            capturing: None,
+            progress: 0.0,
+        }
+    }
+
+    // Moves the capture along given which sides have units nearby. Returns the side that lost the
+    // structure, if it was just neutralised.
+    fn update(&mut self, green: bool, purple: bool, delta_time: f32) -> Option<Side> {
+        let side = match (green, purple) {
+            // Contested, so everything stays where it is.
+            (true, true) => return None,
+            (true, false) => Some(Side::Green),
+            (false, true) => Some(Side::Purple),
+            (false, false) => None,
+        };
+
+        let time = match self.owner {
+            Some(_) => NEUTRALISE_TIME,
+            None => CAPTURE_TIME,
+        };
+
+        match (side, self.capturing) {
+            // Someone else's progress has to drain away before a side can start on its own. The
+            // owner standing guard drains it too.
+            (Some(side), Some(capturing)) if side != capturing => {
+                self.drain(delta_time / time);
+            }
+            (Some(side), _) if self.owner != Some(side) => {
+                self.capturing = Some(side);
+                self.progress += delta_time / time;
+            }
+            _ => self.drain(delta_time / time),
+        }
+
+        if self.progress < 1.0 {
+            return None;
+        }
+
+        self.progress = 0.0;
+
+        match self.owner {
+            Some(owner) => {
+                self.owner = None;
+                Some(owner)
+            }
+            None => {
+                self.owner = self.capturing.take();
+                None
+            }
+        }
+    }
+
+    fn drain(&mut self, amount: f32) {
+        self.progress = (self.progress - amount).max(0.0);
+        if self.progress == 0.0 {
+            self.capturing = None;
+        }
+    }
+}
+
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Airborne)]
+#[write_component(Capturable)]
+pub fn capture_structures(
+    #[resource] delta_time: &DeltaTime,
+    #[resource] total_time: &TotalTime,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alerts: &mut Alerts,
+    #[resource] sound_events: &mut SoundEvents,
+    world: &mut SubWorld,
+) {
+    // Flying units can't land to take anything over.
+    let units: Vec<(Vec2, Side)> = <(&Position, &Side)>::query()
+        .filter(component::<Unit>() & !component::<Airborne>())
+        .iter(world)
+        .map(|(position, side)| (position.0, *side))
+        .collect();
+
+    for (position, capturable) in <(&Position, &mut Capturable)>::query().iter_mut(world) {
+        let mut present = [false, false];
+
+        for (unit_position, side) in &units {
+            if (*unit_position - position.0).mag_sq() <= CAPTURE_RADIUS * CAPTURE_RADIUS {
+                present[*side as usize] = true;
+            }
+        }
+
+        let owner = capturable.owner;
+
+        if capturable.update(present[0], present[1], delta_time.0) == Some(player_side.0) {
+            alerts.ping(position.0, total_time.0);
+            sound_events.play(Sound::Alert);
+        } else if owner != Some(player_side.0) && capturable.owner == Some(player_side.0) {
+            sound_events.play(Sound::Acknowledgement);
+        }
+    }
+}
+
+#[legion::system(for_each)]
+pub fn generate_captured_income(
+    capturable: &Capturable,
+    cooldown: &mut Cooldown,
+    #[resource] player_side: &PlayerSide,
+    #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] stats: &mut GameStats,
+) {
+    let owner = match capturable.owner {
+        Some(owner) if capturable.structure == Structure::CheeseStore => owner,
+        _ => return,
+    };
+
+    if cooldown.0 == 0.0 {
+        if owner == player_side.0 {
+            cheese_coins.0 += STORE_INCOME;
+        }
+        stats.side_mut(owner).cheese_gathered += STORE_INCOME;
+        cooldown.0 = STORE_INCOME_INTERVAL;
+    }
+}
+
+#[test]
+fn capturing_and_contesting() {
+    let mut tower = Capturable::new(Structure::Watchtower);
+    // Chosen so that the progress adds up exactly.
+    let tick = 0.625;
+    let ticks = |time: f32| (time / tick) as usize;
+
+    // Halfway there, then purple turns up.
+    for _ in 0..ticks(CAPTURE_TIME / 2.0) {
+        tower.update(true, false, tick);
+    }
+    assert_eq!(tower.capturing, Some(Side::Green));
+    tower.update(true, true, tick);
+    assert_eq!(tower.progress, 0.5);
+
+    // Purple has to undo green's progress before starting on its own.
+    for _ in 0..ticks(CAPTURE_TIME / 2.0) {
+        tower.update(false, true, tick);
+    }
+    assert_eq!(tower.capturing, None);
+    for _ in 0..ticks(CAPTURE_TIME) {
+        tower.update(false, true, tick);
+    }
+    assert_eq!(tower.owner, Some(Side::Purple));
+    assert_eq!(tower.capturing, None);
+
+    // Green has to neutralise it before it can be taken.
+    let mut lost = None;
+    for _ in 0..ticks(NEUTRALISE_TIME) {
+        lost = lost.or(tower.update(true, false, tick));
+    }
+    assert_eq!(lost, Some(Side::Purple));
+    assert_eq!(tower.owner, None);
+    for _ in 0..ticks(CAPTURE_TIME) {
+        tower.update(true, false, tick);
+    }
+    assert_eq!(tower.owner, Some(Side::Green));
+
+    // Walking away lets the progress drain.
+    tower.update(false, true, tick);
+    assert!(tower.progress > 0.0);
+    tower.update(false, false, tick);
+    assert_eq!(tower.progress, 0.0);
+    assert_eq!(tower.capturing, None);
+}
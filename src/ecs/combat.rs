@@ -1,5 +1,11 @@
 use super::*;
-use crate::resources::{DeltaTime, GameStats, PlayerSide};
+use crate::audio::{Sound, SoundEvents, VoiceLine};
+use crate::high_ground;
+use crate::net::Lockstep;
+use crate::resources::{DeltaTime, GameStats, Settings};
+use fnv::FnvHasher;
+use rand::SeedableRng;
+use std::hash::Hasher;
 
 #[legion::system(for_each)]
 #[read_component(Position)]
@@ -8,7 +14,14 @@ pub fn stop_actions_on_dead_entities(commands: &mut CommandQueue, world: &SubWor
         .0
         .front()
         .map(|command| {
-            if let Command::Attack { target, .. } | Command::Build { target, .. } = command {
+            if let Command::Attack { target, .. }
+            | Command::Build { target, .. }
+            | Command::Follow { target, .. }
+            | Command::Cast {
+                target: AbilityTarget::Unit(target),
+                ..
+            } = command
+            {
                 world.entry_ref(*target).is_err()
             } else {
                 false
@@ -20,15 +33,31 @@ pub fn stop_actions_on_dead_entities(commands: &mut CommandQueue, world: &SubWor
     }
 }
 
+const BULLET_SPEED: f32 = 20.0;
+const BULLET_DAMAGE: f32 = 2.0;
+// Shooting down from a plateau at something below it.
+const HIGH_GROUND_DAMAGE_MULTIPLIER: f32 = 1.25;
+
 #[legion::system(for_each)]
 #[filter(component::<Position>())]
 #[read_component(Position)]
+#[read_component(Velocity)]
+#[read_component(Radius)]
 pub fn firing(
     entity: &Entity,
     facing: &mut Facing,
     cooldown: &mut Cooldown,
     firing_range: &FiringRange,
+    accuracy: &Accuracy,
+    reload_time: &ReloadTime,
+    side: &Side,
+    area_of_effect: Option<&AreaOfEffect>,
+    experience: Option<&Experience>,
     command_queue: &CommandQueue,
+    network_id: &NetworkId,
+    #[resource] lockstep: &Lockstep,
+    #[resource] sound_events: &mut SoundEvents,
+    #[resource] upgrades: &Upgrades,
     world: &SubWorld,
     buffer: &mut CommandBuffer,
 ) {
@@ -48,43 +77,212 @@ pub fn firing(
         let vector = target_position.0 - position.0;
 
         if vector.mag_sq() <= firing_range.0.powi(2) {
-            facing.0 = vector.y.atan2(vector.x);
+            facing.0 = deterministic::angle(vector);
+
+            let start = position.0 + vector.normalized() * 0.5;
+
+            // Lead the target by guessing where it'll be by the time the bullet gets there.
+            let flight_time = (target_position.0 - start).mag() / BULLET_SPEED;
+            let aim = target_position.0 + estimate_velocity(*target, world) * flight_time;
+
+            let mut rng = shot_rng(lockstep, *network_id);
+
+            let impact = if rng.gen_range(0.0, 1.0) < accuracy.0 {
+                aim
+            } else {
+                // Land somewhere just outside of the target instead.
+                let radius = <&Radius>::query()
+                    .get(world, *target)
+                    .map(|radius| radius.0)
+                    .unwrap_or(1.0);
+                let angle = rng.gen_range(0.0, std::f32::consts::TAU);
+                let distance = rng.gen_range(radius + 0.5, radius * 2.0 + 1.5);
+                aim + deterministic::direction(angle) * distance
+            };
+
+            let direction = impact - start;
+
+            buffer.push((ParticleEmitter::new(
+                ParticleEmitterKind::MuzzleFlash,
+                Vec3::new(start.x, Bullet::GUN_HEIGHT, start.y),
+                vector.normalized(),
+            ),));
+            #[cfg(feature = "material-maps")]
+            buffer.push((LightFlash::muzzle_flash(Vec3::new(
+                start.x,
+                Bullet::GUN_HEIGHT,
+                start.y,
+            )),));
+            sound_events.play_at(Sound::Gunshot, start);
+
+            let high_ground = if high_ground::is_high_ground_over(position.0, target_position.0) {
+                HIGH_GROUND_DAMAGE_MULTIPLIER
+            } else {
+                1.0
+            };
 
             buffer.push((
-                Position(position.0 + vector.normalized() * 0.5),
+                Position(start),
                 Bullet {
                     target: *target,
                     source: *entity,
-                    target_position: target_position.0,
+                    side: *side,
+                    damage: (BULLET_DAMAGE + upgrades.modifiers(*side).bullet_damage)
+                        * high_ground
+                        * experience
+                            .map(|experience| experience.multiplier())
+                            .unwrap_or(1.0),
+                    area_of_effect: area_of_effect.map(|area_of_effect| area_of_effect.0),
+                    start,
+                    impact,
                 },
-                Facing(vector.y.atan2(vector.x)),
-                MoveSpeed(20.0),
+                Facing(deterministic::angle(direction)),
+                MaxSpeed(BULLET_SPEED),
             ));
-            cooldown.0 = 10.0 / 60.0;
+            cooldown.0 = reload_time.0;
         }
     }
 }
 
+// Whether a shot hits can't come from the shared rng, as `firing` goes through the shooters in
+// whatever order legion keeps them in, which isn't the same on every machine (see
+// `world_checksum`). Instead, each shot gets its own rng from the tick and who fired it.
+fn shot_rng(lockstep: &Lockstep, shooter: NetworkId) -> SmallRng {
+    let mut hasher = FnvHasher::default();
+    hasher.write_u64(lockstep.seed().unwrap_or(0));
+    hasher.write_u32(lockstep.tick());
+    hasher.write_u32(shooter.0);
+    SmallRng::seed_from_u64(hasher.finish())
+}
+
+// Buildings don't have a velocity, as they don't move.
+fn estimate_velocity(entity: Entity, world: &SubWorld) -> Vec2 {
+    <&Velocity>::query()
+        .get(world, entity)
+        .map(|velocity| velocity.0)
+        .unwrap_or_else(|_| Vec2::zero())
+}
+
 #[legion::system(for_each)]
-// Need this so that entry_ref works instead of erroring for a non-obvious reason.
 #[read_component(Entity)]
+#[read_component(Position)]
+#[read_component(Radius)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Airborne)]
+// Position is read through the world instead of being a parameter, so that the positions of the
+// targets can be looked up too.
+#[filter(component::<Position>())]
 pub fn apply_bullets(
     entity: &Entity,
     bullet: &Bullet,
-    position: &Position,
+    #[resource] rng: &mut SmallRng,
+    #[resource] spatial_hash: &SpatialHash,
+    #[resource] camera_shake: &mut CameraShake,
     world: &SubWorld,
     buffer: &mut CommandBuffer,
 ) {
-    if position.0 == bullet.target_position {
-        if world.entry_ref(bullet.target).is_ok() {
-            buffer.add_component(bullet.target, DamagedThisTick(bullet.source));
+    let position = <&Position>::query()
+        .get(world, *entity)
+        .expect("We've applied a filter to this system for Position");
+
+    if position.0 != bullet.impact {
+        return;
+    }
+
+    let direction = (bullet.impact - bullet.start).normalized();
+
+    let mut damage_entity = |entity: Entity, position: Vec2, damage: f32, kind: DamageKind| {
+        DamagedThisTick::add(
+            buffer,
+            entity,
+            Damage {
+                source: bullet.source,
+                damage,
+                kind,
+            },
+        );
+
+        if <&Unit>::query().get(world, entity).is_ok() {
+            buffer.push((ParticleEmitter::new(
+                ParticleEmitterKind::BloodPuff,
+                Vec3::new(position.x, Bullet::GUN_HEIGHT * 0.75, position.y),
+                direction,
+            ),));
         }
-        buffer.remove(*entity);
+    };
+
+    if let Some(area_of_effect) = bullet.area_of_effect {
+        for entity in spatial_hash.query_radius(bullet.impact, area_of_effect) {
+            // Explosions on the ground don't reach anything flying overhead.
+            let (entity_position, radius, side) = match <(&Position, &Radius, &Side)>::query()
+                .filter(!component::<Airborne>())
+                .get(world, entity)
+            {
+                Ok(components) => components,
+                Err(_) => continue,
+            };
+
+            if *side == bullet.side {
+                continue;
+            }
+
+            let distance = ((entity_position.0 - bullet.impact).mag() - radius.0).max(0.0);
+            let damage = bullet.damage * splash_falloff(distance, area_of_effect);
+
+            if damage > 0.0 {
+                damage_entity(entity, entity_position.0, damage, DamageKind::Splash);
+            }
+        }
+
+        buffer.push((Explosion::new(bullet.impact, rng, area_of_effect),));
+        #[cfg(feature = "material-maps")]
+        buffer.push((LightFlash::explosion(bullet.impact, area_of_effect),));
+        camera_shake.impact(bullet.impact, area_of_effect * SHAKE_PER_RADIUS);
+    } else if let Ok((target_position, radius)) =
+        <(&Position, &Radius)>::query().get(world, bullet.target)
+    {
+        // The target might have moved out of the way, or the shot might have missed.
+        if (target_position.0 - bullet.impact).mag_sq() <= (radius.0 + 0.25).powi(2) {
+            damage_entity(
+                bullet.target,
+                target_position.0,
+                bullet.damage,
+                DamageKind::Bullet,
+            );
+        }
+    }
+
+    buffer.remove(*entity);
+}
+
+fn damage_text(damage: f32) -> String {
+    // Splash damage drops off with distance so it's rarely a whole number.
+    if damage.fract() == 0.0 {
+        format!("{}", damage)
+    } else {
+        format!("{:.1}", damage)
     }
 }
 
+// How hard explosions shake the camera, for each unit of their radius. Mice are too small to shake
+// it when they die, but buildings aren't.
+const SHAKE_PER_RADIUS: f32 = 0.1;
+const MIN_SHAKING_RADIUS: f32 = 2.0;
+
+// Full damage at the impact point, dropping off linearly to nothing at the edge of the area.
+fn splash_falloff(distance: f32, area_of_effect: f32) -> f32 {
+    (1.0 - distance / area_of_effect).max(0.0)
+}
+
 #[legion::system(for_each)]
 #[read_component(Building)]
+#[read_component(Leash)]
+#[read_component(Facing)]
+#[read_component(crate::animation::Skin)]
+#[read_component(AnimationState)]
+#[read_component(Unit)]
+#[read_component(NetworkId)]
 pub fn handle_damaged(
     entity: &Entity,
     position: &Position,
@@ -95,42 +293,141 @@ pub fn handle_damaged(
     // None in the case of a building.
     commands: Option<&mut CommandQueue>,
     can_attack: Option<&CanAttack>,
+    stance: Option<&Stance>,
     map_handle: Option<&MapHandle>,
     buffer: &mut CommandBuffer,
-    #[resource] player_side: &PlayerSide,
     #[resource] stats: &mut GameStats,
     #[resource] map: &mut Map,
     #[resource] rng: &mut SmallRng,
+    #[resource] sound_events: &mut SoundEvents,
+    #[resource] settings: &Settings,
+    #[resource] camera_shake: &mut CameraShake,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alerts: &mut Alerts,
     world: &SubWorld,
 ) {
-    health.0 = (health.0 - 2.0).max(0.0);
+    // The hits were added in whatever order legion went through the bullets in, which isn't the same
+    // on every machine, so they need sorting before being added up.
+    let mut hits = damaged.0.clone();
+    hits.sort_by_key(|hit| {
+        let source = <&NetworkId>::query()
+            .get(world, hit.source)
+            .map(|id| id.0)
+            .unwrap_or(u32::MAX);
+        (source, hit.damage.to_bits(), hit.kind as u8)
+    });
+
+    let mut total_damage = 0.0;
+    let mut killer = None;
+
+    for hit in &hits {
+        if health.0 == 0.0 {
+            break;
+        }
+
+        // There are only two sides, so whatever did the damage was on the other one.
+        stats.side_mut(side.flip()).damage_dealt += hit.damage.min(health.0);
+        health.0 = (health.0 - hit.damage).max(0.0);
+        total_damage += hit.damage;
+
+        if health.0 == 0.0 {
+            killer = Some(hit.source);
+        }
+    }
+
+    // Whatever hit the hardest gets the blame.
+    let worst_hit = *hits
+        .iter()
+        .max_by(|a, b| a.damage.partial_cmp(&b.damage).unwrap())
+        .expect("Hits are only ever added, never an empty list");
+
+    if *side == player_side.0 {
+        alerts.damaged(position.0);
+    }
+
+    // Wall segments don't have a `MapHandle` of their own.
+    let building = <&Building>::query().get(world, *entity).ok().copied();
+    let is_building = building.is_some();
+
+    if settings.damage_numbers {
+        let height = if is_building { 5.0 } else { 3.0 };
+
+        buffer.push((FloatingText::new(
+            damage_text(total_damage),
+            worst_hit.kind.colour(),
+            position.0,
+            height,
+            *side,
+        ),));
+
+        if health.0 == 0.0 {
+            let text = if is_building { "Destroyed!" } else { "Killed!" };
+
+            buffer.push((FloatingText::new(
+                text.to_string(),
+                Vec3::new(1.0, 0.2, 0.2),
+                position.0,
+                height + 1.0,
+                *side,
+            ),));
+        }
+    }
 
     if health.0 == 0.0 {
         buffer.remove(*entity);
 
-        if let Some(map_handle) = map_handle {
-            map.remove(map_handle);
+        if let Some(building) = building {
+            if let Some(map_handle) = map_handle {
+                map.remove(map_handle);
+            }
+
+            if let Some(dimensions) = building.stats().wreckage {
+                Obstacle::add_to_world(buffer, position.0, dimensions, map);
+            }
+        } else {
+            sound_events.play_at(Sound::Squeak, position.0);
+
+            if *side == player_side.0 {
+                if let Ok(unit) = <&Unit>::query().get(world, *entity) {
+                    sound_events.say(*unit, VoiceLine::Death);
+                }
+            }
+            Corpse::add_to_world(buffer, *entity, position.0, *side, world);
         }
 
-        if *side == player_side.0 {
-            stats.units_lost += 1;
-        } else if map_handle.is_some() {
-            stats.enemy_buildings_destroyed += 1;
+        if is_building {
+            stats.side_mut(*side).buildings_lost += 1;
         } else {
-            stats.enemy_units_killed += 1;
+            stats.side_mut(*side).units_lost += 1;
         }
 
         buffer.push((Explosion::new(position.0, rng, radius.0),));
+        #[cfg(feature = "material-maps")]
+        buffer.push((LightFlash::explosion(position.0, radius.0),));
+
+        if radius.0 >= MIN_SHAKING_RADIUS {
+            camera_shake.impact(position.0, radius.0 * SHAKE_PER_RADIUS);
+        }
+
+        if let Some(killer) = killer {
+            Experience::credit_kill(buffer, killer);
+        }
 
         return;
     }
 
     // If the unit is idle and got attacked, go attack back!
     if let Some(commands) = commands {
+        let stance = stance.copied().unwrap_or_default();
+
         if can_attack.is_some()
+            && stance != Stance::Passive
             && (commands.0.is_empty() || is_attacking_building(&commands, world))
         {
-            commands.0.push_front(Command::new_attack(damaged.0, false));
+            leash(*entity, position.0, stance, commands, world, buffer);
+            commands
+                .0
+                .push_front(Command::new_attack(worst_hit.source, false));
         }
     }
 
@@ -153,24 +450,39 @@ fn is_attacking_building(commands: &CommandQueue, world: &SubWorld) -> bool {
 }
 
 #[legion::system(for_each)]
-#[filter(component::<Position>() & component::<Side>() & component::<CanAttack>())]
+#[filter(
+    component::<Position>()
+        & component::<Side>()
+        & component::<CanAttack>()
+        & !component::<Burrow>()
+)]
 #[read_component(Entity)]
 #[read_component(Position)]
 #[read_component(Side)]
 #[read_component(Building)]
+#[read_component(Leash)]
+#[read_component(Unit)]
+#[read_component(Health)]
+#[read_component(CanAttack)]
+#[read_component(Cloaked)]
+#[read_component(Airborne)]
+#[read_component(AntiAir)]
 pub fn agro_units(
     entity: &Entity,
     commands: &mut CommandQueue,
+    firing_range: &FiringRange,
+    stance: &Stance,
+    #[resource] spatial_hash: &SpatialHash,
     world: &SubWorld,
     command_buffer: &mut CommandBuffer,
 ) {
     // Todo: find a clean way to getting units to re-target when an enemy unit is in range and we're
     // currently attacking a building.
 
-    let is_available_to_attack =
-        matches!(commands.0.front(), None | Some(&Command::MoveTo { attack_move: true, .. }));
+    let holding_position = matches!(commands.0.front(), Some(&Command::HoldPosition))
+        || is_escorting_defensively(commands, *stance);
 
-    if !is_available_to_attack {
+    if *stance == Stance::Passive || (!holding_position && !is_available_to_attack(commands)) {
         return;
     }
 
@@ -178,67 +490,207 @@ pub fn agro_units(
         .get(world, *entity)
         .expect("We've applied a filter for these components");
 
-    let agro_range: f32 = 15.0;
+    let (agro_center, agro_range) = match commands.0.front() {
+        // Guards keep watch over the whole area that they're guarding.
+        Some(&Command::Guard { center, radius, .. }) => (center, radius),
+        // Units holding their position (or sticking with who they're following) only go for
+        // things that they can already shoot.
+        _ if holding_position => (
+            position.0,
+            firing_range.0 - movement::FIRING_RANGE_FUDGE_FACTOR,
+        ),
+        _ => (position.0, 15.0),
+    };
 
-    if let Some(target) = find_best_target(position.0, *side, Some(agro_range), world) {
-        commands.0.push_front(Command::new_attack(target, false));
+    let priorities = <&Unit>::query()
+        .get(world, *entity)
+        .map(|unit| unit.stats().targeting)
+        .unwrap_or_default();
+
+    let anti_air = <&AntiAir>::query().get(world, *entity).is_ok();
+
+    if let Some(target) = find_best_target(
+        agro_center,
+        *side,
+        anti_air,
+        &priorities,
+        agro_range,
+        spatial_hash,
+        world,
+    ) {
+        leash(
+            *entity,
+            position.0,
+            *stance,
+            commands,
+            world,
+            command_buffer,
+        );
+
+        let command = if holding_position {
+            Command::new_attack_without_chasing(target)
+        } else {
+            Command::new_attack(target, false)
+        };
+
+        commands.0.push_front(command);
         command_buffer.add_component(*entity, Agroed::ThisTick(target));
     }
 }
 
+// Idle, attack moving, patrolling, following and guarding units all go after enemies that come
+// near, and followers and guards pick up where they left off afterwards.
+fn is_available_to_attack(commands: &CommandQueue) -> bool {
+    matches!(
+        commands.0.front(),
+        None | Some(&Command::MoveTo {
+            attack_move: true,
+            ..
+        }) | Some(&Command::Patrol { .. })
+            | Some(&Command::Follow { .. })
+            | Some(&Command::Guard { .. })
+    )
+}
+
+// Whether a target is somewhere that a unit is allowed to go after it. Guards stay inside the area
+// that they're guarding.
+fn within_guard_area(commands: &CommandQueue, target: Vec2) -> bool {
+    commands
+        .0
+        .iter()
+        .find_map(|command| match command {
+            Command::Guard { center, radius, .. } => {
+                Some((target - *center).mag_sq() <= radius.powi(2))
+            }
+            Command::Attack {
+                explicit: false, ..
+            } => None,
+            _ => Some(true),
+        })
+        .unwrap_or(true)
+}
+
+// Defensive units that are following someone don't chase after anything, so that they don't get
+// left behind.
+fn is_escorting_defensively(commands: &CommandQueue, stance: Stance) -> bool {
+    stance == Stance::Defensive && matches!(commands.0.front(), Some(&Command::Follow { .. }))
+}
+
+// What `target_score` needs to know about a potential target.
+struct TargetInfo {
+    distance: f32,
+    // From 0 (full health) to 1 (dead).
+    health_lost: f32,
+    max_health: f32,
+    can_attack: bool,
+    is_building: bool,
+}
+
+// Higher is better.
+fn target_score(priorities: &TargetPriorities, target: &TargetInfo) -> f32 {
+    let mut score = -target.distance * priorities.distance
+        + target.health_lost * priorities.low_health
+        + target.max_health / 100.0 * priorities.armour;
+
+    if target.can_attack {
+        score += priorities.attackers;
+    }
+
+    if target.is_building {
+        score += priorities.buildings;
+    }
+
+    score
+}
+
 fn find_best_target(
     position: Vec2,
     side: Side,
-    in_range: Option<f32>,
+    anti_air: bool,
+    priorities: &TargetPriorities,
+    range: f32,
+    spatial_hash: &SpatialHash,
     world: &SubWorld,
 ) -> Option<Entity> {
-    <(Entity, &Position, Option<&Building>, &Side)>::query()
-        .iter(world)
-        .filter(|(.., entity_side)| **entity_side != side)
-        .filter(|(_, entity_position, ..)| {
-            in_range
-                .map(|range| (position - entity_position.0).mag_sq() <= range.powi(2))
-                .unwrap_or(true)
+    spatial_hash
+        .query_radius(position, range)
+        .filter_map(|entity| {
+            let components = <(
+                &Position,
+                &Side,
+                Option<&Health>,
+                Option<&Unit>,
+                Option<&Building>,
+                Option<&CanAttack>,
+                Option<&Cloaked>,
+                Option<&Airborne>,
+            )>::query()
+            .get(world, entity)
+            .ok()?;
+            Some((entity, components))
         })
-        .map(|(entity, entity_position, entity_building, _)| {
-            let distance_sq = (position - entity_position.0).mag_sq();
-            (
-                *entity,
-                ordered_float::OrderedFloat(distance_sq),
-                entity_building.is_some(),
-            )
+        .filter(|(_, (_, entity_side, .., cloaked, airborne))| {
+            **entity_side != side
+                && !stealth::is_hidden(*cloaked, side, **entity_side)
+                && (anti_air || airborne.is_none())
         })
-        .min_by(|&(_, a_pos, a_is_building), &(_, b_pos, b_is_building)| {
-            if a_is_building == b_is_building {
-                a_pos.cmp(&b_pos)
-            // If only a is a building, then it a has less priority
-            } else if a_is_building {
-                std::cmp::Ordering::Greater
-            // If only b is a building then it a has higher priority
-            } else {
-                std::cmp::Ordering::Less
-            }
+        .filter(|(_, (entity_position, ..))| {
+            (position - entity_position.0).mag_sq() <= range.powi(2)
         })
-        .map(|(entity, ..)| entity)
+        .map(
+            |(entity, (entity_position, _, health, unit, building, can_attack, ..))| {
+                let max_health = match (unit, building) {
+                    (Some(unit), _) => unit.stats().max_health,
+                    (_, Some(building)) => building.stats().max_health,
+                    _ => 1.0,
+                };
+                let health = health.map(|health| health.0).unwrap_or(max_health);
+
+                let info = TargetInfo {
+                    distance: (position - entity_position.0).mag(),
+                    health_lost: (1.0 - health / max_health).clamp(0.0, 1.0),
+                    max_health,
+                    can_attack: can_attack.is_some(),
+                    is_building: building.is_some(),
+                };
+
+                (
+                    entity,
+                    ordered_float::OrderedFloat(target_score(priorities, &info)),
+                )
+            },
+        )
+        .max_by_key(|&(_, score)| score)
+        .map(|(entity, _)| entity)
 }
 
 #[legion::system(for_each)]
-#[filter(component::<Position>() & component::<Side>() & component::<CanAttack>())]
+#[filter(
+    component::<Position>()
+        & component::<Side>()
+        & component::<CanAttack>()
+        & !component::<Burrow>()
+)]
 #[read_component(Entity)]
 #[read_component(Position)]
 #[read_component(Side)]
 #[read_component(Building)]
 #[read_component(Agroed)]
+#[read_component(Leash)]
+#[read_component(Airborne)]
+#[read_component(AntiAir)]
+#[read_component(NetworkId)]
 pub fn propagate_agro(
     entity: &Entity,
     commands: &mut CommandQueue,
+    stance: &Stance,
     world: &SubWorld,
     command_buffer: &mut CommandBuffer,
 ) {
-    let is_available_to_attack =
-        matches!(commands.0.front(), None | Some(&Command::MoveTo { attack_move: true, .. }));
-
-    if !is_available_to_attack {
+    if *stance == Stance::Passive
+        || !is_available_to_attack(commands)
+        || is_escorting_defensively(commands, *stance)
+    {
         return;
     }
 
@@ -248,24 +700,131 @@ pub fn propagate_agro(
 
     let agro_propagation_distance: f32 = 5.0;
 
-    let agro_entity = <(&Position, &Side, &Agroed)>::query()
+    // Legion doesn't go through entities in the same order on every machine, so pick whichever
+    // agroed unit is nearest, breaking ties by position and then `NetworkId`.
+    let agro_entity = <(&Position, &Side, &Agroed, Option<&NetworkId>)>::query()
         .iter(world)
-        .filter(|(unit_pos, unit_side, _)| {
+        .filter(|(unit_pos, unit_side, ..)| {
             *unit_side == side
                 && (unit_pos.0 - position.0).mag_sq() <= agro_propagation_distance.powi(2)
         })
-        .next()
-        .map(|(.., agroed)| match agroed {
+        .min_by_key(|(unit_pos, _, _, network_id)| {
+            (
+                ordered_float::OrderedFloat((unit_pos.0 - position.0).mag_sq()),
+                ordered_float::OrderedFloat(unit_pos.0.x),
+                ordered_float::OrderedFloat(unit_pos.0.y),
+                network_id.map(|network_id| network_id.0),
+            )
+        })
+        .map(|(_, _, agroed, _)| match agroed {
             Agroed::ThisTick(entity) => *entity,
             Agroed::LastTick(entity) => *entity,
+        })
+        .filter(|target| airborne::can_hit(*entity, *target, world))
+        .filter(|target| {
+            <&Position>::query()
+                .get(world, *target)
+                .map(|target_position| within_guard_area(commands, target_position.0))
+                .unwrap_or(false)
         });
 
     if let Some(target) = agro_entity {
+        leash(
+            *entity,
+            position.0,
+            *stance,
+            commands,
+            world,
+            command_buffer,
+        );
         commands.0.push_front(Command::new_attack(target, false));
         command_buffer.add_component(*entity, Agroed::ThisTick(target));
     }
 }
 
+// Defensive units remember where they were standing when they start a fight from standing still, so
+// that they can walk back there afterwards.
+fn leash(
+    entity: Entity,
+    position: Vec2,
+    stance: Stance,
+    commands: &CommandQueue,
+    world: &SubWorld,
+    buffer: &mut CommandBuffer,
+) {
+    let already_leashed = <&Leash>::query().get(world, entity).is_ok();
+
+    if stance == Stance::Defensive && commands.0.is_empty() && !already_leashed {
+        buffer.add_component(entity, Leash(position));
+    }
+}
+
+// How far from where they started defensive units will chase things.
+const LEASH_RANGE: f32 = 20.0;
+
+#[legion::system(for_each)]
+#[read_component(Position)]
+pub fn leash_defensive_units(
+    entity: &Entity,
+    leash: &Leash,
+    commands: &mut CommandQueue,
+    world: &SubWorld,
+    command_buffer: &mut CommandBuffer,
+) {
+    let walk_back = match commands.0.front() {
+        Some(Command::Attack {
+            target,
+            explicit: false,
+            ..
+        }) => <&Position>::query()
+            .get(world, *target)
+            .map(|position| (position.0 - leash.0).mag_sq() > LEASH_RANGE.powi(2))
+            .unwrap_or(false),
+        None => true,
+        // The player's given it something else to do, so forget about where it was.
+        Some(_) => {
+            command_buffer.remove_component::<Leash>(*entity);
+            return;
+        }
+    };
+
+    if walk_back {
+        commands.0.clear();
+        // Not an attack move, so that it doesn't get distracted on the way back.
+        commands.0.push_back(Command::MoveTo {
+            target: leash.0,
+            attack_move: false,
+            path: Vec::new(),
+        });
+        command_buffer.remove_component::<Leash>(*entity);
+    }
+}
+
+// The size of the area that the player's guard orders cover.
+pub(super) const GUARD_RADIUS: f32 = 12.0;
+
+// Guards give up on anything that gets back out of the area that they're guarding, which leaves
+// them to walk back to where they were standing.
+#[legion::system(for_each)]
+#[read_component(Position)]
+pub fn leash_guards(commands: &mut CommandQueue, world: &SubWorld) {
+    let escaped = match commands.0.front() {
+        Some(Command::Attack {
+            target,
+            explicit: false,
+            ..
+        }) => <&Position>::query()
+            .get(world, *target)
+            .map(|position| !within_guard_area(commands, position.0))
+            .unwrap_or(false),
+        _ => false,
+    };
+
+    if escaped {
+        commands.0.pop_front();
+    }
+}
+
 #[legion::system(for_each)]
 pub fn update_argoed_this_tick(entity: &Entity, agroed: &mut Agroed, buffer: &mut CommandBuffer) {
     match *agroed {
@@ -278,3 +837,238 @@ pub fn update_argoed_this_tick(entity: &Entity, agroed: &mut Agroed, buffer: &mu
 pub fn reduce_cooldowns(cooldown: &mut Cooldown, #[resource] delta_time: &DeltaTime) {
     cooldown.0 = (cooldown.0 - delta_time.0).max(0.0);
 }
+
+#[test]
+fn splash_damage_falls_off_linearly() {
+    assert_eq!(splash_falloff(0.0, 4.0), 1.0);
+    assert_eq!(splash_falloff(1.0, 4.0), 0.75);
+    assert_eq!(splash_falloff(4.0, 4.0), 0.0);
+    assert_eq!(splash_falloff(10.0, 4.0), 0.0);
+}
+
+#[test]
+fn kills_promote_units_and_boost_their_health() {
+    let mut world = World::default();
+    let mut buffer = CommandBuffer::new(&world);
+    let marine = Unit::MouseMarine.add_to_world(
+        &mut buffer,
+        None,
+        Vec2::zero(),
+        Facing(0.0),
+        Side::Green,
+        None,
+    );
+    buffer.flush(&mut world);
+
+    let max_health = Unit::MouseMarine.stats().max_health;
+
+    for _ in 0..2 {
+        Experience::credit_kill(&mut buffer, marine);
+    }
+    buffer.flush(&mut world);
+
+    let (experience, health) = <(&Experience, &Health)>::query()
+        .get(&world, marine)
+        .unwrap();
+    assert_eq!(experience.kills, 2);
+    assert_eq!(experience.rank_name(), "Veteran");
+    assert!((health.0 - max_health * experience.multiplier()).abs() < 0.001);
+}
+
+#[test]
+fn hits_on_the_same_tick_all_do_damage() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    let mut buffer = CommandBuffer::new(&world);
+
+    let marine = Unit::MouseMarine.add_to_world(
+        &mut buffer,
+        None,
+        Vec2::zero(),
+        Facing(0.0),
+        Side::Green,
+        None,
+    );
+    let shooters: Vec<Entity> = (0..2)
+        .map(|i| {
+            Unit::MouseMarine.add_to_world(
+                &mut buffer,
+                None,
+                Vec2::new(10.0, i as f32),
+                Facing(0.0),
+                Side::Purple,
+                None,
+            )
+        })
+        .collect();
+    buffer.flush(&mut world);
+
+    for (shooter, damage) in shooters.iter().zip(&[2.0, 3.0]) {
+        world.push((
+            Position(Vec2::zero()),
+            Bullet {
+                target: marine,
+                source: *shooter,
+                side: Side::Purple,
+                damage: *damage,
+                area_of_effect: None,
+                start: Vec2::new(10.0, 0.0),
+                impact: Vec2::zero(),
+            },
+        ));
+    }
+
+    resources.insert(Map::new());
+    resources.insert(SpatialHash::default());
+    resources.insert(GameStats::default());
+    resources.insert(SmallRng::seed_from_u64(0));
+    resources.insert(SoundEvents::default());
+    resources.insert(CameraShake::default());
+    resources.insert(PlayerSide(Side::Green));
+    resources.insert(Alerts::default());
+    resources.insert(Upgrades::default());
+    resources.insert(Settings::default());
+
+    let mut schedule = Schedule::builder()
+        .add_system(apply_bullets_system())
+        .flush()
+        .add_system(handle_damaged_system())
+        .build();
+    schedule.execute(&mut world, &mut resources);
+
+    let health = <&Health>::query().get(&world, marine).unwrap();
+    assert_eq!(health.0, Unit::MouseMarine.stats().max_health - 5.0);
+    assert_eq!(
+        resources
+            .get::<GameStats>()
+            .unwrap()
+            .side(Side::Purple)
+            .damage_dealt,
+        5.0
+    );
+}
+
+#[test]
+fn destroyed_armouries_leave_wreckage_in_the_way() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    let mut map = Map::new();
+
+    let armoury = Building::Armoury
+        .add_to_world_fully_built(
+            &mut world,
+            Vec2::zero(),
+            Side::Purple,
+            &ModelAnimations::default(),
+            &mut map,
+        )
+        .unwrap();
+    world
+        .entry(armoury)
+        .unwrap()
+        .add_component(DamagedThisTick(vec![Damage {
+            source: armoury,
+            damage: Building::Armoury.stats().max_health,
+            kind: DamageKind::Bullet,
+        }]));
+
+    resources.insert(map);
+    resources.insert(GameStats::default());
+    resources.insert(SmallRng::seed_from_u64(0));
+    resources.insert(SoundEvents::default());
+    resources.insert(CameraShake::default());
+    resources.insert(PlayerSide(Side::Green));
+    resources.insert(Alerts::default());
+    resources.insert(Upgrades::default());
+    resources.insert(Settings::default());
+
+    let mut schedule = Schedule::builder()
+        .add_system(handle_damaged_system())
+        .build();
+    schedule.execute(&mut world, &mut resources);
+
+    assert!(world.entry_ref(armoury).is_err());
+
+    let wreckage = Building::Armoury.stats().wreckage.unwrap();
+    let obstacles = <(&Position, &Obstacle)>::query()
+        .iter(&world)
+        .map(|(position, obstacle)| (position.0, obstacle.dimensions))
+        .collect::<Vec<_>>();
+    assert_eq!(obstacles, vec![(Vec2::zero(), wreckage)]);
+
+    let map = resources.get::<Map>().unwrap();
+    assert!(!map.can_insert(Vec2::new(0.5, 0.5), wreckage));
+    // Where the edge of the armoury used to be.
+    assert!(map.can_insert(Vec2::new(0.0, 4.5), Vec2::new(1.0, 0.5)));
+}
+
+#[test]
+fn marines_prefer_wounded_attackers_over_closer_targets() {
+    let priorities = Unit::MouseMarine.stats().targeting;
+
+    let target = |distance, health_lost, can_attack, is_building| TargetInfo {
+        distance,
+        health_lost,
+        max_health: 50.0,
+        can_attack,
+        is_building,
+    };
+
+    let engineer = target_score(&priorities, &target(5.0, 0.0, false, false));
+    let marine = target_score(&priorities, &target(8.0, 0.0, true, false));
+    let wounded_marine = target_score(&priorities, &target(12.0, 0.9, true, false));
+    let building = target_score(&priorities, &target(1.0, 0.9, false, true));
+
+    assert!(marine > engineer);
+    assert!(wounded_marine > marine);
+    assert!(building < engineer);
+
+    // By default, units just go for whatever's closest.
+    let default = TargetPriorities::default();
+    assert!(
+        target_score(&default, &target(5.0, 0.0, false, false))
+            > target_score(&default, &target(8.0, 0.9, true, false))
+    );
+}
+
+#[test]
+fn guards_give_up_on_enemies_that_leave_the_area() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    let mut schedule = Schedule::builder()
+        .add_system(leash_guards_system())
+        .build();
+
+    let enemy = world.push((Position(Vec2::new(5.0, 0.0)),));
+    let guard = world.push((CommandQueue(
+        vec![
+            Command::new_attack(enemy, false),
+            Command::new_guard(Vec2::zero(), 10.0),
+        ]
+        .into(),
+    ),));
+    let front = |world: &World| {
+        <&CommandQueue>::query()
+            .get(world, guard)
+            .unwrap()
+            .0
+            .front()
+            .cloned()
+    };
+
+    schedule.execute(&mut world, &mut resources);
+    assert!(matches!(front(&world), Some(Command::Attack { .. })));
+
+    *<&mut Position>::query().get_mut(&mut world, enemy).unwrap() = Position(Vec2::new(15.0, 0.0));
+    schedule.execute(&mut world, &mut resources);
+    assert!(matches!(front(&world), Some(Command::Guard { .. })));
+
+    // Anything the player tells them to attack is fair game.
+    <&mut CommandQueue>::query()
+        .get_mut(&mut world, guard)
+        .unwrap()
+        .0
+        .push_front(Command::new_attack(enemy, true));
+    schedule.execute(&mut world, &mut resources);
+    assert!(matches!(front(&world), Some(Command::Attack { .. })));
+}
@@ -0,0 +1,338 @@
+use super::{Damage, DamageKind, DamagedThisTick, Facing, FogOfWar, Health, Selected, Unit};
+use crate::assets::ModelAnimations;
+use crate::deterministic;
+use crate::net::Lockstep;
+use crate::pathfinding::Map;
+use crate::renderer::{Font, TextAlignment, TextBuffer};
+use crate::resources::{
+    CheeseCoins, DebugControls, DpiScaling, GameSpeed, PlayerSide, RayCastLocation,
+    ScreenDimensions,
+};
+use legion::systems::CommandBuffer;
+use legion::world::SubWorld;
+use legion::{component, Entity, IntoQuery};
+use ultraviolet::{Vec2, Vec4};
+
+// How many lines of output are kept around.
+const MAX_LOG_LINES: usize = 10;
+// Spawning too many units at once grinds everything to a halt.
+const MAX_SPAWN_COUNT: u32 = 100;
+
+const HELP: &str = "Commands: spawn <mouse|engineer|medic> [count] [friendly|enemy], \
+                    give_cheese <amount>, reveal_map, kill_selected, set_speed <speed>, \
+                    debug <paths|navmesh|funnels|flow_fields>";
+
+// Opened with the backtick key. Typing is handled in main.rs, as it needs the raw characters that
+// come with `WindowEvent::ReceivedCharacter`.
+#[derive(Default)]
+pub struct Console {
+    pub open: bool,
+    pub input: String,
+    log: Vec<String>,
+    // Lines that have been entered but not run yet.
+    submitted: Vec<String>,
+    // The last line that was entered, so that it can be brought back with the up arrow.
+    last: Option<String>,
+}
+
+impl Console {
+    pub fn submit(&mut self) {
+        let line = std::mem::take(&mut self.input);
+        if !line.trim().is_empty() {
+            self.last = Some(line.clone());
+            self.submitted.push(line);
+        }
+    }
+
+    pub fn recall_last(&mut self) {
+        if let Some(last) = &self.last {
+            self.input = last.clone();
+        }
+    }
+
+    fn print(&mut self, line: String) {
+        self.log.push(line);
+        if self.log.len() > MAX_LOG_LINES {
+            self.log.remove(0);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum SpawnSide {
+    Friendly,
+    Enemy,
+}
+
+// Things that can be drawn over the map to see what the pathfinding is up to.
+#[derive(Debug, PartialEq)]
+enum DebugOverlay {
+    // The paths that every unit is following.
+    Paths,
+    // The whole triangulation, with the constraints in red.
+    Navmesh,
+    // The triangles and funnel portals that the selected units' paths went through.
+    Funnels,
+    // The last flow field that a big group of units used.
+    FlowFields,
+}
+
+#[derive(Debug, PartialEq)]
+enum ConsoleCommand {
+    Spawn {
+        unit: Unit,
+        count: u32,
+        side: SpawnSide,
+    },
+    GiveCheese(u32),
+    RevealMap,
+    KillSelected,
+    SetSpeed(f32),
+    ToggleDebug(DebugOverlay),
+    Help,
+}
+
+fn parse_unit(name: &str) -> Option<Unit> {
+    match name {
+        "mouse" | "mice" | "marine" | "marines" => Some(Unit::MouseMarine),
+        "engineer" | "engineers" => Some(Unit::Engineer),
+        "medic" | "medics" => Some(Unit::Medic),
+        "glider" | "gliders" => Some(Unit::Glider),
+        _ => None,
+    }
+}
+
+fn parse(line: &str) -> Result<ConsoleCommand, String> {
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let args: Vec<&str> = words.collect();
+
+    let command = match (name, &args[..]) {
+        ("spawn", [unit, rest @ ..]) if rest.len() <= 2 => {
+            let unit = parse_unit(unit).ok_or_else(|| format!("Unknown unit '{}'", unit))?;
+            let mut count = 1;
+            let mut side = SpawnSide::Friendly;
+
+            for arg in rest {
+                match *arg {
+                    "friendly" | "ally" | "mine" => side = SpawnSide::Friendly,
+                    "enemy" => side = SpawnSide::Enemy,
+                    _ => {
+                        count = arg
+                            .parse()
+                            .map_err(|_| format!("'{}' isn't a count or a side", arg))?
+                    }
+                }
+            }
+
+            if count == 0 || count > MAX_SPAWN_COUNT {
+                return Err(format!("Can only spawn 1 to {} units", MAX_SPAWN_COUNT));
+            }
+
+            ConsoleCommand::Spawn { unit, count, side }
+        }
+        ("give_cheese", [amount]) => ConsoleCommand::GiveCheese(
+            amount
+                .parse()
+                .map_err(|_| format!("'{}' isn't an amount of cheese", amount))?,
+        ),
+        ("reveal_map", []) => ConsoleCommand::RevealMap,
+        ("kill_selected", []) => ConsoleCommand::KillSelected,
+        ("set_speed", [speed]) => {
+            let speed: f32 = speed
+                .parse()
+                .map_err(|_| format!("'{}' isn't a speed", speed))?;
+            if !(0.1..=8.0).contains(&speed) {
+                return Err("The speed has to be between 0.1 and 8".to_string());
+            }
+            ConsoleCommand::SetSpeed(speed)
+        }
+        ("debug", [overlay]) => ConsoleCommand::ToggleDebug(match *overlay {
+            "paths" => DebugOverlay::Paths,
+            "navmesh" | "triangles" => DebugOverlay::Navmesh,
+            "funnels" => DebugOverlay::Funnels,
+            "flow_fields" | "flow_field" => DebugOverlay::FlowFields,
+            _ => return Err(format!("There's no '{}' debug overlay", overlay)),
+        }),
+        ("help", []) => ConsoleCommand::Help,
+        _ => {
+            return Err(format!(
+                "Can't run '{}'. Type 'help' for a list of commands",
+                line
+            ))
+        }
+    };
+
+    Ok(command)
+}
+
+#[legion::system]
+#[read_component(Health)]
+pub fn run_console_commands(
+    #[resource] console: &mut Console,
+    #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] fog_of_war: &mut FogOfWar,
+    #[resource] game_speed: &mut GameSpeed,
+    #[resource] player_side: &PlayerSide,
+    #[resource] ray_cast_location: &RayCastLocation,
+    #[resource] animations: &ModelAnimations,
+    #[resource] lockstep: &Lockstep,
+    #[resource] debug_controls: &mut DebugControls,
+    #[resource] map: &Map,
+    buffer: &mut CommandBuffer,
+    world: &SubWorld,
+) {
+    for line in std::mem::take(&mut console.submitted) {
+        console.print(format!("> {}", line));
+
+        let command = match parse(&line) {
+            Ok(command) => command,
+            Err(error) => {
+                console.print(error);
+                continue;
+            }
+        };
+
+        // The other player wouldn't know about any of this, so the game would go out of sync. Debug
+        // overlays are only drawn locally though.
+        if lockstep.is_networked() && !matches!(command, ConsoleCommand::ToggleDebug(_)) {
+            console.print("The console can't be used in multiplayer games".to_string());
+            continue;
+        }
+
+        match command {
+            ConsoleCommand::Spawn { unit, count, side } => {
+                let side = match side {
+                    SpawnSide::Friendly => player_side.0,
+                    SpawnSide::Enemy => player_side.0.flip(),
+                };
+
+                // Spread the units out in a spiral, so they're not all on top of each other.
+                for i in 0..count {
+                    let angle = i as f32 * 2.4;
+                    let offset = deterministic::direction(angle) * (i as f32).sqrt() * 0.8;
+
+                    let position = map.nearest_passable_point(
+                        ray_cast_location.pos + offset,
+                        unit.stats().radius,
+                    );
+
+                    unit.add_to_world(buffer, Some(animations), position, Facing(0.0), side, None);
+                }
+
+                console.print(format!("Spawned {} {:?} ({:?})", count, unit, side));
+            }
+            ConsoleCommand::GiveCheese(amount) => {
+                cheese_coins.0 = cheese_coins.0.saturating_add(amount);
+                console.print(format!("Gave {} cheese", amount));
+            }
+            ConsoleCommand::RevealMap => {
+                fog_of_war.reveal_map(player_side.0);
+                console.print("Revealed the map".to_string());
+            }
+            ConsoleCommand::KillSelected => {
+                let mut killed = 0;
+
+                <(Entity, &Health)>::query()
+                    .filter(component::<Selected>())
+                    .for_each(world, |(entity, health)| {
+                        // Go through `handle_damaged` so that there's still a corpse and an
+                        // explosion and so on.
+                        DamagedThisTick::add(
+                            buffer,
+                            *entity,
+                            Damage {
+                                source: *entity,
+                                damage: health.0,
+                                kind: DamageKind::Bullet,
+                            },
+                        );
+                        killed += 1;
+                    });
+
+                console.print(format!("Killed {} selected", killed));
+            }
+            ConsoleCommand::SetSpeed(speed) => {
+                game_speed.speed = speed;
+                console.print(format!("Set the game speed to {}x", speed));
+            }
+            ConsoleCommand::ToggleDebug(overlay) => {
+                let shown = match overlay {
+                    DebugOverlay::Paths => &mut debug_controls.show_unit_paths,
+                    DebugOverlay::Navmesh => &mut debug_controls.show_navmesh,
+                    DebugOverlay::Funnels => &mut debug_controls.show_funnels,
+                    DebugOverlay::FlowFields => &mut debug_controls.show_flow_fields,
+                };
+                *shown = !*shown;
+
+                let state = if *shown { "on" } else { "off" };
+                console.print(format!("Turned {:?} {}", overlay, state));
+            }
+            ConsoleCommand::Help => console.print(HELP.to_string()),
+        }
+    }
+}
+
+#[legion::system]
+pub fn render_console(
+    #[resource] console: &Console,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+) {
+    if !console.open {
+        return;
+    }
+
+    let dpi = dpi_scaling.0;
+    let mut text = console.log.join("\n");
+    text.push_str(&format!("\n> {}_", console.input));
+
+    text_buffer.render_text(
+        Vec2::new(screen_dimensions.as_vec().x * 0.25, 4.0 * dpi),
+        &text,
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::Default,
+        Vec4::new(1.0, 1.0, 1.0, 1.0),
+    );
+}
+
+#[test]
+fn parsing_console_commands() {
+    assert_eq!(
+        parse("spawn mouse 10 enemy"),
+        Ok(ConsoleCommand::Spawn {
+            unit: Unit::MouseMarine,
+            count: 10,
+            side: SpawnSide::Enemy
+        })
+    );
+    assert_eq!(
+        parse("  spawn   medic "),
+        Ok(ConsoleCommand::Spawn {
+            unit: Unit::Medic,
+            count: 1,
+            side: SpawnSide::Friendly
+        })
+    );
+    assert_eq!(
+        parse("give_cheese 500"),
+        Ok(ConsoleCommand::GiveCheese(500))
+    );
+    assert_eq!(parse("set_speed 2.0"), Ok(ConsoleCommand::SetSpeed(2.0)));
+    assert_eq!(parse("reveal_map"), Ok(ConsoleCommand::RevealMap));
+    assert_eq!(
+        parse("debug navmesh"),
+        Ok(ConsoleCommand::ToggleDebug(DebugOverlay::Navmesh))
+    );
+
+    assert!(parse("spawn cat").is_err());
+    assert!(parse("spawn mouse 1000").is_err());
+    assert!(parse("give_cheese lots").is_err());
+    assert!(parse("set_speed 100").is_err());
+    assert!(parse("kill_selected now").is_err());
+    assert!(parse("debug everything").is_err());
+    assert!(parse("").is_err());
+}
@@ -1,12 +1,26 @@
 use super::*;
-use crate::assets::ModelAnimations;
+use crate::audio::{Sound, SoundEvents, VoiceLine};
+use crate::input::{Action, Input};
+use crate::net::{CastTarget, Lockstep, PlayerCommand, RallyTarget};
 use crate::resources::{
-    CheeseCoins, CommandMode, ControlGroups, GameStats, Keypress, Keypresses, LoseCondition, Mode,
-    Objectives, RayCastLocation, SelectedUnitsAbilities, TotalTime, WinCondition,
+    CheeseCoins, CommandMode, ControlGroups, DpiScaling, Formation, GameSpeed, GameStats, Keypress,
+    Keypresses, LoseCondition, Mode, Objectives, Outcome, RayCastLocation, SelectedUnitsAbilities,
+    Settings, TotalTime, WinCondition,
 };
+use abilities::can_cast;
 
 #[legion::system]
-#[write_component(RecruitmentQueue)]
+#[read_component(RecruitmentQueue)]
+#[read_component(Research)]
+#[read_component(Superweapon)]
+#[read_component(Building)]
+#[read_component(Side)]
+#[read_component(FullyBuilt)]
+#[read_component(NetworkId)]
+#[read_component(Abilities)]
+#[read_component(Energy)]
+#[read_component(AbilityCooldowns)]
+#[read_component(Burrow)]
 pub fn handle_keypresses(
     #[resource] keypresses: &mut Keypresses,
     #[resource] camera_controls: &mut CameraControls,
@@ -17,9 +31,18 @@ pub fn handle_keypresses(
     #[resource] selected_units_abilities: &SelectedUnitsAbilities,
     #[resource] mode: &mut Mode,
     #[resource] total_time: &TotalTime,
-    #[resource] game_stats: &mut GameStats,
-    world: &mut SubWorld,
+    #[resource] lockstep: &mut Lockstep,
+    #[resource] player_side: &PlayerSide,
+    #[resource] settings: &Settings,
+    #[resource] inspector: &mut Inspector,
+    #[resource] game_speed: &mut GameSpeed,
+    #[resource] upgrades: &Upgrades,
+    world: &SubWorld,
 ) {
+    let input_map = &settings.input_map;
+    // The other player would have to agree to any changes to the speed.
+    let can_change_speed = !lockstep.is_networked();
+
     for Keypress {
         code,
         scancode,
@@ -29,9 +52,28 @@ pub fn handle_keypresses(
         log::trace!("{:?} (scancode: {}) pressed: {}", code, scancode, pressed);
 
         if let Some(code) = code {
+            let is_ability_hotkey = |code| {
+                selected_units_abilities
+                    .0
+                    .keys()
+                    .any(|ability| ability.hotkey == code)
+            };
+
             if pressed {
                 for (ability, casters) in selected_units_abilities.0.iter() {
                     if code == ability.hotkey {
+                        // Greyed out on the command card.
+                        if !tech::missing_prerequisites(
+                            &ability.ability_type,
+                            player_side.0,
+                            upgrades,
+                            world,
+                        )
+                        .is_empty()
+                        {
+                            continue;
+                        }
+
                         match ability.ability_type {
                             AbilityType::SetRecruitmentWaypoint => {
                                 rts_controls.mode = CommandMode::SetRecruitmentWaypoint;
@@ -39,31 +81,117 @@ pub fn handle_keypresses(
                             AbilityType::Build(building) => {
                                 rts_controls.mode = CommandMode::Construct { building };
                             }
+                            AbilityType::LockGate => {
+                                let gates = casters
+                                    .iter()
+                                    .filter_map(|gate| {
+                                        <&NetworkId>::query().get(world, *gate).ok().copied()
+                                    })
+                                    .collect();
+
+                                lockstep.issue(player_side.0, PlayerCommand::LockGates { gates });
+                            }
+                            AbilityType::Burrow => {
+                                let units = casters
+                                    .iter()
+                                    .filter_map(|unit| {
+                                        <&NetworkId>::query().get(world, *unit).ok().copied()
+                                    })
+                                    .collect();
+
+                                lockstep.issue(player_side.0, PlayerCommand::Burrow { units });
+                            }
+                            AbilityType::Active(ability) => {
+                                let ready: Vec<NetworkId> = casters
+                                    .iter()
+                                    .filter(|caster| can_cast(ability, **caster, world))
+                                    .filter_map(|caster| {
+                                        <&NetworkId>::query().get(world, *caster).ok().copied()
+                                    })
+                                    .collect();
+
+                                if ready.is_empty() {
+                                    continue;
+                                }
+
+                                if ability.stats().targeting == Targeting::Instant {
+                                    log::trace!(target: "command-recording", "{:?}: Casting {:?}", total_time.0, ability);
+
+                                    lockstep.issue(
+                                        player_side.0,
+                                        PlayerCommand::Cast {
+                                            units: ready,
+                                            ability,
+                                            target: CastTarget::Caster,
+                                            queue: rts_controls.shift_held,
+                                        },
+                                    );
+                                } else {
+                                    rts_controls.mode = CommandMode::Cast { ability };
+                                }
+                            }
+                            AbilityType::LaunchStrike => {
+                                let ready = casters.iter().any(|caster| {
+                                    <&Superweapon>::query()
+                                        .filter(component::<FullyBuilt>())
+                                        .get(world, *caster)
+                                        .map(|superweapon| superweapon.is_ready())
+                                        .unwrap_or(false)
+                                });
+
+                                if ready {
+                                    rts_controls.mode = CommandMode::TargetStrike;
+                                }
+                            }
+                            AbilityType::Research(upgrade) => {
+                                let cost = upgrade.stats().cost;
+
+                                if cost > cheese_coins.0 {
+                                    continue;
+                                }
+
+                                let idle_building = casters.iter().find_map(|caster| {
+                                    <(&Research, &NetworkId)>::query()
+                                        .filter(component::<FullyBuilt>())
+                                        .get(world, *caster)
+                                        .ok()
+                                        .filter(|(research, _)| research.current.is_none())
+                                        .map(|(_, id)| *id)
+                                });
+
+                                if let Some(building) = idle_building {
+                                    cheese_coins.0 -= cost;
+                                    log::trace!(target: "command-recording", "{:?}: Researching {:?}", total_time.0, upgrade);
+
+                                    lockstep.issue(
+                                        player_side.0,
+                                        PlayerCommand::Research { building, upgrade },
+                                    );
+                                }
+                            }
                             AbilityType::Recruit(unit) => {
                                 if unit.stats().cost <= cheese_coins.0 {
-                                    let entity_with_shortest_recruitment_queue = casters
+                                    let building_with_shortest_recruitment_queue = casters
                                         .iter()
                                         .filter_map(|caster| {
-                                            <&RecruitmentQueue>::query()
+                                            <(&RecruitmentQueue, &NetworkId)>::query()
                                                 .filter(component::<FullyBuilt>())
                                                 .get(world, *caster)
                                                 .ok()
-                                                .map(|queue| (caster, queue.length()))
+                                                .map(|(queue, id)| (*id, queue.length()))
                                         })
                                         .min_by_key(|(_, queue_len)| *queue_len)
-                                        .map(|(entity, _)| *entity);
+                                        .map(|(id, _)| id);
 
-                                    if let Some(entity) = entity_with_shortest_recruitment_queue {
+                                    if let Some(building) = building_with_shortest_recruitment_queue
+                                    {
                                         cheese_coins.0 -= unit.stats().cost;
                                         log::trace!(target: "command-recording", "{:?}: Recruiting {:?}", total_time.0, unit);
 
-                                        game_stats.units_recruited += 1;
-
-                                        <&mut RecruitmentQueue>::query()
-                                            .get_mut(world, entity)
-                                            .unwrap()
-                                            .queue
-                                            .push_back(unit);
+                                        lockstep.issue(
+                                            player_side.0,
+                                            PlayerCommand::Recruit { building, unit },
+                                        );
                                     }
                                 }
                             }
@@ -72,25 +200,83 @@ pub fn handle_keypresses(
                 }
             }
 
+            for action in input_map.actions(Input::Key(code)) {
+                match action {
+                    Action::CameraUp => camera_controls.up = pressed,
+                    Action::CameraDown => camera_controls.down = pressed,
+                    Action::CameraLeft => camera_controls.left = pressed,
+                    Action::CameraRight => camera_controls.right = pressed,
+                    // Q and E (by default) are also ability hotkeys, so they only rotate the camera
+                    // when none of the selected units have an ability using them. Releasing them
+                    // always stops though.
+                    Action::CameraRotateLeft if !pressed || !is_ability_hotkey(code) => {
+                        camera_controls.rotate_left = pressed
+                    }
+                    Action::CameraRotateRight if !pressed || !is_ability_hotkey(code) => {
+                        camera_controls.rotate_right = pressed
+                    }
+                    Action::Append => rts_controls.shift_held = pressed,
+                    Action::Group => rts_controls.control_held = pressed,
+                    Action::Stop if pressed => rts_controls.stop_pressed = true,
+                    Action::Move if pressed => rts_controls.mode = CommandMode::Move,
+                    Action::AttackMove if pressed => rts_controls.mode = CommandMode::AttackMove,
+                    Action::Patrol if pressed => rts_controls.mode = CommandMode::Patrol,
+                    Action::Guard if pressed => rts_controls.mode = CommandMode::Guard,
+                    Action::HoldPosition if pressed => rts_controls.hold_position_pressed = true,
+                    Action::CycleStance if pressed => rts_controls.cycle_stance_pressed = true,
+                    Action::SelectIdleWorker if pressed => {
+                        rts_controls.select_idle_worker_pressed = true
+                    }
+                    Action::SelectIdleArmy if pressed => {
+                        rts_controls.select_idle_army_pressed = true
+                    }
+                    Action::JumpToAlert if pressed => rts_controls.jump_to_alert_pressed = true,
+                    Action::CycleFormation if pressed => {
+                        rts_controls.formation = rts_controls.formation.next()
+                    }
+                    Action::ToggleInspector if pressed => inspector.enabled = !inspector.enabled,
+                    Action::PauseGame if pressed && can_change_speed => {
+                        game_speed.paused = !game_speed.paused
+                    }
+                    // The inspector uses the same keys for changing values.
+                    Action::IncreaseGameSpeed
+                        if pressed && can_change_speed && !inspector.enabled =>
+                    {
+                        game_speed.faster()
+                    }
+                    Action::DecreaseGameSpeed
+                        if pressed && can_change_speed && !inspector.enabled =>
+                    {
+                        game_speed.slower()
+                    }
+                    Action::Cancel if pressed => {
+                        if rts_controls.mode != CommandMode::Normal {
+                            rts_controls.mode = CommandMode::Normal;
+                        } else {
+                            *mode = Mode::PlayingMenu;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
             match code {
                 //VirtualKeyCode::X if pressed => player_side.0 = Side::Purple,
-                VirtualKeyCode::Up => camera_controls.up = pressed,
-                VirtualKeyCode::Down => camera_controls.down = pressed,
-                VirtualKeyCode::Left => camera_controls.left = pressed,
-                VirtualKeyCode::Right => camera_controls.right = pressed,
-                VirtualKeyCode::LShift | VirtualKeyCode::RShift => {
-                    rts_controls.shift_held = pressed
-                }
-                VirtualKeyCode::LControl => rts_controls.control_held = pressed,
-                VirtualKeyCode::S if pressed => rts_controls.stop_pressed = true,
-                VirtualKeyCode::A if pressed => rts_controls.mode = CommandMode::AttackMove,
                 VirtualKeyCode::T if pressed => debug_controls.set_pathfinding_start_pressed = true,
-                VirtualKeyCode::Escape if pressed => {
-                    if rts_controls.mode != CommandMode::Normal {
-                        rts_controls.mode = CommandMode::Normal;
-                    } else {
-                        *mode = Mode::PlayingMenu;
-                    }
+
+                VirtualKeyCode::PageUp if pressed && inspector.enabled => {
+                    inspector.field = inspector.field.saturating_sub(1)
+                }
+                VirtualKeyCode::PageDown if pressed && inspector.enabled => inspector.field += 1,
+                VirtualKeyCode::Equals | VirtualKeyCode::NumpadAdd
+                    if pressed && inspector.enabled =>
+                {
+                    inspector.adjustment += if rts_controls.shift_held { 10 } else { 1 }
+                }
+                VirtualKeyCode::Minus | VirtualKeyCode::NumpadSubtract
+                    if pressed && inspector.enabled =>
+                {
+                    inspector.adjustment -= if rts_controls.shift_held { 10 } else { 1 }
                 }
 
                 VirtualKeyCode::Key0 if pressed => rts_controls.control_group_key_pressed[0] = true,
@@ -128,12 +314,19 @@ pub fn handle_keypresses(
 #[legion::system]
 pub fn control_camera(
     #[resource] camera: &mut Camera,
+    #[resource] terrain: &Terrain,
     #[resource] camera_controls: &mut CameraControls,
     #[resource] mouse_state: &MouseState,
     #[resource] screen_dimensions: &ScreenDimensions,
-    #[resource] delta_time: &DeltaTime,
+    // So that the camera still moves at the same speed when the game is sped up or paused.
+    #[resource] delta_time: &UnscaledDeltaTime,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] rts_controls: &RtsControls,
+    #[resource] settings: &Settings,
+    #[resource] camera_shake: &mut CameraShake,
 ) {
-    let speed = 45.0 * delta_time.0;
+    let speed = settings.camera_speed * delta_time.0;
+    let minimap_area = MinimapArea::new(screen_dimensions, dpi_scaling);
 
     let edge_thickness = 50.0;
     let &ScreenDimensions {
@@ -144,33 +337,80 @@ pub fn control_camera(
     let screen_height = screen_height as f32;
     let mouse_x = mouse_state.position.x;
     let mouse_y = mouse_state.position.y;
+    // The minimap and the HUD are along the edges of the screen, so don't scroll while using them.
+    // Edge scrolling while dragging the view around would fight with the drag too.
+    let mouse_over_minimap = minimap_area.contains(mouse_state.position);
+    let mouse_over_hud = hud_contains(mouse_state.position, screen_dimensions, dpi_scaling);
+    let edge_scroll = !mouse_over_minimap && !mouse_over_hud && !camera_controls.middle_held;
+
+    // The camera pulls all the way out and stays put while aiming a strike, so that it doesn't run
+    // off when the cursor goes near the edge of the screen. The minimap can aim anywhere else.
+    if rts_controls.mode == CommandMode::TargetStrike {
+        camera.zoom_out_fully();
+        camera_controls.zoom_delta = 0.0;
+        camera_controls.drag_delta = Vec2::zero();
+        camera.update(delta_time.0, terrain);
+        camera_shake.update(camera, delta_time.0, settings.camera_shake);
+        return;
+    }
+
+    // Scroll relative to the way the camera is facing.
+    let right = camera.right() * speed;
+    let forwards = camera.forwards() * speed;
 
-    let right = Vec2::new(speed, 0.0);
-    let forwards = Vec2::new(0.0, -speed);
+    if camera_controls.left || (edge_scroll && mouse_x < edge_thickness) {
+        camera.pan(-right);
+    }
 
-    if camera_controls.left || mouse_x < edge_thickness {
-        camera.looking_at -= right;
+    if camera_controls.right || (edge_scroll && mouse_x > screen_width - edge_thickness) {
+        camera.pan(right);
     }
 
-    if camera_controls.right || mouse_x > screen_width - edge_thickness {
-        camera.looking_at += right;
+    if camera_controls.up || (edge_scroll && mouse_y < edge_thickness) {
+        camera.pan(forwards);
     }
 
-    if camera_controls.up || mouse_y < edge_thickness {
-        camera.looking_at += forwards;
+    if camera_controls.down || (edge_scroll && mouse_y > screen_height - edge_thickness) {
+        camera.pan(-forwards);
     }
 
-    if camera_controls.down || mouse_y > screen_height - edge_thickness {
-        camera.looking_at -= forwards;
+    // Drag the view so that the point on the ground that was under the cursor stays under it.
+    if camera_controls.drag_delta != Vec2::zero() {
+        let grabbed = camera.cast_ray(
+            mouse_state.position - camera_controls.drag_delta,
+            screen_dimensions,
+        );
+        let current = camera.cast_ray(mouse_state.position, screen_dimensions);
+        camera.shift(grabbed - current);
+        camera_controls.drag_delta = Vec2::zero();
     }
 
-    camera.looking_at.x = camera.looking_at.x.min(100.0).max(-100.0);
-    camera.looking_at.y = camera.looking_at.y.min(100.0).max(-100.0);
+    let rotation_speed = 2.0 * delta_time.0;
+
+    if camera_controls.rotate_left {
+        camera.rotate(-rotation_speed);
+    }
+
+    if camera_controls.rotate_right {
+        camera.rotate(rotation_speed);
+    }
+
+    // Clicking on the minimap (or dragging across it) moves the camera to that point. In other
+    // command modes, clicking on the minimap issues the command there instead.
+    let pressed_on_minimap = match mouse_state.left_state.is_held() {
+        Some(start) => minimap_area.contains(start),
+        None => mouse_state.left_state.was_clicked() && mouse_over_minimap,
+    };
+
+    if pressed_on_minimap && rts_controls.mode == CommandMode::Normal {
+        camera.jump_to(minimap_area.to_world(mouse_state.position));
+    }
 
-    camera.distance = (camera.distance - camera_controls.zoom_delta * 0.01)
-        .max(5.0)
-        .min(90.0);
+    camera.zoom(camera_controls.zoom_delta * 0.01);
     camera_controls.zoom_delta = 0.0;
+
+    camera.update(delta_time.0, terrain);
+    camera_shake.update(camera, delta_time.0, settings.camera_shake);
 }
 
 #[legion::system]
@@ -178,14 +418,31 @@ pub fn control_camera(
 #[read_component(Position)]
 pub fn cast_ray(
     #[resource] camera: &Camera,
+    #[resource] terrain: &Terrain,
     #[resource] mouse_state: &MouseState,
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] ray_cast_location: &mut RayCastLocation,
     #[resource] rts_controls: &RtsControls,
+    #[resource] dpi_scaling: &DpiScaling,
     world: &SubWorld,
 ) {
-    ray_cast_location.pos = camera.cast_ray(mouse_state.position, screen_dimensions);
+    let minimap_area = MinimapArea::new(screen_dimensions, dpi_scaling);
+
+    // Pointing at the minimap is the same as pointing at that part of the map.
+    ray_cast_location.pos = if minimap_area.contains(mouse_state.position) {
+        minimap_area.to_world(mouse_state.position)
+    } else {
+        camera.cast_ray_onto_terrain(mouse_state.position, screen_dimensions, terrain)
+    };
     ray_cast_location.snapped_to_guyser = None;
+
+    if let CommandMode::Construct {
+        building: Building::Wall | Building::Gate,
+    } = rts_controls.mode
+    {
+        ray_cast_location.pos = walls::snap_to_wall_grid(ray_cast_location.pos);
+    }
+
     if let CommandMode::Construct {
         building: Building::Pump,
     } = rts_controls.mode
@@ -209,30 +466,45 @@ pub fn cast_ray(
 #[read_component(Side)]
 #[read_component(Radius)]
 #[read_component(Building)]
-#[write_component(CommandQueue)]
-#[write_component(RecruitmentQueue)]
+#[read_component(Unit)]
+#[read_component(NetworkId)]
+#[read_component(RecruitmentQueue)]
+#[read_component(Superweapon)]
+#[read_component(FullyBuilt)]
+#[read_component(Cloaked)]
+#[read_component(CheeseGuyser)]
 pub fn handle_left_click(
     #[resource] mouse_state: &MouseState,
+    #[resource] camera: &Camera,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
     #[resource] ray_cast_location: &RayCastLocation,
     #[resource] rts_controls: &mut RtsControls,
     #[resource] player_side: &PlayerSide,
-    #[resource] map: &mut Map,
-    #[resource] animations: &ModelAnimations,
+    #[resource] map: &Map,
     #[resource] cheese_coins: &mut CheeseCoins,
     #[resource] total_time: &TotalTime,
-    world: &mut SubWorld,
+    #[resource] lockstep: &mut Lockstep,
+    #[resource] sound_events: &mut SoundEvents,
+    #[resource] spatial_hash: &SpatialHash,
+    world: &SubWorld,
     commands: &mut CommandBuffer,
 ) {
-    if !mouse_state.left_state.was_clicked() {
+    // Clicks on the HUD are handled by `handle_command_card_clicks`.
+    if !mouse_state.left_state.was_clicked()
+        || hud_contains(mouse_state.position, screen_dimensions, dpi_scaling)
+    {
         return;
     }
 
     match rts_controls.mode {
-        CommandMode::AttackMove => {
+        CommandMode::Move | CommandMode::AttackMove => {
             issue_command(
                 ray_cast_location,
                 rts_controls,
                 player_side,
+                lockstep,
+                sound_events,
                 world,
                 total_time.0,
             );
@@ -241,24 +513,115 @@ pub fn handle_left_click(
                 rts_controls.mode = CommandMode::Normal;
             }
         }
+        CommandMode::Patrol => {
+            let target = ray_cast_location.pos;
+
+            log::trace!(target: "command-recording", "{:?}: Patrolling to {:?}", total_time.0, target);
+
+            let units = selected_network_ids(player_side, world);
+
+            if let Some(unit) = selected_speaker(player_side, world) {
+                sound_events.say(unit, VoiceLine::Move);
+            }
+
+            lockstep.issue(
+                player_side.0,
+                PlayerCommand::Patrol {
+                    units,
+                    target,
+                    formation: rts_controls.formation,
+                    queue: rts_controls.shift_held,
+                },
+            );
+
+            if !rts_controls.shift_held {
+                rts_controls.mode = CommandMode::Normal;
+            }
+        }
+        CommandMode::Guard => {
+            let center = ray_cast_location.pos;
+
+            log::trace!(target: "command-recording", "{:?}: Guarding {:?}", total_time.0, center);
+
+            let units = selected_network_ids(player_side, world);
+
+            if let Some(unit) = selected_speaker(player_side, world) {
+                sound_events.say(unit, VoiceLine::Move);
+            }
+
+            lockstep.issue(
+                player_side.0,
+                PlayerCommand::Guard {
+                    units,
+                    center,
+                    radius: combat::GUARD_RADIUS,
+                    formation: rts_controls.formation,
+                    queue: rts_controls.shift_held,
+                },
+            );
+
+            if !rts_controls.shift_held {
+                rts_controls.mode = CommandMode::Normal;
+            }
+        }
+        // Handled by `control_camera`.
+        CommandMode::Normal
+            if MinimapArea::new(screen_dimensions, dpi_scaling).contains(mouse_state.position) => {}
         CommandMode::Normal => {
             let position = ray_cast_location.pos;
 
-            let entity = <(Entity, &Position, Option<&Selected>, &Side, &Radius)>::query()
+            let entity = spatial_hash.query_radius(position, 0.0).find_map(|entity| {
+                let (pos, selected, side, radius, cloaked) = <(
+                    &Position,
+                    Option<&Selected>,
+                    &Side,
+                    &Radius,
+                    Option<&Cloaked>,
+                )>::query()
                 .filter(component::<Selectable>())
-                .iter(world)
-                .find(|(_, pos, .., radius)| (position - pos.0).mag_sq() < radius.0.powi(2))
-                .map(|(entity, _, selected, side, _)| (entity, selected.is_some(), side));
+                .get(world, entity)
+                .ok()?;
+
+                if (position - pos.0).mag_sq() < radius.0.powi(2)
+                    && !stealth::is_hidden(cloaked, player_side.0, *side)
+                {
+                    Some((entity, selected.is_some(), side))
+                } else {
+                    None
+                }
+            });
 
             if !rts_controls.shift_held {
                 deselect_all(world, commands);
             }
 
             if let Some((entity, is_selected, side)) = entity {
-                if rts_controls.shift_held && is_selected {
-                    commands.remove_component::<Selected>(*entity);
+                // Control-clicking selects all of the player's units (or buildings) of the same
+                // type that are on the screen.
+                if rts_controls.control_held && *side == player_side.0 {
+                    let unit = <&Unit>::query().get(world, entity).ok().copied();
+                    let building = <&Building>::query().get(world, entity).ok().copied();
+                    let screen = SelectBox::new(
+                        camera,
+                        screen_dimensions,
+                        Vec2::zero(),
+                        screen_dimensions.as_vec(),
+                    );
+
+                    <(Entity, &Position, &Side, Option<&Unit>, Option<&Building>)>::query()
+                        .filter(component::<Selectable>())
+                        .iter(world)
+                        .filter(|(_, position, side, other_unit, other_building)| {
+                            **side == player_side.0
+                                && other_unit.copied() == unit
+                                && other_building.copied() == building
+                                && screen.contains(position.0)
+                        })
+                        .for_each(|(entity, ..)| commands.add_component(*entity, Selected));
+                } else if rts_controls.shift_held && is_selected {
+                    commands.remove_component::<Selected>(entity);
                 } else if !rts_controls.shift_held {
-                    commands.add_component(*entity, Selected);
+                    commands.add_component(entity, Selected);
                 // If we're holding shift but haven't selected the unit, we need to check if we can add it
                 // the current selection, because having a selection of a bunch of enemy units or a mixture
                 // doesn't really make sense.
@@ -269,7 +632,15 @@ pub fn handle_left_click(
                         .all(|side| *side == player_side.0);
 
                     if only_player_units_selected && *side == player_side.0 {
-                        commands.add_component(*entity, Selected);
+                        commands.add_component(entity, Selected);
+                    }
+                }
+
+                let deselecting = rts_controls.shift_held && is_selected;
+
+                if *side == player_side.0 && !deselecting {
+                    if let Ok(unit) = <&Unit>::query().get(world, entity) {
+                        sound_events.say(*unit, VoiceLine::Select);
                     }
                 }
             }
@@ -278,14 +649,17 @@ pub fn handle_left_click(
                 rts_controls.mode = CommandMode::Normal;
             }
         }
+        // Handled by `handle_wall_placement`, as walls can be dragged out.
+        CommandMode::Construct {
+            building: Building::Wall,
+        } => {}
         CommandMode::Construct { building } => {
             let built = build_building_command(
                 building,
                 ray_cast_location,
                 player_side,
                 map,
-                animations,
-                commands,
+                lockstep,
                 world,
                 rts_controls,
                 cheese_coins,
@@ -296,32 +670,110 @@ pub fn handle_left_click(
                 rts_controls.mode = CommandMode::Normal;
             }
         }
-        CommandMode::SetRecruitmentWaypoint => {
+        CommandMode::Cast { ability } => {
             let position = ray_cast_location.pos;
 
-            log::trace!(target: "command-recording", "{:?}: Setting waypoint {:?}", total_time.0, position);
+            let target = match ability.stats().targeting {
+                Targeting::Unit => <(&NetworkId, &Position, &Side, &Radius)>::query()
+                    .filter(component::<Unit>())
+                    .iter(world)
+                    .find(|(_, pos, side, radius)| {
+                        **side == player_side.0 && (position - pos.0).mag_sq() < radius.0.powi(2)
+                    })
+                    .map(|(id, ..)| CastTarget::Unit(*id)),
+                Targeting::Area(_) => Some(CastTarget::Point(position)),
+                Targeting::Instant => Some(CastTarget::Caster),
+            };
+
+            // Keep waiting for a proper target if the click missed.
+            if let Some(target) = target {
+                log::trace!(target: "command-recording", "{:?}: Casting {:?} on {:?}", total_time.0, ability, target);
+
+                lockstep.issue(
+                    player_side.0,
+                    PlayerCommand::Cast {
+                        units: selected_network_ids(player_side, world),
+                        ability,
+                        target,
+                        queue: rts_controls.shift_held,
+                    },
+                );
+
+                sound_events.play(Sound::Acknowledgement);
 
-            <(&mut RecruitmentQueue, &Side)>::query()
-                .filter(component::<Selected>())
-                .iter_mut(world)
+                if !rts_controls.shift_held {
+                    rts_controls.mode = CommandMode::Normal;
+                }
+            }
+        }
+        CommandMode::TargetStrike => {
+            let target = ray_cast_location.pos;
+
+            let silo = <(&NetworkId, &Superweapon, &Side)>::query()
+                .filter(component::<Selected>() & component::<FullyBuilt>())
+                .iter(world)
+                .find(|(_, superweapon, side)| **side == player_side.0 && superweapon.is_ready())
+                .map(|(id, ..)| *id);
+
+            if let Some(silo) = silo {
+                log::trace!(target: "command-recording", "{:?}: Launching a strike at {:?}", total_time.0, target);
+
+                lockstep.issue(player_side.0, PlayerCommand::LaunchStrike { silo, target });
+            }
+
+            rts_controls.mode = CommandMode::Normal;
+        }
+        CommandMode::SetRecruitmentWaypoint => {
+            let target = rally_target(ray_cast_location.pos, player_side, world);
+
+            log::trace!(target: "command-recording", "{:?}: Setting waypoint {:?}", total_time.0, target);
+
+            let buildings = <(&NetworkId, &Side)>::query()
+                .filter(component::<Selected>() & component::<RecruitmentQueue>())
+                .iter(world)
                 .filter(|(_, side)| **side == player_side.0)
-                .for_each(|(queue, _)| {
-                    queue.waypoint = position;
-                });
+                .map(|(id, _)| *id)
+                .collect();
+
+            lockstep.issue(
+                player_side.0,
+                PlayerCommand::SetWaypoint { buildings, target },
+            );
 
             rts_controls.mode = CommandMode::Normal;
         }
     }
 }
 
+// Rally points can go on one of the player's units, or on a cheese guyser, as well as on the ground.
+fn rally_target(position: Vec2, player_side: &PlayerSide, world: &SubWorld) -> RallyTarget {
+    let unit = <(&NetworkId, &Position, &Side, &Radius)>::query()
+        .filter(component::<Unit>())
+        .iter(world)
+        .find(|(_, pos, side, radius)| {
+            **side == player_side.0 && (position - pos.0).mag_sq() < radius.0.powi(2)
+        })
+        .map(|(id, ..)| RallyTarget::Unit(*id));
+
+    // The same distance that pumps snap to guysers from.
+    let guyser = || {
+        <(&NetworkId, &Position)>::query()
+            .filter(component::<CheeseGuyser>())
+            .iter(world)
+            .find(|(_, pos)| (position - pos.0).mag_sq() <= 4.0_f32.powi(2))
+            .map(|(id, _)| RallyTarget::Guyser(*id))
+    };
+
+    unit.or_else(guyser).unwrap_or(RallyTarget::Point(position))
+}
+
 fn build_building_command(
     building: Building,
     ray_cast_location: &RayCastLocation,
     player_side: &PlayerSide,
-    map: &mut Map,
-    animations: &ModelAnimations,
-    commands: &mut CommandBuffer,
-    world: &mut SubWorld,
+    map: &Map,
+    lockstep: &mut Lockstep,
+    world: &SubWorld,
     rts_controls: &RtsControls,
     cheese_coins: &mut CheeseCoins,
     total_time: f32,
@@ -329,54 +781,65 @@ fn build_building_command(
     if building.stats().cost > cheese_coins.0
         || (building == Building::Pump && ray_cast_location.snapped_to_guyser.is_none())
         || unit_under_building(ray_cast_location.pos, building.stats().dimensions, world)
+        || !map.can_insert(ray_cast_location.pos, building.stats().dimensions)
     {
         return false;
     }
 
-    if let Some(building_entity) = building.add_to_world_to_construct(
-        commands,
-        ray_cast_location.pos,
-        player_side.0,
-        animations,
-        map,
-    ) {
-        cheese_coins.0 -= building.stats().cost;
-
-        if let Building::Pump = building {
-            let guyser_entity = ray_cast_location.snapped_to_guyser.unwrap();
-            commands.add_component(
-                guyser_entity,
-                CheeseGuyserBuiltOn {
-                    pump: building_entity,
-                },
-            );
-        }
+    let guyser = ray_cast_location
+        .snapped_to_guyser
+        .and_then(|guyser| <&NetworkId>::query().get(world, guyser).ok().copied());
 
-        log::trace!(target: "command-recording", "{:?}: Building {:?} at {:?}", total_time, building, ray_cast_location);
+    cheese_coins.0 -= building.stats().cost;
 
-        let command = Command::Build {
-            target: building_entity,
-            // Kinda hacky? If we put `ActionState::OutOfRange` with an empty vec it
-            // wouldn't get updated with the current `set_movement_paths` code.
-            state: ActionState::InRange,
-        };
+    log::trace!(target: "command-recording", "{:?}: Building {:?} at {:?}", total_time, building, ray_cast_location);
 
-        <(&mut CommandQueue, &Side)>::query()
-            .filter(component::<Selected>() & component::<CanBuild>())
-            .iter_mut(world)
-            .filter(|(_, side)| **side == player_side.0)
-            .for_each(|(commands, _)| {
-                if !rts_controls.shift_held {
-                    commands.0.clear();
-                }
+    lockstep.issue(
+        player_side.0,
+        PlayerCommand::Construct {
+            builders: selected_network_ids(player_side, world),
+            building,
+            position: ray_cast_location.pos,
+            guyser,
+            queue: rts_controls.shift_held,
+        },
+    );
 
-                commands.0.push_back(command.clone());
-            });
+    true
+}
 
-        true
-    } else {
-        false
+// The ids of the player's selected units (or buildings), for sending commands to.
+fn selected_network_ids(player_side: &PlayerSide, world: &SubWorld) -> Vec<NetworkId> {
+    <(&NetworkId, &Side)>::query()
+        .filter(component::<Selected>())
+        .iter(world)
+        .filter(|(_, side)| **side == player_side.0)
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+// Whichever kind of unit there's most of in a group answers for all of them.
+fn speaker(units: impl Iterator<Item = Unit>) -> Option<Unit> {
+    let mut counts = std::collections::BTreeMap::new();
+
+    for unit in units {
+        *counts.entry(unit).or_insert(0) += 1;
     }
+
+    counts
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(unit, _)| unit)
+}
+
+fn selected_speaker(player_side: &PlayerSide, world: &SubWorld) -> Option<Unit> {
+    speaker(
+        <(&Unit, &Side)>::query()
+            .filter(component::<Selected>())
+            .iter(world)
+            .filter(|(_, side)| **side == player_side.0)
+            .map(|(unit, _)| *unit),
+    )
 }
 
 #[legion::system]
@@ -385,14 +848,19 @@ fn build_building_command(
 #[read_component(Side)]
 #[read_component(Radius)]
 #[read_component(Building)]
-#[write_component(CommandQueue)]
+#[read_component(NetworkId)]
+#[read_component(Selected)]
+#[read_component(Unit)]
+#[read_component(Cloaked)]
 pub fn handle_right_click(
     #[resource] mouse_state: &MouseState,
     #[resource] ray_cast_location: &RayCastLocation,
     #[resource] rts_controls: &mut RtsControls,
     #[resource] player_side: &PlayerSide,
     #[resource] total_time: &TotalTime,
-    world: &mut SubWorld,
+    #[resource] lockstep: &mut Lockstep,
+    #[resource] sound_events: &mut SoundEvents,
+    world: &SubWorld,
 ) {
     if !mouse_state.right_state.was_clicked() {
         return;
@@ -409,6 +877,8 @@ pub fn handle_right_click(
         ray_cast_location,
         rts_controls,
         player_side,
+        lockstep,
+        sound_events,
         world,
         total_time.0,
     )
@@ -418,136 +888,296 @@ fn issue_command(
     ray_cast_location: &RayCastLocation,
     rts_controls: &RtsControls,
     player_side: &PlayerSide,
-    world: &mut SubWorld,
+    lockstep: &mut Lockstep,
+    sound_events: &mut SoundEvents,
+    world: &SubWorld,
     total_time: f32,
 ) {
     let position = ray_cast_location.pos;
 
-    let entity_under_cursor = <(Entity, &Position, &Side, &Radius, Option<&Building>)>::query()
-        .iter(world)
-        .find(|(_, pos, _, radius, _)| (position - pos.0).mag_sq() < radius.0.powi(2))
-        .map(|(entity, _, side, .., building)| {
-            (*entity, *side == player_side.0, building.is_some())
-        });
+    let entity_under_cursor = <(
+        &NetworkId,
+        &Position,
+        &Side,
+        &Radius,
+        Option<&Building>,
+        Option<&Cloaked>,
+    )>::query()
+    .iter(world)
+    .find(|(_, pos, side, radius, _, cloaked)| {
+        (position - pos.0).mag_sq() < radius.0.powi(2)
+            && !stealth::is_hidden(*cloaked, player_side.0, **side)
+    })
+    .map(|(id, _, side, _, building, _)| (*id, *side == player_side.0, building.is_some()));
+
+    let units = selected_network_ids(player_side, world);
+    let queue = rts_controls.shift_held;
 
     let command = match entity_under_cursor {
-        Some((entity, false, _)) => Some(Command::new_attack(entity, true)),
-        Some((entity, true, true)) => Some(Command::Build {
-            target: entity,
-            state: ActionState::InRange,
+        Some((target, false, _)) => Some(PlayerCommand::Attack {
+            units,
+            target,
+            queue,
+        }),
+        Some((target, true, true)) => Some(PlayerCommand::HelpBuild {
+            units,
+            target,
+            queue,
+        }),
+        Some((target, true, false)) => Some(PlayerCommand::Follow {
+            units,
+            target,
+            queue,
         }),
-        Some((_, true, false)) => None,
         None => match rts_controls.mode {
-            CommandMode::Normal => Some(Command::MoveTo {
-                target: position,
-                path: Vec::new(),
-                attack_move: false,
-            }),
-            CommandMode::AttackMove => Some(Command::MoveTo {
-                target: position,
-                path: Vec::new(),
-                attack_move: true,
-            }),
-            CommandMode::Construct { .. } => None,
-            CommandMode::SetRecruitmentWaypoint => None,
+            CommandMode::Normal | CommandMode::Move | CommandMode::AttackMove => {
+                Some(PlayerCommand::Move {
+                    units,
+                    target: position,
+                    attack_move: rts_controls.mode == CommandMode::AttackMove,
+                    formation: rts_controls.formation,
+                    queue,
+                })
+            }
+            CommandMode::Patrol | CommandMode::Guard | CommandMode::Construct { .. } => None,
+            CommandMode::SetRecruitmentWaypoint
+            | CommandMode::Cast { .. }
+            | CommandMode::TargetStrike => None,
         },
     };
 
     if let Some(command) = command {
         log::trace!(target: "command-recording", "{:?}: Performing {:?}", total_time, command);
 
-        if let Command::Build { .. } = command {
-            <(&mut CommandQueue, &Side)>::query()
-                .filter(component::<Selected>() & component::<CanBuild>())
-                .iter_mut(world)
-                .filter(|(_, side)| **side == player_side.0)
-                .for_each(|(commands, _)| {
-                    if !rts_controls.shift_held {
-                        commands.0.clear();
-                    }
-
-                    commands.0.push_back(command.clone());
-                });
-        } else if let Command::Attack { .. } = command {
-            <(&mut CommandQueue, &Side)>::query()
-                .filter(component::<Selected>() & component::<CanAttack>())
-                .iter_mut(world)
-                .filter(|(_, side)| **side == player_side.0)
-                .for_each(|(commands, _)| {
-                    if !rts_controls.shift_held {
-                        commands.0.clear();
-                    }
+        let line = match command {
+            PlayerCommand::Attack { .. }
+            | PlayerCommand::Move {
+                attack_move: true, ..
+            } => VoiceLine::Attack,
+            _ => VoiceLine::Move,
+        };
 
-                    commands.0.push_back(command.clone());
-                });
-        } else {
-            <(&mut CommandQueue, &Side)>::query()
-                .filter(component::<Selected>())
-                .iter_mut(world)
-                .filter(|(_, side)| **side == player_side.0)
-                .for_each(|(commands, _)| {
-                    if !rts_controls.shift_held {
-                        commands.0.clear();
-                    }
+        lockstep.issue(player_side.0, command);
 
-                    commands.0.push_back(command.clone());
-                });
+        if let Some(unit) = selected_speaker(player_side, world) {
+            sound_events.say(unit, line);
         }
     } else {
         log::debug!("Ignoring command on {:?}", entity_under_cursor);
     }
 }
 
+// Work out where each unit should move to so that a group doesn't all fight over the same spot.
+// Returns a destination for each of the given positions.
+pub(super) fn formation_destinations(
+    formation: Formation,
+    target: Vec2,
+    positions: &[Vec2],
+    spacing: f32,
+) -> Vec<Vec2> {
+    let num_units = positions.len();
+
+    if num_units < 2 {
+        return vec![target; num_units];
+    }
+
+    let center = positions.iter().fold(Vec2::zero(), |sum, pos| sum + *pos) / num_units as f32;
+    let forwards = match (target - center).mag_sq() {
+        mag_sq if mag_sq > 0.0 => (target - center).normalized(),
+        _ => Vec2::new(0.0, -1.0),
+    };
+    let right = Vec2::new(-forwards.y, forwards.x);
+
+    // Slots are (across, back) coordinates, relative to the target, in units of `spacing`.
+    let slots: Vec<(f32, f32)> = match formation {
+        Formation::Line | Formation::Box => {
+            let row_length = match formation {
+                Formation::Line => num_units.min(12),
+                _ => (num_units as f32).sqrt().ceil() as usize,
+            };
+
+            (0..num_units)
+                .map(|i| {
+                    let row = i / row_length;
+                    let units_in_row = row_length.min(num_units - row * row_length);
+                    let column = i % row_length;
+                    (column as f32 - (units_in_row - 1) as f32 / 2.0, row as f32)
+                })
+                .collect()
+        }
+        Formation::Wedge => {
+            let mut slots = Vec::with_capacity(num_units);
+            let mut row = 0;
+
+            while slots.len() < num_units {
+                let units_in_row = (row + 1).min(num_units - slots.len());
+                for column in 0..units_in_row {
+                    slots.push((column as f32 - row as f32 / 2.0, row as f32));
+                }
+                row += 1;
+            }
+
+            slots
+        }
+    };
+
+    // Center the formation so that the middle of it ends up on the target.
+    let average_back = slots.iter().map(|(_, back)| back).sum::<f32>() / num_units as f32;
+
+    let slot_positions = slots.iter().map(|&(across, back)| {
+        target + (right * across - forwards * (back - average_back)) * spacing
+    });
+
+    // Greedily give each slot to the nearest unit that doesn't have one yet. This isn't optimal
+    // but it stops most units from crossing paths.
+    let mut destinations = vec![target; num_units];
+    let mut assigned = vec![false; num_units];
+
+    for slot in slot_positions {
+        let nearest = (0..num_units)
+            .filter(|i| !assigned[*i])
+            .min_by_key(|i| ordered_float::OrderedFloat((positions[*i] - slot).mag_sq()));
+
+        if let Some(nearest) = nearest {
+            assigned[nearest] = true;
+            destinations[nearest] = slot;
+        }
+    }
+
+    destinations
+}
+
 #[legion::system]
 #[read_component(Side)]
-#[write_component(CommandQueue)]
+#[read_component(NetworkId)]
 pub fn handle_stop_command(
     #[resource] rts_controls: &RtsControls,
     #[resource] player_side: &PlayerSide,
-    world: &mut SubWorld,
+    #[resource] lockstep: &mut Lockstep,
+    world: &SubWorld,
 ) {
     if !rts_controls.stop_pressed {
         return;
     }
 
-    <(&mut CommandQueue, &Side)>::query()
+    let units = selected_network_ids(player_side, world);
+    lockstep.issue(player_side.0, PlayerCommand::Stop { units });
+}
+
+#[legion::system]
+#[read_component(Side)]
+#[read_component(NetworkId)]
+pub fn handle_hold_position_command(
+    #[resource] rts_controls: &RtsControls,
+    #[resource] player_side: &PlayerSide,
+    #[resource] lockstep: &mut Lockstep,
+    world: &SubWorld,
+) {
+    if !rts_controls.hold_position_pressed {
+        return;
+    }
+
+    let units = selected_network_ids(player_side, world);
+    lockstep.issue(
+        player_side.0,
+        PlayerCommand::HoldPosition {
+            units,
+            queue: rts_controls.shift_held,
+        },
+    );
+}
+
+// Every selected unit is switched to the stance after the first one's, so that a mixed selection ends
+// up all using the same stance.
+#[legion::system]
+#[read_component(Side)]
+#[read_component(NetworkId)]
+#[read_component(Stance)]
+pub fn handle_stance_command(
+    #[resource] rts_controls: &RtsControls,
+    #[resource] player_side: &PlayerSide,
+    #[resource] lockstep: &mut Lockstep,
+    world: &SubWorld,
+) {
+    if !rts_controls.cycle_stance_pressed {
+        return;
+    }
+
+    let current = <(&Stance, &Side)>::query()
         .filter(component::<Selected>())
-        .iter_mut(world)
-        .filter(|(_, side)| **side == player_side.0)
-        .for_each(|(commands, _)| {
-            commands.0.clear();
-        });
+        .iter(world)
+        .find(|(_, side)| **side == player_side.0)
+        .map(|(stance, _)| *stance);
+
+    if let Some(current) = current {
+        let units = selected_network_ids(player_side, world);
+        lockstep.issue(
+            player_side.0,
+            PlayerCommand::SetStance {
+                units,
+                stance: current.next(),
+            },
+        );
+    }
 }
 
 #[legion::system]
 #[read_component(Entity)]
 #[read_component(Side)]
 #[read_component(Position)]
+#[read_component(Building)]
+#[read_component(Unit)]
 pub fn handle_drag_selection(
     #[resource] mouse_state: &MouseState,
     #[resource] camera: &Camera,
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] rts_controls: &RtsControls,
     #[resource] player_side: &PlayerSide,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] sound_events: &mut SoundEvents,
     command_buffer: &mut CommandBuffer,
     world: &SubWorld,
 ) {
-    if let Some(start) = mouse_state.left_state.was_dragged() {
+    let minimap_area = MinimapArea::new(screen_dimensions, dpi_scaling);
+
+    let placing_wall = rts_controls.mode
+        == CommandMode::Construct {
+            building: Building::Wall,
+        };
+
+    if let Some(start) = mouse_state.left_state.was_dragged().filter(|start| {
+        !placing_wall
+            && !minimap_area.contains(*start)
+            && !hud_contains(*start, screen_dimensions, dpi_scaling)
+    }) {
         let select_box = SelectBox::new(camera, screen_dimensions, start, mouse_state.position);
 
         if !rts_controls.shift_held {
             deselect_all(world, command_buffer);
         }
 
-        <(Entity, &Position, &Side)>::query()
-            .filter(component::<Selectable>())
-            .iter(world)
-            .filter(|(.., side)| **side == player_side.0)
-            .for_each(|(entity, position, _)| {
-                if select_box.contains(position.0) {
-                    command_buffer.add_component(*entity, Selected);
-                }
-            })
+        let in_box: Vec<_> =
+            <(Entity, &Position, &Side, Option<&Building>, Option<&Unit>)>::query()
+                .filter(component::<Selectable>())
+                .iter(world)
+                .filter(|(_, position, side, ..)| {
+                    **side == player_side.0 && select_box.contains(position.0)
+                })
+                .map(|(entity, .., building, unit)| (*entity, building.is_some(), unit.copied()))
+                .collect();
+
+        // If there are any units in the box then we only want to select those, as there's no
+        // point having buildings in a selection you're going to be ordering around.
+        let any_units = in_box.iter().any(|(_, is_building, _)| !is_building);
+
+        in_box
+            .iter()
+            .filter(|(_, is_building, _)| !any_units || !is_building)
+            .for_each(|(entity, ..)| command_buffer.add_component(*entity, Selected));
+
+        if let Some(unit) = speaker(in_box.iter().filter_map(|(.., unit)| *unit)) {
+            sound_events.say(unit, VoiceLine::Select);
+        }
     }
 }
 
@@ -564,9 +1194,11 @@ pub fn remove_dead_entities_from_control_groups(
 
 #[legion::system]
 #[read_component(Entity)]
+#[read_component(Unit)]
 pub fn handle_control_groups(
     #[resource] control_groups: &mut ControlGroups,
     #[resource] rts_controls: &RtsControls,
+    #[resource] sound_events: &mut SoundEvents,
     command_buffer: &mut CommandBuffer,
     world: &SubWorld,
 ) {
@@ -597,6 +1229,14 @@ pub fn handle_control_groups(
                 for entity in control_groups.0[i].iter() {
                     command_buffer.add_component(*entity, Selected);
                 }
+
+                let units = control_groups.0[i]
+                    .iter()
+                    .filter_map(|entity| <&Unit>::query().get(world, *entity).ok().copied());
+
+                if let Some(unit) = speaker(units) {
+                    sound_events.say(unit, VoiceLine::Select);
+                }
             }
         }
     }
@@ -605,13 +1245,25 @@ pub fn handle_control_groups(
 #[legion::system]
 #[read_component(Abilities)]
 #[read_component(Side)]
+#[read_component(Research)]
 pub fn update_selected_units_abilities(
     #[resource] player_side: &PlayerSide,
     #[resource] selected_units_abilities: &mut SelectedUnitsAbilities,
+    #[resource] upgrades: &Upgrades,
     world: &SubWorld,
 ) {
     selected_units_abilities.0.clear();
 
+    // Upgrades that have already been researched or are being researched somewhere are left off
+    // the command card.
+    let available = |ability: &Ability| match ability.ability_type {
+        AbilityType::Research(upgrade) => {
+            !upgrades.has(player_side.0, upgrade)
+                && !upgrades::is_researching(upgrade, player_side.0, world)
+        }
+        _ => true,
+    };
+
     <(Entity, &Abilities, &Side)>::query()
         .filter(component::<Selected>())
         .iter(world)
@@ -619,6 +1271,7 @@ pub fn update_selected_units_abilities(
         .flat_map(|(entity, abilities, _)| {
             abilities.0.iter().map(move |ability| (entity, *ability))
         })
+        .filter(|(_, ability)| available(ability))
         .for_each(|(entity, ability)| {
             selected_units_abilities
                 .0
@@ -628,7 +1281,7 @@ pub fn update_selected_units_abilities(
         });
 }
 
-fn deselect_all(world: &SubWorld, commands: &mut CommandBuffer) {
+pub(super) fn deselect_all(world: &SubWorld, commands: &mut CommandBuffer) {
     <Entity>::query()
         .filter(component::<Selected>())
         .for_each(world, |entity| {
@@ -636,40 +1289,65 @@ fn deselect_all(world: &SubWorld, commands: &mut CommandBuffer) {
         });
 }
 
+// Also used to tick off objectives in `render_ui`, so anything that calls this needs to be able to
+// read `Side`, `Building`, `FullyBuilt` and `ScriptTag`.
+pub(super) fn win_condition_met(
+    condition: &WinCondition,
+    player_side: &PlayerSide,
+    stats: &GameStats,
+    total_time: &TotalTime,
+    world: &SubWorld,
+) -> bool {
+    match condition {
+        WinCondition::DestroyAll => {
+            let all_destroyed = <&Side>::query()
+                .iter(world)
+                .all(|side| *side == player_side.0);
+            all_destroyed
+        }
+        WinCondition::BuildN(num, building) => {
+            let num_buildings = <(&Side, &Building)>::query()
+                .filter(component::<FullyBuilt>())
+                .iter(world)
+                .filter(|(side, building_type)| {
+                    **side == player_side.0 && building == *building_type
+                })
+                .count();
+            num_buildings as u8 >= *num
+        }
+        WinCondition::DestroyAllBuildings => <&Side>::query()
+            .filter(component::<Building>())
+            .iter(world)
+            .all(|side| *side == player_side.0),
+        WinCondition::CollectCheese(amount) => stats.side(player_side.0).cheese_gathered >= *amount,
+        WinCondition::SurviveMinutes(minutes) => total_time.0 >= *minutes as f32 * 60.0,
+        WinCondition::Destroy { tag, .. } => !<&ScriptTag>::query()
+            .iter(world)
+            .any(|script_tag| script_tag.0 == *tag),
+    }
+}
+
 #[legion::system]
 #[read_component(Side)]
 #[read_component(Building)]
+#[read_component(FullyBuilt)]
+#[read_component(ScriptTag)]
 pub fn update_playing_state(
     #[resource] objectives: &Objectives,
     #[resource] player_side: &PlayerSide,
+    #[resource] stats: &GameStats,
+    #[resource] total_time: &TotalTime,
     #[resource] mode: &mut Mode,
     world: &SubWorld,
 ) {
     let won = objectives
         .win_conditions
         .iter()
-        .all(|condition| match condition {
-            WinCondition::DestroyAll => {
-                let all_destroyed = <&Side>::query()
-                    .iter(world)
-                    .all(|side| *side == player_side.0);
-                all_destroyed
-            }
-            WinCondition::BuildN(num, building) => {
-                let num_buildings = <(&Side, &Building)>::query()
-                    .filter(component::<FullyBuilt>())
-                    .iter(world)
-                    .filter(|(side, building_type)| {
-                        **side == player_side.0 && building == *building_type
-                    })
-                    .count();
-                num_buildings as u8 >= *num
-            }
-        })
+        .all(|condition| win_condition_met(condition, player_side, stats, total_time, world))
         && !objectives.win_conditions.is_empty();
 
     if won {
-        *mode = Mode::ScenarioWon;
+        *mode = Mode::GameOver(Outcome::Won);
         return;
     }
 
@@ -688,7 +1366,7 @@ pub fn update_playing_state(
         });
 
     if lost {
-        *mode = Mode::ScenarioLost;
+        *mode = Mode::GameOver(Outcome::Lost);
     }
 }
 
@@ -703,6 +1381,10 @@ fn selection_and_deselection() {
     let mut resources = Resources::default();
     resources.insert(Camera::default());
     resources.insert(CameraControls::default());
+    resources.insert(SoundEvents::default());
+    resources.insert(CameraShake::default());
+    resources.insert(Alerts::default());
+    resources.insert(Upgrades::default());
     let screen_dimensions = ScreenDimensions {
         width: 1000,
         height: 1000,
@@ -717,19 +1399,46 @@ fn selection_and_deselection() {
     resources.insert(Map::new());
     resources.insert(Gravity(5.0));
     resources.insert(DebugControls::default());
+    resources.insert(Inspector::default());
+    resources.insert(IdleUnits::default());
+    resources.insert(WorldChecksum::default());
+    resources.insert(Console::default());
+    resources.insert(SpatialHash::default());
+    resources.insert(GameSpeed::default());
     resources.insert(rand::rngs::SmallRng::from_entropy());
     resources.insert(ModelAnimations::default());
+    resources.insert(Mode::Playing);
+    resources.insert(CheeseCoins(0));
+    resources.insert(SelectedUnitsAbilities::default());
+    resources.insert(Keypresses::default());
+    resources.insert(TotalTime(0.0));
+    resources.insert(TimeOfDay::default());
+    resources.insert(AiBuildOrders::default());
+    resources.insert(GameStats::default());
+    resources.insert(Objectives::default());
+    resources.insert(CursorIcon(winit::window::CursorIcon::default()));
+    resources.insert(FogOfWar::default());
+    resources.insert(crate::mission::Mission::default());
+    resources.insert(UnscaledDeltaTime(1.0 / 60.0));
+    resources.insert(PathRequests::default());
+    resources.insert(DpiScaling(1.0));
+    resources.insert(crate::net::Lockstep::default());
+    resources.insert(Terrain::default());
+    resources.insert(Settings::default());
 
     let mut builder = Schedule::builder();
     super::add_gameplay_systems(&mut builder);
     let mut schedule = builder.build();
+    let mut command_buffer = CommandBuffer::new(&world);
     let entity = Unit::MouseMarine.add_to_world(
-        &mut world,
+        &mut command_buffer,
         None,
         Vec2::new(0.0, 0.0),
         Facing(0.0),
         Side::Green,
+        None,
     );
+    command_buffer.flush(&mut world);
     schedule.execute(&mut world, &mut resources);
 
     let mut query = <Option<&Selected>>::query();
@@ -753,3 +1462,78 @@ fn selection_and_deselection() {
     schedule.execute(&mut world, &mut resources);
     assert!(query.get(&world, entity).unwrap().is_none());
 }
+
+#[test]
+fn formations_dont_overlap() {
+    let positions: Vec<_> = (0..7).map(|i| Vec2::new(i as f32, 0.0)).collect();
+    let target = Vec2::new(0.0, 50.0);
+
+    for &formation in &[Formation::Line, Formation::Box, Formation::Wedge] {
+        let destinations = formation_destinations(formation, target, &positions, 2.0);
+        assert_eq!(destinations.len(), positions.len());
+
+        for (i, a) in destinations.iter().enumerate() {
+            assert!((*a - target).mag() < 10.0);
+            for b in &destinations[i + 1..] {
+                assert!((*a - *b).mag() >= 2.0 - 0.001);
+            }
+        }
+    }
+
+    assert_eq!(
+        formation_destinations(Formation::Box, target, &positions[..1], 2.0),
+        vec![target]
+    );
+}
+
+#[test]
+fn the_game_is_won_once_every_objective_is_met() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    resources.insert(Objectives {
+        win_conditions: vec![
+            WinCondition::DestroyAllBuildings,
+            WinCondition::CollectCheese(100),
+        ],
+        lose_conditions: vec![LoseCondition::LetAllUnitsDie],
+    });
+    resources.insert(PlayerSide(Side::Green));
+    resources.insert(GameStats::default());
+    resources.insert(TotalTime(0.0));
+    resources.insert(Mode::Playing);
+
+    let mut buffer = CommandBuffer::new(&world);
+    spawn_unit(&mut buffer, None, Unit::Engineer, Side::Green, Vec2::zero());
+    // Enemy units can be left alive, as long as their buildings are gone.
+    spawn_unit(
+        &mut buffer,
+        None,
+        Unit::MouseMarine,
+        Side::Purple,
+        Vec2::one(),
+    );
+    buffer.flush(&mut world);
+
+    let mut schedule = Schedule::builder()
+        .add_system(update_playing_state_system())
+        .build();
+
+    resources
+        .get_mut::<GameStats>()
+        .unwrap()
+        .green
+        .cheese_gathered = 50;
+    schedule.execute(&mut world, &mut resources);
+    assert_eq!(*resources.get::<Mode>().unwrap(), Mode::Playing);
+
+    resources
+        .get_mut::<GameStats>()
+        .unwrap()
+        .green
+        .cheese_gathered = 100;
+    schedule.execute(&mut world, &mut resources);
+    assert_eq!(
+        *resources.get::<Mode>().unwrap(),
+        Mode::GameOver(Outcome::Won)
+    );
+}
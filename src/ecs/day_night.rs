@@ -0,0 +1,50 @@
+// The sun goes round once every `day_length` minutes of game time (see `TimeOfDay`). The lighting
+// follows it in `renderer::Sunlight`, and with `dark_nights` on, units can't see as far at night.
+
+use crate::resources::{Settings, TimeOfDay, TotalTime};
+
+// How far units can see in the middle of the night, compared to during the day.
+const NIGHT_SIGHT_MULTIPLIER: f32 = 0.6;
+
+// This is worked out from the game time each tick instead of being counted up, so it's always right
+// after loading a save.
+#[legion::system]
+pub fn update_time_of_day(
+    #[resource] time_of_day: &mut TimeOfDay,
+    #[resource] total_time: &TotalTime,
+    #[resource] settings: &Settings,
+) {
+    *time_of_day = TimeOfDay::at(total_time.0, settings.day_length);
+}
+
+pub fn sight_multiplier(time_of_day: &TimeOfDay, settings: &Settings) -> f32 {
+    if settings.dark_nights {
+        1.0 - (1.0 - NIGHT_SIGHT_MULTIPLIER) * time_of_day.darkness()
+    } else {
+        1.0
+    }
+}
+
+#[test]
+fn nights_are_darker_than_days() {
+    let settings = Settings {
+        dark_nights: true,
+        ..Settings::default()
+    };
+    let day_length = settings.day_length * 60.0;
+
+    // Games start in the morning.
+    let morning = TimeOfDay::at(0.0, settings.day_length);
+    assert_eq!(morning.darkness(), 0.0);
+    assert_eq!(sight_multiplier(&morning, &settings), 1.0);
+
+    let midnight = TimeOfDay::at(day_length * 0.7, settings.day_length);
+    assert_eq!(midnight.darkness(), 1.0);
+    assert_eq!(
+        sight_multiplier(&midnight, &settings),
+        NIGHT_SIGHT_MULTIPLIER
+    );
+
+    // Turning the cycle off keeps it day.
+    assert_eq!(TimeOfDay::at(day_length * 0.7, 0.0).darkness(), 0.0);
+}
@@ -1,13 +1,16 @@
 use super::{
-    AnimationState, Building, CommandQueue, FiringRange, MovementDebugging, Position, SelectBox,
-    Selected, Side,
+    AnimationState, Building, CommandQueue, FiringRange, MovementDebugging, PathRequests, Position,
+    SelectBox, Selected, Side,
 };
 use crate::assets::ModelAnimations;
+use crate::camera::Camera;
 use crate::pathfinding::Map;
-use crate::renderer::{Lines3dBuffer, TorusBuffer, TorusInstance};
+use crate::profiler::Profiler;
+use crate::renderer::{Font, Lines3dBuffer, TextAlignment, TextBuffer, TorusBuffer, TorusInstance};
 use crate::resources::{
-    Camera, DebugControls, MouseState, PlayerSide, RayCastLocation, ScreenDimensions,
+    DebugControls, DpiScaling, MouseState, PlayerSide, RayCastLocation, ScreenDimensions,
 };
+use crate::terrain::Terrain;
 use legion::component;
 use legion::systems::CommandBuffer;
 use ultraviolet::{Vec2, Vec3, Vec4};
@@ -20,13 +23,14 @@ pub fn render_firing_ranges(
     side: &Side,
     #[resource] player_side: &PlayerSide,
     #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] terrain: &Terrain,
 ) {
     if *side != player_side.0 {
         return;
     }
 
     torus_buffer.toruses.push(TorusInstance {
-        center: Vec3::new(position.0.x, 0.0, position.0.y),
+        center: terrain.ground(position.0),
         colour: Vec3::new(0.5, 0.0, 0.0),
         radius: firing_range.0,
     });
@@ -51,15 +55,20 @@ pub fn spawn_debug_building(
     command_buffer: &mut CommandBuffer,
 ) {
     if debug_controls.spawn_building_pressed {
-        if let Some((pos, handle, building, radius, selectable, side, health, completeness)) =
-            Building::Pump.parts(ray_cast_location.pos, Side::Purple, map)
+        if let Some((
+            pos,
+            handle,
+            building,
+            radius,
+            selectable,
+            side,
+            health,
+            completeness,
+            sight_range,
+        )) = Building::Pump.parts(ray_cast_location.pos, Side::Purple, map)
         {
             let skin = animations.pump.skin.clone();
-            let animation_state = AnimationState {
-                animation: 0,
-                time: 0.0,
-                total_time: animations.pump.animations[0].total_time,
-            };
+            let animation_state = AnimationState::new(super::PUMP_ANIMATION);
             command_buffer.push((
                 pos,
                 handle,
@@ -71,6 +80,7 @@ pub fn spawn_debug_building(
                 skin,
                 animation_state,
                 completeness,
+                sight_range,
             ));
         }
     }
@@ -90,11 +100,31 @@ pub fn render_building_grid(#[resource] lines_3d_buffer: &mut Lines3dBuffer) {
     }
 }
 
+// The lines are all drawn a little way above the terrain so that they don't disappear into hills.
+// Long edges can still cut through the ground in between their ends, but it's good enough.
+fn draw_ground_line(
+    a: Vec2,
+    b: Vec2,
+    height: f32,
+    colour: Vec4,
+    terrain: &Terrain,
+    lines_3d_buffer: &mut Lines3dBuffer,
+) {
+    let up = Vec3::new(0.0, height, 0.0);
+    lines_3d_buffer.draw_line_3d(terrain.ground(a) + up, terrain.ground(b) + up, colour);
+}
+
 #[legion::system]
 pub fn render_pathfinding_map(
     #[resource] map: &Map,
+    #[resource] debug_controls: &DebugControls,
+    #[resource] terrain: &Terrain,
     #[resource] lines_3d_buffer: &mut Lines3dBuffer,
 ) {
+    if !debug_controls.show_navmesh {
+        return;
+    }
+
     for (a, b, constraint) in map.edges() {
         let colour = if constraint {
             Vec4::new(1.0, 0.0, 0.0, 1.0)
@@ -102,14 +132,54 @@ pub fn render_pathfinding_map(
             Vec4::new(1.0, 1.0, 1.0, 1.0)
         };
 
-        lines_3d_buffer.draw_line(a, b, 0.1, colour);
+        draw_ground_line(a, b, 0.1, colour, terrain, lines_3d_buffer);
+    }
+}
+
+// Each triangle points at the next one on the way to the goal of the last flow field, getting lighter
+// towards the end of the line.
+#[legion::system]
+pub fn render_flow_field(
+    #[resource] map: &Map,
+    #[resource] path_requests: &PathRequests,
+    #[resource] debug_controls: &DebugControls,
+    #[resource] terrain: &Terrain,
+    #[resource] lines_3d_buffer: &mut Lines3dBuffer,
+) {
+    if !debug_controls.show_flow_fields {
+        return;
+    }
+
+    if let Some(flow_field) = &path_requests.last_flow_field {
+        for (from, to) in flow_field.arrows(map) {
+            draw_ground_line(
+                from,
+                to,
+                0.2,
+                Vec4::new(0.0, 0.5, 1.0, 1.0),
+                terrain,
+                lines_3d_buffer,
+            );
+            draw_ground_line(
+                to - (to - from).normalized() * 0.5,
+                to,
+                0.2,
+                Vec4::new(1.0, 1.0, 1.0, 1.0),
+                terrain,
+                lines_3d_buffer,
+            );
+        }
     }
 }
 
 // There is a bug in the pathfinding code that means that units go out to the edge of the map and
 // back in a very specific circumstance.
 #[legion::system]
-pub fn debug_specific_path(#[resource] map: &Map, #[resource] lines_3d_buffer: &mut Lines3dBuffer) {
+pub fn debug_specific_path(
+    #[resource] map: &Map,
+    #[resource] terrain: &Terrain,
+    #[resource] lines_3d_buffer: &mut Lines3dBuffer,
+) {
     let start = Vec2 {
         x: -16.221794,
         y: 4.150668,
@@ -128,10 +198,10 @@ pub fn debug_specific_path(#[resource] map: &Map, #[resource] lines_3d_buffer: &
         Some(&mut debug_triangles),
         Some(&mut debug_funnel_portals),
     ) {
-        render_path(start, &path, lines_3d_buffer);
+        render_path(start, &path, terrain, lines_3d_buffer);
     }
-    render_triangles(&debug_triangles, lines_3d_buffer);
-    render_funnel_points(&debug_funnel_portals, lines_3d_buffer);
+    render_triangles(&debug_triangles, terrain, lines_3d_buffer);
+    render_funnel_points(&debug_funnel_portals, terrain, lines_3d_buffer);
 }
 
 #[legion::system(for_each)]
@@ -139,16 +209,23 @@ pub fn debug_specific_path(#[resource] map: &Map, #[resource] lines_3d_buffer: &
 pub fn render_debug_unit_pathfinding(
     commands: &CommandQueue,
     movement_debugging: &MovementDebugging,
+    #[resource] debug_controls: &DebugControls,
+    #[resource] terrain: &Terrain,
     #[resource] lines_3d_buffer: &mut Lines3dBuffer,
 ) {
+    if !debug_controls.show_funnels {
+        return;
+    }
+
     if let Some(path) = commands.0.front().and_then(|command| command.path()) {
         if path.len() > 1 {
-            render_triangles(&movement_debugging.triangles, lines_3d_buffer);
-            render_funnel_points(&movement_debugging.funnel_points, lines_3d_buffer);
-            // Print out the start and end points of the path. Useful for reproducing.
-            println!(
+            render_triangles(&movement_debugging.triangles, terrain, lines_3d_buffer);
+            render_funnel_points(&movement_debugging.funnel_points, terrain, lines_3d_buffer);
+            // Log the start and end points of the path. Useful for reproducing.
+            log::debug!(
                 "{:?} -> {:?}",
-                movement_debugging.path_start, movement_debugging.path_end
+                movement_debugging.path_start,
+                movement_debugging.path_end
             );
         }
     }
@@ -158,10 +235,16 @@ pub fn render_debug_unit_pathfinding(
 pub fn render_unit_paths(
     position: &Position,
     commands: &CommandQueue,
+    #[resource] debug_controls: &DebugControls,
+    #[resource] terrain: &Terrain,
     #[resource] lines_3d_buffer: &mut Lines3dBuffer,
 ) {
+    if !debug_controls.show_unit_paths {
+        return;
+    }
+
     if let Some(path) = commands.0.front().and_then(|command| command.path()) {
-        render_path(position.0, path, lines_3d_buffer);
+        render_path(position.0, path, terrain, lines_3d_buffer);
     }
 }
 
@@ -185,32 +268,77 @@ pub fn debug_select_box(
     }
 }
 
-fn render_triangles(triangles: &[(Vec2, Vec2)], lines_3d_buffer: &mut Lines3dBuffer) {
+fn render_triangles(
+    triangles: &[(Vec2, Vec2)],
+    terrain: &Terrain,
+    lines_3d_buffer: &mut Lines3dBuffer,
+) {
     let mut prev = None;
     for &(center, special) in triangles {
         if let Some((prev_center, prev_special)) = prev {
-            lines_3d_buffer.draw_line(prev_center, center, 0.3, Vec4::new(0.25, 0.25, 0.25, 1.0));
-            lines_3d_buffer.draw_line(prev_special, special, 1.5, Vec4::new(0.0, 0.0, 0.0, 1.0));
+            let (grey, black) = (
+                Vec4::new(0.25, 0.25, 0.25, 1.0),
+                Vec4::new(0.0, 0.0, 0.0, 1.0),
+            );
+            draw_ground_line(prev_center, center, 0.3, grey, terrain, lines_3d_buffer);
+            draw_ground_line(prev_special, special, 1.5, black, terrain, lines_3d_buffer);
         }
         prev = Some((center, special));
     }
 }
 
-fn render_funnel_points(funnel_points: &[(Vec2, Vec2)], lines_3d_buffer: &mut Lines3dBuffer) {
+fn render_funnel_points(
+    funnel_points: &[(Vec2, Vec2)],
+    terrain: &Terrain,
+    lines_3d_buffer: &mut Lines3dBuffer,
+) {
     let mut prev = None;
     for &(left, right) in funnel_points {
         if let Some((prev_left, prev_right)) = prev {
-            lines_3d_buffer.draw_line(prev_left, left, 1.0, Vec4::new(1.0, 1.0, 0.0, 1.0));
-            lines_3d_buffer.draw_line(prev_right, right, 1.0, Vec4::new(0.0, 1.0, 1.0, 1.0));
+            let (yellow, cyan) = (Vec4::new(1.0, 1.0, 0.0, 1.0), Vec4::new(0.0, 1.0, 1.0, 1.0));
+            draw_ground_line(prev_left, left, 1.0, yellow, terrain, lines_3d_buffer);
+            draw_ground_line(prev_right, right, 1.0, cyan, terrain, lines_3d_buffer);
         }
 
         prev = Some((left, right));
     }
 }
 
-fn render_path(mut prev: Vec2, path: &[Vec2], lines_3d_buffer: &mut Lines3dBuffer) {
+fn render_path(
+    mut prev: Vec2,
+    path: &[Vec2],
+    terrain: &Terrain,
+    lines_3d_buffer: &mut Lines3dBuffer,
+) {
     for &point in path {
-        lines_3d_buffer.draw_line(prev, point, 0.5, Vec4::new(1.0, 0.0, 1.0, 1.0));
+        let magenta = Vec4::new(1.0, 0.0, 1.0, 1.0);
+        draw_ground_line(prev, point, 0.5, magenta, terrain, lines_3d_buffer);
         prev = point;
     }
 }
+
+// Under the cheese coins in the top right. The numbers are from the last frame, as the render passes
+// happen after the schedule has run.
+#[legion::system]
+pub fn render_profiler(
+    #[resource] profiler: &Profiler,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+) {
+    if !profiler.enabled {
+        return;
+    }
+
+    let dpi = dpi_scaling.0;
+
+    text_buffer.render_text(
+        Vec2::new(screen_dimensions.as_vec().x - 10.0 * dpi, 40.0 * dpi),
+        &profiler.overlay_text(),
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::HorizontalRight,
+        Vec4::new(1.0, 1.0, 1.0, 1.0),
+    );
+}
@@ -1,12 +1,20 @@
+#[cfg(feature = "material-maps")]
+use super::LightFlash;
 use super::{
     CheeseDropletPosition, CheeseDropletVelocity, CheeseGuyser, CheeseGuyserBuiltOn, Cooldown,
-    Explosion, Position,
+    Corpse, Explosion, FloatingText, Particle, ParticleEmitter, ParticleEmitterKind, Position,
+    ScorchMark,
 };
-use crate::renderer::{ModelBuffers, ModelInstance};
+use crate::animation::AnimationState;
+use crate::assets::ModelAnimations;
+#[cfg(feature = "material-maps")]
+use crate::renderer::PointLight;
+use crate::renderer::{DecalBuffer, DecalKind, ModelBuffers, ModelInstance, ParticleInstance};
 use crate::resources::{DeltaTime, Gravity};
+use crate::terrain::Terrain;
 use legion::{component, systems::CommandBuffer, Entity};
 use rand::Rng;
-use ultraviolet::{Mat4, Rotor3, Vec3, Vec4};
+use ultraviolet::{Mat4, Rotor3, Vec2, Vec3, Vec4};
 
 #[legion::system(for_each)]
 pub fn apply_gravity(
@@ -24,9 +32,11 @@ pub fn move_cheese_droplets(
     velocity: &CheeseDropletVelocity,
     buffer: &mut CommandBuffer,
     #[resource] delta_time: &DeltaTime,
+    #[resource] terrain: &Terrain,
 ) {
     position.0 += velocity.0 * delta_time.0;
-    if position.0.y < -1.0 {
+    let ground_height = terrain.height_at(Vec2::new(position.0.x, position.0.z));
+    if position.0.y < ground_height - 1.0 {
         buffer.remove(*entity);
     }
 }
@@ -36,6 +46,7 @@ pub fn move_cheese_droplets(
 pub fn spawn_cheese_droplets(
     position: &Position,
     #[resource] rng: &mut rand::rngs::SmallRng,
+    #[resource] terrain: &Terrain,
     buffer: &mut CommandBuffer,
     cooldown: &mut Cooldown,
 ) {
@@ -47,7 +58,7 @@ pub fn spawn_cheese_droplets(
         let rotation = rng.gen_range(0.0, std::f32::consts::TAU);
         let velocity = Vec3::new(rotation.cos() * 0.75, 10.0, rotation.sin() * 0.75);
         buffer.push((
-            CheeseDropletPosition(Vec3::new(position.0.x, 0.0, position.0.y)),
+            CheeseDropletPosition(terrain.ground(position.0)),
             CheeseDropletVelocity(velocity),
         ));
         cooldown.0 = 1.0 / 60.0;
@@ -71,13 +82,52 @@ pub fn render_cheese_droplets(
 }
 
 #[legion::system(for_each)]
-pub fn render_explosions(explosion: &Explosion, #[resource] model_buffers: &mut ModelBuffers) {
+pub fn render_explosions(
+    explosion: &Explosion,
+    #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] terrain: &Terrain,
+) {
+    let ground = Vec3::new(0.0, terrain.height_at(explosion.position), 0.0);
+
     model_buffers.explosions.push(ModelInstance {
-        transform: explosion.translation_rotation * Mat4::from_scale(explosion.size()),
+        transform: Mat4::from_translation(ground)
+            * explosion.translation_rotation
+            * Mat4::from_scale(explosion.size()),
         flat_colour: Vec4::new(1.0, 1.0, 1.0, 1.0 / 3.0),
     });
 }
 
+#[cfg(feature = "material-maps")]
+#[legion::system(for_each)]
+pub fn fade_light_flashes(
+    entity: &Entity,
+    flash: &mut LightFlash,
+    #[resource] delta_time: &DeltaTime,
+    buffer: &mut CommandBuffer,
+) {
+    flash.age += delta_time.0;
+
+    if flash.age >= flash.lifetime {
+        buffer.remove(*entity);
+    }
+}
+
+#[cfg(feature = "material-maps")]
+#[legion::system(for_each)]
+pub fn render_light_flashes(
+    flash: &LightFlash,
+    #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] terrain: &Terrain,
+) {
+    let ground = terrain.height_at(Vec2::new(flash.position.x, flash.position.z));
+
+    model_buffers.point_lights.push(PointLight {
+        position: flash.position + Vec3::new(0.0, ground, 0.0),
+        radius: flash.radius,
+        colour: Vec4::from(flash.colour * flash.brightness()),
+    });
+}
+
 #[legion::system(for_each)]
 pub fn expand_explosions(
     entity: &Entity,
@@ -89,5 +139,164 @@ pub fn expand_explosions(
 
     if explosion.progress > 1.0 {
         buffer.remove(*entity);
+        buffer.push((ScorchMark::from_explosion(explosion),));
+    }
+}
+
+#[legion::system(for_each)]
+pub fn fade_scorch_marks(
+    entity: &Entity,
+    scorch_mark: &mut ScorchMark,
+    #[resource] delta_time: &DeltaTime,
+    buffer: &mut CommandBuffer,
+) {
+    scorch_mark.age += delta_time.0;
+
+    if scorch_mark.age >= ScorchMark::LIFETIME {
+        buffer.remove(*entity);
     }
 }
+
+#[legion::system(for_each)]
+pub fn render_scorch_marks(
+    scorch_mark: &ScorchMark,
+    #[resource] decal_buffer: &mut DecalBuffer,
+    #[resource] terrain: &Terrain,
+) {
+    decal_buffer.push(
+        DecalKind::Scorch,
+        super::rendering::decal_instance(
+            terrain,
+            scorch_mark.position,
+            scorch_mark.size,
+            scorch_mark.rotation,
+            Vec4::new(1.0, 1.0, 1.0, scorch_mark.alpha()),
+        ),
+    );
+}
+
+#[legion::system(for_each)]
+pub fn decay_corpses(
+    entity: &Entity,
+    corpse: &mut Corpse,
+    animation_state: &mut AnimationState,
+    #[resource] animations: &ModelAnimations,
+    #[resource] delta_time: &DeltaTime,
+    buffer: &mut CommandBuffer,
+) {
+    corpse.age += delta_time.0;
+    animation_state.advance(delta_time.0, &animations.mouse.animations);
+
+    if corpse.faded() >= 1.0 {
+        buffer.remove(*entity);
+    }
+}
+
+#[legion::system(for_each)]
+pub fn float_text_upwards(
+    entity: &Entity,
+    floating_text: &mut FloatingText,
+    #[resource] delta_time: &DeltaTime,
+    buffer: &mut CommandBuffer,
+) {
+    floating_text.height += FloatingText::RISE_SPEED * delta_time.0;
+    floating_text.progress += delta_time.0 / FloatingText::DURATION;
+
+    if floating_text.progress > 1.0 {
+        buffer.remove(*entity);
+    }
+}
+
+#[legion::system(for_each)]
+pub fn emit_particles(
+    entity: &Entity,
+    emitter: &ParticleEmitter,
+    #[resource] rng: &mut rand::rngs::SmallRng,
+    #[resource] terrain: &Terrain,
+    buffer: &mut CommandBuffer,
+) {
+    let direction = Vec3::new(emitter.direction.x, 0.0, emitter.direction.y);
+    let ground_height = terrain.height_at(Vec2::new(emitter.position.x, emitter.position.z));
+
+    let (count, colour, forwards, random, upwards, size, gravity, lifetime) = match emitter.kind {
+        ParticleEmitterKind::MuzzleFlash => (
+            5,
+            Vec4::new(1.0, 0.8, 0.3, 1.0),
+            6.0,
+            2.0,
+            0.0,
+            0.12,
+            0.0,
+            0.1,
+        ),
+        ParticleEmitterKind::BloodPuff => (
+            8,
+            Vec4::new(0.8, 0.1, 0.1, 1.0),
+            2.0,
+            2.0,
+            1.0,
+            0.1,
+            5.0,
+            0.4,
+        ),
+        ParticleEmitterKind::BuildingDust => (
+            2,
+            Vec4::new(0.4, 0.35, 0.25, 1.0),
+            0.0,
+            1.0,
+            1.5,
+            0.25,
+            0.0,
+            0.8,
+        ),
+    };
+
+    for _ in 0..count {
+        let random_direction = crate::titlescreen::uniform_sphere_distribution_from_coords(
+            rng.gen_range(0.0, 1.0),
+            rng.gen_range(0.0, 1.0),
+        );
+
+        buffer.push((Particle {
+            position: emitter.position + Vec3::new(0.0, ground_height, 0.0),
+            velocity: direction * forwards + random_direction * random + Vec3::unit_y() * upwards,
+            colour,
+            size,
+            gravity,
+            age: 0.0,
+            lifetime: lifetime * rng.gen_range(0.75, 1.25),
+        },));
+    }
+
+    buffer.remove(*entity);
+}
+
+#[legion::system(for_each)]
+pub fn move_particles(
+    entity: &Entity,
+    particle: &mut Particle,
+    #[resource] delta_time: &DeltaTime,
+    buffer: &mut CommandBuffer,
+) {
+    particle.age += delta_time.0;
+
+    if particle.age >= particle.lifetime {
+        buffer.remove(*entity);
+        return;
+    }
+
+    particle.velocity.y -= particle.gravity * delta_time.0;
+    particle.position += particle.velocity * delta_time.0;
+}
+
+#[legion::system(for_each)]
+pub fn render_particles(particle: &Particle, #[resource] model_buffers: &mut ModelBuffers) {
+    // Fade out and shrink a bit over time.
+    let fade = 1.0 - (particle.age / particle.lifetime).min(1.0);
+    let size = particle.size * (0.5 + 0.5 * fade);
+
+    model_buffers.particles.push(ParticleInstance {
+        colour: particle.colour * fade,
+        transform: Mat4::from_translation(particle.position) * Mat4::from_scale(size),
+    });
+}
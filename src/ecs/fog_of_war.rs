@@ -0,0 +1,245 @@
+use super::day_night;
+use super::*;
+use crate::high_ground;
+use crate::pathfinding::MAP_SIZE;
+use crate::renderer::FogOfWarBuffer;
+use crate::resources::{Settings, TimeOfDay};
+use ultraviolet::Vec4;
+
+// The map is split into 2 x 2 cells.
+const CELL_SIZE: f32 = 2.0;
+const CELLS: usize = (MAP_SIZE / CELL_SIZE) as usize;
+
+// Drawn just above the ground so it doesn't z-fight.
+const FOG_HEIGHT: f32 = 0.05;
+const UNEXPLORED_COLOUR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.85);
+const EXPLORED_COLOUR: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.5);
+
+// Anything up on a plateau can see this much further.
+const HIGH_GROUND_SIGHT_MULTIPLIER: f32 = 1.25;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Visibility {
+    Unexplored,
+    // Has been seen at some point, but isn't currently being looked at.
+    Explored,
+    Visible,
+}
+
+pub struct VisibilityGrid {
+    cells: Vec<Visibility>,
+    // Set by the `reveal_map` console command.
+    revealed: bool,
+}
+
+impl Default for VisibilityGrid {
+    fn default() -> Self {
+        Self {
+            cells: vec![Visibility::Unexplored; CELLS * CELLS],
+            revealed: false,
+        }
+    }
+}
+
+impl VisibilityGrid {
+    fn cell_coords(position: Vec2) -> Option<(usize, usize)> {
+        let offset = (position + Vec2::broadcast(MAP_SIZE / 2.0)) / CELL_SIZE;
+
+        if offset.x < 0.0 || offset.y < 0.0 {
+            return None;
+        }
+
+        let (x, y) = (offset.x as usize, offset.y as usize);
+
+        if x < CELLS && y < CELLS {
+            Some((x, y))
+        } else {
+            None
+        }
+    }
+
+    fn cell_center(x: usize, y: usize) -> Vec2 {
+        Vec2::new(x as f32 + 0.5, y as f32 + 0.5) * CELL_SIZE - Vec2::broadcast(MAP_SIZE / 2.0)
+    }
+
+    pub fn get(&self, position: Vec2) -> Visibility {
+        match Self::cell_coords(position) {
+            Some((x, y)) => self.cells[y * CELLS + x],
+            None => Visibility::Unexplored,
+        }
+    }
+
+    // Everything that was visible last tick becomes explored.
+    fn fade(&mut self) {
+        if self.revealed {
+            return;
+        }
+
+        for cell in self.cells.iter_mut() {
+            if *cell == Visibility::Visible {
+                *cell = Visibility::Explored;
+            }
+        }
+    }
+
+    fn reveal(&mut self, position: Vec2, sight_range: f32) {
+        let corner = Vec2::broadcast(sight_range);
+        let (min_x, min_y) = Self::clamped_cell_coords(position - corner);
+        let (max_x, max_y) = Self::clamped_cell_coords(position + corner);
+
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                if (Self::cell_center(x, y) - position).mag_sq() <= sight_range * sight_range {
+                    self.cells[y * CELLS + x] = Visibility::Visible;
+                }
+            }
+        }
+    }
+
+    fn reveal_all(&mut self) {
+        self.revealed = true;
+        for cell in self.cells.iter_mut() {
+            *cell = Visibility::Visible;
+        }
+    }
+
+    fn clamped_cell_coords(position: Vec2) -> (usize, usize) {
+        let offset = (position + Vec2::broadcast(MAP_SIZE / 2.0)) / CELL_SIZE;
+        let max = (CELLS - 1) as f32;
+        (
+            offset.x.max(0.0).min(max) as usize,
+            offset.y.max(0.0).min(max) as usize,
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct FogOfWar {
+    green: VisibilityGrid,
+    purple: VisibilityGrid,
+}
+
+impl FogOfWar {
+    pub fn grid(&self, side: Side) -> &VisibilityGrid {
+        match side {
+            Side::Green => &self.green,
+            Side::Purple => &self.purple,
+        }
+    }
+
+    fn grid_mut(&mut self, side: Side) -> &mut VisibilityGrid {
+        match side {
+            Side::Green => &mut self.green,
+            Side::Purple => &mut self.purple,
+        }
+    }
+
+    // Everything stays visible for `side` until the fog of war is reset.
+    pub fn reveal_map(&mut self, side: Side) {
+        self.grid_mut(side).reveal_all();
+    }
+
+    // Can `viewer` see something belonging to `owner` at `position`? You can always see your own
+    // stuff.
+    pub fn can_see(&self, viewer: Side, owner: Side, position: Vec2) -> bool {
+        viewer == owner || self.grid(viewer).get(position) == Visibility::Visible
+    }
+}
+
+#[legion::system]
+#[read_component(Position)]
+#[read_component(SightRange)]
+#[read_component(Side)]
+#[read_component(Capturable)]
+pub fn update_visibility(
+    #[resource] fog_of_war: &mut FogOfWar,
+    #[resource] time_of_day: &TimeOfDay,
+    #[resource] settings: &Settings,
+    world: &SubWorld,
+) {
+    fog_of_war.green.fade();
+    fog_of_war.purple.fade();
+
+    let night_multiplier = day_night::sight_multiplier(time_of_day, settings);
+
+    <(&Position, &SightRange, &Side)>::query().for_each(world, |(position, sight_range, side)| {
+        let sight_range = if high_ground::is_on_high_ground(position.0) {
+            sight_range.0 * HIGH_GROUND_SIGHT_MULTIPLIER
+        } else {
+            sight_range.0
+        };
+
+        fog_of_war
+            .grid_mut(*side)
+            .reveal(position.0, sight_range * night_multiplier);
+    });
+
+    // Watchtowers are up high already, and they see just as far at night.
+    <(&Position, &Capturable)>::query().for_each(world, |(position, capturable)| {
+        if let (Structure::Watchtower, Some(owner)) = (capturable.structure, capturable.owner) {
+            fog_of_war
+                .grid_mut(owner)
+                .reveal(position.0, capture::WATCHTOWER_SIGHT);
+        }
+    });
+}
+
+#[legion::system]
+pub fn render_fog_of_war(
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] fog_of_war_buffer: &mut FogOfWarBuffer,
+    #[resource] terrain: &Terrain,
+) {
+    let grid = fog_of_war.grid(player_side.0);
+
+    // Each cell gets its own rect so that the fog can follow the shape of the terrain. Cells are
+    // the same size as the terrain cells, so there's no need to go any finer than that.
+    for y in 0..CELLS {
+        for x in 0..CELLS {
+            let colour = match grid.cells[y * CELLS + x] {
+                Visibility::Visible => continue,
+                Visibility::Explored => EXPLORED_COLOUR,
+                Visibility::Unexplored => UNEXPLORED_COLOUR,
+            };
+
+            let offset = Vec2::broadcast(MAP_SIZE / 2.0);
+            let top_left = Vec2::new(x as f32, y as f32) * CELL_SIZE - offset;
+            let bottom_right = Vec2::new((x + 1) as f32, (y + 1) as f32) * CELL_SIZE - offset;
+
+            fog_of_war_buffer.draw_rect(
+                top_left,
+                bottom_right,
+                |point| terrain.height_at(point) + FOG_HEIGHT,
+                colour,
+            );
+        }
+    }
+}
+
+#[test]
+fn revealing_and_fading() {
+    let mut grid = VisibilityGrid::default();
+    let position = Vec2::new(10.0, -20.0);
+
+    assert_eq!(grid.get(position), Visibility::Unexplored);
+
+    grid.reveal(position, 5.0);
+    assert_eq!(grid.get(position), Visibility::Visible);
+    assert_eq!(
+        grid.get(position + Vec2::new(3.0, 0.0)),
+        Visibility::Visible
+    );
+    assert_eq!(
+        grid.get(position + Vec2::new(8.0, 0.0)),
+        Visibility::Unexplored
+    );
+
+    grid.fade();
+    assert_eq!(grid.get(position), Visibility::Explored);
+
+    // Revealing at the edge of the map shouldn't panic.
+    grid.reveal(Vec2::new(100.0, 100.0), 10.0);
+    assert_eq!(grid.get(Vec2::new(99.0, 99.0)), Visibility::Visible);
+    assert_eq!(grid.get(Vec2::new(150.0, 0.0)), Visibility::Unexplored);
+}
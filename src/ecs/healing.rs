@@ -0,0 +1,168 @@
+use super::*;
+use crate::resources::DeltaTime;
+use ordered_float::OrderedFloat;
+
+// Healers stick with the same target until it's fully healed or out of range, rather than
+// flitting between several. When picking a new one, the worst off goes first. Ties are broken by
+// position instead of iteration order so that every peer picks the same one.
+#[legion::system(for_each)]
+#[filter(component::<Position>() & component::<Side>())]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Radius)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(FullyBuilt)]
+#[read_component(Experience)]
+#[write_component(Health)]
+pub fn heal_nearby(
+    entity: &Entity,
+    healer: &Healer,
+    commands: &CommandQueue,
+    healing: Option<&Healing>,
+    #[resource] delta_time: &DeltaTime,
+    #[resource] spatial_hash: &SpatialHash,
+    world: &mut SubWorld,
+    buffer: &mut CommandBuffer,
+) {
+    // Medics escorting someone heal whoever's hurt along the way.
+    let idle = matches!(
+        commands.0.front(),
+        None | Some(Command::HoldPosition) | Some(Command::Follow { .. })
+    );
+
+    let (position, side) = <(&Position, &Side)>::query()
+        .get(world, *entity)
+        .map(|(position, side)| (position.0, *side))
+        .expect("We've applied a filter to this system for Position and Side");
+
+    // The fraction of health something has left, where it is and its max health, if it's
+    // something this healer can work on.
+    let needs_healing = |target: Entity, world: &SubWorld| -> Option<(f32, Vec2, f32)> {
+        if target == *entity {
+            return None;
+        }
+
+        let (target_position, target_side, radius, health, unit, building, built, experience) =
+            <(
+                &Position,
+                &Side,
+                &Radius,
+                &Health,
+                Option<&Unit>,
+                Option<&Building>,
+                Option<&FullyBuilt>,
+                Option<&Experience>,
+            )>::query()
+            .get(world, target)
+            .ok()?;
+
+        let max_health = match (healer.target, unit, building) {
+            (HealTarget::Units, Some(unit), _) => {
+                unit.stats().max_health
+                    * experience
+                        .map(|experience| experience.multiplier())
+                        .unwrap_or(1.0)
+            }
+            // Buildings that are still going up are the job of the builders.
+            (HealTarget::Buildings, _, Some(building)) if built.is_some() => {
+                building.stats().max_health
+            }
+            _ => return None,
+        };
+
+        let in_range = (target_position.0 - position).mag() - radius.0 <= healer.range;
+
+        if *target_side == side && in_range && health.0 < max_health {
+            Some((health.0 / max_health, target_position.0, max_health))
+        } else {
+            None
+        }
+    };
+
+    let current = healing
+        .map(|healing| healing.0)
+        .filter(|target| idle && needs_healing(*target, world).is_some());
+
+    let target = current.or_else(|| {
+        if !idle {
+            return None;
+        }
+
+        spatial_hash
+            .query_radius(position, healer.range)
+            .filter_map(|target| {
+                needs_healing(target, world)
+                    .map(|(fraction, position, _)| (target, fraction, position))
+            })
+            .min_by_key(|(_, fraction, position)| {
+                (
+                    OrderedFloat(*fraction),
+                    OrderedFloat(position.x),
+                    OrderedFloat(position.y),
+                )
+            })
+            .map(|(target, ..)| target)
+    });
+
+    let (target, max_health) =
+        match target.and_then(|target| Some((target, needs_healing(target, world)?.2))) {
+            Some(target) => target,
+            None => {
+                if healing.is_some() {
+                    buffer.remove_component::<Healing>(*entity);
+                }
+                return;
+            }
+        };
+
+    if current != Some(target) {
+        buffer.add_component(*entity, Healing(target));
+    }
+
+    if let Ok(health) = <&mut Health>::query().get_mut(world, target) {
+        health.0 = (health.0 + healer.rate * delta_time.0).min(max_health);
+    }
+}
+
+#[test]
+fn medics_heal_the_worst_off_friendly_unit_nearby() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    resources.insert(DeltaTime(1.0));
+    resources.insert(SpatialHash::default());
+
+    let mut buffer = CommandBuffer::new(&world);
+    let medic = spawn_unit(&mut buffer, None, Unit::Medic, Side::Green, Vec2::zero());
+    let mut marine = |side, x, health| {
+        let entity = spawn_unit(
+            &mut buffer,
+            None,
+            Unit::MouseMarine,
+            side,
+            Vec2::new(x, 0.0),
+        );
+        buffer.add_component(entity, Health(health));
+        entity
+    };
+    let scratched = marine(Side::Green, 2.0, 40.0);
+    let wounded = marine(Side::Green, -2.0, 10.0);
+    let enemy = marine(Side::Purple, 3.0, 5.0);
+    let far_away = marine(Side::Green, 50.0, 1.0);
+    buffer.flush(&mut world);
+
+    let mut schedule = Schedule::builder()
+        .add_system(super::spatial_hash::update_spatial_hash_system())
+        .add_system(heal_nearby_system())
+        .build();
+    schedule.execute(&mut world, &mut resources);
+
+    let health = |entity| <&Health>::query().get(&world, entity).unwrap().0;
+    let rate = Unit::Medic.stats().healer.unwrap().rate;
+
+    assert_eq!(health(wounded), 10.0 + rate);
+    assert_eq!(health(scratched), 40.0);
+    assert_eq!(health(enemy), 5.0);
+    assert_eq!(health(far_away), 1.0);
+    assert_eq!(<&Healing>::query().get(&world, medic).unwrap().0, wounded);
+}
@@ -0,0 +1,919 @@
+use super::*;
+use crate::input::{Action, Input, InputMap};
+use crate::net::Lockstep;
+use crate::renderer::{Font, LineBuffers, TextAlignment, TextBuffer};
+use crate::resources::{
+    CheeseCoins, CommandMode, DpiScaling, GameSpeed, Keypress, Keypresses, Mode,
+    SelectedUnitsAbilities, Settings,
+};
+use minimap::{MINIMAP_MARGIN, MINIMAP_SIZE};
+use rendering::BLACK;
+
+const MARGIN: f32 = 10.0;
+
+const BUTTON_SIZE: f32 = 56.0;
+const BUTTON_GAP: f32 = 6.0;
+const BUTTON_COLUMNS: usize = 5;
+const BUTTON_ROWS: usize = 3;
+
+const PORTRAIT_SIZE: f32 = 40.0;
+const PORTRAIT_GAP: f32 = 4.0;
+const PORTRAIT_COLUMNS: usize = 12;
+const PORTRAIT_ROWS: usize = 2;
+
+const SPEED_BUTTON_SIZE: f32 = 24.0;
+
+const LABEL_SIZE: f32 = 18.0;
+
+// The most the HUD is allowed to shrink to fit a small window, and the range `ui_scale` is clamped to.
+const MIN_UI_SCALE: f32 = 0.5;
+const MAX_UI_SCALE: f32 = 3.0;
+// How much of the height of the screen the HUD can take up.
+const MAX_HUD_HEIGHT: f32 = 0.5;
+
+const BACKGROUND: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.75);
+const BUTTON_COLOUR: Vec4 = Vec4::new(0.091, 0.118, 0.543, 1.0);
+const ACTIVE_BUTTON_COLOUR: Vec4 = Vec4::new(0.3, 0.4, 1.0, 1.0);
+const COOLDOWN_OVERLAY: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.6);
+const ENERGY_COLOUR: Vec4 = Vec4::new(0.4, 0.8, 1.0, 1.0);
+const GREYED_OUT_TEXT: Vec4 = Vec4::new(0.5, 0.5, 0.5, 1.0);
+
+// A grid of square cells sitting at the bottom of the screen. Positions are in pixels, but sizes
+// are unscaled because `LineBuffers` multiplies them by the dpi itself.
+struct HudGrid {
+    top_left: Vec2,
+    cell_size: f32,
+    gap: f32,
+    columns: usize,
+    rows: usize,
+    dpi: f32,
+}
+
+impl HudGrid {
+    // The buttons in the bottom-right corner of the screen, for giving orders.
+    fn command_card(screen_dimensions: &ScreenDimensions, dpi_scaling: &DpiScaling) -> Self {
+        let mut grid = Self {
+            top_left: Vec2::zero(),
+            cell_size: BUTTON_SIZE,
+            gap: BUTTON_GAP,
+            columns: BUTTON_COLUMNS,
+            rows: BUTTON_ROWS,
+            dpi: dpi_scaling.0,
+        };
+
+        let size = grid.size() * grid.dpi;
+        grid.top_left = screen_dimensions.as_vec() - size - Vec2::broadcast(MARGIN * grid.dpi);
+        grid
+    }
+
+    // The portraits of the selected units along the bottom of the screen.
+    fn selection_panel(screen_dimensions: &ScreenDimensions, dpi_scaling: &DpiScaling) -> Self {
+        let mut grid = Self {
+            top_left: Vec2::zero(),
+            cell_size: PORTRAIT_SIZE,
+            gap: PORTRAIT_GAP,
+            columns: PORTRAIT_COLUMNS,
+            rows: PORTRAIT_ROWS,
+            dpi: dpi_scaling.0,
+        };
+
+        let size = grid.size() * grid.dpi;
+        let dims = screen_dimensions.as_vec();
+        grid.top_left = Vec2::new((dims.x - size.x) / 2.0, dims.y - size.y - MARGIN * grid.dpi);
+        grid
+    }
+
+    // Slow down, pause and speed up buttons, under the cheese counter in the top-right corner.
+    fn speed_controls(screen_dimensions: &ScreenDimensions, dpi_scaling: &DpiScaling) -> Self {
+        let mut grid = Self {
+            top_left: Vec2::zero(),
+            cell_size: SPEED_BUTTON_SIZE,
+            gap: PORTRAIT_GAP,
+            columns: 3,
+            rows: 1,
+            dpi: dpi_scaling.0,
+        };
+
+        let size = grid.size() * grid.dpi;
+        grid.top_left = Vec2::new(
+            screen_dimensions.as_vec().x - size.x - MARGIN * grid.dpi,
+            40.0 * grid.dpi,
+        );
+        grid
+    }
+
+    // A single button just above the minimap, showing how many engineers are standing around.
+    fn idle_workers(screen_dimensions: &ScreenDimensions, dpi_scaling: &DpiScaling) -> Self {
+        let mut grid = Self {
+            top_left: Vec2::zero(),
+            cell_size: BUTTON_SIZE,
+            gap: BUTTON_GAP,
+            columns: 1,
+            rows: 1,
+            dpi: dpi_scaling.0,
+        };
+
+        let size = grid.size() * grid.dpi;
+        let minimap_top = MinimapArea::new(screen_dimensions, dpi_scaling).top();
+        grid.top_left = Vec2::new(MARGIN * grid.dpi, minimap_top - size.y - MARGIN * grid.dpi);
+        grid
+    }
+
+    fn size(&self) -> Vec2 {
+        Vec2::new(
+            self.columns as f32 * (self.cell_size + self.gap) + self.gap,
+            self.rows as f32 * (self.cell_size + self.gap) + self.gap,
+        )
+    }
+
+    fn contains(&self, screen_position: Vec2) -> bool {
+        let relative = screen_position - self.top_left;
+        let size = self.size() * self.dpi;
+        relative.x >= 0.0 && relative.y >= 0.0 && relative.x <= size.x && relative.y <= size.y
+    }
+
+    fn center(&self) -> Vec2 {
+        self.top_left + self.size() * self.dpi / 2.0
+    }
+
+    fn cell_center(&self, index: usize) -> Vec2 {
+        let column = (index % self.columns) as f32;
+        let row = (index / self.columns) as f32;
+        let stride = (self.cell_size + self.gap) * self.dpi;
+
+        self.top_left
+            + Vec2::broadcast((self.gap + self.cell_size / 2.0) * self.dpi)
+            + Vec2::new(column, row) * stride
+    }
+
+    fn cell_at(&self, screen_position: Vec2) -> Option<usize> {
+        (0..self.columns * self.rows).find(|&index| {
+            let relative = screen_position - self.cell_center(index);
+            let half_size = self.cell_size / 2.0 * self.dpi;
+            relative.x.abs() <= half_size && relative.y.abs() <= half_size
+        })
+    }
+}
+
+// How big the UI is drawn: the monitor's scale factor, times `ui_scale` from the settings. Everything
+// is laid out in unscaled pixels which are multiplied by this, and anchored to the edges of the
+// screen, so wide screens just get more space in between. On small windows (or with a big
+// `ui_scale`) it's shrunk until the selection panel fits between the minimap and the command card.
+pub fn ui_scale(
+    scale_factor: f64,
+    settings: &Settings,
+    screen_dimensions: &ScreenDimensions,
+) -> DpiScaling {
+    // Flooring the scale factor keeps the lines a whole number of pixels wide, see `main`.
+    let wanted =
+        scale_factor.floor().max(1.0) as f32 * settings.ui_scale.clamp(MIN_UI_SCALE, MAX_UI_SCALE);
+
+    let unscaled = DpiScaling(1.0);
+    let command_card = HudGrid::command_card(screen_dimensions, &unscaled).size();
+    let selection_panel = HudGrid::selection_panel(screen_dimensions, &unscaled).size();
+    let minimap = MINIMAP_SIZE + MINIMAP_MARGIN;
+
+    // The selection panel is in the middle, so it needs half the screen plus whichever of the
+    // minimap or the command card is wider.
+    let half_width = selection_panel.x / 2.0 + MARGIN + (command_card.x + MARGIN).max(minimap);
+    let height = (command_card.y + MARGIN).max(minimap);
+
+    let fits = (screen_dimensions.width as f32 / 2.0 / half_width)
+        .min(screen_dimensions.height as f32 * MAX_HUD_HEIGHT / height);
+
+    DpiScaling(wanted.min(fits).max(MIN_UI_SCALE))
+}
+
+// Clicks that land on any part of the HUD shouldn't also go through to the world underneath it.
+pub fn hud_contains(
+    screen_position: Vec2,
+    screen_dimensions: &ScreenDimensions,
+    dpi_scaling: &DpiScaling,
+) -> bool {
+    HudGrid::command_card(screen_dimensions, dpi_scaling).contains(screen_position)
+        || HudGrid::selection_panel(screen_dimensions, dpi_scaling).contains(screen_position)
+        || HudGrid::speed_controls(screen_dimensions, dpi_scaling).contains(screen_position)
+        || HudGrid::idle_workers(screen_dimensions, dpi_scaling).contains(screen_position)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CommandButton {
+    Move,
+    Stop,
+    HoldPosition,
+    Patrol,
+    AttackMove,
+    Stance,
+    Guard,
+    Ability(&'static Ability),
+}
+
+impl CommandButton {
+    // Clicking a button just presses its hotkey for you, so the two always do the same thing. The
+    // unit commands can be rebound (or even unbound), so this looks them up in the input map.
+    fn hotkey(self, input_map: &InputMap) -> Option<VirtualKeyCode> {
+        let action = match self {
+            Self::Move => Action::Move,
+            Self::Stop => Action::Stop,
+            Self::HoldPosition => Action::HoldPosition,
+            Self::Patrol => Action::Patrol,
+            Self::AttackMove => Action::AttackMove,
+            Self::Stance => Action::CycleStance,
+            Self::Guard => Action::Guard,
+            Self::Ability(ability) => return Some(ability.hotkey),
+        };
+
+        input_map.key(action)
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Move => "Move",
+            Self::Stop => "Stop",
+            Self::HoldPosition => "Hold",
+            Self::Patrol => "Patrol",
+            Self::AttackMove => "Attack",
+            Self::Stance => "Stance",
+            Self::Guard => "Guard",
+            Self::Ability(_) => "",
+        }
+    }
+
+    fn is_active(self, mode: &CommandMode) -> bool {
+        match self {
+            Self::Move => *mode == CommandMode::Move,
+            Self::Patrol => *mode == CommandMode::Patrol,
+            Self::Guard => *mode == CommandMode::Guard,
+            Self::AttackMove => *mode == CommandMode::AttackMove,
+            Self::Ability(ability) => match ability.ability_type {
+                AbilityType::Build(building) => *mode == CommandMode::Construct { building },
+                AbilityType::SetRecruitmentWaypoint => *mode == CommandMode::SetRecruitmentWaypoint,
+                AbilityType::Active(ability) => *mode == CommandMode::Cast { ability },
+                AbilityType::LaunchStrike => *mode == CommandMode::TargetStrike,
+                AbilityType::Recruit(_)
+                | AbilityType::LockGate
+                | AbilityType::Research(_)
+                | AbilityType::Burrow => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+// The buttons for what's selected, along with the cell of the command card that they go in. Unit
+// commands go along the top and abilities along the bottom, like in SC2.
+fn command_buttons(
+    player_side: &PlayerSide,
+    selected_units_abilities: &SelectedUnitsAbilities,
+    world: &SubWorld,
+) -> Vec<(usize, CommandButton)> {
+    let mut buttons = Vec::new();
+
+    let has_stances: Vec<bool> = <(&Side, Option<&Stance>)>::query()
+        .filter(component::<Selected>() & component::<CommandQueue>())
+        .iter(world)
+        .filter(|(side, _)| **side == player_side.0)
+        .map(|(_, stance)| stance.is_some())
+        .collect();
+
+    let any_units = !has_stances.is_empty();
+    let any_stances = has_stances.iter().any(|has_stance| *has_stance);
+
+    if any_units {
+        buttons.extend(
+            [
+                CommandButton::Move,
+                CommandButton::Stop,
+                CommandButton::HoldPosition,
+                CommandButton::Patrol,
+                CommandButton::AttackMove,
+            ]
+            .iter()
+            .copied()
+            .enumerate(),
+        );
+    }
+
+    if any_stances {
+        buttons.push((BUTTON_COLUMNS, CommandButton::Stance));
+        buttons.push((BUTTON_COLUMNS + 1, CommandButton::Guard));
+    }
+
+    let abilities_row = (BUTTON_ROWS - 1) * BUTTON_COLUMNS;
+    let (research, abilities): (Vec<&Ability>, Vec<_>) = selected_units_abilities
+        .0
+        .keys()
+        .copied()
+        .partition(|ability| matches!(ability.ability_type, AbilityType::Research(_)));
+
+    buttons.extend(
+        abilities
+            .into_iter()
+            .take(BUTTON_COLUMNS)
+            .enumerate()
+            .map(|(i, ability)| (abilities_row + i, CommandButton::Ability(ability))),
+    );
+
+    // Only buildings research upgrades, and they don't have any unit commands, so the upgrades go
+    // in the middle row.
+    buttons.extend(
+        research
+            .into_iter()
+            .take(BUTTON_COLUMNS)
+            .enumerate()
+            .map(|(i, ability)| (BUTTON_COLUMNS + i, CommandButton::Ability(ability))),
+    );
+
+    buttons
+}
+
+#[legion::system]
+#[read_component(Side)]
+#[read_component(Stance)]
+#[read_component(Selected)]
+#[read_component(CommandQueue)]
+pub fn handle_command_card_clicks(
+    #[resource] mouse_state: &MouseState,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] player_side: &PlayerSide,
+    #[resource] selected_units_abilities: &SelectedUnitsAbilities,
+    #[resource] keypresses: &mut Keypresses,
+    #[resource] settings: &Settings,
+    world: &SubWorld,
+) {
+    if !mouse_state.left_state.was_clicked() {
+        return;
+    }
+
+    let card = HudGrid::command_card(screen_dimensions, dpi_scaling);
+
+    let cell = match card.cell_at(mouse_state.position) {
+        Some(cell) => cell,
+        None => return,
+    };
+
+    let button = command_buttons(player_side, selected_units_abilities, world)
+        .into_iter()
+        .find(|(button_cell, _)| *button_cell == cell);
+
+    if let Some(hotkey) = button.and_then(|(_, button)| button.hotkey(&settings.input_map)) {
+        keypresses.0.push(Keypress {
+            code: Some(hotkey),
+            scancode: 0,
+            pressed: true,
+        });
+    }
+}
+
+#[legion::system]
+#[read_component(Side)]
+#[read_component(Stance)]
+#[read_component(Selected)]
+#[read_component(CommandQueue)]
+#[read_component(Abilities)]
+#[read_component(Energy)]
+#[read_component(AbilityCooldowns)]
+#[read_component(Burrow)]
+#[read_component(Building)]
+#[read_component(FullyBuilt)]
+#[read_component(Superweapon)]
+pub fn render_command_card(
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] line_buffers: &mut LineBuffers,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] cheese_coins: &CheeseCoins,
+    #[resource] player_side: &PlayerSide,
+    #[resource] rts_controls: &RtsControls,
+    #[resource] selected_units_abilities: &SelectedUnitsAbilities,
+    #[resource] mode: &Mode,
+    #[resource] settings: &Settings,
+    #[resource] upgrades: &Upgrades,
+    #[resource] mouse_state: &MouseState,
+    world: &SubWorld,
+) {
+    if *mode != Mode::Playing {
+        return;
+    }
+
+    let dpi = dpi_scaling.0;
+    let card = HudGrid::command_card(screen_dimensions, dpi_scaling);
+    let size = Vec2::broadcast(BUTTON_SIZE);
+    let border = 2.0;
+    let hovered = card.cell_at(mouse_state.position);
+    let mut tooltip = None;
+
+    line_buffers.draw_filled_rect(card.center(), card.size(), BACKGROUND, dpi);
+
+    for (cell, button) in command_buttons(player_side, selected_units_abilities, world) {
+        let center = card.cell_center(cell);
+
+        line_buffers.draw_filled_rect(center, size + Vec2::broadcast(border * 2.0), BLACK, dpi);
+
+        let missing = match button {
+            CommandButton::Ability(ability) => {
+                tech::missing_prerequisites(&ability.ability_type, player_side.0, upgrades, world)
+            }
+            _ => Vec::new(),
+        };
+
+        if !missing.is_empty() && hovered == Some(cell) {
+            tooltip = Some(tech::tooltip(&missing));
+        }
+
+        // Greyed out until everything it needs has been built or researched.
+        let (colour, mut text_colour) = if !missing.is_empty() {
+            (BUTTON_COLOUR * 0.5, GREYED_OUT_TEXT)
+        } else if button.is_active(&rts_controls.mode) {
+            (ACTIVE_BUTTON_COLOUR, Vec4::one())
+        } else {
+            (BUTTON_COLOUR, Vec4::one())
+        };
+
+        line_buffers.draw_filled_rect(center, size, colour, dpi);
+
+        if let CommandButton::Ability(ability) = button {
+            let cost = match ability.ability_type {
+                AbilityType::Build(building) => Some(building.stats().cost),
+                AbilityType::Recruit(unit) => Some(unit.stats().cost),
+                AbilityType::Research(upgrade) => Some(upgrade.stats().cost),
+                AbilityType::SetRecruitmentWaypoint
+                | AbilityType::Active(_)
+                | AbilityType::LockGate
+                | AbilityType::Burrow
+                | AbilityType::LaunchStrike => None,
+            };
+            let can_use =
+                cost.map(|cost| cost <= cheese_coins.0).unwrap_or(true) && missing.is_empty();
+
+            match ability.image() {
+                Some(image) => {
+                    line_buffers.draw_image(center, size, image, !can_use, dpi);
+                    text_colour = BLACK;
+                }
+                None => {
+                    text_buffer.draw_text(
+                        center + Vec2::new(0.0, 6.0) * dpi,
+                        ability_label(ability),
+                        LABEL_SIZE * dpi,
+                        text_colour,
+                    );
+                }
+            }
+
+            if let Some(cost) = cost {
+                text_buffer.render_text(
+                    center + Vec2::new(BUTTON_SIZE / 2.0 - 2.0, BUTTON_SIZE / 2.0 - 20.0) * dpi,
+                    &format!("{}", cost),
+                    Font::Ui,
+                    0.75,
+                    dpi,
+                    TextAlignment::HorizontalRight,
+                    text_colour,
+                );
+            }
+
+            if ability.ability_type == AbilityType::LaunchStrike {
+                let casters = selected_units_abilities
+                    .0
+                    .get(ability)
+                    .map(|casters| casters.as_slice())
+                    .unwrap_or(&[]);
+
+                render_charge_overlay(casters, center, line_buffers, world, dpi);
+            }
+
+            if let AbilityType::Active(active) = ability.ability_type {
+                let casters = selected_units_abilities
+                    .0
+                    .get(ability)
+                    .map(|casters| casters.as_slice())
+                    .unwrap_or(&[]);
+
+                render_cooldown_overlay(active, casters, center, line_buffers, world, dpi);
+
+                text_buffer.render_text(
+                    center + Vec2::new(BUTTON_SIZE / 2.0 - 2.0, BUTTON_SIZE / 2.0 - 20.0) * dpi,
+                    &format!("{}", active.stats().energy_cost),
+                    Font::Ui,
+                    0.75,
+                    dpi,
+                    TextAlignment::HorizontalRight,
+                    ENERGY_COLOUR,
+                );
+            }
+        } else {
+            text_buffer.draw_text(
+                center + Vec2::new(0.0, 6.0) * dpi,
+                button.label(),
+                LABEL_SIZE * dpi,
+                text_colour,
+            );
+        }
+
+        if let Some(hotkey) = button.hotkey(&settings.input_map) {
+            text_buffer.render_text(
+                center - (size / 2.0 - Vec2::new(2.0, 0.0)) * dpi,
+                &format!("{:?}", hotkey),
+                Font::Ui,
+                0.75,
+                dpi,
+                TextAlignment::Default,
+                text_colour,
+            );
+        }
+    }
+
+    if let Some(tooltip) = tooltip {
+        text_buffer.render_text(
+            Vec2::new(
+                card.top_left.x + card.size().x * dpi,
+                card.top_left.y - 24.0 * dpi,
+            ),
+            &tooltip,
+            Font::Ui,
+            1.0,
+            dpi,
+            TextAlignment::HorizontalRight,
+            Vec4::one(),
+        );
+    }
+}
+
+// The speed can't be changed over the network, so the buttons aren't there at all.
+#[legion::system]
+pub fn handle_game_speed_clicks(
+    #[resource] mouse_state: &MouseState,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] game_speed: &mut GameSpeed,
+    #[resource] lockstep: &Lockstep,
+) {
+    if !mouse_state.left_state.was_clicked() || lockstep.is_networked() {
+        return;
+    }
+
+    match HudGrid::speed_controls(screen_dimensions, dpi_scaling).cell_at(mouse_state.position) {
+        Some(0) => game_speed.slower(),
+        Some(1) => game_speed.paused = !game_speed.paused,
+        Some(2) => game_speed.faster(),
+        _ => {}
+    }
+}
+
+#[legion::system]
+pub fn render_game_speed_controls(
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] line_buffers: &mut LineBuffers,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] game_speed: &GameSpeed,
+    #[resource] lockstep: &Lockstep,
+    #[resource] mode: &Mode,
+) {
+    if *mode != Mode::Playing || lockstep.is_networked() {
+        return;
+    }
+
+    let dpi = dpi_scaling.0;
+    let grid = HudGrid::speed_controls(screen_dimensions, dpi_scaling);
+    let pause_label = if game_speed.paused { ">" } else { "||" };
+
+    line_buffers.draw_filled_rect(grid.center(), grid.size(), BACKGROUND, dpi);
+
+    for (cell, label) in ["-", pause_label, "+"].iter().enumerate() {
+        let center = grid.cell_center(cell);
+        let colour = if cell == 1 && game_speed.paused {
+            ACTIVE_BUTTON_COLOUR
+        } else {
+            BUTTON_COLOUR
+        };
+
+        line_buffers.draw_filled_rect(center, Vec2::broadcast(SPEED_BUTTON_SIZE), colour, dpi);
+        text_buffer.draw_text(
+            center + Vec2::new(0.0, 6.0) * dpi,
+            label,
+            LABEL_SIZE * dpi,
+            Vec4::one(),
+        );
+    }
+
+    let text = if game_speed.paused {
+        "Paused".to_string()
+    } else {
+        format!("{}x", game_speed.speed)
+    };
+
+    text_buffer.render_text(
+        Vec2::new(grid.top_left.x - MARGIN * dpi, grid.center().y),
+        &text,
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::HorizontalRight,
+        BUTTON_COLOUR,
+    );
+}
+
+// Like the command card, clicking the button just presses its hotkey.
+#[legion::system]
+pub fn handle_idle_workers_click(
+    #[resource] mouse_state: &MouseState,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] keypresses: &mut Keypresses,
+    #[resource] settings: &Settings,
+) {
+    if !mouse_state.left_state.was_clicked()
+        || !HudGrid::idle_workers(screen_dimensions, dpi_scaling).contains(mouse_state.position)
+    {
+        return;
+    }
+
+    let hotkey = settings
+        .input_map
+        .bindings(Action::SelectIdleWorker)
+        .iter()
+        .find_map(|input| match input {
+            Input::Key(key) => Some(*key),
+            Input::Mouse(_) => None,
+        });
+
+    if let Some(hotkey) = hotkey {
+        keypresses.0.push(Keypress {
+            code: Some(hotkey),
+            scancode: 0,
+            pressed: true,
+        });
+    }
+}
+
+#[legion::system]
+pub fn render_idle_workers_button(
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] line_buffers: &mut LineBuffers,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] idle_units: &IdleUnits,
+    #[resource] mode: &Mode,
+) {
+    if *mode != Mode::Playing {
+        return;
+    }
+
+    let dpi = dpi_scaling.0;
+    let grid = HudGrid::idle_workers(screen_dimensions, dpi_scaling);
+    let center = grid.cell_center(0);
+    let idle = idle_units.workers.len();
+
+    // Greyed out when everyone's busy.
+    let (colour, text_colour) = if idle > 0 {
+        (BUTTON_COLOUR, Vec4::one())
+    } else {
+        (BUTTON_COLOUR * 0.5, Vec4::new(0.5, 0.5, 0.5, 1.0))
+    };
+
+    line_buffers.draw_filled_rect(grid.center(), grid.size(), BACKGROUND, dpi);
+    line_buffers.draw_filled_rect(center, Vec2::broadcast(BUTTON_SIZE), colour, dpi);
+    text_buffer.draw_text(
+        center - Vec2::new(0.0, 8.0) * dpi,
+        "Idle",
+        LABEL_SIZE * dpi,
+        text_colour,
+    );
+    text_buffer.draw_text(
+        center + Vec2::new(0.0, 14.0) * dpi,
+        &idle.to_string(),
+        LABEL_SIZE * dpi,
+        text_colour,
+    );
+}
+
+fn ability_label(ability: &Ability) -> &'static str {
+    match ability.ability_type {
+        AbilityType::Active(active) => active.stats().label,
+        AbilityType::Build(Building::Wall) => "Wall",
+        AbilityType::Build(Building::Gate) => "Gate",
+        AbilityType::Build(Building::Silo) => "Silo",
+        AbilityType::LaunchStrike => "Strike",
+        AbilityType::LockGate => "Lock",
+        AbilityType::Burrow => "Burrow",
+        AbilityType::Research(upgrade) => upgrade.stats().label,
+        _ => "",
+    }
+}
+
+// The button gets covered up from the top down while the ability is cooling down, and is dimmed
+// completely if nobody has enough energy to use it.
+fn render_cooldown_overlay(
+    ability: ActiveAbility,
+    casters: &[Entity],
+    center: Vec2,
+    line_buffers: &mut LineBuffers,
+    world: &SubWorld,
+    dpi: f32,
+) {
+    let fraction = abilities::cooldown_fraction(ability, casters, world);
+
+    let fraction = if fraction == 0.0
+        && !casters
+            .iter()
+            .any(|caster| abilities::can_cast(ability, *caster, world))
+    {
+        1.0
+    } else {
+        fraction
+    };
+
+    cover_button(fraction, center, line_buffers, dpi);
+}
+
+// Silos cover up their strike button in the same way while they're charging.
+fn render_charge_overlay(
+    casters: &[Entity],
+    center: Vec2,
+    line_buffers: &mut LineBuffers,
+    world: &SubWorld,
+    dpi: f32,
+) {
+    let fraction = casters
+        .iter()
+        .filter_map(|caster| {
+            <&Superweapon>::query()
+                .filter(component::<FullyBuilt>())
+                .get(world, *caster)
+                .ok()
+        })
+        .map(|superweapon| superweapon.remaining / superweapon::CHARGE_TIME)
+        .fold(1.0, f32::min);
+
+    cover_button(fraction, center, line_buffers, dpi);
+}
+
+fn cover_button(fraction: f32, center: Vec2, line_buffers: &mut LineBuffers, dpi: f32) {
+    if fraction > 0.0 {
+        let height = BUTTON_SIZE * fraction;
+
+        line_buffers.draw_filled_rect(
+            center + Vec2::new(0.0, (height - BUTTON_SIZE) / 2.0) * dpi,
+            Vec2::new(BUTTON_SIZE, height),
+            COOLDOWN_OVERLAY,
+            dpi,
+        );
+    }
+}
+
+#[legion::system]
+#[read_component(Side)]
+#[read_component(Selected)]
+#[read_component(Health)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(FullyBuilt)]
+#[read_component(Experience)]
+#[read_component(Energy)]
+pub fn render_selection_panel(
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] line_buffers: &mut LineBuffers,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] player_side: &PlayerSide,
+    #[resource] mode: &Mode,
+    world: &SubWorld,
+) {
+    if *mode != Mode::Playing {
+        return;
+    }
+
+    let dpi = dpi_scaling.0;
+    let panel = HudGrid::selection_panel(screen_dimensions, dpi_scaling);
+
+    line_buffers.draw_filled_rect(panel.center(), panel.size(), BACKGROUND, dpi);
+
+    let mut selected: Vec<_> = <(
+        &Side,
+        &Health,
+        Option<&Unit>,
+        Option<&Building>,
+        Option<&FullyBuilt>,
+        Option<&Experience>,
+        Option<&Energy>,
+    )>::query()
+    .filter(component::<Selected>())
+    .iter(world)
+    .filter(|(side, ..)| **side == player_side.0)
+    .filter_map(|(_, health, unit, building, built, experience, energy)| {
+        let (name, image, max_health) = match (unit, building) {
+            (Some(unit), _) => {
+                let stats = unit.stats();
+                match experience.filter(|experience| experience.rank() > 0) {
+                    Some(experience) => (
+                        format!("{} {:?}", experience.rank_name(), unit),
+                        stats.image,
+                        stats.max_health * experience.multiplier(),
+                    ),
+                    None => (format!("{:?}", unit), stats.image, stats.max_health),
+                }
+            }
+            (_, Some(building)) => {
+                let stats = building.stats();
+                (format!("{:?}", building), stats.image, stats.max_health)
+            }
+            _ => return None,
+        };
+
+        // Buildings that are still being constructed are greyed out.
+        let greyed = building.is_some() && built.is_none();
+
+        let energy = unit
+            .zip(energy)
+            .map(|(unit, energy)| energy.0 / unit.stats().max_energy);
+
+        Some((name, image, greyed, health.0, max_health, energy))
+    })
+    .collect();
+
+    // Keep the same kinds of units together.
+    selected.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let size = Vec2::broadcast(PORTRAIT_SIZE);
+    let bar_height = 4.0;
+
+    for (i, (_, image, greyed, health, max_health, energy)) in selected
+        .iter()
+        .take(PORTRAIT_COLUMNS * PORTRAIT_ROWS)
+        .enumerate()
+    {
+        let center = panel.cell_center(i);
+        let percentage = health / max_health;
+
+        line_buffers.draw_image(center, size, *image, *greyed, dpi);
+
+        let bar_center = center + Vec2::new(0.0, (PORTRAIT_SIZE - bar_height) / 2.0) * dpi;
+        line_buffers.draw_filled_rect(bar_center, Vec2::new(PORTRAIT_SIZE, bar_height), BLACK, dpi);
+
+        let health_colour =
+            Vec3::new(1.0, 0.0, 0.0) * (1.0 - percentage) + Vec3::new(0.0, 1.0, 0.0) * percentage;
+
+        line_buffers.draw_filled_rect(
+            bar_center - Vec2::new((1.0 - percentage) * PORTRAIT_SIZE / 2.0, 0.0) * dpi,
+            Vec2::new(PORTRAIT_SIZE * percentage, bar_height),
+            Vec4::new(health_colour.x, health_colour.y, health_colour.z, 1.0),
+            dpi,
+        );
+
+        // Energy goes in a second bar just above the health.
+        if let Some(energy) = energy {
+            let bar_center = bar_center - Vec2::new(0.0, bar_height) * dpi;
+            line_buffers.draw_filled_rect(
+                bar_center,
+                Vec2::new(PORTRAIT_SIZE, bar_height),
+                BLACK,
+                dpi,
+            );
+            line_buffers.draw_filled_rect(
+                bar_center - Vec2::new((1.0 - energy) * PORTRAIT_SIZE / 2.0, 0.0) * dpi,
+                Vec2::new(PORTRAIT_SIZE * energy, bar_height),
+                ENERGY_COLOUR,
+                dpi,
+            );
+        }
+    }
+
+    // With a single thing selected there's room to spell out its name and exact health.
+    if let [(name, _, _, health, max_health, _)] = &selected[..] {
+        text_buffer.draw_text(
+            Vec2::new(panel.center().x, panel.top_left.y - 12.0 * dpi),
+            &format!("{}: {}/{}", name, health.ceil(), max_health),
+            LABEL_SIZE * dpi,
+            Vec4::one(),
+        );
+    }
+}
+
+#[test]
+fn the_ui_shrinks_to_fit_small_windows() {
+    let settings = Settings {
+        ui_scale: 2.0,
+        ..Settings::default()
+    };
+    let big = ScreenDimensions {
+        width: 3840,
+        height: 2160,
+    };
+    let small = ScreenDimensions {
+        width: 1024,
+        height: 600,
+    };
+
+    assert_eq!(ui_scale(1.0, &settings, &big).0, 2.0);
+    assert_eq!(ui_scale(1.5, &Settings::default(), &big).0, 1.0);
+
+    let shrunk = ui_scale(1.0, &settings, &small);
+    assert!(shrunk.0 < 1.0);
+    // The selection panel doesn't overlap the command card.
+    let panel = HudGrid::selection_panel(&small, &shrunk);
+    let command_card = HudGrid::command_card(&small, &shrunk);
+    assert!(panel.top_left.x + panel.size().x * shrunk.0 <= command_card.top_left.x);
+}
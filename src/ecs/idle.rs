@@ -0,0 +1,116 @@
+use super::*;
+use controls::deselect_all;
+
+// The player's units that haven't got anything to do, so that they can be found again with F1
+// (for engineers) and F2 (for everything else). Rebuilt every frame by `track_idle_units`.
+#[derive(Default)]
+pub struct IdleUnits {
+    pub workers: Vec<Entity>,
+    pub army: Vec<Entity>,
+    // The units that were last jumped to, so that pressing the key again moves on to the next one.
+    last_worker: Option<Entity>,
+    last_army: Option<Entity>,
+}
+
+// The unit after `last` in the list, going back round to the start at the end. If `last` isn't
+// idle any more then this starts from the beginning again.
+fn next_idle(units: &[Entity], last: Option<Entity>) -> Option<Entity> {
+    if units.is_empty() {
+        return None;
+    }
+
+    let index = last
+        .and_then(|last| units.iter().position(|unit| *unit == last))
+        .map(|index| index + 1)
+        .unwrap_or(0);
+
+    Some(units[index % units.len()])
+}
+
+#[legion::system]
+#[read_component(Side)]
+#[read_component(CommandQueue)]
+#[read_component(CanBuild)]
+pub fn track_idle_units(
+    #[resource] idle_units: &mut IdleUnits,
+    #[resource] player_side: &PlayerSide,
+    world: &SubWorld,
+) {
+    idle_units.workers.clear();
+    idle_units.army.clear();
+
+    <(Entity, &Side, &CommandQueue, Option<&CanBuild>)>::query()
+        .filter(component::<Unit>())
+        .for_each(world, |(entity, side, commands, can_build)| {
+            if *side != player_side.0 || !commands.0.is_empty() {
+                return;
+            }
+
+            match can_build {
+                Some(_) => idle_units.workers.push(*entity),
+                None => idle_units.army.push(*entity),
+            }
+        });
+}
+
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(Position)]
+pub fn cycle_idle_units(
+    #[resource] idle_units: &mut IdleUnits,
+    #[resource] rts_controls: &RtsControls,
+    #[resource] camera: &mut Camera,
+    command_buffer: &mut CommandBuffer,
+    world: &SubWorld,
+) {
+    let IdleUnits {
+        workers,
+        army,
+        last_worker,
+        last_army,
+    } = idle_units;
+
+    let (units, last) = if rts_controls.select_idle_worker_pressed {
+        (workers, last_worker)
+    } else if rts_controls.select_idle_army_pressed {
+        (army, last_army)
+    } else {
+        return;
+    };
+
+    let next = match next_idle(units, *last) {
+        Some(next) => next,
+        None => return,
+    };
+    *last = Some(next);
+
+    deselect_all(world, command_buffer);
+
+    // Holding control grabs all of them at once, like control-clicking does for units on screen.
+    if rts_controls.control_held {
+        for unit in units.iter() {
+            command_buffer.add_component(*unit, Selected);
+        }
+    } else {
+        command_buffer.add_component(next, Selected);
+    }
+
+    if let Ok(position) = <&Position>::query().get(world, next) {
+        camera.jump_to(position.0);
+    }
+}
+
+#[test]
+fn idle_units_are_cycled_through_in_turn() {
+    let mut world = World::default();
+    let units: Vec<Entity> = (0..3).map(|_| world.push((Selectable,))).collect();
+
+    assert_eq!(next_idle(&units, None), Some(units[0]));
+    assert_eq!(next_idle(&units, Some(units[0])), Some(units[1]));
+    assert_eq!(next_idle(&units, Some(units[2])), Some(units[0]));
+
+    // Units that have been given an order since drop out of the list.
+    let busy = world.push((Selectable,));
+    assert_eq!(next_idle(&units, Some(busy)), Some(units[0]));
+    assert_eq!(next_idle(&[], Some(units[0])), None);
+}
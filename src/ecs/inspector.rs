@@ -0,0 +1,326 @@
+use super::{
+    AbilityCooldowns, Acceleration, Building, CommandQueue, Cooldown, Energy, Facing, FiringRange,
+    Health, MaxSpeed, Position, Radius, ReloadTime, Side, SightRange, Stance, Unit,
+};
+use crate::net::Lockstep;
+use crate::renderer::{Font, TextAlignment, TextBuffer};
+use crate::resources::{DpiScaling, MouseState, RayCastLocation};
+use legion::world::{EntryMut, EntryRef, SubWorld};
+use legion::{component, Entity, EntityStore, IntoQuery};
+use ultraviolet::{Vec2, Vec4};
+
+// How many entities are listed before the rest are cut off.
+const MAX_LISTED: usize = 12;
+
+// A debug panel for looking at (and tuning) the components of whatever was last clicked on. It's
+// toggled with F4 by default.
+#[derive(Default)]
+pub struct Inspector {
+    pub enabled: bool,
+    pub entity: Option<Entity>,
+    // Which of the inspected entity's fields the +/- keys change, out of the ones it has.
+    pub field: usize,
+    // How many steps to change the field by, set by `handle_keypresses`.
+    pub adjustment: i32,
+}
+
+// The values that can be edited. Anything else is only shown.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Field {
+    Health,
+    Energy,
+    PositionX,
+    PositionY,
+    Facing,
+    MaxSpeed,
+    Acceleration,
+    FiringRange,
+    ReloadTime,
+    SightRange,
+    Cooldown,
+}
+
+impl Field {
+    const ALL: [Self; 11] = [
+        Self::Health,
+        Self::Energy,
+        Self::PositionX,
+        Self::PositionY,
+        Self::Facing,
+        Self::MaxSpeed,
+        Self::Acceleration,
+        Self::FiringRange,
+        Self::ReloadTime,
+        Self::SightRange,
+        Self::Cooldown,
+    ];
+
+    fn step(self) -> f32 {
+        match self {
+            Self::Health | Self::Energy | Self::Acceleration => 5.0,
+            Self::Facing => 0.1,
+            Self::ReloadTime | Self::Cooldown => 0.05,
+            _ => 0.5,
+        }
+    }
+
+    fn get(self, entry: &EntryRef) -> Option<f32> {
+        match self {
+            Self::Health => entry.get_component::<Health>().ok().map(|c| c.0),
+            Self::Energy => entry.get_component::<Energy>().ok().map(|c| c.0),
+            Self::PositionX => entry.get_component::<Position>().ok().map(|c| c.0.x),
+            Self::PositionY => entry.get_component::<Position>().ok().map(|c| c.0.y),
+            Self::Facing => entry.get_component::<Facing>().ok().map(|c| c.0),
+            Self::MaxSpeed => entry.get_component::<MaxSpeed>().ok().map(|c| c.0),
+            Self::Acceleration => entry.get_component::<Acceleration>().ok().map(|c| c.0),
+            Self::FiringRange => entry.get_component::<FiringRange>().ok().map(|c| c.0),
+            Self::ReloadTime => entry.get_component::<ReloadTime>().ok().map(|c| c.0),
+            Self::SightRange => entry.get_component::<SightRange>().ok().map(|c| c.0),
+            Self::Cooldown => entry.get_component::<Cooldown>().ok().map(|c| c.0),
+        }
+    }
+
+    fn get_mut<'a>(self, entry: &'a mut EntryMut) -> Option<&'a mut f32> {
+        match self {
+            Self::Health => entry.get_component_mut::<Health>().ok().map(|c| &mut c.0),
+            Self::Energy => entry.get_component_mut::<Energy>().ok().map(|c| &mut c.0),
+            Self::PositionX => entry
+                .get_component_mut::<Position>()
+                .ok()
+                .map(|c| &mut c.0.x),
+            Self::PositionY => entry
+                .get_component_mut::<Position>()
+                .ok()
+                .map(|c| &mut c.0.y),
+            Self::Facing => entry.get_component_mut::<Facing>().ok().map(|c| &mut c.0),
+            Self::MaxSpeed => entry.get_component_mut::<MaxSpeed>().ok().map(|c| &mut c.0),
+            Self::Acceleration => entry
+                .get_component_mut::<Acceleration>()
+                .ok()
+                .map(|c| &mut c.0),
+            Self::FiringRange => entry
+                .get_component_mut::<FiringRange>()
+                .ok()
+                .map(|c| &mut c.0),
+            Self::ReloadTime => entry
+                .get_component_mut::<ReloadTime>()
+                .ok()
+                .map(|c| &mut c.0),
+            Self::SightRange => entry
+                .get_component_mut::<SightRange>()
+                .ok()
+                .map(|c| &mut c.0),
+            Self::Cooldown => entry.get_component_mut::<Cooldown>().ok().map(|c| &mut c.0),
+        }
+    }
+}
+
+// The fields that the entity actually has.
+fn fields(entry: &EntryRef) -> Vec<(Field, f32)> {
+    Field::ALL
+        .iter()
+        .filter_map(|field| field.get(entry).map(|value| (*field, value)))
+        .collect()
+}
+
+fn describe(entry: &EntryRef) -> String {
+    let kind = match (
+        entry.get_component::<Unit>(),
+        entry.get_component::<Building>(),
+    ) {
+        (Ok(unit), _) => format!("{:?}", unit),
+        (_, Ok(building)) => format!("{:?}", building),
+        _ => "Entity".to_string(),
+    };
+
+    match entry.get_component::<Side>() {
+        Ok(side) => format!("{} ({:?})", kind, side),
+        Err(_) => kind,
+    }
+}
+
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Radius)]
+pub fn pick_inspected_entity(
+    #[resource] inspector: &mut Inspector,
+    #[resource] mouse_state: &MouseState,
+    #[resource] ray_cast_location: &RayCastLocation,
+    world: &SubWorld,
+) {
+    // Forget about it once it's dead.
+    if let Some(entity) = inspector.entity {
+        if world.entry_ref(entity).is_err() {
+            inspector.entity = None;
+        }
+    }
+
+    if !inspector.enabled || !mouse_state.left_state.was_clicked() {
+        return;
+    }
+
+    let clicked = <(Entity, &Position, &Radius)>::query()
+        .iter(world)
+        .map(|(entity, position, radius)| {
+            let distance = (position.0 - ray_cast_location.pos).mag();
+            (*entity, distance, radius.0)
+        })
+        .filter(|(_, distance, radius)| distance < radius)
+        .min_by(|(_, a, _), (_, b, _)| a.partial_cmp(b).unwrap())
+        .map(|(entity, ..)| entity);
+
+    if clicked.is_some() && clicked != inspector.entity {
+        inspector.entity = clicked;
+        inspector.field = 0;
+    }
+}
+
+#[legion::system]
+#[write_component(Health)]
+#[write_component(Energy)]
+#[write_component(Position)]
+#[write_component(Facing)]
+#[write_component(MaxSpeed)]
+#[write_component(Acceleration)]
+#[write_component(FiringRange)]
+#[write_component(ReloadTime)]
+#[write_component(SightRange)]
+#[write_component(Cooldown)]
+pub fn edit_inspected_entity(
+    #[resource] inspector: &mut Inspector,
+    #[resource] lockstep: &Lockstep,
+    world: &mut SubWorld,
+) {
+    let adjustment = std::mem::replace(&mut inspector.adjustment, 0);
+
+    // Editing things on one side of a networked game would make it go out of sync.
+    if adjustment == 0 || lockstep.is_networked() {
+        return;
+    }
+
+    let entity = match inspector.entity {
+        Some(entity) => entity,
+        None => return,
+    };
+
+    let field = match world.entry_ref(entity) {
+        Ok(entry) => {
+            let fields = fields(&entry);
+            if fields.is_empty() {
+                return;
+            }
+            fields[inspector.field % fields.len()].0
+        }
+        Err(_) => return,
+    };
+
+    if let Ok(mut entry) = world.entry_mut(entity) {
+        if let Some(value) = field.get_mut(&mut entry) {
+            *value += adjustment as f32 * field.step();
+        }
+    }
+}
+
+#[legion::system]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(Side)]
+#[read_component(Health)]
+#[read_component(Energy)]
+#[read_component(Position)]
+#[read_component(Facing)]
+#[read_component(MaxSpeed)]
+#[read_component(Acceleration)]
+#[read_component(FiringRange)]
+#[read_component(ReloadTime)]
+#[read_component(SightRange)]
+#[read_component(Cooldown)]
+#[read_component(CommandQueue)]
+#[read_component(AbilityCooldowns)]
+#[read_component(Stance)]
+pub fn render_inspector(
+    #[resource] inspector: &Inspector,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] dpi_scaling: &DpiScaling,
+    world: &SubWorld,
+) {
+    if !inspector.enabled {
+        return;
+    }
+
+    let mut text = String::from("Entities:\n");
+
+    let listed: Vec<Entity> = <Entity>::query()
+        .filter(component::<Position>() & (component::<Unit>() | component::<Building>()))
+        .iter(world)
+        .copied()
+        .collect();
+
+    for entity in listed.iter().take(MAX_LISTED) {
+        if let Ok(entry) = world.entry_ref(*entity) {
+            let marker = if Some(*entity) == inspector.entity {
+                ">"
+            } else {
+                " "
+            };
+            text.push_str(&format!("{} {:?} {}\n", marker, entity, describe(&entry)));
+        }
+    }
+    if listed.len() > MAX_LISTED {
+        text.push_str(&format!("  ... and {} more\n", listed.len() - MAX_LISTED));
+    }
+
+    match inspector
+        .entity
+        .and_then(|entity| world.entry_ref(entity).ok())
+    {
+        Some(entry) => {
+            text.push_str(&format!("\n{}:\n", describe(&entry)));
+
+            let fields = fields(&entry);
+            for (i, (field, value)) in fields.iter().enumerate() {
+                let marker = if i == inspector.field % fields.len() {
+                    ">"
+                } else {
+                    " "
+                };
+                text.push_str(&format!("{} {:?}: {:.2}\n", marker, field, value));
+            }
+
+            if let Ok(stance) = entry.get_component::<Stance>() {
+                text.push_str(&format!("  Stance: {:?}\n", stance));
+            }
+
+            if let Ok(cooldowns) = entry.get_component::<AbilityCooldowns>() {
+                for (ability, remaining) in &cooldowns.0 {
+                    text.push_str(&format!("  {:?} cooldown: {:.2}\n", ability, remaining));
+                }
+            }
+
+            if let Ok(queue) = entry.get_component::<CommandQueue>() {
+                text.push_str(&format!("  Commands ({}):\n", queue.0.len()));
+                for command in &queue.0 {
+                    // Just the name of the command, as the paths make the whole thing very long.
+                    let debug = format!("{:?}", command);
+                    let name = debug.split(|c| c == ' ' || c == '{').next().unwrap_or("");
+                    text.push_str(&format!("    {}\n", name));
+                }
+            }
+
+            text.push_str("\nPageUp/PageDown: pick a field, +/-: change it (shift for 10x)\n");
+        }
+        None => text.push_str("\nLeft click on something to inspect it\n"),
+    }
+
+    let dpi = dpi_scaling.0;
+
+    text_buffer.render_text(
+        Vec2::new(10.0, 120.0) * dpi,
+        &text,
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::Default,
+        Vec4::new(1.0, 1.0, 1.0, 1.0),
+    );
+}
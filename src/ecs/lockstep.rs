@@ -0,0 +1,774 @@
+use super::*;
+use crate::audio::{Sound, SoundEvents};
+use crate::net::{CastTarget, Lockstep, PlayerCommand, RallyTarget};
+use crate::resources::{CheeseCoins, Formation, TotalTime};
+use abilities::can_cast;
+use controls::formation_destinations;
+use fnv::FnvHasher;
+use std::collections::HashMap;
+use std::hash::Hasher;
+use std::path::PathBuf;
+
+// Give everything that commands can refer to a `NetworkId`. Anything new is sorted by position
+// first so that both peers hand out the same ids, regardless of legion's internal ordering.
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(Position)]
+#[read_component(NetworkId)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(CheeseGuyser)]
+pub fn assign_network_ids(
+    #[resource] lockstep: &mut Lockstep,
+    world: &SubWorld,
+    commands: &mut CommandBuffer,
+) {
+    let mut new_entities: Vec<_> = <(Entity, &Position)>::query()
+        .filter(
+            !component::<NetworkId>()
+                & (component::<Unit>() | component::<Building>() | component::<CheeseGuyser>()),
+        )
+        .iter(world)
+        .map(|(entity, position)| (*entity, position.0))
+        .collect();
+
+    new_entities.sort_by_key(|(_, position)| {
+        (
+            ordered_float::OrderedFloat(position.x),
+            ordered_float::OrderedFloat(position.y),
+        )
+    });
+
+    for (entity, _) in new_entities {
+        commands.add_component(entity, lockstep.next_network_id());
+    }
+}
+
+// A hash of the state of everything with a `NetworkId`, from the end of the last tick. If two peers
+// (or a replay and the game it was recorded from) come up with different checksums for the same
+// tick, then they've desynced.
+#[derive(Default)]
+pub struct WorldChecksum(pub u64);
+
+pub fn world_checksum(world: &impl EntityStore) -> u64 {
+    let mut entities: Vec<_> = <(
+        &NetworkId,
+        &Position,
+        &Side,
+        Option<&Facing>,
+        Option<&Velocity>,
+        Option<&Health>,
+        Option<&CommandQueue>,
+    )>::query()
+    .iter(world)
+    .collect();
+
+    // Legion doesn't iterate over entities in the same order on every machine.
+    entities.sort_by_key(|(id, ..)| id.0);
+
+    // Unlike the std hasher, this is guaranteed to give the same results on every platform.
+    let mut hasher = FnvHasher::default();
+
+    for (id, position, side, facing, velocity, health, commands) in entities {
+        hasher.write_u32(id.0);
+        hasher.write_u32(position.0.x.to_bits());
+        hasher.write_u32(position.0.y.to_bits());
+        hasher.write_u8(*side as u8);
+        hasher.write_u32(facing.map(|facing| facing.0.to_bits()).unwrap_or(0));
+        if let Some(velocity) = velocity {
+            hasher.write_u32(velocity.0.x.to_bits());
+            hasher.write_u32(velocity.0.y.to_bits());
+        }
+        hasher.write_u32(health.map(|health| health.0.to_bits()).unwrap_or(0));
+        hasher.write_usize(commands.map(|commands| commands.0.len()).unwrap_or(0));
+    }
+
+    hasher.finish()
+}
+
+// The same things that go into the checksum, but in a form that can be compared between peers after
+// a desync to see what went wrong.
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct EntityState {
+    side: Side,
+    position: (f32, f32),
+    facing: Option<f32>,
+    velocity: Option<(f32, f32)>,
+    health: Option<f32>,
+    commands: Vec<String>,
+}
+
+fn dump_world(world: &impl EntityStore) -> String {
+    let entities: BTreeMap<u32, EntityState> = <(
+        &NetworkId,
+        &Position,
+        &Side,
+        Option<&Facing>,
+        Option<&Velocity>,
+        Option<&Health>,
+        Option<&CommandQueue>,
+    )>::query()
+    .iter(world)
+    .map(|(id, position, side, facing, velocity, health, commands)| {
+        let state = EntityState {
+            side: *side,
+            position: (position.0.x, position.0.y),
+            facing: facing.map(|facing| facing.0),
+            velocity: velocity.map(|velocity| (velocity.0.x, velocity.0.y)),
+            health: health.map(|health| health.0),
+            commands: commands
+                .map(|commands| {
+                    commands
+                        .0
+                        .iter()
+                        .map(|command| format!("{:?}", command))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        (id.0, state)
+    })
+    .collect();
+
+    serde_json::to_string(&entities).unwrap()
+}
+
+#[derive(serde::Serialize)]
+struct Difference {
+    network_id: u32,
+    ours: Option<EntityState>,
+    theirs: Option<EntityState>,
+}
+
+// Just the entities that are different (or only exist on one side).
+fn diff_dumps(ours: &str, theirs: &str) -> anyhow::Result<Vec<Difference>> {
+    let mut ours: BTreeMap<u32, EntityState> = serde_json::from_str(ours)?;
+    let mut theirs: BTreeMap<u32, EntityState> = serde_json::from_str(theirs)?;
+
+    let mut ids: Vec<u32> = ours.keys().chain(theirs.keys()).copied().collect();
+    ids.sort_unstable();
+    ids.dedup();
+
+    Ok(ids
+        .into_iter()
+        .filter_map(|id| {
+            let (ours, theirs) = (ours.remove(&id), theirs.remove(&id));
+            if ours == theirs {
+                None
+            } else {
+                Some(Difference {
+                    network_id: id,
+                    ours,
+                    theirs,
+                })
+            }
+        })
+        .collect())
+}
+
+fn write_desync_report(tick: u32, ours: &str, theirs: &str) -> anyhow::Result<PathBuf> {
+    #[derive(serde::Serialize)]
+    struct DesyncReport {
+        desynced_on_tick: u32,
+        differences: Vec<Difference>,
+    }
+
+    let report = DesyncReport {
+        desynced_on_tick: tick,
+        differences: diff_dumps(ours, theirs)?,
+    };
+
+    let path = PathBuf::from(format!("desync_{}.json", tick));
+    std::fs::write(&path, serde_json::to_string_pretty(&report)?)?;
+    Ok(path)
+}
+
+#[legion::system]
+#[read_component(NetworkId)]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Facing)]
+#[read_component(Velocity)]
+#[read_component(Health)]
+#[read_component(CommandQueue)]
+pub fn checksum_world(
+    #[resource] checksum: &mut WorldChecksum,
+    #[resource] lockstep: &mut Lockstep,
+    world: &SubWorld,
+) {
+    checksum.0 = world_checksum(world);
+    lockstep.record_checksum(checksum.0);
+
+    if lockstep.dump_due() {
+        lockstep.record_dump(dump_world(world));
+    }
+
+    if let Some((tick, ours, theirs)) = lockstep.take_dumps() {
+        match write_desync_report(tick, &ours, &theirs) {
+            Ok(path) => log::error!("Wrote what was different to {}", path.display()),
+            Err(error) => log::error!("Failed to write the desync report: {}", error),
+        }
+    }
+
+    // A NaN doesn't show up as a desync until it's spread to something else, by which point it's a
+    // lot harder to work out where it came from.
+    if cfg!(feature = "deterministic") {
+        for (id, position) in <(&NetworkId, &Position)>::query().iter(world) {
+            if !deterministic::is_finite(position.0) {
+                log::error!("{:?} has ended up at {:?}", id, position.0);
+            }
+        }
+    }
+}
+
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(NetworkId)]
+#[read_component(Position)]
+#[read_component(Radius)]
+#[read_component(Side)]
+#[read_component(CanAttack)]
+#[read_component(Airborne)]
+#[read_component(AntiAir)]
+#[read_component(CanBuild)]
+#[read_component(Abilities)]
+#[read_component(Energy)]
+#[read_component(AbilityCooldowns)]
+#[read_component(FullyBuilt)]
+#[read_component(Building)]
+#[read_component(Unit)]
+#[write_component(Burrow)]
+#[write_component(CommandQueue)]
+#[write_component(RecruitmentQueue)]
+#[write_component(Research)]
+#[write_component(Superweapon)]
+#[write_component(Stance)]
+#[write_component(Gate)]
+pub fn apply_player_commands(
+    #[resource] lockstep: &mut Lockstep,
+    #[resource] map: &mut Map,
+    #[resource] animations: &ModelAnimations,
+    #[resource] player_side: &PlayerSide,
+    #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] sound_events: &mut SoundEvents,
+    #[resource] upgrades: &Upgrades,
+    #[resource] alerts: &mut Alerts,
+    #[resource] total_time: &TotalTime,
+    world: &mut SubWorld,
+    commands: &mut CommandBuffer,
+) {
+    let player_commands = lockstep.take_commands();
+
+    if player_commands.is_empty() {
+        return;
+    }
+
+    let entities: HashMap<NetworkId, Entity> = <(Entity, &NetworkId)>::query()
+        .iter(world)
+        .map(|(entity, id)| (*id, *entity))
+        .collect();
+
+    // Only let players command their own things.
+    let owned = |ids: &[NetworkId], side: Side, world: &SubWorld| -> Vec<Entity> {
+        ids.iter()
+            .filter_map(|id| entities.get(id).copied())
+            .filter(|entity| {
+                <&Side>::query()
+                    .get(world, *entity)
+                    .map(|entity_side| *entity_side == side)
+                    .unwrap_or(false)
+            })
+            .collect()
+    };
+
+    for (side, player_command) in player_commands {
+        log::trace!("Applying {:?} for {:?}", player_command, side);
+
+        match player_command {
+            PlayerCommand::Move {
+                units,
+                target,
+                attack_move,
+                formation,
+                queue,
+            } => {
+                let units = owned(&units, side, world);
+
+                for (entity, _, destination) in
+                    formation_moves(units, target, formation, queue, map, world)
+                {
+                    push_command(
+                        entity,
+                        Command::MoveTo {
+                            target: destination,
+                            attack_move,
+                            path: Vec::new(),
+                        },
+                        queue,
+                        world,
+                    );
+                }
+            }
+            PlayerCommand::Attack {
+                units,
+                target,
+                queue,
+            } => {
+                if let Some(target) = entities.get(&target) {
+                    for entity in owned(&units, side, world) {
+                        if <&CanAttack>::query().get(world, entity).is_ok()
+                            && airborne::can_hit(entity, *target, world)
+                        {
+                            push_command(entity, Command::new_attack(*target, true), queue, world);
+                        }
+                    }
+                }
+            }
+            PlayerCommand::HelpBuild {
+                units,
+                target,
+                queue,
+            } => {
+                if let Some(target) = entities.get(&target) {
+                    for entity in owned(&units, side, world) {
+                        if <&CanBuild>::query().get(world, entity).is_ok() {
+                            push_command(entity, Command::new_build(*target), queue, world);
+                        }
+                    }
+                }
+            }
+            PlayerCommand::Construct {
+                builders,
+                building,
+                position,
+                guyser,
+                queue,
+            } => {
+                let allowed = tech::missing_prerequisites(
+                    &AbilityType::Build(building),
+                    side,
+                    upgrades,
+                    world,
+                )
+                .is_empty();
+
+                let building_entity = if allowed {
+                    building.add_to_world_to_construct(commands, position, side, animations, map)
+                } else {
+                    None
+                };
+
+                let building_entity = match building_entity {
+                    Some(entity) => entity,
+                    None => {
+                        // The player already paid for it when they placed it, so refund them.
+                        if side == player_side.0 {
+                            cheese_coins.0 += building.stats().cost;
+                        }
+                        continue;
+                    }
+                };
+
+                // Only for our own buildings, otherwise you could hear the enemy building things
+                // under the fog of war.
+                if side == player_side.0 {
+                    sound_events.play_at(Sound::Placement, position);
+                }
+
+                if let Some(guyser) = guyser.and_then(|guyser| entities.get(&guyser)) {
+                    commands.add_component(
+                        *guyser,
+                        CheeseGuyserBuiltOn {
+                            pump: building_entity,
+                        },
+                    );
+                }
+
+                for entity in owned(&builders, side, world) {
+                    if <&CanBuild>::query().get(world, entity).is_ok() {
+                        push_command(entity, Command::new_build(building_entity), queue, world);
+                    }
+                }
+            }
+            PlayerCommand::BuildWall {
+                builders,
+                start,
+                end,
+                queue,
+            } => {
+                let segments = walls::wall_segments(start, end);
+
+                let entities = match walls::add_wall_to_construct(commands, &segments, side, map) {
+                    Some(entities) => entities,
+                    None => {
+                        if side == player_side.0 {
+                            cheese_coins.0 += Building::Wall.stats().cost * segments.len() as u32;
+                        }
+                        continue;
+                    }
+                };
+
+                if side == player_side.0 {
+                    sound_events.play_at(Sound::Placement, segments[0]);
+                }
+
+                // Builders work their way along the wall from where it was started.
+                for entity in owned(&builders, side, world) {
+                    if <&CanBuild>::query().get(world, entity).is_ok() {
+                        for (i, segment) in entities.iter().enumerate() {
+                            push_command(
+                                entity,
+                                Command::new_build(*segment),
+                                queue || i > 0,
+                                world,
+                            );
+                        }
+                    }
+                }
+            }
+            PlayerCommand::LockGates { gates } => {
+                walls::lock_gates(&owned(&gates, side, world), world);
+            }
+            PlayerCommand::Burrow { units } => {
+                burrowing::toggle_burrow(&owned(&units, side, world), world, commands);
+            }
+            PlayerCommand::Follow {
+                units,
+                target,
+                queue,
+            } => {
+                // Units can only follow their own side's units.
+                let target = owned(&[target], side, world)
+                    .first()
+                    .copied()
+                    .filter(|target| <&Unit>::query().get(world, *target).is_ok());
+
+                if let Some(target) = target {
+                    for entity in owned(&units, side, world) {
+                        if entity != target {
+                            push_command(entity, Command::new_follow(target), queue, world);
+                        }
+                    }
+                }
+            }
+            PlayerCommand::Recruit { building, unit } => {
+                if !tech::missing_prerequisites(&AbilityType::Recruit(unit), side, upgrades, world)
+                    .is_empty()
+                {
+                    if side == player_side.0 {
+                        cheese_coins.0 += unit.stats().cost;
+                    }
+                    continue;
+                }
+
+                if let Some(entity) = owned(&[building], side, world).first() {
+                    if let Ok(queue) = <&mut RecruitmentQueue>::query().get_mut(world, *entity) {
+                        queue.queue.push_back(unit);
+                    }
+                }
+            }
+            PlayerCommand::LaunchStrike { silo, target } => {
+                let silo = owned(&[silo], side, world)
+                    .first()
+                    .copied()
+                    .filter(|entity| {
+                        <&mut Superweapon>::query()
+                            .filter(component::<FullyBuilt>())
+                            .get_mut(world, *entity)
+                            .map(|superweapon| {
+                                let ready = superweapon.is_ready();
+                                if ready {
+                                    *superweapon = Superweapon::default();
+                                }
+                                ready
+                            })
+                            .unwrap_or(false)
+                    });
+
+                if let Some(source) = silo {
+                    let strike = Strike {
+                        target,
+                        side,
+                        source,
+                        remaining: superweapon::STRIKE_DELAY,
+                    };
+
+                    superweapon::announce_strike(
+                        &strike,
+                        player_side,
+                        alerts,
+                        sound_events,
+                        total_time.0,
+                    );
+                    commands.push((strike,));
+                }
+            }
+            PlayerCommand::Research { building, upgrade } => {
+                // Both players could have started the same research on the same tick, or the
+                // armoury could have been destroyed in the meantime.
+                let can_research = !upgrades.has(side, upgrade)
+                    && tech::missing_prerequisites(
+                        &AbilityType::Research(upgrade),
+                        side,
+                        upgrades,
+                        world,
+                    )
+                    .is_empty()
+                    && !upgrades::is_researching(upgrade, side, world);
+
+                let research = owned(&[building], side, world)
+                    .first()
+                    .copied()
+                    .filter(|_| can_research)
+                    .and_then(|entity| {
+                        <&mut Research>::query()
+                            .filter(component::<FullyBuilt>())
+                            .get_mut(world, entity)
+                            .ok()
+                    })
+                    .filter(|research| research.current.is_none());
+
+                match research {
+                    Some(research) => research.current = Some(upgrade),
+                    None => {
+                        if side == player_side.0 {
+                            cheese_coins.0 += upgrade.stats().cost;
+                        }
+                    }
+                }
+            }
+            PlayerCommand::SetWaypoint { buildings, target } => {
+                let waypoint = match target {
+                    RallyTarget::Point(point) => RallyPoint::Position(point),
+                    RallyTarget::Guyser(guyser) => match entities.get(&guyser) {
+                        Some(entity) => RallyPoint::Guyser(*entity),
+                        None => continue,
+                    },
+                    // Units can only rally on their own side's units.
+                    RallyTarget::Unit(unit) => match owned(&[unit], side, world).first() {
+                        Some(entity) => RallyPoint::Unit(*entity),
+                        None => continue,
+                    },
+                };
+
+                for entity in owned(&buildings, side, world) {
+                    if let Ok(queue) = <&mut RecruitmentQueue>::query().get_mut(world, entity) {
+                        queue.waypoint = waypoint;
+                    }
+                }
+            }
+            PlayerCommand::Stop { units } => {
+                for entity in owned(&units, side, world) {
+                    if let Ok(commands) = <&mut CommandQueue>::query().get_mut(world, entity) {
+                        commands.0.clear();
+                    }
+                }
+            }
+            PlayerCommand::Patrol {
+                units,
+                target,
+                formation,
+                queue,
+            } => {
+                let units = owned(&units, side, world);
+
+                for (entity, start, destination) in
+                    formation_moves(units, target, formation, queue, map, world)
+                {
+                    push_command(
+                        entity,
+                        Command::new_patrol(start, destination),
+                        queue,
+                        world,
+                    );
+                }
+            }
+            PlayerCommand::Guard {
+                units,
+                center,
+                radius,
+                formation,
+                queue,
+            } => {
+                let units = owned(&units, side, world)
+                    .into_iter()
+                    .filter(|entity| <&CanAttack>::query().get(world, *entity).is_ok())
+                    .collect();
+
+                // Each unit gets its own spot to stand on, so they don't all crowd into the middle.
+                for (entity, _, destination) in
+                    formation_moves(units, center, formation, queue, map, world)
+                {
+                    push_command(
+                        entity,
+                        Command::new_guard(destination, radius),
+                        queue,
+                        world,
+                    );
+                }
+            }
+            PlayerCommand::HoldPosition { units, queue } => {
+                for entity in owned(&units, side, world) {
+                    if <&CanAttack>::query().get(world, entity).is_ok() {
+                        push_command(entity, Command::HoldPosition, queue, world);
+                    }
+                }
+            }
+            PlayerCommand::SetStance { units, stance } => {
+                for entity in owned(&units, side, world) {
+                    if let Ok(unit_stance) = <&mut Stance>::query().get_mut(world, entity) {
+                        *unit_stance = stance;
+
+                        if stance != Stance::Defensive {
+                            commands.remove_component::<Leash>(entity);
+                        }
+                    }
+                }
+            }
+            PlayerCommand::Cast {
+                units,
+                ability,
+                target,
+                queue,
+            } => {
+                let target = match target {
+                    CastTarget::Caster => AbilityTarget::Caster,
+                    CastTarget::Point(point) => AbilityTarget::Point(point),
+                    CastTarget::Unit(unit) => match entities.get(&unit) {
+                        Some(entity) => AbilityTarget::Unit(*entity),
+                        None => continue,
+                    },
+                };
+
+                if !tech::missing_prerequisites(
+                    &AbilityType::Active(ability),
+                    side,
+                    upgrades,
+                    world,
+                )
+                .is_empty()
+                {
+                    continue;
+                }
+
+                let casters = owned(&units, side, world)
+                    .into_iter()
+                    .filter(|entity| can_cast(ability, *entity, world));
+
+                // Everyone can boost themselves at once, but it only takes one unit to throw a
+                // grenade or heal someone, so the closest one does it.
+                if let AbilityTarget::Caster = target {
+                    for entity in casters.collect::<Vec<_>>() {
+                        push_command(entity, Command::new_cast(ability, target), queue, world);
+                    }
+                } else {
+                    let closest = casters.min_by_key(|entity| {
+                        let position = <&Position>::query()
+                            .get(world, *entity)
+                            .map(|position| position.0)
+                            .unwrap_or_default();
+                        let distance = target
+                            .position(position, world)
+                            .map(|target_position| (target_position - position).mag_sq())
+                            .unwrap_or(f32::MAX);
+                        ordered_float::OrderedFloat(distance)
+                    });
+
+                    if let Some(entity) = closest {
+                        push_command(entity, Command::new_cast(ability, target), queue, world);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Spread a group of units out around a target, returning where each one is starting from and where
+// it should end up. Queued commands start from the end of the unit's queue.
+fn formation_moves(
+    units: Vec<Entity>,
+    target: Vec2,
+    formation: Formation,
+    queue: bool,
+    map: &Map,
+    world: &SubWorld,
+) -> Vec<(Entity, Vec2, Vec2)> {
+    let units: Vec<_> = units
+        .into_iter()
+        .filter_map(|entity| {
+            <(&Position, &Radius, &CommandQueue)>::query()
+                .get(world, entity)
+                .ok()
+                .map(|(position, radius, commands)| {
+                    let position = if queue {
+                        commands.last_waypoint().unwrap_or(position.0)
+                    } else {
+                        position.0
+                    };
+                    (entity, position, radius.0)
+                })
+        })
+        .collect();
+
+    let positions: Vec<_> = units.iter().map(|(_, position, _)| *position).collect();
+    let spacing = units.iter().map(|(.., radius)| *radius).fold(0.0, f32::max) * 2.5;
+
+    // Clicking on a building sends everyone to just outside of it instead.
+    let target = map.nearest_passable_point(target, spacing / 2.5);
+    let destinations = formation_destinations(formation, target, &positions, spacing);
+
+    units
+        .into_iter()
+        .zip(destinations)
+        .map(|((entity, position, radius), destination)| {
+            // Don't try and send units into buildings, or to the other side of them.
+            let destination = if map.impassable_between(target, destination) {
+                target
+            } else {
+                destination
+            };
+
+            (
+                entity,
+                position,
+                map.nearest_passable_point(destination, radius),
+            )
+        })
+        .collect()
+}
+
+// Burrowed units ignore everything until they're told to come back up.
+fn push_command(entity: Entity, command: Command, queue: bool, world: &mut SubWorld) {
+    if let Ok(commands) = <&mut CommandQueue>::query()
+        .filter(!component::<Burrow>())
+        .get_mut(world, entity)
+    {
+        if !queue {
+            commands.0.clear();
+        }
+
+        commands.0.push_back(command);
+    }
+}
+
+#[test]
+fn desync_dumps_are_diffed() {
+    let mut world = World::default();
+    let unit = |world: &mut World, id, x| {
+        world.push((NetworkId(id), Position(Vec2::new(x, 0.0)), Side::Green))
+    };
+    unit(&mut world, 0, 1.0);
+    let moved = unit(&mut world, 1, 2.0);
+    let ours = dump_world(&world);
+
+    *<&mut Position>::query().get_mut(&mut world, moved).unwrap() = Position(Vec2::new(2.5, 0.0));
+    unit(&mut world, 2, 3.0);
+    let theirs = dump_world(&world);
+
+    let differences = diff_dumps(&ours, &theirs).unwrap();
+    assert_eq!(differences.len(), 2);
+    assert_eq!(differences[0].network_id, 1);
+    assert_eq!(differences[0].ours.as_ref().unwrap().position, (2.0, 0.0));
+    assert_eq!(differences[0].theirs.as_ref().unwrap().position, (2.5, 0.0));
+    assert!(differences[1].ours.is_none());
+}
@@ -0,0 +1,179 @@
+use super::*;
+use crate::pathfinding::MAP_SIZE;
+use crate::renderer::LineBuffers;
+use crate::resources::{DpiScaling, Mode, TeamColours, TotalTime};
+use ultraviolet::Vec4;
+
+pub(super) const MINIMAP_SIZE: f32 = 200.0;
+pub(super) const MINIMAP_MARGIN: f32 = 10.0;
+
+const BACKGROUND: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.75);
+const CAMERA_OUTLINE: Vec4 = Vec4::new(1.0, 1.0, 1.0, 1.0);
+const ALERT_FLARE: Vec4 = Vec4::new(1.0, 0.2, 0.1, 1.0);
+// How far the flares spread out, in minimap pixels.
+const ALERT_FLARE_SIZE: f32 = 25.0;
+
+// The square in the bottom-left corner of the screen that the minimap is drawn in.
+pub struct MinimapArea {
+    top_left: Vec2,
+    size: f32,
+}
+
+impl MinimapArea {
+    pub fn new(screen_dimensions: &ScreenDimensions, dpi_scaling: &DpiScaling) -> Self {
+        let dpi = dpi_scaling.0;
+        let size = MINIMAP_SIZE * dpi;
+
+        Self {
+            top_left: Vec2::new(
+                MINIMAP_MARGIN * dpi,
+                screen_dimensions.height as f32 - size - MINIMAP_MARGIN * dpi,
+            ),
+            size,
+        }
+    }
+
+    pub fn top(&self) -> f32 {
+        self.top_left.y
+    }
+
+    pub fn contains(&self, screen_position: Vec2) -> bool {
+        let relative = screen_position - self.top_left;
+        relative.x >= 0.0 && relative.y >= 0.0 && relative.x <= self.size && relative.y <= self.size
+    }
+
+    pub fn to_world(&self, screen_position: Vec2) -> Vec2 {
+        let relative = (screen_position - self.top_left) / self.size;
+        (relative - Vec2::broadcast(0.5)) * MAP_SIZE
+    }
+
+    fn to_screen(&self, world_position: Vec2) -> Vec2 {
+        let relative = world_position / MAP_SIZE + Vec2::broadcast(0.5);
+        // Clamp so that the camera outline doesn't go off the side of the minimap.
+        let relative = Vec2::new(relative.x.clamp(0.0, 1.0), relative.y.clamp(0.0, 1.0));
+        self.top_left + relative * self.size
+    }
+
+    fn center(&self) -> Vec2 {
+        self.top_left + Vec2::broadcast(self.size / 2.0)
+    }
+}
+
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(Building)]
+#[read_component(Capturable)]
+#[read_component(Cloaked)]
+pub fn render_minimap(
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] camera: &Camera,
+    #[resource] player_side: &PlayerSide,
+    #[resource] team_colours: &TeamColours,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] mode: &Mode,
+    #[resource] alerts: &Alerts,
+    #[resource] total_time: &TotalTime,
+    #[resource] line_buffers: &mut LineBuffers,
+    world: &SubWorld,
+) {
+    if *mode != Mode::Playing {
+        return;
+    }
+
+    let area = MinimapArea::new(screen_dimensions, dpi_scaling);
+    // `draw_filled_rect` multiplies dimensions by the dpi, so we need to divide sizes by it.
+    let scale = area.size / MAP_SIZE / dpi_scaling.0;
+
+    line_buffers.draw_filled_rect(
+        area.center(),
+        Vec2::broadcast(MINIMAP_SIZE),
+        BACKGROUND,
+        dpi_scaling.0,
+    );
+
+    <(
+        &Position,
+        &Side,
+        Option<&Unit>,
+        Option<&Building>,
+        Option<&Cloaked>,
+    )>::query()
+    .iter(world)
+    .filter(|(position, side, .., cloaked)| {
+        fog_of_war.can_see(player_side.0, **side, position.0)
+            && !stealth::is_hidden(*cloaked, player_side.0, **side)
+    })
+    .for_each(|(position, side, unit, building, _)| {
+        let colour = team_colours.get(*side);
+
+        let dimensions = match (unit, building) {
+            (_, Some(building)) => building.stats().dimensions * scale,
+            // Make sure units are at least a couple of pixels big.
+            (Some(unit), _) => Vec2::broadcast((unit.stats().radius * 2.0 * scale).max(3.0)),
+            _ => return,
+        };
+
+        line_buffers.draw_filled_rect(
+            area.to_screen(position.0),
+            dimensions,
+            Vec4::new(colour.x, colour.y, colour.z, 1.0),
+            dpi_scaling.0,
+        );
+    });
+
+    // Structures are always on the minimap, so that both players know where to fight over.
+    <(&Position, &Capturable)>::query()
+        .iter(world)
+        .for_each(|(position, capturable)| {
+            let colour = match capturable.owner {
+                Some(owner) if fog_of_war.can_see(player_side.0, owner, position.0) => {
+                    team_colours.get(owner)
+                }
+                _ => Vec3::broadcast(0.6),
+            };
+
+            line_buffers.draw_filled_rect(
+                area.to_screen(position.0),
+                (capturable.structure.dimensions() * scale).max_by_component(Vec2::broadcast(3.0)),
+                Vec4::new(colour.x, colour.y, colour.z, 1.0),
+                dpi_scaling.0,
+            );
+        });
+
+    // Draw the part of the ground that the camera can see.
+    let dims = screen_dimensions.as_vec();
+    let corners = [
+        Vec2::new(0.0, 0.0),
+        Vec2::new(dims.x, 0.0),
+        dims,
+        Vec2::new(0.0, dims.y),
+    ];
+    let outline: Vec<_> = corners
+        .iter()
+        .map(|corner| area.to_screen(camera.cast_ray(*corner, screen_dimensions)))
+        .collect();
+
+    line_buffers.draw_polygon_outline(&outline, CAMERA_OUTLINE, dpi_scaling.0);
+
+    // Alerts flare outwards from where the player's things are being attacked, fading as they go.
+    for (position, progress) in alerts.flares(total_time.0) {
+        let center = area.to_screen(position);
+        let size = ALERT_FLARE_SIZE * progress * dpi_scaling.0;
+        let colour = Vec4 {
+            w: 1.0 - progress,
+            ..ALERT_FLARE
+        };
+
+        let diamond = [
+            center - Vec2::new(0.0, size),
+            center + Vec2::new(size, 0.0),
+            center + Vec2::new(0.0, size),
+            center - Vec2::new(size, 0.0),
+        ];
+
+        line_buffers.draw_polygon_outline(&diamond, colour, dpi_scaling.0);
+    }
+}
@@ -1,13 +1,251 @@
 use super::*;
-use crate::pathfinding::Map;
-use crate::resources::DeltaTime;
+use crate::pathfinding::{FlowField, Map};
+use crate::resources::{DebugControls, DeltaTime};
+use abilities::SPEED_BOOST_MULTIPLIER;
+use std::collections::HashSet;
+use std::f32::consts::PI;
 
 // Units try to get this much closer to enemies than their firing range.
-const FIRING_RANGE_FUDGE_FACTOR: f32 = 0.05;
+pub(super) const FIRING_RANGE_FUDGE_FACTOR: f32 = 0.05;
+// How far behind the unit they're following followers try to stay.
+const FOLLOW_DISTANCE: f32 = 3.0;
+// Guards that have been nudged this far off their spot don't bother walking back to it.
+const GUARD_SLACK: f32 = 1.0;
+
+// Re-run pathfinding for units whose current path goes through a part of the map that has changed
+// (e.g. a building has been placed or destroyed) since it was computed. Flying units don't care.
+#[legion::system(for_each)]
+#[filter(!component::<Airborne>())]
+pub fn repath_invalidated_paths(
+    position: &Position,
+    radius: &Radius,
+    max_speed: Option<&MaxSpeed>,
+    turn_rate: Option<&TurnRate>,
+    path_version: &mut PathVersion,
+    command_queue: &mut CommandQueue,
+    #[resource] map: &Map,
+) {
+    if path_version.0 == map.version() {
+        return;
+    }
+
+    let mut pop_front = false;
+
+    if let Some(path) = command_queue
+        .0
+        .front_mut()
+        .and_then(|command| command.path_mut())
+    {
+        if let Some(&end) = path.last() {
+            if map.path_invalidated_since(path_version.0, position.0, path, radius.0) {
+                match map.pathfind(position.0, end, radius.0, None, None) {
+                    Some(new_path) => {
+                        let turn_radius = turn_radius(max_speed, turn_rate);
+                        *path = map.smooth_path(position.0, new_path, radius.0, turn_radius);
+                    }
+                    None => pop_front = true,
+                }
+            }
+        }
+    }
+
+    if pop_front {
+        command_queue.0.pop_front();
+    }
+
+    path_version.0 = map.version();
+}
+
+// How many paths get solved each tick. Ordering a big group to move at once used to mean doing
+// all of their pathfinding in a single tick, which caused a noticeable hitch. Now the requests are
+// spread out over a few ticks instead.
+const PATHS_PER_TICK: usize = 16;
+// Groups of at least this many units going to within this distance of each other use a flow field.
+const FLOW_FIELD_GROUP_SIZE: usize = 20;
+const FLOW_FIELD_GROUP_SPREAD: f32 = 20.0;
+
+struct PathRequest {
+    entity: Entity,
+    end: Vec2,
+    radius: f32,
+}
+
+// Move and patrol orders queue up their pathfinding here instead of doing it straight away. This
+// can't be done on another thread, as every peer in a networked game needs the paths to arrive on
+// the same tick.
+#[derive(Default)]
+pub struct PathRequests {
+    // Requests made this tick. These come in in legion's order, which isn't the same on every
+    // machine, so they get sorted before they join the back of the queue.
+    incoming: Vec<PathRequest>,
+    queue: VecDeque<PathRequest>,
+    // Entities that have either got a request in the queue or a solved `Path` waiting for them.
+    pending: HashSet<Entity>,
+    // Kept around so that it can be drawn with `debug flow_fields`.
+    pub(super) last_flow_field: Option<FlowField>,
+}
+
+impl PathRequests {
+    fn request(&mut self, entity: Entity, end: Vec2, radius: f32) {
+        if self.pending.insert(entity) {
+            self.incoming.push(PathRequest {
+                entity,
+                end,
+                radius,
+            });
+        }
+    }
+
+    pub fn is_pending(&self, entity: Entity) -> bool {
+        self.pending.contains(&entity)
+    }
+}
+
+// A solved path request. If the unit has been given a different order since asking for it then
+// `end` won't match and it gets thrown away.
+pub struct Path {
+    end: Vec2,
+    waypoints: Option<Vec<Vec2>>,
+}
 
 #[legion::system]
-pub fn reset_map_updated(#[resource] map: &mut Map) {
-    map.updated_this_tick = false;
+#[read_component(Position)]
+#[read_component(NetworkId)]
+#[write_component(MovementDebugging)]
+pub fn solve_path_requests(
+    world: &mut SubWorld,
+    command_buffer: &mut CommandBuffer,
+    #[resource] path_requests: &mut PathRequests,
+    #[resource] map: &Map,
+    #[resource] debug_controls: &DebugControls,
+) {
+    // Otherwise each peer could spend the budget on a different set of units. Anything that doesn't
+    // have a `NetworkId` yet goes by where it is instead.
+    let mut incoming = std::mem::take(&mut path_requests.incoming);
+    incoming.sort_by_cached_key(|request| {
+        let network_id = <&NetworkId>::query()
+            .get(world, request.entity)
+            .ok()
+            .map(|network_id| network_id.0);
+        let position = <&Position>::query()
+            .get(world, request.entity)
+            .map(|position| position.0)
+            .unwrap_or_else(|_| Vec2::new(0.0, 0.0));
+        (
+            network_id,
+            ordered_float::OrderedFloat(position.x),
+            ordered_float::OrderedFloat(position.y),
+        )
+    });
+    path_requests.queue.extend(incoming);
+
+    let mut budget = PATHS_PER_TICK;
+
+    while budget > 0 {
+        let request = match path_requests.queue.pop_front() {
+            Some(request) => request,
+            None => break,
+        };
+        budget -= 1;
+
+        // Big groups heading to the same place (like a move order in formation) share a flow field
+        // instead of each doing their own pathfinding, and they all get their paths at once. The
+        // group is formed around the first request in the queue, which has been sorted, so every
+        // peer puts the same units in it.
+        let in_group = |other: &PathRequest| {
+            other.radius == request.radius
+                && (other.end - request.end).mag() < FLOW_FIELD_GROUP_SPREAD
+        };
+
+        if path_requests
+            .queue
+            .iter()
+            .filter(|other| in_group(other))
+            .count()
+            + 1
+            >= FLOW_FIELD_GROUP_SIZE
+        {
+            if let Some(flow_field) = map.flow_field(request.end, request.radius) {
+                let (group, rest): (VecDeque<_>, VecDeque<_>) = path_requests
+                    .queue
+                    .drain(..)
+                    .partition(|other| in_group(other));
+                path_requests.queue = rest;
+
+                for request in std::iter::once(request).chain(group) {
+                    match <&Position>::query().get(world, request.entity) {
+                        Ok(position) => {
+                            let waypoints = flow_field.path(map, position.0, request.end);
+                            deliver_path(command_buffer, &request, waypoints);
+                        }
+                        Err(_) => {
+                            path_requests.pending.remove(&request.entity);
+                        }
+                    }
+                }
+
+                path_requests.last_flow_field = Some(flow_field);
+                continue;
+            }
+        }
+
+        let start = match <&Position>::query().get(world, request.entity) {
+            Ok(position) => position.0,
+            // The unit died while waiting.
+            Err(_) => {
+                path_requests.pending.remove(&request.entity);
+                continue;
+            }
+        };
+
+        let waypoints = match <&mut MovementDebugging>::query().get_mut(world, request.entity) {
+            Ok(movement_debugging) => {
+                movement_debugging.path_start = start;
+                movement_debugging.path_end = request.end;
+                map.pathfind(
+                    start,
+                    request.end,
+                    request.radius,
+                    Some(&mut movement_debugging.triangles),
+                    Some(&mut movement_debugging.funnel_points),
+                )
+            }
+            // Units only get a `MovementDebugging` of their own once `debug funnels` is turned on.
+            Err(_) if debug_controls.show_funnels => {
+                let mut movement_debugging = MovementDebugging {
+                    path_start: start,
+                    path_end: request.end,
+                    ..Default::default()
+                };
+                let waypoints = map.pathfind(
+                    start,
+                    request.end,
+                    request.radius,
+                    Some(&mut movement_debugging.triangles),
+                    Some(&mut movement_debugging.funnel_points),
+                );
+                command_buffer.add_component(request.entity, movement_debugging);
+                waypoints
+            }
+            Err(_) => map.pathfind(start, request.end, request.radius, None, None),
+        };
+
+        deliver_path(command_buffer, &request, waypoints);
+    }
+}
+
+fn deliver_path(
+    command_buffer: &mut CommandBuffer,
+    request: &PathRequest,
+    waypoints: Option<Vec<Vec2>>,
+) {
+    command_buffer.add_component(
+        request.entity,
+        Path {
+            end: request.end,
+            waypoints,
+        },
+    );
 }
 
 #[legion::system(for_each)]
@@ -18,10 +256,15 @@ pub fn set_movement_paths(
     entity: &Entity,
     radius: &Radius,
     firing_range: Option<&FiringRange>,
+    max_speed: Option<&MaxSpeed>,
+    turn_rate: Option<&TurnRate>,
     command_queue: &mut CommandQueue,
-    mut movement_debugging: Option<&mut MovementDebugging>,
+    solved_path: Option<&Path>,
+    airborne: Option<&Airborne>,
     world: &SubWorld,
+    command_buffer: &mut CommandBuffer,
     #[resource] map: &Map,
+    #[resource] path_requests: &mut PathRequests,
 ) {
     // Grrrr.... In a `for_each` system, you can't pass in an `&T` and also have a query accessing
     // it, so we have to add `filter(component::<T>())` and do this.
@@ -31,41 +274,53 @@ pub fn set_movement_paths(
 
     let mut pop_front = false;
 
+    let turn_radius = turn_radius(max_speed, turn_rate);
+    // Flying units go straight there.
+    let pathfind = |end: Vec2| match airborne {
+        Some(_) => Some(vec![end]),
+        None => map
+            .pathfind(position.0, end, radius.0, None, None)
+            .map(|path| map.smooth_path(position.0, path, radius.0, turn_radius)),
+    };
+
+    // Take the solved path, if there is one, so that the unit can ask for another.
+    let solved_path = solved_path.map(|solved_path| {
+        command_buffer.remove_component::<Path>(*entity);
+        path_requests.pending.remove(entity);
+        solved_path
+    });
+
     // Todo: if a unit has an implicit attack command on a building and it gets destroyed, the
     // underlying attack move doesnt have its path updated. The solution is maybe to use a dirty
     // flag for this.
 
     match command_queue.0.front_mut() {
         Some(&mut Command::MoveTo {
-            target,
+            target: end,
+            ref mut path,
+            ..
+        })
+        | Some(&mut Command::Patrol {
+            b: end,
             ref mut path,
             ..
         }) => {
-            if path.is_empty() || map.updated_this_tick {
-                let (debug_triangles, debug_funnel_points) = match movement_debugging.as_mut() {
-                    Some(movement_debugging) => (
-                        Some(&mut movement_debugging.triangles),
-                        Some(&mut movement_debugging.funnel_points),
-                    ),
-                    None => (None, None),
-                };
-
-                match map.pathfind(
-                    position.0,
-                    target,
-                    radius.0,
-                    debug_triangles,
-                    debug_funnel_points,
-                ) {
-                    Some(pathing) => {
-                        *path = pathing;
-
-                        if let Some(md) = movement_debugging {
-                            md.path_start = position.0;
-                            md.path_end = target;
+            if path.is_empty() && airborne.is_some() {
+                *path = vec![end];
+            } else if path.is_empty() {
+                match solved_path {
+                    Some(solved_path) if solved_path.end == end => match &solved_path.waypoints {
+                        Some(waypoints) => {
+                            *path = map.smooth_path(
+                                position.0,
+                                waypoints.clone(),
+                                radius.0,
+                                turn_radius,
+                            )
                         }
-                    }
-                    None => pop_front = true,
+                        None => pop_front = true,
+                    },
+                    _ => path_requests.request(*entity, end, radius.0),
                 }
             }
         }
@@ -100,7 +355,7 @@ pub fn set_movement_paths(
                     target_pos.0
                 };
 
-                match map.pathfind(position.0, target_pos, radius.0, None, None) {
+                match pathfind(target_pos) {
                     Some(path) => *state = ActionState::OutOfRange { path },
                     None => pop_front = true,
                 }
@@ -132,8 +387,8 @@ pub fn set_movement_paths(
 
             let out_of_range = vector.mag_sq() > 0.1_f32.powi(2);
 
-            if out_of_range && (!state.is_out_of_range() || map.updated_this_tick) {
-                match map.pathfind(position.0, target_pos, radius.0, None, None) {
+            if out_of_range && !state.is_out_of_range() {
+                match pathfind(target_pos) {
                     Some(path) => *state = ActionState::OutOfRange { path },
                     None => pop_front = true,
                 }
@@ -141,7 +396,81 @@ pub fn set_movement_paths(
                 *state = ActionState::InRange
             }
         }
-        None => {}
+        Some(&mut Command::Cast {
+            ability,
+            target,
+            ref mut state,
+        }) => {
+            let target_pos = target
+                .position(position.0, world)
+                .expect("We've cancelled actions on dead entities");
+
+            let range = ability.stats().range;
+            let out_of_range = (target_pos - position.0).mag_sq() > range.powi(2);
+
+            // Units that are being healed might wander off, so follow them if they get too far
+            // from the end of the path.
+            let path_outdated = match state {
+                ActionState::OutOfRange { path } => path
+                    .last()
+                    .map(|end| (*end - target_pos).mag_sq() > range.powi(2))
+                    .unwrap_or(true),
+                ActionState::InRange => true,
+            };
+
+            if out_of_range && path_outdated {
+                match pathfind(target_pos) {
+                    Some(path) => *state = ActionState::OutOfRange { path },
+                    None => pop_front = true,
+                }
+            } else if !out_of_range {
+                *state = ActionState::InRange;
+            }
+        }
+        Some(&mut Command::Follow {
+            target,
+            ref mut path,
+        }) => {
+            let target_pos = <&Position>::query()
+                .get(world, target)
+                .expect("We've cancelled actions on dead entities")
+                .0;
+
+            // Trail along behind on whichever side the follower is coming from.
+            let offset = position.0 - target_pos;
+            let trailing_point = match offset.mag_sq() {
+                mag_sq if mag_sq > 0.0 => target_pos + offset.normalized() * FOLLOW_DISTANCE,
+                _ => target_pos,
+            };
+            let trailing_point = map.nearest_passable_point(trailing_point, radius.0);
+
+            // Only pathfind again once the target has moved on a bit, instead of every tick.
+            let path_outdated = match path.last() {
+                Some(end) => (*end - trailing_point).mag_sq() > FOLLOW_DISTANCE.powi(2),
+                None => offset.mag_sq() > (FOLLOW_DISTANCE * 2.0).powi(2),
+            };
+
+            if path_outdated {
+                match pathfind(trailing_point) {
+                    Some(new_path) => *path = new_path,
+                    None => pop_front = true,
+                }
+            }
+        }
+        Some(&mut Command::Guard {
+            center,
+            ref mut path,
+            ..
+        }) => {
+            // Either it's just been told to guard, or it's finished a fight.
+            if path.is_empty() && (center - position.0).mag_sq() > GUARD_SLACK.powi(2) {
+                match pathfind(center) {
+                    Some(new_path) => *path = new_path,
+                    None => pop_front = true,
+                }
+            }
+        }
+        Some(&mut Command::HoldPosition) | None => {}
     }
     if pop_front {
         command_queue.0.pop_front();
@@ -150,14 +479,35 @@ pub fn set_movement_paths(
 
 #[legion::system(for_each)]
 pub fn move_units(
+    entity: &Entity,
     position: &mut Position,
     facing: &mut Facing,
-    move_speed: &MoveSpeed,
+    max_speed: &MaxSpeed,
+    acceleration: Option<&Acceleration>,
+    turn_rate: Option<&TurnRate>,
+    velocity: Option<&mut Velocity>,
+    speed_boost: Option<&SpeedBoost>,
     commands: &mut CommandQueue,
     #[resource] delta_time: &DeltaTime,
+    #[resource] path_requests: &PathRequests,
 ) {
+    // Otherwise the empty path would look like the unit had already arrived.
+    if path_requests.is_pending(*entity) {
+        return;
+    }
+
     let mut pop_front = false;
 
+    let max_speed = match speed_boost {
+        Some(_) => max_speed.0 * SPEED_BOOST_MULTIPLIER,
+        None => max_speed.0,
+    };
+
+    // Units only come to a stop at the end of their last order. Patrols never end.
+    let stops_at_end =
+        commands.0.len() == 1 && !matches!(commands.0.front(), Some(Command::Patrol { .. }));
+    let mut new_velocity = Vec2::zero();
+
     if let Some(path) = commands
         .0
         .front_mut()
@@ -166,13 +516,44 @@ pub fn move_units(
         if path.is_empty() {
             pop_front = true;
         } else {
-            move_towards(
-                &mut position.0,
-                &mut facing.0,
-                path[0],
-                move_speed.0,
-                delta_time.0,
-            );
+            let direction = path[0] - position.0;
+            turn_towards(&mut facing.0, direction, turn_rate, delta_time.0);
+
+            let speed = match acceleration {
+                Some(acceleration) => {
+                    let mut target_speed = max_speed;
+
+                    // Brake in time to stop right at the end of the path.
+                    if stops_at_end {
+                        let remaining = direction.mag()
+                            + path
+                                .windows(2)
+                                .map(|pair| (pair[1] - pair[0]).mag())
+                                .sum::<f32>();
+                        target_speed = target_speed.min((2.0 * acceleration.0 * remaining).sqrt());
+                    }
+
+                    // Units that need to turn around slow down (or stop) to do it, instead of
+                    // walking sideways or backwards.
+                    if turn_rate.is_some() && direction.mag_sq() > 0.0 {
+                        let facing = deterministic::direction(facing.0);
+                        target_speed *= facing.dot(direction.normalized()).max(0.0);
+                    }
+
+                    let current_speed = velocity.as_ref().map(|v| v.0.mag()).unwrap_or(0.0);
+                    let change = acceleration.0 * delta_time.0;
+                    target_speed
+                        .max(current_speed - change)
+                        .min(current_speed + change)
+                }
+                None => max_speed,
+            };
+
+            move_towards(&mut position.0, path[0], speed, delta_time.0);
+
+            if direction.mag_sq() > 0.0 {
+                new_velocity = direction.normalized() * speed;
+            }
 
             if position.0 == path[0] {
                 path.remove(0);
@@ -182,8 +563,21 @@ pub fn move_units(
             }
         }
     }
+    if let Some(velocity) = velocity {
+        velocity.0 = new_velocity;
+    }
+
     if pop_front {
-        commands.0.pop_front();
+        match commands.0.front_mut() {
+            // Patrols never finish, they just turn around and get a new path next tick.
+            Some(Command::Patrol { a, b, .. }) => std::mem::swap(a, b),
+            // Followers wait where they are until the unit they're following moves on, and guards
+            // stay put until something comes near.
+            Some(Command::Follow { .. }) | Some(Command::Guard { .. }) => {}
+            _ => {
+                commands.0.pop_front();
+            }
+        }
     }
 }
 
@@ -191,36 +585,112 @@ pub struct Avoidance(pub Vec2);
 pub struct Avoids;
 pub struct Avoidable;
 
+// How far ahead moving units look for other units that they're about to walk into.
+const AVOIDANCE_LOOKAHEAD: f32 = 1.5;
+
 #[legion::system]
 #[read_component(Position)]
 #[read_component(Radius)]
+#[read_component(CommandQueue)]
+#[read_component(Burrow)]
+#[read_component(Airborne)]
+#[read_component(NetworkId)]
 pub fn avoidance(world: &SubWorld, command_buffer: &mut CommandBuffer) {
     let command_buffer = std::sync::Mutex::new(command_buffer);
 
-    <(Entity, &Position, &Radius)>::query()
-        .filter(component::<Avoids>())
-        .par_for_each(world, |(entity, position, radius)| {
-            let mut avoidance_direction = Vec2::new(0.0, 0.0);
-            let mut count = 0;
-
-            for (other_position, other_radius) in <(&Position, &Radius)>::query()
-                .filter(component::<Avoidable>())
-                .iter(world)
+    <(Entity, &Position, &Radius, Option<&CommandQueue>)>::query()
+        // Burrowed units stay where they are, underneath everyone else.
+        .filter(component::<Avoids>() & !component::<Burrow>())
+        .par_for_each(world, |(entity, position, radius, command_queue)| {
+            // Not part of the query, as legion panics on a second optional component when
+            // iterating in parallel.
+            let airborne = <&Airborne>::query().get(world, *entity).ok();
+            // Adding floats up in a different order can give a different answer, so these get
+            // sorted before they're added up, instead of going in legion's order.
+            let mut pushes = Vec::new();
+
+            // The direction the unit is currently walking in, if it's walking anywhere.
+            let heading = command_queue
+                .and_then(|queue| queue.0.front())
+                .and_then(|command| command.path())
+                .and_then(|path| path.first())
+                .map(|next_point| *next_point - position.0)
+                .filter(|direction| direction.mag_sq() > 0.0)
+                .map(|direction| direction.normalized());
+
+            for (other_entity, other_position, other_radius, other_airborne) in
+                <(Entity, &Position, &Radius, Option<&Airborne>)>::query()
+                    .filter(component::<Avoidable>() & !component::<Burrow>())
+                    .iter(world)
             {
+                // Flying units pass over everything on the ground.
+                if other_entity == entity || !airborne::same_layer(airborne, other_airborne) {
+                    continue;
+                }
+
                 let away_vector = position.0 - other_position.0;
                 let distance_sq = away_vector.mag_sq();
                 let desired_seperation = radius.0 + other_radius.0;
 
-                if distance_sq > 0.0 && distance_sq < desired_seperation.powi(2) {
+                if distance_sq == 0.0 {
+                    // Units that are exactly on top of each other (e.g. freshly recruited ones)
+                    // need to be pushed in opposite directions, so pick one based on the units.
+                    let direction = if stacking_order(*entity, position.0, world)
+                        < stacking_order(*other_entity, other_position.0, world)
+                    {
+                        Vec2::new(1.0, 0.0)
+                    } else {
+                        Vec2::new(-1.0, 0.0)
+                    };
+                    pushes.push((
+                        *other_entity,
+                        other_position.0,
+                        direction / desired_seperation,
+                    ));
+                } else if distance_sq < desired_seperation.powi(2) {
                     let distance = distance_sq.sqrt();
 
-                    avoidance_direction += away_vector.normalized() / distance;
-                    count += 1;
+                    pushes.push((
+                        *other_entity,
+                        other_position.0,
+                        away_vector.normalized() / distance,
+                    ));
+                } else if let Some(heading) = heading {
+                    // Start stepping to the side of units that are in the way before we bump
+                    // into them, instead of walking through them.
+                    let towards_other = -away_vector;
+                    let predicted = position.0 + heading * AVOIDANCE_LOOKAHEAD;
+
+                    if towards_other.dot(heading) > 0.0
+                        && (predicted - other_position.0).mag_sq() < desired_seperation.powi(2)
+                    {
+                        let mut sideways = Vec2::new(-heading.y, heading.x);
+                        if sideways.dot(towards_other) > 0.0 {
+                            sideways = -sideways;
+                        }
+
+                        pushes.push((
+                            *other_entity,
+                            other_position.0,
+                            sideways / distance_sq.sqrt(),
+                        ));
+                    }
                 }
             }
 
-            if count > 0 {
-                avoidance_direction /= count as f32;
+            if !pushes.is_empty() {
+                // Pushes from things that tie are the same, so it doesn't matter which goes first.
+                pushes.sort_by_cached_key(|(other_entity, other_position, push)| {
+                    (
+                        stacking_order(*other_entity, *other_position, world),
+                        ordered_float::OrderedFloat(push.x),
+                        ordered_float::OrderedFloat(push.y),
+                    )
+                });
+                let avoidance_direction = pushes
+                    .iter()
+                    .fold(Vec2::new(0.0, 0.0), |sum, (.., push)| sum + *push)
+                    / pushes.len() as f32;
                 command_buffer
                     .lock()
                     .unwrap()
@@ -229,59 +699,105 @@ pub fn avoidance(world: &SubWorld, command_buffer: &mut CommandBuffer) {
         })
 }
 
+// Puts units in the same order on every peer. Entity ids differ between peers, so this goes by
+// `NetworkId`s instead. Things that were only spawned this tick might not have one yet, so those go
+// by position. Two of those stacked on top of each other tie and get pushed the same way, which is
+// still the same on every peer, and they'll have ids by the next tick.
+fn stacking_order(
+    entity: Entity,
+    position: Vec2,
+    world: &SubWorld,
+) -> (
+    Option<u32>,
+    ordered_float::OrderedFloat<f32>,
+    ordered_float::OrderedFloat<f32>,
+) {
+    let network_id = <&NetworkId>::query()
+        .get(world, entity)
+        .ok()
+        .map(|network_id| network_id.0);
+    (
+        network_id,
+        ordered_float::OrderedFloat(position.x),
+        ordered_float::OrderedFloat(position.y),
+    )
+}
+
 #[legion::system(for_each)]
 pub fn apply_steering(
     entity: &Entity,
     position: &mut Position,
     avoidance: &Avoidance,
+    airborne: Option<&Airborne>,
     #[resource] map: &Map,
     command_buffer: &mut CommandBuffer,
     #[resource] delta_time: &DeltaTime,
 ) {
+    // Avoidance is recalculated every tick, so always remove it, even if we can't move.
+    command_buffer.remove_component::<Avoidance>(*entity);
+
     let avoidance_movement_per_second = 6.0;
     let new_position = position.0 + avoidance.0 * avoidance_movement_per_second * delta_time.0;
 
-    // We don't want units to get pushed inside of buildings!
-    if map.impassable_between(position.0, new_position) {
+    // We don't want units to get pushed inside of buildings! Flying units can hover over them.
+    if airborne.is_none() && map.impassable_between(position.0, new_position) {
         return;
     }
 
     position.0 = new_position;
-    command_buffer.remove_component::<Avoidance>(*entity);
 }
 
 #[legion::system(for_each)]
-#[write_component(Position)]
 pub fn move_bullets(
-    entity: &Entity,
-    move_speed: &MoveSpeed,
+    position: &mut Position,
+    max_speed: &MaxSpeed,
     facing: &mut Facing,
-    bullet: &mut Bullet,
+    bullet: &Bullet,
     #[resource] delta_time: &DeltaTime,
-    world: &mut SubWorld,
 ) {
-    if let Ok(target_position) = <&Position>::query().get(world, bullet.target) {
-        bullet.target_position = target_position.0;
-    }
-
-    let bullet_position = <&mut Position>::query().get_mut(world, *entity).unwrap();
-
-    move_towards(
-        &mut bullet_position.0,
+    // Bullets don't home in on their target, they just fly to wherever they were aimed.
+    turn_towards(
         &mut facing.0,
-        bullet.target_position,
-        move_speed.0,
+        bullet.impact - position.0,
+        None,
         delta_time.0,
     );
+    move_towards(&mut position.0, bullet.impact, max_speed.0, delta_time.0);
 }
 
-fn move_towards(pos: &mut Vec2, facing: &mut f32, target: Vec2, speed: f32, delta_time: f32) {
-    let direction = target - *pos;
-    if direction.mag_sq() > 0.0 {
-        let correct_facing = direction.y.atan2(direction.x);
-        *facing = interpolate_rotations(*facing, correct_facing, 1.0 / 3.0);
+// How tight a circle a unit can walk around. Paths get their corners rounded off to this so that
+// units aren't walking off to the side while they turn. Things that can turn instantly don't need
+// any rounding.
+fn turn_radius(max_speed: Option<&MaxSpeed>, turn_rate: Option<&TurnRate>) -> f32 {
+    match (max_speed, turn_rate) {
+        (Some(max_speed), Some(turn_rate)) if turn_rate.0 > 0.0 => max_speed.0 / turn_rate.0,
+        _ => 0.0,
+    }
+}
+
+// Turn to face `direction`, but no faster than `turn_rate` radians per second. Without a turn
+// rate it just eases round quickly, like everything used to.
+fn turn_towards(facing: &mut f32, direction: Vec2, turn_rate: Option<&TurnRate>, delta_time: f32) {
+    if direction.mag_sq() == 0.0 {
+        return;
     }
 
+    let correct_facing = deterministic::angle(direction);
+
+    match turn_rate {
+        Some(turn_rate) => {
+            // Wrap the difference into -PI..PI so that units turn the short way round.
+            let difference = (correct_facing - *facing + PI).rem_euclid(2.0 * PI) - PI;
+            let max_turn = turn_rate.0 * delta_time;
+            *facing += difference.max(-max_turn).min(max_turn);
+        }
+        None => *facing = interpolate_rotations(*facing, correct_facing, 1.0 / 3.0),
+    }
+}
+
+fn move_towards(pos: &mut Vec2, target: Vec2, speed: f32, delta_time: f32) {
+    let direction = target - *pos;
+
     if direction.mag_sq() <= (speed * delta_time).powi(2) {
         *pos = target;
     } else {
@@ -291,7 +807,129 @@ fn move_towards(pos: &mut Vec2, facing: &mut f32, target: Vec2, speed: f32, delt
 
 // Works well enough.
 fn interpolate_rotations(a: f32, b: f32, factor: f32) -> f32 {
-    let cos = mix(a.cos(), b.cos(), factor);
-    let sin = mix(a.sin(), b.sin(), factor);
-    sin.atan2(cos)
+    let cos = mix(deterministic::cos(a), deterministic::cos(b), factor);
+    let sin = mix(deterministic::sin(a), deterministic::sin(b), factor);
+    deterministic::atan2(sin, cos)
+}
+
+#[test]
+fn followers_keep_up_until_the_target_dies() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    resources.insert(Map::new());
+    resources.insert(PathRequests::default());
+    resources.insert(DeltaTime(0.1));
+    let mut schedule = Schedule::builder()
+        .add_system(combat::stop_actions_on_dead_entities_system())
+        .add_system(set_movement_paths_system())
+        .add_system(move_units_system())
+        .build();
+
+    let target = world.push((Position(Vec2::new(0.0, 5.0)),));
+    let follower = world.push((
+        Position(Vec2::new(-20.0, 5.0)),
+        Radius(0.5),
+        MaxSpeed(10.0),
+        Facing(0.0),
+        CommandQueue(vec![Command::new_follow(target)].into()),
+    ));
+    let position = |world: &World| <&Position>::query().get(world, follower).unwrap().0;
+    let queue_len = |world: &World| {
+        <&CommandQueue>::query()
+            .get(world, follower)
+            .unwrap()
+            .0
+            .len()
+    };
+
+    for _ in 0..30 {
+        schedule.execute(&mut world, &mut resources);
+    }
+    // Stopped a little way behind instead of walking into it.
+    assert!((position(&world) - Vec2::new(-3.0, 5.0)).mag() < 0.01);
+    assert_eq!(queue_len(&world), 1);
+
+    // Moving a bit doesn't make it go again, but moving further does.
+    *<&mut Position>::query()
+        .get_mut(&mut world, target)
+        .unwrap() = Position(Vec2::new(2.0, 5.0));
+    schedule.execute(&mut world, &mut resources);
+    assert!((position(&world) - Vec2::new(-3.0, 5.0)).mag() < 0.01);
+
+    *<&mut Position>::query()
+        .get_mut(&mut world, target)
+        .unwrap() = Position(Vec2::new(20.0, 5.0));
+    for _ in 0..30 {
+        schedule.execute(&mut world, &mut resources);
+    }
+    assert!((position(&world) - Vec2::new(17.0, 5.0)).mag() < 0.01);
+
+    world.remove(target);
+    schedule.execute(&mut world, &mut resources);
+    assert_eq!(queue_len(&world), 0);
+}
+
+#[cfg(test)]
+fn solve_requests_in_order(
+    requests: impl Iterator<Item = u32>,
+    end: impl Fn(u32) -> Vec2,
+) -> Vec<u32> {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    resources.insert(Map::new());
+    resources.insert(DebugControls::default());
+    let mut path_requests = PathRequests::default();
+
+    let entities: Vec<_> = (0..50)
+        .map(|i| {
+            world.push((
+                Position(Vec2::new(i as f32 * 2.0 - 20.0, -10.0)),
+                NetworkId(i),
+            ))
+        })
+        .collect();
+
+    for i in requests {
+        path_requests.request(entities[i as usize], end(i), 0.5);
+    }
+    resources.insert(path_requests);
+
+    let mut schedule = Schedule::builder()
+        .add_system(solve_path_requests_system())
+        .build();
+    schedule.execute(&mut world, &mut resources);
+
+    let mut solved: Vec<u32> = <&NetworkId>::query()
+        .filter(component::<Path>())
+        .iter(&world)
+        .map(|network_id| network_id.0)
+        .collect();
+    solved.sort_unstable();
+    solved
+}
+
+#[test]
+fn every_peer_solves_the_same_paths_first() {
+    // Spread out so that they don't get grouped into a flow field.
+    let end = |i: u32| Vec2::new(i as f32 * 2.0 - 20.0, 10.0 + i as f32 * 25.0);
+    let forwards = solve_requests_in_order(0..20, end);
+    let backwards = solve_requests_in_order((0..20).rev(), end);
+
+    assert_eq!(forwards.len(), PATHS_PER_TICK);
+    assert_eq!(forwards, backwards);
+}
+
+#[test]
+fn every_peer_groups_the_same_units_into_a_flow_field() {
+    // The first 30 are in a line, so which of them end up in the group depends on which one it's
+    // formed around. That changes how much of the budget is left for the rest.
+    let end = |i: u32| match i {
+        0..=29 => Vec2::new(i as f32 - 15.0, 10.0),
+        _ => Vec2::new(i as f32 * 2.0 - 60.0, 40.0 + i as f32 * 25.0),
+    };
+    let forwards = solve_requests_in_order(0..50, end);
+    let backwards = solve_requests_in_order((0..50).rev(), end);
+
+    assert!(forwards.len() >= FLOW_FIELD_GROUP_SIZE);
+    assert_eq!(forwards, backwards);
 }
@@ -1,13 +1,14 @@
+use crate::input::{Action, Input};
 use crate::renderer::{Font, LineBuffers, TextAlignment, TextBuffer};
 use crate::resources::{
-    CursorIcon, DpiScaling, GameStats, Keypress, Keypresses, Mode, MouseState, ScreenDimensions,
+    CursorIcon, DpiScaling, GameStats, Keypress, Keypresses, Mode, MouseState, Outcome, PlayerSide,
+    ScreenDimensions, Settings, SideStats, TotalTime,
 };
 use crate::titlescreen::{point_in_area, selected_colour, text_selection_area, TEXT_COLOUR};
 use ultraviolet::Vec2;
-use winit::event::VirtualKeyCode;
 
-const WIN_LOSE_MENU: &'static [(&'static str, Vec2)] =
-    &[("Back to main menu", Vec2::new(0.5, 0.6))];
+const GAME_OVER_MENU: &'static [(&'static str, Vec2)] =
+    &[("Back to main menu", Vec2::new(0.5, 0.8))];
 
 const PLAYING_MENU: &'static [(&'static str, Vec2)] = &[
     ("Continue", Vec2::new(0.5, 0.6)),
@@ -16,7 +17,7 @@ const PLAYING_MENU: &'static [(&'static str, Vec2)] = &[
 
 fn buttons(mode: &Mode) -> Option<&'static [(&'static str, Vec2)]> {
     match mode {
-        Mode::ScenarioWon | Mode::ScenarioLost => Some(WIN_LOSE_MENU),
+        Mode::GameOver(_) => Some(GAME_OVER_MENU),
         Mode::PlayingMenu => Some(PLAYING_MENU),
         _ => None,
     }
@@ -29,11 +30,17 @@ pub fn handle_playing_menu_controls(
     #[resource] mouse_state: &MouseState,
     #[resource] mode: &mut Mode,
     #[resource] keypresses: &mut Keypresses,
+    #[resource] settings: &Settings,
 ) {
     // Allow double-pressing escape to toggle the menu.
     for Keypress { code, pressed, .. } in keypresses.0.drain(..) {
         if let Some(code) = code {
-            if pressed && code == VirtualKeyCode::Escape && *mode == Mode::PlayingMenu {
+            let cancel = settings
+                .input_map
+                .actions(Input::Key(code))
+                .any(|action| action == Action::Cancel);
+
+            if pressed && cancel && *mode == Mode::PlayingMenu {
                 *mode = Mode::Playing;
                 return;
             }
@@ -71,18 +78,21 @@ pub fn render_playing_menu(
     #[resource] mouse_state: &MouseState,
     #[resource] cursor_icon: &mut CursorIcon,
     #[resource] stats: &GameStats,
+    #[resource] player_side: &PlayerSide,
+    #[resource] total_time: &TotalTime,
 ) {
-    let text = match mode {
-        Mode::ScenarioWon => "Scenario Won",
-        Mode::ScenarioLost => "Scenario Lost",
-        Mode::PlayingMenu => "Playing Menu",
+    // The stats screen needs the room, so the title goes higher up.
+    let (text, title_y) = match mode {
+        Mode::GameOver(Outcome::Won) => ("Victory", 0.15),
+        Mode::GameOver(Outcome::Lost) => ("Defeat", 0.15),
+        Mode::PlayingMenu => ("Paused", 0.4),
         _ => return,
     };
 
     let screen_dims = screen_dimensions.as_vec();
 
     text_buffer.render_text(
-        Vec2::new(0.5, 0.4) * screen_dims,
+        Vec2::new(0.5, title_y) * screen_dims,
         text,
         Font::Title,
         1.5,
@@ -117,34 +127,92 @@ pub fn render_playing_menu(
         }
     }
 
-    if matches!(mode, Mode::ScenarioWon | Mode::ScenarioLost) {
-        let items = [
-            format!("Units recruited: {}", stats.units_recruited),
-            format!("Units lost: {}", stats.units_lost),
-            format!("Buildings built: {}", stats.buildings_built),
-            format!("Enemy units killed: {}", stats.enemy_units_killed),
-            format!(
-                "Enemy buildings destoyed: {}",
-                stats.enemy_buildings_destroyed
-            ),
-        ];
-        let mut y = 0.7;
-
-        for text in &items {
+    if let Mode::GameOver(_) = mode {
+        render_stats(
+            text_buffer,
+            screen_dims,
+            dpi_scaling.0,
+            stats.side(player_side.0),
+            stats.side(player_side.0.flip()),
+            total_time.0,
+        );
+    }
+}
+
+// A table of how both sides did, with the player on the left.
+fn render_stats(
+    text_buffer: &mut TextBuffer,
+    screen_dims: Vec2,
+    dpi_scaling: f32,
+    player: &SideStats,
+    enemy: &SideStats,
+    total_time: f32,
+) {
+    let minutes = total_time as u32 / 60;
+    let seconds = total_time as u32 % 60;
+
+    text_buffer.render_text(
+        Vec2::new(0.5, 0.25) * screen_dims,
+        &format!("Time played: {}:{:02}", minutes, seconds),
+        Font::Ui,
+        1.0,
+        dpi_scaling,
+        TextAlignment::Center,
+        TEXT_COLOUR,
+    );
+
+    let rows = [
+        ("", "You".to_string(), "Enemy".to_string()),
+        row("Units recruited", player, enemy, |stats| {
+            stats.units_recruited
+        }),
+        row("Units lost", player, enemy, |stats| stats.units_lost),
+        row("Buildings built", player, enemy, |stats| {
+            stats.buildings_built
+        }),
+        row("Buildings lost", player, enemy, |stats| {
+            stats.buildings_lost
+        }),
+        row("Damage dealt", player, enemy, |stats| {
+            stats.damage_dealt.round() as u32
+        }),
+        row("Cheese gathered", player, enemy, |stats| {
+            stats.cheese_gathered
+        }),
+    ];
+
+    let mut y = 0.35;
+
+    for (label, player, enemy) in &rows {
+        for (x, text, alignment) in vec![
+            (0.45, *label, TextAlignment::HorizontalRight),
+            (0.55, player.as_str(), TextAlignment::Center),
+            (0.7, enemy.as_str(), TextAlignment::Center),
+        ] {
             text_buffer.render_text(
-                Vec2::new(0.5, y) * screen_dims,
+                Vec2::new(x, y) * screen_dims,
                 text,
                 Font::Ui,
                 1.0,
-                dpi_scaling.0,
-                TextAlignment::Center,
+                dpi_scaling,
+                alignment,
                 TEXT_COLOUR,
             );
-            y += 0.05;
         }
+
+        y += 0.05;
     }
 }
 
+fn row(
+    label: &'static str,
+    player: &SideStats,
+    enemy: &SideStats,
+    stat: fn(&SideStats) -> u32,
+) -> (&'static str, String, String) {
+    (label, stat(player).to_string(), stat(enemy).to_string())
+}
+
 #[legion::system]
 pub fn render_playing_menu_click_regions(
     #[resource] line_buffers: &mut LineBuffers,
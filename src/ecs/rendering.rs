@@ -1,25 +1,65 @@
 use super::*;
 use crate::animation::Skin;
 use crate::renderer::{
-    Font, Image, LineBuffers, ModelBuffers, ModelInstance, TextAlignment, TextBuffer, TorusBuffer,
-    TorusInstance,
+    DecalBuffer, DecalKind, Font, Image, LineBuffers, Lines3dBuffer, ModelBuffers, ModelInstance,
+    TextAlignment, TextBuffer, TorusBuffer, TorusInstance,
 };
 use crate::resources::{
-    CheeseCoins, CommandMode, CursorIcon, DpiScaling, Mode, Objectives, RayCastLocation,
-    SelectedUnitsAbilities,
+    CheeseCoins, CommandMode, CursorIcon, DpiScaling, GameStats, Mode, Objectives, RayCastLocation,
+    Settings, TeamColours, TotalTime,
 };
 use ultraviolet::Vec4;
 
-const COLOUR_MAX: Vec3 = Vec3::new(255.0, 255.0, 255.0);
-const GREEN: Vec3 = Vec3::new(43.0, 140.0, 0.0);
-const PURPLE: Vec3 = Vec3::new(196.0, 0.0, 109.0);
-const BLACK: Vec4 = Vec4::new(0.0, 0.0, 0.0, 1.0);
+pub(super) const BLACK: Vec4 = Vec4::new(0.0, 0.0, 0.0, 1.0);
 const WHITE: Vec3 = Vec3::new(1.0, 1.0, 1.0);
+// How much bigger than the unit its outline is.
+const OUTLINE_SCALE: f32 = 1.1;
+// How far from the camera units switch to each of the simplified levels of detail.
+const UNIT_LOD_DISTANCES: [f32; 2] = [45.0, 65.0];
 
 fn mix(colour_a: Vec3, colour_b: Vec3, factor: f32) -> Vec3 {
     colour_a * (1.0 - factor) + colour_b * factor
 }
 
+// See-through things need drawing furthest first so that the ones in front blend over the ones
+// behind. This sorts by how far along the camera's view direction each point is, rather than the
+// straight-line distance, so that things side by side on the screen don't swap order as the camera
+// pans.
+fn sort_back_to_front<T>(items: &mut [T], camera: &Camera, position: impl Fn(&T) -> Vec3) {
+    let view = camera.to_matrix();
+    // Looking down -z in view space.
+    let depth = |item: &T| -view.transform_point3(position(item)).z;
+
+    items.sort_by(|a, b| {
+        depth(b)
+            .partial_cmp(&depth(a))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+}
+
+// Lays a decal down on the ground, tilted to match the slope. `size` is how wide it is across.
+pub(super) fn decal_instance(
+    terrain: &Terrain,
+    position: Vec2,
+    size: f32,
+    rotation: f32,
+    colour: Vec4,
+) -> ModelInstance {
+    let tilt =
+        ultraviolet::Rotor3::from_rotation_between(Vec3::unit_y(), terrain.normal_at(position))
+            .into_matrix()
+            .into_homogeneous();
+
+    ModelInstance {
+        // Lifted up a touch so that it doesn't z-fight with the ground.
+        transform: Mat4::from_translation(terrain.ground(position) + Vec3::new(0.0, 0.03, 0.0))
+            * tilt
+            * Mat4::from_rotation_y(rotation)
+            * Mat4::from_scale(size),
+        flat_colour: colour,
+    }
+}
+
 #[legion::system]
 #[read_component(Position)]
 pub fn render_building_plan(
@@ -27,31 +67,71 @@ pub fn render_building_plan(
     #[resource] rts_controls: &RtsControls,
     #[resource] cheese_coins: &CheeseCoins,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] map: &Map,
+    #[resource] terrain: &Terrain,
+    #[resource] mouse_state: &MouseState,
+    #[resource] camera: &Camera,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
     world: &SubWorld,
 ) {
     let allowed = Vec4::new(0.0, 1.0, 0.0, 0.25);
     let not_allowed = Vec4::new(1.0, 0.25, 0.0, 1.0 / 2.5);
     let cant_afford = Vec4::new(1.0, 0.0, 0.0, 1.0 / 3.0);
 
-    if let CommandMode::Construct { building } = rts_controls.mode {
+    if let CommandMode::Construct {
+        building: Building::Wall,
+    } = rts_controls.mode
+    {
+        model_buffers.building_plan.clear();
+
+        let segments = walls::planned_wall(
+            mouse_state,
+            camera,
+            terrain,
+            screen_dimensions,
+            dpi_scaling,
+            ray_cast_location,
+        );
+
+        let colour = if Building::Wall.stats().cost * segments.len() as u32 > cheese_coins.0 {
+            cant_afford
+        } else if !walls::can_place_wall(&segments, map, world) {
+            not_allowed
+        } else {
+            allowed
+        };
+
+        for segment in segments {
+            model_buffers.wall_plan.push(ModelInstance {
+                transform: walls::segment_transform(terrain.ground(segment), Building::Wall, None),
+                flat_colour: colour,
+            });
+        }
+    } else if let CommandMode::Construct { building } = rts_controls.mode {
         let colour = if building.stats().cost > cheese_coins.0 {
             cant_afford
         } else if (building == Building::Pump && ray_cast_location.snapped_to_guyser.is_none())
             || unit_under_building(ray_cast_location.pos, building.stats().dimensions, world)
+            || !map.can_insert(ray_cast_location.pos, building.stats().dimensions)
         {
             not_allowed
         } else {
             allowed
         };
 
+        let ground = terrain.ground(ray_cast_location.pos);
+
         model_buffers.building_plan.set(
             building,
             ModelInstance {
-                transform: Mat4::from_translation(Vec3::new(
-                    ray_cast_location.pos.x,
-                    0.0,
-                    ray_cast_location.pos.y,
-                )),
+                transform: match building {
+                    Building::Gate => walls::segment_transform(ground, building, None),
+                    Building::Silo => {
+                        Mat4::from_translation(ground) * superweapon::model_transform()
+                    }
+                    _ => Mat4::from_translation(ground),
+                },
                 flat_colour: colour,
             },
         );
@@ -60,62 +140,327 @@ pub fn render_building_plan(
     }
 }
 
+// Show how much of an area an ability is going to hit before using it.
+#[legion::system]
+pub fn render_ability_targeting(
+    #[resource] ray_cast_location: &RayCastLocation,
+    #[resource] rts_controls: &RtsControls,
+    #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] terrain: &Terrain,
+) {
+    if let CommandMode::Cast { ability } = rts_controls.mode {
+        if let Targeting::Area(radius) = ability.stats().targeting {
+            torus_buffer.toruses.push(TorusInstance {
+                center: terrain.ground(ray_cast_location.pos),
+                colour: Vec3::new(1.0, 0.5, 0.1),
+                radius,
+            });
+        }
+    }
+
+    if rts_controls.mode == CommandMode::TargetStrike {
+        torus_buffer.toruses.push(TorusInstance {
+            center: terrain.ground(ray_cast_location.pos),
+            colour: STRIKE_COLOUR,
+            radius: superweapon::STRIKE_RADIUS,
+        });
+    }
+
+    if rts_controls.mode == CommandMode::Guard {
+        torus_buffer.toruses.push(TorusInstance {
+            center: terrain.ground(ray_cast_location.pos),
+            colour: GUARD_COLOUR,
+            radius: combat::GUARD_RADIUS,
+        });
+    }
+}
+
+const GUARD_COLOUR: Vec3 = Vec3::new(1.0, 0.5, 0.0);
+
+const STRIKE_COLOUR: Vec3 = Vec3::new(1.0, 0.1, 0.1);
+
+// Both players can see where a strike is going to land, even through the fog of war. The ring
+// closes in as it gets closer.
 #[legion::system(for_each)]
+pub fn render_incoming_strikes(
+    strike: &Strike,
+    #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] terrain: &Terrain,
+) {
+    let center = terrain.ground(strike.target);
+    let closing = strike.remaining / superweapon::STRIKE_DELAY;
+
+    torus_buffer.toruses.push(TorusInstance {
+        center,
+        colour: STRIKE_COLOUR,
+        radius: superweapon::STRIKE_RADIUS,
+    });
+    torus_buffer.toruses.push(TorusInstance {
+        center,
+        colour: STRIKE_COLOUR,
+        radius: superweapon::STRIKE_RADIUS * closing,
+    });
+}
+
+// A unit that's been through fog of war and level of detail checks, and is going to be drawn.
+struct VisibleUnit<'a> {
+    position: Vec2,
+    ground: Vec3,
+    side: Side,
+    facing: f32,
+    radius: f32,
+    model: UnitModel,
+    selected: bool,
+    lod: usize,
+    animation_state: &'a AnimationState,
+    skin: &'a mut Skin,
+}
+
+// Posing skins is most of the work of drawing a big army, so all the units are posed in one go
+// (spread across threads, see `animation::pose_all`) before the buffers are filled in order.
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Facing)]
+#[read_component(Radius)]
+#[read_component(AnimationState)]
+#[read_component(Unit)]
+#[read_component(Selected)]
+#[read_component(Cloaked)]
+#[read_component(Burrow)]
+#[write_component(Skin)]
 pub fn render_units(
-    position: &Position,
-    side: &Side,
-    facing: &Facing,
-    skin: &Skin,
-    unit: &Unit,
+    world: &mut SubWorld,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] animations: &ModelAnimations,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] team_colours: &TeamColours,
+    #[resource] terrain: &Terrain,
+    #[resource] ray_cast_location: &RayCastLocation,
+    #[resource] camera: &Camera,
+    #[resource] settings: &Settings,
 ) {
-    let translation = Mat4::from_translation(Vec3::new(position.0.x, 0.0, position.0.y));
-    let rotation = Mat4::from_rotation_y(facing.0);
-
-    let (instance_buffer, joint_buffer) = match unit {
-        Unit::MouseMarine => (
-            &mut model_buffers.mice_marines,
-            &mut model_buffers.mice_marines_joints,
-        ),
-        Unit::Engineer => (
-            &mut model_buffers.mice_engineers,
-            &mut model_buffers.mice_engineers_joints,
-        ),
-    };
+    let mut units: Vec<VisibleUnit> = <(
+        &Position,
+        &Side,
+        &Facing,
+        &Radius,
+        &mut Skin,
+        &AnimationState,
+        &Unit,
+        Option<&Selected>,
+        Option<&Cloaked>,
+        Option<&Burrow>,
+    )>::query()
+    .iter_mut(world)
+    .filter(|(position, side, .., cloaked, _)| {
+        fog_of_war.can_see(player_side.0, **side, position.0)
+            && !stealth::is_hidden(*cloaked, player_side.0, **side)
+    })
+    .map(
+        |(position, side, facing, radius, skin, animation_state, unit, selected, _, burrow)| {
+            let depth = burrow.map_or(0.0, |burrow| burrow.depth());
+            let ground = terrain.ground(position.0)
+                + Vec3::unit_y() * (airborne::height(*unit) - depth * burrowing::BURROW_DEPTH);
+
+            // Far away units are too small for anyone to notice that they're not animated (or
+            // outlined), and skipping the skinning for them makes a big difference when zoomed out
+            // over a large army.
+            let lod = if settings.unit_lod {
+                UNIT_LOD_DISTANCES
+                    .iter()
+                    .filter(|distance| (camera.position() - ground).mag() > **distance)
+                    .count()
+            } else {
+                0
+            };
 
-    instance_buffer.push(ModelInstance {
-        transform: translation * rotation,
-        flat_colour: {
-            let colour = match side {
-                Side::Green => GREEN,
-                Side::Purple => PURPLE,
-            } / COLOUR_MAX;
-            let colour = mix(colour, WHITE, 0.25);
-
-            Vec4::new(colour.x, colour.y, colour.z, 0.2)
+            VisibleUnit {
+                position: position.0,
+                ground,
+                side: *side,
+                facing: facing.0,
+                radius: radius.0,
+                model: unit.stats().model,
+                selected: selected.is_some(),
+                lod,
+                animation_state,
+                skin,
+            }
         },
-    });
-    for joint in &skin.joints {
-        joint_buffer.push(joint.matrix);
+    )
+    .collect();
+
+    // The helmets are drawn with the same instances as the mice, so they need to be in order too.
+    sort_back_to_front(&mut units, camera, |unit| unit.ground);
+
+    #[cfg(not(feature = "gpu-skinning"))]
+    {
+        let mut skins: Vec<_> = units
+            .iter_mut()
+            .filter(|unit| unit.lod == 0)
+            .map(|unit| (unit.animation_state, &mut *unit.skin))
+            .collect();
+        crate::animation::pose_all(&mut skins, &animations.mouse.animations);
+    }
+
+    for unit in &units {
+        let translation = Mat4::from_translation(unit.ground);
+        let rotation = Mat4::from_rotation_y(unit.facing);
+        let team_colour = team_colours.get(unit.side);
+
+        if unit.lod > 0 {
+            let distant_buffers = match unit.model {
+                UnitModel::MouseMarine => &mut model_buffers.distant_mice_marines,
+                UnitModel::Engineer => &mut model_buffers.distant_mice_engineers,
+            };
+
+            distant_buffers[unit.lod - 1].push(ModelInstance {
+                transform: translation * rotation,
+                flat_colour: Vec4::new(team_colour.x, team_colour.y, team_colour.z, 1.0),
+            });
+            continue;
+        }
+
+        let (instance_buffer, joint_buffer) = match unit.model {
+            UnitModel::MouseMarine => (
+                &mut model_buffers.mice_marines,
+                &mut model_buffers.mice_marines_joints,
+            ),
+            UnitModel::Engineer => (
+                &mut model_buffers.mice_engineers,
+                &mut model_buffers.mice_engineers_joints,
+            ),
+        };
+
+        instance_buffer.push(ModelInstance {
+            transform: translation * rotation,
+            flat_colour: {
+                let colour = mix(team_colour, WHITE, 0.25);
+                Vec4::new(colour.x, colour.y, colour.z, 0.2)
+            },
+        });
+        joint_buffer.push_posed(
+            unit.animation_state,
+            &animations.mouse.animations,
+            unit.skin,
+        );
+
+        // Selected units are outlined in their side's colour and the one under the cursor in white.
+        let hovered = (ray_cast_location.pos - unit.position).mag_sq() < unit.radius.powi(2);
+        let outline_colour = match (unit.selected, hovered) {
+            (true, true) => mix(team_colour, WHITE, 0.5),
+            (true, false) => team_colour,
+            (false, true) => WHITE,
+            (false, false) => continue,
+        };
+
+        model_buffers.mice_outline_masks.push(ModelInstance {
+            transform: translation * rotation,
+            flat_colour: Vec4::one(),
+        });
+        model_buffers.mice_outlines.push(ModelInstance {
+            transform: translation * rotation * Mat4::from_scale(OUTLINE_SCALE),
+            flat_colour: Vec4::new(outline_colour.x, outline_colour.y, outline_colour.z, 1.0),
+        });
+        model_buffers.mice_outlines_joints.push_posed(
+            unit.animation_state,
+            &animations.mouse.animations,
+            unit.skin,
+        );
+    }
+}
+
+// Corpses are drawn as flat, see-through silhouettes in their side's colour, toppling over and
+// sinking into the ground as they fade.
+#[legion::system]
+#[read_component(Corpse)]
+#[read_component(AnimationState)]
+#[write_component(Skin)]
+pub fn render_corpses(
+    world: &mut SubWorld,
+    #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] animations: &ModelAnimations,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] team_colours: &TeamColours,
+    #[resource] terrain: &Terrain,
+    #[resource] camera: &Camera,
+) {
+    let mut corpses: Vec<_> = <(&Corpse, &mut Skin, &AnimationState)>::query()
+        .iter_mut(world)
+        .filter(|(corpse, ..)| fog_of_war.can_see(player_side.0, corpse.side, corpse.position))
+        .map(|(corpse, skin, animation_state)| {
+            let ground = terrain.ground(corpse.position);
+            (ground, corpse, skin, animation_state)
+        })
+        .collect();
+
+    sort_back_to_front(&mut corpses, camera, |&(ground, ..)| ground);
+
+    for (ground, corpse, skin, animation_state) in corpses {
+        let faded = corpse.faded();
+        let translation = Mat4::from_translation(ground - Vec3::unit_y() * faded * 0.5);
+        let rotation = Mat4::from_rotation_y(corpse.facing)
+            * Mat4::from_rotation_x(corpse.fallen() * std::f32::consts::FRAC_PI_2);
+
+        let colour = team_colours.get(corpse.side);
+        let colour = mix(colour, BLACK.xyz(), 0.5);
+
+        model_buffers.mice_corpses.push(ModelInstance {
+            transform: translation * rotation,
+            flat_colour: Vec4::new(colour.x, colour.y, colour.z, 0.6 * (1.0 - faded)),
+        });
+        model_buffers
+            .mice_corpses_joints
+            .push(animation_state, &animations.mouse.animations, skin);
     }
 }
 
+// Units get a ring on the ground as well as their outline (see `render_units`), while buildings
+// are too big and boxy for a flat ring so they get a torus instead.
 #[legion::system(for_each)]
 #[filter(component::<Selected>())]
 pub fn render_selections(
     position: &Position,
     side: &Side,
     radius: &Radius,
+    unit: Option<&Unit>,
+    cloaked: Option<&Cloaked>,
     #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] decal_buffer: &mut DecalBuffer,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] team_colours: &TeamColours,
+    #[resource] terrain: &Terrain,
 ) {
-    torus_buffer.toruses.push(TorusInstance {
-        center: Vec3::new(position.0.x, 0.0, position.0.y),
-        colour: match side {
-            Side::Green => GREEN / COLOUR_MAX,
-            Side::Purple => PURPLE / COLOUR_MAX,
-        },
-        radius: radius.0,
-    });
+    if !fog_of_war.can_see(player_side.0, *side, position.0)
+        || stealth::is_hidden(cloaked, player_side.0, *side)
+    {
+        return;
+    }
+
+    let colour = team_colours.get(*side);
+
+    if unit.is_some() {
+        decal_buffer.push(
+            DecalKind::SelectionRing,
+            decal_instance(
+                terrain,
+                position.0,
+                radius.0 * 2.5,
+                0.0,
+                Vec4::new(colour.x, colour.y, colour.z, 0.8),
+            ),
+        );
+    } else {
+        torus_buffer.toruses.push(TorusInstance {
+            center: terrain.ground(position.0),
+            colour,
+            radius: radius.0,
+        });
+    }
 }
 
 #[legion::system]
@@ -129,13 +474,26 @@ pub fn render_under_select_box(
     #[resource] player_side: &PlayerSide,
     #[resource] torus_buffer: &mut TorusBuffer,
     #[resource] mode: &Mode,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] terrain: &Terrain,
+    #[resource] rts_controls: &RtsControls,
     world: &SubWorld,
 ) {
-    if *mode != Mode::Playing {
+    // Dragging while placing walls lays them out instead of selecting, see `render_building_plan`.
+    let placing_wall = rts_controls.mode
+        == CommandMode::Construct {
+            building: Building::Wall,
+        };
+
+    if *mode != Mode::Playing || placing_wall {
         return;
     }
 
-    if let Some(start) = mouse_state.left_state.is_being_dragged() {
+    let minimap_area = MinimapArea::new(screen_dimensions, dpi_scaling);
+
+    if let Some(start) = mouse_state.left_state.is_being_dragged().filter(|start| {
+        !minimap_area.contains(*start) && !hud_contains(*start, screen_dimensions, dpi_scaling)
+    }) {
         let select_box = SelectBox::new(camera, screen_dimensions, start, mouse_state.position);
 
         <(&Position, &Radius, &Side)>::query()
@@ -145,7 +503,7 @@ pub fn render_under_select_box(
             .filter(|(position, ..)| select_box.contains(position.0))
             .for_each(|(position, radius, _)| {
                 torus_buffer.toruses.push(TorusInstance {
-                    center: Vec3::new(position.0.x, 0.0, position.0.y),
+                    center: terrain.ground(position.0),
                     colour: WHITE,
                     radius: radius.0,
                 });
@@ -157,17 +515,35 @@ pub fn render_under_select_box(
 pub fn render_health_bars(
     position: &Position,
     health: &Health,
+    side: &Side,
     unit: Option<&Unit>,
     building: Option<&Building>,
+    selected: Option<&Selected>,
+    experience: Option<&Experience>,
+    cloaked: Option<&Cloaked>,
     #[resource] camera: &Camera,
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] dpi_scaling: &DpiScaling,
     #[resource] line_buffers: &mut LineBuffers,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] team_colours: &TeamColours,
+    #[resource] terrain: &Terrain,
 ) {
+    if !fog_of_war.can_see(player_side.0, *side, position.0)
+        || stealth::is_hidden(cloaked, player_side.0, *side)
+    {
+        return;
+    }
+
     let stats = {
         let unit_stats = unit.map(|unit| {
             let stats = unit.stats();
-            (stats.max_health, stats.health_bar_height)
+            let multiplier = experience.map(|experience| experience.multiplier());
+            (
+                stats.max_health * multiplier.unwrap_or(1.0),
+                stats.health_bar_height + airborne::height(*unit),
+            )
         });
         let building_stats = building.map(|building| {
             let stats = building.stats();
@@ -177,23 +553,37 @@ pub fn render_health_bars(
     };
 
     if let Some((max_health, health_bar_height)) = stats {
-        if health.0 != max_health {
-            let floating = Vec3::new(position.0.x, health_bar_height, position.0.y);
+        if health.0 != max_health || selected.is_some() {
+            let floating = terrain.ground(position.0) + Vec3::new(0.0, health_bar_height, 0.0);
             let location = screen_location(floating, camera, screen_dimensions);
 
+            // Shrink the bars as the camera zooms out (and grow them as it zooms in) so that they
+            // stay roughly in proportion to the units.
+            let zoom_scale = (Camera::ANGLE.mag() / camera.distance).clamp(0.5, 1.5);
+
             let health_percentage = health.0 as f32 / max_health as f32;
-            let length = 60.0 * health_percentage;
+            let length = 60.0 * health_percentage * zoom_scale;
+            let height = 10.0 * zoom_scale;
+
+            let outline = team_colours.get(*side);
+
+            line_buffers.draw_filled_rect(
+                location,
+                Vec2::new(length + 4.0, height + 4.0),
+                Vec4::new(outline.x, outline.y, outline.z, 1.0),
+                dpi_scaling.0,
+            );
 
             line_buffers.draw_filled_rect(
                 location,
-                Vec2::new(length + 2.0, 12.0),
+                Vec2::new(length + 2.0, height + 2.0),
                 BLACK,
                 dpi_scaling.0,
             );
 
             line_buffers.draw_filled_rect(
                 location,
-                Vec2::new(length, 10.0),
+                Vec2::new(length, height),
                 Vec4::new(1.0 - health_percentage, health_percentage, 0.0, 1.0),
                 dpi_scaling.0,
             );
@@ -201,6 +591,59 @@ pub fn render_health_bars(
     }
 }
 
+// Gold chevrons above the health bars of units that have been promoted, one per rank.
+#[legion::system(for_each)]
+pub fn render_rank_chevrons(
+    position: &Position,
+    side: &Side,
+    unit: &Unit,
+    experience: &Experience,
+    cloaked: Option<&Cloaked>,
+    #[resource] camera: &Camera,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] line_buffers: &mut LineBuffers,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] terrain: &Terrain,
+) {
+    if experience.rank() == 0
+        || !fog_of_war.can_see(player_side.0, *side, position.0)
+        || stealth::is_hidden(cloaked, player_side.0, *side)
+    {
+        return;
+    }
+
+    let height = unit.stats().health_bar_height + airborne::height(*unit);
+    let floating = terrain.ground(position.0) + Vec3::new(0.0, height, 0.0);
+    let location = screen_location(floating, camera, screen_dimensions);
+    let zoom_scale = (Camera::ANGLE.mag() / camera.distance).clamp(0.5, 1.5);
+
+    let width = 12.0 * zoom_scale;
+    let height = 5.0 * zoom_scale;
+    let thickness = 3.0 * zoom_scale;
+    let gold = Vec4::new(1.0, 0.8, 0.2, 1.0);
+
+    for i in 0..experience.rank() {
+        // Just above the health bar.
+        let offset = 10.0 * zoom_scale + i as f32 * (thickness + 2.0 * zoom_scale);
+        let tip = location - Vec2::new(0.0, offset);
+
+        line_buffers.draw_polygon_outline(
+            &[
+                tip + Vec2::new(-width / 2.0, -height),
+                tip,
+                tip + Vec2::new(width / 2.0, -height),
+                tip + Vec2::new(width / 2.0, -height - thickness),
+                tip - Vec2::new(0.0, thickness),
+                tip + Vec2::new(-width / 2.0, -height - thickness),
+            ],
+            gold,
+            dpi_scaling.0,
+        );
+    }
+}
+
 fn screen_location(position: Vec3, camera: &Camera, screen_dimensions: &ScreenDimensions) -> Vec2 {
     let &ScreenDimensions { width, height } = screen_dimensions;
     let view = camera.to_matrix();
@@ -219,6 +662,11 @@ fn wgpu_to_screen(wgpu: Vec2, width: f32, height: f32) -> Vec2 {
 #[read_component(Side)]
 #[read_component(Building)]
 #[read_component(FullyBuilt)]
+#[read_component(Stance)]
+#[read_component(Research)]
+#[read_component(Superweapon)]
+#[read_component(Selected)]
+#[read_component(ScriptTag)]
 pub fn render_ui(
     #[resource] dpi_scaling: &DpiScaling,
     #[resource] cheese_coins: &CheeseCoins,
@@ -228,6 +676,9 @@ pub fn render_ui(
     #[resource] mode: &Mode,
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] line_buffers: &mut LineBuffers,
+    #[resource] rts_controls: &RtsControls,
+    #[resource] stats: &GameStats,
+    #[resource] total_time: &TotalTime,
     world: &SubWorld,
 ) {
     let blue = Vec4::new(0.091, 0.118, 0.543, 1.0);
@@ -238,12 +689,10 @@ pub fn render_ui(
     }
 
     let objectives = std::iter::once(format!("Objectives:\n"))
-        .chain(
-            objectives
-                .win_conditions
-                .iter()
-                .map(|cond| format!("{}\n", cond)),
-        )
+        .chain(objectives.win_conditions.iter().map(|cond| {
+            let met = controls::win_condition_met(cond, player_side, stats, total_time, world);
+            format!("[{}] {}\n", if met { "x" } else { " " }, cond)
+        }))
         .chain(
             objectives
                 .lose_conditions
@@ -266,6 +715,7 @@ pub fn render_ui(
     );
 
     let dims = screen_dimensions.as_vec();
+    let minimap_area = MinimapArea::new(screen_dimensions, dpi_scaling);
 
     text_buffer.render_text(
         Vec2::new(dims.x - 32.0 * dpi, y_offset * dpi),
@@ -285,6 +735,100 @@ pub fn render_ui(
         dpi_scaling.0,
     );
 
+    text_buffer.render_text(
+        Vec2::new(10.0 * dpi, minimap_area.top() - 16.0 * dpi),
+        &format!("Formation: {}", rts_controls.formation),
+        Font::Ui,
+        1.0,
+        dpi_scaling.0,
+        TextAlignment::CenterLeft,
+        blue,
+    );
+
+    let selected_stance = <(&Stance, &Side)>::query()
+        .filter(component::<Selected>())
+        .iter(world)
+        .find(|(_, side)| **side == player_side.0)
+        .map(|(stance, _)| *stance);
+
+    if let Some(stance) = selected_stance {
+        text_buffer.render_text(
+            Vec2::new(10.0 * dpi, minimap_area.top() - 40.0 * dpi),
+            &format!("Stance: {:?}", stance),
+            Font::Ui,
+            1.0,
+            dpi_scaling.0,
+            TextAlignment::CenterLeft,
+            blue,
+        );
+    }
+
+    let mut research: Vec<_> = <(&Research, &Side)>::query()
+        .iter(world)
+        .filter(|(_, side)| **side == player_side.0)
+        .filter_map(|(research, _)| research.current.map(|upgrade| (upgrade, research.progress)))
+        .collect();
+
+    research.sort_unstable_by_key(|(upgrade, _)| *upgrade);
+
+    // Stacked up above the stance.
+    for (i, (upgrade, progress)) in research.into_iter().enumerate() {
+        text_buffer.render_text(
+            Vec2::new(
+                10.0 * dpi,
+                minimap_area.top() - (64.0 + i as f32 * 24.0) * dpi,
+            ),
+            &format!(
+                "Researching {}: {}%",
+                upgrade.stats().label,
+                (progress * 100.0) as u32
+            ),
+            Font::Ui,
+            1.0,
+            dpi_scaling.0,
+            TextAlignment::CenterLeft,
+            blue,
+        );
+    }
+
+    // Both players can see how long it is until each silo is ready, to give the other player a
+    // chance to do something about it.
+    let mut silos: Vec<_> = <(&Superweapon, &Side)>::query()
+        .filter(component::<FullyBuilt>())
+        .iter(world)
+        .map(|(superweapon, side)| (*side != player_side.0, superweapon.remaining))
+        .collect();
+
+    silos.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+    for (i, (enemy, remaining)) in silos.into_iter().enumerate() {
+        let whose = if enemy {
+            "Enemy superweapon"
+        } else {
+            "Superweapon"
+        };
+        let text = if remaining == 0.0 {
+            format!("{} ready", whose)
+        } else {
+            let seconds = remaining.ceil() as u32;
+            format!("{}: {}:{:02}", whose, seconds / 60, seconds % 60)
+        };
+
+        text_buffer.render_text(
+            Vec2::new(dims.x / 2.0, (y_offset + 12.0 + i as f32 * 24.0) * dpi),
+            &text,
+            Font::Ui,
+            1.0,
+            dpi_scaling.0,
+            TextAlignment::Center,
+            if enemy {
+                Vec4::new(0.8, 0.1, 0.1, 1.0)
+            } else {
+                blue
+            },
+        );
+    }
+
     // Recruitment queue rendering
 
     let max_queues_we_can_fit_on_a_1080p_monitor = 28;
@@ -370,35 +914,63 @@ pub fn render_ui(
         });
 }
 
+// Rally points on guysers and units are coloured differently from ones on the ground, so that it's
+// clear that new recruits will go and do something there.
 #[legion::system(for_each)]
-#[filter(component::<Selected>())]
+#[filter(component::<Selected>() & component::<Position>())]
+#[read_component(Position)]
 pub fn render_recruitment_waypoints(
-    position: &Position,
+    entity: &Entity,
     recruitment_queue: &RecruitmentQueue,
     side: &Side,
+    world: &SubWorld,
     #[resource] player_side: &PlayerSide,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] decal_buffer: &mut DecalBuffer,
+    #[resource] terrain: &Terrain,
 ) {
     if *side != player_side.0 {
         return;
     }
 
-    let colour = Vec4::new(0.125, 0.5, 0.125, 1.0);
+    let position = <&Position>::query()
+        .get(world, *entity)
+        .expect("We've applied a filter to this system for Position");
 
-    let waypoint = recruitment_queue.waypoint;
+    let colour = match recruitment_queue.waypoint {
+        RallyPoint::Position(_) => Vec4::new(0.125, 0.5, 0.125, 1.0),
+        RallyPoint::Guyser(_) => Vec4::new(0.8, 0.65, 0.1, 1.0),
+        RallyPoint::Unit(_) => Vec4::new(0.2, 0.45, 0.8, 1.0),
+    };
 
-    model_buffers.command_indicators.push(ModelInstance {
-        transform: Mat4::from_translation(Vec3::new(waypoint.x, 0.02, waypoint.y)),
-        flat_colour: colour,
-    });
+    // Whatever it was on has gone.
+    let waypoint = match recruitment_queue.waypoint.position(world) {
+        Some(waypoint) => waypoint,
+        None => return,
+    };
 
-    let center = (waypoint + position.0) / 2.0;
-    let vector = waypoint - position.0;
+    decal_buffer.push(
+        DecalKind::RallyPoint,
+        decal_instance(terrain, waypoint, 1.5, 0.0, colour),
+    );
+
+    push_command_path(model_buffers, terrain, position.0, waypoint, colour);
+}
+
+fn push_command_path(
+    model_buffers: &mut ModelBuffers,
+    terrain: &Terrain,
+    from: Vec2,
+    to: Vec2,
+    colour: Vec4,
+) {
+    let center = (from + to) / 2.0;
+    let vector = to - from;
     let rotation = vector.y.atan2(vector.x);
     let scale = vector.mag();
 
     model_buffers.command_paths.push(ModelInstance {
-        transform: Mat4::from_translation(Vec3::new(center.x, 0.01, center.y))
+        transform: Mat4::from_translation(terrain.ground(center) + Vec3::new(0.0, 0.01, 0.0))
             * Mat4::from_rotation_y(rotation)
             * Mat4::from_nonuniform_scale(Vec3::new(scale, 1.0, 1.0)),
         flat_colour: colour,
@@ -413,7 +985,10 @@ pub fn render_command_paths(
     entity: &Entity,
     side: &Side,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] decal_buffer: &mut DecalBuffer,
+    #[resource] torus_buffer: &mut TorusBuffer,
     #[resource] player_side: &PlayerSide,
+    #[resource] terrain: &Terrain,
     world: &SubWorld,
 ) {
     let position = <&Position>::query()
@@ -448,14 +1023,30 @@ pub fn render_command_paths(
                     .0;
                 Some(position)
             }
+            // Followers stop to fight, and whoever they're following could die in the meantime.
+            Command::Follow { target, .. } => <&Position>::query()
+                .get(world, *target)
+                .ok()
+                .map(|position| position.0),
+            Command::Patrol { b, .. } => Some(*b),
+            Command::Guard { center, .. } => Some(*center),
+            Command::Cast {
+                target: AbilityTarget::Caster,
+                ..
+            } => None,
+            Command::Cast { target, .. } => target.position(prev, world),
             Command::Attack {
                 explicit: false, ..
-            } => None,
+            }
+            | Command::HoldPosition => None,
         };
 
         let move_colour = Vec4::new(0.25, 0.25, 1.0, 1.0);
         let attack_colour = Vec4::new(1.0, 0.0, 0.0, 1.0);
         let build_colour = Vec4::new(0.25, 1.0, 0.25, 1.0);
+        let patrol_colour = Vec4::new(1.0, 1.0, 0.25, 1.0);
+        let cast_colour = Vec4::new(0.25, 1.0, 1.0, 1.0);
+        let guard_colour = Vec4::new(GUARD_COLOUR.x, GUARD_COLOUR.y, GUARD_COLOUR.z, 1.0);
 
         let colour = match command {
             Command::MoveTo { attack_move, .. } => {
@@ -465,29 +1056,64 @@ pub fn render_command_paths(
                     move_colour
                 }
             }
-            Command::Attack { .. } => attack_colour,
+            Command::Attack { .. } | Command::HoldPosition => attack_colour,
             Command::Build { .. } => build_colour,
+            Command::Patrol { .. } => patrol_colour,
+            Command::Follow { .. } => move_colour,
+            Command::Guard { .. } => guard_colour,
+            Command::Cast { .. } => cast_colour,
+        };
+
+        let is_attack = match command {
+            Command::MoveTo { attack_move, .. } => *attack_move,
+            Command::Attack { .. } => true,
+            _ => false,
         };
 
         if let Some(position) = position {
-            model_buffers.command_indicators.push(ModelInstance {
-                transform: Mat4::from_translation(Vec3::new(position.x, 0.02, position.y)),
-                flat_colour: colour,
-            });
+            if is_attack {
+                decal_buffer.push(
+                    DecalKind::AttackFlare,
+                    decal_instance(terrain, position, 1.5, 0.0, colour),
+                );
+            } else {
+                model_buffers.command_indicators.push(ModelInstance {
+                    transform: Mat4::from_translation(
+                        terrain.ground(position) + Vec3::new(0.0, 0.02, 0.0),
+                    ),
+                    flat_colour: colour,
+                });
+            }
 
-            let center = (prev + position) / 2.0;
-            let vector = position - prev;
-            let rotation = vector.y.atan2(vector.x);
-            let scale = vector.mag();
+            // Follow the path the unit is actually going to take for any leg that's been
+            // pathfound already (queued legs only get their path when they're started).
+            let path = command.path().map(|path| path.as_slice()).unwrap_or(&[]);
+
+            for &point in path.iter().chain(std::iter::once(&position)) {
+                if point != prev {
+                    push_command_path(model_buffers, terrain, prev, point, colour);
+                    prev = point;
+                }
+            }
+        }
+
+        if let Command::Guard { center, radius, .. } = command {
+            torus_buffer.toruses.push(TorusInstance {
+                center: terrain.ground(*center),
+                colour: GUARD_COLOUR,
+                radius: *radius,
+            });
+        }
 
-            model_buffers.command_paths.push(ModelInstance {
-                transform: Mat4::from_translation(Vec3::new(center.x, 0.01, center.y))
-                    * Mat4::from_rotation_y(rotation)
-                    * Mat4::from_nonuniform_scale(Vec3::new(scale, 1.0, 1.0)),
+        // Patrols loop back to where they started from.
+        if let Command::Patrol { a, .. } = command {
+            model_buffers.command_indicators.push(ModelInstance {
+                transform: Mat4::from_translation(terrain.ground(*a) + Vec3::new(0.0, 0.02, 0.0)),
                 flat_colour: colour,
             });
 
-            prev = position;
+            push_command_path(model_buffers, terrain, prev, *a, colour);
+            prev = *a;
         }
     }
 }
@@ -497,168 +1123,321 @@ pub fn render_buildings(
     position: &Position,
     building: &Building,
     building_completeness: &BuildingCompleteness,
-    skin: Option<&Skin>,
+    side: &Side,
+    skin: Option<&mut Skin>,
+    animation_state: Option<&AnimationState>,
+    gate: Option<&Gate>,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] animations: &ModelAnimations,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] terrain: &Terrain,
 ) {
-    let buffer = match building {
-        Building::Armoury => &mut model_buffers.armouries,
-        Building::Pump => &mut model_buffers.pumps,
+    if !fog_of_war.can_see(player_side.0, *side, position.0) {
+        return;
+    }
+
+    let (buffer, transform) = match building {
+        Building::Armoury => (&mut model_buffers.armouries, Mat4::identity()),
+        Building::Pump => (&mut model_buffers.pumps, Mat4::identity()),
+        Building::Silo => (&mut model_buffers.armouries, superweapon::model_transform()),
+        Building::Wall | Building::Gate => (
+            &mut model_buffers.walls,
+            walls::segment_transform(Vec3::zero(), *building, gate),
+        ),
     };
 
     let scale = (building_completeness.0 as f32 / building.stats().max_health as f32).max(0.01);
 
     buffer.push(ModelInstance {
-        transform: Mat4::from_translation(Vec3::new(position.0.x, 0.0, position.0.y))
-            * Mat4::from_nonuniform_scale(Vec3::new(1.0, scale, 1.0)),
+        transform: Mat4::from_translation(terrain.ground(position.0))
+            * Mat4::from_nonuniform_scale(Vec3::new(1.0, scale, 1.0))
+            * transform,
         flat_colour: Vec4::new(1.0, 1.0, 1.0, 1.0),
     });
 
-    if let Some(skin) = skin {
-        for joint in &skin.joints {
-            model_buffers.pump_joints.push(joint.matrix);
-        }
+    if let (Some(skin), Some(animation_state)) = (skin, animation_state) {
+        model_buffers
+            .pump_joints
+            .push(animation_state, &animations.pump.animations, skin);
     }
 }
 
+// The rocks are just squashed copies of the cheese moon from the titlescreen. They're drawn a bit
+// bigger than the obstacle itself so that the corners of the box are covered.
+#[legion::system(for_each)]
+pub fn render_obstacles(
+    position: &Position,
+    obstacle: &Obstacle,
+    #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] terrain: &Terrain,
+) {
+    let radii = obstacle.dimensions / 2.0 * 1.2;
+    let height = radii.x.min(radii.y) * 0.6;
+
+    model_buffers.rocks.push(ModelInstance {
+        transform: Mat4::from_translation(terrain.ground(position.0))
+            * Mat4::from_nonuniform_scale(Vec3::new(radii.x, height, radii.y)),
+        flat_colour: Vec4::one(),
+    });
+}
+
+// Structures are drawn grey until someone takes them. The ring around them shows how far anyone is
+// from capturing it, but only when the player can see it.
+#[legion::system(for_each)]
+pub fn render_capturable_structures(
+    position: &Position,
+    capturable: &Capturable,
+    #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] team_colours: &TeamColours,
+    #[resource] terrain: &Terrain,
+) {
+    let ground = terrain.ground(position.0);
+
+    model_buffers.walls.push(ModelInstance {
+        transform: Mat4::from_translation(ground) * capturable.structure.model_transform(),
+        flat_colour: Vec4::one(),
+    });
+
+    let visible = fog_of_war.grid(player_side.0).get(position.0) == fog_of_war::Visibility::Visible
+        || capturable.owner == Some(player_side.0);
+
+    if !visible {
+        return;
+    }
+
+    torus_buffer.toruses.push(TorusInstance {
+        center: ground,
+        colour: capturable
+            .owner
+            .map_or(NEUTRAL_COLOUR, |owner| team_colours.get(owner)),
+        radius: capture::CAPTURE_RADIUS,
+    });
+
+    if let Some(capturing) = capturable.capturing {
+        torus_buffer.toruses.push(TorusInstance {
+            center: ground,
+            colour: team_colours.get(capturing),
+            radius: capture::CAPTURE_RADIUS * capturable.progress,
+        });
+    }
+}
+
+const NEUTRAL_COLOUR: Vec3 = Vec3::new(0.6, 0.6, 0.6);
+
 #[legion::system]
 pub fn render_drag_box(
     #[resource] mouse_state: &MouseState,
+    #[resource] rts_controls: &RtsControls,
     #[resource] dpi_scaling: &DpiScaling,
     #[resource] mode: &Mode,
+    #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] line_buffers: &mut LineBuffers,
 ) {
-    if *mode != Mode::Playing {
+    let placing_wall = rts_controls.mode
+        == CommandMode::Construct {
+            building: Building::Wall,
+        };
+
+    if *mode != Mode::Playing || placing_wall {
         return;
     }
 
-    if let Some(start) = mouse_state.left_state.is_being_dragged() {
+    let minimap_area = MinimapArea::new(screen_dimensions, dpi_scaling);
+
+    if let Some(start) = mouse_state.left_state.is_being_dragged().filter(|start| {
+        !minimap_area.contains(*start) && !hud_contains(*start, screen_dimensions, dpi_scaling)
+    }) {
         let (top_left, bottom_right) = sort_points(start, mouse_state.position);
         line_buffers.draw_rect(top_left, bottom_right, dpi_scaling.0);
     }
 }
 
+// A wobbly green beam between healers and whatever they're healing, drawn a few times over with
+// fainter copies around it so that it looks like it's glowing.
+#[legion::system(for_each)]
+#[read_component(Position)]
+pub fn render_healing_beams(
+    entity: &Entity,
+    side: &Side,
+    healing: &Healing,
+    #[resource] lines_3d_buffer: &mut Lines3dBuffer,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] terrain: &Terrain,
+    #[resource] total_time: &TotalTime,
+    world: &SubWorld,
+) {
+    let (start, end) = match (
+        <&Position>::query().get(world, *entity),
+        <&Position>::query().get(world, healing.0),
+    ) {
+        (Ok(start), Ok(end)) => (start.0, end.0),
+        _ => return,
+    };
+
+    if !fog_of_war.can_see(player_side.0, *side, start) {
+        return;
+    }
+
+    let height = Vec3::new(0.0, 1.5, 0.0);
+    let start = terrain.ground(start) + height;
+    let end = terrain.ground(end) + height;
+
+    let pulse = 0.75 + 0.25 * (total_time.0 * 8.0).sin();
+    let colour = Vec4::new(0.4, 1.0, 0.4, pulse);
+    let glow = Vec4::new(0.4, 1.0, 0.4, pulse * 0.3);
+
+    lines_3d_buffer.draw_line_3d(start, end, colour);
+
+    for offset in &[
+        Vec3::new(0.08, 0.0, 0.0),
+        Vec3::new(-0.08, 0.0, 0.0),
+        Vec3::new(0.0, 0.08, 0.0),
+        Vec3::new(0.0, 0.0, 0.08),
+        Vec3::new(0.0, 0.0, -0.08),
+    ] {
+        lines_3d_buffer.draw_line_3d(start + *offset, end + *offset, glow);
+    }
+}
+
 #[legion::system(for_each)]
-#[filter(component::<Bullet>())]
 pub fn render_bullets(
     position: &Position,
     facing: &Facing,
+    bullet: &Bullet,
     #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] lines_3d_buffer: &mut Lines3dBuffer,
+    #[resource] terrain: &Terrain,
 ) {
-    let gun_height = 1.8;
-    let translation = Mat4::from_translation(Vec3::new(position.0.x, gun_height, position.0.y));
-    let rotation = Mat4::from_rotation_y(facing.0);
+    // The arc is relative to the ground, so lift it from the height of the shooter to the height
+    // of wherever the bullet is going to land.
+    let start_height = terrain.height_at(bullet.start);
+    let impact_height = terrain.height_at(bullet.impact);
+    let point_at = |progress: f32| {
+        let ground_height = start_height + (impact_height - start_height) * progress;
+        bullet.point_at(progress) + Vec3::new(0.0, ground_height, 0.0)
+    };
+
+    let progress = bullet.progress(position.0);
+    let point = point_at(progress);
+
+    // Pitch the bullet so that it follows the arc.
+    let ahead = point_at((progress + 0.01).min(1.0));
+    let behind = point_at((progress - 0.01).max(0.0));
+    let horizontal = Vec2::new(ahead.x - behind.x, ahead.z - behind.z).mag();
+    let pitch = (ahead.y - behind.y).atan2(horizontal);
+
+    let translation = Mat4::from_translation(point);
+    let rotation = Mat4::from_rotation_y(facing.0) * Mat4::from_rotation_z(pitch);
 
     model_buffers.bullets.push(ModelInstance {
         transform: translation * rotation,
         flat_colour: Vec4::one(),
     });
+
+    // Draw a tracer trailing along the arc behind the bullet.
+    let tracer_length = 0.15;
+    let segments = 4;
+    let tracer_colour = Vec4::new(1.0, 0.9, 0.5, 1.0);
+
+    let mut previous = point;
+    for i in 1..=segments {
+        let t = (progress - tracer_length * i as f32 / segments as f32).max(0.0);
+        let next = point_at(t);
+        lines_3d_buffer.draw_line_3d(previous, next, tracer_colour);
+        previous = next;
+    }
+}
+
+#[legion::system(for_each)]
+pub fn render_floating_text(
+    floating_text: &FloatingText,
+    #[resource] camera: &Camera,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] fog_of_war: &FogOfWar,
+    #[resource] player_side: &PlayerSide,
+    #[resource] terrain: &Terrain,
+) {
+    if !fog_of_war.can_see(player_side.0, floating_text.side, floating_text.position) {
+        return;
+    }
+
+    let floating =
+        terrain.ground(floating_text.position) + Vec3::new(0.0, floating_text.height, 0.0);
+    let location = screen_location(floating, camera, screen_dimensions);
+    let colour = floating_text.colour;
+
+    text_buffer.draw_text(
+        location,
+        &floating_text.text,
+        20.0 * dpi_scaling.0,
+        Vec4::new(colour.x, colour.y, colour.z, floating_text.alpha()),
+    );
 }
 
 #[legion::system]
 #[read_component(Position)]
 #[read_component(Radius)]
+#[read_component(Unit)]
+#[read_component(Side)]
+#[read_component(Cloaked)]
 pub fn render_unit_under_cursor(
     #[resource] ray_cast_location: &RayCastLocation,
+    #[resource] player_side: &PlayerSide,
     #[resource] cursor_icon: &mut CursorIcon,
     #[resource] torus_buffer: &mut TorusBuffer,
+    #[resource] terrain: &Terrain,
     world: &SubWorld,
 ) {
-    if let Some((pos, radius)) = unit_under_cursor(ray_cast_location, world) {
+    if let Some((pos, radius, is_unit)) = unit_under_cursor(ray_cast_location, player_side, world) {
         cursor_icon.0 = winit::window::CursorIcon::Hand;
-        torus_buffer.toruses.push(TorusInstance {
-            center: Vec3::new(pos.x, 0.0, pos.y),
-            colour: WHITE,
-            radius,
-        });
+        // Units get a highlighted outline instead.
+        if !is_unit {
+            torus_buffer.toruses.push(TorusInstance {
+                center: terrain.ground(pos),
+                colour: WHITE,
+                radius,
+            });
+        }
     }
 }
 
-fn unit_under_cursor(ray_cast_location: &RayCastLocation, world: &SubWorld) -> Option<(Vec2, f32)> {
+fn unit_under_cursor(
+    ray_cast_location: &RayCastLocation,
+    player_side: &PlayerSide,
+    world: &SubWorld,
+) -> Option<(Vec2, f32, bool)> {
     let position = ray_cast_location.pos;
 
-    <(&Position, &Radius)>::query()
+    <(&Position, &Radius, Option<&Unit>, &Side, Option<&Cloaked>)>::query()
         .iter(world)
-        .find(|(pos, radius)| (position - pos.0).mag_sq() < radius.0.powi(2))
-        .map(|(pos, radius)| (pos.0, radius.0))
+        .find(|(pos, radius, _, side, cloaked)| {
+            (position - pos.0).mag_sq() < radius.0.powi(2)
+                && !stealth::is_hidden(*cloaked, player_side.0, **side)
+        })
+        .map(|(pos, radius, unit, ..)| (pos.0, radius.0, unit.is_some()))
 }
 
-#[legion::system]
-pub fn render_abilities(
-    #[resource] dpi_scaling: &DpiScaling,
-    #[resource] line_buffers: &mut LineBuffers,
-    #[resource] screen_dimensions: &ScreenDimensions,
-    #[resource] cheese_coins: &CheeseCoins,
-    #[resource] text_buffer: &mut TextBuffer,
-    #[resource] selected_units_abilities: &SelectedUnitsAbilities,
-) {
-    let dims = screen_dimensions.as_vec();
-    let dpi = dpi_scaling.0;
-    let ability_size = 64.0 * 1.5;
-    let gap = 10.0;
-    let border = 2.0;
-
-    let offset =
-        (selected_units_abilities.0.len() as f32 - 1.0) * ((ability_size + gap) * dpi / 2.0);
-
-    let position = |i| {
-        Vec2::new(
-            (dims.x as f32 / 2.0) - offset + (i as f32 * (ability_size + gap) * dpi),
-            dims.y as f32 - (ability_size / 2.0 + gap - border) * dpi,
-        )
-    };
-
-    for (i, (ability, _)) in selected_units_abilities.0.iter().enumerate() {
-        line_buffers.draw_filled_rect(
-            position(i),
-            Vec2::new(ability_size + border * 2.0, ability_size + border * 2.0),
-            BLACK,
-            dpi_scaling.0,
-        );
-
-        let can_use = match ability.ability_type {
-            AbilityType::Build(building) => building.stats().cost <= cheese_coins.0,
-            AbilityType::Recruit(unit) => unit.stats().cost <= cheese_coins.0,
-            AbilityType::SetRecruitmentWaypoint => true,
-        };
-
-        line_buffers.draw_image(
-            position(i),
-            Vec2::new(ability_size, ability_size),
-            ability.image(),
-            !can_use,
-            dpi_scaling.0,
-        );
-
-        let nudge = Vec2::new(2.0, -2.0);
-
-        text_buffer.render_text(
-            position(i) - (Vec2::new(ability_size, ability_size) / 2.0 - nudge) * dpi,
-            &format!("{:?}", ability.hotkey),
-            Font::Ui,
-            1.0,
-            dpi_scaling.0,
-            TextAlignment::Default,
-            Vec4::new(0.0, 0.0, 0.0, 1.0),
-        );
-
-        let cost = match ability.ability_type {
-            AbilityType::Build(building) => Some(building.stats().cost),
-            AbilityType::Recruit(unit) => Some(unit.stats().cost),
-            AbilityType::SetRecruitmentWaypoint => None,
-        };
+#[test]
+fn transparent_things_are_sorted_furthest_first() {
+    let camera = Camera::new(Vec2::zero(), 20.0);
+    let forwards = camera.forwards();
+    let mut points: Vec<Vec3> = [-5.0, 10.0, 0.0, 3.0]
+        .iter()
+        .map(|&distance| Vec3::new(forwards.x, 0.0, forwards.y) * distance)
+        .collect();
 
-        if let Some(cost) = cost {
-            let nudge = Vec2::new(-2.0, -2.0);
+    sort_back_to_front(&mut points, &camera, |&point| point);
 
-            text_buffer.render_text(
-                position(i) - (Vec2::new(-ability_size, ability_size) / 2.0 - nudge) * dpi,
-                &format!("{}", cost),
-                Font::Ui,
-                1.0,
-                dpi_scaling.0,
-                TextAlignment::HorizontalRight,
-                Vec4::new(0.0, 0.0, 0.0, 1.0),
-            );
-        }
-    }
+    let distances: Vec<f32> = points
+        .iter()
+        .map(|point| Vec2::new(point.x, point.z).dot(forwards).round())
+        .collect();
+    assert_eq!(distances, vec![10.0, 3.0, 0.0, -5.0]);
 }
@@ -0,0 +1,782 @@
+use super::*;
+use crate::mission::Mission;
+use crate::net::Lockstep;
+use crate::resources::{
+    AiBuildOrderItem, AiBuildOrders, CheeseCoins, ControlGroups, GameStats, Objectives, TotalTime,
+};
+use crate::scenery::{PropInstance, Scenery};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+// Bump this whenever the format changes. Old saves just fail to load instead of loading wrongly.
+const SAVE_VERSION: u32 = 1;
+
+pub const QUICKSAVE_PATH: &str = "quicksave.json";
+
+// Entities are referred to by their `NetworkId`s, as they're stable between runs. The map isn't
+// saved directly, instead its constraints are recreated by inserting the buildings again. Things
+// that only last a few moments such as bullets, explosions and cheese droplets aren't saved.
+#[derive(Serialize, Deserialize)]
+struct SaveFile {
+    version: u32,
+    total_time: f32,
+    cheese_coins: u32,
+    player_side: Side,
+    camera_looking_at: [f32; 2],
+    camera_distance: f32,
+    next_network_id: u32,
+    objectives: Objectives,
+    game_stats: GameStats,
+    #[serde(default)]
+    upgrades: Upgrades,
+    ai_build_orders: Vec<(f32, SavedAiBuildOrderItem)>,
+    units: Vec<SavedUnit>,
+    buildings: Vec<SavedBuilding>,
+    guysers: Vec<SavedGuyser>,
+    // Only generated maps have obstacles.
+    #[serde(default)]
+    obstacles: Vec<SavedObstacle>,
+    // Saved as it was scattered rather than as the mission's `[[scenery]]` entries, as the script
+    // isn't saved.
+    #[serde(default)]
+    scenery: Vec<PropInstance>,
+    #[serde(default)]
+    strikes: Vec<SavedStrike>,
+    #[serde(default)]
+    structures: Vec<SavedStructure>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedUnit {
+    id: NetworkId,
+    unit: Unit,
+    side: Side,
+    position: [f32; 2],
+    facing: f32,
+    health: f32,
+    commands: Vec<SavedCommand>,
+    // Units that can't attack don't have a stance. Older saves don't have them either.
+    #[serde(default)]
+    stance: Option<Stance>,
+    #[serde(default)]
+    experience: Experience,
+    // Cooldowns aren't saved, so everything's ready to go again after loading.
+    #[serde(default)]
+    energy: Option<f32>,
+    // How long the unit has left cloaked. Burrowed units get their cloak back from `burrow`.
+    #[serde(default)]
+    cloak: Option<f32>,
+    #[serde(default)]
+    burrow: Option<Burrow>,
+}
+
+// Paths are recalculated after loading.
+#[derive(Serialize, Deserialize)]
+enum SavedCommand {
+    MoveTo { target: [f32; 2], attack_move: bool },
+    Attack { target: NetworkId, explicit: bool },
+    Build { target: NetworkId },
+    Patrol { a: [f32; 2], b: [f32; 2] },
+    HoldPosition,
+    Follow { target: NetworkId },
+    Guard { center: [f32; 2], radius: f32 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedBuilding {
+    id: NetworkId,
+    building: Building,
+    side: Side,
+    position: [f32; 2],
+    health: f32,
+    completeness: f32,
+    fully_built: bool,
+    recruitment_queue: Option<SavedRecruitmentQueue>,
+    // Wall segments that were placed together have the id of the first segment here.
+    #[serde(default)]
+    wall: Option<NetworkId>,
+    #[serde(default)]
+    gate_open: bool,
+    #[serde(default)]
+    gate_locked: bool,
+    // The upgrade an armoury is researching and how far along it is.
+    #[serde(default)]
+    research: Option<(Upgrade, f32)>,
+    // How long a silo has left to charge.
+    #[serde(default)]
+    superweapon: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedRecruitmentQueue {
+    percentage_progress: f32,
+    queue: Vec<Unit>,
+    // Where the rally point was when the game was saved, which is used if it was on something that
+    // has since gone.
+    waypoint: [f32; 2],
+    #[serde(default)]
+    rally: Option<SavedRallyPoint>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum SavedRallyPoint {
+    Guyser(NetworkId),
+    Unit(NetworkId),
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedGuyser {
+    id: NetworkId,
+    position: [f32; 2],
+    built_on: Option<NetworkId>,
+}
+
+// Strikes from silos that have since been destroyed are lost, as there's nothing to blame the
+// damage on.
+#[derive(Serialize, Deserialize)]
+struct SavedStrike {
+    target: [f32; 2],
+    side: Side,
+    source: NetworkId,
+    remaining: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedObstacle {
+    position: [f32; 2],
+    dimensions: [f32; 2],
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedStructure {
+    structure: Structure,
+    position: [f32; 2],
+    owner: Option<Side>,
+    capturing: Option<Side>,
+    progress: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+enum SavedAiBuildOrderItem {
+    BuildPump(NetworkId),
+    BuildArmoury([f32; 2]),
+    RecruitMarine(u32),
+    AttackMove([f32; 2]),
+    SetWaypoint([f32; 2]),
+}
+
+// Everything that goes into a save, taken out of the world so that it can be written out later on
+// (or on another thread, which is what autosaves do).
+pub struct SaveSnapshot(SaveFile);
+
+impl SaveSnapshot {
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string(&self.0)?)?;
+        log::info!("Saved game to {}", path.display());
+        Ok(())
+    }
+}
+
+pub fn save_game(world: &World, resources: &Resources, path: &Path) -> anyhow::Result<()> {
+    snapshot_game(world, resources).write(path)
+}
+
+pub fn snapshot_game(world: &World, resources: &Resources) -> SaveSnapshot {
+    let lockstep = resources.get::<Lockstep>().unwrap();
+
+    // Anything spawned this tick won't have been given an id yet. These ids are only used in the
+    // save and aren't taken from the lockstep, as saving on one side of a networked game would
+    // make it go out of sync.
+    let mut next_network_id = lockstep.network_ids_issued();
+    let mut ids: HashMap<Entity, NetworkId> = HashMap::new();
+    let mut entities: Vec<_> = <(Entity, Option<&NetworkId>)>::query()
+        .filter(component::<Unit>() | component::<Building>() | component::<CheeseGuyser>())
+        .iter(world)
+        .map(|(entity, id)| (*entity, id.copied()))
+        .collect();
+    entities.sort_by_key(|(_, id)| id.is_none());
+    for (entity, id) in entities {
+        let id = id.unwrap_or_else(|| {
+            next_network_id += 1;
+            NetworkId(next_network_id - 1)
+        });
+        ids.insert(entity, id);
+    }
+
+    let id = |entity: &Entity| ids.get(entity).copied();
+
+    let units = <(
+        Entity,
+        &Unit,
+        &Side,
+        &Position,
+        &Facing,
+        &Health,
+        &CommandQueue,
+        Option<&Stance>,
+        Option<&Experience>,
+        Option<&Energy>,
+        Option<&Cloaked>,
+        Option<&Burrow>,
+    )>::query()
+    .iter(world)
+    .map(
+        |(
+            entity,
+            unit,
+            side,
+            position,
+            facing,
+            health,
+            commands,
+            stance,
+            experience,
+            energy,
+            cloaked,
+            burrow,
+        )| {
+            SavedUnit {
+                id: ids[entity],
+                unit: *unit,
+                side: *side,
+                position: position.0.into(),
+                facing: facing.0,
+                health: health.0,
+                commands: commands
+                    .0
+                    .iter()
+                    .filter_map(|command| match command {
+                        Command::MoveTo {
+                            target,
+                            attack_move,
+                            ..
+                        } => Some(SavedCommand::MoveTo {
+                            target: (*target).into(),
+                            attack_move: *attack_move,
+                        }),
+                        Command::Attack {
+                            target, explicit, ..
+                        } => Some(SavedCommand::Attack {
+                            target: id(target)?,
+                            explicit: *explicit,
+                        }),
+                        Command::Build { target, .. } => Some(SavedCommand::Build {
+                            target: id(target)?,
+                        }),
+                        Command::Patrol { a, b, .. } => Some(SavedCommand::Patrol {
+                            a: (*a).into(),
+                            b: (*b).into(),
+                        }),
+                        Command::HoldPosition => Some(SavedCommand::HoldPosition),
+                        Command::Follow { target, .. } => Some(SavedCommand::Follow {
+                            target: id(target)?,
+                        }),
+                        Command::Guard { center, radius, .. } => Some(SavedCommand::Guard {
+                            center: (*center).into(),
+                            radius: *radius,
+                        }),
+                        // These are over in a moment, so they're dropped like bullets are.
+                        Command::Cast { .. } => None,
+                    })
+                    .collect(),
+                stance: stance.copied(),
+                experience: experience.copied().unwrap_or_default(),
+                energy: energy.map(|energy| energy.0),
+                cloak: cloaked.and_then(|cloaked| cloaked.remaining),
+                burrow: burrow.copied(),
+            }
+        },
+    )
+    .collect();
+
+    let wall_ids: HashMap<Entity, NetworkId> = <&walls::Wall>::query()
+        .iter(world)
+        .flat_map(|wall| {
+            let first = wall.segments().next().and_then(|segment| id(&segment));
+            wall.segments()
+                .filter_map(move |segment| Some((segment, first?)))
+        })
+        .collect();
+
+    let buildings = <(
+        Entity,
+        &Building,
+        &Side,
+        &Position,
+        &Health,
+        &BuildingCompleteness,
+        Option<&FullyBuilt>,
+        Option<&RecruitmentQueue>,
+        Option<&Gate>,
+        Option<&Research>,
+        Option<&Superweapon>,
+    )>::query()
+    .iter(world)
+    .map(
+        |(
+            entity,
+            building,
+            side,
+            position,
+            health,
+            completeness,
+            fully_built,
+            queue,
+            gate,
+            research,
+            superweapon,
+        )| {
+            SavedBuilding {
+                id: ids[entity],
+                building: *building,
+                side: *side,
+                position: position.0.into(),
+                health: health.0,
+                completeness: completeness.0,
+                fully_built: fully_built.is_some(),
+                recruitment_queue: queue.map(|queue| SavedRecruitmentQueue {
+                    percentage_progress: queue.percentage_progress,
+                    queue: queue.queue.iter().copied().collect(),
+                    waypoint: queue.waypoint.position(world).unwrap_or(position.0).into(),
+                    rally: match queue.waypoint {
+                        RallyPoint::Position(_) => None,
+                        RallyPoint::Guyser(guyser) => id(&guyser).map(SavedRallyPoint::Guyser),
+                        RallyPoint::Unit(unit) => id(&unit).map(SavedRallyPoint::Unit),
+                    },
+                }),
+                wall: wall_ids.get(entity).copied(),
+                gate_open: gate.is_some_and(|gate| gate.open),
+                gate_locked: gate.is_some_and(|gate| gate.locked),
+                research: research.and_then(|research| {
+                    research.current.map(|upgrade| (upgrade, research.progress))
+                }),
+                superweapon: superweapon.map(|superweapon| superweapon.remaining),
+            }
+        },
+    )
+    .collect();
+
+    let guysers = <(Entity, &Position, Option<&CheeseGuyserBuiltOn>)>::query()
+        .filter(component::<CheeseGuyser>())
+        .iter(world)
+        .map(|(entity, position, built_on)| SavedGuyser {
+            id: ids[entity],
+            position: position.0.into(),
+            built_on: built_on.and_then(|built_on| id(&built_on.pump)),
+        })
+        .collect();
+
+    let obstacles = <(&Position, &Obstacle)>::query()
+        .iter(world)
+        .map(|(position, obstacle)| SavedObstacle {
+            position: position.0.into(),
+            dimensions: obstacle.dimensions.into(),
+        })
+        .collect();
+
+    let structures = <(&Position, &Capturable)>::query()
+        .iter(world)
+        .map(|(position, capturable)| SavedStructure {
+            structure: capturable.structure,
+            position: position.0.into(),
+            owner: capturable.owner,
+            capturing: capturable.capturing,
+            progress: capturable.progress,
+        })
+        .collect();
+
+    let ai_build_orders = resources
+        .get::<AiBuildOrders>()
+        .unwrap()
+        .0
+        .iter()
+        .filter_map(|(time, item)| {
+            let item = match item {
+                AiBuildOrderItem::BuildPump(guyser) => {
+                    SavedAiBuildOrderItem::BuildPump(id(guyser)?)
+                }
+                AiBuildOrderItem::BuildArmoury(position) => {
+                    SavedAiBuildOrderItem::BuildArmoury((*position).into())
+                }
+                AiBuildOrderItem::RecruitMarine(times) => {
+                    SavedAiBuildOrderItem::RecruitMarine(*times)
+                }
+                AiBuildOrderItem::AttackMove(position) => {
+                    SavedAiBuildOrderItem::AttackMove((*position).into())
+                }
+                AiBuildOrderItem::SetWaypoint(position) => {
+                    SavedAiBuildOrderItem::SetWaypoint((*position).into())
+                }
+            };
+            Some((*time, item))
+        })
+        .collect();
+
+    let strikes = <&Strike>::query()
+        .iter(world)
+        .filter_map(|strike| {
+            Some(SavedStrike {
+                target: strike.target.into(),
+                side: strike.side,
+                source: id(&strike.source)?,
+                remaining: strike.remaining,
+            })
+        })
+        .collect();
+
+    let camera = resources.get::<Camera>().unwrap();
+
+    SaveSnapshot(SaveFile {
+        version: SAVE_VERSION,
+        total_time: resources.get::<TotalTime>().unwrap().0,
+        cheese_coins: resources.get::<CheeseCoins>().unwrap().0,
+        player_side: resources.get::<PlayerSide>().unwrap().0,
+        camera_looking_at: camera.looking_at.into(),
+        camera_distance: camera.distance,
+        next_network_id,
+        objectives: resources.get::<Objectives>().unwrap().clone(),
+        game_stats: resources.get::<GameStats>().unwrap().clone(),
+        upgrades: resources.get::<Upgrades>().unwrap().clone(),
+        ai_build_orders,
+        units,
+        buildings,
+        guysers,
+        obstacles,
+        scenery: resources.get::<Scenery>().unwrap().props().to_vec(),
+        strikes,
+        structures,
+    })
+}
+
+pub fn load_game(world: &mut World, resources: &mut Resources, path: &Path) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+
+    let version = serde_json::from_str::<Version>(&contents)?.version;
+    if version != SAVE_VERSION {
+        return Err(anyhow::anyhow!(
+            "{} is from version {} of the save format, but we can only load version {}",
+            path.display(),
+            version,
+            SAVE_VERSION
+        ));
+    }
+
+    let save_file: SaveFile = serde_json::from_str(&contents)?;
+
+    world.clear();
+
+    let animations = resources.get::<ModelAnimations>().unwrap();
+    let mut map = resources.get_mut::<Map>().unwrap();
+    *map = Map::new();
+    *resources.get_mut::<FogOfWar>().unwrap() = FogOfWar::default();
+    *resources.get_mut::<PathRequests>().unwrap() = PathRequests::default();
+    *resources.get_mut::<ControlGroups>().unwrap() = ControlGroups::default();
+    // Mission scripts aren't saved, see `mission::Mission`.
+    *resources.get_mut::<Mission>().unwrap() = Mission::default();
+    resources
+        .get_mut::<Scenery>()
+        .unwrap()
+        .set(save_file.scenery.clone());
+
+    let mut lockstep = resources.get_mut::<Lockstep>().unwrap();
+    lockstep.reset();
+    lockstep.set_network_ids_issued(save_file.next_network_id);
+
+    let mut buffer = CommandBuffer::new(world);
+    let mut entities: HashMap<NetworkId, Entity> = HashMap::new();
+
+    // These go in first, as they're part of the map that the buildings were placed around.
+    for obstacle in &save_file.obstacles {
+        let position = obstacle.position.into();
+        if Obstacle::add_to_world(&mut buffer, position, obstacle.dimensions.into(), &mut map)
+            .is_none()
+        {
+            log::warn!("Couldn't place an obstacle at {:?} when loading", position);
+        }
+    }
+
+    for saved in &save_file.structures {
+        let position = saved.position.into();
+        match saved
+            .structure
+            .add_to_world(&mut buffer, position, &mut map)
+        {
+            Some(entity) => buffer.add_component(
+                entity,
+                Capturable {
+                    structure: saved.structure,
+                    owner: saved.owner,
+                    capturing: saved.capturing,
+                    progress: saved.progress,
+                },
+            ),
+            None => log::warn!("Couldn't place a {:?} when loading", saved.structure),
+        }
+    }
+
+    // Walls are put back together from their segments, so that they still share their health and
+    // constraints in the map.
+    let mut saved_walls: Vec<(NetworkId, Vec<&SavedBuilding>)> = Vec::new();
+    for building in &save_file.buildings {
+        if let Some(wall) = building.wall {
+            match saved_walls.iter_mut().find(|(id, _)| *id == wall) {
+                Some((_, segments)) => segments.push(building),
+                None => saved_walls.push((wall, vec![building])),
+            }
+        }
+    }
+
+    let mut wall_segments: HashMap<NetworkId, Entity> = HashMap::new();
+    for (_, mut segments) in saved_walls {
+        // They're in a straight line, so this puts them back in order.
+        segments.sort_by_key(|segment| {
+            (
+                ordered_float::OrderedFloat(segment.position[0]),
+                ordered_float::OrderedFloat(segment.position[1]),
+            )
+        });
+
+        let positions: Vec<Vec2> = segments
+            .iter()
+            .map(|segment| segment.position.into())
+            .collect();
+
+        if let Some(entities) =
+            walls::add_wall_to_construct(&mut buffer, &positions, segments[0].side, &mut map)
+        {
+            for (segment, entity) in segments.iter().zip(entities) {
+                wall_segments.insert(segment.id, entity);
+            }
+        }
+    }
+
+    let mut gates = Vec::new();
+    let mut queues = Vec::new();
+
+    for building in &save_file.buildings {
+        let entity = match building.wall {
+            Some(_) => wall_segments.get(&building.id).copied(),
+            None => building.building.add_to_world_to_construct(
+                &mut buffer,
+                building.position.into(),
+                building.side,
+                &animations,
+                &mut map,
+            ),
+        };
+
+        let entity = match entity {
+            Some(entity) => entity,
+            None => {
+                log::warn!("Couldn't place {:?} when loading", building.building);
+                continue;
+            }
+        };
+
+        buffer.add_component(entity, building.id);
+        buffer.add_component(entity, Health(building.health));
+        buffer.add_component(entity, BuildingCompleteness(building.completeness));
+
+        if building.fully_built {
+            buffer.add_component(entity, FullyBuilt);
+        }
+
+        if building.building == Building::Gate {
+            let gate = Gate {
+                open: building.gate_open,
+                locked: building.gate_locked,
+            };
+            gates.push((entity, gate));
+        }
+
+        // Rally points can be on units, which don't exist yet.
+        if let Some(queue) = &building.recruitment_queue {
+            queues.push((entity, queue));
+        }
+
+        if let Some(remaining) = building.superweapon {
+            buffer.add_component(entity, Superweapon { remaining });
+        }
+
+        if let Some((upgrade, progress)) = building.research {
+            buffer.add_component(
+                entity,
+                Research {
+                    current: Some(upgrade),
+                    progress,
+                },
+            );
+        }
+
+        entities.insert(building.id, entity);
+    }
+
+    for unit in &save_file.units {
+        let entity = unit.unit.add_to_world(
+            &mut buffer,
+            Some(&animations),
+            unit.position.into(),
+            Facing(unit.facing),
+            unit.side,
+            None,
+        );
+
+        buffer.add_component(entity, unit.id);
+        buffer.add_component(entity, Health(unit.health));
+        buffer.add_component(entity, unit.experience);
+        if let Some(energy) = unit.energy {
+            buffer.add_component(entity, Energy(energy));
+        }
+        if let Some(stance) = unit.stance {
+            buffer.add_component(entity, stance);
+        }
+        if let Some(remaining) = unit.cloak {
+            buffer.add_component(entity, Cloaked::new(remaining));
+        }
+        if let Some(burrow) = unit.burrow {
+            if burrow == Burrow::Burrowed {
+                buffer.add_component(entity, Cloaked::permanent());
+            }
+            buffer.add_component(entity, burrow);
+        }
+        entities.insert(unit.id, entity);
+    }
+
+    for guyser in &save_file.guysers {
+        let entity = buffer.push((
+            Position(guyser.position.into()),
+            CheeseGuyser,
+            Cooldown(0.0),
+            guyser.id,
+        ));
+
+        if let Some(pump) = guyser.built_on.and_then(|pump| entities.get(&pump)) {
+            buffer.add_component(entity, CheeseGuyserBuiltOn { pump: *pump });
+        }
+
+        entities.insert(guyser.id, entity);
+    }
+
+    for strike in &save_file.strikes {
+        if let Some(source) = entities.get(&strike.source) {
+            buffer.push((Strike {
+                target: strike.target.into(),
+                side: strike.side,
+                source: *source,
+                remaining: strike.remaining,
+            },));
+        }
+    }
+
+    // Now that every entity exists, commands can refer to them.
+    for unit in &save_file.units {
+        let commands = unit
+            .commands
+            .iter()
+            .filter_map(|command| match command {
+                SavedCommand::MoveTo {
+                    target,
+                    attack_move,
+                } => Some(Command::MoveTo {
+                    target: (*target).into(),
+                    attack_move: *attack_move,
+                    path: Vec::new(),
+                }),
+                SavedCommand::Attack { target, explicit } => {
+                    Some(Command::new_attack(*entities.get(target)?, *explicit))
+                }
+                SavedCommand::Build { target } => Some(Command::new_build(*entities.get(target)?)),
+                SavedCommand::Patrol { a, b } => {
+                    Some(Command::new_patrol((*a).into(), (*b).into()))
+                }
+                SavedCommand::HoldPosition => Some(Command::HoldPosition),
+                SavedCommand::Follow { target } => {
+                    Some(Command::new_follow(*entities.get(target)?))
+                }
+                SavedCommand::Guard { center, radius } => {
+                    Some(Command::new_guard((*center).into(), *radius))
+                }
+            })
+            .collect();
+
+        buffer.add_component(entities[&unit.id], CommandQueue(commands));
+    }
+
+    for (entity, queue) in queues {
+        let rally = queue.rally.as_ref().and_then(|rally| match rally {
+            SavedRallyPoint::Guyser(id) => Some(RallyPoint::Guyser(*entities.get(id)?)),
+            SavedRallyPoint::Unit(id) => Some(RallyPoint::Unit(*entities.get(id)?)),
+        });
+
+        buffer.add_component(
+            entity,
+            RecruitmentQueue {
+                percentage_progress: queue.percentage_progress,
+                queue: queue.queue.iter().copied().collect(),
+                waypoint: rally.unwrap_or_else(|| RallyPoint::Position(queue.waypoint.into())),
+            },
+        );
+    }
+
+    buffer.flush(world);
+
+    // Gates are always shut when they're placed, and they might have been open with a unit in the
+    // way of shutting them.
+    for (entity, gate) in gates {
+        if let Some(mut entry) = world.entry(entity) {
+            if gate.open {
+                if let Ok(handle) = entry.get_component_mut::<MapHandle>() {
+                    map.set_constraint_enabled(handle, false);
+                }
+            }
+            entry.add_component(gate);
+        }
+    }
+
+    *resources.get_mut::<AiBuildOrders>().unwrap() = AiBuildOrders(
+        save_file
+            .ai_build_orders
+            .into_iter()
+            .filter_map(|(time, item)| {
+                let item = match item {
+                    SavedAiBuildOrderItem::BuildPump(guyser) => {
+                        AiBuildOrderItem::BuildPump(*entities.get(&guyser)?)
+                    }
+                    SavedAiBuildOrderItem::BuildArmoury(position) => {
+                        AiBuildOrderItem::BuildArmoury(position.into())
+                    }
+                    SavedAiBuildOrderItem::RecruitMarine(times) => {
+                        AiBuildOrderItem::RecruitMarine(times)
+                    }
+                    SavedAiBuildOrderItem::AttackMove(position) => {
+                        AiBuildOrderItem::AttackMove(position.into())
+                    }
+                    SavedAiBuildOrderItem::SetWaypoint(position) => {
+                        AiBuildOrderItem::SetWaypoint(position.into())
+                    }
+                };
+                Some((time, item))
+            })
+            .collect(),
+    );
+
+    *resources.get_mut::<Camera>().unwrap() = Camera::new(
+        save_file.camera_looking_at.into(),
+        save_file.camera_distance,
+    );
+    resources.get_mut::<TotalTime>().unwrap().0 = save_file.total_time;
+    resources.get_mut::<CheeseCoins>().unwrap().0 = save_file.cheese_coins;
+    resources.get_mut::<PlayerSide>().unwrap().0 = save_file.player_side;
+    *resources.get_mut::<Objectives>().unwrap() = save_file.objectives;
+    *resources.get_mut::<GameStats>().unwrap() = save_file.game_stats;
+    *resources.get_mut::<Upgrades>().unwrap() = save_file.upgrades;
+
+    log::info!("Loaded game from {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,238 @@
+use super::*;
+use crate::renderer::{ModelBuffers, ModelInstance};
+use crate::resources::DeltaTime;
+
+// How long a shield lights up for after stopping a bullet.
+const HIT_FLASH_TIME: f32 = 0.2;
+const SHIELD_COLOUR: Vec3 = Vec3::new(0.3, 0.6, 1.0);
+
+// Stats for units that project a shield, from `units.toml`.
+#[derive(Clone, Copy, Debug, serde::Deserialize)]
+pub struct ShieldStats {
+    pub radius: f32,
+    pub hitpoints: f32,
+    // Hitpoints per second.
+    pub regeneration: f32,
+    // How long the shield has to go without being hit before it starts regenerating.
+    pub regeneration_delay: f32,
+}
+
+// A dome around a unit that stops enemy bullets from getting through until its hitpoints run out.
+pub struct Shield {
+    pub stats: ShieldStats,
+    pub hitpoints: f32,
+    pub since_hit: f32,
+}
+
+impl Shield {
+    pub fn new(stats: ShieldStats) -> Self {
+        Self {
+            stats,
+            hitpoints: stats.hitpoints,
+            // So it doesn't start off flashing.
+            since_hit: f32::INFINITY,
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        self.hitpoints > 0.0
+    }
+
+    fn absorb(&mut self, damage: f32) {
+        self.hitpoints = (self.hitpoints - damage).max(0.0);
+        self.since_hit = 0.0;
+    }
+}
+
+// Bullets are stopped when they pass into an enemy shield. Shots fired from inside a shield still
+// get out though, otherwise you could just walk up to a shielded unit and shoot it.
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(Bullet)]
+#[read_component(Position)]
+#[read_component(Side)]
+#[write_component(Shield)]
+pub fn intercept_bullets(world: &mut SubWorld, buffer: &mut CommandBuffer) {
+    let shields: Vec<(Entity, Vec2, Side, f32)> = <(Entity, &Position, &Side, &Shield)>::query()
+        .iter(world)
+        .filter(|(.., shield)| shield.is_up())
+        .map(|(entity, position, side, shield)| (*entity, position.0, *side, shield.stats.radius))
+        .collect();
+
+    if shields.is_empty() {
+        return;
+    }
+
+    let mut interceptions = Vec::new();
+
+    for (entity, bullet, position) in <(Entity, &Bullet, &Position)>::query().iter(world) {
+        let intercepted_by = shields.iter().find(|(_, center, side, radius)| {
+            *side != bullet.side
+                && (position.0 - *center).mag_sq() <= radius.powi(2)
+                && (bullet.start - *center).mag_sq() > radius.powi(2)
+        });
+
+        if let Some((shield_entity, ..)) = intercepted_by {
+            interceptions.push((*entity, *shield_entity, bullet.damage));
+        }
+    }
+
+    for (bullet_entity, shield_entity, damage) in interceptions {
+        // An earlier bullet this tick might have already brought the shield down.
+        if let Ok(shield) = <&mut Shield>::query().get_mut(world, shield_entity) {
+            if shield.is_up() {
+                shield.absorb(damage);
+                buffer.remove(bullet_entity);
+            }
+        }
+    }
+}
+
+#[legion::system(for_each)]
+pub fn regenerate_shields(shield: &mut Shield, #[resource] delta_time: &DeltaTime) {
+    shield.since_hit += delta_time.0;
+
+    if shield.since_hit >= shield.stats.regeneration_delay {
+        shield.hitpoints = (shield.hitpoints + shield.stats.regeneration * delta_time.0)
+            .min(shield.stats.hitpoints);
+    }
+}
+
+#[legion::system(for_each)]
+pub fn render_shields(
+    position: &Position,
+    side: &Side,
+    shield: &Shield,
+    cloaked: Option<&Cloaked>,
+    #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] terrain: &Terrain,
+    #[resource] player_side: &PlayerSide,
+    #[resource] fog_of_war: &FogOfWar,
+) {
+    if !shield.is_up()
+        || !fog_of_war.can_see(player_side.0, *side, position.0)
+        || stealth::is_hidden(cloaked, player_side.0, *side)
+    {
+        return;
+    }
+
+    let strength = shield.hitpoints / shield.stats.hitpoints;
+    let flash = (1.0 - shield.since_hit / HIT_FLASH_TIME).max(0.0);
+    let alpha = 0.1 + strength * 0.15 + flash * 0.3;
+
+    let ground = Vec3::new(position.0.x, terrain.height_at(position.0), position.0.y);
+    let radius = shield.stats.radius;
+
+    // The bottom half of the sphere is underground, which leaves a dome.
+    model_buffers.shields.push(ModelInstance {
+        transform: Mat4::from_translation(ground)
+            * Mat4::from_nonuniform_scale(Vec3::new(radius, radius * 0.6, radius)),
+        flat_colour: Vec4::new(
+            SHIELD_COLOUR.x + flash,
+            SHIELD_COLOUR.y + flash,
+            SHIELD_COLOUR.z,
+            alpha,
+        ),
+    });
+}
+
+#[test]
+fn shields_stop_enemy_bullets_from_outside() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    resources.insert(DeltaTime(1.0));
+
+    let stats = ShieldStats {
+        radius: 5.0,
+        hitpoints: 15.0,
+        regeneration: 5.0,
+        regeneration_delay: 2.0,
+    };
+
+    let shield = world.push((Position(Vec2::zero()), Side::Green, Shield::new(stats)));
+    let source = world.push((Position(Vec2::new(20.0, 0.0)), Side::Purple));
+
+    let bullet = |world: &mut World, start: Vec2, position: Vec2, side: Side| {
+        world.push((
+            Position(position),
+            Bullet {
+                source,
+                target: shield,
+                side,
+                damage: 10.0,
+                area_of_effect: None,
+                start,
+                impact: Vec2::zero(),
+            },
+        ))
+    };
+
+    let incoming = bullet(
+        &mut world,
+        Vec2::new(20.0, 0.0),
+        Vec2::new(4.0, 0.0),
+        Side::Purple,
+    );
+    let still_outside = bullet(
+        &mut world,
+        Vec2::new(20.0, 0.0),
+        Vec2::new(8.0, 0.0),
+        Side::Purple,
+    );
+    let from_inside = bullet(
+        &mut world,
+        Vec2::new(1.0, 0.0),
+        Vec2::new(3.0, 0.0),
+        Side::Purple,
+    );
+    let friendly = bullet(
+        &mut world,
+        Vec2::new(20.0, 0.0),
+        Vec2::new(4.0, 0.0),
+        Side::Green,
+    );
+
+    let mut schedule = Schedule::builder()
+        .add_system(intercept_bullets_system())
+        .flush()
+        .add_system(regenerate_shields_system())
+        .build();
+    schedule.execute(&mut world, &mut resources);
+
+    assert!(world.entry(incoming).is_none());
+    assert!(world.entry(still_outside).is_some());
+    assert!(world.entry(from_inside).is_some());
+    assert!(world.entry(friendly).is_some());
+
+    let hitpoints = |world: &World| {
+        <&Shield>::query()
+            .get(world, shield)
+            .map(|shield| shield.hitpoints)
+            .unwrap()
+    };
+
+    // Just been hit, so it doesn't regenerate yet.
+    assert_eq!(hitpoints(&world), 5.0);
+
+    // A second bullet takes the shield down, and the one after that gets through.
+    let second = bullet(
+        &mut world,
+        Vec2::new(20.0, 0.0),
+        Vec2::new(4.0, 0.0),
+        Side::Purple,
+    );
+    let third = bullet(
+        &mut world,
+        Vec2::new(20.0, 0.0),
+        Vec2::new(4.5, 0.0),
+        Side::Purple,
+    );
+    schedule.execute(&mut world, &mut resources);
+    assert!(world.entry(second).is_none());
+    assert!(world.entry(third).is_some());
+    assert_eq!(hitpoints(&world), 0.0);
+
+    // Then it comes back once it's been left alone for long enough.
+    schedule.execute(&mut world, &mut resources);
+    assert_eq!(hitpoints(&world), 5.0);
+}
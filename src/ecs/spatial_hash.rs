@@ -0,0 +1,173 @@
+use super::*;
+use std::collections::HashMap;
+
+// About as far as units look for targets, so most queries only have to check a handful of cells.
+const CELL_SIZE: f32 = 10.0;
+
+// Units and buildings bucketed into a grid by position, so that finding everything near a point
+// doesn't mean looking at every entity in the world. It's rebuilt at the start of each tick, so
+// anything that's moved, spawned or died since then is a bit out of date. Check the actual positions
+// of whatever comes out of it.
+#[derive(Default)]
+pub struct SpatialHash {
+    cells: HashMap<(i32, i32), Vec<(Entity, Vec2, f32)>>,
+    // The biggest radius of anything in the grid. Big things can overlap a query from a cell or two
+    // away.
+    max_radius: f32,
+}
+
+impl SpatialHash {
+    fn cell(position: Vec2) -> (i32, i32) {
+        (
+            (position.x / CELL_SIZE).floor() as i32,
+            (position.y / CELL_SIZE).floor() as i32,
+        )
+    }
+
+    fn clear(&mut self) {
+        // Hold onto the allocations for the next tick.
+        for entities in self.cells.values_mut() {
+            entities.clear();
+        }
+        self.max_radius = 0.0;
+    }
+
+    fn insert(&mut self, entity: Entity, position: Vec2, radius: f32) {
+        self.cells
+            .entry(Self::cell(position))
+            .or_default()
+            .push((entity, position, radius));
+        self.max_radius = self.max_radius.max(radius);
+    }
+
+    // Everything that's within `radius` of `position`, measured to the edge of its own radius. The
+    // cells are always gone through in the same order, and everything in a cell was sorted when it
+    // went in, so that every peer gets the same results.
+    pub fn query_radius(&self, position: Vec2, radius: f32) -> impl Iterator<Item = Entity> + '_ {
+        let reach = Vec2::broadcast(radius + self.max_radius);
+        let (min_x, min_y) = Self::cell(position - reach);
+        let (max_x, max_y) = Self::cell(position + reach);
+
+        (min_x..=max_x)
+            .flat_map(move |x| (min_y..=max_y).map(move |y| (x, y)))
+            .filter_map(move |cell| self.cells.get(&cell))
+            .flatten()
+            .filter(move |(_, entity_position, entity_radius)| {
+                (*entity_position - position).mag() - entity_radius <= radius
+            })
+            .map(|(entity, ..)| *entity)
+    }
+}
+
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(Position)]
+#[read_component(Radius)]
+#[read_component(Side)]
+#[read_component(NetworkId)]
+pub fn update_spatial_hash(#[resource] spatial_hash: &mut SpatialHash, world: &SubWorld) {
+    spatial_hash.clear();
+
+    let mut entities: Vec<_> = <(Entity, &Position, Option<&Radius>, Option<&NetworkId>)>::query()
+        .filter(component::<Side>())
+        .iter(world)
+        .map(|(entity, position, radius, network_id)| {
+            (
+                *entity,
+                position.0,
+                radius.map_or(0.0, |radius| radius.0),
+                network_id.map(|network_id| network_id.0),
+            )
+        })
+        .collect();
+
+    // Legion doesn't iterate over entities in the same order on every machine.
+    entities.sort_by_key(|(_, position, _, network_id)| {
+        (
+            ordered_float::OrderedFloat(position.x),
+            ordered_float::OrderedFloat(position.y),
+            *network_id,
+        )
+    });
+
+    for (entity, position, radius, _) in entities {
+        spatial_hash.insert(entity, position, radius);
+    }
+}
+
+#[test]
+fn spatial_hash_queries_match_checking_everything() {
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+
+    let mut world = World::default();
+    let mut rng = SmallRng::seed_from_u64(0);
+
+    for _ in 0..500 {
+        let position = Vec2::new(rng.gen_range(-100.0, 100.0), rng.gen_range(-100.0, 100.0));
+        world.push((
+            Position(position),
+            Radius(rng.gen_range(0.5, 6.0)),
+            Side::Green,
+        ));
+    }
+
+    let mut resources = Resources::default();
+    resources.insert(SpatialHash::default());
+    let mut schedule = Schedule::builder()
+        .add_system(update_spatial_hash_system())
+        .build();
+    schedule.execute(&mut world, &mut resources);
+
+    let spatial_hash = resources.get::<SpatialHash>().unwrap();
+
+    for _ in 0..50 {
+        let center = Vec2::new(rng.gen_range(-110.0, 110.0), rng.gen_range(-110.0, 110.0));
+        let radius = rng.gen_range(0.0, 25.0);
+
+        let expected: HashSet<Entity> = <(Entity, &Position, &Radius)>::query()
+            .iter(&world)
+            .filter(|(_, position, entity_radius)| {
+                (position.0 - center).mag() - entity_radius.0 <= radius
+            })
+            .map(|(entity, ..)| *entity)
+            .collect();
+        let found: Vec<Entity> = spatial_hash.query_radius(center, radius).collect();
+
+        assert_eq!(found.len(), expected.len());
+        assert_eq!(found.into_iter().collect::<HashSet<_>>(), expected);
+    }
+}
+
+#[test]
+fn spatial_hash_queries_come_out_in_the_same_order() {
+    let positions: Vec<Vec2> = (0..20)
+        .map(|i| Vec2::new((i % 5) as f32 * 3.0, (i / 5) as f32 * 2.0))
+        .collect();
+
+    let query = |positions: &mut dyn Iterator<Item = Vec2>| {
+        let mut world = World::default();
+        for position in positions {
+            world.push((Position(position), Side::Green));
+        }
+
+        let mut resources = Resources::default();
+        resources.insert(SpatialHash::default());
+        let mut schedule = Schedule::builder()
+            .add_system(update_spatial_hash_system())
+            .build();
+        schedule.execute(&mut world, &mut resources);
+
+        let spatial_hash = resources.get::<SpatialHash>().unwrap();
+        spatial_hash
+            .query_radius(Vec2::new(5.0, 3.0), 20.0)
+            .map(|entity| <&Position>::query().get(&world, entity).unwrap().0)
+            .collect::<Vec<_>>()
+    };
+
+    let forwards = query(&mut positions.iter().copied());
+    let backwards = query(&mut positions.iter().rev().copied());
+
+    assert_eq!(forwards.len(), positions.len());
+    assert_eq!(forwards, backwards);
+}
@@ -0,0 +1,220 @@
+use super::*;
+use crate::renderer::{ModelBuffers, ModelInstance};
+use crate::resources::{DeltaTime, TotalTime};
+use ultraviolet::Vec4;
+
+// Cloaked units can't be seen or shot at by the other side unless one of their detectors is close
+// enough. The owner still sees them, with a shimmer around them so they know the cloak is up.
+pub struct Cloaked {
+    // Burrowed units stay hidden for as long as they're in the ground.
+    pub remaining: Option<f32>,
+    // Set each tick by `update_detection`.
+    pub detected: bool,
+}
+
+impl Cloaked {
+    pub fn new(duration: f32) -> Self {
+        Self {
+            remaining: Some(duration),
+            detected: false,
+        }
+    }
+
+    pub fn permanent() -> Self {
+        Self {
+            remaining: None,
+            detected: false,
+        }
+    }
+}
+
+// Reveals cloaked enemies within this range. Buildings only detect once they're finished.
+pub struct Detector(pub f32);
+
+// Armouries can see cloaked units that sneak into a base.
+pub(super) const ARMOURY_DETECTION_RANGE: f32 = 15.0;
+
+const SHIMMER_COLOUR: Vec3 = Vec3::new(0.7, 0.9, 1.0);
+// How many times a second the shimmer pulses.
+const SHIMMER_SPEED: f32 = 1.5;
+
+// Whether something that belongs to `owner` is invisible to `viewer` because of a cloak, on top of
+// whatever the fog of war hides.
+pub(super) fn is_hidden(cloaked: Option<&Cloaked>, viewer: Side, owner: Side) -> bool {
+    viewer != owner && matches!(cloaked, Some(cloaked) if !cloaked.detected)
+}
+
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Side)]
+#[read_component(Detector)]
+#[read_component(Building)]
+#[read_component(FullyBuilt)]
+#[write_component(Cloaked)]
+pub fn update_detection(world: &mut SubWorld) {
+    let detectors: Vec<(Vec2, Side, f32)> = <(
+        &Position,
+        &Side,
+        &Detector,
+        Option<&Building>,
+        Option<&FullyBuilt>,
+    )>::query()
+    .iter(world)
+    .filter(|(.., building, fully_built)| building.is_none() || fully_built.is_some())
+    .map(|(position, side, detector, ..)| (position.0, *side, detector.0))
+    .collect();
+
+    for (position, side, cloaked) in <(&Position, &Side, &mut Cloaked)>::query().iter_mut(world) {
+        cloaked.detected = detectors.iter().any(|(detector, detector_side, range)| {
+            detector_side != side && (*detector - position.0).mag_sq() <= range * range
+        });
+    }
+}
+
+#[legion::system(for_each)]
+pub fn wear_off_cloaks(
+    entity: &Entity,
+    cloaked: &mut Cloaked,
+    #[resource] delta_time: &DeltaTime,
+    buffer: &mut CommandBuffer,
+) {
+    if let Some(remaining) = cloaked.remaining.as_mut() {
+        *remaining -= delta_time.0;
+
+        if *remaining <= 0.0 {
+            buffer.remove_component::<Cloaked>(*entity);
+        }
+    }
+}
+
+// Units that were going after something that's just cloaked lose track of it. Units only start
+// attacking things they can see, so this only matters for the one that they're attacking now.
+#[legion::system(for_each)]
+#[read_component(Side)]
+#[read_component(Cloaked)]
+// Side is read through the world instead of being a parameter, so that the target's can be too.
+#[filter(component::<Side>())]
+pub fn lose_track_of_cloaked_targets(
+    entity: &Entity,
+    commands: &mut CommandQueue,
+    world: &SubWorld,
+) {
+    if let Some(Command::Attack { target, .. }) = commands.0.front() {
+        let side = <&Side>::query()
+            .get(world, *entity)
+            .expect("We've applied a filter to this system for Side");
+
+        if let Ok((target_side, cloaked)) = <(&Side, &Cloaked)>::query().get(world, *target) {
+            if is_hidden(Some(cloaked), *side, *target_side) {
+                commands.0.pop_front();
+            }
+        }
+    }
+}
+
+// Burrowed units are already half hidden in the ground.
+#[legion::system(for_each)]
+#[filter(!component::<Burrow>())]
+pub fn render_cloak_shimmer(
+    position: &Position,
+    side: &Side,
+    radius: &Radius,
+    cloaked: &Cloaked,
+    #[resource] model_buffers: &mut ModelBuffers,
+    #[resource] player_side: &PlayerSide,
+    #[resource] total_time: &TotalTime,
+    #[resource] terrain: &Terrain,
+) {
+    if *side != player_side.0 {
+        return;
+    }
+
+    let pulse = (total_time.0 * SHIMMER_SPEED * std::f32::consts::TAU).sin() * 0.5 + 0.5;
+    // Fades out over the last second, as a warning that it's about to wear off.
+    let fading = cloaked.remaining.unwrap_or(1.0).min(1.0);
+    let size = radius.0 * (1.4 + pulse * 0.2);
+
+    model_buffers.shields.push(ModelInstance {
+        transform: Mat4::from_translation(terrain.ground(position.0))
+            * Mat4::from_nonuniform_scale(Vec3::new(size, size * 1.5, size)),
+        flat_colour: Vec4::new(
+            SHIMMER_COLOUR.x,
+            SHIMMER_COLOUR.y,
+            SHIMMER_COLOUR.z,
+            (0.15 + pulse * 0.2) * fading,
+        ),
+    });
+}
+
+#[test]
+fn attackers_lose_track_of_targets_that_cloak() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    let mut schedule = Schedule::builder()
+        .add_system(lose_track_of_cloaked_targets_system())
+        .build();
+
+    let cloaked = world.push((Position(Vec2::zero()), Side::Green, Cloaked::new(10.0)));
+    let visible = world.push((Position(Vec2::zero()), Side::Green));
+    let attacker = |world: &mut World, target| {
+        world.push((
+            Side::Purple,
+            CommandQueue(vec![Command::new_attack(target, true)].into()),
+        ))
+    };
+    let cloaked_attacker = attacker(&mut world, cloaked);
+    let visible_attacker = attacker(&mut world, visible);
+    schedule.execute(&mut world, &mut resources);
+
+    let commands = |entity| {
+        <&CommandQueue>::query()
+            .get(&world, entity)
+            .unwrap()
+            .0
+            .len()
+    };
+    assert_eq!(commands(cloaked_attacker), 0);
+    assert_eq!(commands(visible_attacker), 1);
+}
+
+#[test]
+fn detectors_reveal_cloaked_enemies_in_range() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    let mut schedule = Schedule::builder()
+        .add_system(update_detection_system())
+        .build();
+
+    let cloaked = world.push((Position(Vec2::zero()), Side::Green, Cloaked::new(10.0)));
+    let detected = |world: &World| <&Cloaked>::query().get(world, cloaked).unwrap().detected;
+
+    // Friendly detectors don't count.
+    world.push((Position(Vec2::new(5.0, 0.0)), Side::Green, Detector(10.0)));
+    schedule.execute(&mut world, &mut resources);
+    assert!(!detected(&world));
+    assert!(is_hidden(
+        <&Cloaked>::query().get(&world, cloaked).ok(),
+        Side::Purple,
+        Side::Green
+    ));
+
+    // Nor do armouries that are still being built.
+    let armoury = world.push((
+        Position(Vec2::new(5.0, 0.0)),
+        Side::Purple,
+        Building::Armoury,
+        Detector(10.0),
+    ));
+    schedule.execute(&mut world, &mut resources);
+    assert!(!detected(&world));
+
+    world.entry(armoury).unwrap().add_component(FullyBuilt);
+    schedule.execute(&mut world, &mut resources);
+    assert!(detected(&world));
+
+    // Out of range.
+    world.remove(armoury);
+    world.push((Position(Vec2::new(20.0, 0.0)), Side::Purple, Detector(10.0)));
+    schedule.execute(&mut world, &mut resources);
+    assert!(!detected(&world));
+}
@@ -0,0 +1,131 @@
+use super::*;
+use crate::audio::{Sound, SoundEvents};
+use crate::resources::{DeltaTime, TotalTime};
+
+// The silo is the late game superweapon. Once it's been built it takes a few minutes to charge,
+// which both players can see counting down, then it can strike anywhere on the map. The strike
+// lands a few seconds after it's launched, and the other player is warned about where it's going to
+// land so they have a chance to get out of the way.
+pub(super) const CHARGE_TIME: f32 = 180.0;
+pub(super) const STRIKE_DELAY: f32 = 8.0;
+pub(super) const STRIKE_RADIUS: f32 = 12.0;
+// Enough to take out an armoury that's right in the middle of it.
+const STRIKE_DAMAGE: f32 = 600.0;
+// How often the target flares up on the defender's minimap while the strike is on its way.
+const PING_INTERVAL: f32 = 2.0;
+
+// Squashes the armoury model down to the silo's footprint and stretches it up.
+pub(super) fn model_transform() -> Mat4 {
+    Mat4::from_nonuniform_scale(Vec3::new(1.0, 1.6, 0.6))
+}
+
+// On silos. Only counts down once the silo has been built.
+pub struct Superweapon {
+    pub remaining: f32,
+}
+
+impl Default for Superweapon {
+    fn default() -> Self {
+        Self {
+            remaining: CHARGE_TIME,
+        }
+    }
+}
+
+impl Superweapon {
+    pub fn is_ready(&self) -> bool {
+        self.remaining == 0.0
+    }
+}
+
+// A strike on its way down.
+pub struct Strike {
+    pub target: Vec2,
+    pub side: Side,
+    // The silo that launched it, which gets the blame for the damage.
+    pub source: Entity,
+    pub remaining: f32,
+}
+
+#[legion::system(for_each)]
+#[filter(component::<FullyBuilt>())]
+pub fn charge_superweapons(superweapon: &mut Superweapon, #[resource] delta_time: &DeltaTime) {
+    superweapon.remaining = (superweapon.remaining - delta_time.0).max(0.0);
+}
+
+// Called when a strike is launched, so both players hear about it straight away.
+pub(super) fn announce_strike(
+    strike: &Strike,
+    player_side: &PlayerSide,
+    alerts: &mut Alerts,
+    sound_events: &mut SoundEvents,
+    time: f32,
+) {
+    if strike.side == player_side.0 {
+        sound_events.play(Sound::Acknowledgement);
+    } else {
+        alerts.ping(strike.target, time);
+        sound_events.play(Sound::Alert);
+    }
+}
+
+#[legion::system(for_each)]
+pub fn progress_strikes(
+    entity: &Entity,
+    strike: &mut Strike,
+    #[resource] delta_time: &DeltaTime,
+    #[resource] total_time: &TotalTime,
+    #[resource] player_side: &PlayerSide,
+    #[resource] alerts: &mut Alerts,
+    buffer: &mut CommandBuffer,
+) {
+    let before = strike.remaining;
+    strike.remaining = (strike.remaining - delta_time.0).max(0.0);
+
+    if strike.side != player_side.0
+        && crossed_ping(before, strike.remaining)
+        && strike.remaining > 0.0
+    {
+        alerts.ping(strike.target, total_time.0);
+    }
+
+    if strike.remaining > 0.0 {
+        return;
+    }
+
+    // It comes straight down, so `apply_bullets` sets it off on the next tick.
+    buffer.push((
+        Position(strike.target),
+        Bullet {
+            source: strike.source,
+            target: strike.source,
+            side: strike.side,
+            damage: STRIKE_DAMAGE,
+            area_of_effect: Some(STRIKE_RADIUS),
+            start: strike.target,
+            impact: strike.target,
+        },
+        Facing(0.0),
+        MaxSpeed(0.0),
+    ));
+    buffer.remove(*entity);
+}
+
+// Whether the countdown passed a multiple of `PING_INTERVAL` this tick.
+fn crossed_ping(before: f32, after: f32) -> bool {
+    (before / PING_INTERVAL).ceil() != (after / PING_INTERVAL).ceil()
+}
+
+#[test]
+fn strikes_ping_every_couple_of_seconds() {
+    let pings = (0..STRIKE_DELAY as usize * 10)
+        .map(|tick| {
+            let before = STRIKE_DELAY - tick as f32 * 0.1;
+            (before, (before - 0.1).max(0.0))
+        })
+        .filter(|(before, after)| crossed_ping(*before, *after) && *after > 0.0)
+        .count();
+
+    // At 6, 4 and 2 seconds to go. It's already been announced at 8, and it's landed at 0.
+    assert_eq!(pings, 3);
+}
@@ -0,0 +1,110 @@
+use super::*;
+
+// The tech tree. Some things on the command card need a building to be finished or an upgrade to
+// be researched first. They still show up on the command card, greyed out, with a tooltip saying
+// what's missing.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Prerequisite {
+    Building(Building),
+    Upgrade(Upgrade),
+}
+
+impl Prerequisite {
+    fn label(self) -> String {
+        match self {
+            Self::Building(building) => format!("a {:?}", building),
+            Self::Upgrade(upgrade) => format!("{} research", upgrade.stats().label),
+        }
+    }
+}
+
+fn prerequisites(ability_type: &AbilityType) -> Vec<Prerequisite> {
+    let others = match ability_type {
+        // Armouries cost more than you can afford without any income.
+        AbilityType::Build(Building::Armoury) => vec![Prerequisite::Building(Building::Pump)],
+        AbilityType::Research(Upgrade::Grenades) => vec![Prerequisite::Upgrade(Upgrade::Attack)],
+        // The superweapon is for late in the game.
+        AbilityType::Build(Building::Silo) => vec![
+            Prerequisite::Building(Building::Armoury),
+            Prerequisite::Upgrade(Upgrade::Attack),
+        ],
+        _ => Vec::new(),
+    };
+
+    Upgrade::unlocking(ability_type)
+        .map(Prerequisite::Upgrade)
+        .into_iter()
+        .chain(others)
+        .collect()
+}
+
+// What a side still needs before it can use an ability. Needs to read `Building`, `Side` and
+// `FullyBuilt`.
+pub fn missing_prerequisites(
+    ability_type: &AbilityType,
+    side: Side,
+    upgrades: &Upgrades,
+    world: &impl EntityStore,
+) -> Vec<Prerequisite> {
+    prerequisites(ability_type)
+        .into_iter()
+        .filter(|prerequisite| match prerequisite {
+            Prerequisite::Building(building) => !<(&Building, &Side)>::query()
+                .filter(component::<FullyBuilt>())
+                .iter(world)
+                .any(|(built, built_side)| built == building && *built_side == side),
+            Prerequisite::Upgrade(upgrade) => !upgrades.has(side, *upgrade),
+        })
+        .collect()
+}
+
+pub fn tooltip(missing: &[Prerequisite]) -> String {
+    let labels: Vec<_> = missing
+        .iter()
+        .map(|prerequisite| prerequisite.label())
+        .collect();
+
+    format!("Needs {}", labels.join(" and "))
+}
+
+#[test]
+fn prerequisites_are_checked_per_side() {
+    let mut world = World::default();
+    let mut upgrades = Upgrades::default();
+    let armoury = AbilityType::Build(Building::Armoury);
+    let grenades = AbilityType::Research(Upgrade::Grenades);
+    let medic = AbilityType::Recruit(Unit::Medic);
+    let marine = AbilityType::Recruit(Unit::MouseMarine);
+
+    assert!(missing_prerequisites(&marine, Side::Green, &upgrades, &world).is_empty());
+    assert_eq!(
+        missing_prerequisites(&medic, Side::Green, &upgrades, &world),
+        vec![Prerequisite::Upgrade(Upgrade::FieldMedicine)]
+    );
+    assert_eq!(
+        tooltip(&missing_prerequisites(
+            &grenades,
+            Side::Green,
+            &upgrades,
+            &world
+        )),
+        "Needs Attack +1 research"
+    );
+
+    // Pumps that are still being built don't count.
+    world.push((Building::Pump, Side::Green));
+    assert_eq!(
+        missing_prerequisites(&armoury, Side::Green, &upgrades, &world),
+        vec![Prerequisite::Building(Building::Pump)]
+    );
+
+    world.push((Building::Pump, Side::Green, FullyBuilt));
+    upgrades.research(Side::Green, Upgrade::Attack);
+
+    assert!(missing_prerequisites(&armoury, Side::Green, &upgrades, &world).is_empty());
+    assert!(missing_prerequisites(&grenades, Side::Green, &upgrades, &world).is_empty());
+    assert_eq!(
+        missing_prerequisites(&armoury, Side::Purple, &upgrades, &world),
+        vec![Prerequisite::Building(Building::Pump)]
+    );
+}
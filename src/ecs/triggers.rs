@@ -0,0 +1,176 @@
+use super::{Position, Side, Unit};
+use crate::assets::ModelAnimations;
+use crate::mission::{wrap, Action, Condition, DialogueLine, Mission};
+use crate::renderer::{Font, LineBuffers, TextAlignment, TextBuffer};
+use crate::resources::{
+    CheeseCoins, DpiScaling, Mode, Objectives, Outcome, ScreenDimensions, TotalTime,
+    UnscaledDeltaTime,
+};
+use legion::systems::CommandBuffer;
+use legion::world::SubWorld;
+use legion::{component, IntoQuery};
+use std::collections::HashSet;
+use ultraviolet::{Vec2, Vec4};
+
+const DIALOGUE_BOX_SIZE: Vec2 = Vec2::new(560.0, 100.0);
+const DIALOGUE_PADDING: f32 = 10.0;
+const DIALOGUE_BACKGROUND: Vec4 = Vec4::new(0.0, 0.0, 0.0, 0.75);
+const PORTRAIT_SIZE: f32 = 80.0;
+const SPEAKER_COLOUR: Vec4 = Vec4::new(1.0, 0.9, 0.5, 1.0);
+// Roughly how many characters fit across the box next to the portrait.
+const DIALOGUE_LINE_WIDTH: usize = 60;
+
+// Marks entities that a mission's triggers can refer to.
+pub struct ScriptTag(pub String);
+
+#[legion::system]
+#[read_component(ScriptTag)]
+#[read_component(Position)]
+#[read_component(Side)]
+pub fn run_mission_triggers(
+    #[resource] mission: &mut Mission,
+    #[resource] total_time: &TotalTime,
+    #[resource] delta_time: &UnscaledDeltaTime,
+    #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] objectives: &mut Objectives,
+    #[resource] animations: &ModelAnimations,
+    #[resource] mode: &mut Mode,
+    buffer: &mut CommandBuffer,
+    world: &SubWorld,
+) {
+    let Mission {
+        triggers,
+        fired,
+        dialogue,
+        seen_tags,
+    } = mission;
+
+    let finished = dialogue.front_mut().is_some_and(|line| {
+        line.shown_for += delta_time.0;
+        line.shown_for >= line.duration()
+    });
+    if finished {
+        dialogue.pop_front();
+    }
+
+    let alive_tags: HashSet<&str> = <&ScriptTag>::query()
+        .iter(world)
+        .map(|tag| tag.0.as_str())
+        .collect();
+    seen_tags.extend(alive_tags.iter().map(|tag| tag.to_string()));
+
+    for (trigger, fired) in triggers.iter().zip(fired.iter_mut()) {
+        if *fired {
+            continue;
+        }
+
+        let met = match &trigger.when {
+            Condition::Timer { seconds } => total_time.0 >= *seconds,
+            // Waves that haven't been spawned yet can't have died.
+            Condition::UnitDied { tag } => {
+                seen_tags.contains(tag) && !alive_tags.contains(tag.as_str())
+            }
+            Condition::RegionEntered {
+                center,
+                radius,
+                side,
+            } => {
+                let center = Vec2::from(*center);
+                <(&Position, &Side)>::query()
+                    .filter(component::<Unit>())
+                    .iter(world)
+                    .any(|(position, unit_side)| {
+                        unit_side == side && (position.0 - center).mag() <= *radius
+                    })
+            }
+        };
+
+        if !met {
+            continue;
+        }
+
+        *fired = true;
+
+        for action in &trigger.actions {
+            match action {
+                Action::SpawnWave(group) => group.spawn(buffer, animations),
+                Action::Dialogue {
+                    speaker,
+                    text,
+                    portrait,
+                    game_speed,
+                } => dialogue.push_back(DialogueLine {
+                    speaker: speaker.clone(),
+                    text: text.clone(),
+                    portrait: *portrait,
+                    game_speed: *game_speed,
+                    shown_for: 0.0,
+                }),
+                Action::AddObjective { objective } => {
+                    objectives.win_conditions.push(objective.clone())
+                }
+                Action::GiveCheese { amount } => cheese_coins.0 += amount,
+                Action::Win => *mode = Mode::GameOver(Outcome::Won),
+                Action::Lose => *mode = Mode::GameOver(Outcome::Lost),
+            }
+        }
+    }
+}
+
+#[legion::system]
+pub fn render_mission_dialogue(
+    #[resource] mission: &Mission,
+    #[resource] text_buffer: &mut TextBuffer,
+    #[resource] line_buffers: &mut LineBuffers,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+) {
+    let line = match mission.dialogue.front() {
+        Some(line) => line,
+        None => return,
+    };
+
+    let dpi = dpi_scaling.0;
+    let center = Vec2::new(
+        screen_dimensions.as_vec().x / 2.0,
+        (DIALOGUE_BOX_SIZE.y / 2.0 + 40.0) * dpi,
+    );
+    let top_left = center - DIALOGUE_BOX_SIZE / 2.0 * dpi;
+
+    line_buffers.draw_filled_rect(center, DIALOGUE_BOX_SIZE, DIALOGUE_BACKGROUND, dpi);
+
+    let mut text_left = top_left.x + DIALOGUE_PADDING * dpi;
+
+    if let Some(portrait) = line.portrait {
+        line_buffers.draw_image(
+            top_left + Vec2::broadcast(DIALOGUE_PADDING + PORTRAIT_SIZE / 2.0) * dpi,
+            Vec2::broadcast(PORTRAIT_SIZE),
+            portrait,
+            false,
+            dpi,
+        );
+        text_left += (PORTRAIT_SIZE + DIALOGUE_PADDING) * dpi;
+    }
+
+    let text_top = top_left.y + DIALOGUE_PADDING * dpi;
+
+    text_buffer.render_text(
+        Vec2::new(text_left, text_top),
+        &line.speaker,
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::Default,
+        SPEAKER_COLOUR,
+    );
+
+    text_buffer.render_text(
+        Vec2::new(text_left, text_top + 20.0 * dpi),
+        &wrap(&line.typed_text(), DIALOGUE_LINE_WIDTH),
+        Font::Ui,
+        1.0,
+        dpi,
+        TextAlignment::Default,
+        Vec4::new(1.0, 1.0, 1.0, 1.0),
+    );
+}
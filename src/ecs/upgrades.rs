@@ -0,0 +1,219 @@
+use super::*;
+use crate::resources::DeltaTime;
+use std::collections::BTreeSet;
+
+// Upgrades are researched at armouries, one at a time in each armoury, and last for the rest of the
+// game. Each side has its own. Instead of changing components when an upgrade finishes, systems
+// look up what a side's upgrades add up to when they need a stat (see `Upgrades::modifiers`), so
+// units recruited after an upgrade get it too. Some abilities and units can't be used at all until
+// the upgrade that unlocks them has been researched, see `tech::missing_prerequisites`.
+#[derive(
+    Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, serde::Serialize, serde::Deserialize,
+)]
+pub enum Upgrade {
+    Attack,
+    Harvesting,
+    Grenades,
+    FieldMedicine,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum UpgradeEffect {
+    // Added on to every bullet fired, before the high ground and experience multipliers.
+    BulletDamage(f32),
+    // How many more cheese coins each pump makes at a time.
+    CheesePerPump(u32),
+    UnlockAbility(ActiveAbility),
+    UnlockUnit(Unit),
+}
+
+pub struct UpgradeStats {
+    pub label: &'static str,
+    pub cost: u32,
+    pub research_time: f32,
+    pub effect: UpgradeEffect,
+}
+
+impl Upgrade {
+    pub const ALL: [Self; 4] = [
+        Self::Attack,
+        Self::Harvesting,
+        Self::Grenades,
+        Self::FieldMedicine,
+    ];
+
+    pub fn stats(self) -> UpgradeStats {
+        match self {
+            // A quarter more damage for marines.
+            Self::Attack => UpgradeStats {
+                label: "Attack +1",
+                cost: 150,
+                research_time: 40.0,
+                effect: UpgradeEffect::BulletDamage(0.5),
+            },
+            Self::Harvesting => UpgradeStats {
+                label: "Pumps",
+                cost: 100,
+                research_time: 30.0,
+                effect: UpgradeEffect::CheesePerPump(1),
+            },
+            Self::Grenades => UpgradeStats {
+                label: "Grenades",
+                cost: 100,
+                research_time: 25.0,
+                effect: UpgradeEffect::UnlockAbility(ActiveAbility::Grenade),
+            },
+            Self::FieldMedicine => UpgradeStats {
+                label: "Medics",
+                cost: 75,
+                research_time: 20.0,
+                effect: UpgradeEffect::UnlockUnit(Unit::Medic),
+            },
+        }
+    }
+
+    // The upgrade that has to be researched before an ability can be used, if there is one.
+    pub(super) fn unlocking(ability_type: &AbilityType) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|upgrade| match (upgrade.stats().effect, ability_type) {
+                (UpgradeEffect::UnlockAbility(unlocks), AbilityType::Active(ability)) => {
+                    unlocks == *ability
+                }
+                (UpgradeEffect::UnlockUnit(unlocks), AbilityType::Recruit(unit)) => {
+                    unlocks == *unit
+                }
+                _ => false,
+            })
+    }
+}
+
+// What a side's upgrades add up to.
+#[derive(Default, Debug, PartialEq)]
+pub struct Modifiers {
+    pub bullet_damage: f32,
+    pub cheese_per_pump: u32,
+}
+
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Upgrades {
+    green: BTreeSet<Upgrade>,
+    purple: BTreeSet<Upgrade>,
+}
+
+impl Upgrades {
+    fn side(&self, side: Side) -> &BTreeSet<Upgrade> {
+        match side {
+            Side::Green => &self.green,
+            Side::Purple => &self.purple,
+        }
+    }
+
+    fn side_mut(&mut self, side: Side) -> &mut BTreeSet<Upgrade> {
+        match side {
+            Side::Green => &mut self.green,
+            Side::Purple => &mut self.purple,
+        }
+    }
+
+    pub fn has(&self, side: Side, upgrade: Upgrade) -> bool {
+        self.side(side).contains(&upgrade)
+    }
+
+    pub fn modifiers(&self, side: Side) -> Modifiers {
+        self.side(side)
+            .iter()
+            .fold(Modifiers::default(), |mut modifiers, upgrade| {
+                match upgrade.stats().effect {
+                    UpgradeEffect::BulletDamage(damage) => modifiers.bullet_damage += damage,
+                    UpgradeEffect::CheesePerPump(cheese) => modifiers.cheese_per_pump += cheese,
+                    UpgradeEffect::UnlockAbility(_) | UpgradeEffect::UnlockUnit(_) => {}
+                }
+                modifiers
+            })
+    }
+
+    pub(super) fn research(&mut self, side: Side, upgrade: Upgrade) {
+        self.side_mut(side).insert(upgrade);
+    }
+}
+
+// On armouries. An upgrade can only be researched in one place at a time.
+#[derive(Default)]
+pub struct Research {
+    pub current: Option<Upgrade>,
+    pub progress: f32,
+}
+
+// Whether an upgrade is already being researched somewhere on a side. Needs to read `Research` and
+// `Side`.
+pub fn is_researching(upgrade: Upgrade, side: Side, world: &SubWorld) -> bool {
+    <(&Research, &Side)>::query()
+        .iter(world)
+        .any(|(research, research_side)| {
+            *research_side == side && research.current == Some(upgrade)
+        })
+}
+
+#[legion::system(for_each)]
+#[filter(component::<FullyBuilt>())]
+pub fn progress_research(
+    research: &mut Research,
+    side: &Side,
+    #[resource] upgrades: &mut Upgrades,
+    #[resource] delta_time: &DeltaTime,
+) {
+    let upgrade = match research.current {
+        Some(upgrade) => upgrade,
+        None => return,
+    };
+
+    research.progress += delta_time.0 / upgrade.stats().research_time;
+
+    if research.progress >= 1.0 {
+        upgrades.research(*side, upgrade);
+        *research = Research::default();
+    }
+}
+
+#[test]
+fn upgrades_add_up_and_unlock_things() {
+    let mut upgrades = Upgrades::default();
+
+    assert_eq!(upgrades.modifiers(Side::Green), Modifiers::default());
+    assert_eq!(
+        Upgrade::unlocking(&AbilityType::Active(ActiveAbility::Grenade)),
+        Some(Upgrade::Grenades)
+    );
+    assert_eq!(
+        Upgrade::unlocking(&AbilityType::Recruit(Unit::Medic)),
+        Some(Upgrade::FieldMedicine)
+    );
+    assert_eq!(
+        Upgrade::unlocking(&AbilityType::Recruit(Unit::MouseMarine)),
+        None
+    );
+    assert_eq!(
+        Upgrade::unlocking(&AbilityType::Active(ActiveAbility::Heal)),
+        None
+    );
+
+    upgrades.research(Side::Green, Upgrade::Attack);
+    upgrades.research(Side::Green, Upgrade::Harvesting);
+
+    assert_eq!(
+        upgrades.modifiers(Side::Green),
+        Modifiers {
+            bullet_damage: 0.5,
+            cheese_per_pump: 1,
+        }
+    );
+    assert!(upgrades.has(Side::Green, Upgrade::Attack));
+    assert!(!upgrades.has(Side::Green, Upgrade::FieldMedicine));
+
+    // The other side doesn't get any of them.
+    assert_eq!(upgrades.modifiers(Side::Purple), Modifiers::default());
+    assert!(!upgrades.has(Side::Purple, Upgrade::Attack));
+}
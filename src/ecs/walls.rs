@@ -0,0 +1,652 @@
+use super::*;
+use crate::net::{Lockstep, PlayerCommand};
+use crate::pathfinding::segment_intersects_rect;
+use crate::resources::{
+    CheeseCoins, CommandMode, DpiScaling, RayCastLocation, ScreenDimensions, TotalTime,
+};
+use std::ops::Range;
+
+// Walls and gates snap to a grid of this size, with one segment in each cell. It's a bit wider than
+// a mouse, so that they can fit through an open gate.
+pub const WALL_GRID: f32 = 3.0;
+// Constraints that touch can't be inserted into the map, so walls and gates are shrunk by this much
+// on each side to leave a gap between them. Nothing's anywhere near thin enough to get through.
+const WALL_INSET: f32 = 0.05;
+// So that a long drag can't spend all of your cheese coins in one go.
+const MAX_WALL_SEGMENTS: usize = 16;
+// Gates open for friendly units within this distance of their edge.
+const GATE_OPENING_RANGE: f32 = WALL_GRID;
+
+const WALL_HEIGHT: f32 = 2.0;
+const GATE_HEIGHT: f32 = 1.5;
+const OPEN_GATE_HEIGHT: f32 = 0.2;
+
+// A straight line of wall segments that were placed together. Each segment is a building of its
+// own, but the map only gets one rectangle for every unbroken run of them, so there aren't any
+// cracks in between for the pathfinding to try and squeeze through.
+pub struct Wall {
+    // In order from one end to the other.
+    segments: Vec<(Entity, Vec2)>,
+    handles: Vec<MapHandle>,
+}
+
+impl Wall {
+    pub(super) fn segments(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.segments.iter().map(|(segment, _)| *segment)
+    }
+
+    fn positions(&self) -> Vec<Vec2> {
+        self.segments
+            .iter()
+            .map(|(_, position)| *position)
+            .collect()
+    }
+
+    fn runs(&self) -> Vec<&[(Entity, Vec2)]> {
+        runs(&self.positions())
+            .into_iter()
+            .map(|run| &self.segments[run])
+            .collect()
+    }
+
+    fn insert_runs(&mut self, map: &mut Map) {
+        let positions = self.positions();
+
+        self.handles = runs(&positions)
+            .into_iter()
+            .filter_map(|run| {
+                let (center, dimensions) = run_rectangle(&positions[run]);
+                map.insert(center, dimensions)
+            })
+            .collect();
+    }
+}
+
+// The ranges of segments that don't have any gaps in between them.
+fn runs(positions: &[Vec2]) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+
+    for i in 1..=positions.len() {
+        let broken =
+            i == positions.len() || (positions[i] - positions[i - 1]).mag() > WALL_GRID * 1.5;
+
+        if broken {
+            runs.push(start..i);
+            start = i;
+        }
+    }
+
+    runs
+}
+
+// A gate's constraint is disabled in the map while it's open, so paths can go through it. Locking a
+// gate keeps it shut, even for your own units.
+#[derive(Default)]
+pub struct Gate {
+    pub open: bool,
+    pub locked: bool,
+}
+
+pub fn snap_to_wall_grid(position: Vec2) -> Vec2 {
+    let snap = |x: f32| ((x / WALL_GRID).floor() + 0.5) * WALL_GRID;
+    Vec2::new(snap(position.x), snap(position.y))
+}
+
+// The centers of the segments of a wall dragged out from `start` to `end`. Walls only go in a
+// straight line along whichever axis the drag went further in.
+pub fn wall_segments(start: Vec2, end: Vec2) -> Vec<Vec2> {
+    let start = snap_to_wall_grid(start);
+    let delta = snap_to_wall_grid(end) - start;
+
+    let (step, length) = if delta.x.abs() >= delta.y.abs() {
+        (Vec2::new(WALL_GRID.copysign(delta.x), 0.0), delta.x.abs())
+    } else {
+        (Vec2::new(0.0, WALL_GRID.copysign(delta.y)), delta.y.abs())
+    };
+
+    let count = ((length / WALL_GRID).round() as usize + 1).min(MAX_WALL_SEGMENTS);
+
+    (0..count).map(|i| start + step * i as f32).collect()
+}
+
+// What actually goes into the map for a building on the wall grid.
+pub fn footprint(dimensions: Vec2) -> Vec2 {
+    dimensions - Vec2::broadcast(WALL_INSET * 2.0)
+}
+
+// The center and dimensions of the rectangle covering a straight run of segments.
+fn run_rectangle(segments: &[Vec2]) -> (Vec2, Vec2) {
+    let first = segments[0];
+    let last = segments[segments.len() - 1];
+    let extent = last - first;
+
+    (
+        (first + last) / 2.0,
+        footprint(Vec2::new(extent.x.abs(), extent.y.abs()) + Vec2::broadcast(WALL_GRID)),
+    )
+}
+
+pub fn can_place_wall(segments: &[Vec2], map: &Map, world: &SubWorld) -> bool {
+    let (center, dimensions) = run_rectangle(segments);
+
+    map.can_insert(center, dimensions)
+        && !segments
+            .iter()
+            .any(|segment| unit_under_building(*segment, Vec2::broadcast(WALL_GRID), world))
+}
+
+// Returns the segments, in order from the start of the wall, or None if it's blocked. The segments
+// don't all have to be next to each other, as loading a game can bring back a wall with gaps in it.
+pub fn add_wall_to_construct(
+    buffer: &mut CommandBuffer,
+    segments: &[Vec2],
+    side: Side,
+    map: &mut Map,
+) -> Option<Vec<Entity>> {
+    if segments.is_empty() {
+        return None;
+    }
+
+    let blocked = runs(segments).into_iter().any(|run| {
+        let (center, dimensions) = run_rectangle(&segments[run]);
+        !map.can_insert(center, dimensions)
+    });
+
+    if blocked {
+        return None;
+    }
+
+    let stats = Building::Wall.stats();
+
+    let entities: Vec<Entity> = segments
+        .iter()
+        .map(|position| {
+            buffer.push((
+                Position(*position),
+                Building::Wall,
+                Radius(stats.radius),
+                Selectable,
+                side,
+                Health(1.0),
+                BuildingCompleteness(1.0),
+                SightRange(stats.sight_range),
+            ))
+        })
+        .collect();
+
+    let mut wall = Wall {
+        segments: entities
+            .iter()
+            .copied()
+            .zip(segments.iter().copied())
+            .collect(),
+        handles: Vec::new(),
+    };
+
+    wall.insert_runs(map);
+    buffer.push((wall,));
+
+    Some(entities)
+}
+
+// Locks all of the gates, unless they're all locked already, in which case they're unlocked.
+pub fn lock_gates(gates: &[Entity], world: &mut SubWorld) {
+    let lock = gates.iter().any(|gate| {
+        <&Gate>::query()
+            .get(world, *gate)
+            .is_ok_and(|gate| !gate.locked)
+    });
+
+    for gate in gates {
+        if let Ok(gate) = <&mut Gate>::query().get_mut(world, *gate) {
+            gate.locked = lock;
+        }
+    }
+}
+
+// Does a unit at `start` following `path` go through the rectangle?
+fn path_goes_through(start: Vec2, path: &[Vec2], center: Vec2, dimensions: Vec2) -> bool {
+    let (min, max) = (center - dimensions / 2.0, center + dimensions / 2.0);
+
+    std::iter::once(&start)
+        .chain(path.iter())
+        .zip(path.iter())
+        .any(|(a, b)| segment_intersects_rect(*a, *b, min, max))
+}
+
+// Gates open for friendly units that are close by or that are following a path through them, and
+// shut once they've gone. A shut gate is just another building as far as pathfinding goes, so units
+// that are further away path around it, but anything that got its path while the gate was open
+// holds it open until it's through. Gates won't shut on top of anyone.
+#[legion::system]
+#[read_component(Entity)]
+#[read_component(Position)]
+#[read_component(Radius)]
+#[read_component(Side)]
+#[read_component(Unit)]
+#[read_component(CommandQueue)]
+#[read_component(FullyBuilt)]
+#[write_component(Gate)]
+#[write_component(MapHandle)]
+pub fn update_gates(
+    #[resource] map: &mut Map,
+    #[resource] spatial_hash: &SpatialHash,
+    world: &mut SubWorld,
+) {
+    let dimensions = Building::Gate.stats().dimensions;
+
+    let gates: Vec<(Entity, Vec2, Side, bool, bool)> = <(Entity, &Position, &Side, &Gate)>::query()
+        .filter(component::<FullyBuilt>())
+        .iter(world)
+        .map(|(entity, position, side, gate)| (*entity, position.0, *side, gate.open, gate.locked))
+        .collect();
+
+    for (entity, position, side, open, locked) in gates {
+        let friendly = |unit: Entity| {
+            <&Side>::query()
+                .filter(component::<Unit>())
+                .get(world, unit)
+                .is_ok_and(|unit_side| *unit_side == side)
+        };
+
+        let approaching = !locked
+            && (spatial_hash
+                .query_radius(position, dimensions.x / 2.0 + GATE_OPENING_RANGE)
+                .any(friendly)
+                || <(&Position, &Side, &Radius, &CommandQueue)>::query()
+                    .filter(component::<Unit>())
+                    .iter(world)
+                    .filter(|(_, unit_side, ..)| **unit_side == side)
+                    .any(|(unit_position, _, radius, commands)| {
+                        commands
+                            .0
+                            .front()
+                            .and_then(|command| command.path())
+                            .is_some_and(|path| {
+                                path_goes_through(
+                                    unit_position.0,
+                                    path,
+                                    position,
+                                    dimensions + Vec2::broadcast(radius.0 * 2.0),
+                                )
+                            })
+                    }));
+
+        if approaching == open || (!approaching && unit_under_building(position, dimensions, world))
+        {
+            continue;
+        }
+
+        let changed = match <&mut MapHandle>::query().get_mut(world, entity) {
+            Ok(handle) => map.set_constraint_enabled(handle, !approaching),
+            Err(_) => false,
+        };
+
+        if changed {
+            if let Ok(gate) = <&mut Gate>::query().get_mut(world, entity) {
+                gate.open = approaching;
+            }
+        }
+    }
+}
+
+// Once segments are destroyed, the wall's rectangles in the map get redone around whatever's left.
+#[legion::system(for_each)]
+#[read_component(Building)]
+pub fn update_walls(
+    entity: &Entity,
+    wall: &mut Wall,
+    #[resource] map: &mut Map,
+    world: &SubWorld,
+    buffer: &mut CommandBuffer,
+) {
+    let segments = wall.segments.len();
+    wall.segments
+        .retain(|(segment, _)| <&Building>::query().get(world, *segment).is_ok());
+
+    if wall.segments.len() == segments {
+        return;
+    }
+
+    for handle in wall.handles.drain(..) {
+        map.remove(&handle);
+    }
+
+    if wall.segments.is_empty() {
+        buffer.remove(*entity);
+        return;
+    }
+
+    // Nothing else can have been inserted where the wall already was.
+    wall.insert_runs(map);
+}
+
+// Connected segments pool their health, so that a wall gets worn down all at once instead of having
+// a hole knocked in it wherever the enemy happens to be shooting. Anything that's still being built
+// is left out.
+#[legion::system(for_each)]
+#[write_component(Health)]
+#[read_component(FullyBuilt)]
+pub fn share_wall_health(wall: &Wall, world: &mut SubWorld) {
+    for run in wall.runs() {
+        let built: Vec<(Entity, f32)> = run
+            .iter()
+            .filter_map(|(segment, _)| {
+                <&Health>::query()
+                    .filter(component::<FullyBuilt>())
+                    .get(world, *segment)
+                    .ok()
+                    .map(|health| (*segment, health.0))
+            })
+            .collect();
+
+        if built.len() < 2 {
+            continue;
+        }
+
+        let shared = built.iter().map(|(_, health)| health).sum::<f32>() / built.len() as f32;
+
+        for (segment, _) in built {
+            if let Ok(health) = <&mut Health>::query().get_mut(world, segment) {
+                health.0 = shared;
+            }
+        }
+    }
+}
+
+// Where the wall being dragged out starts. It's worked out from where the mouse was pressed on the
+// screen, so it moves about a bit if the camera does, but that's the same as the selection box.
+fn drag_start(
+    screen_position: Vec2,
+    camera: &Camera,
+    terrain: &Terrain,
+    screen_dimensions: &ScreenDimensions,
+    dpi_scaling: &DpiScaling,
+) -> Option<Vec2> {
+    if hud_contains(screen_position, screen_dimensions, dpi_scaling) {
+        return None;
+    }
+
+    let minimap_area = MinimapArea::new(screen_dimensions, dpi_scaling);
+
+    Some(if minimap_area.contains(screen_position) {
+        minimap_area.to_world(screen_position)
+    } else {
+        camera.cast_ray_onto_terrain(screen_position, screen_dimensions, terrain)
+    })
+}
+
+// The segments that would be placed if the mouse was let go of now.
+pub fn planned_wall(
+    mouse_state: &MouseState,
+    camera: &Camera,
+    terrain: &Terrain,
+    screen_dimensions: &ScreenDimensions,
+    dpi_scaling: &DpiScaling,
+    ray_cast_location: &RayCastLocation,
+) -> Vec<Vec2> {
+    let start = mouse_state
+        .left_state
+        .is_held()
+        .and_then(|start| drag_start(start, camera, terrain, screen_dimensions, dpi_scaling))
+        .unwrap_or(ray_cast_location.pos);
+
+    wall_segments(start, ray_cast_location.pos)
+}
+
+// Clicking places a single segment, and dragging lays down a whole line of them.
+#[legion::system]
+#[read_component(Position)]
+#[read_component(Unit)]
+#[read_component(Side)]
+#[read_component(Selected)]
+#[read_component(NetworkId)]
+pub fn handle_wall_placement(
+    #[resource] mouse_state: &MouseState,
+    #[resource] camera: &Camera,
+    #[resource] terrain: &Terrain,
+    #[resource] screen_dimensions: &ScreenDimensions,
+    #[resource] dpi_scaling: &DpiScaling,
+    #[resource] ray_cast_location: &RayCastLocation,
+    #[resource] rts_controls: &mut RtsControls,
+    #[resource] player_side: &PlayerSide,
+    #[resource] map: &Map,
+    #[resource] cheese_coins: &mut CheeseCoins,
+    #[resource] lockstep: &mut Lockstep,
+    #[resource] total_time: &TotalTime,
+    world: &SubWorld,
+) {
+    if rts_controls.mode
+        != (CommandMode::Construct {
+            building: Building::Wall,
+        })
+    {
+        return;
+    }
+
+    let start = if mouse_state.left_state.was_clicked() {
+        mouse_state.position
+    } else if let Some(start) = mouse_state.left_state.was_dragged() {
+        start
+    } else {
+        return;
+    };
+
+    let start = match drag_start(start, camera, terrain, screen_dimensions, dpi_scaling) {
+        Some(start) => start,
+        None => return,
+    };
+    let end = ray_cast_location.pos;
+
+    let segments = wall_segments(start, end);
+    let cost = Building::Wall.stats().cost * segments.len() as u32;
+
+    if cost > cheese_coins.0 || !can_place_wall(&segments, map, world) {
+        return;
+    }
+
+    cheese_coins.0 -= cost;
+
+    log::trace!(target: "command-recording", "{:?}: Building a wall from {:?} to {:?}", total_time.0, start, end);
+
+    let builders = <(&NetworkId, &Side)>::query()
+        .filter(component::<Selected>())
+        .iter(world)
+        .filter(|(_, side)| **side == player_side.0)
+        .map(|(id, _)| *id)
+        .collect();
+
+    lockstep.issue(
+        player_side.0,
+        PlayerCommand::BuildWall {
+            builders,
+            start,
+            end,
+            queue: rts_controls.shift_held,
+        },
+    );
+
+    if !rts_controls.shift_held {
+        rts_controls.mode = CommandMode::Normal;
+    }
+}
+
+// Walls are squashed rocks, the same as the obstacles on generated maps, stretched a bit so that
+// the segments run into each other. Open gates sink down into the ground.
+pub fn segment_transform(ground: Vec3, building: Building, gate: Option<&Gate>) -> Mat4 {
+    let height = match (building, gate) {
+        (Building::Gate, Some(Gate { open: true, .. })) => OPEN_GATE_HEIGHT,
+        (Building::Gate, _) => GATE_HEIGHT,
+        _ => WALL_HEIGHT,
+    };
+    let radius = WALL_GRID / 2.0 * 1.2;
+
+    Mat4::from_translation(ground) * Mat4::from_nonuniform_scale(Vec3::new(radius, height, radius))
+}
+
+#[test]
+fn walls_are_straight_and_snapped() {
+    let segments = wall_segments(Vec2::new(1.0, 1.0), Vec2::new(10.0, -2.0));
+    assert_eq!(
+        segments,
+        vec![
+            Vec2::new(1.5, 1.5),
+            Vec2::new(4.5, 1.5),
+            Vec2::new(7.5, 1.5),
+            Vec2::new(10.5, 1.5),
+        ]
+    );
+
+    // Going the other way along the other axis.
+    let segments = wall_segments(Vec2::new(1.0, 1.0), Vec2::new(0.0, -5.0));
+    assert_eq!(segments.len(), 3);
+    assert_eq!(segments[2], Vec2::new(1.5, -4.5));
+
+    // Clicking without dragging gives a single segment.
+    assert_eq!(wall_segments(Vec2::zero(), Vec2::zero()).len(), 1);
+    assert_eq!(
+        wall_segments(Vec2::zero(), Vec2::new(1000.0, 0.0)).len(),
+        MAX_WALL_SEGMENTS
+    );
+}
+
+#[test]
+fn destroyed_segments_break_up_the_wall() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    resources.insert(Map::new());
+
+    let segments = wall_segments(Vec2::new(10.0, 10.0), Vec2::new(22.0, 10.0));
+    let entities = {
+        let mut map = resources.get_mut::<Map>().unwrap();
+        let mut buffer = CommandBuffer::new(&world);
+        let entities = add_wall_to_construct(&mut buffer, &segments, Side::Green, &mut map)
+            .expect("Nothing's in the way");
+        buffer.flush(&mut world);
+        entities
+    };
+
+    let blocked = |resources: &Resources, a: Vec2, b: Vec2| {
+        resources.get::<Map>().unwrap().impassable_between(a, b)
+    };
+
+    // Crossing the middle segment.
+    let (above, below) = (
+        segments[2] + Vec2::new(0.0, 3.0),
+        segments[2] - Vec2::new(0.0, 3.0),
+    );
+    assert!(blocked(&resources, above, below));
+
+    let mut schedule = Schedule::builder()
+        .add_system(update_walls_system())
+        .build();
+
+    world.remove(entities[2]);
+    schedule.execute(&mut world, &mut resources);
+    assert!(!blocked(&resources, above, below));
+
+    // The rest of the wall is still there on either side of the gap.
+    for segment in [segments[0], segments[4]].iter() {
+        assert!(blocked(
+            &resources,
+            *segment + Vec2::new(0.0, 3.0),
+            *segment - Vec2::new(0.0, 3.0)
+        ));
+    }
+
+    for entity in entities {
+        world.remove(entity);
+    }
+    schedule.execute(&mut world, &mut resources);
+    assert_eq!(<&Wall>::query().iter(&world).count(), 0);
+}
+
+#[test]
+fn gates_only_open_for_friendly_units() {
+    let mut world = World::default();
+    let mut resources = Resources::default();
+    let mut map = Map::new();
+
+    let position = snap_to_wall_grid(Vec2::new(10.0, 10.0));
+    let dimensions = Building::Gate.stats().dimensions;
+    let handle = map.insert(position, footprint(dimensions)).unwrap();
+    let gate = world.push((
+        Position(position),
+        Side::Green,
+        FullyBuilt,
+        Gate::default(),
+        handle,
+    ));
+
+    resources.insert(map);
+    resources.insert(SpatialHash::default());
+
+    let mut schedule = Schedule::builder()
+        .add_system(update_spatial_hash_system())
+        .flush()
+        .add_system(update_gates_system())
+        .build();
+
+    let (above, below) = (
+        position + Vec2::new(0.0, 3.0),
+        position - Vec2::new(0.0, 3.0),
+    );
+    let is_open = |world: &World, resources: &Resources| {
+        let open = world
+            .entry_ref(gate)
+            .unwrap()
+            .get_component::<Gate>()
+            .unwrap()
+            .open;
+        assert_eq!(
+            open,
+            !resources
+                .get::<Map>()
+                .unwrap()
+                .impassable_between(above, below)
+        );
+        open
+    };
+
+    let enemy = world.push((
+        Position(below),
+        Side::Purple,
+        Unit::MouseMarine,
+        Radius(0.5),
+        CommandQueue::default(),
+    ));
+    schedule.execute(&mut world, &mut resources);
+    assert!(!is_open(&world, &resources));
+
+    world.remove(enemy);
+    let friendly = world.push((
+        Position(below),
+        Side::Green,
+        Unit::MouseMarine,
+        Radius(0.5),
+        CommandQueue::default(),
+    ));
+    schedule.execute(&mut world, &mut resources);
+    assert!(is_open(&world, &resources));
+
+    // It can't shut on top of the unit, even once it's locked.
+    world
+        .entry(friendly)
+        .unwrap()
+        .get_component_mut::<Position>()
+        .unwrap()
+        .0 = position;
+    world
+        .entry(gate)
+        .unwrap()
+        .get_component_mut::<Gate>()
+        .unwrap()
+        .locked = true;
+    schedule.execute(&mut world, &mut resources);
+    assert!(is_open(&world, &resources));
+
+    world.remove(friendly);
+    schedule.execute(&mut world, &mut resources);
+    assert!(!is_open(&world, &resources));
+}
@@ -0,0 +1,220 @@
+use ultraviolet::Vec2;
+
+// Something has to be at least this much higher up than something else to count as being on the
+// high ground over it.
+const HIGH_GROUND_DIFFERENCE: f32 = 1.0;
+
+// Like the lakes, the plateaus are the same in every match for now. There's one on either side of
+// the map, mirrored through the middle, with a ramp facing the middle and another one facing the
+// nearest base.
+pub const PLATEAUS: [Plateau; 2] = [
+    Plateau {
+        center: Vec2::new(75.0, -28.0),
+        dimensions: Vec2::new(22.0, 18.0),
+        height: 3.0,
+        ramps: &[
+            Ramp {
+                direction: Vec2::new(-1.0, 0.0),
+                offset: 0.0,
+                width: 6.0,
+            },
+            Ramp {
+                direction: Vec2::new(0.0, 1.0),
+                offset: 4.0,
+                width: 6.0,
+            },
+        ],
+    },
+    Plateau {
+        center: Vec2::new(-75.0, 28.0),
+        dimensions: Vec2::new(22.0, 18.0),
+        height: 3.0,
+        ramps: &[
+            Ramp {
+                direction: Vec2::new(1.0, 0.0),
+                offset: 0.0,
+                width: 6.0,
+            },
+            Ramp {
+                direction: Vec2::new(0.0, -1.0),
+                offset: 4.0,
+                width: 6.0,
+            },
+        ],
+    },
+];
+
+// How far out from the edge of a plateau its ramps go.
+const RAMP_LENGTH: f32 = 8.0;
+
+// A raised rectangle of ground. The edges are cliffs (which are inserted into the map so nothing can
+// walk up them), apart from where the ramps are.
+#[derive(Clone, Copy, Debug)]
+pub struct Plateau {
+    pub center: Vec2,
+    pub dimensions: Vec2,
+    // How far up it is from the ground around it.
+    pub height: f32,
+    pub ramps: &'static [Ramp],
+}
+
+// A slope leading down from the edge of a plateau.
+#[derive(Clone, Copy, Debug)]
+pub struct Ramp {
+    // Which edge of the plateau the ramp is on, pointing away from the plateau along one of the axes.
+    pub direction: Vec2,
+    // How far along the edge it is from the middle, going to the left when facing `direction`.
+    pub offset: f32,
+    pub width: f32,
+}
+
+impl Ramp {
+    fn along(&self) -> Vec2 {
+        Vec2::new(-self.direction.y, self.direction.x)
+    }
+}
+
+impl Plateau {
+    // The middle of the edge facing `direction`.
+    fn edge_middle(&self, direction: Vec2) -> Vec2 {
+        self.center + direction * (direction * self.dimensions / 2.0).abs().component_max()
+    }
+
+    // How high up a point is if it's on the plateau or one of its ramps.
+    fn elevation_at(&self, point: Vec2) -> Option<f32> {
+        let offset = (point - self.center).abs();
+        if offset.x <= self.dimensions.x / 2.0 && offset.y <= self.dimensions.y / 2.0 {
+            return Some(self.height);
+        }
+
+        self.ramps.iter().find_map(|ramp| {
+            let from_edge = point - self.edge_middle(ramp.direction);
+            let out = from_edge.dot(ramp.direction);
+            let sideways = from_edge.dot(ramp.along()) - ramp.offset;
+
+            if out >= 0.0 && out <= RAMP_LENGTH && sideways.abs() <= ramp.width / 2.0 {
+                Some(self.height * (1.0 - out / RAMP_LENGTH))
+            } else {
+                None
+            }
+        })
+    }
+
+    // The cliffs around the edge, as lines of points. They go all the way around the plateau and
+    // along either side of each ramp, with a gap at the bottom of each ramp. Without any ramps it's
+    // one closed loop, and the last point joins back up with the first.
+    pub fn cliffs(&self) -> Vec<Vec<Vec2>> {
+        let half = self.dimensions / 2.0;
+        // Going anticlockwise, so the outside of each edge is on the right.
+        let corners = [
+            self.center + Vec2::new(-half.x, -half.y),
+            self.center + Vec2::new(half.x, -half.y),
+            self.center + Vec2::new(half.x, half.y),
+            self.center + Vec2::new(-half.x, half.y),
+        ];
+
+        // Each point, and whether there's a gap between it and the next one.
+        let mut outline: Vec<(Vec2, bool)> = Vec::new();
+
+        for i in 0..4 {
+            let (start, end) = (corners[i], corners[(i + 1) % 4]);
+            let along = (end - start).normalized();
+            let outwards = Vec2::new(along.y, -along.x);
+
+            outline.push((start, false));
+
+            let mut ramps: Vec<&Ramp> = self
+                .ramps
+                .iter()
+                .filter(|ramp| (ramp.direction - outwards).mag_sq() < 0.01)
+                .collect();
+            ramps.sort_by(|a, b| a.offset.partial_cmp(&b.offset).unwrap());
+
+            for ramp in ramps {
+                let middle = self.edge_middle(outwards) + along * ramp.offset;
+                let (left, right) = (
+                    middle - along * ramp.width / 2.0,
+                    middle + along * ramp.width / 2.0,
+                );
+
+                outline.push((left, false));
+                outline.push((left + outwards * RAMP_LENGTH, true));
+                outline.push((right + outwards * RAMP_LENGTH, false));
+                outline.push((right, false));
+            }
+        }
+
+        let first_gap = match outline.iter().position(|(_, gap)| *gap) {
+            Some(gap) => gap,
+            None => return vec![outline.into_iter().map(|(point, _)| point).collect()],
+        };
+
+        // Start just after a gap, so that the lines all end at the next one.
+        outline.rotate_left(first_gap + 1);
+
+        let mut cliffs = vec![Vec::new()];
+        for (point, gap) in outline {
+            cliffs.last_mut().unwrap().push(point);
+
+            if gap {
+                cliffs.push(Vec::new());
+            }
+        }
+        cliffs.pop();
+
+        cliffs
+    }
+}
+
+// How far up from the rest of the terrain a point is raised by being on a plateau or a ramp.
+pub fn elevation_at(point: Vec2) -> f32 {
+    PLATEAUS
+        .iter()
+        .filter_map(|plateau| plateau.elevation_at(point))
+        .fold(0.0, f32::max)
+}
+
+pub fn is_high_ground_over(point: Vec2, other: Vec2) -> bool {
+    elevation_at(point) - elevation_at(other) >= HIGH_GROUND_DIFFERENCE
+}
+
+pub fn is_on_high_ground(point: Vec2) -> bool {
+    elevation_at(point) >= HIGH_GROUND_DIFFERENCE
+}
+
+#[test]
+fn ramps_lead_down_from_plateaus() {
+    let plateau = &PLATEAUS[0];
+    let ramp = &plateau.ramps[0];
+    let top = plateau.edge_middle(ramp.direction) + ramp.along() * ramp.offset;
+
+    assert_eq!(elevation_at(plateau.center), plateau.height);
+    assert_eq!(
+        elevation_at(top + ramp.direction * RAMP_LENGTH / 2.0),
+        plateau.height / 2.0
+    );
+    assert_eq!(
+        elevation_at(top + ramp.direction * (RAMP_LENGTH + 1.0)),
+        0.0
+    );
+    // Next to the ramp is still at the bottom of the cliff.
+    assert_eq!(
+        elevation_at(top + ramp.direction + ramp.along() * ramp.width),
+        0.0
+    );
+
+    assert!(is_high_ground_over(
+        plateau.center,
+        top + ramp.direction * RAMP_LENGTH
+    ));
+    assert!(!is_high_ground_over(
+        top + ramp.direction * RAMP_LENGTH,
+        plateau.center
+    ));
+
+    // There's a line of cliffs in between each pair of ramps, and each one goes up one side of a
+    // ramp and down the side of the next.
+    let cliffs = plateau.cliffs();
+    assert_eq!(cliffs.len(), plateau.ramps.len());
+    assert!(cliffs.iter().all(|cliff| cliff.len() >= 4));
+}
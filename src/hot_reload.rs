@@ -0,0 +1,190 @@
+// With `hot_reload` turned on in `settings.toml`, the models, textures and compiled shaders get read
+// from the `models`, `textures` and `shaders/compiled` directories (relative to where the game is
+// run from) instead of the copies built into the executable, and the directories are checked for
+// changes every second. When something changes, the assets and pipelines are all rebuilt, so the
+// mouse can be tweaked in blender or a shader recompiled without restarting the game.
+//
+// Animations aren't reloaded, as every animated unit would need its skin rebuilding to match.
+// Changing the skeleton of a model needs a restart.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const ASSET_DIRECTORIES: &[&str] = &["models", "textures"];
+const SHADER_DIRECTORY: &str = "shaders/compiled";
+
+// Every SPIR-V file starts with this.
+const SPIRV_MAGIC_NUMBER: u32 = 0x0723_0203;
+
+// Whether `read` looks on the disk. This has to be global because assets and shaders are loaded from
+// all over the renderer.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Reads `path` from the disk if hot reloading is on, or otherwise gives back the `embedded` copy.
+// Use `asset!` or `shader!` instead of calling this directly.
+pub fn read(path: &str, embedded: &'static [u8]) -> Cow<'static, [u8]> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Cow::Borrowed(embedded);
+    }
+
+    match std::fs::read(path) {
+        Ok(bytes) => Cow::Owned(bytes),
+        Err(error) => {
+            log::warn!(
+                "Failed to read {}, using the built in copy: {}",
+                path,
+                error
+            );
+            Cow::Borrowed(embedded)
+        }
+    }
+}
+
+// Like `wgpu::include_spirv!`, but for shaders that might have been read from the disk. A half
+// written (or otherwise broken) shader would bring the whole game down, so those fall back to the
+// built in copy.
+pub fn read_shader(path: &str, embedded: &'static [u8]) -> wgpu::ShaderModuleSource<'static> {
+    let bytes = read(path, embedded);
+
+    let bytes = if is_spirv(&bytes) {
+        bytes
+    } else {
+        log::error!("{} isn't valid SPIR-V, using the built in copy", path);
+        Cow::Borrowed(embedded)
+    };
+
+    let words = bytes
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+
+    wgpu::ShaderModuleSource::SpirV(Cow::Owned(words))
+}
+
+fn is_spirv(bytes: &[u8]) -> bool {
+    bytes.len() >= 4
+        && bytes.len().is_multiple_of(4)
+        && u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) == SPIRV_MAGIC_NUMBER
+}
+
+// The path is relative to the root of the repository, e.g. `asset!("models/mouse.gltf")`.
+macro_rules! asset {
+    ($path:literal) => {
+        &*crate::hot_reload::read(
+            $path,
+            include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $path)),
+        )
+    };
+}
+
+// The name of a file in `shaders/compiled`, e.g. `shader!("lines.vert.spv")`.
+macro_rules! shader {
+    ($name:literal) => {
+        crate::hot_reload::read_shader(
+            concat!("shaders/compiled/", $name),
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/shaders/compiled/",
+                $name
+            )),
+        )
+    };
+}
+
+pub(crate) use {asset, shader};
+
+// What needs rebuilding.
+pub struct Changes {
+    pub assets: bool,
+    pub shaders: bool,
+}
+
+fn modified_times(directory: &Path) -> HashMap<PathBuf, SystemTime> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return HashMap::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let modified = entry.metadata().and_then(|metadata| metadata.modified());
+            Some((entry.path(), modified.ok()?))
+        })
+        .collect()
+}
+
+pub struct AssetWatcher {
+    enabled: bool,
+    assets: HashMap<PathBuf, SystemTime>,
+    shaders: HashMap<PathBuf, SystemTime>,
+    last_checked: Instant,
+}
+
+fn asset_modified_times() -> HashMap<PathBuf, SystemTime> {
+    ASSET_DIRECTORIES
+        .iter()
+        .flat_map(|directory| modified_times(Path::new(directory)))
+        .collect()
+}
+
+impl AssetWatcher {
+    // This needs to be created before anything is loaded, so that it all comes from the right place.
+    pub fn new(enabled: bool) -> Self {
+        ENABLED.store(enabled, Ordering::Relaxed);
+
+        Self {
+            enabled,
+            assets: asset_modified_times(),
+            shaders: modified_times(Path::new(SHADER_DIRECTORY)),
+            last_checked: Instant::now(),
+        }
+    }
+
+    // Returns what's changed since we last looked, if anything.
+    pub fn poll(&mut self, enabled: bool) -> Option<Changes> {
+        if enabled != self.enabled {
+            ENABLED.store(enabled, Ordering::Relaxed);
+            self.enabled = enabled;
+            // Everything gets reloaded when hot reloading is turned on, as the files on the disk
+            // might not match the built in ones.
+            self.assets.clear();
+            self.shaders.clear();
+        }
+
+        if !enabled || self.last_checked.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.last_checked = Instant::now();
+
+        let assets = asset_modified_times();
+        let shaders = modified_times(Path::new(SHADER_DIRECTORY));
+
+        let changes = Changes {
+            assets: assets != self.assets,
+            shaders: shaders != self.shaders,
+        };
+
+        self.assets = assets;
+        self.shaders = shaders;
+
+        if changes.assets || changes.shaders {
+            Some(changes)
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn only_spirv_is_used_for_shaders() {
+    let lines = include_bytes!("../shaders/compiled/lines.vert.spv");
+    assert!(is_spirv(lines));
+    assert!(!is_spirv(&lines[..lines.len() - 1]));
+    assert!(!is_spirv(b"#version 450\n"));
+}
@@ -0,0 +1,380 @@
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use winit::event::{MouseButton, VirtualKeyCode};
+
+// Everything the player can do with a key or mouse button, apart from control groups (which are
+// always the number keys) and abilities (which have their hotkeys set in `ecs::Ability`).
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+// toml only allows strings as keys, so actions are written as their `key` in the settings file.
+#[serde(try_from = "String", into = "String")]
+pub enum Action {
+    CameraUp,
+    CameraDown,
+    CameraLeft,
+    CameraRight,
+    CameraRotateLeft,
+    CameraRotateRight,
+    DragCamera,
+    Select,
+    Command,
+    // Held to add to the selection, the command queue or a control group instead of replacing it.
+    Append,
+    // Held to select everything of the same type or to set a control group.
+    Group,
+    Move,
+    Stop,
+    HoldPosition,
+    Patrol,
+    Guard,
+    AttackMove,
+    CycleStance,
+    CycleFormation,
+    // Jump to the next engineer or army unit that hasn't got anything to do.
+    SelectIdleWorker,
+    SelectIdleArmy,
+    // Moves the camera to wherever the player's things were last attacked, see `ecs::Alerts`.
+    JumpToAlert,
+    // Leaves whatever command mode you're in, or opens the menu if you aren't in one.
+    Cancel,
+    Quicksave,
+    Quickload,
+    ToggleProfiler,
+    ToggleInspector,
+    ToggleConsole,
+    Screenshot,
+    // Starts or stops saving every frame, see `renderer::Screenshots`.
+    ToggleRecording,
+    // Only in single player.
+    PauseGame,
+    IncreaseGameSpeed,
+    DecreaseGameSpeed,
+}
+
+impl Action {
+    pub const ALL: &'static [Action] = &[
+        Self::CameraUp,
+        Self::CameraDown,
+        Self::CameraLeft,
+        Self::CameraRight,
+        Self::CameraRotateLeft,
+        Self::CameraRotateRight,
+        Self::DragCamera,
+        Self::Select,
+        Self::Command,
+        Self::Append,
+        Self::Group,
+        Self::Move,
+        Self::Stop,
+        Self::HoldPosition,
+        Self::Patrol,
+        Self::Guard,
+        Self::AttackMove,
+        Self::CycleStance,
+        Self::CycleFormation,
+        Self::SelectIdleWorker,
+        Self::SelectIdleArmy,
+        Self::JumpToAlert,
+        Self::Cancel,
+        Self::Quicksave,
+        Self::Quickload,
+        Self::ToggleProfiler,
+        Self::ToggleInspector,
+        Self::ToggleConsole,
+        Self::Screenshot,
+        Self::ToggleRecording,
+        Self::PauseGame,
+        Self::IncreaseGameSpeed,
+        Self::DecreaseGameSpeed,
+    ];
+
+    fn key(self) -> &'static str {
+        match self {
+            Self::CameraUp => "camera_up",
+            Self::CameraDown => "camera_down",
+            Self::CameraLeft => "camera_left",
+            Self::CameraRight => "camera_right",
+            Self::CameraRotateLeft => "camera_rotate_left",
+            Self::CameraRotateRight => "camera_rotate_right",
+            Self::DragCamera => "drag_camera",
+            Self::Select => "select",
+            Self::Command => "command",
+            Self::Append => "append",
+            Self::Group => "group",
+            Self::Move => "move",
+            Self::Stop => "stop",
+            Self::HoldPosition => "hold_position",
+            Self::Patrol => "patrol",
+            Self::Guard => "guard",
+            Self::AttackMove => "attack_move",
+            Self::CycleStance => "cycle_stance",
+            Self::CycleFormation => "cycle_formation",
+            Self::SelectIdleWorker => "select_idle_worker",
+            Self::SelectIdleArmy => "select_idle_army",
+            Self::JumpToAlert => "jump_to_alert",
+            Self::Cancel => "cancel",
+            Self::Quicksave => "quicksave",
+            Self::Quickload => "quickload",
+            Self::ToggleProfiler => "toggle_profiler",
+            Self::ToggleInspector => "toggle_inspector",
+            Self::ToggleConsole => "toggle_console",
+            Self::Screenshot => "screenshot",
+            Self::ToggleRecording => "toggle_recording",
+            Self::PauseGame => "pause_game",
+            Self::IncreaseGameSpeed => "increase_game_speed",
+            Self::DecreaseGameSpeed => "decrease_game_speed",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::CameraUp => "Camera up",
+            Self::CameraDown => "Camera down",
+            Self::CameraLeft => "Camera left",
+            Self::CameraRight => "Camera right",
+            Self::CameraRotateLeft => "Rotate left",
+            Self::CameraRotateRight => "Rotate right",
+            Self::DragCamera => "Drag camera",
+            Self::Select => "Select",
+            Self::Command => "Command",
+            Self::Append => "Append",
+            Self::Group => "Group",
+            Self::Move => "Move",
+            Self::Stop => "Stop",
+            Self::HoldPosition => "Hold position",
+            Self::Patrol => "Patrol",
+            Self::Guard => "Guard",
+            Self::AttackMove => "Attack move",
+            Self::CycleStance => "Cycle stance",
+            Self::CycleFormation => "Cycle formation",
+            Self::SelectIdleWorker => "Idle worker",
+            Self::SelectIdleArmy => "Idle army",
+            Self::JumpToAlert => "Last alert",
+            Self::Cancel => "Cancel",
+            Self::Quicksave => "Quicksave",
+            Self::Quickload => "Quickload",
+            Self::ToggleProfiler => "Profiler",
+            Self::ToggleInspector => "Inspector",
+            Self::ToggleConsole => "Console",
+            Self::Screenshot => "Screenshot",
+            Self::ToggleRecording => "Record frames",
+            Self::PauseGame => "Pause",
+            Self::IncreaseGameSpeed => "Speed up",
+            Self::DecreaseGameSpeed => "Slow down",
+        }
+    }
+
+    // These are handled as the mouse moves and clicks rather than as key presses, so binding them
+    // to keys doesn't do anything.
+    pub fn is_pointer(self) -> bool {
+        matches!(self, Self::DragCamera | Self::Select | Self::Command)
+    }
+}
+
+impl From<Action> for String {
+    fn from(action: Action) -> Self {
+        action.key().to_string()
+    }
+}
+
+impl TryFrom<String> for Action {
+    type Error = String;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|action| action.key() == string)
+            .ok_or_else(|| format!("'{}' isn't an action", string))
+    }
+}
+
+// In `settings.toml` keys are written the same way as winit names them (e.g. "W" or "LShift") and
+// mouse buttons as "Mouse Left", "Mouse Right", "Mouse Middle" or "Mouse <number>".
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Input {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Key(key) => write!(f, "{:?}", key),
+            Self::Mouse(MouseButton::Other(number)) => write!(f, "Mouse {}", number),
+            Self::Mouse(button) => write!(f, "Mouse {:?}", button),
+        }
+    }
+}
+
+impl From<Input> for String {
+    fn from(input: Input) -> Self {
+        input.to_string()
+    }
+}
+
+impl TryFrom<String> for Input {
+    type Error = String;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        if let Some(button) = string.strip_prefix("Mouse ") {
+            return match button {
+                "Left" => Ok(Self::Mouse(MouseButton::Left)),
+                "Right" => Ok(Self::Mouse(MouseButton::Right)),
+                "Middle" => Ok(Self::Mouse(MouseButton::Middle)),
+                _ => button
+                    .parse()
+                    .map(|number| Self::Mouse(MouseButton::Other(number)))
+                    .map_err(|_| format!("'{}' isn't a mouse button", string)),
+            };
+        }
+
+        // Let serde match the name against the variants of `VirtualKeyCode` for us.
+        let deserializer: serde::de::value::StrDeserializer<serde::de::value::Error> =
+            string.as_str().into_deserializer();
+        VirtualKeyCode::deserialize(deserializer)
+            .map(Self::Key)
+            .map_err(|_| format!("'{}' isn't a key", string))
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(
+    from = "BTreeMap<Action, Vec<Input>>",
+    into = "BTreeMap<Action, Vec<Input>>"
+)]
+pub struct InputMap(BTreeMap<Action, Vec<Input>>);
+
+impl Default for InputMap {
+    fn default() -> Self {
+        use MouseButton as Mouse;
+        use VirtualKeyCode as Key;
+
+        let bindings = vec![
+            (Action::CameraUp, vec![Input::Key(Key::Up)]),
+            (Action::CameraDown, vec![Input::Key(Key::Down)]),
+            (Action::CameraLeft, vec![Input::Key(Key::Left)]),
+            (Action::CameraRight, vec![Input::Key(Key::Right)]),
+            (Action::CameraRotateLeft, vec![Input::Key(Key::Q)]),
+            (Action::CameraRotateRight, vec![Input::Key(Key::E)]),
+            (Action::DragCamera, vec![Input::Mouse(Mouse::Middle)]),
+            (Action::Select, vec![Input::Mouse(Mouse::Left)]),
+            (Action::Command, vec![Input::Mouse(Mouse::Right)]),
+            (
+                Action::Append,
+                vec![Input::Key(Key::LShift), Input::Key(Key::RShift)],
+            ),
+            (Action::Group, vec![Input::Key(Key::LControl)]),
+            (Action::Move, vec![Input::Key(Key::M)]),
+            (Action::Stop, vec![Input::Key(Key::S)]),
+            (Action::HoldPosition, vec![Input::Key(Key::H)]),
+            (Action::Patrol, vec![Input::Key(Key::P)]),
+            // Every letter is taken by something, and this one only clashes with silos.
+            (Action::Guard, vec![Input::Key(Key::K)]),
+            (Action::AttackMove, vec![Input::Key(Key::A)]),
+            (Action::CycleStance, vec![Input::Key(Key::V)]),
+            (Action::CycleFormation, vec![Input::Key(Key::G)]),
+            (Action::SelectIdleWorker, vec![Input::Key(Key::F1)]),
+            (Action::SelectIdleArmy, vec![Input::Key(Key::F2)]),
+            (Action::JumpToAlert, vec![Input::Key(Key::Space)]),
+            (Action::Cancel, vec![Input::Key(Key::Escape)]),
+            (Action::Quicksave, vec![Input::Key(Key::F5)]),
+            (Action::Quickload, vec![Input::Key(Key::F9)]),
+            (Action::ToggleProfiler, vec![Input::Key(Key::F3)]),
+            (Action::ToggleInspector, vec![Input::Key(Key::F4)]),
+            (Action::ToggleConsole, vec![Input::Key(Key::Grave)]),
+            (Action::Screenshot, vec![Input::Key(Key::F12)]),
+            (Action::ToggleRecording, vec![Input::Key(Key::F11)]),
+            (Action::PauseGame, vec![Input::Key(Key::Pause)]),
+            (
+                Action::IncreaseGameSpeed,
+                vec![Input::Key(Key::Equals), Input::Key(Key::NumpadAdd)],
+            ),
+            (
+                Action::DecreaseGameSpeed,
+                vec![Input::Key(Key::Minus), Input::Key(Key::NumpadSubtract)],
+            ),
+        ];
+
+        Self(bindings.into_iter().collect())
+    }
+}
+
+// Actions that are missing from the settings file keep their default bindings, so that adding new
+// actions doesn't leave them unbound for everyone who already has a settings file.
+impl From<BTreeMap<Action, Vec<Input>>> for InputMap {
+    fn from(bindings: BTreeMap<Action, Vec<Input>>) -> Self {
+        let mut input_map = Self::default();
+        input_map.0.extend(bindings);
+        input_map
+    }
+}
+
+impl From<InputMap> for BTreeMap<Action, Vec<Input>> {
+    fn from(input_map: InputMap) -> Self {
+        input_map.0
+    }
+}
+
+impl InputMap {
+    pub fn actions(&self, input: Input) -> impl Iterator<Item = Action> + '_ {
+        self.0
+            .iter()
+            .filter(move |(_, inputs)| inputs.contains(&input))
+            .map(|(action, _)| *action)
+    }
+
+    pub fn bindings(&self, action: Action) -> &[Input] {
+        self.0.get(&action).map(|inputs| &inputs[..]).unwrap_or(&[])
+    }
+
+    // The first key bound to an action, for showing on buttons.
+    pub fn key(&self, action: Action) -> Option<VirtualKeyCode> {
+        self.bindings(action).iter().find_map(|input| match input {
+            Input::Key(key) => Some(*key),
+            Input::Mouse(_) => None,
+        })
+    }
+
+    pub fn rebind(&mut self, action: Action, input: Input) {
+        self.0.insert(action, vec![input]);
+    }
+}
+
+#[test]
+fn input_map_reads_bindings_and_fills_in_defaults() {
+    #[derive(Deserialize)]
+    struct File {
+        input_map: InputMap,
+    }
+
+    let file: File = toml::from_str(
+        "[input_map]\n\
+         stop = [\"X\"]\n\
+         select = [\"Mouse Right\"]\n\
+         command = [\"Mouse 4\"]\n",
+    )
+    .unwrap();
+    let input_map = file.input_map;
+
+    assert_eq!(
+        input_map
+            .actions(Input::Key(VirtualKeyCode::X))
+            .collect::<Vec<_>>(),
+        vec![Action::Stop]
+    );
+    assert_eq!(input_map.key(Action::Stop), Some(VirtualKeyCode::X));
+    assert_eq!(input_map.actions(Input::Key(VirtualKeyCode::S)).count(), 0);
+    assert_eq!(
+        input_map.bindings(Action::Command),
+        &[Input::Mouse(MouseButton::Other(4))]
+    );
+    // Not mentioned in the file, so it keeps its default.
+    assert_eq!(input_map.key(Action::Move), Some(VirtualKeyCode::M));
+
+    assert!(Input::try_from("Mouse Up".to_string()).is_err());
+    assert!(Input::try_from("NotAKey".to_string()).is_err());
+}
@@ -0,0 +1,345 @@
+// Setting up a multiplayer game from the titlescreen. One player hosts and the other joins them by
+// typing in their address, then they both pick a side and a colour. The host picks the map. Once
+// both players are ready, the host tells the other player to start, along with the side and colour
+// it saw them pick, and after a short countdown they both load the map and the lockstep simulation
+// takes over.
+
+use crate::ecs::Side;
+use crate::net::{Listener, LobbyState, Lockstep, Peer};
+use crate::resources::{DeltaTime, Mode, PlayerSide, TeamColours, TEAM_COLOURS};
+use crate::scenarios::MULTIPLAYER_MAPS;
+use crate::titlescreen::Menu;
+use std::io;
+use std::thread::JoinHandle;
+
+const DEFAULT_PORT: u16 = 4000;
+// In seconds.
+const COUNTDOWN: f32 = 3.0;
+
+enum Connection {
+    Idle,
+    Hosting(Listener),
+    // Connecting blocks, so it happens on another thread.
+    Joining(JoinHandle<io::Result<(Peer, u64)>>),
+    Connected { peer: Peer, seed: u64 },
+}
+
+pub struct Lobby {
+    // Who to connect to when joining. Only the port is used when hosting.
+    pub address: String,
+    // Whether typing goes into `address`.
+    pub editing_address: bool,
+    connection: Connection,
+    hosting: bool,
+    local: LobbyState,
+    remote: Option<LobbyState>,
+    countdown: Option<f32>,
+    // Whether `local` needs sending.
+    changed: bool,
+    status: Option<String>,
+}
+
+impl Default for Lobby {
+    fn default() -> Self {
+        Self {
+            address: format!("127.0.0.1:{}", DEFAULT_PORT),
+            editing_address: false,
+            connection: Connection::Idle,
+            hosting: false,
+            local: LobbyState {
+                side: Side::Green,
+                colour: 0,
+                map: 0,
+                ready: false,
+                starting: None,
+            },
+            remote: None,
+            countdown: None,
+            changed: false,
+            status: None,
+        }
+    }
+}
+
+impl Lobby {
+    pub fn host(&mut self) {
+        let port = self
+            .address
+            .rsplit(':')
+            .next()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(DEFAULT_PORT);
+
+        match Listener::bind(("0.0.0.0", port)) {
+            Ok(listener) => {
+                self.reset(true);
+                self.connection = Connection::Hosting(listener);
+                self.status = Some(format!("Waiting for someone to join on port {}", port));
+            }
+            Err(error) => self.status = Some(format!("Failed to host: {}", error)),
+        }
+    }
+
+    pub fn join(&mut self) {
+        let address = self.address.clone();
+        self.reset(false);
+        self.connection = Connection::Joining(std::thread::spawn(move || Peer::connect(address)));
+        self.status = Some(format!("Connecting to {}", self.address));
+    }
+
+    // Dropping the connection lets the other player know that we've gone.
+    pub fn leave(&mut self) {
+        self.connection = Connection::Idle;
+        self.remote = None;
+        self.countdown = None;
+        self.status = None;
+    }
+
+    fn reset(&mut self, hosting: bool) {
+        self.hosting = hosting;
+        self.editing_address = false;
+        self.remote = None;
+        self.countdown = None;
+        // The host starts out as green and whoever joins as purple, like with `--host` and
+        // `--connect`.
+        self.local = LobbyState {
+            side: if hosting { Side::Green } else { Side::Purple },
+            colour: if hosting { 0 } else { 1 },
+            map: 0,
+            ready: false,
+            starting: None,
+        };
+        self.changed = true;
+    }
+
+    fn is_connected(&self) -> bool {
+        matches!(self.connection, Connection::Connected { .. })
+    }
+
+    // Nothing can be changed once the countdown has started.
+    fn can_change(&self) -> bool {
+        self.countdown.is_none()
+    }
+
+    pub fn cycle_side(&mut self) {
+        if self.can_change() {
+            self.local.side = self.local.side.flip();
+            self.local.ready = false;
+            self.changed = true;
+        }
+    }
+
+    pub fn cycle_colour(&mut self) {
+        if self.can_change() {
+            self.local.colour = (self.local.colour + 1) % TEAM_COLOURS.len() as u8;
+            self.local.ready = false;
+            self.changed = true;
+        }
+    }
+
+    pub fn cycle_map(&mut self) {
+        if self.hosting && self.can_change() {
+            self.local.map = (self.local.map + 1) % MULTIPLAYER_MAPS.len() as u8;
+            self.local.ready = false;
+            self.changed = true;
+        }
+    }
+
+    pub fn toggle_ready(&mut self) {
+        if self.can_change() && self.is_connected() {
+            self.local.ready = !self.local.ready;
+            self.changed = true;
+        }
+    }
+
+    fn map(&self) -> u8 {
+        match (self.hosting, self.remote) {
+            (false, Some(remote)) => remote.map,
+            _ => self.local.map,
+        }
+    }
+
+    // Why the game can't start with the current choices.
+    fn problem(&self) -> Option<&'static str> {
+        let remote = self.remote?;
+
+        if remote.side == self.local.side {
+            Some("Both players have picked the same side")
+        } else if remote.colour == self.local.colour {
+            Some("Both players have picked the same colour")
+        } else {
+            None
+        }
+    }
+
+    // The lobby menu items show what's currently picked, e.g. 'Side: Green'.
+    pub fn label(&self, text: &str) -> Option<String> {
+        match text {
+            "Address" => Some(format!(
+                "Address: {}{}",
+                self.address,
+                if self.editing_address { "_" } else { "" }
+            )),
+            "Side" => Some(format!("Side: {:?}", self.local.side)),
+            "Colour" => Some(format!(
+                "Colour: {}",
+                TEAM_COLOURS[self.local.colour as usize].0
+            )),
+            "Map" => Some(format!("Map: {}", MULTIPLAYER_MAPS[self.map() as usize].0)),
+            "Ready" if self.local.ready => Some("Ready: Yes".into()),
+            "Ready" => Some("Ready: No".into()),
+            _ => None,
+        }
+    }
+
+    // Shown under the menu.
+    pub fn status_text(&self) -> Option<String> {
+        if let Some(countdown) = self.countdown {
+            return Some(format!("Starting in {}", countdown.ceil()));
+        }
+
+        if !self.is_connected() {
+            return self.status.clone();
+        }
+
+        match self.remote {
+            None => Some("Connected".into()),
+            Some(remote) => {
+                let problem = self.problem().map(|problem| format!(". {}", problem));
+                Some(format!(
+                    "Other player: {:?}, {}, {}{}",
+                    remote.side,
+                    TEAM_COLOURS[remote.colour as usize].0,
+                    if remote.ready { "ready" } else { "not ready" },
+                    problem.unwrap_or_default()
+                ))
+            }
+        }
+    }
+
+    fn disconnected(&mut self, status: String) {
+        self.leave();
+        self.status = Some(status);
+    }
+}
+
+#[legion::system]
+pub fn update_lobby(
+    #[resource] lobby: &mut Lobby,
+    #[resource] menu: &mut Menu,
+    #[resource] mode: &mut Mode,
+    #[resource] delta_time: &DeltaTime,
+    #[resource] lockstep: &mut Lockstep,
+    #[resource] player_side: &mut PlayerSide,
+    #[resource] team_colours: &mut TeamColours,
+) {
+    match &mut lobby.connection {
+        Connection::Idle => return,
+        Connection::Hosting(listener) => match listener.accept() {
+            Ok(Some((peer, seed))) => lobby.connection = Connection::Connected { peer, seed },
+            Ok(None) => return,
+            Err(error) => return lobby.disconnected(format!("Failed to host: {}", error)),
+        },
+        Connection::Joining(thread) => {
+            if !thread.is_finished() {
+                return;
+            }
+
+            let thread = match std::mem::replace(&mut lobby.connection, Connection::Idle) {
+                Connection::Joining(thread) => thread,
+                _ => unreachable!(),
+            };
+
+            match thread.join() {
+                Ok(Ok((peer, seed))) => lobby.connection = Connection::Connected { peer, seed },
+                Ok(Err(error)) => return lobby.disconnected(format!("Failed to join: {}", error)),
+                Err(_) => return lobby.disconnected("Failed to join".into()),
+            }
+        }
+        Connection::Connected { .. } => {}
+    }
+
+    let peer = match &mut lobby.connection {
+        Connection::Connected { peer, .. } => peer,
+        _ => return,
+    };
+
+    let received = match peer.receive_lobby_states() {
+        Ok(received) => received,
+        Err(error) => return lobby.disconnected(format!("Lost the connection: {}", error)),
+    };
+
+    match received.last() {
+        // Once the host has started, they stick with what they last saw of the other player. Any
+        // changes that crossed paths with the start get overridden on the other end.
+        Some(_) if lobby.hosting && lobby.countdown.is_some() => {}
+        Some(&remote) => {
+            lobby.remote = Some(remote);
+
+            let starting = remote
+                .starting
+                .filter(|_| !lobby.hosting && lobby.countdown.is_none());
+
+            if let Some(colour) = starting {
+                lobby.local.side = remote.side.flip();
+                lobby.local.colour = colour;
+                lobby.local.ready = true;
+                lobby.changed = true;
+                lobby.countdown = Some(COUNTDOWN);
+            }
+        }
+        None => {}
+    }
+
+    if lobby.hosting && lobby.countdown.is_none() {
+        let both_ready = lobby.local.ready && lobby.remote.map(|remote| remote.ready) == Some(true);
+
+        if both_ready && lobby.problem().is_none() {
+            lobby.local.starting = lobby.remote.map(|remote| remote.colour);
+            lobby.changed = true;
+            lobby.countdown = Some(COUNTDOWN);
+        }
+    }
+
+    if std::mem::take(&mut lobby.changed) {
+        let local = lobby.local;
+        let sent = match &mut lobby.connection {
+            Connection::Connected { peer, .. } => peer.send_lobby_state(local),
+            _ => Ok(()),
+        };
+
+        if let Err(error) = sent {
+            return lobby.disconnected(format!("Lost the connection: {}", error));
+        }
+    }
+
+    let countdown = match &mut lobby.countdown {
+        Some(countdown) => countdown,
+        None => return,
+    };
+
+    *countdown -= delta_time.0;
+    if *countdown > 0.0 {
+        return;
+    }
+
+    // Both players load the map when their countdowns finish. They won't finish at exactly the same
+    // time, but the lockstep waits for whoever's behind anyway.
+    let map = lobby.map();
+    let remote = lobby.remote.unwrap();
+    let (peer, seed) = match std::mem::replace(&mut lobby.connection, Connection::Idle) {
+        Connection::Connected { peer, seed } => (peer, seed),
+        _ => unreachable!(),
+    };
+
+    *lockstep = Lockstep::new(peer, lobby.local.side, seed);
+    player_side.0 = lobby.local.side;
+    team_colours.0[lobby.local.side as usize] = lobby.local.colour as usize;
+    team_colours.0[remote.side as usize] = remote.colour as usize;
+    *mode = Mode::StartScenario(MULTIPLAYER_MAPS[map as usize].1);
+    *menu = Menu::Main;
+    *lobby = Lobby {
+        address: std::mem::take(&mut lobby.address),
+        ..Lobby::default()
+    };
+}
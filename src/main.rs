@@ -4,105 +4,278 @@
 
 mod animation;
 mod assets;
+mod audio;
+mod autosave;
+mod bench;
+mod camera;
+mod config;
+mod crumbs;
+mod deterministic;
 mod ecs;
+mod high_ground;
+mod hot_reload;
+mod input;
+mod lobby;
+mod mapgen;
+mod mission;
+mod net;
 mod pathfinding;
+mod profiler;
 mod renderer;
 mod resources;
 mod scenarios;
+mod scenery;
+mod simulation;
+mod terrain;
 mod titlescreen;
+mod water;
 
 use crate::assets::Assets;
+use crate::audio::{Audio, SoundEvents};
+use crate::camera::{Camera, CameraControls, CameraShake};
+use crate::input::{Action, Input};
+use crate::profiler::{PassTimer, Profiler};
 use crate::renderer::{
-    LineBuffers, Lines3dBuffer, Lines3dPipeline, LinesPipeline, ModelBuffers, ModelPipelines,
-    RenderContext, ShadowPipeline, TextBuffer, TitlescreenBuffer, TorusBuffer, TorusPipeline,
+    DecalBuffer, DecalPipeline, FogOfWarBuffer, FogOfWarPipeline, GraphResource, LineBuffers,
+    Lines3dBuffer, Lines3dPipeline, LinesPipeline, ModelBuffers, ModelPipelines, ParticlePipeline,
+    PostProcessing, RenderContext, RenderGraph, SceneryBuffers, Screenshots, ShadowPipeline,
+    SkyPipeline, Sunlight, TerrainPipeline, TextBuffer, TitlescreenBuffer, TorusBuffer,
+    TorusPipeline, WaterPipeline,
 };
 use crate::resources::{
-    AiBuildOrders, Camera, CameraControls, CheeseCoins, ControlGroups, CursorIcon, DebugControls,
-    DeltaTime, DpiScaling, GameStats, Gravity, Keypress, Keypresses, Mode, MouseState, Objectives,
-    PlayerSide, RayCastLocation, RtsControls, ScreenDimensions, SelectedUnitsAbilities, Settings,
-    TotalTime,
+    AiBuildOrders, CheeseCoins, ControlGroups, CursorIcon, DebugControls, DeltaTime, GameSpeed,
+    GameStats, Gravity, Keypress, Keypresses, Mode, MouseState, Objectives, PlayerSide,
+    RayCastLocation, RtsControls, ScreenDimensions, SelectedUnitsAbilities, Settings, TeamColours,
+    TimeOfDay, TotalTime, UnscaledDeltaTime,
 };
+use crate::terrain::Terrain;
 use legion::*;
 use rand::{rngs::SmallRng, SeedableRng};
 use ultraviolet::Vec2;
 use winit::{
     dpi::PhysicalPosition,
-    event::{ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta, WindowEvent},
+    event::{ElementState, Event, KeyboardInput, MouseScrollDelta, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
 };
 
 fn main() -> anyhow::Result<()> {
-    futures::executor::block_on(run())
+    env_logger::init();
+    autosave::install_panic_hook();
+
+    if let Some(options) = simulation::options() {
+        return simulation::run(options);
+    }
+
+    match bench::options() {
+        Some(options) if options.headless => bench::run_headless(bench::Bench::new(options)),
+        _ => futures::executor::block_on(run()),
+    }
+}
+
+// Run with `--mission <path>` to go straight into a mission script, instead of the one that's
+// started from the menu.
+fn mission_path() -> Option<std::path::PathBuf> {
+    let args: Vec<String> = std::env::args().collect();
+
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("--mission") => args.get(2).map(std::path::PathBuf::from),
+        _ => None,
+    }
+}
+
+// Run with `--load <path>` to load a save (such as an autosave or `crash_save.json`) straight away.
+// Load on the main menu loads it again too, instead of the quicksave.
+fn load_path() -> Option<std::path::PathBuf> {
+    let args: Vec<String> = std::env::args().collect();
+
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("--load") => args.get(2).map(std::path::PathBuf::from),
+        _ => None,
+    }
+}
+
+// Run with `--host <address>` or `--connect <address>` to play against someone over the network.
+// The host plays as green and whoever connects plays as purple.
+fn connect_to_peer() -> anyhow::Result<Option<(net::Peer, u64, ecs::Side)>> {
+    let args: Vec<String> = std::env::args().collect();
+
+    let (peer, seed, side) = match args.get(1).map(|arg| arg.as_str()) {
+        Some("--host") => {
+            let address = args
+                .get(2)
+                .map(|arg| arg.as_str())
+                .unwrap_or("0.0.0.0:4000");
+            let (peer, seed) = net::Peer::host(address)?;
+            (peer, seed, ecs::Side::Green)
+        }
+        Some("--connect") => {
+            let address = args
+                .get(2)
+                .ok_or_else(|| anyhow::anyhow!("--connect needs an address to connect to"))?;
+            let (peer, seed) = net::Peer::connect(address.as_str())?;
+            (peer, seed, ecs::Side::Purple)
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some((peer, seed, side)))
 }
 
 async fn run() -> anyhow::Result<()> {
-    env_logger::init();
+    // Connect to the other player before opening the window, as it blocks.
+    let (lockstep, player_side) = match connect_to_peer()? {
+        Some((peer, seed, side)) => (net::Lockstep::new(peer, side, seed), side),
+        None => (net::Lockstep::default(), ecs::Side::Green),
+    };
+
+    let chosen_mission_path = mission_path();
+    let mission_path = chosen_mission_path
+        .clone()
+        .unwrap_or_else(|| mission::DEFAULT_MISSION_PATH.into());
+
+    let chosen_load_path = load_path();
+    let load_path = chosen_load_path
+        .clone()
+        .unwrap_or_else(|| ecs::QUICKSAVE_PATH.into());
+    let mut autosaver = autosave::Autosaver::default();
+    let mut bench = bench::options().map(bench::Bench::new);
 
     let event_loop = EventLoop::new();
 
     let mut rng = SmallRng::from_entropy();
-    let settings = Settings::default();
+    let (mut config_watcher, mut settings) =
+        config::ConfigWatcher::new(std::path::Path::new(config::SETTINGS_PATH));
+    // Otherwise the frame times would all just be the refresh rate.
+    if bench.is_some() {
+        settings.vsync = false;
+    }
 
     let mut render_context = RenderContext::new(&event_loop, &settings).await?;
-    let (assets, animations, command_buffer) = Assets::new(&render_context.device())?;
+    let terrain = Terrain::generate();
+    let mut asset_watcher = hot_reload::AssetWatcher::new(settings.hot_reload);
+    let (mut assets, animations, command_buffer) = Assets::new(render_context.device(), &terrain)?;
     render_context.submit(command_buffer);
-    let model_pipelines = ModelPipelines::new(&render_context, &assets);
-    let torus_pipeline = TorusPipeline::new(&render_context);
-    let lines_pipeline = LinesPipeline::new(&render_context, &assets);
-    let lines_3d_pipeline = Lines3dPipeline::new(&render_context);
-    let shadow_pipeline = ShadowPipeline::new(&render_context);
-    let model_buffers = ModelBuffers::new(&render_context, &assets);
+    // These are all built for the sample count the render context was set up with, so they get
+    // recreated if the msaa setting changes (or the shaders are hot reloaded).
+    let mut model_pipelines = ModelPipelines::new(&render_context, &assets);
+    let mut torus_pipeline = TorusPipeline::new(&render_context);
+    let mut decal_pipeline = DecalPipeline::new(&render_context, &assets);
+    let mut lines_pipeline = LinesPipeline::new(&render_context, &assets);
+    let mut lines_3d_pipeline = Lines3dPipeline::new(&render_context);
+    let mut shadow_pipeline = ShadowPipeline::new(&render_context);
+    let mut fog_of_war_pipeline = FogOfWarPipeline::new(&render_context);
+    let mut particle_pipeline = ParticlePipeline::new(&render_context);
+    let mut terrain_pipeline = TerrainPipeline::new(&render_context, &assets);
+    let mut water_pipeline = WaterPipeline::new(&render_context, &water::LAKES);
+    let mut sky_pipeline = SkyPipeline::new(&render_context, &assets);
+    let mut post_processing = PostProcessing::new(&render_context, &settings);
+    let mut screenshots = Screenshots::new(&render_context);
+    let mut scenery_buffers = SceneryBuffers::default();
+    #[cfg(feature = "water-shader")]
+    let mut reflection_model_pipelines =
+        ModelPipelines::new_for_reflections(&render_context, &assets);
+    #[cfg(feature = "gpu-skinning")]
+    let mut skinning_pipeline = renderer::SkinningPipeline::new(&render_context);
+    #[cfg(feature = "crumbs")]
+    let crumbs = crumbs::scatter(&terrain);
+    #[cfg(feature = "crumbs")]
+    let mut crumb_pipeline = renderer::CrumbPipeline::new(&render_context, &crumbs);
+    let model_buffers = ModelBuffers::new(&render_context, &assets, &animations);
     let torus_buffer = TorusBuffer::new(render_context.device());
+    let decal_buffer = DecalBuffer::new(render_context.device());
     let lines_buffers = LineBuffers::new(render_context.device());
     let text_buffer = TextBuffer::new(render_context.device())?;
     let lines_3d_buffer = Lines3dBuffer::new(render_context.device());
     let titlescreen_buffer = TitlescreenBuffer::new(render_context.device(), &mut rng);
+    let fog_of_war_buffer = FogOfWarBuffer::new(render_context.device());
+    let mut audio = Audio::new(&settings);
 
     let mut world = World::default();
     let mut resources = Resources::default();
     resources.insert(model_buffers);
     resources.insert(torus_buffer);
+    resources.insert(decal_buffer);
     resources.insert(lines_buffers);
     resources.insert(text_buffer);
     resources.insert(lines_3d_buffer);
     resources.insert(titlescreen_buffer);
+    resources.insert(fog_of_war_buffer);
+    resources.insert(terrain);
     resources.insert(render_context.screen_dimensions());
     resources.insert(CameraControls::default());
     resources.insert(Camera::default());
+    resources.insert(SoundEvents::default());
+    resources.insert(CameraShake::default());
+    resources.insert(ecs::Alerts::default());
+    resources.insert(ecs::Upgrades::default());
+    resources.insert(settings.clone());
+    // What the window and audio were last set up with, to tell when they need updating.
+    let mut applied_settings = settings;
     resources.insert(MouseState::new(&render_context.screen_dimensions()));
     resources.insert(RtsControls::default());
     resources.insert(RayCastLocation::default());
-    resources.insert(PlayerSide(ecs::Side::Green));
+    resources.insert(PlayerSide(player_side));
     resources.insert(ControlGroups::default());
     resources.insert(titlescreen::TitlescreenMoon::default());
     resources.insert(titlescreen::Menu::Main);
-    resources.insert(Mode::Titlescreen);
+    resources.insert(lobby::Lobby::default());
+    resources.insert(TeamColours::default());
+    resources.insert(if lockstep.is_networked() {
+        Mode::StartScenario(scenarios::MULTIPLAYER_SCENARIO)
+    } else if chosen_mission_path.is_some() {
+        Mode::StartScenario(scenarios::MISSION_SCENARIO)
+    } else if chosen_load_path.is_some() {
+        Mode::LoadGame
+    } else if bench.is_some() {
+        Mode::StartScenario(scenarios::BENCH_SCENARIO)
+    } else {
+        Mode::Titlescreen
+    });
     resources.insert(DebugControls::default());
     resources.insert(Gravity(5.0));
     resources.insert(CheeseCoins(0));
     resources.insert(SelectedUnitsAbilities::default());
     resources.insert(Keypresses::default());
     resources.insert(TotalTime(0.0));
+    resources.insert(TimeOfDay::default());
     resources.insert(AiBuildOrders::default());
     resources.insert(GameStats::default());
     // Dpi scale factors are wierd. One of my laptops has it set at 1.33 and the other has it at 2.0.
     // Scaling things like selection boxes by 1.33 looks bad because one side can take up 1 pixel
     // and the other can take up 2 pixels. So I guess the best solution is to just floor the value
     // (a ui that's too small is generally better than one thats too big).
-    resources.insert(DpiScaling(
-        render_context.window.scale_factor().floor() as f32
+    resources.insert(ecs::ui_scale(
+        render_context.window.scale_factor(),
+        &applied_settings,
+        &render_context.screen_dimensions(),
     ));
     resources.insert(animations);
     resources.insert(pathfinding::Map::new());
     resources.insert(rng);
     resources.insert(Objectives::default());
+    resources.insert(ecs::FogOfWar::default());
+    resources.insert(ecs::PathRequests::default());
+    resources.insert(lockstep);
+    resources.insert(Profiler::default());
+    resources.insert(ecs::Inspector::default());
+    resources.insert(ecs::IdleUnits::default());
+    resources.insert(ecs::WorldChecksum::default());
+    resources.insert(ecs::Console::default());
+    resources.insert(ecs::SpatialHash::default());
+    resources.insert(GameSpeed::default());
+    resources.insert(mission::Mission::default());
+    resources.insert(scenery::Scenery::default());
 
     let mut titlescreen_schedule = titlescreen::titlescreen_schedule();
 
     let mut builder = Schedule::builder();
+    profiler::mark_stage(&mut builder, Some("Gameplay"));
     ecs::add_gameplay_systems(&mut builder);
+    profiler::mark_stage(&mut builder, Some("Rendering"));
     ecs::add_rendering_systems(&mut builder);
+    profiler::mark_stage(&mut builder, Some("Cleanup"));
     ecs::add_cleanup_systems(&mut builder);
+    profiler::mark_stage(&mut builder, None);
     let mut schedule = builder.build();
 
     let mut playing_menu_system = Schedule::builder();
@@ -111,19 +284,36 @@ async fn run() -> anyhow::Result<()> {
     ecs::add_cleanup_systems(&mut playing_menu_system);
     let mut playing_menu_system = playing_menu_system.build();
 
+    // Run while waiting for the other player's commands.
+    let mut waiting_for_peer_schedule = Schedule::builder();
+    ecs::add_rendering_systems(&mut waiting_for_peer_schedule);
+    let mut waiting_for_peer_schedule = waiting_for_peer_schedule.build();
+
+    // Run instead of the gameplay schedule while paused, so that you can still look around and
+    // give orders.
+    let mut paused_schedule = Schedule::builder();
+    ecs::add_paused_systems(&mut paused_schedule);
+    ecs::add_rendering_systems(&mut paused_schedule);
+    ecs::add_cleanup_systems(&mut paused_schedule);
+    let mut paused_schedule = paused_schedule.build();
+
     let mut time = std::time::Instant::now();
+    let mut tick_accumulator = 0.0;
+    let mut quicksave_pressed = false;
+    let mut quickload_pressed = false;
+    let mut alt_held = false;
 
     event_loop.run(move |event, _, control_flow| {
         match event {
             Event::WindowEvent { ref event, .. } => match event {
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                WindowEvent::Resized(size) => {
-                    render_context.resize(size.width as u32, size.height as u32);
-                    resources.insert(ScreenDimensions {
-                        width: size.width as u32,
-                        height: size.height as u32,
-                    })
+                WindowEvent::Resized(size) => resize(&mut render_context, &mut resources, *size),
+                // Moving the window to a monitor with a different dpi. The ui scale is updated along
+                // with the settings.
+                WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                    resize(&mut render_context, &mut resources, **new_inner_size)
                 }
+                WindowEvent::ModifiersChanged(modifiers) => alt_held = modifiers.alt(),
                 WindowEvent::KeyboardInput {
                     input:
                         KeyboardInput {
@@ -135,10 +325,99 @@ async fn run() -> anyhow::Result<()> {
                     ..
                 } => {
                     let pressed = *state == ElementState::Pressed;
+
+                    // Alt+Enter isn't rebindable, as it's what every other game uses for this. The
+                    // change goes through the settings so that it's remembered.
+                    let enter = matches!(
+                        code,
+                        Some(VirtualKeyCode::Return) | Some(VirtualKeyCode::NumpadEnter)
+                    );
+                    if pressed && alt_held && enter {
+                        let mut settings = resources.get_mut::<Settings>().unwrap();
+                        if !settings.fullscreen {
+                            // So that leaving fullscreen goes back to the same size.
+                            settings.resolution = render_context.window_size();
+                        }
+                        settings.fullscreen = !settings.fullscreen;
+                        return;
+                    }
+
                     let mode = resources.get::<Mode>().unwrap();
+                    let networked = resources.get::<net::Lockstep>().unwrap().is_networked();
+                    let settings = resources.get::<Settings>().unwrap();
+
+                    let toggle_profiler = code.is_some_and(|code| {
+                        settings
+                            .input_map
+                            .actions(Input::Key(code))
+                            .any(|action| action == Action::ToggleProfiler)
+                    });
+                    if pressed && toggle_profiler {
+                        let mut profiler = resources.get_mut::<Profiler>().unwrap();
+                        profiler.enabled = !profiler.enabled;
+                    }
+
+                    if pressed {
+                        let actions = code
+                            .into_iter()
+                            .flat_map(|code| settings.input_map.actions(Input::Key(*code)));
+
+                        for action in actions {
+                            match action {
+                                Action::Screenshot => screenshots.take_screenshot(),
+                                Action::ToggleRecording => screenshots.toggle_recording(),
+                                _ => {}
+                            }
+                        }
+                    }
+
+                    let toggle_console = code.is_some_and(|code| {
+                        settings
+                            .input_map
+                            .actions(Input::Key(code))
+                            .any(|action| action == Action::ToggleConsole)
+                    });
+                    let mut console = resources.get_mut::<ecs::Console>().unwrap();
+                    if pressed && toggle_console && *mode == Mode::Playing {
+                        console.open = !console.open;
+                    }
+
+                    if console.open && pressed {
+                        match code {
+                            Some(VirtualKeyCode::Escape) => console.open = false,
+                            Some(VirtualKeyCode::Return) | Some(VirtualKeyCode::NumpadEnter) => {
+                                console.submit()
+                            }
+                            Some(VirtualKeyCode::Back) => {
+                                console.input.pop();
+                            }
+                            Some(VirtualKeyCode::Up) => console.recall_last(),
+                            _ => {}
+                        }
+                        // Don't let typing trigger any hotkeys. Releases still go through so that
+                        // keys that were held when the console was opened don't get stuck.
+                        return;
+                    }
+
+                    // Saving and loading needs the whole world, so it's done outside of the
+                    // schedule. It doesn't make sense over the network though.
+                    if pressed && *mode == Mode::Playing && !networked {
+                        let actions = code
+                            .into_iter()
+                            .flat_map(|code| settings.input_map.actions(Input::Key(*code)));
+
+                        for action in actions {
+                            match action {
+                                Action::Quicksave => quicksave_pressed = true,
+                                Action::Quickload => quickload_pressed = true,
+                                _ => {}
+                            }
+                        }
+                    }
+
                     let mut keypresses = resources.get_mut::<Keypresses>().unwrap();
                     // We only push keypresses in modes that consume them. This is a bit messy.
-                    if matches!(*mode, Mode::Playing | Mode::PlayingMenu) {
+                    if matches!(*mode, Mode::Playing | Mode::PlayingMenu | Mode::Titlescreen) {
                         keypresses.0.push(Keypress {
                             code: *code,
                             scancode: *scancode,
@@ -146,6 +425,18 @@ async fn run() -> anyhow::Result<()> {
                         });
                     }
                 }
+                WindowEvent::ReceivedCharacter(character) => {
+                    let mut lobby = resources.get_mut::<lobby::Lobby>().unwrap();
+                    if lobby.editing_address && !character.is_control() {
+                        lobby.address.push(*character);
+                    }
+
+                    let mut console = resources.get_mut::<ecs::Console>().unwrap();
+                    // The backtick comes through as a character when the console is opened.
+                    if console.open && !character.is_control() && *character != '`' {
+                        console.input.push(*character);
+                    }
+                }
                 WindowEvent::MouseWheel { delta, .. } => {
                     let mut camera_controls = resources.get_mut::<CameraControls>().unwrap();
 
@@ -156,17 +447,33 @@ async fn run() -> anyhow::Result<()> {
                 }
                 WindowEvent::CursorMoved { position, .. } => {
                     let mut mouse_state = resources.get_mut::<MouseState>().unwrap();
-                    mouse_state.position = Vec2::new(position.x as f32, position.y as f32);
+                    let mut camera_controls = resources.get_mut::<CameraControls>().unwrap();
+                    let position = Vec2::new(position.x as f32, position.y as f32);
+
+                    if camera_controls.middle_held {
+                        camera_controls.drag_delta += position - mouse_state.position;
+                    }
+
+                    mouse_state.position = position;
                 }
                 WindowEvent::MouseInput { state, button, .. } => {
                     let pressed = *state == ElementState::Pressed;
 
                     let mut mouse_state = resources.get_mut::<MouseState>().unwrap();
+                    let settings = resources.get::<Settings>().unwrap();
                     let position = mouse_state.position;
-                    match button {
-                        MouseButton::Left => mouse_state.left_state.handle(position, pressed),
-                        MouseButton::Right => mouse_state.right_state.handle(position, pressed),
-                        _ => {}
+                    // Mouse buttons only do anything when bound to one of the pointer actions.
+                    for action in settings.input_map.actions(Input::Mouse(*button)) {
+                        match action {
+                            Action::Select => mouse_state.left_state.handle(position, pressed),
+                            Action::Command => mouse_state.right_state.handle(position, pressed),
+                            Action::DragCamera => {
+                                let mut camera_controls =
+                                    resources.get_mut::<CameraControls>().unwrap();
+                                camera_controls.middle_held = pressed;
+                            }
+                            _ => {}
+                        }
                     }
                 }
                 _ => {}
@@ -176,89 +483,232 @@ async fn run() -> anyhow::Result<()> {
                 let elapsed = (now - time).as_secs_f32();
                 time = now;
                 resources.insert(DeltaTime(elapsed));
+                resources.insert(UnscaledDeltaTime(elapsed));
                 resources.insert(CursorIcon(winit::window::CursorIcon::default()));
 
-                let mut mode: Mode = *resources.get_mut::<Mode>().unwrap();
+                let reloaded_settings = config_watcher.poll();
+                if let Some(settings) = &reloaded_settings {
+                    *resources.get_mut::<Settings>().unwrap() = settings.clone();
+                }
 
-                if let Mode::StartScenario(scenario) = mode {
-                    let animations = resources.get::<assets::ModelAnimations>().unwrap();
-                    let mut map = resources.get_mut::<pathfinding::Map>().unwrap();
-                    let mut rng = resources.get_mut::<SmallRng>().unwrap();
-                    let mut objectives = resources.get_mut::<Objectives>().unwrap();
-                    let mut camera = resources.get_mut::<Camera>().unwrap();
-                    let mut cheese_coins = resources.get_mut::<CheeseCoins>().unwrap();
-                    let mut ai_build_orders = resources.get_mut::<AiBuildOrders>().unwrap();
-                    resources.get_mut::<TotalTime>().unwrap().0 = 0.0;
-                    *resources.get_mut::<GameStats>().unwrap() = GameStats::default();
-                    *map = pathfinding::Map::new();
-
-                    world.clear();
-
-                    match scenario {
-                        1 => {
-                            scenarios::one(
-                                &mut world,
-                                &animations,
-                                &mut map,
-                                &mut rng,
-                                &mut objectives,
-                                &mut camera,
-                                &mut cheese_coins,
-                                &mut ai_build_orders,
-                            );
-                        }
-                        2 => {
-                            scenarios::two(
-                                &mut world,
-                                &animations,
-                                &mut map,
-                                &mut rng,
-                                &mut objectives,
-                                &mut camera,
-                                &mut cheese_coins,
-                                &mut ai_build_orders,
-                            );
-                        }
-                        3 => {
-                            scenarios::three(
-                                &mut world,
-                                &animations,
-                                &mut map,
-                                &mut rng,
-                                &mut objectives,
-                                &mut camera,
-                                &mut cheese_coins,
-                                &mut ai_build_orders,
-                            );
+                let settings = resources.get::<Settings>().unwrap().clone();
+                if settings != applied_settings {
+                    config::apply(
+                        &applied_settings,
+                        &settings,
+                        &mut render_context,
+                        &mut audio,
+                    );
+                }
+
+                // Recalculated every frame so that it follows the window being resized, moved to
+                // another monitor or `ui_scale` being changed.
+                let ui_scale = ecs::ui_scale(
+                    render_context.window.scale_factor(),
+                    &settings,
+                    &resources.get::<ScreenDimensions>().unwrap(),
+                );
+                resources.insert(ui_scale);
+
+                let reloaded_assets = asset_watcher.poll(settings.hot_reload);
+                if let Some(changes) = &reloaded_assets {
+                    // The animations that come back are ignored, see `hot_reload`.
+                    if changes.assets {
+                        let terrain = resources.get::<Terrain>().unwrap();
+                        match Assets::new(render_context.device(), &terrain) {
+                            Ok((new_assets, _, command_buffer)) => {
+                                render_context.submit(command_buffer);
+                                assets = new_assets;
+                                log::info!("Reloaded the models and textures");
+                            }
+                            Err(error) => log::error!("Failed to reload the assets: {}", error),
                         }
-                        255 => scenarios::sandbox(
-                            &mut world,
-                            &animations,
-                            &mut map,
-                            &mut rng,
-                            &mut objectives,
-                            &mut camera,
-                            &mut cheese_coins,
-                            &mut ai_build_orders,
-                        ),
-                        _ => {}
                     }
+                    if changes.shaders {
+                        log::info!("Reloaded the shaders");
+                    }
+                }
+
+                if settings.msaa != applied_settings.msaa || reloaded_assets.is_some() {
+                    model_pipelines = ModelPipelines::new(&render_context, &assets);
+                    torus_pipeline = TorusPipeline::new(&render_context);
+                    decal_pipeline = DecalPipeline::new(&render_context, &assets);
+                    lines_pipeline = LinesPipeline::new(&render_context, &assets);
+                    lines_3d_pipeline = Lines3dPipeline::new(&render_context);
+                    fog_of_war_pipeline = FogOfWarPipeline::new(&render_context);
+                    particle_pipeline = ParticlePipeline::new(&render_context);
+                    terrain_pipeline = TerrainPipeline::new(&render_context, &assets);
+                    water_pipeline = WaterPipeline::new(&render_context, &water::LAKES);
+                    sky_pipeline = SkyPipeline::new(&render_context, &assets);
+                    #[cfg(feature = "crumbs")]
+                    {
+                        crumb_pipeline = renderer::CrumbPipeline::new(&render_context, &crumbs);
+                    }
+                }
+
+                // These don't depend on the msaa setting, so they only need rebuilding for new
+                // shaders.
+                if reloaded_assets.is_some() {
+                    shadow_pipeline = ShadowPipeline::new(&render_context);
+                    post_processing = PostProcessing::new(&render_context, &settings);
+                    #[cfg(feature = "water-shader")]
+                    {
+                        reflection_model_pipelines =
+                            ModelPipelines::new_for_reflections(&render_context, &assets);
+                    }
+                    #[cfg(feature = "gpu-skinning")]
+                    {
+                        skinning_pipeline = renderer::SkinningPipeline::new(&render_context);
+                    }
+                }
+
+                if settings != applied_settings {
+                    post_processing.configure(&render_context, &settings);
+                    // Changes made in the settings menu get saved to the file.
+                    if reloaded_settings.is_none() {
+                        config_watcher.save(&settings);
+                    }
+                    applied_settings = settings;
+                }
+
+                let mut mode: Mode = *resources.get_mut::<Mode>().unwrap();
+
+                if let Mode::StartScenario(scenario) = mode {
+                    let network_seed = resources.get::<net::Lockstep>().unwrap().seed();
+                    scenarios::start(
+                        &mut world,
+                        &resources,
+                        scenario,
+                        network_seed,
+                        &mission_path,
+                        bench.as_ref().map(|bench| bench.options.units).unwrap_or(0),
+                    );
+                    tick_accumulator = 0.0;
+
                     // Gotta change both the Mode in resources and the local copy.
                     *resources.get_mut::<Mode>().unwrap() = Mode::Playing;
                     mode = Mode::Playing;
+                    autosaver.reset(0.0);
+                }
+
+                if mode == Mode::LoadGame {
+                    mode = match ecs::load_game(&mut world, &mut resources, &load_path) {
+                        Ok(()) => {
+                            tick_accumulator = 0.0;
+                            autosaver.reset(resources.get::<TotalTime>().unwrap().0);
+                            Mode::Playing
+                        }
+                        Err(error) => {
+                            log::error!("Failed to load the game: {}", error);
+                            Mode::Titlescreen
+                        }
+                    };
+                    *resources.get_mut::<Mode>().unwrap() = mode;
                 }
 
+                if std::mem::take(&mut quicksave_pressed) {
+                    let path = std::path::Path::new(ecs::QUICKSAVE_PATH);
+                    if let Err(error) = ecs::save_game(&world, &resources, path) {
+                        log::error!("Failed to save the game: {}", error);
+                    }
+                }
+
+                if std::mem::take(&mut quickload_pressed) {
+                    let path = std::path::Path::new(ecs::QUICKSAVE_PATH);
+                    match ecs::load_game(&mut world, &mut resources, path) {
+                        Ok(()) => autosaver.reset(resources.get::<TotalTime>().unwrap().0),
+                        Err(error) => log::error!("Failed to load the game: {}", error),
+                    }
+                }
+
+                let mut sim_time_elapsed = elapsed;
+
                 match mode {
-                    Mode::Playing => schedule.execute(&mut world, &mut resources),
+                    Mode::Playing if resources.get::<net::Lockstep>().unwrap().is_networked() => {
+                        // Over the network, the simulation runs at a fixed timestep so that it
+                        // stays in sync. Don't try and catch up on more than a few ticks though.
+                        tick_accumulator = (tick_accumulator + elapsed).min(net::TICK_LENGTH * 5.0);
+                        sim_time_elapsed = 0.0;
+
+                        let ready = tick_accumulator >= net::TICK_LENGTH
+                            && resources
+                                .get_mut::<net::Lockstep>()
+                                .unwrap()
+                                .poll()
+                                .unwrap_or_else(|error| {
+                                    log::error!("Lost connection to the other player: {}", error);
+                                    *control_flow = ControlFlow::Exit;
+                                    false
+                                });
+
+                        if ready {
+                            tick_accumulator -= net::TICK_LENGTH;
+                            sim_time_elapsed = net::TICK_LENGTH;
+                            resources.insert(DeltaTime(net::TICK_LENGTH));
+                            schedule.execute(&mut world, &mut resources);
+
+                            if let Err(error) =
+                                resources.get_mut::<net::Lockstep>().unwrap().end_tick()
+                            {
+                                log::error!("Lost connection to the other player: {}", error);
+                                *control_flow = ControlFlow::Exit;
+                            }
+                        } else {
+                            waiting_for_peer_schedule.execute(&mut world, &mut resources);
+                        }
+                    }
+                    Mode::Playing if resources.get::<GameSpeed>().unwrap().paused => {
+                        sim_time_elapsed = 0.0;
+                        resources.insert(DeltaTime(0.0));
+                        paused_schedule.execute(&mut world, &mut resources);
+                    }
+                    Mode::Playing => {
+                        // Briefings can slow down or pause the game.
+                        let game_speed = resources.get::<GameSpeed>().unwrap().speed
+                            * resources.get::<mission::Mission>().unwrap().game_speed();
+                        sim_time_elapsed *= game_speed;
+                        if bench.is_some() {
+                            sim_time_elapsed = net::TICK_LENGTH;
+                        }
+                        resources.insert(DeltaTime(sim_time_elapsed));
+                        schedule.execute(&mut world, &mut resources);
+                        // Not networked, so this can't fail.
+                        let _ = resources.get_mut::<net::Lockstep>().unwrap().end_tick();
+                    }
                     Mode::Titlescreen => titlescreen_schedule.execute(&mut world, &mut resources),
                     Mode::Quit => *control_flow = ControlFlow::Exit,
-                    Mode::ScenarioWon | Mode::ScenarioLost | Mode::PlayingMenu => {
+                    Mode::GameOver(_) | Mode::PlayingMenu => {
                         playing_menu_system.execute(&mut world, &mut resources)
                     }
-                    Mode::StartScenario(_) => unreachable!(),
+                    Mode::StartScenario(_) | Mode::LoadGame => unreachable!(),
+                }
+
+                resources.get_mut::<TotalTime>().unwrap().0 += sim_time_elapsed;
+
+                if let (Some(bench), Mode::Playing) = (&mut bench, mode) {
+                    let finished = bench.record_frame(
+                        std::time::Duration::from_secs_f32(elapsed),
+                        &resources.get::<Profiler>().unwrap(),
+                        resources
+                            .get::<pathfinding::Map>()
+                            .unwrap()
+                            .take_query_count(),
+                    );
+
+                    if finished {
+                        bench.finish();
+                        *control_flow = ControlFlow::Exit;
+                    }
+                } else if mode == Mode::Playing {
+                    let total_time = resources.get::<TotalTime>().unwrap().0;
+                    let settings = resources.get::<Settings>().unwrap();
+                    autosaver.update(&world, &resources, total_time, &settings);
                 }
 
-                resources.get_mut::<TotalTime>().unwrap().0 += elapsed;
+                audio.play(
+                    &mut resources.get_mut::<SoundEvents>().unwrap(),
+                    &resources.get::<Camera>().unwrap(),
+                );
 
                 let cursor_icon = resources.get::<CursorIcon>().unwrap();
                 render_context.set_cursor_icon(cursor_icon.0);
@@ -267,278 +717,296 @@ async fn run() -> anyhow::Result<()> {
             Event::RedrawRequested(_) => {
                 let mut model_buffers = resources.get_mut::<ModelBuffers>().unwrap();
                 let mut torus_buffer = resources.get_mut::<TorusBuffer>().unwrap();
+                let mut decal_buffer = resources.get_mut::<DecalBuffer>().unwrap();
                 let mut line_buffers = resources.get_mut::<LineBuffers>().unwrap();
                 let mut text_buffer = resources.get_mut::<TextBuffer>().unwrap();
                 let mut lines_3d_buffer = resources.get_mut::<Lines3dBuffer>().unwrap();
                 let titlescreen_buffer = resources.get::<TitlescreenBuffer>().unwrap();
+                let mut fog_of_war_buffer = resources.get_mut::<FogOfWarBuffer>().unwrap();
                 let mode = *resources.get::<Mode>().unwrap();
+                // Read the settings each frame so that changing them in the menu takes effect.
+                let settings = resources.get::<Settings>().unwrap();
+                let mut profiler = resources.get_mut::<Profiler>().unwrap();
+                let mut passes = PassTimer::new();
 
                 // Upload buffers to the gpu.
 
+                passes.next("Upload");
+
                 match mode {
-                    Mode::Playing | Mode::ScenarioWon | Mode::ScenarioLost => {
+                    Mode::Playing | Mode::GameOver(_) => {
                         let camera = resources.get::<Camera>().unwrap();
-                        render_context.update_from_camera(&camera);
+                        let time_of_day = resources.get::<TimeOfDay>().unwrap();
+                        render_context.set_sunlight(Sunlight::at(&time_of_day));
+                        let camera_shake = resources.get::<CameraShake>().unwrap();
+                        render_context.update_from_camera(&camera, &camera_shake);
                     }
                     Mode::Titlescreen => {
+                        render_context.set_sunlight(Sunlight::default());
                         render_context.update_view(titlescreen::camera_view());
                     }
                     _ => {}
                 }
 
+                sky_pipeline.update(
+                    &render_context,
+                    &assets,
+                    &resources.get::<TimeOfDay>().unwrap(),
+                    settings.dynamic_sky,
+                );
                 model_buffers.upload(&render_context, &assets);
                 torus_buffer.upload(&render_context);
+                decal_buffer.upload(&render_context);
                 line_buffers.upload(&render_context);
                 lines_3d_buffer.upload(&render_context);
                 titlescreen_buffer.upload(&render_context);
+                fog_of_war_buffer.upload(&render_context);
+                scenery_buffers.upload(
+                    &render_context,
+                    &resources.get::<scenery::Scenery>().unwrap(),
+                    &resources.get::<Terrain>().unwrap(),
+                );
+                #[cfg(feature = "water-shader")]
+                water_pipeline
+                    .update_time(&render_context, resources.get::<TotalTime>().unwrap().0);
+                #[cfg(feature = "crumbs")]
+                crumb_pipeline
+                    .update_time(&render_context, resources.get::<TotalTime>().unwrap().0);
+
+                if profiler.enabled {
+                    profiler.buffers = model_buffers.stats();
+                    profiler.buffers.push(decal_buffer.stats());
+                }
 
                 if let Ok(frame) = render_context.swap_chain.get_current_frame() {
+                    use GraphResource::*;
+
                     let mut encoder = render_context.device.create_command_encoder(
                         &wgpu::CommandEncoderDescriptor {
                             label: Some("Cheese render encoder"),
                         },
                     );
+                    let render_context = &render_context;
+                    let assets = &assets;
+                    let mut graph = RenderGraph::<wgpu::CommandEncoder>::new();
 
-                    // Shadow pass
-
-                    let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                        color_attachments: &[],
-                        depth_stencil_attachment: Some(
-                            wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                                attachment: &render_context.shadow_texture,
-                                depth_ops: Some(wgpu::Operations {
-                                    load: wgpu::LoadOp::Clear(1.0),
-                                    store: true,
-                                }),
-                                stencil_ops: None,
-                            },
-                        ),
+                    #[cfg(feature = "gpu-skinning")]
+                    graph.add_pass("Skinning", &[], &[Joints], |encoder| {
+                        skinning_pipeline.compute(
+                            encoder,
+                            &render_context.device,
+                            &[
+                                &model_buffers.mice_marines_joints,
+                                &model_buffers.mice_engineers_joints,
+                                &model_buffers.mice_corpses_joints,
+                                &model_buffers.mice_outlines_joints,
+                                &model_buffers.pump_joints,
+                            ],
+                        )
                     });
 
-                    if mode.should_render() {
-                        render_shadows(&mut shadow_pass, &shadow_pipeline, &model_buffers, &assets);
-                    }
+                    // The shadow map gets cleared even when shadows are off.
+                    graph.add_pass("Shadows", &[Joints], &[ShadowMap], |encoder| {
+                        let mut shadow_pass =
+                            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                                color_attachments: &[],
+                                depth_stencil_attachment: Some(
+                                    wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                                        attachment: &render_context.shadow_texture,
+                                        depth_ops: Some(wgpu::Operations {
+                                            load: wgpu::LoadOp::Clear(1.0),
+                                            store: true,
+                                        }),
+                                        stencil_ops: None,
+                                    },
+                                ),
+                            });
 
-                    drop(shadow_pass);
-
-                    // Main rendering pass
-
-                    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                        color_attachments: &[
-                            wgpu::RenderPassColorAttachmentDescriptor {
-                                attachment: &render_context.framebuffer,
-                                resolve_target: None,
-                                ops: wgpu::Operations {
-                                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                                        r: 0.0,
-                                        g: 0.0,
-                                        b: 0.0,
-                                        a: 1.0,
-                                    }),
-                                    store: true,
-                                },
-                            },
-                            wgpu::RenderPassColorAttachmentDescriptor {
-                                attachment: &render_context.bloombuffer,
-                                resolve_target: None,
-                                ops: wgpu::Operations {
-                                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                                        r: 0.0,
-                                        g: 0.0,
-                                        b: 0.0,
-                                        a: 1.0,
-                                    }),
-                                    store: true,
-                                },
-                            },
-                        ],
-                        depth_stencil_attachment: Some(
-                            wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                                attachment: &render_context.depth_texture,
-                                depth_ops: Some(wgpu::Operations {
-                                    load: wgpu::LoadOp::Clear(1.0),
-                                    store: true,
-                                }),
-                                stencil_ops: None,
-                            },
-                        ),
+                        if settings.shadows && mode.should_render() {
+                            render_shadows(
+                                &mut shadow_pass,
+                                &shadow_pipeline,
+                                &model_buffers,
+                                &scenery_buffers,
+                                assets,
+                            );
+                        }
                     });
 
-                    if mode.should_render() {
-                        render_playing(
-                            &mut render_pass,
-                            &model_pipelines,
-                            &model_buffers,
-                            &torus_pipeline,
-                            &torus_buffer,
-                            &lines_pipeline,
-                            &line_buffers,
-                            &lines_3d_pipeline,
-                            &lines_3d_buffer,
-                            &assets,
-                        );
-                    } else if let Mode::Titlescreen = mode {
-                        model_pipelines.render_single_with_transform(
-                            &mut render_pass,
-                            &assets.cheese_moon_model,
-                            &assets.surface_texture,
-                            &titlescreen_buffer.moon,
-                        );
-                        model_pipelines.render_transparent_buffer(
-                            &mut render_pass,
-                            &assets.billboard_model,
-                            &titlescreen_buffer.stars,
-                            titlescreen_buffer.num_stars,
-                        );
-                        lines_pipeline.render(&mut render_pass, &line_buffers, &assets);
-                    }
+                    #[cfg(feature = "water-shader")]
+                    graph.add_pass("Reflections", &[Joints], &[Reflections], |encoder| {
+                        let mut reflection_pass = water_pipeline.begin_reflection_pass(encoder);
 
-                    drop(render_pass);
+                        if mode.should_render() {
+                            render_reflections(
+                                &mut reflection_pass,
+                                &reflection_model_pipelines,
+                                &model_buffers,
+                                assets,
+                            );
+                        }
+                    });
 
-                    if settings.bloom {
-                        // First bloom pass
-                        let mut render_pass =
-                            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                                    attachment: &render_context.bloombuffer_after_vertical,
-                                    resolve_target: None,
-                                    ops: wgpu::Operations {
-                                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                                            r: 0.0,
-                                            g: 0.0,
-                                            b: 0.0,
-                                            a: 1.0,
-                                        }),
-                                        store: true,
-                                    },
-                                }],
-                                depth_stencil_attachment: None,
-                            });
+                    graph.add_pass(
+                        "Opaque",
+                        &[Joints, ShadowMap, Reflections],
+                        &[Scene, Bloom],
+                        |encoder| {
+                            let mut render_pass = render_context.begin_scene_pass(encoder, true);
 
-                        render_pass.set_pipeline(&render_context.bloom_blur_pipeline);
-                        render_pass.set_bind_group(
-                            0,
-                            &render_context.bloom_first_pass_bind_group,
-                            &[],
-                        );
-                        render_pass.draw(0..3, 0..1);
+                            if mode.should_render() {
+                                sky_pipeline.render(&mut render_pass);
+                                // Before the fog of war, so that it hides them.
+                                #[cfg(feature = "crumbs")]
+                                crumb_pipeline.render(&mut render_pass);
+                                render_opaque(
+                                    &mut render_pass,
+                                    &model_pipelines,
+                                    &model_buffers,
+                                    &scenery_buffers,
+                                    &torus_pipeline,
+                                    &torus_buffer,
+                                    &decal_pipeline,
+                                    &decal_buffer,
+                                    &lines_3d_pipeline,
+                                    &lines_3d_buffer,
+                                    &fog_of_war_pipeline,
+                                    &fog_of_war_buffer,
+                                    &terrain_pipeline,
+                                    &water_pipeline,
+                                    assets,
+                                );
+                            } else if let Mode::Titlescreen = mode {
+                                model_pipelines.render_single_with_transform(
+                                    &mut render_pass,
+                                    &assets.cheese_moon_model,
+                                    &assets.surface_texture,
+                                    &titlescreen_buffer.moon,
+                                );
+                            }
+                        },
+                    );
 
-                        drop(render_pass);
+                    graph.add_pass(
+                        "Transparent",
+                        &[Joints, ShadowMap],
+                        &[Scene, Bloom],
+                        |encoder| {
+                            let mut render_pass = render_context.begin_scene_pass(encoder, false);
 
-                        // Second bloom pass and composit onto framebuffer
-                        let mut render_pass =
-                            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                                    attachment: &render_context.framebuffer,
-                                    resolve_target: None,
-                                    ops: wgpu::Operations {
-                                        load: wgpu::LoadOp::Load,
-                                        store: true,
-                                    },
-                                }],
-                                depth_stencil_attachment: None,
-                            });
+                            if mode.should_render() {
+                                render_transparent(
+                                    &mut render_pass,
+                                    &model_pipelines,
+                                    &model_buffers,
+                                    &particle_pipeline,
+                                    assets,
+                                );
+                            } else if let Mode::Titlescreen = mode {
+                                model_pipelines.render_transparent_buffer(
+                                    &mut render_pass,
+                                    &assets.billboard_model,
+                                    &titlescreen_buffer.stars,
+                                    titlescreen_buffer.num_stars,
+                                );
+                            }
 
-                        render_pass.set_pipeline(&render_context.bloom_blur_pipeline);
-                        render_pass.set_bind_group(
-                            0,
-                            &render_context.bloom_second_pass_bind_group,
-                            &[],
-                        );
-                        render_pass.draw(0..3, 0..1);
+                            // The 2D lines get drawn over the top of everything else.
+                            if mode.should_render() || matches!(mode, Mode::Titlescreen) {
+                                lines_pipeline.render(&mut render_pass, &line_buffers, assets);
+                            }
+                        },
+                    );
 
-                        drop(render_pass);
-                    }
+                    graph.add_pass("Bloom", &[Bloom], &[Bloom], |encoder| {
+                        post_processing.render_bloom(encoder, render_context)
+                    });
 
                     // Darken the screen if we're in a menu.
                     if mode.is_playing_menu() {
-                        let mut render_pass =
-                            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                                    attachment: &render_context.framebuffer,
-                                    resolve_target: None,
-                                    ops: wgpu::Operations {
-                                        load: wgpu::LoadOp::Load,
-                                        store: true,
-                                    },
-                                }],
-                                depth_stencil_attachment: None,
-                            });
+                        graph.add_pass("Darken", &[], &[Scene], |encoder| {
+                            let mut render_pass =
+                                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                                    color_attachments: &[
+                                        wgpu::RenderPassColorAttachmentDescriptor {
+                                            attachment: &render_context.framebuffer,
+                                            resolve_target: None,
+                                            ops: wgpu::Operations {
+                                                load: wgpu::LoadOp::Load,
+                                                store: true,
+                                            },
+                                        },
+                                    ],
+                                    depth_stencil_attachment: None,
+                                });
 
-                        render_pass.set_pipeline(&render_context.darken_pipeline);
-                        render_pass.draw(0..3, 0..1);
-                        drop(render_pass);
+                            render_pass.set_pipeline(&render_context.darken_pipeline);
+                            render_pass.draw(0..3, 0..1);
+                        });
                     }
-                    // Post-processing pass
-
-                    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                        color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                            attachment: &frame.output.view,
-                            resolve_target: None,
-                            ops: wgpu::Operations {
-                                load: wgpu::LoadOp::Clear(wgpu::Color {
-                                    r: 0.0,
-                                    g: 0.0,
-                                    b: 0.0,
-                                    a: 1.0,
-                                }),
-                                store: true,
-                            },
-                        }],
-                        depth_stencil_attachment: None,
-                    });
 
-                    render_pass.set_pipeline(&render_context.post_processing_pipeline);
-                    render_pass.set_bind_group(0, &render_context.framebuffer_bind_group, &[]);
-                    render_pass.draw(0..3, 0..1);
+                    // Frames that are being saved get composited somewhere they can be copied
+                    // from first.
+                    let output = screenshots.begin_frame(render_context, &frame.output.view);
 
-                    drop(render_pass);
-
-                    // Text rendering pass
-
-                    let size = render_context.window.inner_size();
-                    let mut staging_belt = wgpu::util::StagingBelt::new(10);
+                    graph.add_pass("Composite", &[Scene, Bloom], &[Output], |encoder| {
+                        post_processing.render_composite(encoder, render_context, output)
+                    });
 
-                    // Now render all the text to a seperate render pass.
-                    text_buffer
-                        .glyph_brush
-                        .draw_queued(
-                            &render_context.device,
-                            &mut staging_belt,
-                            &mut encoder,
-                            &frame.output.view,
-                            size.width,
-                            size.height,
-                        )
-                        .unwrap();
+                    graph.add_pass("Text", &[], &[Output], |encoder| {
+                        text_buffer.render(render_context, encoder, output)
+                    });
 
-                    staging_belt.finish();
+                    graph.execute(&mut encoder, |pass| passes.next(pass));
 
-                    // Do I need to do this?
-                    // staging_belt.recall();
+                    screenshots.end_frame(render_context, &mut encoder, &frame.output.view);
 
+                    passes.next("Submit");
                     render_context.queue.submit(Some(encoder.finish()));
+                    screenshots.save_finished(render_context);
                 }
+
+                passes.finish(&mut profiler);
             }
             _ => {}
         }
     });
 }
 
+fn resize(
+    render_context: &mut RenderContext,
+    resources: &mut Resources,
+    size: winit::dpi::PhysicalSize<u32>,
+) {
+    render_context.resize(size.width, size.height);
+
+    // Minimised.
+    if size.width > 0 && size.height > 0 {
+        resources.insert(ScreenDimensions {
+            width: size.width,
+            height: size.height,
+        });
+    }
+}
+
 fn render_shadows<'a>(
     shadow_pass: &mut wgpu::RenderPass<'a>,
     shadow_pipeline: &'a ShadowPipeline,
     model_buffers: &'a ModelBuffers,
+    scenery_buffers: &'a SceneryBuffers,
     assets: &'a Assets,
 ) {
     shadow_pipeline.render_static(shadow_pass, &assets.armoury_model, &model_buffers.armouries);
+    shadow_pipeline.render_static(shadow_pass, &assets.cheese_moon_model, &model_buffers.rocks);
+    shadow_pipeline.render_static(shadow_pass, &assets.cheese_moon_model, &model_buffers.walls);
     shadow_pipeline.render_animated(
         shadow_pass,
         &assets.pump_model,
         &model_buffers.pump_joints.bind_group,
         &model_buffers.pumps,
     );
-    shadow_pipeline.render_single(shadow_pass, &assets.surface_model);
+    shadow_pipeline.render_single(shadow_pass, &assets.terrain_model);
+    scenery_buffers.render_shadows(shadow_pass, shadow_pipeline, assets);
     shadow_pipeline.render_static(
         shadow_pass,
         &assets.cheese_droplet_model,
@@ -570,42 +1038,103 @@ fn render_shadows<'a>(
         &model_buffers.mice_engineers_joints.bind_group,
         &model_buffers.mice_engineers,
     );
+    // Far away mice. Their shadows are too blurry for the level of detail to matter.
+    for marines in &model_buffers.distant_mice_marines {
+        shadow_pipeline.render_static(shadow_pass, &assets.mouse_model.static_model, marines);
+        shadow_pipeline.render_static(shadow_pass, &assets.blaster_model.static_model, marines);
+    }
+    for engineers in &model_buffers.distant_mice_engineers {
+        shadow_pipeline.render_static(shadow_pass, &assets.mouse_model.static_model, engineers);
+        shadow_pipeline.render_static(shadow_pass, &assets.wrench_model.static_model, engineers);
+    }
+}
+
+// Only the big things that stand on the ground are worth reflecting.
+#[cfg(feature = "water-shader")]
+fn render_reflections<'a>(
+    render_pass: &mut wgpu::RenderPass<'a>,
+    model_pipelines: &'a ModelPipelines,
+    model_buffers: &'a ModelBuffers,
+    assets: &'a Assets,
+) {
+    model_pipelines.render_instanced(
+        render_pass,
+        &model_buffers.armouries,
+        &assets.props_texture,
+        &assets.armoury_model,
+    );
+    model_pipelines.render_animated(
+        render_pass,
+        &model_buffers.pumps,
+        &assets.props_texture,
+        &assets.pump_model,
+        &model_buffers.pump_joints.bind_group,
+    );
+    model_pipelines.render_team_coloured_animated(
+        render_pass,
+        &model_buffers.mice_marines,
+        &assets.mouse_texture,
+        &assets.mouse_model,
+        &model_buffers.mice_marines_joints.bind_group,
+    );
+    model_pipelines.render_team_coloured_animated(
+        render_pass,
+        &model_buffers.mice_engineers,
+        &assets.mouse_texture,
+        &assets.mouse_model,
+        &model_buffers.mice_engineers_joints.bind_group,
+    );
 }
 
-fn render_playing<'a>(
+// Everything that's drawn before (or is) the ground.
+fn render_opaque<'a>(
     mut render_pass: &mut wgpu::RenderPass<'a>,
     model_pipelines: &'a ModelPipelines,
     model_buffers: &'a ModelBuffers,
+    scenery_buffers: &'a SceneryBuffers,
     torus_pipeline: &'a TorusPipeline,
     torus_buffer: &'a TorusBuffer,
-    lines_pipeline: &'a LinesPipeline,
-    line_buffers: &'a LineBuffers,
+    decal_pipeline: &'a DecalPipeline,
+    decal_buffer: &'a DecalBuffer,
     lines_3d_pipeline: &'a Lines3dPipeline,
     lines_3d_buffer: &'a Lines3dBuffer,
+    fog_of_war_pipeline: &'a FogOfWarPipeline,
+    fog_of_war_buffer: &'a FogOfWarBuffer,
+    terrain_pipeline: &'a TerrainPipeline,
+    water_pipeline: &'a WaterPipeline,
     assets: &'a Assets,
 ) {
-    // Render a bunch of models.
-    model_pipelines.render_instanced(
+    // Render a bunch of models. The props all share the same texture atlas, so they're drawn together.
+    model_pipelines.render_instanced_batch(
         &mut render_pass,
-        &model_buffers.armouries,
-        &assets.armoury_texture,
-        &assets.armoury_model,
+        &assets.props_texture,
+        &[
+            (&model_buffers.armouries, &assets.armoury_model),
+            (&model_buffers.cheese_droplets, &assets.cheese_droplet_model),
+        ],
+    );
+    scenery_buffers.render(render_pass, model_pipelines, assets);
+    model_pipelines.render_instanced(
+        render_pass,
+        &model_buffers.rocks,
+        &assets.surface_texture,
+        &assets.cheese_moon_model,
+    );
+    model_pipelines.render_instanced(
+        render_pass,
+        &model_buffers.walls,
+        &assets.surface_texture,
+        &assets.cheese_moon_model,
     );
     model_pipelines.render_animated(
         &mut render_pass,
         &model_buffers.pumps,
-        &assets.pump_texture,
+        &assets.props_texture,
         &assets.pump_model,
         &model_buffers.pump_joints.bind_group,
     );
-    model_pipelines.render_instanced(
-        &mut render_pass,
-        &model_buffers.cheese_droplets,
-        &assets.surface_texture,
-        &assets.cheese_droplet_model,
-    );
     // Mice marines
-    model_pipelines.render_animated(
+    model_pipelines.render_team_coloured_animated(
         &mut render_pass,
         &model_buffers.mice_marines,
         &assets.mouse_texture,
@@ -615,12 +1144,12 @@ fn render_playing<'a>(
     model_pipelines.render_animated(
         &mut render_pass,
         &model_buffers.mice_marines,
-        &assets.blaster_texture,
+        &assets.props_texture,
         &assets.blaster_model,
         &model_buffers.mice_marines_joints.bind_group,
     );
     // Mice engineers
-    model_pipelines.render_animated(
+    model_pipelines.render_team_coloured_animated(
         &mut render_pass,
         &model_buffers.mice_engineers,
         &assets.mouse_texture,
@@ -630,10 +1159,58 @@ fn render_playing<'a>(
     model_pipelines.render_animated(
         &mut render_pass,
         &model_buffers.mice_engineers,
-        &assets.armoury_texture,
+        &assets.props_texture,
         &assets.wrench_model,
         &model_buffers.mice_engineers_joints.bind_group,
     );
+    // Far away mice
+    for (i, (marines, engineers)) in model_buffers
+        .distant_mice_marines
+        .iter()
+        .zip(&model_buffers.distant_mice_engineers)
+        .enumerate()
+    {
+        let lod = i + 1;
+        let mouse = &assets.mouse_model.static_model;
+
+        model_pipelines.render_instanced_lod(
+            render_pass,
+            marines,
+            &assets.mouse_texture,
+            mouse,
+            lod,
+        );
+        model_pipelines.render_instanced_lod(
+            render_pass,
+            marines,
+            &assets.props_texture,
+            &assets.blaster_model.static_model,
+            lod,
+        );
+        model_pipelines.render_instanced_lod(
+            render_pass,
+            engineers,
+            &assets.mouse_texture,
+            mouse,
+            lod,
+        );
+        model_pipelines.render_instanced_lod(
+            render_pass,
+            engineers,
+            &assets.props_texture,
+            &assets.wrench_model.static_model,
+            lod,
+        );
+    }
+    // Outlines around selected units and the one under the cursor
+    model_pipelines.render_outlines(
+        render_pass,
+        &model_buffers.mice_outline_masks,
+        &model_buffers.mice_outlines,
+        &assets.mouse_texture,
+        &assets.mouse_model,
+        &model_buffers.mice_outlines_joints.bind_group,
+    );
     // Bullets
     model_pipelines.render_transparent_textured_with_bloom(
         &mut render_pass,
@@ -643,11 +1220,23 @@ fn render_playing<'a>(
     );
     torus_pipeline.render(&mut render_pass, &torus_buffer.toruses, &assets.torus_model);
     lines_3d_pipeline.render(&mut render_pass, &lines_3d_buffer.lines);
-    model_pipelines.render_single(
-        &mut render_pass,
-        &assets.surface_texture,
-        &assets.surface_model,
-    );
+    terrain_pipeline.render(render_pass, assets);
+    water_pipeline.render(render_pass);
+    // Needs to go after the ground so that it's drawn on top of it.
+    decal_pipeline.render(render_pass, decal_buffer, assets);
+    // Needs to go after the ground and anything standing on it.
+    fog_of_war_pipeline.render(render_pass, fog_of_war_buffer);
+}
+
+// Everything that's blended over the top of the ground, in a second pass that carries on from
+// `render_opaque`.
+fn render_transparent<'a>(
+    mut render_pass: &mut wgpu::RenderPass<'a>,
+    model_pipelines: &'a ModelPipelines,
+    model_buffers: &'a ModelBuffers,
+    particle_pipeline: &'a ParticlePipeline,
+    assets: &'a Assets,
+) {
     // Explosions
     // Needs to go after the ground because otherwise underground bloom fragments will be written to
     // And not overwritten.
@@ -657,6 +1246,7 @@ fn render_playing<'a>(
         &assets.explosion_texture,
         &assets.explosion_model,
     );
+    particle_pipeline.render(render_pass, &model_buffers.particles);
     model_pipelines.render_transparent_textured_without_depth(
         &mut render_pass,
         &model_buffers.command_paths,
@@ -684,19 +1274,38 @@ fn render_playing<'a>(
         &assets.mouse_helmet_model,
         &model_buffers.mice_engineers_joints.bind_group,
     );
+    model_pipelines.render_transparent_animated(
+        render_pass,
+        &model_buffers.mice_corpses,
+        &assets.mouse_texture,
+        &assets.mouse_model,
+        &model_buffers.mice_corpses_joints.bind_group,
+    );
+
+    // Drawn last out of the 3D things as they write to the depth buffer, and the units inside them
+    // need to show through.
+    model_pipelines.render_transparent(
+        render_pass,
+        &model_buffers.shields,
+        &assets.explosion_model,
+    );
 
     if let Some((building, buffer)) = model_buffers.building_plan.get() {
         model_pipelines.render_transparent_buffer(
             &mut render_pass,
             match building {
                 ecs::Building::Pump => &assets.pump_static_model,
-                ecs::Building::Armoury => &assets.armoury_model,
+                ecs::Building::Armoury | ecs::Building::Silo => &assets.armoury_model,
+                ecs::Building::Wall | ecs::Building::Gate => &assets.cheese_moon_model,
             },
             buffer,
             1,
         );
     }
 
-    // Render 2D items.
-    lines_pipeline.render(&mut render_pass, &line_buffers, &assets);
+    model_pipelines.render_transparent(
+        render_pass,
+        &model_buffers.wall_plan,
+        &assets.cheese_moon_model,
+    );
 }
@@ -0,0 +1,249 @@
+// Random maps for skirmishes. Everything is mirrored through the middle of the map, the same way as
+// on the handmade skirmish map, so that neither side starts out with a better base.
+
+use crate::deterministic;
+use crate::ecs::Building;
+use crate::pathfinding::{Map, MAP_SIZE};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::f32::consts::PI;
+use ultraviolet::Vec2;
+
+// Layouts that turn out to be bad are just thrown away and rolled again.
+const MAX_ATTEMPTS: u32 = 100;
+// Nothing gets generated this close to the edges of the map.
+const EDGE_MARGIN: f32 = 10.0;
+// Keep rocks this far away from the start locations so that there's room to build a base.
+const BASE_CLEARANCE: f32 = 20.0;
+// And this far away from guysers so that pumps fit on them.
+const GUYSER_CLEARANCE: f32 = 5.0;
+// The biggest radius of any unit, for checking that everything is reachable.
+const UNIT_RADIUS: f32 = 1.0;
+
+#[derive(Debug, PartialEq)]
+pub struct GeneratedMap {
+    // Green's things come first, followed by purple's mirrored copies of them.
+    pub start_locations: [Vec2; 2],
+    pub armoury_locations: [Vec2; 2],
+    pub base_guysers: [Vec<Vec2>; 2],
+    // Guysers that belong to neither side, further out.
+    pub expansion_guysers: Vec<Vec2>,
+    // The centers and dimensions of rocks.
+    pub obstacles: Vec<(Vec2, Vec2)>,
+}
+
+impl GeneratedMap {
+    // Insert all the obstacles into a fresh map, checking that everyone can get to everything.
+    fn validate(&self) -> Option<Map> {
+        let mut map = Map::new();
+
+        for &(center, dimensions) in &self.obstacles {
+            map.insert(center, dimensions)?;
+        }
+
+        let start = self.start_locations[0];
+        let destinations = std::iter::once(self.start_locations[1])
+            .chain(self.base_guysers[0].iter().copied())
+            .chain(self.expansion_guysers.iter().copied());
+
+        for destination in destinations {
+            map.pathfind(start, destination, UNIT_RADIUS, None, None)?;
+        }
+
+        Some(map)
+    }
+}
+
+pub fn generate(seed: u64) -> GeneratedMap {
+    let mut rng = SmallRng::seed_from_u64(seed);
+
+    for _ in 0..MAX_ATTEMPTS {
+        if let Some(generated) = random_layout(&mut rng) {
+            if generated.validate().is_some() {
+                return generated;
+            }
+        }
+    }
+
+    // It'd be very unlucky to get here, but an empty map is always fine.
+    log::warn!(
+        "Couldn't generate a map with seed {}, so using a plain one",
+        seed
+    );
+    plain_layout()
+}
+
+fn in_bounds(point: Vec2, half_size: Vec2) -> bool {
+    let limit = Vec2::broadcast(MAP_SIZE / 2.0 - EDGE_MARGIN) - half_size;
+    point.x.abs() <= limit.x && point.y.abs() <= limit.y
+}
+
+fn rect_distance(point: Vec2, center: Vec2, dimensions: Vec2) -> f32 {
+    let offset = (point - center).abs() - dimensions / 2.0;
+    offset.max_by_component(Vec2::zero()).mag()
+}
+
+fn random_point_around(rng: &mut SmallRng, center: Vec2, min: f32, max: f32) -> Vec2 {
+    let angle = rng.gen_range(0.0, 2.0 * PI);
+    center + deterministic::direction(angle) * rng.gen_range(min, max)
+}
+
+fn random_layout(rng: &mut SmallRng) -> Option<GeneratedMap> {
+    // The map is just used for checking for water and overlaps here.
+    let mut map = Map::new();
+
+    let start = random_point_around(rng, Vec2::zero(), 55.0, 75.0);
+    if !in_bounds(start, Vec2::broadcast(BASE_CLEARANCE)) {
+        return None;
+    }
+
+    let towards_middle = -start.normalized();
+    let armoury = start + towards_middle * 14.0;
+    if !map.can_insert(
+        armoury,
+        Building::Armoury.stats().dimensions + Vec2::broadcast(4.0),
+    ) {
+        return None;
+    }
+
+    let mut guysers = Vec::new();
+    let fits = |position: Vec2, map: &Map, guysers: &[Vec2]| {
+        in_bounds(position, Vec2::zero())
+            && map.can_insert(
+                position,
+                Building::Pump.stats().dimensions + Vec2::broadcast(2.0),
+            )
+            && (position - start).mag() > 8.0
+            && (position - armoury).mag() > 10.0
+            && guysers.iter().all(|other| (position - *other).mag() > 8.0)
+    };
+
+    while guysers.len() < 4 {
+        let position = random_point_around(rng, start, 10.0, 18.0);
+        if fits(position, &map, &guysers) {
+            guysers.push(position);
+        } else if rng.gen_range(0, 10) == 0 {
+            // Give up on layouts that just don't have the room.
+            return None;
+        }
+    }
+
+    let base_guysers = guysers.clone();
+
+    let mut expansion_guysers = Vec::new();
+    for _ in 0..2 {
+        let sideways = Vec2::new(-towards_middle.y, towards_middle.x);
+        let position = start * rng.gen_range(0.3, 0.6) + sideways * rng.gen_range(-25.0, 25.0);
+
+        if fits(position, &map, &guysers) && fits(-position, &map, &guysers) {
+            guysers.push(position);
+            expansion_guysers.push(position);
+            expansion_guysers.push(-position);
+        }
+    }
+
+    if map.can_insert(Vec2::zero(), Building::Pump.stats().dimensions) {
+        expansion_guysers.push(Vec2::zero());
+    }
+
+    let mut obstacles = Vec::new();
+    let all_guysers: Vec<Vec2> = base_guysers
+        .iter()
+        .chain(expansion_guysers.iter())
+        .flat_map(|&guyser| vec![guyser, -guyser])
+        .collect();
+
+    for _ in 0..rng.gen_range(3, 6) {
+        let cluster = random_point_around(rng, Vec2::zero(), 10.0, 80.0);
+
+        for _ in 0..rng.gen_range(2, 5) {
+            let center = cluster + Vec2::new(rng.gen_range(-6.0, 6.0), rng.gen_range(-6.0, 6.0));
+            let dimensions = Vec2::new(rng.gen_range(3.0, 9.0), rng.gen_range(3.0, 9.0));
+
+            let clear_of = |point: Vec2, distance: f32| {
+                rect_distance(point, center, dimensions) > distance
+                    && rect_distance(-point, center, dimensions) > distance
+            };
+
+            // A rock right in the middle would overlap with its own mirror image.
+            let overlaps_mirror =
+                center.x.abs() < dimensions.x / 2.0 && center.y.abs() < dimensions.y / 2.0;
+
+            let placeable = in_bounds(center, dimensions / 2.0)
+                && !overlaps_mirror
+                && clear_of(start, BASE_CLEARANCE)
+                && clear_of(armoury, BASE_CLEARANCE / 2.0)
+                && all_guysers
+                    .iter()
+                    .all(|guyser| clear_of(*guyser, GUYSER_CLEARANCE))
+                && map.can_insert(center, dimensions)
+                && map.can_insert(-center, dimensions);
+
+            if placeable {
+                map.insert(center, dimensions)?;
+                map.insert(-center, dimensions)?;
+                obstacles.push((center, dimensions));
+                obstacles.push((-center, dimensions));
+            }
+        }
+    }
+
+    Some(GeneratedMap {
+        start_locations: [start, -start],
+        armoury_locations: [armoury, -armoury],
+        base_guysers: [
+            base_guysers.clone(),
+            base_guysers.iter().map(|guyser| -*guyser).collect(),
+        ],
+        expansion_guysers,
+        obstacles,
+    })
+}
+
+fn plain_layout() -> GeneratedMap {
+    let start = Vec2::new(-57.57, -59.81);
+    let base_guysers = vec![
+        Vec2::new(-72.23, -78.57),
+        Vec2::new(-74.96, -63.91),
+        Vec2::new(-46.65, -78.57),
+        Vec2::new(-56.55, -33.89),
+    ];
+
+    GeneratedMap {
+        start_locations: [start, -start],
+        armoury_locations: [Vec2::new(-35.88, -38.61), Vec2::new(35.88, 38.61)],
+        base_guysers: [
+            base_guysers.clone(),
+            base_guysers.iter().map(|guyser| -*guyser).collect(),
+        ],
+        expansion_guysers: vec![Vec2::zero()],
+        obstacles: Vec::new(),
+    }
+}
+
+#[test]
+fn generated_maps_are_reproducible_symmetric_and_connected() {
+    for seed in 0..5 {
+        let generated = generate(seed);
+        assert_eq!(generated, generate(seed));
+
+        assert_eq!(generated.start_locations[0], -generated.start_locations[1]);
+        for &(center, dimensions) in &generated.obstacles {
+            assert!(generated.obstacles.iter().any(
+                |&(other, other_dimensions)| other == -center && other_dimensions == dimensions
+            ));
+        }
+
+        let map = generated.validate().unwrap();
+        assert!(map
+            .pathfind(
+                generated.start_locations[1],
+                generated.base_guysers[1][0],
+                UNIT_RADIUS,
+                None,
+                None
+            )
+            .is_some());
+    }
+
+    assert_ne!(generate(0), generate(1));
+}
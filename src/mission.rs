@@ -0,0 +1,270 @@
+// Single player missions that are loaded from toml files at runtime, so that they can be written
+// and tweaked without recompiling the game. See `missions/ambush.toml` for an example.
+//
+// A mission lists what's on the map to begin with, and a set of triggers. Each trigger fires once,
+// when its condition is met, and runs its actions in order.
+
+use crate::assets::ModelAnimations;
+use crate::ecs::{Building, Command, Facing, ScriptTag, Side, Unit};
+use crate::renderer::Image;
+use crate::resources::{LoseCondition, WinCondition};
+use crate::scenery::SceneryGroup;
+use legion::systems::CommandBuffer;
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+use ultraviolet::Vec2;
+
+pub const DEFAULT_MISSION_PATH: &str = "missions/ambush.toml";
+
+// How fast dialogue gets typed out, in characters per second.
+const TYPEWRITER_SPEED: f32 = 40.0;
+
+#[derive(serde::Deserialize)]
+pub struct MissionScript {
+    pub name: String,
+    pub camera: [f32; 2],
+    #[serde(default)]
+    pub cheese_coins: u32,
+    #[serde(default)]
+    pub win_conditions: Vec<WinCondition>,
+    #[serde(default)]
+    pub lose_conditions: Vec<LoseCondition>,
+    #[serde(default)]
+    pub units: Vec<SpawnGroup>,
+    #[serde(default)]
+    pub buildings: Vec<BuildingPlacement>,
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+    #[serde(default)]
+    pub scenery: Vec<SceneryGroup>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+pub struct SpawnGroup {
+    pub unit: Unit,
+    #[serde(default = "one")]
+    pub count: u32,
+    pub side: Side,
+    pub position: [f32; 2],
+    // The units are spread out in a circle of this radius.
+    #[serde(default = "default_spread")]
+    pub spread: f32,
+    // In degrees.
+    #[serde(default)]
+    pub facing: f32,
+    // Lets `UnitDied` conditions refer to these units.
+    pub tag: Option<String>,
+    // Attack move here as soon as they're spawned.
+    pub attack_move_to: Option<[f32; 2]>,
+}
+
+impl SpawnGroup {
+    pub fn spawn(&self, buffer: &mut CommandBuffer, animations: &ModelAnimations) {
+        let center = Vec2::from(self.position);
+        let spread = if self.count > 1 { self.spread } else { 0.0 };
+
+        for i in 0..self.count {
+            let rads = (i as f32 / self.count as f32 * 360.0).to_radians();
+            let command = self.attack_move_to.map(|target| Command::MoveTo {
+                target: target.into(),
+                attack_move: true,
+                path: Vec::new(),
+            });
+
+            let entity = self.unit.add_to_world(
+                buffer,
+                Some(animations),
+                center + Vec2::new(rads.sin(), rads.cos()) * spread,
+                Facing(self.facing.to_radians()),
+                self.side,
+                command,
+            );
+
+            if let Some(tag) = &self.tag {
+                buffer.add_component(entity, ScriptTag(tag.clone()));
+            }
+        }
+    }
+}
+
+fn one() -> u32 {
+    1
+}
+
+fn default_spread() -> f32 {
+    2.0
+}
+
+fn normal_speed() -> f32 {
+    1.0
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct BuildingPlacement {
+    pub building: Building,
+    pub side: Side,
+    pub position: [f32; 2],
+    pub tag: Option<String>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct Trigger {
+    pub when: Condition,
+    pub actions: Vec<Action>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum Condition {
+    // Some number of seconds into the mission.
+    Timer {
+        seconds: f32,
+    },
+    // Everything spawned with this tag has died (or been destroyed).
+    UnitDied {
+        tag: String,
+    },
+    // A unit belonging to `side` is within `radius` of `center`.
+    RegionEntered {
+        center: [f32; 2],
+        radius: f32,
+        side: Side,
+    },
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum Action {
+    SpawnWave(SpawnGroup),
+    Dialogue {
+        speaker: String,
+        text: String,
+        portrait: Option<Image>,
+        // How fast the game runs while this line is up. 0 pauses it for a briefing.
+        #[serde(default = "normal_speed")]
+        game_speed: f32,
+    },
+    // Shows up in the objectives panel, and has to be met (along with the rest) to win.
+    AddObjective {
+        objective: WinCondition,
+    },
+    GiveCheese {
+        amount: u32,
+    },
+    Win,
+    Lose,
+}
+
+pub fn load(path: &Path) -> anyhow::Result<MissionScript> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|error| anyhow::anyhow!("Couldn't read {}: {}", path.display(), error))?;
+    Ok(toml::from_str(&contents)?)
+}
+
+// The state of the mission that's currently being played. This isn't saved with the rest of the
+// game, so loading a quicksave of a mission clears it.
+#[derive(Default)]
+pub struct Mission {
+    pub triggers: Vec<Trigger>,
+    pub fired: Vec<bool>,
+    // Lines of dialogue waiting to be shown. The first one is on screen.
+    pub dialogue: VecDeque<DialogueLine>,
+    // Every tag that's been on something so far.
+    pub seen_tags: HashSet<String>,
+}
+
+impl Mission {
+    pub fn new(script: &MissionScript) -> Self {
+        Self {
+            triggers: script.triggers.clone(),
+            fired: vec![false; script.triggers.len()],
+            dialogue: VecDeque::new(),
+            seen_tags: HashSet::new(),
+        }
+    }
+
+    // The game speed that the current line of dialogue wants.
+    pub fn game_speed(&self) -> f32 {
+        self.dialogue.front().map_or(1.0, |line| line.game_speed)
+    }
+}
+
+pub struct DialogueLine {
+    pub speaker: String,
+    pub text: String,
+    pub portrait: Option<Image>,
+    pub game_speed: f32,
+    // In real time, so that lines still go away when the game is paused.
+    pub shown_for: f32,
+}
+
+impl DialogueLine {
+    // How long it takes to type out, plus a bit of time to read it. Long lines need longer.
+    pub fn duration(&self) -> f32 {
+        let characters = self.text.chars().count() as f32;
+        characters / TYPEWRITER_SPEED + 2.0 + characters * 0.03
+    }
+
+    pub fn typed_text(&self) -> String {
+        let characters = (self.shown_for * TYPEWRITER_SPEED) as usize;
+        self.text.chars().take(characters).collect()
+    }
+}
+
+// Break text up into lines of at most `width` characters, as the text renderer doesn't wrap it.
+pub fn wrap(text: &str, width: usize) -> String {
+    let mut wrapped = String::new();
+    let mut line_length = 0;
+
+    for word in text.split(' ') {
+        let word_length = word.chars().count();
+
+        if line_length > 0 && line_length + 1 + word_length > width {
+            wrapped.push('\n');
+            line_length = 0;
+        } else if line_length > 0 {
+            wrapped.push(' ');
+            line_length += 1;
+        }
+
+        wrapped.push_str(word);
+        line_length += word_length;
+    }
+
+    wrapped
+}
+
+#[test]
+fn the_example_mission_loads() {
+    let script = load(Path::new(DEFAULT_MISSION_PATH)).unwrap();
+
+    assert!(!script.units.is_empty());
+    assert!(script
+        .triggers
+        .iter()
+        .any(|trigger| matches!(trigger.when, Condition::Timer { .. })));
+    assert!(script.triggers.iter().any(|trigger| trigger
+        .actions
+        .iter()
+        .any(|action| matches!(action, Action::Dialogue { .. }))));
+}
+
+#[test]
+fn dialogue_is_typed_out_and_wrapped() {
+    let mut line = DialogueLine {
+        speaker: "Commander".to_string(),
+        text: "Hold the line!".to_string(),
+        portrait: None,
+        game_speed: 1.0,
+        shown_for: 0.1,
+    };
+
+    assert_eq!(line.typed_text(), "Hold");
+    line.shown_for = line.duration();
+    assert_eq!(line.typed_text(), line.text);
+
+    assert_eq!(
+        wrap("The enemy is coming from the north", 12),
+        "The enemy is\ncoming from\nthe north"
+    );
+}
@@ -0,0 +1,1259 @@
+// Deterministic lockstep networking between two peers.
+//
+// Rather than syncing the state of the world, each peer runs the exact same simulation and only
+// sends the commands that its player issued. Commands issued on tick `t` are scheduled to be run
+// on tick `t + INPUT_DELAY`, which gives them time to get to the other peer. A peer only advances
+// the simulation once it has the commands from both sides for the current tick (even if that's no
+// commands at all).
+//
+// This only works if the simulation is deterministic, so when playing over the network we run it
+// at a fixed timestep and refer to entities by their `NetworkId`, as legion's entity ids aren't
+// the same between peers.
+//
+// Something to keep an eye on: local-only things like selecting units still add and remove
+// components, which changes the order legion iterates over entities in. Any gameplay system that
+// depends on that order (summing up floats, picking the first of several equally good targets)
+// could cause the peers to drift apart.
+//
+// To catch that when it happens, the peers also send each other a checksum of the world at the end
+// of every tick. If they ever differ, both peers dump the state of everything a few ticks later and
+// send it to each other, and the differences get written to `desync_<tick>.json`.
+
+use crate::ecs::{ActiveAbility, Building, NetworkId, Side, Stance, Unit, Upgrade};
+use crate::resources::{Formation, TEAM_COLOURS};
+use crate::scenarios::MULTIPLAYER_MAPS;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use ultraviolet::Vec2;
+
+pub const TICK_LENGTH: f32 = 1.0 / 60.0;
+// About 100ms at 60 ticks per second.
+const INPUT_DELAY: u32 = 6;
+// How long after a desync is noticed to dump the state of the world. Both peers have to dump it on
+// the same tick, and neither can be more than `INPUT_DELAY` ticks ahead of the other, so this is
+// far enough ahead that both will have noticed by then.
+const DUMP_DELAY: u32 = INPUT_DELAY * 2;
+
+// A command as issued by a player, before it's been turned into `ecs::Command`s for each unit.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerCommand {
+    Move {
+        units: Vec<NetworkId>,
+        target: Vec2,
+        attack_move: bool,
+        formation: Formation,
+        queue: bool,
+    },
+    Attack {
+        units: Vec<NetworkId>,
+        target: NetworkId,
+        queue: bool,
+    },
+    HelpBuild {
+        units: Vec<NetworkId>,
+        target: NetworkId,
+        queue: bool,
+    },
+    Construct {
+        builders: Vec<NetworkId>,
+        building: Building,
+        position: Vec2,
+        guyser: Option<NetworkId>,
+        queue: bool,
+    },
+    Recruit {
+        building: NetworkId,
+        unit: Unit,
+    },
+    SetWaypoint {
+        buildings: Vec<NetworkId>,
+        target: RallyTarget,
+    },
+    Stop {
+        units: Vec<NetworkId>,
+    },
+    Patrol {
+        units: Vec<NetworkId>,
+        target: Vec2,
+        formation: Formation,
+        queue: bool,
+    },
+    HoldPosition {
+        units: Vec<NetworkId>,
+        queue: bool,
+    },
+    SetStance {
+        units: Vec<NetworkId>,
+        stance: Stance,
+    },
+    Cast {
+        units: Vec<NetworkId>,
+        ability: ActiveAbility,
+        target: CastTarget,
+        queue: bool,
+    },
+    // The segments in between are worked out from the two ends when the command is applied.
+    BuildWall {
+        builders: Vec<NetworkId>,
+        start: Vec2,
+        end: Vec2,
+        queue: bool,
+    },
+    // Unlocks the gates instead if they're all locked already.
+    LockGates {
+        gates: Vec<NetworkId>,
+    },
+    Research {
+        building: NetworkId,
+        upgrade: Upgrade,
+    },
+    LaunchStrike {
+        silo: NetworkId,
+        target: Vec2,
+    },
+    // Brings them back up instead if they're all burrowed already.
+    Burrow {
+        units: Vec<NetworkId>,
+    },
+    Follow {
+        units: Vec<NetworkId>,
+        target: NetworkId,
+        queue: bool,
+    },
+    Guard {
+        units: Vec<NetworkId>,
+        center: Vec2,
+        radius: f32,
+        formation: Formation,
+        queue: bool,
+    },
+}
+
+// What each player has picked in the lobby, sent whenever it changes. See `lobby::Lobby`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LobbyState {
+    pub side: Side,
+    // An index into `resources::TEAM_COLOURS`.
+    pub colour: u8,
+    // An index into `scenarios::MULTIPLAYER_MAPS`. Only the host's choice counts.
+    pub map: u8,
+    pub ready: bool,
+    // Set by the host once both players are ready, to start the countdown. It's the colour that the
+    // host saw the other player pick, as they might have changed something since. They play as
+    // whichever side the host isn't.
+    pub starting: Option<u8>,
+}
+
+impl LobbyState {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.push(side_index(self.side) as u8);
+        bytes.push(self.colour);
+        bytes.push(self.map);
+        bytes.push(self.ready as u8);
+        match self.starting {
+            Some(colour) => {
+                bytes.push(1);
+                bytes.push(colour);
+            }
+            None => bytes.push(0),
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Option<Self> {
+        Some(Self {
+            side: match reader.u8()? {
+                0 => Side::Green,
+                1 => Side::Purple,
+                _ => return None,
+            },
+            // These get used as indices, so anything out of range is rejected here.
+            colour: reader
+                .u8()
+                .filter(|colour| (*colour as usize) < TEAM_COLOURS.len())?,
+            map: reader
+                .u8()
+                .filter(|map| (*map as usize) < MULTIPLAYER_MAPS.len())?,
+            ready: reader.bool()?,
+            starting: match reader.bool()? {
+                true => Some(
+                    reader
+                        .u8()
+                        .filter(|colour| (*colour as usize) < TEAM_COLOURS.len())?,
+                ),
+                false => None,
+            },
+        })
+    }
+}
+
+// What an ability was aimed at, like `ecs::AbilityTarget` but with ids instead of entities.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CastTarget {
+    Caster,
+    Point(Vec2),
+    Unit(NetworkId),
+}
+
+// Where a rally point goes, like `ecs::RallyPoint` but with ids instead of entities.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RallyTarget {
+    Point(Vec2),
+    Guyser(NetworkId),
+    Unit(NetworkId),
+}
+
+impl PlayerCommand {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::Move {
+                units,
+                target,
+                attack_move,
+                formation,
+                queue,
+            } => {
+                bytes.push(0);
+                write_ids(bytes, units);
+                write_vec2(bytes, *target);
+                bytes.push(*attack_move as u8);
+                bytes.push(formation_to_byte(*formation));
+                bytes.push(*queue as u8);
+            }
+            Self::Attack {
+                units,
+                target,
+                queue,
+            } => {
+                bytes.push(1);
+                write_ids(bytes, units);
+                bytes.extend_from_slice(&target.0.to_le_bytes());
+                bytes.push(*queue as u8);
+            }
+            Self::HelpBuild {
+                units,
+                target,
+                queue,
+            } => {
+                bytes.push(2);
+                write_ids(bytes, units);
+                bytes.extend_from_slice(&target.0.to_le_bytes());
+                bytes.push(*queue as u8);
+            }
+            Self::Construct {
+                builders,
+                building,
+                position,
+                guyser,
+                queue,
+            } => {
+                bytes.push(3);
+                write_ids(bytes, builders);
+                bytes.push(building_to_byte(*building));
+                write_vec2(bytes, *position);
+                match guyser {
+                    Some(guyser) => {
+                        bytes.push(1);
+                        bytes.extend_from_slice(&guyser.0.to_le_bytes());
+                    }
+                    None => bytes.push(0),
+                }
+                bytes.push(*queue as u8);
+            }
+            Self::Recruit { building, unit } => {
+                bytes.push(4);
+                bytes.extend_from_slice(&building.0.to_le_bytes());
+                bytes.push(unit_to_byte(*unit));
+            }
+            Self::SetWaypoint { buildings, target } => {
+                bytes.push(5);
+                write_ids(bytes, buildings);
+                match target {
+                    RallyTarget::Point(point) => {
+                        bytes.push(0);
+                        write_vec2(bytes, *point);
+                    }
+                    RallyTarget::Guyser(guyser) => {
+                        bytes.push(1);
+                        bytes.extend_from_slice(&guyser.0.to_le_bytes());
+                    }
+                    RallyTarget::Unit(unit) => {
+                        bytes.push(2);
+                        bytes.extend_from_slice(&unit.0.to_le_bytes());
+                    }
+                }
+            }
+            Self::Stop { units } => {
+                bytes.push(6);
+                write_ids(bytes, units);
+            }
+            Self::Patrol {
+                units,
+                target,
+                formation,
+                queue,
+            } => {
+                bytes.push(7);
+                write_ids(bytes, units);
+                write_vec2(bytes, *target);
+                bytes.push(formation_to_byte(*formation));
+                bytes.push(*queue as u8);
+            }
+            Self::HoldPosition { units, queue } => {
+                bytes.push(8);
+                write_ids(bytes, units);
+                bytes.push(*queue as u8);
+            }
+            Self::SetStance { units, stance } => {
+                bytes.push(9);
+                write_ids(bytes, units);
+                bytes.push(stance_to_byte(*stance));
+            }
+            Self::Cast {
+                units,
+                ability,
+                target,
+                queue,
+            } => {
+                bytes.push(10);
+                write_ids(bytes, units);
+                bytes.push(ability_to_byte(*ability));
+                match target {
+                    CastTarget::Caster => bytes.push(0),
+                    CastTarget::Point(point) => {
+                        bytes.push(1);
+                        write_vec2(bytes, *point);
+                    }
+                    CastTarget::Unit(unit) => {
+                        bytes.push(2);
+                        bytes.extend_from_slice(&unit.0.to_le_bytes());
+                    }
+                }
+                bytes.push(*queue as u8);
+            }
+            Self::BuildWall {
+                builders,
+                start,
+                end,
+                queue,
+            } => {
+                bytes.push(11);
+                write_ids(bytes, builders);
+                write_vec2(bytes, *start);
+                write_vec2(bytes, *end);
+                bytes.push(*queue as u8);
+            }
+            Self::LockGates { gates } => {
+                bytes.push(12);
+                write_ids(bytes, gates);
+            }
+            Self::Research { building, upgrade } => {
+                bytes.push(13);
+                bytes.extend_from_slice(&building.0.to_le_bytes());
+                bytes.push(upgrade_to_byte(*upgrade));
+            }
+            Self::LaunchStrike { silo, target } => {
+                bytes.push(14);
+                bytes.extend_from_slice(&silo.0.to_le_bytes());
+                write_vec2(bytes, *target);
+            }
+            Self::Burrow { units } => {
+                bytes.push(15);
+                write_ids(bytes, units);
+            }
+            Self::Follow {
+                units,
+                target,
+                queue,
+            } => {
+                bytes.push(16);
+                write_ids(bytes, units);
+                bytes.extend_from_slice(&target.0.to_le_bytes());
+                bytes.push(*queue as u8);
+            }
+            Self::Guard {
+                units,
+                center,
+                radius,
+                formation,
+                queue,
+            } => {
+                bytes.push(17);
+                write_ids(bytes, units);
+                write_vec2(bytes, *center);
+                bytes.extend_from_slice(&radius.to_le_bytes());
+                bytes.push(formation_to_byte(*formation));
+                bytes.push(*queue as u8);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Option<Self> {
+        let command = match reader.u8()? {
+            0 => Self::Move {
+                units: reader.ids()?,
+                target: reader.vec2()?,
+                attack_move: reader.bool()?,
+                formation: formation_from_byte(reader.u8()?)?,
+                queue: reader.bool()?,
+            },
+            1 => Self::Attack {
+                units: reader.ids()?,
+                target: NetworkId(reader.u32()?),
+                queue: reader.bool()?,
+            },
+            2 => Self::HelpBuild {
+                units: reader.ids()?,
+                target: NetworkId(reader.u32()?),
+                queue: reader.bool()?,
+            },
+            3 => Self::Construct {
+                builders: reader.ids()?,
+                building: building_from_byte(reader.u8()?)?,
+                position: reader.vec2()?,
+                guyser: match reader.bool()? {
+                    true => Some(NetworkId(reader.u32()?)),
+                    false => None,
+                },
+                queue: reader.bool()?,
+            },
+            4 => Self::Recruit {
+                building: NetworkId(reader.u32()?),
+                unit: unit_from_byte(reader.u8()?)?,
+            },
+            5 => Self::SetWaypoint {
+                buildings: reader.ids()?,
+                target: match reader.u8()? {
+                    0 => RallyTarget::Point(reader.vec2()?),
+                    1 => RallyTarget::Guyser(NetworkId(reader.u32()?)),
+                    2 => RallyTarget::Unit(NetworkId(reader.u32()?)),
+                    _ => return None,
+                },
+            },
+            6 => Self::Stop {
+                units: reader.ids()?,
+            },
+            7 => Self::Patrol {
+                units: reader.ids()?,
+                target: reader.vec2()?,
+                formation: formation_from_byte(reader.u8()?)?,
+                queue: reader.bool()?,
+            },
+            8 => Self::HoldPosition {
+                units: reader.ids()?,
+                queue: reader.bool()?,
+            },
+            9 => Self::SetStance {
+                units: reader.ids()?,
+                stance: stance_from_byte(reader.u8()?)?,
+            },
+            10 => Self::Cast {
+                units: reader.ids()?,
+                ability: ability_from_byte(reader.u8()?)?,
+                target: match reader.u8()? {
+                    0 => CastTarget::Caster,
+                    1 => CastTarget::Point(reader.vec2()?),
+                    2 => CastTarget::Unit(NetworkId(reader.u32()?)),
+                    _ => return None,
+                },
+                queue: reader.bool()?,
+            },
+            11 => Self::BuildWall {
+                builders: reader.ids()?,
+                start: reader.vec2()?,
+                end: reader.vec2()?,
+                queue: reader.bool()?,
+            },
+            12 => Self::LockGates {
+                gates: reader.ids()?,
+            },
+            13 => Self::Research {
+                building: NetworkId(reader.u32()?),
+                upgrade: upgrade_from_byte(reader.u8()?)?,
+            },
+            14 => Self::LaunchStrike {
+                silo: NetworkId(reader.u32()?),
+                target: reader.vec2()?,
+            },
+            15 => Self::Burrow {
+                units: reader.ids()?,
+            },
+            16 => Self::Follow {
+                units: reader.ids()?,
+                target: NetworkId(reader.u32()?),
+                queue: reader.bool()?,
+            },
+            17 => Self::Guard {
+                units: reader.ids()?,
+                center: reader.vec2()?,
+                radius: reader.f32()?,
+                formation: formation_from_byte(reader.u8()?)?,
+                queue: reader.bool()?,
+            },
+            _ => return None,
+        };
+
+        Some(command)
+    }
+}
+
+fn write_ids(bytes: &mut Vec<u8>, ids: &[NetworkId]) {
+    bytes.extend_from_slice(&(ids.len() as u16).to_le_bytes());
+    for id in ids {
+        bytes.extend_from_slice(&id.0.to_le_bytes());
+    }
+}
+
+fn write_vec2(bytes: &mut Vec<u8>, vec: Vec2) {
+    bytes.extend_from_slice(&vec.x.to_le_bytes());
+    bytes.extend_from_slice(&vec.y.to_le_bytes());
+}
+
+fn formation_to_byte(formation: Formation) -> u8 {
+    match formation {
+        Formation::Line => 0,
+        Formation::Box => 1,
+        Formation::Wedge => 2,
+    }
+}
+
+fn formation_from_byte(byte: u8) -> Option<Formation> {
+    match byte {
+        0 => Some(Formation::Line),
+        1 => Some(Formation::Box),
+        2 => Some(Formation::Wedge),
+        _ => None,
+    }
+}
+
+fn stance_to_byte(stance: Stance) -> u8 {
+    match stance {
+        Stance::Aggressive => 0,
+        Stance::Defensive => 1,
+        Stance::Passive => 2,
+    }
+}
+
+fn stance_from_byte(byte: u8) -> Option<Stance> {
+    match byte {
+        0 => Some(Stance::Aggressive),
+        1 => Some(Stance::Defensive),
+        2 => Some(Stance::Passive),
+        _ => None,
+    }
+}
+
+fn building_to_byte(building: Building) -> u8 {
+    match building {
+        Building::Armoury => 0,
+        Building::Pump => 1,
+        Building::Wall => 2,
+        Building::Gate => 3,
+        Building::Silo => 4,
+    }
+}
+
+fn building_from_byte(byte: u8) -> Option<Building> {
+    match byte {
+        0 => Some(Building::Armoury),
+        1 => Some(Building::Pump),
+        2 => Some(Building::Wall),
+        3 => Some(Building::Gate),
+        4 => Some(Building::Silo),
+        _ => None,
+    }
+}
+
+fn unit_to_byte(unit: Unit) -> u8 {
+    match unit {
+        Unit::MouseMarine => 0,
+        Unit::Engineer => 1,
+        Unit::Medic => 2,
+        Unit::Glider => 3,
+    }
+}
+
+fn unit_from_byte(byte: u8) -> Option<Unit> {
+    match byte {
+        0 => Some(Unit::MouseMarine),
+        1 => Some(Unit::Engineer),
+        2 => Some(Unit::Medic),
+        3 => Some(Unit::Glider),
+        _ => None,
+    }
+}
+
+fn ability_to_byte(ability: ActiveAbility) -> u8 {
+    match ability {
+        ActiveAbility::Grenade => 0,
+        ActiveAbility::Heal => 1,
+        ActiveAbility::SpeedBoost => 2,
+        ActiveAbility::Cloak => 3,
+    }
+}
+
+fn ability_from_byte(byte: u8) -> Option<ActiveAbility> {
+    match byte {
+        0 => Some(ActiveAbility::Grenade),
+        1 => Some(ActiveAbility::Heal),
+        2 => Some(ActiveAbility::SpeedBoost),
+        3 => Some(ActiveAbility::Cloak),
+        _ => None,
+    }
+}
+
+fn upgrade_to_byte(upgrade: Upgrade) -> u8 {
+    match upgrade {
+        Upgrade::Attack => 0,
+        Upgrade::Harvesting => 1,
+        Upgrade::Grenades => 2,
+        Upgrade::FieldMedicine => 3,
+    }
+}
+
+fn upgrade_from_byte(byte: u8) -> Option<Upgrade> {
+    match byte {
+        0 => Some(Upgrade::Attack),
+        1 => Some(Upgrade::Harvesting),
+        2 => Some(Upgrade::Grenades),
+        3 => Some(Upgrade::FieldMedicine),
+        _ => None,
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.0.len() < N {
+            return None;
+        }
+
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(&self.0[..N]);
+        self.0 = &self.0[N..];
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes::<1>().map(|bytes| bytes[0])
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        self.u8().map(|byte| byte != 0)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.bytes().map(f32::from_le_bytes)
+    }
+
+    fn vec2(&mut self) -> Option<Vec2> {
+        Some(Vec2::new(self.f32()?, self.f32()?))
+    }
+
+    fn ids(&mut self) -> Option<Vec<NetworkId>> {
+        let len = self.u16()?;
+        (0..len).map(|_| self.u32().map(NetworkId)).collect()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    // The commands for a tick.
+    Commands(u32, Vec<PlayerCommand>),
+    // The checksum of the world at the end of a tick.
+    Checksum(u32, u64),
+    // The state of the world at the end of a tick, after a desync. See `ecs::dump_world`.
+    Dump(u32, String),
+    // Sent before the game starts.
+    Lobby(LobbyState),
+}
+
+impl Message {
+    // Each message is length-prefixed, followed by a byte saying what sort of message it is.
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+
+        match self {
+            Self::Commands(tick, commands) => {
+                body.push(0);
+                body.extend_from_slice(&tick.to_le_bytes());
+                body.extend_from_slice(&(commands.len() as u16).to_le_bytes());
+                for command in commands {
+                    command.encode(&mut body);
+                }
+            }
+            Self::Checksum(tick, checksum) => {
+                body.push(1);
+                body.extend_from_slice(&tick.to_le_bytes());
+                body.extend_from_slice(&checksum.to_le_bytes());
+            }
+            Self::Dump(tick, dump) => {
+                body.push(2);
+                body.extend_from_slice(&tick.to_le_bytes());
+                body.extend_from_slice(dump.as_bytes());
+            }
+            Self::Lobby(state) => {
+                body.push(3);
+                state.encode(&mut body);
+            }
+        }
+
+        let mut message = (body.len() as u32).to_le_bytes().to_vec();
+        message.extend_from_slice(&body);
+        message
+    }
+
+    fn decode(body: &[u8]) -> Option<Self> {
+        let mut reader = Reader(body);
+
+        match reader.u8()? {
+            0 => {
+                let tick = reader.u32()?;
+                let len = reader.u16()?;
+                let commands = (0..len)
+                    .map(|_| PlayerCommand::decode(&mut reader))
+                    .collect::<Option<_>>()?;
+                Some(Self::Commands(tick, commands))
+            }
+            1 => Some(Self::Checksum(reader.u32()?, reader.u64()?)),
+            2 => {
+                let tick = reader.u32()?;
+                let dump = String::from_utf8(reader.0.to_vec()).ok()?;
+                Some(Self::Dump(tick, dump))
+            }
+            3 => Some(Self::Lobby(LobbyState::decode(&mut reader)?)),
+            _ => None,
+        }
+    }
+}
+
+pub struct Peer {
+    stream: TcpStream,
+    received: Vec<u8>,
+}
+
+impl Peer {
+    // Wait for the other player to connect. Returns the seed that both peers should use for their
+    // rngs.
+    pub fn host(address: impl ToSocketAddrs) -> io::Result<(Self, u64)> {
+        let listener = TcpListener::bind(address)?;
+        log::info!("Waiting for a connection on {}", listener.local_addr()?);
+        let (stream, address) = listener.accept()?;
+        log::info!("{} connected", address);
+
+        Self::accepted(stream)
+    }
+
+    // The host picks the seed and sends it to whoever connected.
+    fn accepted(mut stream: TcpStream) -> io::Result<(Self, u64)> {
+        stream.set_nonblocking(false)?;
+        let seed = rand::random::<u64>();
+        stream.write_all(&seed.to_le_bytes())?;
+
+        Ok((Self::new(stream)?, seed))
+    }
+
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<(Self, u64)> {
+        let mut stream = TcpStream::connect(address)?;
+        let mut seed = [0; 8];
+        stream.read_exact(&mut seed)?;
+
+        Ok((Self::new(stream)?, u64::from_le_bytes(seed)))
+    }
+
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        // We're sending lots of tiny messages and care about latency, not throughput.
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+
+        Ok(Self {
+            stream,
+            received: Vec::new(),
+        })
+    }
+
+    pub fn send_lobby_state(&mut self, state: LobbyState) -> io::Result<()> {
+        self.send(&Message::Lobby(state))
+    }
+
+    // The game hasn't started yet, so there shouldn't be anything else coming in.
+    pub fn receive_lobby_states(&mut self) -> io::Result<Vec<LobbyState>> {
+        Ok(self
+            .receive()?
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::Lobby(state) => Some(state),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        // The messages are tiny, so it's simplest to just block until this one is sent.
+        self.stream.set_nonblocking(false)?;
+        let result = self.stream.write_all(&message.encode());
+        self.stream.set_nonblocking(true)?;
+        result
+    }
+
+    fn receive(&mut self) -> io::Result<Vec<Message>> {
+        let mut buffer = [0; 4096];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.received.extend_from_slice(&buffer[..read]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        let mut messages = Vec::new();
+
+        while self.received.len() >= 4 {
+            let mut length = [0; 4];
+            length.copy_from_slice(&self.received[..4]);
+            let length = u32::from_le_bytes(length) as usize;
+
+            if self.received.len() < 4 + length {
+                break;
+            }
+
+            let message = Message::decode(&self.received[4..4 + length])
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid message"))?;
+            messages.push(message);
+            self.received.drain(..4 + length);
+        }
+
+        Ok(messages)
+    }
+}
+
+// Like `Peer::host`, but for waiting for a connection without blocking, so that the lobby can still
+// be drawn in the meantime.
+pub struct Listener(TcpListener);
+
+impl Listener {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        log::info!("Waiting for a connection on {}", listener.local_addr()?);
+        Ok(Self(listener))
+    }
+
+    pub fn accept(&self) -> io::Result<Option<(Peer, u64)>> {
+        match self.0.accept() {
+            Ok((stream, address)) => {
+                log::info!("{} connected", address);
+                Peer::accepted(stream).map(Some)
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TickInputs {
+    commands: [Vec<PlayerCommand>; 2],
+    received: [bool; 2],
+}
+
+fn side_index(side: Side) -> usize {
+    match side {
+        Side::Green => 0,
+        Side::Purple => 1,
+    }
+}
+
+// There's no getting back in sync after a desync, so this is just for working out why it happened.
+struct Desync {
+    // The first tick the checksums were different on. The world gets dumped `DUMP_DELAY` ticks
+    // after this.
+    tick: u32,
+    // Indexed by side, like `TickInputs`.
+    dumps: [Option<String>; 2],
+    // Whether both dumps have been handed over by `take_dumps` already.
+    taken: bool,
+}
+
+#[derive(Default)]
+pub struct Lockstep {
+    tick: u32,
+    local_side: Option<Side>,
+    peer: Option<Peer>,
+    inputs: BTreeMap<u32, TickInputs>,
+    next_network_id: u32,
+    // Shared by both peers, so that they generate the same random numbers.
+    seed: Option<u64>,
+    // The checksums from both sides for the ticks that haven't been compared yet.
+    checksums: BTreeMap<u32, [Option<u64>; 2]>,
+    // The local checksum for the current tick, to be sent off at the end of it.
+    local_checksum: Option<u64>,
+    desync: Option<Desync>,
+}
+
+impl Lockstep {
+    pub fn new(peer: Peer, local_side: Side, seed: u64) -> Self {
+        let mut lockstep = Self {
+            peer: Some(peer),
+            local_side: Some(local_side),
+            seed: Some(seed),
+            ..Default::default()
+        };
+        lockstep.reset();
+        lockstep
+    }
+
+    pub fn is_networked(&self) -> bool {
+        self.peer.is_some()
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    // Called when a scenario starts.
+    pub fn reset(&mut self) {
+        self.tick = 0;
+        self.next_network_id = 0;
+        self.inputs.clear();
+        self.checksums.clear();
+        self.local_checksum = None;
+        self.desync = None;
+
+        if self.is_networked() {
+            // Nothing can have been issued for the first few ticks.
+            for tick in 0..INPUT_DELAY {
+                self.inputs.entry(tick).or_default().received = [true; 2];
+            }
+        }
+    }
+
+    pub fn network_ids_issued(&self) -> u32 {
+        self.next_network_id
+    }
+
+    // Used when loading a save, so that new entities don't reuse ids.
+    pub fn set_network_ids_issued(&mut self, issued: u32) {
+        self.next_network_id = issued;
+    }
+
+    pub fn next_network_id(&mut self) -> NetworkId {
+        let id = NetworkId(self.next_network_id);
+        self.next_network_id += 1;
+        id
+    }
+
+    // Schedule a command issued by the local player.
+    pub fn issue(&mut self, side: Side, command: PlayerCommand) {
+        let delay = if self.is_networked() { INPUT_DELAY } else { 0 };
+        self.inputs.entry(self.tick + delay).or_default().commands[side_index(side)].push(command);
+    }
+
+    // Can we advance the simulation? If we're not networked, then we always can.
+    pub fn poll(&mut self) -> io::Result<bool> {
+        let peer = match self.peer.as_mut() {
+            Some(peer) => peer,
+            None => return Ok(true),
+        };
+
+        let remote = side_index(self.local_side.unwrap().flip());
+
+        for message in peer.receive()? {
+            match message {
+                Message::Commands(tick, commands) => {
+                    let inputs = self.inputs.entry(tick).or_default();
+                    inputs.commands[remote] = commands;
+                    inputs.received[remote] = true;
+                }
+                Message::Checksum(tick, checksum) => {
+                    if self.desync.is_none() {
+                        self.checksums.entry(tick).or_default()[remote] = Some(checksum);
+                        self.compare_checksums(tick);
+                    }
+                }
+                Message::Dump(tick, dump) => match self.desync.as_mut() {
+                    Some(desync) if desync.tick + DUMP_DELAY == tick => {
+                        desync.dumps[remote] = Some(dump)
+                    }
+                    _ => log::warn!(
+                        "Got an unexpected dump of tick {} from the other player",
+                        tick
+                    ),
+                },
+                // Left over from before the game started.
+                Message::Lobby(_) => {}
+            }
+        }
+
+        Ok(self
+            .inputs
+            .get(&self.tick)
+            .map(|inputs| inputs.received == [true; 2])
+            .unwrap_or(false))
+    }
+
+    // The commands to run this tick, in the same order on both peers.
+    pub fn take_commands(&mut self) -> Vec<(Side, PlayerCommand)> {
+        let inputs = match self.inputs.get_mut(&self.tick) {
+            Some(inputs) => inputs,
+            None => return Vec::new(),
+        };
+
+        let [green, purple] = std::mem::take(&mut inputs.commands);
+        let green = green.into_iter().map(|command| (Side::Green, command));
+        let purple = purple.into_iter().map(|command| (Side::Purple, command));
+        green.chain(purple).collect()
+    }
+
+    // Called at the end of each tick with the checksum of the world, so that it can be compared
+    // with the other peer's.
+    pub fn record_checksum(&mut self, checksum: u64) {
+        let local = match self.local_side {
+            Some(side) if self.desync.is_none() => side_index(side),
+            _ => return,
+        };
+
+        self.local_checksum = Some(checksum);
+        self.checksums.entry(self.tick).or_default()[local] = Some(checksum);
+        self.compare_checksums(self.tick);
+    }
+
+    fn compare_checksums(&mut self, tick: u32) {
+        let local = side_index(self.local_side.unwrap());
+
+        let checksums = match self.checksums.get(&tick) {
+            Some([Some(green), Some(purple)]) => [*green, *purple],
+            _ => return,
+        };
+        self.checksums.remove(&tick);
+
+        if checksums[0] != checksums[1] {
+            log::error!(
+                "Desynced from the other player on tick {} (our checksum was {:016x}, theirs was {:016x})",
+                tick,
+                checksums[local],
+                checksums[1 - local]
+            );
+            self.desync = Some(Desync {
+                tick,
+                dumps: Default::default(),
+                taken: false,
+            });
+            self.checksums.clear();
+        }
+    }
+
+    // Whether the world should be dumped with `record_dump` at the end of this tick.
+    pub fn dump_due(&self) -> bool {
+        matches!(&self.desync, Some(desync) if desync.tick + DUMP_DELAY == self.tick)
+    }
+
+    pub fn record_dump(&mut self, dump: String) {
+        if let (Some(desync), Some(local_side)) = (self.desync.as_mut(), self.local_side) {
+            desync.dumps[side_index(local_side)] = Some(dump);
+        }
+    }
+
+    // The tick the desync happened on, and our dump and theirs, once both have been made.
+    pub fn take_dumps(&mut self) -> Option<(u32, String, String)> {
+        let local = side_index(self.local_side?);
+        let desync = self.desync.as_mut()?;
+
+        if desync.taken || desync.dumps.iter().any(Option::is_none) {
+            return None;
+        }
+        desync.taken = true;
+
+        // Ours might not have been sent off yet, so it has to stay around until the end of the tick.
+        let [green, purple] = desync.dumps.clone();
+        let (ours, theirs) = if local == 0 {
+            (green, purple)
+        } else {
+            (purple, green)
+        };
+        Some((desync.tick, ours?, theirs?))
+    }
+
+    // Send off the commands the local player issued this tick (along with the checksum, and the dump
+    // of the world if there's been a desync) and move on to the next one.
+    pub fn end_tick(&mut self) -> io::Result<()> {
+        if let (Some(peer), Some(local_side)) = (self.peer.as_mut(), self.local_side) {
+            let local = side_index(local_side);
+            let scheduled = self.tick + INPUT_DELAY;
+            let inputs = self.inputs.entry(scheduled).or_default();
+            peer.send(&Message::Commands(
+                scheduled,
+                inputs.commands[local].clone(),
+            ))?;
+            inputs.received[local] = true;
+
+            if let Some(checksum) = self.local_checksum.take() {
+                peer.send(&Message::Checksum(self.tick, checksum))?;
+            }
+
+            if let Some(desync) = &self.desync {
+                if let (true, Some(dump)) =
+                    (desync.tick + DUMP_DELAY == self.tick, &desync.dumps[local])
+                {
+                    peer.send(&Message::Dump(self.tick, dump.clone()))?;
+                }
+            }
+        }
+
+        self.inputs.remove(&self.tick);
+        self.tick += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn commands_round_trip() {
+    let commands = vec![
+        PlayerCommand::Move {
+            units: vec![NetworkId(1), NetworkId(2)],
+            target: Vec2::new(-10.5, 3.0),
+            attack_move: true,
+            formation: Formation::Wedge,
+            queue: false,
+        },
+        PlayerCommand::Construct {
+            builders: vec![NetworkId(7)],
+            building: Building::Pump,
+            position: Vec2::new(1.0, 2.0),
+            guyser: Some(NetworkId(3)),
+            queue: true,
+        },
+        PlayerCommand::Recruit {
+            building: NetworkId(4),
+            unit: Unit::Engineer,
+        },
+        PlayerCommand::SetWaypoint {
+            buildings: vec![NetworkId(4)],
+            target: RallyTarget::Point(Vec2::new(12.0, -6.5)),
+        },
+        PlayerCommand::SetWaypoint {
+            buildings: vec![NetworkId(4), NetworkId(16)],
+            target: RallyTarget::Unit(NetworkId(2)),
+        },
+        PlayerCommand::Stop { units: Vec::new() },
+        PlayerCommand::Patrol {
+            units: vec![NetworkId(5)],
+            target: Vec2::new(0.0, -20.0),
+            formation: Formation::Line,
+            queue: true,
+        },
+        PlayerCommand::HoldPosition {
+            units: vec![NetworkId(5), NetworkId(6)],
+            queue: false,
+        },
+        PlayerCommand::SetStance {
+            units: vec![NetworkId(8)],
+            stance: Stance::Defensive,
+        },
+        PlayerCommand::Cast {
+            units: vec![NetworkId(9)],
+            ability: ActiveAbility::Grenade,
+            target: CastTarget::Point(Vec2::new(4.0, -2.5)),
+            queue: true,
+        },
+        PlayerCommand::Cast {
+            units: vec![NetworkId(10)],
+            ability: ActiveAbility::Heal,
+            target: CastTarget::Unit(NetworkId(9)),
+            queue: false,
+        },
+        PlayerCommand::BuildWall {
+            builders: vec![NetworkId(7), NetworkId(11)],
+            start: Vec2::new(3.0, 4.5),
+            end: Vec2::new(15.0, 4.5),
+            queue: true,
+        },
+        PlayerCommand::LockGates {
+            gates: vec![NetworkId(12)],
+        },
+        PlayerCommand::Research {
+            building: NetworkId(4),
+            upgrade: Upgrade::FieldMedicine,
+        },
+        PlayerCommand::LaunchStrike {
+            silo: NetworkId(13),
+            target: Vec2::new(-40.0, 75.5),
+        },
+        PlayerCommand::Burrow {
+            units: vec![NetworkId(14), NetworkId(15)],
+        },
+        PlayerCommand::Follow {
+            units: vec![NetworkId(5), NetworkId(6)],
+            target: NetworkId(2),
+            queue: true,
+        },
+        PlayerCommand::Guard {
+            units: vec![NetworkId(5)],
+            center: Vec2::new(8.0, -16.5),
+            radius: 12.0,
+            formation: Formation::Box,
+            queue: false,
+        },
+    ];
+
+    let message = Message::Commands(42, commands.clone()).encode();
+    assert_eq!(
+        message.len() - 4,
+        u32::from_le_bytes([message[0], message[1], message[2], message[3]]) as usize
+    );
+    assert_eq!(
+        Message::decode(&message[4..]),
+        Some(Message::Commands(42, commands))
+    );
+
+    // Truncated messages shouldn't decode.
+    assert_eq!(Message::decode(&message[4..message.len() - 1]), None);
+
+    let checksum = Message::Checksum(43, 0x0123_4567_89ab_cdef);
+    assert_eq!(Message::decode(&checksum.encode()[4..]), Some(checksum));
+    let dump = Message::Dump(44, "{}".into());
+    assert_eq!(Message::decode(&dump.encode()[4..]), Some(dump));
+    let lobby = Message::Lobby(LobbyState {
+        side: Side::Purple,
+        colour: 3,
+        map: 1,
+        ready: true,
+        starting: Some(2),
+    });
+    assert_eq!(Message::decode(&lobby.encode()[4..]), Some(lobby));
+
+    // Nor should lobby states with a colour or map that doesn't exist.
+    for (colour, map, starting) in &[
+        (TEAM_COLOURS.len() as u8, 0, None),
+        (0, MULTIPLAYER_MAPS.len() as u8, None),
+        (0, 0, Some(TEAM_COLOURS.len() as u8)),
+    ] {
+        let lobby = Message::Lobby(LobbyState {
+            side: Side::Green,
+            colour: *colour,
+            map: *map,
+            ready: false,
+            starting: *starting,
+        });
+        assert_eq!(Message::decode(&lobby.encode()[4..]), None);
+    }
+}
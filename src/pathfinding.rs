@@ -1,35 +1,109 @@
+use crate::deterministic;
+use crate::high_ground::PLATEAUS;
+use crate::water::{Water, LAKES};
 use cgmath::Point2;
 use ordered_float::OrderedFloat;
 use spade::{
     delaunay::{
-        CdtEdge, ConstrainedDelaunayTriangulation, FaceHandle, PositionInTriangulation,
-        VertexHandle,
+        CdtEdge, ConstrainedDelaunayTriangulation, FaceHandle, FixedFaceHandle,
+        PositionInTriangulation, VertexHandle,
     },
     kernels::FloatKernel,
 };
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use ultraviolet::Vec2;
 
+// How many map changes to remember. Anything that hasn't caught up with the map for longer than
+// this just has to assume that everything changed.
+const MAX_REMEMBERED_CHANGES: usize = 64;
+
+// Paths go at most this many unit radii out from a sharp corner.
+const MAX_CORNER_OFFSET: f32 = 3.0;
+
+// The map is a square centered on the origin.
+pub const MAP_SIZE: f32 = 200.0;
+
+// How many times `nearest_passable_point` tries pushing a point out of things before it starts
+// looking further away, and how far it looks.
+const MAX_PUSHES: usize = 4;
+const MAX_SEARCH_RINGS: usize = 10;
+// How many straight pieces each rounded off corner of a path is made out of.
+const CURVE_SEGMENTS: usize = 4;
+
+// Points get pushed this little bit further than they need to be, so that they don't end up right on
+// the edge.
+const PUSH_MARGIN: f32 = 0.01;
+
+// The corners of something that was inserted into the map, in the order they were inserted.
 pub struct MapHandle {
-    top_left: Point2<f32>,
-    top_right: Point2<f32>,
-    bottom_left: Point2<f32>,
-    bottom_right: Point2<f32>,
+    points: Vec<Point2<f32>>,
+    // Whether the constraint is actually in the map right now. See `Map::set_constraint_enabled`.
+    enabled: bool,
+    // Whether the last point joins back up with the first.
+    closed: bool,
+    // The key of the matching outline in `Map::outlines`.
+    id: u32,
+}
+
+// The shape of something in the map, for working out whether a point is inside it.
+struct Outline {
+    points: Vec<Vec2>,
+    closed: bool,
 }
 
 pub struct Map {
     dlt: ConstrainedDelaunayTriangulation<Point2<f32>, FloatKernel>,
-    pub updated_this_tick: bool,
+    // Incremented every time a constraint is inserted or removed.
+    version: u32,
+    // The bounding boxes of recent changes, along with the version that they resulted in.
+    changes: VecDeque<(u32, Vec2, Vec2)>,
+    water: Vec<Water>,
+    // Everything that's in the map, in the order it was inserted so that every peer goes through
+    // them the same way.
+    outlines: BTreeMap<u32, Outline>,
+    next_outline_id: u32,
+    // How many paths and flow fields have been worked out, for `--bench`. The systems that
+    // pathfind only get to read the map, hence the atomic.
+    queries: AtomicUsize,
 }
 
 impl Map {
     pub fn new() -> Self {
         let mut this = Self {
             dlt: ConstrainedDelaunayTriangulation::with_tree_locate(),
-            updated_this_tick: false,
+            version: 0,
+            changes: VecDeque::new(),
+            water: Vec::new(),
+            outlines: BTreeMap::new(),
+            next_outline_id: 0,
+            queries: AtomicUsize::new(0),
         };
 
-        this.insert(Vec2::new(0.0, 0.0), Vec2::new(200.0, 200.0));
+        this.insert(Vec2::new(0.0, 0.0), Vec2::broadcast(MAP_SIZE));
+        // Everything is inside the edges of the map, so they're handled separately.
+        this.outlines.clear();
+
+        // Everything that walks is a land unit, so the water is just another set of constraints.
+        // (This has to happen before `water` is filled in, as nothing can be inserted over it.)
+        for water in LAKES.iter() {
+            this.insert(water.center, water.dimensions);
+        }
+        this.water = LAKES.to_vec();
+
+        // The only ways up onto the plateaus are the gaps in their cliffs where the ramps are.
+        for plateau in PLATEAUS.iter() {
+            for cliff in plateau.cliffs() {
+                if plateau.ramps.is_empty() {
+                    this.insert_polygon(&cliff);
+                } else {
+                    this.insert_chain(&cliff);
+                }
+            }
+        }
+
         this
     }
 
@@ -43,10 +117,13 @@ impl Map {
     }
 
     fn locate(&self, point: Vec2) -> Option<TriangleRef> {
+        self.locate_face(point)
+            .map(|face| TriangleRef::new(face, point))
+    }
+
+    fn locate_face(&self, point: Vec2) -> Option<Face<'_>> {
         match self.dlt.locate(&Point2::new(point.x, point.y)) {
-            PositionInTriangulation::InTriangle(triangle) => {
-                Some(TriangleRef::new(triangle, point))
-            }
+            PositionInTriangulation::InTriangle(face) => Some(face),
             // These two seem very unlikely.
             PositionInTriangulation::OnPoint(_) => None,
             PositionInTriangulation::OnEdge(_) => None,
@@ -55,50 +132,275 @@ impl Map {
         }
     }
 
-    pub fn insert(&mut self, center: Vec2, dimensions: Vec2) -> Option<MapHandle> {
-        let tl = center - dimensions / 2.0;
-        let br = center + dimensions / 2.0;
-
-        let top_left = Point2::new(tl.x, tl.y);
-        let top_right = Point2::new(br.x, tl.y);
-        let bottom_left = Point2::new(tl.x, br.y);
-        let bottom_right = Point2::new(br.x, br.y);
-
-        if self.dlt.intersects_constraint(&top_left, &top_right)
-            || self.dlt.intersects_constraint(&top_right, &bottom_right)
-            || self.dlt.intersects_constraint(&bottom_right, &bottom_left)
-            || self.dlt.intersects_constraint(&bottom_left, &top_left)
+    // Would inserting a rectangle here overlap with an existing one, the water or the map edges?
+    pub fn can_insert(&self, center: Vec2, dimensions: Vec2) -> bool {
+        self.can_insert_polygon(&rectangle(center, dimensions))
+    }
+
+    pub fn can_insert_polygon(&self, points: &[Vec2]) -> bool {
+        if points.len() < 3 {
+            return false;
+        }
+
+        // The lakes are all rectangles, so checking against the bounding box of the polygon is a
+        // bit conservative, but it's close enough.
+        let (min, max) = bounding_box(points);
+        if self
+            .water
+            .iter()
+            .any(|water| water.overlaps((min + max) / 2.0, max - min))
         {
+            return false;
+        }
+
+        !polygon_edges(points).any(|(a, b)| {
+            self.dlt
+                .intersects_constraint(&Point2::new(a.x, a.y), &Point2::new(b.x, b.y))
+        })
+    }
+
+    pub fn insert(&mut self, center: Vec2, dimensions: Vec2) -> Option<MapHandle> {
+        self.insert_polygon(&rectangle(center, dimensions))
+    }
+
+    // Insert the outline of a polygon as constraint edges. The points go around the outside of the
+    // polygon in either direction, and the edges can't cross each other.
+    pub fn insert_polygon(&mut self, points: &[Vec2]) -> Option<MapHandle> {
+        if !self.can_insert_polygon(points) {
             return None;
         }
 
-        {
-            let top_left = self.dlt.insert(top_left);
-            let top_right = self.dlt.insert(top_right);
-            let bottom_left = self.dlt.insert(bottom_left);
-            let bottom_right = self.dlt.insert(bottom_right);
+        Some(self.add_constraints(points, true))
+    }
 
-            self.dlt.add_constraint(top_left, top_right);
-            self.dlt.add_constraint(bottom_left, bottom_right);
-            self.dlt.add_constraint(top_left, bottom_left);
-            self.dlt.add_constraint(top_right, bottom_right);
+    // Like `insert_polygon`, but the last point doesn't join back up with the first.
+    fn insert_chain(&mut self, points: &[Vec2]) -> Option<MapHandle> {
+        let crosses_constraint = points.windows(2).any(|pair| {
+            self.dlt.intersects_constraint(
+                &Point2::new(pair[0].x, pair[0].y),
+                &Point2::new(pair[1].x, pair[1].y),
+            )
+        });
+
+        if points.len() < 2 || crosses_constraint {
+            return None;
         }
 
-        self.updated_this_tick = true;
+        Some(self.add_constraints(points, false))
+    }
 
-        Some(MapHandle {
-            top_left,
-            top_right,
-            bottom_left,
-            bottom_right,
-        })
+    fn add_constraints(&mut self, points: &[Vec2], closed: bool) -> MapHandle {
+        let (min, max) = bounding_box(points);
+
+        let vertices: Vec<_> = points
+            .iter()
+            .map(|point| self.dlt.insert(Point2::new(point.x, point.y)))
+            .collect();
+
+        let edges = if closed {
+            vertices.len()
+        } else {
+            vertices.len() - 1
+        };
+
+        for i in 0..edges {
+            self.dlt
+                .add_constraint(vertices[i], vertices[(i + 1) % vertices.len()]);
+        }
+
+        self.record_change(min, max);
+
+        let id = self.next_outline_id;
+        self.next_outline_id += 1;
+        self.outlines.insert(
+            id,
+            Outline {
+                points: points.to_vec(),
+                closed,
+            },
+        );
+
+        MapHandle {
+            points: points
+                .iter()
+                .map(|point| Point2::new(point.x, point.y))
+                .collect(),
+            enabled: true,
+            closed,
+            id,
+        }
     }
 
     pub fn remove(&mut self, handle: &MapHandle) {
-        self.dlt.locate_and_remove(&handle.bottom_right);
-        self.dlt.locate_and_remove(&handle.bottom_left);
-        self.dlt.locate_and_remove(&handle.top_right);
-        self.dlt.locate_and_remove(&handle.top_left);
+        if !handle.enabled {
+            return;
+        }
+
+        for point in handle.points.iter().rev() {
+            self.dlt.locate_and_remove(point);
+        }
+
+        self.outlines.remove(&handle.id);
+
+        let points: Vec<Vec2> = handle.points.iter().copied().map(point_to_vec2).collect();
+        let (min, max) = bounding_box(&points);
+        self.record_change(min, max);
+    }
+
+    // Take something out of the map while holding onto its handle, or put it back again. Putting it
+    // back fails (returning false) if something else has been inserted over it in the meantime.
+    pub fn set_constraint_enabled(&mut self, handle: &mut MapHandle, enabled: bool) -> bool {
+        if handle.enabled == enabled {
+            return true;
+        }
+
+        if enabled {
+            let points: Vec<Vec2> = handle.points.iter().copied().map(point_to_vec2).collect();
+
+            let inserted = if handle.closed {
+                self.insert_polygon(&points)
+            } else {
+                self.insert_chain(&points)
+            };
+
+            match inserted {
+                Some(inserted) => handle.id = inserted.id,
+                None => return false,
+            }
+        } else {
+            self.remove(handle);
+        }
+
+        handle.enabled = enabled;
+        true
+    }
+
+    // The closest point to `point` that a unit with this radius could stand on without being inside
+    // or overlapping anything, for when units are put down or sent somewhere that could be inside a
+    // building. It's only a best guess if everything nearby is packed in too tightly.
+    pub fn nearest_passable_point(&self, point: Vec2, radius: f32) -> Vec2 {
+        let limit = Vec2::broadcast(MAP_SIZE / 2.0 - radius - PUSH_MARGIN);
+        let clamp = |point: Vec2| point.clamped(-limit, limit);
+
+        // Getting pushed out of one thing can mean getting pushed into another.
+        let mut pushed = clamp(point);
+        for _ in 0..MAX_PUSHES {
+            match self.push_out(pushed, radius) {
+                Some(out) => pushed = clamp(out),
+                None => return pushed,
+            }
+        }
+
+        // It's stuck in a gap that's too small, so look around in rings further and further out.
+        for ring in 1..=MAX_SEARCH_RINGS {
+            let distance = ring as f32 * radius.max(0.5) * 2.0;
+
+            for i in 0..8 {
+                let angle = i as f32 / 8.0 * std::f32::consts::TAU;
+                let candidate = clamp(point + deterministic::direction(angle) * distance);
+
+                if self.push_out(candidate, radius).is_none() {
+                    return candidate;
+                }
+            }
+        }
+
+        pushed
+    }
+
+    // If a unit at `point` would be in the way of something, where it'd have to go to get clear of
+    // (just) that.
+    fn push_out(&self, point: Vec2, radius: f32) -> Option<Vec2> {
+        self.outlines.values().find_map(|outline| {
+            let inside = outline.closed && polygon_contains(&outline.points, point);
+
+            let edges = outline.points.len() - if outline.closed { 0 } else { 1 };
+            let (nearest, a, b) = (0..edges)
+                .map(|i| {
+                    let (a, b) = (
+                        outline.points[i],
+                        outline.points[(i + 1) % outline.points.len()],
+                    );
+                    (nearest_point_on_segment(point, a, b), a, b)
+                })
+                .min_by_key(|(nearest, ..)| OrderedFloat((*nearest - point).mag_sq()))?;
+
+            let offset = point - nearest;
+            if !inside && offset.mag() >= radius {
+                return None;
+            }
+
+            let away = if offset.mag_sq() > 0.0 {
+                if inside {
+                    -offset.normalized()
+                } else {
+                    offset.normalized()
+                }
+            } else {
+                // Right on the edge, so go out along its normal. Polygons can go around either
+                // way, so pick the side that's further from the middle.
+                let along = (b - a).normalized();
+                let normal = Vec2::new(-along.y, along.x);
+                let (min, max) = bounding_box(&outline.points);
+                if normal.dot(nearest - (min + max) / 2.0) < 0.0 {
+                    -normal
+                } else {
+                    normal
+                }
+            };
+
+            Some(nearest + away * (radius + PUSH_MARGIN))
+        })
+    }
+
+    fn record_change(&mut self, min: Vec2, max: Vec2) {
+        self.version += 1;
+        self.changes.push_back((self.version, min, max));
+
+        if self.changes.len() > MAX_REMEMBERED_CHANGES {
+            self.changes.pop_front();
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // Could a path that was computed at `version` now be going through a building? We check this by
+    // seeing if any part of the path (which starts at `start`) comes within `unit_radius` of a
+    // change. This doesn't catch shortcuts opened up by a building being removed somewhere the path
+    // went around rather than through, so those paths keep their detour.
+    pub fn path_invalidated_since(
+        &self,
+        version: u32,
+        start: Vec2,
+        path: &[Vec2],
+        unit_radius: f32,
+    ) -> bool {
+        if version == self.version {
+            return false;
+        }
+
+        match self.changes.front() {
+            // We've forgotten some of the changes, so we can't tell.
+            Some(&(oldest, ..)) if oldest > version + 1 => return true,
+            None => return true,
+            _ => {}
+        }
+
+        let padding = Vec2::broadcast(unit_radius);
+
+        self.changes
+            .iter()
+            .filter(|(change_version, ..)| *change_version > version)
+            .any(|&(_, min, max)| {
+                let (min, max) = (min - padding, max + padding);
+
+                std::iter::once(&start)
+                    .chain(path.iter())
+                    .zip(path.iter())
+                    .any(|(a, b)| segment_intersects_rect(*a, *b, min, max))
+            })
     }
 
     pub fn impassable_between(&self, a: Vec2, b: Vec2) -> bool {
@@ -106,6 +408,25 @@ impl Map {
             .intersects_constraint(&Point2::new(a.x, a.y), &Point2::new(b.x, b.y))
     }
 
+    // Is there room for a unit to walk in a straight line between two points? As well as the line
+    // itself, this checks the lines along either side of the unit, so that big units don't try and
+    // squeeze through gaps between buildings. Anything thinner than the unit that's entirely
+    // between those lines would get missed, but nothing on the map is that small.
+    fn clear_between(&self, a: Vec2, b: Vec2, unit_radius: f32) -> bool {
+        if self.impassable_between(a, b) {
+            return false;
+        }
+
+        let direction = b - a;
+        if direction.mag_sq() == 0.0 {
+            return true;
+        }
+
+        let side = Vec2::new(-direction.y, direction.x).normalized() * unit_radius;
+
+        !self.impassable_between(a + side, b + side) && !self.impassable_between(a - side, b - side)
+    }
+
     pub fn pathfind(
         &self,
         start: Vec2,
@@ -114,17 +435,16 @@ impl Map {
         debug_triangles: Option<&mut Vec<(Vec2, Vec2)>>,
         debug_funnel_portals: Option<&mut Vec<(Vec2, Vec2)>>,
     ) -> Option<Vec<Vec2>> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+
         // If there's nothing between the points then just go straight to the end.
-        // This assumes that the unit can fit through all the gaps (all the edges that the line crosses)
-        // in between.
-        // It'd be better to iterate over all edges that intersect the line and check them against
-        // the unit radius.
-        if !self.impassable_between(start, end) {
+        if self.clear_between(start, end, unit_radius) {
             return Some(vec![end]);
         }
 
         let start_tri = self.locate(start)?;
-        let end_tri = self.locate(end)?;
+        let end_face = self.locate_face(end)?;
+        let end_tri = TriangleRef::new(end_face, end);
 
         // What we do here is we pathfind across the triangulation using each triangles neighbours,
         // and a distance metric that just uses the distance from the triangle centers.
@@ -140,7 +460,7 @@ impl Map {
 
         let (triangles, _length) = pathfinding::directed::astar::astar(
             &start_tri,
-            |&tri| tri.neighbours(self, unit_radius * 2.0, &end_tri),
+            |&tri| tri.neighbours(self, unit_radius * 2.0, &end_tri, end_face),
             |&tri| OrderedFloat((tri.point - end).mag()),
             |&tri| tri == end_tri,
         )?;
@@ -160,20 +480,280 @@ impl Map {
         Some(funnel(&funnel_portals))
     }
 
+    // The triangles on the other side of each edge of a triangle, as long as the edge isn't a
+    // constraint and is wide enough to fit through. Triangles that are wide enough to get into but
+    // too cramped to get back out of are left out too, unless they're the `end` of the path.
+    fn passable_neighbours<'a>(
+        &'a self,
+        [a, b, c]: [Vertex<'a>; 3],
+        gap: f32,
+        end: Option<Face<'a>>,
+    ) -> impl Iterator<Item = Face<'a>> + 'a {
+        arrayvec::ArrayVec::from([(a, b), (b, c), (c, a)])
+            .into_iter()
+            .filter_map(move |(a, b)| {
+                // Flipped here because we want the edge facing outside.
+                let edge = self.dlt.get_edge_from_neighbors(b.fix(), a.fix()).unwrap();
+
+                let face = edge.face();
+
+                if !self.dlt.is_constraint_edge(edge.fix())
+                    && gap.powi(2) <= (point_to_vec2(*a) - point_to_vec2(*b)).mag_sq()
+                    && face != self.dlt.infinite_face()
+                    && (Some(face) == end || self.can_cross(face, a, b, gap))
+                {
+                    Some(face)
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn is_constraint(&self, a: Vertex, b: Vertex) -> bool {
+        self.dlt
+            .get_edge_from_neighbors(a.fix(), b.fix())
+            .map(|edge| self.dlt.is_constraint_edge(edge.fix()))
+            .unwrap_or(false)
+    }
+
+    // Can a unit that comes into `face` over the edge `a`-`b` get out of it over one of the other
+    // two edges? Going out over `b`-`c` means squeezing between `b` and the `a`-`c` edge, and the
+    // other way around for `a`-`c`.
+    fn can_cross(&self, face: Face, a: Vertex, b: Vertex, gap: f32) -> bool {
+        let c = match face
+            .as_triangle()
+            .iter()
+            .find(|vertex| **vertex != a && **vertex != b)
+        {
+            Some(c) => *c,
+            None => return false,
+        };
+
+        let can_exit = |pivot: Vertex, other: Vertex| {
+            let exit_length = (point_to_vec2(*pivot) - point_to_vec2(*c)).mag();
+
+            !self.is_constraint(pivot, c)
+                && exit_length >= gap
+                && (!self.is_constraint(other, c)
+                    || point_segment_distance(
+                        point_to_vec2(*pivot),
+                        point_to_vec2(*other),
+                        point_to_vec2(*c),
+                    ) >= gap)
+        };
+
+        can_exit(b, a) || can_exit(a, b)
+    }
+
+    // How many pathfinding queries have been made since this was last called.
+    pub fn take_query_count(&self) -> usize {
+        self.queries.swap(0, Ordering::Relaxed)
+    }
+
+    // Round off the corners of a path (which starts from `start`) so that a unit that can't turn any
+    // tighter than `turn_radius` can follow it without snapping around them. Each corner becomes a
+    // curve that starts and ends up to `turn_radius` away from it, unless that would cut too close
+    // to something, in which case the corner is left sharp.
+    pub fn smooth_path(
+        &self,
+        start: Vec2,
+        path: Vec<Vec2>,
+        unit_radius: f32,
+        turn_radius: f32,
+    ) -> Vec<Vec2> {
+        if path.len() < 2 || turn_radius <= 0.0 {
+            return path;
+        }
+
+        let mut smoothed = Vec::with_capacity(path.len() * CURVE_SEGMENTS);
+        let mut previous = start;
+
+        for (i, &corner) in path.iter().enumerate() {
+            let next = match path.get(i + 1) {
+                Some(next) => *next,
+                None => {
+                    smoothed.push(corner);
+                    break;
+                }
+            };
+
+            let (incoming, outgoing) = (corner - previous, next - corner);
+            previous = corner;
+
+            // Only going up to halfway along either side, so that curves don't overlap.
+            let cut = turn_radius
+                .min(incoming.mag() / 2.0)
+                .min(outgoing.mag() / 2.0);
+            let straight = incoming.normalized().dot(outgoing.normalized()) > 0.999;
+
+            if cut <= 0.0 || straight {
+                smoothed.push(corner);
+                continue;
+            }
+
+            let entry = corner - incoming.normalized() * cut;
+            let exit = corner + outgoing.normalized() * cut;
+
+            // A quadratic bezier curve with the corner as the control point.
+            let curve: Vec<Vec2> = (0..=CURVE_SEGMENTS)
+                .map(|j| {
+                    let t = j as f32 / CURVE_SEGMENTS as f32;
+                    entry * (1.0 - t).powi(2) + corner * 2.0 * t * (1.0 - t) + exit * t.powi(2)
+                })
+                .collect();
+
+            if curve
+                .windows(2)
+                .all(|pair| self.clear_between(pair[0], pair[1], unit_radius))
+            {
+                smoothed.extend(curve);
+            } else {
+                smoothed.push(corner);
+            }
+        }
+
+        smoothed
+    }
+
+    // Work out the way to `goal` from every triangle on the map at once. This is a lot cheaper
+    // than pathfinding separately for every unit in a big group.
+    pub fn flow_field(&self, goal: Vec2, unit_radius: f32) -> Option<FlowField> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let goal_face = self.locate_face(goal)?;
+
+        let mut next = vec![None; self.dlt.num_faces()];
+        let mut distances = vec![f32::INFINITY; self.dlt.num_faces()];
+        let mut heap = BinaryHeap::new();
+
+        distances[goal_face.fix()] = 0.0;
+        heap.push((Reverse(OrderedFloat(0.0)), goal_face.fix()));
+
+        while let Some((Reverse(OrderedFloat(distance)), index)) = heap.pop() {
+            if distance > distances[index] {
+                continue;
+            }
+
+            let face = self.dlt.face(index);
+            let center = TriangleRef::new(face, Vec2::zero()).center();
+
+            for neighbour in self.passable_neighbours(face.as_triangle(), unit_radius * 2.0, None) {
+                let neighbour_distance =
+                    distance + (TriangleRef::new(neighbour, Vec2::zero()).center() - center).mag();
+
+                if neighbour_distance < distances[neighbour.fix()] {
+                    distances[neighbour.fix()] = neighbour_distance;
+                    next[neighbour.fix()] = Some(index);
+                    heap.push((Reverse(OrderedFloat(neighbour_distance)), neighbour.fix()));
+                }
+            }
+        }
+
+        Some(FlowField {
+            unit_radius,
+            version: self.version,
+            goal_face: goal_face.fix(),
+            next,
+        })
+    }
+
     fn offset_by_normal(&self, vertex: Vertex, offset: f32) -> Vec2 {
-        // Sum up the lengths of all constraint edges that connect to the vertex
-        let sum = vertex
+        let edges: Vec<Vec2> = vertex
             .ccw_out_edges()
             .filter(|edge| self.dlt.is_constraint_edge(edge.fix()))
-            .fold(cgmath::Point2::new(0.0, 0.0), |normal, edge| {
-                let edge_delta = *edge.from() - *edge.to();
-                normal + edge_delta
-            });
+            .map(|edge| (point_to_vec2(*edge.to()) - point_to_vec2(*edge.from())).normalized())
+            .collect();
+
+        // Sum up the directions of all constraint edges that connect to the vertex and flip them
+        // into a normal pointing away from the edges.
+        let sum = edges
+            .iter()
+            .fold(Vec2::zero(), |normal, direction| normal - *direction);
+
+        if sum.mag_sq() == 0.0 {
+            return point_to_vec2(*vertex);
+        }
+
+        let normal = sum.normalized();
+
+        // Going `offset` along the normal only puts the point `offset` away from the edges if they
+        // form a straight line. At a corner it has to go further out so that big units don't clip
+        // it, but not too far for really sharp corners.
+        let closest_edge = edges
+            .iter()
+            .map(|direction| (normal.x * direction.y - normal.y * direction.x).abs())
+            .fold(1.0, f32::min);
+
+        point_to_vec2(*vertex) + normal * offset / closest_edge.max(1.0 / MAX_CORNER_OFFSET)
+    }
+}
+
+// Each triangle of the map points towards the next triangle on the way to the goal.
+pub struct FlowField {
+    unit_radius: f32,
+    version: u32,
+    goal_face: FixedFaceHandle,
+    next: Vec<Option<FixedFaceHandle>>,
+}
+
+impl FlowField {
+    // A line from the middle of each triangle to the next one on the way to the goal, for debugging.
+    // There's nothing to draw once the map has changed, as the triangles won't line up any more.
+    pub fn arrows(&self, map: &Map) -> Vec<(Vec2, Vec2)> {
+        if self.version != map.version {
+            return Vec::new();
+        }
+
+        let center = |face| TriangleRef::new(map.dlt.face(face), Vec2::zero()).center();
+
+        self.next
+            .iter()
+            .enumerate()
+            .filter_map(|(face, next)| next.map(|next| (center(face), center(next))))
+            .collect()
+    }
+
+    // The triangles from `face` to the goal, or `None` if the goal can't be reached from there.
+    fn chain(&self, face: FixedFaceHandle) -> Option<Vec<FixedFaceHandle>> {
+        let mut chain = vec![face];
+
+        while *chain.last().unwrap() != self.goal_face {
+            chain.push(self.next[*chain.last().unwrap()]?);
+        }
+
+        Some(chain)
+    }
+
+    // A path from `start` to anywhere near the goal. Both ends are followed towards the goal
+    // until they meet, so the path doesn't actually have to go through the goal.
+    pub fn path(&self, map: &Map, start: Vec2, end: Vec2) -> Option<Vec<Vec2>> {
+        debug_assert_eq!(self.version, map.version());
+
+        if map.clear_between(start, end, self.unit_radius) {
+            return Some(vec![end]);
+        }
+
+        let start_chain = self.chain(map.locate_face(start)?.fix())?;
+        let end_chain = self.chain(map.locate_face(end)?.fix())?;
 
-        // Normalize them into a normal pointing away from the edge.
-        let normal = point_to_vec2(sum).normalized();
+        let end_index = |face| end_chain.iter().position(|other| *other == face);
+        let (start_meeting, end_meeting) = start_chain
+            .iter()
+            .enumerate()
+            .find_map(|(i, face)| end_index(*face).map(|j| (i, j)))?;
 
-        point_to_vec2(*vertex) + (normal * offset)
+        let triangles: Vec<TriangleRef> = start_chain[..=start_meeting]
+            .iter()
+            .chain(end_chain[..end_meeting].iter().rev())
+            .map(|face| TriangleRef::new(map.dlt.face(*face), Vec2::zero()))
+            .collect();
+
+        if triangles.len() < 2 {
+            return Some(vec![end]);
+        }
+
+        let funnel_portals = funnel_portals(start, end, self.unit_radius, &triangles, map);
+
+        Some(funnel(&funnel_portals))
     }
 }
 
@@ -316,11 +896,85 @@ pub fn funnel(portals: &[(Vec2, Vec2)]) -> Vec<Vec2> {
     points
 }
 
+// Uses the Liang-Barsky line clipping algorithm.
+pub fn segment_intersects_rect(a: Vec2, b: Vec2, min: Vec2, max: Vec2) -> bool {
+    let delta = b - a;
+    let mut t_min = 0.0_f32;
+    let mut t_max = 1.0_f32;
+
+    for &(p, q) in &[
+        (-delta.x, a.x - min.x),
+        (delta.x, max.x - a.x),
+        (-delta.y, a.y - min.y),
+        (delta.y, max.y - a.y),
+    ] {
+        if p == 0.0 {
+            // Parallel to this edge, so check that we're on the inside of it.
+            if q < 0.0 {
+                return false;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                t_min = t_min.max(t);
+            } else {
+                t_max = t_max.min(t);
+            }
+        }
+    }
+
+    t_min <= t_max
+}
+
 fn point_to_vec2(point: Point2<f32>) -> Vec2 {
     Vec2::new(point.x, point.y)
 }
 
+fn point_segment_distance(point: Vec2, a: Vec2, b: Vec2) -> f32 {
+    (nearest_point_on_segment(point, a, b) - point).mag()
+}
+
+fn nearest_point_on_segment(point: Vec2, a: Vec2, b: Vec2) -> Vec2 {
+    let ab = b - a;
+    let t = ((point - a).dot(ab) / ab.mag_sq()).clamp(0.0, 1.0);
+    a + ab * t
+}
+
+// Counts how many edges a line going off to the right crosses.
+fn polygon_contains(points: &[Vec2], point: Vec2) -> bool {
+    polygon_edges(points)
+        .filter(|(a, b)| {
+            (a.y > point.y) != (b.y > point.y)
+                && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+        })
+        .count()
+        % 2
+        == 1
+}
+
+fn rectangle(center: Vec2, dimensions: Vec2) -> [Vec2; 4] {
+    let tl = center - dimensions / 2.0;
+    let br = center + dimensions / 2.0;
+    [tl, Vec2::new(br.x, tl.y), br, Vec2::new(tl.x, br.y)]
+}
+
+fn bounding_box(points: &[Vec2]) -> (Vec2, Vec2) {
+    points.iter().fold(
+        (Vec2::broadcast(f32::MAX), Vec2::broadcast(f32::MIN)),
+        |(min, max), point| (min.min_by_component(*point), max.max_by_component(*point)),
+    )
+}
+
+// Each pair of neighbouring points, including the last and the first.
+fn polygon_edges(points: &[Vec2]) -> impl Iterator<Item = (Vec2, Vec2)> + '_ {
+    points
+        .iter()
+        .copied()
+        .zip(points.iter().copied().cycle().skip(1))
+}
+
 type Vertex<'a> = VertexHandle<'a, Point2<f32>, CdtEdge>;
+type Face<'a> = FaceHandle<'a, Point2<f32>, CdtEdge>;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 struct TriangleRef<'a> {
@@ -331,7 +985,7 @@ struct TriangleRef<'a> {
 }
 
 impl<'a> TriangleRef<'a> {
-    fn new(face: FaceHandle<'a, Point2<f32>, CdtEdge>, point: Vec2) -> Self {
+    fn new(face: Face<'a>, point: Vec2) -> Self {
         let [a, b, c] = face.as_triangle();
         Self { a, b, c, point }
     }
@@ -356,30 +1010,13 @@ impl<'a> TriangleRef<'a> {
         map: &'b Map,
         gap: f32,
         end_tri: &'b Self,
+        end_face: Face<'b>,
     ) -> impl Iterator<Item = (TriangleRef<'b>, OrderedFloat<f32>)> + 'b {
         let this = *self;
 
-        arrayvec::ArrayVec::from([(this.a, this.b), (this.b, this.c), (this.c, this.a)])
-            .into_iter()
-            .filter_map(move |(a, b)| {
-                // Flipped here because we want the edge facing outside.
-                let edge = map.dlt.get_edge_from_neighbors(b.fix(), a.fix()).unwrap();
-
-                let a = point_to_vec2(*a);
-                let b = point_to_vec2(*b);
-
-                let face = edge.face();
-
-                if !map.dlt.is_constraint_edge(edge.fix())
-                    && gap.powi(2) <= (a - b).mag_sq()
-                    && face != map.dlt.infinite_face()
-                {
-                    // Return a triangle with the 'focus point' set to zero.
-                    Some(TriangleRef::new(face, Vec2::zero()))
-                } else {
-                    None
-                }
-            })
+        map.passable_neighbours([this.a, this.b, this.c], gap, Some(end_face))
+            // Return a triangle with the 'focus point' set to zero.
+            .map(|face| TriangleRef::new(face, Vec2::zero()))
             // Iterate over all 3 corners and the center and return triangles set with that as the focus point.
             .flat_map(move |triangle| {
                 let center = triangle.center();
@@ -450,3 +1087,225 @@ fn hash_point<H: Hasher>(point: Point2<f32>, hasher: &mut H) {
     ordered_float::OrderedFloat(point.x).hash(hasher);
     ordered_float::OrderedFloat(point.y).hash(hasher);
 }
+
+#[test]
+fn paths_are_invalidated_by_nearby_changes() {
+    let mut map = Map::new();
+    let version = map.version();
+    let start = Vec2::new(-20.0, 0.0);
+    let path = [Vec2::new(20.0, 0.0)];
+
+    assert!(!map.path_invalidated_since(version, start, &path, 1.0));
+
+    // A building off to the side of the path shouldn't affect it.
+    let handle = map
+        .insert(Vec2::new(0.0, 30.0), Vec2::new(4.0, 4.0))
+        .unwrap();
+    assert!(!map.path_invalidated_since(version, start, &path, 1.0));
+
+    // But one right in the way should.
+    map.insert(Vec2::new(0.0, 2.5), Vec2::new(4.0, 4.0))
+        .unwrap();
+    assert!(map.path_invalidated_since(version, start, &path, 1.0));
+
+    // And so should removing one.
+    let version = map.version();
+    map.remove(&handle);
+    assert!(map.path_invalidated_since(
+        version,
+        Vec2::new(-5.0, 30.0),
+        &[Vec2::new(5.0, 30.0)],
+        1.0
+    ));
+}
+
+#[test]
+fn polygons_block_paths_until_removed() {
+    let mut map = Map::new();
+    let start = Vec2::new(-20.0, 0.0);
+    let end = Vec2::new(20.0, 0.0);
+
+    // An L-shaped rock, which isn't convex.
+    let rock = [
+        Vec2::new(-5.0, -10.0),
+        Vec2::new(5.0, -10.0),
+        Vec2::new(5.0, 10.0),
+        Vec2::new(0.0, 10.0),
+        Vec2::new(0.0, -5.0),
+        Vec2::new(-5.0, -5.0),
+    ];
+
+    assert!(!map.can_insert_polygon(&rock[..2]));
+
+    let handle = map.insert_polygon(&rock).unwrap();
+    assert!(!map.can_insert(Vec2::new(2.5, 0.0), Vec2::new(6.0, 2.0)));
+    // The gap in the L is still free.
+    assert!(map.can_insert(Vec2::new(-2.5, 5.0), Vec2::new(2.0, 2.0)));
+
+    let path = map.pathfind(start, end, 1.0, None, None).unwrap();
+    assert!(path.len() > 1);
+    assert!(path
+        .iter()
+        .all(|point| point.y.abs() > 10.0 || point.x.abs() > 5.0));
+
+    map.remove(&handle);
+    assert_eq!(map.pathfind(start, end, 1.0, None, None), Some(vec![end]));
+    assert!(map.can_insert(Vec2::new(2.5, 0.0), Vec2::new(6.0, 2.0)));
+}
+
+#[test]
+fn disabled_constraints_can_be_walked_through() {
+    let mut map = Map::new();
+    let start = Vec2::new(0.0, -10.0);
+    let end = Vec2::new(0.0, 10.0);
+
+    let mut handle = map.insert(Vec2::zero(), Vec2::new(20.0, 2.0)).unwrap();
+    let version = map.version();
+    assert_ne!(map.pathfind(start, end, 1.0, None, None), Some(vec![end]));
+
+    assert!(map.set_constraint_enabled(&mut handle, false));
+    assert_eq!(map.pathfind(start, end, 1.0, None, None), Some(vec![end]));
+    assert!(map.path_invalidated_since(version, start, &[end], 1.0));
+    // Removing it while it's disabled doesn't do anything.
+    map.remove(&handle);
+
+    // It can't be put back over something else.
+    let blocker = map
+        .insert(Vec2::new(5.0, 0.0), Vec2::new(2.0, 4.0))
+        .unwrap();
+    assert!(!map.set_constraint_enabled(&mut handle, true));
+    map.remove(&blocker);
+
+    assert!(map.set_constraint_enabled(&mut handle, true));
+    assert!(map.impassable_between(start, end));
+}
+
+#[test]
+fn plateaus_can_only_be_reached_up_ramps() {
+    let map = Map::new();
+    let plateau = &PLATEAUS[0];
+    let ramp = &plateau.ramps[0];
+
+    // Straight up the cliff on the far side from the ramp.
+    let below = plateau.center - ramp.direction * (plateau.dimensions.x / 2.0 + 4.0);
+    assert!(map.impassable_between(below, plateau.center));
+
+    let path = map
+        .pathfind(below, plateau.center, 1.0, None, None)
+        .unwrap();
+    // It has to go all the way around to one of the ramps.
+    assert!(path.len() > 2);
+    assert!(path
+        .iter()
+        .any(|point| crate::high_ground::elevation_at(*point) < plateau.height));
+}
+
+#[test]
+fn points_get_pushed_out_of_buildings() {
+    let mut map = Map::new();
+    map.insert(Vec2::new(0.0, 0.0), Vec2::new(10.0, 4.0))
+        .unwrap();
+
+    // Out of the nearest side.
+    let point = map.nearest_passable_point(Vec2::new(1.0, 1.5), 0.5);
+    assert!((point - Vec2::new(1.0, 2.5)).mag() < 0.1);
+
+    // Already clear, so it stays put.
+    let clear = Vec2::new(0.0, 10.0);
+    assert_eq!(map.nearest_passable_point(clear, 0.5), clear);
+
+    // Too close to the edge of something counts too.
+    let point = map.nearest_passable_point(Vec2::new(5.2, 0.0), 1.0);
+    assert!(point.x >= 6.0);
+
+    // A gap between two buildings that's too small to stand in.
+    map.insert(Vec2::new(0.0, 3.5), Vec2::new(10.0, 2.0))
+        .unwrap();
+    let point = map.nearest_passable_point(Vec2::new(0.0, 2.25), 1.0);
+    assert!(map.push_out(point, 1.0).is_none());
+    assert!(point.y.abs() > 3.0 || point.x.abs() > 5.0);
+
+    // And off the edge of the map.
+    let point = map.nearest_passable_point(Vec2::new(500.0, 0.0), 1.0);
+    assert!(point.x < MAP_SIZE / 2.0);
+}
+
+#[test]
+fn smoothed_paths_round_off_corners() {
+    let mut map = Map::new();
+    let start = Vec2::new(0.0, 0.0);
+    let path = vec![Vec2::new(10.0, 0.0), Vec2::new(10.0, 10.0)];
+
+    let smoothed = map.smooth_path(start, path.clone(), 1.0, 2.0);
+    assert_eq!(smoothed.first(), Some(&Vec2::new(8.0, 0.0)));
+    assert_eq!(smoothed.last(), Some(&Vec2::new(10.0, 10.0)));
+    // The corner itself gets cut.
+    assert!(!smoothed.contains(&Vec2::new(10.0, 0.0)));
+    // Every turn along the way is gentler than the right angle was.
+    let mut previous = start;
+    for pair in smoothed.windows(2) {
+        let (incoming, outgoing) = (pair[0] - previous, pair[1] - pair[0]);
+        assert!(incoming.normalized().dot(outgoing.normalized()) > 0.5);
+        previous = pair[0];
+    }
+
+    // A building just inside the corner means the curve would clip it.
+    map.insert(Vec2::new(8.0, 2.0), Vec2::new(2.0, 2.0))
+        .unwrap();
+    assert_eq!(map.smooth_path(start, path.clone(), 1.0, 2.0), path);
+}
+
+#[test]
+fn flow_fields_lead_around_obstacles() {
+    let mut map = Map::new();
+    map.insert(Vec2::new(0.0, 0.0), Vec2::new(4.0, 40.0))
+        .unwrap();
+
+    let goal = Vec2::new(20.0, 0.0);
+    let field = map.flow_field(goal, 1.0).unwrap();
+
+    for &start in &[Vec2::new(-20.0, 0.0), Vec2::new(-10.0, 15.0)] {
+        // Ending a bit away from the goal, like units in a formation do.
+        for &end in &[goal, Vec2::new(25.0, 5.0)] {
+            let path = field.path(&map, start, end).unwrap();
+            assert_eq!(*path.last().unwrap(), end);
+            // It has to go around the end of the wall.
+            assert!(path.iter().any(|point| point.y.abs() > 20.0));
+        }
+    }
+
+    // Nothing's in the way here.
+    let start = Vec2::new(40.0, 0.0);
+    assert_eq!(field.path(&map, start, goal), Some(vec![goal]));
+}
+
+#[test]
+fn big_units_keep_clear_of_corners_and_narrow_gaps() {
+    let mut map = Map::new();
+    // Two buildings with a 3 unit gap between them.
+    map.insert(Vec2::new(0.0, 6.5), Vec2::new(4.0, 10.0))
+        .unwrap();
+    map.insert(Vec2::new(0.0, -6.5), Vec2::new(4.0, 10.0))
+        .unwrap();
+
+    let start = Vec2::new(-10.0, 0.0);
+    let end = Vec2::new(10.0, 0.0);
+
+    // Small units go through the gap.
+    let path = map.pathfind(start, end, 1.0, None, None).unwrap();
+    assert!(path.iter().all(|point| point.y.abs() < 5.0));
+
+    // Big ones have to go around, without cutting the corners.
+    let radius = 2.0;
+    let path = map.pathfind(start, end, radius, None, None).unwrap();
+    assert!(path.iter().any(|point| point.y.abs() > 11.5));
+
+    for point in &path {
+        for &center in &[Vec2::new(0.0, 6.5), Vec2::new(0.0, -6.5)] {
+            let outside = ((*point - center).abs() - Vec2::new(2.0, 5.0))
+                .max_by_component(Vec2::zero())
+                .mag();
+            assert!(outside >= radius * 0.99, "{:?} is too close", point);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::time::{Duration, Instant};
+
+// Timings are smoothed out over a few frames, otherwise the numbers flicker too fast to read.
+const SMOOTHING: f32 = 0.1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Section {
+    // A group of systems in a schedule, see `mark_stage`.
+    Stage(&'static str),
+    // Encoding a render pass. wgpu 0.6 doesn't support timestamp queries, so this is only how long
+    // the cpu takes to record the commands, not how long the gpu takes to run them.
+    Pass(&'static str),
+}
+
+impl Section {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Stage(name) | Self::Pass(name) => name,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BufferStats {
+    pub label: &'static str,
+    pub len: usize,
+    pub size_in_bytes: u64,
+}
+
+// Toggled with F3 (by default) and drawn in `ecs::render_profiler`.
+#[derive(Default)]
+pub struct Profiler {
+    pub enabled: bool,
+    current_stage: Option<(&'static str, Instant)>,
+    // Smoothed timings in milliseconds, in the order that they were first seen.
+    timings: Vec<(Section, f32)>,
+    // The same, but just from the last time each section was recorded, for `--bench`.
+    latest: Vec<(Section, f32)>,
+    pub buffers: Vec<BufferStats>,
+}
+
+impl Profiler {
+    pub fn begin_stage(&mut self, stage: Option<&'static str>) {
+        if let Some((name, start)) = self.current_stage.take() {
+            self.record(Section::Stage(name), start.elapsed());
+        }
+
+        self.current_stage = stage.map(|stage| (stage, Instant::now()));
+    }
+
+    pub fn record(&mut self, section: Section, duration: Duration) {
+        let ms = duration.as_secs_f32() * 1000.0;
+
+        match self.latest.iter_mut().find(|(s, _)| *s == section) {
+            Some((_, latest)) => *latest = ms,
+            None => self.latest.push((section, ms)),
+        }
+
+        match self.timings.iter_mut().find(|(s, _)| *s == section) {
+            Some((_, smoothed)) => *smoothed += (ms - *smoothed) * SMOOTHING,
+            None => self.timings.push((section, ms)),
+        }
+    }
+
+    // The unsmoothed timings from the last frame, named like "Stage: Gameplay" or "Pass: Shadows".
+    pub fn latest_timings(&self) -> impl Iterator<Item = (String, f32)> + '_ {
+        self.latest.iter().map(|(section, ms)| {
+            let kind = match section {
+                Section::Stage(_) => "Stage",
+                Section::Pass(_) => "Pass",
+            };
+            (format!("{}: {}", kind, section.name()), *ms)
+        })
+    }
+
+    pub fn overlay_text(&self) -> String {
+        let mut text = String::new();
+
+        for (heading, is_pass) in &[("Schedule stages", false), ("Render passes (cpu)", true)] {
+            text.push_str(&format!("{}:\n", heading));
+            for (section, ms) in &self.timings {
+                if matches!(section, Section::Pass(_)) == *is_pass {
+                    text.push_str(&format!("  {}: {:.2} ms\n", section.name(), ms));
+                }
+            }
+        }
+
+        text.push_str("Buffers:\n");
+        for buffer in self.buffers.iter().filter(|buffer| buffer.len > 0) {
+            text.push_str(&format!(
+                "  {}: {} ({:.1} KiB)\n",
+                buffer.label.trim_start_matches("Cheese "),
+                buffer.len,
+                buffer.size_in_bytes as f32 / 1024.0
+            ));
+        }
+
+        text
+    }
+}
+
+// Times how long each pass takes to encode, one after the other.
+pub struct PassTimer {
+    current: Option<(&'static str, Instant)>,
+    finished: Vec<(&'static str, Duration)>,
+}
+
+impl PassTimer {
+    pub fn new() -> Self {
+        Self {
+            current: None,
+            finished: Vec::new(),
+        }
+    }
+
+    // Ends the current pass (if there is one) and starts timing the next.
+    pub fn next(&mut self, pass: &'static str) {
+        self.end();
+        self.current = Some((pass, Instant::now()));
+    }
+
+    pub fn end(&mut self) {
+        if let Some((name, start)) = self.current.take() {
+            self.finished.push((name, start.elapsed()));
+        }
+    }
+
+    pub fn finish(mut self, profiler: &mut Profiler) {
+        self.end();
+
+        for (name, duration) in self.finished {
+            profiler.record(Section::Pass(name), duration);
+        }
+    }
+}
+
+// Marks the start of a stage of a schedule, or the end of the last one with `None`. This is a thread
+// local step, so the stages on either side of it can't run in parallel with each other anymore.
+pub fn mark_stage(builder: &mut legion::systems::Builder, stage: Option<&'static str>) {
+    builder.add_thread_local_fn(move |_, resources| {
+        resources.get_mut::<Profiler>().unwrap().begin_stage(stage);
+    });
+}
+
+#[test]
+fn timings_are_smoothed_between_frames() {
+    let mut profiler = Profiler::default();
+
+    profiler.record(Section::Pass("Main"), Duration::from_millis(10));
+    profiler.record(Section::Pass("Main"), Duration::from_millis(20));
+    profiler.record(Section::Stage("Main"), Duration::from_millis(5));
+
+    assert_eq!(profiler.timings.len(), 2);
+    assert!((profiler.timings[0].1 - 11.0).abs() < 0.001);
+    assert!((profiler.timings[1].1 - 5.0).abs() < 0.001);
+}
@@ -1,4 +1,6 @@
-use crate::resources::{Camera, ScreenDimensions, Settings};
+use crate::camera::{Camera, CameraShake};
+use crate::hot_reload::shader;
+use crate::resources::{ScreenDimensions, Settings, TimeOfDay};
 use std::sync::Arc;
 use ultraviolet::{Mat4, Vec2, Vec3, Vec4};
 use wgpu::util::DeviceExt;
@@ -7,24 +9,77 @@ use winit::{
     window::{Window, WindowBuilder},
 };
 
+mod adapter;
+#[cfg(feature = "crumbs")]
+mod crumb_pipeline;
+mod decal_pipeline;
+mod fog_of_war_pipeline;
+mod graph;
 mod lines_3d_pipeline;
 mod lines_pipeline;
 mod model_pipelines;
+mod particle_pipeline;
+pub mod post_processing;
+mod scenery_buffers;
+mod screenshot;
 mod shadow_pipeline;
+#[cfg(feature = "gpu-skinning")]
+mod skinning_pipeline;
+mod sky_pipeline;
+mod terrain_pipeline;
+mod text;
 mod torus_pipeline;
+mod water_pipeline;
 
+#[cfg(feature = "crumbs")]
+pub use crumb_pipeline::CrumbPipeline;
+pub use decal_pipeline::{decal_atlas, DecalBuffer, DecalKind, DecalPipeline};
+pub use fog_of_war_pipeline::{FogOfWarBuffer, FogOfWarPipeline};
+pub use graph::{GraphResource, RenderGraph};
 pub use lines_3d_pipeline::{Lines3dBuffer, Lines3dPipeline};
 pub use lines_pipeline::{Image, LineBuffers, LinesPipeline};
 pub use model_pipelines::{ModelBuffers, ModelInstance, ModelPipelines, TitlescreenBuffer};
+pub use particle_pipeline::{ParticleInstance, ParticlePipeline};
+pub use post_processing::PostProcessing;
+use scenery_buffers::PersistentBuffer;
+pub use scenery_buffers::SceneryBuffers;
+pub use screenshot::Screenshots;
 pub use shadow_pipeline::ShadowPipeline;
+#[cfg(feature = "gpu-skinning")]
+pub use skinning_pipeline::{SkinnedModel, SkinningPipeline};
+pub use sky_pipeline::SkyPipeline;
+pub use terrain_pipeline::TerrainPipeline;
+pub use text::{Font, TextAlignment, TextBuffer};
 pub use torus_pipeline::{TorusBuffer, TorusInstance, TorusPipeline};
+pub use water_pipeline::WaterPipeline;
 
 const DISPLAY_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Bgra8UnormSrgb;
+// What the scene is rendered into before post-processing. With the `hdr` feature it's floating
+// point, so bright things can go past 1.0 and be brought back down by the tone mapping.
+#[cfg(feature = "hdr")]
+const SCENE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+#[cfg(not(feature = "hdr"))]
+const SCENE_FORMAT: wgpu::TextureFormat = DISPLAY_FORMAT;
 pub const TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
-const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+// The stencil is used to cut the units out of their outlines.
+const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth24PlusStencil8;
+const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 const INDEX_FORMAT: wgpu::IndexFormat = wgpu::IndexFormat::Uint32;
 
 const SUN_DIRECTION: Vec3 = Vec3::new(5.0, 10.0, 0.0);
+// Slightly warm, so the shadows look a bit cooler in comparison.
+const SUN_COLOUR: Vec3 = Vec3::new(1.0, 0.95, 0.85);
+const AMBIENT_COLOUR: Vec3 = Vec3::new(0.5, 0.5, 0.55);
+// For the day/night cycle, see `Sunlight::at`.
+const DUSK_COLOUR: Vec3 = Vec3::new(1.0, 0.55, 0.3);
+const MOON_COLOUR: Vec3 = Vec3::new(0.2, 0.25, 0.4);
+const NIGHT_AMBIENT_COLOUR: Vec3 = Vec3::new(0.15, 0.15, 0.25);
+// How many point lights can be drawn at once. The ones closest to the camera are picked if there are
+// more than this, and anything further than `LIGHT_CULL_DISTANCE` away isn't drawn at all.
+#[cfg(feature = "material-maps")]
+const MAX_POINT_LIGHTS: usize = 64;
+#[cfg(feature = "material-maps")]
+const LIGHT_CULL_DISTANCE: f32 = 80.0;
 const BLUR_SCALE: f32 = 2.0;
 const BLUR_STRENGTH: f32 = 5.0;
 
@@ -37,22 +92,28 @@ pub struct RenderContext {
     surface: wgpu::Surface,
     swap_chain_desc: wgpu::SwapChainDescriptor,
     pub depth_texture: wgpu::TextureView,
+    // How many samples each pixel gets for MSAA, and how big the scene is rendered compared to the
+    // window. The framebuffer gets stretched back to fit the window in the post-processing pass.
+    sample_count: u32,
+    render_scale: f32,
+    // With MSAA on, the main pass renders into these and they get resolved into the framebuffer
+    // and bloombuffer.
+    msaa_framebuffer: Option<wgpu::TextureView>,
+    msaa_bloombuffer: Option<wgpu::TextureView>,
 
     framebuffer_bind_group_layout: wgpu::BindGroupLayout,
-    pub framebuffer_bind_group: wgpu::BindGroup,
+    framebuffer_bind_group: wgpu::BindGroup,
     pub framebuffer: wgpu::TextureView,
     pub framebuffer_sampler: wgpu::Sampler,
     pub screen_dimension_uniform_buffer: wgpu::Buffer,
-    pub post_processing_pipeline: wgpu::RenderPipeline,
 
-    pub bloombuffer: wgpu::TextureView,
-    pub bloombuffer_after_vertical: wgpu::TextureView,
+    bloombuffer: wgpu::TextureView,
+    bloombuffer_after_vertical: wgpu::TextureView,
     bloom_blur_vert_buffer: wgpu::Buffer,
     bloom_blur_hori_buffer: wgpu::Buffer,
-    pub bloom_first_pass_bind_group: wgpu::BindGroup,
-    pub bloom_second_pass_bind_group: wgpu::BindGroup,
+    bloom_first_pass_bind_group: wgpu::BindGroup,
+    bloom_second_pass_bind_group: wgpu::BindGroup,
     bloom_bind_group_layout: wgpu::BindGroupLayout,
-    pub bloom_blur_pipeline: wgpu::RenderPipeline,
 
     pub shadow_texture: wgpu::TextureView,
 
@@ -60,9 +121,20 @@ pub struct RenderContext {
 
     perspective_buffer: wgpu::Buffer,
     view_buffer: wgpu::Buffer,
+    view: Mat4,
+    sun_buffer: wgpu::Buffer,
+    sunlight: Sunlight,
+    lighting_buffer: wgpu::Buffer,
+    #[cfg(feature = "material-maps")]
+    point_light_buffer: wgpu::Buffer,
     main_bind_group_layout: wgpu::BindGroupLayout,
     main_bind_group: Arc<wgpu::BindGroup>,
 
+    #[cfg(feature = "water-shader")]
+    reflection_view_buffer: wgpu::Buffer,
+    #[cfg(feature = "water-shader")]
+    reflection_bind_group: Arc<wgpu::BindGroup>,
+
     pub joint_bind_group_layout: wgpu::BindGroupLayout,
 
     pub vs_transparent_module: wgpu::ShaderModule,
@@ -80,34 +152,19 @@ pub struct RenderContext {
 impl RenderContext {
     pub async fn new(event_loop: &EventLoop<()>, settings: &Settings) -> anyhow::Result<Self> {
         let window = WindowBuilder::new()
-            .with_inner_size(winit::dpi::LogicalSize::new(1280, 720))
+            .with_inner_size(winit::dpi::LogicalSize::new(
+                settings.resolution[0],
+                settings.resolution[1],
+            ))
             .with_title("Cheese")
+            .with_fullscreen(fullscreen(settings.fullscreen))
             .build(event_loop)?;
 
-        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
-        let surface = unsafe { instance.create_surface(&window) };
-
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: Some(&surface),
-            })
-            .await
-            .ok_or_else(|| anyhow::anyhow!(
-                "'request_adapter' failed. If you get this on linux, try installing the vulkan drivers for your gpu. \
-                You can check that they're working properly by running `vulkaninfo` or `vkcube`."
-            ))?;
-
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
-                    limits: wgpu::Limits::default(),
-                    shader_validation: true,
-                },
-                None,
-            )
-            .await?;
+        let adapter::ChosenAdapter {
+            surface,
+            device,
+            queue,
+        } = adapter::choose(&window, settings).await?;
 
         // Create samplers
 
@@ -127,14 +184,21 @@ impl RenderContext {
             ..Default::default()
         });
 
+        // Linear so that the framebuffer looks alright when stretched with a render scale.
         let framebuffer_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: Some("Cheese framebuffer sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
             ..Default::default()
         });
 
         // Create basic buffers
 
         let window_size = window.inner_size();
+        let sample_count = valid_sample_count(settings.msaa);
+        let render_scale = valid_render_scale(settings.render_scale);
+        let (target_width, target_height) =
+            scaled_size(window_size.width, window_size.height, render_scale);
 
         let perspective_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Cheese perspective buffer"),
@@ -145,16 +209,35 @@ impl RenderContext {
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
         });
 
+        let view = Mat4::look_at(Vec3::one(), Vec3::zero(), Vec3::unit_y());
         let view_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Cheese view buffer"),
-            contents: bytemuck::bytes_of(&Mat4::look_at(Vec3::one(), Vec3::zero(), Vec3::unit_y())),
+            contents: bytemuck::bytes_of(&view),
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
         });
 
         let sun_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Cheese sun buffer"),
             contents: &bytemuck::bytes_of(&SUN_DIRECTION),
-            usage: wgpu::BufferUsage::UNIFORM,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let lighting_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese lighting buffer"),
+            contents: bytemuck::bytes_of(&LightingUniforms::new(
+                Vec3::one(),
+                0,
+                &Sunlight::default(),
+            )),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        #[cfg(feature = "material-maps")]
+        let point_light_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cheese point light buffer"),
+            size: (MAX_POINT_LIGHTS * std::mem::size_of::<PointLight>()) as u64,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
         });
 
         let screen_dimension_uniform_buffer =
@@ -174,71 +257,97 @@ impl RenderContext {
             "Cheese shadow texture",
             settings.shadow_resolution,
             settings.shadow_resolution,
-            DEPTH_FORMAT,
+            SHADOW_FORMAT,
             wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
         );
 
         // Create the main bind group
 
+        #[allow(unused_mut)]
+        let mut main_bind_group_layout_entries = vec![
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStage::VERTEX,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStage::VERTEX,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler { comparison: false },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler { comparison: false },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::SampledTexture {
+                    multisampled: false,
+                    dimension: wgpu::TextureViewDimension::D2,
+                    component_type: wgpu::TextureComponentType::Float,
+                },
+                count: None,
+            },
+            // The colour of the sun and how many point lights there are, see
+            // `LightingUniforms`.
+            wgpu::BindGroupLayoutEntry {
+                binding: 6,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::UniformBuffer {
+                    dynamic: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ];
+        // The point lights.
+        #[cfg(feature = "material-maps")]
+        main_bind_group_layout_entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 7,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::StorageBuffer {
+                dynamic: false,
+                min_binding_size: None,
+                readonly: true,
+            },
+            count: None,
+        });
+
         let main_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Cheese main bind group layout"),
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStage::VERTEX,
-                        ty: wgpu::BindingType::UniformBuffer {
-                            dynamic: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStage::VERTEX,
-                        ty: wgpu::BindingType::UniformBuffer {
-                            dynamic: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::UniformBuffer {
-                            dynamic: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 3,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler { comparison: false },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 4,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler { comparison: false },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 5,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::SampledTexture {
-                            multisampled: false,
-                            dimension: wgpu::TextureViewDimension::D2,
-                            component_type: wgpu::TextureComponentType::Float,
-                        },
-                        count: None,
-                    },
-                ],
+                entries: &main_bind_group_layout_entries,
             });
 
-        let main_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &main_bind_group_layout,
-            entries: &[
+        let create_main_bind_group = |label, view_buffer: &wgpu::Buffer| {
+            #[allow(unused_mut)]
+            let mut entries = vec![
                 wgpu::BindGroupEntry {
                     binding: 0,
                     resource: wgpu::BindingResource::Buffer(perspective_buffer.slice(..)),
@@ -263,10 +372,39 @@ impl RenderContext {
                     binding: 5,
                     resource: wgpu::BindingResource::TextureView(&shadow_texture),
                 },
-            ],
-            label: Some("Cheese main bind group"),
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Buffer(lighting_buffer.slice(..)),
+                },
+            ];
+            #[cfg(feature = "material-maps")]
+            entries.push(wgpu::BindGroupEntry {
+                binding: 7,
+                resource: wgpu::BindingResource::Buffer(point_light_buffer.slice(..)),
+            });
+
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &main_bind_group_layout,
+                entries: &entries,
+                label: Some(label),
+            })
+        };
+
+        let main_bind_group = create_main_bind_group("Cheese main bind group", &view_buffer);
+
+        // The same as the main bind group, but looking up from underneath the water.
+        #[cfg(feature = "water-shader")]
+        let reflection_view_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cheese reflection view buffer"),
+            size: std::mem::size_of::<Mat4>() as u64,
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
         });
 
+        #[cfg(feature = "water-shader")]
+        let reflection_bind_group =
+            create_main_bind_group("Cheese reflection bind group", &reflection_view_buffer);
+
         // Post-processing
 
         let framebuffer_bind_group_layout =
@@ -296,48 +434,12 @@ impl RenderContext {
             &device,
             &framebuffer_bind_group_layout,
             &framebuffer_sampler,
-            window_size.width,
-            window_size.height,
+            target_width,
+            target_height,
         );
 
-        let post_processing_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Cheese post-processing pipeline layout"),
-                bind_group_layouts: &[&framebuffer_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-
-        let vs_full_screen_quad =
-            wgpu::include_spirv!("../shaders/compiled/full_screen_quad.vert.spv");
+        let vs_full_screen_quad = shader!("full_screen_quad.vert.spv");
         let vs_full_screen_quad_module = device.create_shader_module(vs_full_screen_quad);
-        let fs_post_processing =
-            wgpu::include_spirv!("../shaders/compiled/post_processing.frag.spv");
-        let fs_post_processing_module = device.create_shader_module(fs_post_processing);
-
-        let post_processing_pipeline =
-            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: Some("Cheese post-processing pipeline"),
-                layout: Some(&post_processing_pipeline_layout),
-                vertex_stage: wgpu::ProgrammableStageDescriptor {
-                    module: &vs_full_screen_quad_module,
-                    entry_point: "main",
-                },
-                fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
-                    module: &fs_post_processing_module,
-                    entry_point: "main",
-                }),
-                rasterization_state: Some(wgpu::RasterizationStateDescriptor::default()),
-                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
-                color_states: &[colour_state_descriptor(false)],
-                depth_stencil_state: None,
-                vertex_state: wgpu::VertexStateDescriptor {
-                    index_format: INDEX_FORMAT,
-                    vertex_buffers: &[],
-                },
-                sample_count: 1,
-                sample_mask: !0,
-                alpha_to_coverage_enabled: false,
-            });
 
         // Re-usable bind group layouts, buffers and shader modules
 
@@ -369,10 +471,10 @@ impl RenderContext {
                 ],
             });
 
-        let vs_transparent = wgpu::include_spirv!("../shaders/compiled/transparent.vert.spv");
+        let vs_transparent = shader!("transparent.vert.spv");
         let vs_transparent_module = device.create_shader_module(vs_transparent);
 
-        let fs_transparent = wgpu::include_spirv!("../shaders/compiled/transparent.frag.spv");
+        let fs_transparent = shader!("transparent.frag.spv");
         let fs_transparent_module = device.create_shader_module(fs_transparent);
 
         let identity_instance_buffer =
@@ -387,10 +489,9 @@ impl RenderContext {
         let shadow_uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Cheese shadow uniform buffer"),
             contents: bytemuck::bytes_of(&ShadowUniforms::new(
-                Vec2::one(),
-                Vec2::zero(),
-                Vec2::zero(),
-                Vec2::zero(),
+                Vec3::new(1.0, 0.0, 1.0),
+                [Vec2::zero(); 3],
+                SUN_DIRECTION,
             )),
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
         });
@@ -475,17 +576,17 @@ impl RenderContext {
         let bloombuffer = create_texture(
             &device,
             "Cheese bloombuffer texture",
-            window_size.width,
-            window_size.height,
-            DISPLAY_FORMAT,
+            target_width,
+            target_height,
+            SCENE_FORMAT,
             wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
         );
         let bloombuffer_after_vertical = create_texture(
             &device,
             "Cheese bloombuffer after vert texture",
-            window_size.width,
-            window_size.height,
-            DISPLAY_FORMAT,
+            target_width,
+            target_height,
+            SCENE_FORMAT,
             wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
         );
         let bloom_first_pass_bind_group = create_bloom_blur_pass(
@@ -505,43 +606,9 @@ impl RenderContext {
             &bloom_blur_hori_buffer,
         );
 
-        let fs_blur = wgpu::include_spirv!("../shaders/compiled/blur.frag.spv");
-        let fs_blur_module = device.create_shader_module(fs_blur);
-
-        let bloom_blur_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Cheese bloom blur pipeline layout"),
-                bind_group_layouts: &[&bloom_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-
-        let bloom_blur_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Cheese bloom blur pipeline"),
-            layout: Some(&bloom_blur_pipeline_layout),
-            vertex_stage: wgpu::ProgrammableStageDescriptor {
-                module: &vs_full_screen_quad_module,
-                entry_point: "main",
-            },
-            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
-                module: &fs_blur_module,
-                entry_point: "main",
-            }),
-            rasterization_state: Some(wgpu::RasterizationStateDescriptor::default()),
-            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
-            color_states: &[additive_colour_state_descriptor()],
-            depth_stencil_state: None,
-            vertex_state: wgpu::VertexStateDescriptor {
-                index_format: INDEX_FORMAT,
-                vertex_buffers: &[],
-            },
-            sample_count: 1,
-            sample_mask: !0,
-            alpha_to_coverage_enabled: false,
-        });
-
         // Darkening pipeline for menus on top of the game.
 
-        let fs_darken = wgpu::include_spirv!("../shaders/compiled/darken.frag.spv");
+        let fs_darken = shader!("darken.frag.spv");
         let fs_darken_module = device.create_shader_module(fs_darken);
 
         let darken_pipeline_layout =
@@ -582,22 +649,26 @@ impl RenderContext {
             format: DISPLAY_FORMAT,
             width: window_size.width,
             height: window_size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode: present_mode(settings.vsync),
         };
 
         let swap_chain = device.create_swap_chain(&surface, &swap_chain_desc);
 
         // Create the depth attachment
 
-        let depth_texture = create_texture(
+        let depth_texture = create_multisampled_texture(
             &device,
             "Cheese depth texture",
-            window_size.width,
-            window_size.height,
+            target_width,
+            target_height,
             DEPTH_FORMAT,
             wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            sample_count,
         );
 
+        let (msaa_framebuffer, msaa_bloombuffer) =
+            create_msaa_buffers(&device, target_width, target_height, sample_count);
+
         Ok(Self {
             swap_chain,
             window,
@@ -606,19 +677,32 @@ impl RenderContext {
             surface,
             swap_chain_desc,
             depth_texture,
+            sample_count,
+            render_scale,
+            msaa_framebuffer,
+            msaa_bloombuffer,
             perspective_buffer,
             view_buffer,
+            view,
+            sun_buffer,
+            sunlight: Sunlight::default(),
+            lighting_buffer,
+            #[cfg(feature = "material-maps")]
+            point_light_buffer,
             main_bind_group_layout,
             sampler,
             joint_bind_group_layout,
             main_bind_group: Arc::new(main_bind_group),
+            #[cfg(feature = "water-shader")]
+            reflection_view_buffer,
+            #[cfg(feature = "water-shader")]
+            reflection_bind_group: Arc::new(reflection_bind_group),
             fs_transparent_module,
             vs_transparent_module,
             framebuffer,
             framebuffer_bind_group,
             framebuffer_bind_group_layout,
             framebuffer_sampler,
-            post_processing_pipeline,
             screen_dimension_uniform_buffer,
             shadow_texture,
             identity_instance_buffer: Arc::new(identity_instance_buffer),
@@ -632,7 +716,6 @@ impl RenderContext {
             bloom_first_pass_bind_group,
             bloom_second_pass_bind_group,
             bloom_bind_group_layout,
-            bloom_blur_pipeline,
             darken_pipeline,
         })
     }
@@ -645,20 +728,163 @@ impl RenderContext {
         self.window.request_redraw();
     }
 
+    // This gets us a `WindowEvent::Resized`, which is where everything is actually resized.
+    pub fn set_window_size(&self, width: u32, height: u32) {
+        self.window
+            .set_inner_size(winit::dpi::LogicalSize::new(width, height));
+    }
+
+    pub fn set_fullscreen(&self, enabled: bool) {
+        self.window.set_fullscreen(fullscreen(enabled));
+    }
+
+    // In logical pixels, like `Settings::resolution`.
+    pub fn window_size(&self) -> [u32; 2] {
+        let size = self
+            .window
+            .inner_size()
+            .to_logical::<u32>(self.window.scale_factor());
+        [size.width, size.height]
+    }
+
+    pub fn set_vsync(&mut self, vsync: bool) {
+        self.swap_chain_desc.present_mode = present_mode(vsync);
+        self.swap_chain = self
+            .device
+            .create_swap_chain(&self.surface, &self.swap_chain_desc);
+    }
+
     pub fn resize(&mut self, width: u32, height: u32) {
+        // Minimising the window on windows resizes it to nothing, which the swap chain can't be.
+        // Everything just stays the size it was until the window comes back.
+        if width == 0 || height == 0 {
+            return;
+        }
+
         self.swap_chain_desc.width = width;
         self.swap_chain_desc.height = height;
         self.swap_chain = self
             .device
             .create_swap_chain(&self.surface, &self.swap_chain_desc);
-        self.depth_texture = create_texture(
+        self.create_render_targets();
+
+        self.queue.write_buffer(
+            &self.screen_dimension_uniform_buffer,
+            0,
+            bytemuck::bytes_of(&ScreenDimensionUniform::new(width, height)),
+        );
+
+        self.queue.write_buffer(
+            &self.perspective_buffer,
+            0,
+            bytemuck::bytes_of(&create_perspective_mat4(width, height)),
+        );
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    // The pipelines that draw in the main pass need to be recreated after this, as they're built
+    // for a particular sample count.
+    pub fn set_msaa(&mut self, msaa: u32) {
+        self.sample_count = valid_sample_count(msaa);
+        self.create_render_targets();
+    }
+
+    pub fn set_render_scale(&mut self, render_scale: f32) {
+        self.render_scale = valid_render_scale(render_scale);
+        self.create_render_targets();
+    }
+
+    // Where the main pass should draw the scene to, and what it needs resolving into, if anything.
+    pub fn framebuffer_attachment(&self) -> (&wgpu::TextureView, Option<&wgpu::TextureView>) {
+        match &self.msaa_framebuffer {
+            Some(msaa_framebuffer) => (msaa_framebuffer, Some(&self.framebuffer)),
+            None => (&self.framebuffer, None),
+        }
+    }
+
+    pub fn bloombuffer_attachment(&self) -> (&wgpu::TextureView, Option<&wgpu::TextureView>) {
+        match &self.msaa_bloombuffer {
+            Some(msaa_bloombuffer) => (msaa_bloombuffer, Some(&self.bloombuffer)),
+            None => (&self.bloombuffer, None),
+        }
+    }
+
+    // Starts a pass that draws into the framebuffer and bloom buffer, either clearing them or
+    // carrying on from the last pass. The stencil buffer is only used for outlines within a pass, so
+    // it's always cleared.
+    pub fn begin_scene_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        clear: bool,
+    ) -> wgpu::RenderPass<'a> {
+        let colour_ops = wgpu::Operations {
+            load: if clear {
+                wgpu::LoadOp::Clear(wgpu::Color::BLACK)
+            } else {
+                wgpu::LoadOp::Load
+            },
+            store: true,
+        };
+
+        let (framebuffer, framebuffer_resolve_target) = self.framebuffer_attachment();
+        let (bloombuffer, bloombuffer_resolve_target) = self.bloombuffer_attachment();
+
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: framebuffer,
+                    resolve_target: framebuffer_resolve_target,
+                    ops: colour_ops,
+                },
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: bloombuffer,
+                    resolve_target: bloombuffer_resolve_target,
+                    ops: colour_ops,
+                },
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.depth_texture,
+                depth_ops: Some(wgpu::Operations {
+                    load: if clear {
+                        wgpu::LoadOp::Clear(1.0)
+                    } else {
+                        wgpu::LoadOp::Load
+                    },
+                    store: true,
+                }),
+                stencil_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(0),
+                    store: false,
+                }),
+            }),
+        })
+    }
+
+    // (Re)create everything that the scene is rendered into, for the current window size, render
+    // scale and sample count.
+    fn create_render_targets(&mut self) {
+        let (width, height) = scaled_size(
+            self.swap_chain_desc.width,
+            self.swap_chain_desc.height,
+            self.render_scale,
+        );
+
+        self.depth_texture = create_multisampled_texture(
             &self.device,
             "Cheese depth texture",
             width,
             height,
             DEPTH_FORMAT,
             wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            self.sample_count,
         );
+        let (msaa_framebuffer, msaa_bloombuffer) =
+            create_msaa_buffers(&self.device, width, height, self.sample_count);
+        self.msaa_framebuffer = msaa_framebuffer;
+        self.msaa_bloombuffer = msaa_bloombuffer;
         let (framebuffer, framebuffer_bind_group) = create_framebuffer(
             &self.device,
             &self.framebuffer_bind_group_layout,
@@ -674,7 +900,7 @@ impl RenderContext {
             "Cheese bloombuffer texture",
             width,
             height,
-            DISPLAY_FORMAT,
+            SCENE_FORMAT,
             wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
         );
         self.bloombuffer_after_vertical = create_texture(
@@ -682,7 +908,7 @@ impl RenderContext {
             "Cheese bloombuffer after vert texture",
             width,
             height,
-            DISPLAY_FORMAT,
+            SCENE_FORMAT,
             wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
         );
         self.bloom_first_pass_bind_group = create_bloom_blur_pass(
@@ -701,27 +927,28 @@ impl RenderContext {
             &self.bloombuffer_after_vertical,
             &self.bloom_blur_hori_buffer,
         );
-
-        self.queue.write_buffer(
-            &self.screen_dimension_uniform_buffer,
-            0,
-            bytemuck::bytes_of(&ScreenDimensionUniform::new(width, height)),
-        );
-
-        self.queue.write_buffer(
-            &self.perspective_buffer,
-            0,
-            bytemuck::bytes_of(&create_perspective_mat4(width, height)),
-        );
     }
 
-    pub fn update_view(&self, view: Mat4) {
+    pub fn update_view(&mut self, view: Mat4) {
+        self.view = view;
         self.queue
             .write_buffer(&self.view_buffer, 0, bytemuck::bytes_of(&view));
     }
 
-    pub fn update_from_camera(&self, camera: &Camera) {
-        self.update_view(camera.to_matrix());
+    // The view matrix that was last uploaded.
+    pub fn view(&self) -> Mat4 {
+        self.view
+    }
+
+    pub fn update_from_camera(&mut self, camera: &Camera, shake: &CameraShake) {
+        self.update_view(shake.apply(camera.to_matrix()));
+
+        #[cfg(feature = "water-shader")]
+        self.queue.write_buffer(
+            &self.reflection_view_buffer,
+            0,
+            bytemuck::bytes_of(&water_pipeline::reflection_view(self.view())),
+        );
 
         let screen_dimensions = self.screen_dimensions();
         let top_left = camera.cast_ray(Vec2::new(0.0, 0.0), &screen_dimensions);
@@ -735,10 +962,48 @@ impl RenderContext {
             &self.shadow_uniform_buffer,
             0,
             bytemuck::bytes_of(&ShadowUniforms::new(
-                camera.looking_at,
-                top_left,
-                top_right,
-                bottom_right,
+                camera.looking_at_3(),
+                [top_left, top_right, bottom_right],
+                self.sunlight.direction,
+            )),
+        );
+    }
+
+    // This needs to happen before `update_from_camera`, so that the shadows line up with the sun.
+    pub fn set_sunlight(&mut self, sunlight: Sunlight) {
+        self.sunlight = sunlight;
+        self.queue
+            .write_buffer(&self.sun_buffer, 0, bytemuck::bytes_of(&sunlight.direction));
+    }
+
+    // Without the `material-maps` feature, nothing is lit by point lights so there aren't any.
+    pub fn update_lighting(&self, #[cfg(feature = "material-maps")] point_lights: &[PointLight]) {
+        let camera_position = self.view.inversed().cols[3].xyz();
+
+        #[cfg(feature = "material-maps")]
+        let num_point_lights = {
+            let point_lights = cull_point_lights(point_lights, camera_position);
+
+            if !point_lights.is_empty() {
+                self.queue.write_buffer(
+                    &self.point_light_buffer,
+                    0,
+                    bytemuck::cast_slice(&point_lights),
+                );
+            }
+
+            point_lights.len() as u32
+        };
+        #[cfg(not(feature = "material-maps"))]
+        let num_point_lights = 0;
+
+        self.queue.write_buffer(
+            &self.lighting_buffer,
+            0,
+            bytemuck::bytes_of(&LightingUniforms::new(
+                camera_position,
+                num_point_lights,
+                &self.sunlight,
             )),
         );
     }
@@ -760,6 +1025,14 @@ impl RenderContext {
     }
 }
 
+fn fullscreen(enabled: bool) -> Option<winit::window::Fullscreen> {
+    if enabled {
+        Some(winit::window::Fullscreen::Borderless(None))
+    } else {
+        None
+    }
+}
+
 pub fn create_perspective_mat4(window_width: u32, window_height: u32) -> Mat4 {
     ultraviolet::projection::perspective_wgpu_dx(
         45.0,
@@ -809,7 +1082,7 @@ fn create_framebuffer(
         "Cheese framebuffer texture",
         width,
         height,
-        DISPLAY_FORMAT,
+        SCENE_FORMAT,
         wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
     );
 
@@ -838,6 +1111,18 @@ fn create_texture(
     height: u32,
     format: wgpu::TextureFormat,
     usage: wgpu::TextureUsage,
+) -> wgpu::TextureView {
+    create_multisampled_texture(device, label, width, height, format, usage, 1)
+}
+
+fn create_multisampled_texture(
+    device: &wgpu::Device,
+    label: &str,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+    usage: wgpu::TextureUsage,
+    sample_count: u32,
 ) -> wgpu::TextureView {
     device
         .create_texture(&wgpu::TextureDescriptor {
@@ -848,7 +1133,7 @@ fn create_texture(
                 depth: 1,
             },
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format,
             usage,
@@ -856,10 +1141,65 @@ fn create_texture(
         .create_view(&wgpu::TextureViewDescriptor::default())
 }
 
+fn create_msaa_buffers(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+) -> (Option<wgpu::TextureView>, Option<wgpu::TextureView>) {
+    if sample_count == 1 {
+        return (None, None);
+    }
+
+    let create = |label| {
+        create_multisampled_texture(
+            device,
+            label,
+            width,
+            height,
+            SCENE_FORMAT,
+            wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+            sample_count,
+        )
+    };
+
+    (
+        Some(create("Cheese msaa framebuffer texture")),
+        Some(create("Cheese msaa bloombuffer texture")),
+    )
+}
+
+// These are the counts that every backend supports for our formats.
+fn valid_sample_count(msaa: u32) -> u32 {
+    match msaa {
+        2 | 4 | 8 => msaa,
+        _ => 1,
+    }
+}
+
+fn valid_render_scale(render_scale: f32) -> f32 {
+    render_scale.clamp(0.25, 2.0)
+}
+
+fn scaled_size(width: u32, height: u32, render_scale: f32) -> (u32, u32) {
+    (
+        ((width as f32 * render_scale).round() as u32).max(1),
+        ((height as f32 * render_scale).round() as u32).max(1),
+    )
+}
+
+fn present_mode(vsync: bool) -> wgpu::PresentMode {
+    if vsync {
+        wgpu::PresentMode::Fifo
+    } else {
+        wgpu::PresentMode::Immediate
+    }
+}
+
 fn colour_state_descriptor(alpha_blend: bool) -> wgpu::ColorStateDescriptor {
     if alpha_blend {
         wgpu::ColorStateDescriptor {
-            format: DISPLAY_FORMAT,
+            format: SCENE_FORMAT,
             color_blend: wgpu::BlendDescriptor {
                 src_factor: wgpu::BlendFactor::SrcAlpha,
                 dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
@@ -874,7 +1214,7 @@ fn colour_state_descriptor(alpha_blend: bool) -> wgpu::ColorStateDescriptor {
         }
     } else {
         wgpu::ColorStateDescriptor {
-            format: DISPLAY_FORMAT,
+            format: SCENE_FORMAT,
             color_blend: wgpu::BlendDescriptor::REPLACE,
             alpha_blend: wgpu::BlendDescriptor::REPLACE,
             write_mask: wgpu::ColorWrite::ALL,
@@ -884,7 +1224,7 @@ fn colour_state_descriptor(alpha_blend: bool) -> wgpu::ColorStateDescriptor {
 
 fn additive_colour_state_descriptor() -> wgpu::ColorStateDescriptor {
     wgpu::ColorStateDescriptor {
-        format: DISPLAY_FORMAT,
+        format: SCENE_FORMAT,
         write_mask: wgpu::ColorWrite::ALL,
         color_blend: wgpu::BlendDescriptor {
             operation: wgpu::BlendOperation::Add,
@@ -1027,88 +1367,40 @@ impl<T: bytemuck::Pod> DynamicBuffer<T> {
     pub fn len_waiting(&self) -> usize {
         self.waiting.len()
     }
-}
-
-pub struct TextBuffer {
-    pub glyph_brush: wgpu_glyph::GlyphBrush<(), wgpu_glyph::ab_glyph::FontRef<'static>>,
-}
-
-pub enum Font {
-    Ui = 0,
-    Title = 1,
-}
 
-impl Font {
-    pub fn scale(&self) -> f32 {
-        match self {
-            Self::Ui => 24.0,
-            Self::Title => 64.0,
+    // How much was in the buffer when it was last uploaded, and how big it is on the gpu.
+    pub fn stats(&self) -> crate::profiler::BufferStats {
+        crate::profiler::BufferStats {
+            label: self.label,
+            len: self.len,
+            size_in_bytes: (self.capacity * std::mem::size_of::<T>()) as u64,
         }
     }
-}
-
-pub enum TextAlignment {
-    Default,
-    Center,
-    HorizontalRight,
-    CenterLeft,
-}
-
-impl TextBuffer {
-    pub fn new(device: &wgpu::Device) -> anyhow::Result<Self> {
-        let fonts = vec![
-            wgpu_glyph::ab_glyph::FontRef::try_from_slice(include_bytes!(
-                "../fonts/Roboto_Mono/RobotoMono-Bold.ttf"
-            ))?,
-            wgpu_glyph::ab_glyph::FontRef::try_from_slice(include_bytes!(
-                "../fonts/Chewy/Chewy-Regular.ttf"
-            ))?,
-        ];
 
-        let glyph_brush =
-            wgpu_glyph::GlyphBrushBuilder::using_fonts(fonts).build(&device, DISPLAY_FORMAT);
+    // Make room for `len` items that get written on the gpu instead of being pushed. Returns
+    // whether the gpu buffer was resized.
+    #[cfg(feature = "gpu-skinning")]
+    fn reserve(&mut self, device: &wgpu::Device, len: usize) -> bool {
+        self.len = len;
 
-        Ok(Self { glyph_brush })
-    }
-
-    pub fn render_text(
-        &mut self,
-        screen_position: Vec2,
-        text: &str,
-        font: Font,
-        scale_multiplier: f32,
-        dpi_scaling: f32,
-        alignment: TextAlignment,
-        colour: Vec4,
-    ) {
-        let layout = match alignment {
-            TextAlignment::Default => wgpu_glyph::Layout::default(),
-            TextAlignment::Center => wgpu_glyph::Layout::default()
-                .h_align(wgpu_glyph::HorizontalAlign::Center)
-                .v_align(wgpu_glyph::VerticalAlign::Center),
-            TextAlignment::HorizontalRight => {
-                wgpu_glyph::Layout::default().h_align(wgpu_glyph::HorizontalAlign::Right)
-            }
-            TextAlignment::CenterLeft => {
-                wgpu_glyph::Layout::default().v_align(wgpu_glyph::VerticalAlign::Center)
-            }
-        };
+        if len <= self.capacity {
+            return false;
+        }
 
-        let scale = font.scale();
-        let id = font as usize;
-        let colour: [f32; 4] = colour.into();
-
-        self.glyph_brush.queue(
-            wgpu_glyph::Section::new()
-                .with_screen_position((screen_position.x, screen_position.y))
-                .with_layout(layout)
-                .add_text(
-                    wgpu_glyph::Text::new(text)
-                        .with_color(colour)
-                        .with_font_id(wgpu_glyph::FontId(id))
-                        .with_scale(scale * scale_multiplier * dpi_scaling),
-                ),
+        self.capacity = (self.capacity * 2).max(len);
+        log::debug!(
+            "Resizing '{}' to {} items to fit {} items",
+            self.label,
+            self.capacity,
+            self.len
         );
+        self.buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(self.label),
+            size: (self.capacity * std::mem::size_of::<T>()) as u64,
+            usage: self.usage | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        true
     }
 }
 
@@ -1132,6 +1424,9 @@ pub struct Vertex {
     pub position: Vec3,
     pub normal: Vec3,
     pub uv: Vec2,
+    // Which way the uvs go across the surface, for normal mapping. The w is either 1 or -1 depending
+    // on which way the bitangent (`normal.cross(tangent)`) goes.
+    pub tangent: Vec4,
 }
 
 #[repr(C)]
@@ -1142,6 +1437,7 @@ pub struct AnimatedVertex {
     pub uv: Vec2,
     pub joints: Vec4,
     pub joint_weights: Vec4,
+    pub tangent: Vec4,
 }
 
 #[repr(C)]
@@ -1151,32 +1447,28 @@ struct ShadowUniforms {
 }
 
 impl ShadowUniforms {
-    fn new(look_at: Vec2, top_left: Vec2, top_right: Vec2, bottom_right: Vec2) -> Self {
-        // Use the corner points of the camera view to figure out good corners for the projection
-        // matrix.
-        let top_left = top_left - look_at;
-        let top_right = top_right - look_at;
-        let bottom_right = bottom_right - look_at;
+    fn new(look_at: Vec3, corners: [Vec2; 3], sun_direction: Vec3) -> Self {
+        // Use the corner points of the camera view to figure out good bounds for the projection
+        // matrix. The camera can be rotated, so just make the bounds big enough to fit the furthest
+        // corner in any direction.
+        let look_at_2d = Vec2::new(look_at.x, look_at.z);
+        let radius = corners
+            .iter()
+            .map(|corner| (*corner - look_at_2d).mag())
+            .fold(0.0, f32::max);
         // multiply the sun direction by a 10 so that we can view shadows from a greater distance.
         // todo: this is hacky and doesn't produce great looking shadows on the pumps. It's probably
         // a better solution to lower this down and not show shadows from above a certain height.
-        let sun_direction_multiplied = SUN_DIRECTION * 10.0;
+        let sun_direction_multiplied = sun_direction * 10.0;
 
         // Using the camera distance from the ground is just a guesstimate that seems to work well here.
         let near_plane = 0.1;
         let far_plane = (sun_direction_multiplied * 1.5).mag();
 
         let projection = ultraviolet::projection::orthographic_wgpu_dx(
-            -bottom_right.y,
-            -top_left.y,
-            -top_right.x,
-            -top_left.x,
-            near_plane,
-            far_plane,
+            -radius, radius, -radius, radius, near_plane, far_plane,
         );
 
-        let look_at = Vec3::new(look_at.x, 0.0, look_at.y);
-
         let view = Mat4::look_at(sun_direction_multiplied + look_at, look_at, Vec3::unit_y());
 
         Self {
@@ -1185,6 +1477,129 @@ impl ShadowUniforms {
     }
 }
 
+#[cfg(feature = "material-maps")]
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy, Debug, PartialEq)]
+pub struct PointLight {
+    pub position: Vec3,
+    // How far away from the light things stop being lit by it.
+    pub radius: f32,
+    pub colour: Vec4,
+}
+
+// Laid out the same as the `Lighting` block in shaders/lighting.glsl.
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy)]
+struct LightingUniforms {
+    sun_colour: Vec4,
+    ambient: Vec4,
+    // For the specular highlights.
+    camera_position: Vec4,
+    num_point_lights: u32,
+    // How much the emissive maps that only glow at night are lit up.
+    night_glow: f32,
+    padding: [u32; 2],
+}
+
+impl LightingUniforms {
+    fn new(camera_position: Vec3, num_point_lights: u32, sunlight: &Sunlight) -> Self {
+        Self {
+            sun_colour: Vec4::from(sunlight.colour),
+            ambient: Vec4::from(sunlight.ambient),
+            camera_position: Vec4::from(camera_position),
+            num_point_lights,
+            night_glow: sunlight.night_glow,
+            padding: [0; 2],
+        }
+    }
+}
+
+// The light that comes from the sky. During the night, the moon takes over from the sun.
+//
+// The colours and the night glow only make a difference with the `material-maps` feature. The
+// default shaders just use the direction, so without it only the shadows change over the day.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sunlight {
+    pub direction: Vec3,
+    pub colour: Vec3,
+    pub ambient: Vec3,
+    pub night_glow: f32,
+}
+
+impl Default for Sunlight {
+    fn default() -> Self {
+        Self {
+            direction: SUN_DIRECTION,
+            colour: SUN_COLOUR,
+            ambient: AMBIENT_COLOUR,
+            night_glow: 0.0,
+        }
+    }
+}
+
+impl Sunlight {
+    // Low enough for long evening shadows, but not so low that the shadow map stops covering the
+    // screen.
+    const MIN_ELEVATION: f32 = 0.35;
+
+    pub fn at(time_of_day: &TimeOfDay) -> Self {
+        let time = match time_of_day.0 {
+            Some(time) => time,
+            None => return Self::default(),
+        };
+
+        let angle = (time - 0.25) * std::f32::consts::TAU;
+        let height = time_of_day.sun_height();
+        let darkness = time_of_day.darkness();
+
+        // The sun rises in the east and the moon follows it round, so whichever one is up shines
+        // from the same side of the sky.
+        let across = if height >= 0.0 {
+            angle.cos()
+        } else {
+            -angle.cos()
+        };
+        let direction = Vec3::new(across, height.abs().max(Self::MIN_ELEVATION), 0.25).normalized()
+            * SUN_DIRECTION.mag();
+
+        let colour = if height >= 0.0 {
+            let sunset = 1.0 - (height / 0.4).min(1.0);
+            SUN_COLOUR + (DUSK_COLOUR - SUN_COLOUR) * sunset
+        } else {
+            MOON_COLOUR
+        };
+        // Fade out around the horizon, so the switch between the sun and the moon isn't noticeable.
+        let strength = (height.abs() / 0.15).min(1.0);
+
+        Self {
+            direction,
+            colour: colour * strength,
+            ambient: AMBIENT_COLOUR + (NIGHT_AMBIENT_COLOUR - AMBIENT_COLOUR) * darkness,
+            night_glow: darkness,
+        }
+    }
+}
+
+// Throws away the lights that are too far away to see, and then the furthest ones if there are still
+// too many.
+#[cfg(feature = "material-maps")]
+fn cull_point_lights(point_lights: &[PointLight], camera_position: Vec3) -> Vec<PointLight> {
+    let distance = |light: &PointLight| (light.position - camera_position).mag() - light.radius;
+
+    let mut point_lights: Vec<PointLight> = point_lights
+        .iter()
+        .filter(|light| distance(light) < LIGHT_CULL_DISTANCE)
+        .copied()
+        .collect();
+
+    if point_lights.len() > MAX_POINT_LIGHTS {
+        point_lights.sort_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap());
+        point_lights.truncate(MAX_POINT_LIGHTS);
+    }
+
+    point_lights
+}
+
 #[repr(C)]
 #[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy)]
 struct BloomBlurSettings {
@@ -1192,3 +1607,21 @@ struct BloomBlurSettings {
     blur_strength: f32,
     blur_direction: i32,
 }
+
+#[cfg(feature = "material-maps")]
+#[test]
+fn far_away_lights_are_culled() {
+    let light = |x: f32| PointLight {
+        position: Vec3::new(x, 0.0, 0.0),
+        radius: 5.0,
+        colour: Vec4::one(),
+    };
+
+    let lights: Vec<PointLight> = (0..MAX_POINT_LIGHTS * 2)
+        .map(|i| light(i as f32))
+        .chain(std::iter::once(light(LIGHT_CULL_DISTANCE + 10.0)))
+        .collect();
+
+    let culled = cull_point_lights(&lights, Vec3::zero());
+    assert_eq!(culled, &lights[..MAX_POINT_LIGHTS]);
+}
@@ -0,0 +1,154 @@
+// Picking a gpu to draw with. Whichever backend is set in `settings.toml` is tried first, and if it
+// doesn't work out (the drivers aren't installed, or it doesn't exist on this platform) we fall back
+// to all the others. For each backend, a discrete gpu is preferred over an integrated one unless
+// `low_power_gpu` is set, but anything that works is better than nothing.
+
+use crate::resources::{GraphicsBackend, Settings};
+use winit::window::Window;
+
+pub struct ChosenAdapter {
+    pub surface: wgpu::Surface,
+    pub device: wgpu::Device,
+    pub queue: wgpu::Queue,
+}
+
+// In the order to try them.
+fn backends_to_try(backend: GraphicsBackend) -> Vec<wgpu::BackendBit> {
+    let chosen = match backend {
+        GraphicsBackend::Auto => {
+            return vec![wgpu::BackendBit::PRIMARY, wgpu::BackendBit::SECONDARY]
+        }
+        GraphicsBackend::Vulkan => wgpu::BackendBit::VULKAN,
+        GraphicsBackend::Dx12 => wgpu::BackendBit::DX12,
+        GraphicsBackend::Dx11 => wgpu::BackendBit::DX11,
+        GraphicsBackend::Metal => wgpu::BackendBit::METAL,
+        GraphicsBackend::Gl => wgpu::BackendBit::GL,
+    };
+
+    let others = (wgpu::BackendBit::PRIMARY | wgpu::BackendBit::SECONDARY) - chosen;
+
+    vec![
+        chosen,
+        others & wgpu::BackendBit::PRIMARY,
+        others & wgpu::BackendBit::SECONDARY,
+    ]
+}
+
+fn power_preferences(low_power: bool) -> [wgpu::PowerPreference; 2] {
+    if low_power {
+        [
+            wgpu::PowerPreference::LowPower,
+            wgpu::PowerPreference::HighPerformance,
+        ]
+    } else {
+        [
+            wgpu::PowerPreference::HighPerformance,
+            wgpu::PowerPreference::LowPower,
+        ]
+    }
+}
+
+pub async fn choose(window: &Window, settings: &Settings) -> anyhow::Result<ChosenAdapter> {
+    // For the error message if nothing works.
+    let mut problems = Vec::new();
+
+    for backends in backends_to_try(settings.backend) {
+        // Surfaces belong to the instance, so each set of backends needs its own. The device keeps
+        // the instance alive after this.
+        let instance = wgpu::Instance::new(backends);
+        let surface = unsafe { instance.create_surface(window) };
+
+        for &power_preference in &power_preferences(settings.low_power_gpu) {
+            let adapter = match instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference,
+                    compatible_surface: Some(&surface),
+                })
+                .await
+            {
+                Some(adapter) => adapter,
+                None => continue,
+            };
+
+            let info = adapter.get_info();
+
+            let device = adapter
+                .request_device(
+                    &wgpu::DeviceDescriptor {
+                        features: wgpu::Features::empty(),
+                        limits: wgpu::Limits::default(),
+                        shader_validation: true,
+                    },
+                    None,
+                )
+                .await;
+
+            match device {
+                Ok((device, queue)) => {
+                    log::info!(
+                        "Drawing with {} ({:?}, {:?})",
+                        info.name,
+                        info.backend,
+                        info.device_type
+                    );
+
+                    if backends != backends_to_try(settings.backend)[0] {
+                        log::warn!(
+                            "Couldn't use {:?} (from `backend` in the settings), fell back to {:?}",
+                            settings.backend,
+                            info.backend
+                        );
+                    }
+
+                    return Ok(ChosenAdapter {
+                        surface,
+                        device,
+                        queue,
+                    });
+                }
+                Err(error) => problems.push(format!(
+                    "{} ({:?}) failed to start: {}",
+                    info.name, info.backend, error
+                )),
+            }
+        }
+
+        problems.push(format!("No usable gpu was found with {:?}", backends));
+    }
+
+    Err(anyhow::anyhow!(
+        "Couldn't find a graphics card to draw the game with.\n\n\
+        {}\n\n\
+        If you're on linux, try installing the vulkan drivers for your gpu. You can check that \
+        they're working properly by running `vulkaninfo` or `vkcube`. On other platforms, try \
+        updating your graphics drivers. Setting `backend` in `settings.toml` to a different one \
+        (\"vulkan\", \"dx12\", \"dx11\", \"metal\" or \"gl\") might help as well.",
+        problems.join("\n")
+    ))
+}
+
+#[test]
+fn the_chosen_backend_is_tried_first_then_the_rest() {
+    let all = wgpu::BackendBit::PRIMARY | wgpu::BackendBit::SECONDARY;
+
+    for &backend in &[
+        GraphicsBackend::Auto,
+        GraphicsBackend::Vulkan,
+        GraphicsBackend::Gl,
+    ] {
+        let backends = backends_to_try(backend);
+
+        // Nothing gets tried twice, and everything gets tried eventually.
+        let mut tried = wgpu::BackendBit::empty();
+        for &backends in &backends {
+            assert!(!tried.intersects(backends));
+            tried |= backends;
+        }
+        assert_eq!(tried, all);
+    }
+
+    assert_eq!(
+        backends_to_try(GraphicsBackend::Vulkan)[0],
+        wgpu::BackendBit::VULKAN
+    );
+}
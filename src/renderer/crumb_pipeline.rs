@@ -0,0 +1,188 @@
+use super::{colour_state_descriptor, RenderContext, DEPTH_FORMAT, INDEX_FORMAT};
+use crate::crumbs::CrumbInstance;
+use crate::hot_reload::shader;
+use std::sync::Arc;
+use wgpu::util::DeviceExt;
+
+// The crumbs are all drawn in one go, as billboards built out of `gl_VertexIndex` in the vertex
+// shader, so there's no vertex buffer, just the instances. See `crumbs`.
+
+pub struct CrumbPipeline {
+    pipeline: wgpu::RenderPipeline,
+    main_bind_group: Arc<wgpu::BindGroup>,
+    bind_group: wgpu::BindGroup,
+    time_buffer: wgpu::Buffer,
+    instances: wgpu::Buffer,
+    num_instances: u32,
+}
+
+impl CrumbPipeline {
+    pub fn new(context: &RenderContext, crumbs: &[CrumbInstance]) -> Self {
+        let device = &context.device;
+
+        let vs_module = device.create_shader_module(shader!("crumbs.vert.spv"));
+        let fs_module = device.create_shader_module(shader!("crumbs.frag.spv"));
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Cheese crumb bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        multisampled: false,
+                        dimension: wgpu::TextureViewDimension::D2,
+                        component_type: wgpu::TextureComponentType::Float,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Cheese crumb pipeline layout"),
+            bind_group_layouts: &[&context.main_bind_group_layout, &bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Cheese crumb pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex_stage: wgpu::ProgrammableStageDescriptor {
+                module: &vs_module,
+                entry_point: "main",
+            },
+            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                module: &fs_module,
+                entry_point: "main",
+            }),
+            // The billboards always face the camera anyway.
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                cull_mode: wgpu::CullMode::None,
+                ..Default::default()
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            // Crumbs don't glow.
+            color_states: &[
+                colour_state_descriptor(false),
+                wgpu::ColorStateDescriptor {
+                    write_mask: wgpu::ColorWrite::empty(),
+                    ..colour_state_descriptor(false)
+                },
+            ],
+            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilStateDescriptor::default(),
+            }),
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: INDEX_FORMAT,
+                vertex_buffers: &[wgpu::VertexBufferDescriptor {
+                    stride: std::mem::size_of::<CrumbInstance>() as u64,
+                    step_mode: wgpu::InputStepMode::Instance,
+                    attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float, 2 => Float, 3 => Float, 4 => Float, 5 => Float],
+                }],
+            },
+            sample_count: context.sample_count(),
+            sample_mask: !0,
+            alpha_to_coverage_enabled: false,
+        });
+
+        let texture = crate::crumbs::texture();
+        let texture_extent = wgpu::Extent3d {
+            width: texture.width(),
+            height: texture.height(),
+            depth: 1,
+        };
+        let texture_handle = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Cheese crumb texture"),
+            size: texture_extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+        context.queue.write_texture(
+            wgpu::TextureCopyView {
+                texture: &texture_handle,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &texture,
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 4 * texture.width(),
+                rows_per_image: 0,
+            },
+            texture_extent,
+        );
+        let texture = texture_handle.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let time_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese crumb time buffer"),
+            contents: bytemuck::bytes_of(&0.0_f32),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Cheese crumb bind group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(time_buffer.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&texture),
+                },
+            ],
+        });
+
+        // Scattered once and never changed, like the scenery.
+        let instances = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese crumb instances"),
+            contents: bytemuck::cast_slice(crumbs),
+            usage: wgpu::BufferUsage::VERTEX,
+        });
+
+        Self {
+            pipeline,
+            main_bind_group: context.main_bind_group.clone(),
+            bind_group,
+            time_buffer,
+            instances,
+            num_instances: crumbs.len() as u32,
+        }
+    }
+
+    // Used to blow the crumbs around.
+    pub fn update_time(&self, context: &RenderContext, time: f32) {
+        context
+            .queue
+            .write_buffer(&self.time_buffer, 0, bytemuck::bytes_of(&time));
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if self.num_instances == 0 {
+            return;
+        }
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.instances.slice(..));
+        render_pass.draw(0..6, 0..self.num_instances);
+    }
+}
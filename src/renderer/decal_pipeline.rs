@@ -0,0 +1,323 @@
+use super::{
+    colour_state_descriptor, DynamicBuffer, ModelInstance, RenderContext, Vertex, DEPTH_FORMAT,
+    INDEX_FORMAT,
+};
+use crate::assets::{Assets, Model};
+use crate::hot_reload::shader;
+use std::ops::Range;
+use std::sync::Arc;
+use ultraviolet::{Vec2, Vec3, Vec4};
+use wgpu::util::DeviceExt;
+
+// Decals are flat quads laid on the ground, textured with one cell of a 2x2 atlas that's drawn on
+// the cpu in `decal_atlas`. They're all drawn with a single instance buffer, sorted by kind.
+
+const ATLAS_CELL_SIZE: u32 = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum DecalKind {
+    SelectionRing,
+    RallyPoint,
+    AttackFlare,
+    Scorch,
+}
+
+impl DecalKind {
+    const ALL: [Self; 4] = [
+        Self::SelectionRing,
+        Self::RallyPoint,
+        Self::AttackFlare,
+        Self::Scorch,
+    ];
+
+    // The top left corner of the cell in the atlas, in cells.
+    fn atlas_cell(self) -> (u32, u32) {
+        match self {
+            Self::SelectionRing => (0, 0),
+            Self::RallyPoint => (1, 0),
+            Self::AttackFlare => (0, 1),
+            Self::Scorch => (1, 1),
+        }
+    }
+
+    // The colour of a texel in the cell, where `point` goes from -1 to 1 across it. Everything but
+    // the scorch marks is white so it can be tinted with the instance colour.
+    fn texel(self, point: Vec2) -> [u8; 4] {
+        let distance = point.mag();
+        // Antialiased band between two distances from the centre.
+        let band = |inner: f32, outer: f32| {
+            let softness = 2.0 / ATLAS_CELL_SIZE as f32;
+            smoothstep(inner - softness, inner + softness, distance)
+                * (1.0 - smoothstep(outer - softness, outer + softness, distance))
+        };
+
+        let (colour, alpha) = match self {
+            Self::SelectionRing => (1.0, band(0.78, 0.92)),
+            Self::RallyPoint => (1.0, band(-1.0, 0.25).max(band(0.55, 0.7))),
+            Self::AttackFlare => {
+                let spikes = (1.0 - point.x.abs().min(point.y.abs()) * 8.0).max(0.0);
+                let glow = (1.0 - distance * 2.5).max(0.0);
+                (1.0, (spikes * (0.9 - distance).max(0.0) + glow).min(1.0))
+            }
+            Self::Scorch => {
+                // Wobble the edge so that they aren't perfect circles.
+                let angle = point.y.atan2(point.x);
+                let edge = 0.75 + 0.08 * (angle * 5.0).sin() + 0.05 * (angle * 11.0 + 1.0).sin();
+                let falloff = 1.0 - smoothstep(edge - 0.3, edge, distance);
+                (0.06, falloff * 0.85)
+            }
+        };
+
+        let to_byte = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        [
+            to_byte(colour),
+            to_byte(colour * 0.9),
+            to_byte(colour * 0.8),
+            to_byte(alpha),
+        ]
+    }
+}
+
+fn smoothstep(edge_a: f32, edge_b: f32, x: f32) -> f32 {
+    let t = ((x - edge_a) / (edge_b - edge_a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+pub fn decal_atlas() -> image::RgbaImage {
+    let size = ATLAS_CELL_SIZE * 2;
+
+    image::RgbaImage::from_fn(size, size, |x, y| {
+        let (cell_x, cell_y) = (x / ATLAS_CELL_SIZE, y / ATLAS_CELL_SIZE);
+        let kind = DecalKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.atlas_cell() == (cell_x, cell_y))
+            .expect("Every cell in the atlas is used");
+
+        let to_cell = |pixel: u32| {
+            ((pixel % ATLAS_CELL_SIZE) as f32 + 0.5) / ATLAS_CELL_SIZE as f32 * 2.0 - 1.0
+        };
+
+        image::Rgba(kind.texel(Vec2::new(to_cell(x), to_cell(y))))
+    })
+}
+
+pub struct DecalBuffer {
+    decals: Vec<(DecalKind, ModelInstance)>,
+    instances: DynamicBuffer<ModelInstance>,
+    // Where each kind is in `instances`.
+    ranges: Vec<(DecalKind, Range<u32>)>,
+}
+
+impl DecalBuffer {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            decals: Vec::new(),
+            instances: DynamicBuffer::new(
+                device,
+                100,
+                "Cheese decal buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            ranges: Vec::new(),
+        }
+    }
+
+    // The instance transform should lie the unit quad down where the decal goes.
+    pub fn push(&mut self, kind: DecalKind, instance: ModelInstance) {
+        self.decals.push((kind, instance));
+    }
+
+    pub fn stats(&self) -> crate::profiler::BufferStats {
+        self.instances.stats()
+    }
+
+    pub fn upload(&mut self, context: &RenderContext) {
+        // This is stable, so decals of the same kind are drawn in the order they were pushed.
+        self.decals.sort_by_key(|(kind, _)| *kind);
+        self.ranges.clear();
+
+        for (i, (kind, instance)) in self.decals.drain(..).enumerate() {
+            let i = i as u32;
+            match self.ranges.last_mut() {
+                Some((last_kind, range)) if *last_kind == kind => range.end = i + 1,
+                _ => self.ranges.push((kind, i..i + 1)),
+            }
+            self.instances.push(instance);
+        }
+
+        self.instances.upload(context);
+    }
+}
+
+pub struct DecalPipeline {
+    pipeline: wgpu::RenderPipeline,
+    main_bind_group: Arc<wgpu::BindGroup>,
+    // One quad for each kind, with the uvs for its cell of the atlas.
+    quads: Vec<Model>,
+}
+
+impl DecalPipeline {
+    pub fn new(context: &RenderContext, assets: &Assets) -> Self {
+        let fs = shader!("transparent_textured.frag.spv");
+        let fs_module = context.device.create_shader_module(fs);
+
+        let pipeline_layout =
+            context
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Cheese decal pipeline layout"),
+                    bind_group_layouts: &[
+                        &context.main_bind_group_layout,
+                        &assets.material_bind_group_layout,
+                    ],
+                    push_constant_ranges: &[],
+                });
+
+        let pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Cheese decal pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex_stage: wgpu::ProgrammableStageDescriptor {
+                module: &context.vs_transparent_module,
+                entry_point: "main",
+            },
+            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                module: &fs_module,
+                entry_point: "main",
+            }),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor::default()),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[
+                colour_state_descriptor(true),
+                // Decals shouldn't glow.
+                wgpu::ColorStateDescriptor {
+                    write_mask: wgpu::ColorWrite::empty(),
+                    ..colour_state_descriptor(false)
+                },
+            ],
+            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilStateDescriptor::default(),
+            }),
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: INDEX_FORMAT,
+                vertex_buffers: &[
+                    wgpu::VertexBufferDescriptor {
+                        stride: std::mem::size_of::<Vertex>() as u64,
+                        step_mode: wgpu::InputStepMode::Vertex,
+                        attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float3, 2 => Float2],
+                    },
+                    wgpu::VertexBufferDescriptor {
+                        stride: std::mem::size_of::<ModelInstance>() as u64,
+                        step_mode: wgpu::InputStepMode::Instance,
+                        attributes: &wgpu::vertex_attr_array![3 => Float4, 4 => Float4, 5 => Float4, 6 => Float4, 7 => Float4],
+                    },
+                ],
+            },
+            sample_count: context.sample_count(),
+            sample_mask: !0,
+            alpha_to_coverage_enabled: false,
+        });
+
+        Self {
+            pipeline,
+            main_bind_group: context.main_bind_group.clone(),
+            quads: DecalKind::ALL
+                .iter()
+                .map(|kind| create_quad(&context.device, *kind))
+                .collect(),
+        }
+    }
+
+    pub fn render<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        decal_buffer: &'a DecalBuffer,
+        assets: &'a Assets,
+    ) {
+        if let Some((slice, _)) = decal_buffer.instances.get() {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+            render_pass.set_bind_group(1, &assets.decals_texture, &[]);
+            render_pass.set_vertex_buffer(1, slice);
+
+            for (kind, range) in &decal_buffer.ranges {
+                let quad = &self.quads[*kind as usize];
+                render_pass.set_vertex_buffer(0, quad.vertices.slice(..));
+                render_pass.set_index_buffer(quad.indices.slice(..));
+                render_pass.draw_indexed(0..quad.num_indices, 0, range.clone());
+            }
+        }
+    }
+}
+
+// A 1x1 quad on the xz plane, centered on the origin.
+fn create_quad(device: &wgpu::Device, kind: DecalKind) -> Model {
+    let (cell_x, cell_y) = kind.atlas_cell();
+    let uv = |x: f32, y: f32| Vec2::new((cell_x as f32 + x) / 2.0, (cell_y as f32 + y) / 2.0);
+
+    let vertex = |x: f32, z: f32| Vertex {
+        position: Vec3::new(x - 0.5, 0.0, z - 0.5),
+        normal: Vec3::unit_y(),
+        uv: uv(x, z),
+        // The uvs go along x and z, and z is the opposite way to the bitangent of a tangent along x.
+        tangent: Vec4::new(1.0, 0.0, 0.0, -1.0),
+    };
+
+    let vertices = [
+        vertex(0.0, 0.0),
+        vertex(1.0, 0.0),
+        vertex(1.0, 1.0),
+        vertex(0.0, 1.0),
+    ];
+    let indices: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+    Model {
+        vertices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese decal quad vertices"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        }),
+        indices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese decal quad indices"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsage::INDEX,
+        }),
+        num_indices: indices.len() as u32,
+        lods: Vec::new(),
+    }
+}
+
+#[test]
+fn decals_dont_bleed_into_each_other_in_the_atlas() {
+    let atlas = decal_atlas();
+
+    for kind in DecalKind::ALL.iter() {
+        let (cell_x, cell_y) = kind.atlas_cell();
+        let (left, top) = (cell_x * ATLAS_CELL_SIZE, cell_y * ATLAS_CELL_SIZE);
+        let right = left + ATLAS_CELL_SIZE - 1;
+        let bottom = top + ATLAS_CELL_SIZE - 1;
+
+        for i in 0..ATLAS_CELL_SIZE {
+            for (x, y) in [
+                (left + i, top),
+                (left + i, bottom),
+                (left, top + i),
+                (right, top + i),
+            ]
+            .iter()
+            {
+                assert_eq!(
+                    atlas.get_pixel(*x, *y).0[3],
+                    0,
+                    "{:?} at {}, {}",
+                    kind,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+}
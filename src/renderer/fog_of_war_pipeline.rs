@@ -0,0 +1,139 @@
+use super::{colour_state_descriptor, DynamicBuffer, RenderContext, DEPTH_FORMAT, INDEX_FORMAT};
+use crate::hot_reload::shader;
+use std::sync::Arc;
+use ultraviolet::{Vec2, Vec3, Vec4};
+
+// Fog of war is drawn as a bunch of flat, transparent black quads hovering just above the ground.
+// The vertex format is the same as the 3d lines, so we can get away with reusing those shaders.
+
+pub struct FogOfWarPipeline {
+    pipeline: wgpu::RenderPipeline,
+    main_bind_group: Arc<wgpu::BindGroup>,
+}
+
+impl FogOfWarPipeline {
+    pub fn new(context: &RenderContext) -> Self {
+        let pipeline_layout =
+            context
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Cheese fog of war pipeline layout"),
+                    bind_group_layouts: &[&context.main_bind_group_layout],
+                    push_constant_ranges: &[],
+                });
+
+        let vs = shader!("lines_3d.vert.spv");
+        let vs_module = context.device.create_shader_module(vs);
+
+        let pipeline = context
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Cheese fog of war pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &vs_module,
+                    entry_point: "main",
+                },
+                fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                    module: &context.fs_transparent_module,
+                    entry_point: "main",
+                }),
+                rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                    cull_mode: wgpu::CullMode::None,
+                    ..Default::default()
+                }),
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                color_states: &[
+                    colour_state_descriptor(true),
+                    colour_state_descriptor(false),
+                ],
+                // Don't write to the depth buffer so that the fog doesn't hide any transparent
+                // things drawn after it.
+                depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                    format: DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: wgpu::StencilStateDescriptor::default(),
+                }),
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format: INDEX_FORMAT,
+                    vertex_buffers: &[wgpu::VertexBufferDescriptor {
+                        stride: std::mem::size_of::<FogOfWarVertex>() as u64,
+                        step_mode: wgpu::InputStepMode::Vertex,
+                        attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float4],
+                    }],
+                },
+                sample_count: context.sample_count(),
+                sample_mask: !0,
+                alpha_to_coverage_enabled: false,
+            });
+
+        Self {
+            pipeline,
+            main_bind_group: context.main_bind_group.clone(),
+        }
+    }
+
+    pub fn render<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        fog_of_war_buffer: &'a FogOfWarBuffer,
+    ) {
+        if let Some((slice, num)) = fog_of_war_buffer.vertices.get() {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, slice);
+            render_pass.draw(0..num, 0..1);
+        }
+    }
+}
+
+pub struct FogOfWarBuffer {
+    vertices: DynamicBuffer<FogOfWarVertex>,
+}
+
+impl FogOfWarBuffer {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            vertices: DynamicBuffer::new(
+                device,
+                600,
+                "Cheese fog of war buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+        }
+    }
+
+    pub fn upload(&mut self, context: &RenderContext) {
+        self.vertices.upload(context);
+    }
+
+    pub fn draw_rect(
+        &mut self,
+        top_left: Vec2,
+        bottom_right: Vec2,
+        height: impl Fn(Vec2) -> f32,
+        colour: Vec4,
+    ) {
+        let vertex = |x: f32, z: f32| FogOfWarVertex {
+            position: Vec3::new(x, height(Vec2::new(x, z)), z),
+            colour,
+        };
+
+        let a = vertex(top_left.x, top_left.y);
+        let b = vertex(bottom_right.x, top_left.y);
+        let c = vertex(top_left.x, bottom_right.y);
+        let d = vertex(bottom_right.x, bottom_right.y);
+
+        for vertex in [a, b, c, b, d, c].iter() {
+            self.vertices.push(*vertex);
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy)]
+struct FogOfWarVertex {
+    position: Vec3,
+    colour: Vec4,
+}
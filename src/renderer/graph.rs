@@ -0,0 +1,143 @@
+// A small render graph. Each pass has a name and says which resources it reads from and writes to,
+// and gets recorded by a closure. Passes can be added in whatever order is convenient (or skipped
+// entirely) and the graph works out an order where everything that writes to a resource comes before
+// anything that only reads from it. Passes that write to the same resource stay in the order they
+// were added, so e.g. the bloom blur still happens after everything has been drawn into the bloom
+// buffer.
+//
+// The closures are given a context to record into, which is the command encoder in the game, but it
+// can be anything so that the ordering can be tested without a gpu.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphResource {
+    // The skinned vertices, with the `gpu-skinning` feature.
+    Joints,
+    ShadowMap,
+    Reflections,
+    // The framebuffer (and the depth buffer that goes with it) that the 3D scene is drawn into.
+    Scene,
+    Bloom,
+    // Either the swap chain or where a screenshot is being saved from.
+    Output,
+}
+
+struct Pass<'a, C> {
+    name: &'static str,
+    reads: Vec<GraphResource>,
+    writes: Vec<GraphResource>,
+    record: Box<dyn FnOnce(&mut C) + 'a>,
+}
+
+pub struct RenderGraph<'a, C> {
+    passes: Vec<Pass<'a, C>>,
+}
+
+impl<'a, C> RenderGraph<'a, C> {
+    pub fn new() -> Self {
+        Self { passes: Vec::new() }
+    }
+
+    pub fn add_pass(
+        &mut self,
+        name: &'static str,
+        reads: &[GraphResource],
+        writes: &[GraphResource],
+        record: impl FnOnce(&mut C) + 'a,
+    ) {
+        self.passes.push(Pass {
+            name,
+            reads: reads.to_vec(),
+            writes: writes.to_vec(),
+            record: Box::new(record),
+        });
+    }
+
+    // Records all the passes, calling `before_pass` with the name of each one first (for timing them).
+    pub fn execute(self, context: &mut C, mut before_pass: impl FnMut(&'static str)) {
+        let order = self.order();
+        let mut passes: Vec<_> = self.passes.into_iter().map(Some).collect();
+
+        for i in order {
+            let pass = passes[i].take().unwrap();
+            before_pass(pass.name);
+            (pass.record)(context);
+        }
+    }
+
+    // Which passes have to come before each one.
+    fn dependencies(&self, pass: usize) -> impl Iterator<Item = usize> + '_ {
+        let this = &self.passes[pass];
+
+        self.passes
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, other)| {
+                let earlier_writer = i < pass
+                    && other
+                        .writes
+                        .iter()
+                        .any(|resource| this.writes.contains(resource));
+                let writes_what_we_read = i != pass
+                    && other.writes.iter().any(|resource| {
+                        this.reads.contains(resource) && !this.writes.contains(resource)
+                    });
+
+                if earlier_writer || writes_what_we_read {
+                    Some(i)
+                } else {
+                    None
+                }
+            })
+    }
+
+    // A topological sort that sticks to the order the passes were added in where it can.
+    fn order(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.passes.len());
+        let mut done = vec![false; self.passes.len()];
+
+        while order.len() < self.passes.len() {
+            let next = (0..self.passes.len())
+                .find(|&i| !done[i] && self.dependencies(i).all(|dependency| done[dependency]));
+
+            match next {
+                Some(i) => {
+                    done[i] = true;
+                    order.push(i);
+                }
+                None => {
+                    let stuck: Vec<_> = (0..self.passes.len())
+                        .filter(|&i| !done[i])
+                        .map(|i| self.passes[i].name)
+                        .collect();
+                    panic!("The render passes {:?} depend on each other", stuck);
+                }
+            }
+        }
+
+        order
+    }
+}
+
+#[test]
+fn passes_run_after_the_ones_they_depend_on() {
+    use GraphResource::*;
+
+    let mut graph = RenderGraph::new();
+    let record = |name| move |recorded: &mut Vec<&'static str>| recorded.push(name);
+
+    graph.add_pass("Composite", &[Scene, Bloom], &[Output], record("Composite"));
+    graph.add_pass("Text", &[], &[Output], record("Text"));
+    graph.add_pass("Main", &[ShadowMap], &[Scene, Bloom], record("Main"));
+    graph.add_pass("Bloom", &[Bloom], &[Bloom], record("Bloom"));
+    graph.add_pass("Shadows", &[], &[ShadowMap], record("Shadows"));
+
+    let mut recorded = Vec::new();
+    let mut timed = Vec::new();
+    graph.execute(&mut recorded, |name| timed.push(name));
+
+    assert_eq!(
+        recorded,
+        vec!["Shadows", "Main", "Bloom", "Composite", "Text"]
+    );
+    assert_eq!(recorded, timed);
+}
@@ -1,4 +1,5 @@
 use super::{colour_state_descriptor, DynamicBuffer, RenderContext, DEPTH_FORMAT, INDEX_FORMAT};
+use crate::hot_reload::shader;
 use std::sync::Arc;
 use ultraviolet::{Vec2, Vec3, Vec4};
 
@@ -21,7 +22,7 @@ impl Lines3dPipeline {
                     push_constant_ranges: &[],
                 });
 
-        let vs = wgpu::include_spirv!("../../shaders/compiled/lines_3d.vert.spv");
+        let vs = shader!("lines_3d.vert.spv");
         let vs_module = context.device.create_shader_module(vs);
 
         let pipeline = context
@@ -60,7 +61,7 @@ impl Lines3dPipeline {
                         attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float4],
                     }],
                 },
-                sample_count: 1,
+                sample_count: context.sample_count(),
                 sample_mask: !0,
                 alpha_to_coverage_enabled: false,
             });
@@ -116,6 +117,17 @@ impl Lines3dBuffer {
         });
     }
 
+    pub fn draw_line_3d(&mut self, a: Vec3, b: Vec3, colour: Vec4) {
+        self.lines.push(Lines3dVertex {
+            position: a,
+            colour,
+        });
+        self.lines.push(Lines3dVertex {
+            position: b,
+            colour,
+        });
+    }
+
     pub fn draw_triangle(&mut self, a: Vec2, b: Vec2, c: Vec2, height: f32, colour: Vec4) {
         self.draw_line(a, b, height, colour);
         self.draw_line(b, c, height, colour);
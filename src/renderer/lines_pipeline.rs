@@ -1,5 +1,6 @@
 use super::{colour_state_descriptor, DynamicBuffer, RenderContext, DEPTH_FORMAT, INDEX_FORMAT};
 use crate::assets::Assets;
+use crate::hot_reload::shader;
 use ultraviolet::{Vec2, Vec4};
 
 const WHITE: Vec4 = Vec4::new(1.0, 1.0, 1.0, 1.0);
@@ -59,14 +60,14 @@ impl LinesPipeline {
                 .device
                 .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                     label: Some("Cheese line pipeline layout"),
-                    bind_group_layouts: &[&bind_group_layout, &assets.texture_bind_group_layout],
+                    bind_group_layouts: &[&bind_group_layout, &assets.material_bind_group_layout],
                     push_constant_ranges: &[],
                 });
 
-        let vs = wgpu::include_spirv!("../../shaders/compiled/lines.vert.spv");
+        let vs = shader!("lines.vert.spv");
         let vs_module = context.device.create_shader_module(vs);
 
-        let fs = wgpu::include_spirv!("../../shaders/compiled/lines.frag.spv");
+        let fs = shader!("lines.frag.spv");
         let fs_module = context.device.create_shader_module(fs);
 
         let pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
@@ -97,7 +98,7 @@ impl LinesPipeline {
                     attributes: &wgpu::vertex_attr_array![0 => Float2, 1 => Float2, 2 => Float4, 3 => Int],
                 }],
             },
-            sample_count: 1,
+            sample_count: context.sample_count(),
             sample_mask: !0,
             alpha_to_coverage_enabled: false,
         });
@@ -126,8 +127,8 @@ impl LinesPipeline {
 }
 
 use lyon_tessellation::{
-    basic_shapes::{fill_rectangle, stroke_rectangle},
-    math::{rect, Point},
+    basic_shapes::{fill_rectangle, stroke_polyline, stroke_rectangle},
+    math::{point, rect, Point},
     BasicVertexConstructor, BuffersBuilder, FillOptions, StrokeAttributes, StrokeOptions,
     StrokeVertexConstructor, VertexBuffers,
 };
@@ -164,6 +165,7 @@ pub struct LineBuffers {
     lyon_buffers: VertexBuffers<Vertex, u16>,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug, serde::Deserialize)]
 pub enum Image {
     BuildPump,
     BuildArmoury,
@@ -286,6 +288,21 @@ impl LineBuffers {
         self.buffer();
     }
 
+    pub fn draw_polygon_outline(&mut self, points: &[Vec2], colour: Vec4, dpi_scaling: f32) {
+        let mut options = StrokeOptions::default();
+        options.line_width = dpi_scaling;
+
+        stroke_polyline(
+            points.iter().map(|p| point(p.x, p.y)),
+            true,
+            &options,
+            &mut BuffersBuilder::new(&mut self.lyon_buffers, Constructor { colour }),
+        )
+        .unwrap();
+
+        self.buffer();
+    }
+
     fn buffer(&mut self) {
         let num_vertices = self.vertices.len_waiting();
 
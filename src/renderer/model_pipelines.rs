@@ -1,17 +1,27 @@
+#[cfg(feature = "material-maps")]
+use super::PointLight;
+#[cfg(feature = "gpu-skinning")]
+use super::SkinnedModel;
 use super::{
-    colour_state_descriptor, draw_model, AnimatedVertex, DynamicBuffer, RenderContext,
-    StaticBuffer, Vertex, DEPTH_FORMAT,
+    colour_state_descriptor, draw_model, AnimatedVertex, DynamicBuffer, ParticleInstance,
+    PersistentBuffer, RenderContext, StaticBuffer, Vertex, DEPTH_FORMAT,
 };
-use crate::assets::{AnimatedModel, Assets, Model};
+use crate::animation::{Animation, AnimationState, Skin};
+use crate::assets::{AnimatedModel, AnimationInfo, Assets, Model, ModelAnimations, LOD_CELL_SIZES};
+use crate::hot_reload::shader;
+use crate::profiler::BufferStats;
 use std::sync::Arc;
 use ultraviolet::{Mat4, Vec4};
 use wgpu::util::DeviceExt;
 
 pub struct ModelPipelines {
-    identity_instance_buffer: Arc<wgpu::Buffer>,
     model_pipeline: wgpu::RenderPipeline,
     animated_pipeline: wgpu::RenderPipeline,
+    #[cfg(feature = "team-colour")]
+    team_coloured_animated_pipeline: wgpu::RenderPipeline,
     transparent_animated_pipeline: wgpu::RenderPipeline,
+    outline_mask_pipeline: wgpu::RenderPipeline,
+    outline_pipeline: wgpu::RenderPipeline,
     transparent_textured_bloom_pipeline: wgpu::RenderPipeline,
     transparent_textured_no_depth_pipeline: wgpu::RenderPipeline,
     transparent_pipeline: wgpu::RenderPipeline,
@@ -21,22 +31,55 @@ pub struct ModelPipelines {
 
 impl ModelPipelines {
     pub fn new(context: &RenderContext, assets: &Assets) -> Self {
-        let vs = wgpu::include_spirv!("../../shaders/compiled/static_model.vert.spv");
-        let vs_module = context.device.create_shader_module(vs);
+        Self::with_main_bind_group(
+            context,
+            assets,
+            context.main_bind_group.clone(),
+            context.sample_count(),
+        )
+    }
 
-        let vs_animated = wgpu::include_spirv!("../../shaders/compiled/animated_model.vert.spv");
-        let vs_animated_module = context.device.create_shader_module(vs_animated);
+    // For rendering the reflections in the water. Everything is drawn upside down and mirrored, but
+    // otherwise the same as usual.
+    #[cfg(feature = "water-shader")]
+    pub fn new_for_reflections(context: &RenderContext, assets: &Assets) -> Self {
+        // The reflection texture isn't multisampled.
+        Self::with_main_bind_group(context, assets, context.reflection_bind_group.clone(), 1)
+    }
 
-        let fs = wgpu::include_spirv!("../../shaders/compiled/textured.frag.spv");
+    fn with_main_bind_group(
+        context: &RenderContext,
+        assets: &Assets,
+        main_bind_group: Arc<wgpu::BindGroup>,
+        sample_count: u32,
+    ) -> Self {
+        // With `material-maps`, the models and units are lit with the normal and emissive maps of
+        // their materials, the colour of the sun and the point lights (see shaders/lighting.glsl).
+        #[cfg(feature = "material-maps")]
+        let (vs, vs_lit_animated, fs) = (
+            shader!("material.vert.spv"),
+            shader!("animated_material.vert.spv"),
+            shader!("material.frag.spv"),
+        );
+        #[cfg(not(feature = "material-maps"))]
+        let (vs, vs_lit_animated, fs) = (
+            shader!("static_model.vert.spv"),
+            shader!("animated_model.vert.spv"),
+            shader!("textured.frag.spv"),
+        );
+        let vs_module = context.device.create_shader_module(vs);
+        let vs_lit_animated_module = context.device.create_shader_module(vs_lit_animated);
         let fs_module = context.device.create_shader_module(fs);
 
-        let fs_transparent_textured =
-            wgpu::include_spirv!("../../shaders/compiled/transparent_textured.frag.spv");
+        // For the transparent units and outlines, which aren't lit.
+        let vs_animated = shader!("animated_model.vert.spv");
+        let vs_animated_module = context.device.create_shader_module(vs_animated);
+
+        let fs_transparent_textured = shader!("transparent_textured.frag.spv");
         let fs_transparent_textured_module =
             context.device.create_shader_module(fs_transparent_textured);
 
-        let fs_transparent_textured_bloom =
-            wgpu::include_spirv!("../../shaders/compiled/transparent_textured_bloom.frag.spv");
+        let fs_transparent_textured_bloom = shader!("transparent_textured_bloom.frag.spv");
         let fs_transparent_textured_bloom_module = context
             .device
             .create_shader_module(fs_transparent_textured_bloom);
@@ -45,7 +88,7 @@ impl ModelPipelines {
             &context.device,
             &[
                 &context.main_bind_group_layout,
-                &assets.texture_bind_group_layout,
+                &assets.material_bind_group_layout,
                 &context.shadow_uniform_bind_group_layout,
             ],
             "Cheese model pipeline",
@@ -53,58 +96,169 @@ impl ModelPipelines {
             &fs_module,
             false,
             true,
+            sample_count,
         );
 
         let animated_pipeline = create_animated_pipeline(
             &context.device,
             &[
                 &context.main_bind_group_layout,
-                &assets.texture_bind_group_layout,
+                &assets.material_bind_group_layout,
                 &context.joint_bind_group_layout,
                 &context.shadow_uniform_bind_group_layout,
             ],
-            &vs_animated_module,
+            &vs_lit_animated_module,
             &fs_module,
             false,
+            sample_count,
         );
 
+        #[cfg(feature = "team-colour")]
+        let team_coloured_animated_pipeline = {
+            #[cfg(feature = "material-maps")]
+            let fs_team_coloured = shader!("material_team_coloured.frag.spv");
+            #[cfg(not(feature = "material-maps"))]
+            let fs_team_coloured = shader!("team_coloured.frag.spv");
+            let fs_team_coloured_module = context.device.create_shader_module(fs_team_coloured);
+
+            create_animated_pipeline(
+                &context.device,
+                &[
+                    &context.main_bind_group_layout,
+                    &assets.material_bind_group_layout,
+                    &context.joint_bind_group_layout,
+                    &context.shadow_uniform_bind_group_layout,
+                ],
+                &vs_lit_animated_module,
+                &fs_team_coloured_module,
+                false,
+                sample_count,
+            )
+        };
+
         let transparent_animated_pipeline = create_animated_pipeline(
             &context.device,
             &[
                 &context.main_bind_group_layout,
-                &assets.texture_bind_group_layout,
+                &assets.material_bind_group_layout,
                 &context.joint_bind_group_layout,
                 &context.shadow_uniform_bind_group_layout,
             ],
             &vs_animated_module,
             &context.fs_transparent_module,
             true,
+            sample_count,
+        );
+
+        // Outlines are drawn by marking where the units are in the stencil buffer, and then drawing
+        // slightly bigger, flat coloured copies of them everywhere apart from there.
+        let outline_mask_pipeline = create_animated_pipeline_with_states(
+            &context.device,
+            &[
+                &context.main_bind_group_layout,
+                &assets.material_bind_group_layout,
+                &context.joint_bind_group_layout,
+                &context.shadow_uniform_bind_group_layout,
+            ],
+            "Cheese outline mask pipeline",
+            &vs_animated_module,
+            &context.fs_transparent_module,
+            &[
+                wgpu::ColorStateDescriptor {
+                    write_mask: wgpu::ColorWrite::empty(),
+                    ..colour_state_descriptor(false)
+                },
+                wgpu::ColorStateDescriptor {
+                    write_mask: wgpu::ColorWrite::empty(),
+                    ..colour_state_descriptor(false)
+                },
+            ],
+            wgpu::DepthStencilStateDescriptor {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: false,
+                // The whole unit is masked out, even if something is in front of it, so that the
+                // outline doesn't cut into it.
+                depth_compare: wgpu::CompareFunction::Always,
+                stencil: wgpu::StencilStateDescriptor {
+                    front: wgpu::StencilStateFaceDescriptor {
+                        pass_op: wgpu::StencilOperation::Replace,
+                        ..Default::default()
+                    },
+                    back: wgpu::StencilStateFaceDescriptor {
+                        pass_op: wgpu::StencilOperation::Replace,
+                        ..Default::default()
+                    },
+                    read_mask: !0,
+                    write_mask: !0,
+                },
+            },
+            sample_count,
+        );
+
+        let outline_pipeline = create_animated_pipeline_with_states(
+            &context.device,
+            &[
+                &context.main_bind_group_layout,
+                &assets.material_bind_group_layout,
+                &context.joint_bind_group_layout,
+                &context.shadow_uniform_bind_group_layout,
+            ],
+            "Cheese outline pipeline",
+            &vs_animated_module,
+            &context.fs_transparent_module,
+            &[
+                colour_state_descriptor(true),
+                wgpu::ColorStateDescriptor {
+                    write_mask: wgpu::ColorWrite::empty(),
+                    ..colour_state_descriptor(false)
+                },
+            ],
+            wgpu::DepthStencilStateDescriptor {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilStateDescriptor {
+                    front: wgpu::StencilStateFaceDescriptor {
+                        compare: wgpu::CompareFunction::NotEqual,
+                        ..Default::default()
+                    },
+                    back: wgpu::StencilStateFaceDescriptor {
+                        compare: wgpu::CompareFunction::NotEqual,
+                        ..Default::default()
+                    },
+                    read_mask: !0,
+                    write_mask: 0,
+                },
+            },
+            sample_count,
         );
 
         let transparent_textured_bloom_pipeline = create_render_pipeline(
             &context.device,
             &[
                 &context.main_bind_group_layout,
-                &assets.texture_bind_group_layout,
+                &assets.material_bind_group_layout,
             ],
             "Cheese transparent textured pipeline",
             &context.vs_transparent_module,
             &fs_transparent_textured_bloom_module,
             true,
             true,
+            sample_count,
         );
 
         let transparent_textured_no_depth_pipeline = create_render_pipeline(
             &context.device,
             &[
                 &context.main_bind_group_layout,
-                &assets.texture_bind_group_layout,
+                &assets.material_bind_group_layout,
             ],
             "Cheese transparent textured pipeline",
             &context.vs_transparent_module,
             &fs_transparent_textured_module,
             true,
             false,
+            sample_count,
         );
 
         let transparent_pipeline = create_render_pipeline(
@@ -115,17 +269,21 @@ impl ModelPipelines {
             &context.fs_transparent_module,
             true,
             true,
+            sample_count,
         );
 
         Self {
             model_pipeline,
             animated_pipeline,
+            #[cfg(feature = "team-colour")]
+            team_coloured_animated_pipeline,
             transparent_animated_pipeline,
+            outline_mask_pipeline,
+            outline_pipeline,
             transparent_textured_bloom_pipeline,
             transparent_textured_no_depth_pipeline,
             transparent_pipeline,
-            main_bind_group: context.main_bind_group.clone(),
-            identity_instance_buffer: context.identity_instance_buffer.clone(),
+            main_bind_group,
             shadow_uniform_bind_group: context.shadow_uniform_bind_group.clone(),
         }
     }
@@ -137,9 +295,54 @@ impl ModelPipelines {
         texture: &'a wgpu::BindGroup,
         model: &'a AnimatedModel,
         joints: &'a wgpu::BindGroup,
+    ) {
+        self.render_animated_with_pipeline(
+            render_pass,
+            &self.animated_pipeline,
+            instances,
+            texture,
+            model,
+            joints,
+        );
+    }
+
+    // For models with a team colour mask in their texture (see `assets::add_team_colour_mask`). The
+    // instance's flat colour is used as the team colour. Without the `team-colour` feature they're
+    // just drawn like any other model.
+    pub fn render_team_coloured_animated<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        instances: &'a DynamicBuffer<ModelInstance>,
+        texture: &'a wgpu::BindGroup,
+        model: &'a AnimatedModel,
+        joints: &'a wgpu::BindGroup,
+    ) {
+        #[cfg(feature = "team-colour")]
+        let pipeline = &self.team_coloured_animated_pipeline;
+        #[cfg(not(feature = "team-colour"))]
+        let pipeline = &self.animated_pipeline;
+
+        self.render_animated_with_pipeline(
+            render_pass,
+            pipeline,
+            instances,
+            texture,
+            model,
+            joints,
+        );
+    }
+
+    fn render_animated_with_pipeline<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        pipeline: &'a wgpu::RenderPipeline,
+        instances: &'a DynamicBuffer<ModelInstance>,
+        texture: &'a wgpu::BindGroup,
+        model: &'a AnimatedModel,
+        joints: &'a wgpu::BindGroup,
     ) {
         if let Some((slice, num)) = instances.get() {
-            render_pass.set_pipeline(&self.animated_pipeline);
+            render_pass.set_pipeline(pipeline);
             render_pass.set_bind_group(0, &self.main_bind_group, &[]);
             render_pass.set_bind_group(1, texture, &[]);
             render_pass.set_bind_group(2, joints, &[]);
@@ -176,22 +379,34 @@ impl ModelPipelines {
         }
     }
 
-    pub fn render_single<'a>(
+    // `masks` are the units as they're normally drawn and `outlines` are the bigger copies. They
+    // both share the same joints.
+    pub fn render_outlines<'a>(
         &'a self,
         render_pass: &mut wgpu::RenderPass<'a>,
-        texture: &'a wgpu::BindGroup,
-        model: &'a Model,
+        masks: &'a DynamicBuffer<ModelInstance>,
+        outlines: &'a DynamicBuffer<ModelInstance>,
+        dummy_texture: &'a wgpu::BindGroup,
+        model: &'a AnimatedModel,
+        joints: &'a wgpu::BindGroup,
     ) {
-        render_pass.set_pipeline(&self.model_pipeline);
-        render_pass.set_bind_group(0, &self.main_bind_group, &[]);
-        render_pass.set_bind_group(1, texture, &[]);
-        render_pass.set_bind_group(2, &self.shadow_uniform_bind_group, &[]);
-        draw_model(
-            render_pass,
-            model,
-            self.identity_instance_buffer.slice(..),
-            1,
-        );
+        if let (Some((masks, num)), Some((outlines, _))) = (masks.get(), outlines.get()) {
+            render_pass.set_stencil_reference(1);
+            render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+            render_pass.set_bind_group(1, dummy_texture, &[]);
+            render_pass.set_bind_group(2, joints, &[]);
+            render_pass.set_bind_group(3, &self.shadow_uniform_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, model.vertices.slice(..));
+            render_pass.set_index_buffer(model.indices.slice(..));
+
+            render_pass.set_pipeline(&self.outline_mask_pipeline);
+            render_pass.set_vertex_buffer(1, masks);
+            render_pass.draw_indexed(0..model.num_indices, 0, 0..num);
+
+            render_pass.set_pipeline(&self.outline_pipeline);
+            render_pass.set_vertex_buffer(1, outlines);
+            render_pass.draw_indexed(0..model.num_indices, 0, 0..num);
+        }
     }
 
     pub fn render_single_with_transform<'a>(
@@ -224,6 +439,74 @@ impl ModelPipelines {
         }
     }
 
+    // Draws a bunch of models that share a material (e.g. `Assets::props_texture`), only setting up
+    // the pipeline and bind groups once.
+    pub fn render_instanced_batch<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        texture: &'a wgpu::BindGroup,
+        batch: &[(&'a DynamicBuffer<ModelInstance>, &'a Model)],
+    ) {
+        let mut batch = batch
+            .iter()
+            .filter_map(|(instances, model)| Some((instances.get()?, model)))
+            .peekable();
+
+        if batch.peek().is_none() {
+            return;
+        }
+
+        render_pass.set_pipeline(&self.model_pipeline);
+        render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+        render_pass.set_bind_group(1, texture, &[]);
+        render_pass.set_bind_group(2, &self.shadow_uniform_bind_group, &[]);
+
+        for ((slice, num), model) in batch {
+            draw_model(render_pass, model, slice, num);
+        }
+    }
+
+    // For instances that stay in the same buffer for a long time, like the scenery's.
+    pub(super) fn render_static_instances<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        instances: &'a PersistentBuffer,
+        texture: &'a wgpu::BindGroup,
+        model: &'a Model,
+    ) {
+        render_pass.set_pipeline(&self.model_pipeline);
+        render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+        render_pass.set_bind_group(1, texture, &[]);
+        render_pass.set_bind_group(2, &self.shadow_uniform_bind_group, &[]);
+        draw_model(
+            render_pass,
+            model,
+            instances.buffer.slice(..),
+            instances.len,
+        );
+    }
+
+    // Draws one of the model's simplified levels of detail, see `Model::lod_indices`.
+    pub fn render_instanced_lod<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        instances: &'a DynamicBuffer<ModelInstance>,
+        texture: &'a wgpu::BindGroup,
+        model: &'a Model,
+        lod: usize,
+    ) {
+        if let Some((slice, num)) = instances.get() {
+            render_pass.set_pipeline(&self.model_pipeline);
+            render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+            render_pass.set_bind_group(1, texture, &[]);
+            render_pass.set_bind_group(2, &self.shadow_uniform_bind_group, &[]);
+            render_pass.set_vertex_buffer(0, model.vertices.slice(..));
+            render_pass.set_vertex_buffer(1, slice);
+            render_pass.set_index_buffer(model.indices.slice(..));
+            render_pass.draw_indexed(model.lod_indices(lod), 0, 0..num);
+        }
+    }
+
     pub fn render_transparent_textured_with_bloom<'a>(
         &'a self,
         render_pass: &mut wgpu::RenderPass<'a>,
@@ -267,9 +550,23 @@ impl ModelPipelines {
             draw_model(render_pass, model, instances.slice(..), num_instances)
         }
     }
+
+    // Flat coloured see-through models, like shield domes.
+    pub fn render_transparent<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        instances: &'a DynamicBuffer<ModelInstance>,
+        model: &'a Model,
+    ) {
+        if let Some((slice, num)) = instances.get() {
+            render_pass.set_pipeline(&self.transparent_pipeline);
+            render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+            draw_model(render_pass, model, slice, num);
+        }
+    }
 }
 
-fn create_render_pipeline(
+pub(super) fn create_render_pipeline(
     device: &wgpu::Device,
     bind_group_layouts: &[&wgpu::BindGroupLayout],
     label: &str,
@@ -277,6 +574,7 @@ fn create_render_pipeline(
     fs_module: &wgpu::ShaderModule,
     alpha_blend: bool,
     write_depth: bool,
+    sample_count: u32,
 ) -> wgpu::RenderPipeline {
     let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Cheese pipeline layout"),
@@ -313,7 +611,7 @@ fn create_render_pipeline(
 				wgpu::VertexBufferDescriptor {
 					stride: std::mem::size_of::<Vertex>() as u64,
 					step_mode: wgpu::InputStepMode::Vertex,
-					attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float3, 2 => Float2],
+					attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float3, 2 => Float2, 8 => Float4],
 				},
 				wgpu::VertexBufferDescriptor {
 					stride: std::mem::size_of::<ModelInstance>() as u64,
@@ -322,7 +620,7 @@ fn create_render_pipeline(
 				},
 			],
 		},
-		sample_count: 1,
+		sample_count,
 		sample_mask: !0,
 		alpha_to_coverage_enabled: false,
 	})
@@ -334,6 +632,39 @@ fn create_animated_pipeline(
     vs_module: &wgpu::ShaderModule,
     fs_module: &wgpu::ShaderModule,
     alpha_blend: bool,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    create_animated_pipeline_with_states(
+        device,
+        bind_group_layouts,
+        "Cheese animated pipeline",
+        vs_module,
+        fs_module,
+        &[
+            colour_state_descriptor(alpha_blend),
+            colour_state_descriptor(false),
+        ],
+        wgpu::DepthStencilStateDescriptor {
+            format: DEPTH_FORMAT,
+            // Otherwise see-through mice would hide the parts of the ones behind them that haven't
+            // been drawn yet.
+            depth_write_enabled: !alpha_blend,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilStateDescriptor::default(),
+        },
+        sample_count,
+    )
+}
+
+fn create_animated_pipeline_with_states(
+    device: &wgpu::Device,
+    bind_group_layouts: &[&wgpu::BindGroupLayout],
+    label: &str,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+    color_states: &[wgpu::ColorStateDescriptor],
+    depth_stencil_state: wgpu::DepthStencilStateDescriptor,
+    sample_count: u32,
 ) -> wgpu::RenderPipeline {
     let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("Cheese animated pipeline layout"),
@@ -342,7 +673,7 @@ fn create_animated_pipeline(
     });
 
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-		label: Some("Cheese animated pipeline"),
+		label: Some(label),
 		layout: Some(&pipeline_layout),
 		vertex_stage: wgpu::ProgrammableStageDescriptor {
 			module: vs_module,
@@ -357,20 +688,15 @@ fn create_animated_pipeline(
 			..Default::default()
 		}),
 		primitive_topology: wgpu::PrimitiveTopology::TriangleList,
-		color_states: &[colour_state_descriptor(alpha_blend), colour_state_descriptor(false)],
-		depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
-			format: DEPTH_FORMAT,
-			depth_write_enabled: true,
-			depth_compare: wgpu::CompareFunction::Less,
-			stencil: wgpu::StencilStateDescriptor::default(),
-		}),
+		color_states,
+		depth_stencil_state: Some(depth_stencil_state),
 		vertex_state: wgpu::VertexStateDescriptor {
 			index_format: wgpu::IndexFormat::Uint32,
 			vertex_buffers: &[
 				wgpu::VertexBufferDescriptor {
 					stride: std::mem::size_of::<AnimatedVertex>() as u64,
 					step_mode: wgpu::InputStepMode::Vertex,
-					attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float3, 2 => Float2, 3 => Float4, 4 => Float4],
+					attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float3, 2 => Float2, 3 => Float4, 4 => Float4, 10 => Float4],
 				},
 				wgpu::VertexBufferDescriptor {
 					stride: std::mem::size_of::<ModelInstance>() as u64,
@@ -379,7 +705,7 @@ fn create_animated_pipeline(
 				},
 			],
 		},
-		sample_count: 1,
+		sample_count,
 		sample_mask: !0,
 		alpha_to_coverage_enabled: false,
 	})
@@ -435,9 +761,11 @@ fn create_joint_bind_group(
 }
 
 pub struct JointBuffer {
-    buffer: DynamicBuffer<Mat4>,
+    pub(super) buffer: DynamicBuffer<Mat4>,
     pub bind_group: wgpu::BindGroup,
     bind_group_label: &'static str,
+    #[cfg(feature = "gpu-skinning")]
+    pub(super) skinning: SkinnedModel,
 }
 
 impl JointBuffer {
@@ -447,19 +775,32 @@ impl JointBuffer {
         label: &'static str,
         bind_group_label: &'static str,
         model: &AnimatedModel,
+        animation_info: &AnimationInfo,
     ) -> Self {
         let buffer =
             DynamicBuffer::new(&context.device, capacity, label, wgpu::BufferUsage::STORAGE);
 
+        #[cfg(not(feature = "gpu-skinning"))]
+        let _ = animation_info;
+
         Self {
             bind_group: create_joint_bind_group(context, bind_group_label, &buffer, model),
             buffer,
             bind_group_label,
+            #[cfg(feature = "gpu-skinning")]
+            skinning: SkinnedModel::new(
+                context,
+                capacity / animation_info.skin.joints.len().max(1),
+                animation_info,
+            ),
         }
     }
 
     fn upload(&mut self, context: &RenderContext, model: &AnimatedModel) {
+        #[cfg(not(feature = "gpu-skinning"))]
         let resized = self.buffer.upload(context);
+        #[cfg(feature = "gpu-skinning")]
+        let resized = self.skinning.upload(context, &mut self.buffer);
 
         if resized {
             self.bind_group =
@@ -467,8 +808,42 @@ impl JointBuffer {
         }
     }
 
-    pub fn push(&mut self, joint: Mat4) {
-        self.buffer.push(joint);
+    // Queue up the joint matrices for an instance of the model. With gpu skinning, we only need
+    // to push the animation state and `SkinningPipeline` does the rest.
+    pub fn push(
+        &mut self,
+        animation_state: &AnimationState,
+        animations: &[Animation],
+        skin: &mut Skin,
+    ) {
+        #[cfg(not(feature = "gpu-skinning"))]
+        animation_state.pose(animations, skin);
+
+        self.push_posed(animation_state, animations, skin);
+    }
+
+    // For skins that have already been posed this frame, e.g. by `animation::pose_all`.
+    pub fn push_posed(
+        &mut self,
+        animation_state: &AnimationState,
+        animations: &[Animation],
+        skin: &Skin,
+    ) {
+        #[cfg(not(feature = "gpu-skinning"))]
+        {
+            let _ = (animation_state, animations);
+
+            for joint in &skin.joints {
+                self.buffer.push(joint.matrix);
+            }
+        }
+
+        #[cfg(feature = "gpu-skinning")]
+        {
+            let _ = skin;
+            self.skinning
+                .push(animation_state.skinning_instance(animations));
+        }
     }
 }
 
@@ -479,6 +854,18 @@ pub struct ModelBuffers {
     pub mice_engineers: DynamicBuffer<ModelInstance>,
     pub mice_engineers_joints: JointBuffer,
 
+    // Far away mice, drawn with the static models. There's one buffer for each simplified level of
+    // detail, so `distant_mice_marines[0]` is level 1.
+    pub distant_mice_marines: Vec<DynamicBuffer<ModelInstance>>,
+    pub distant_mice_engineers: Vec<DynamicBuffer<ModelInstance>>,
+
+    pub mice_corpses: DynamicBuffer<ModelInstance>,
+    pub mice_corpses_joints: JointBuffer,
+
+    pub mice_outline_masks: DynamicBuffer<ModelInstance>,
+    pub mice_outlines: DynamicBuffer<ModelInstance>,
+    pub mice_outlines_joints: JointBuffer,
+
     pub pumps: DynamicBuffer<ModelInstance>,
     pub pump_joints: JointBuffer,
 
@@ -486,14 +873,26 @@ pub struct ModelBuffers {
     pub command_indicators: DynamicBuffer<ModelInstance>,
     pub command_paths: DynamicBuffer<ModelInstance>,
     pub armouries: DynamicBuffer<ModelInstance>,
+    pub rocks: DynamicBuffer<ModelInstance>,
+    // Wall segments and gates.
+    pub walls: DynamicBuffer<ModelInstance>,
     pub cheese_droplets: DynamicBuffer<ModelInstance>,
     pub explosions: DynamicBuffer<ModelInstance>,
+    pub particles: DynamicBuffer<ParticleInstance>,
+    pub shields: DynamicBuffer<ModelInstance>,
+
+    // Lights the models, terrain and units with the `material-maps` feature. These get cleared when
+    // they're uploaded, like the instance buffers.
+    #[cfg(feature = "material-maps")]
+    pub point_lights: Vec<PointLight>,
 
     pub building_plan: BuildingPlan,
+    // The segments of a wall that's being dragged out, which can be any number of them.
+    pub wall_plan: DynamicBuffer<ModelInstance>,
 }
 
 impl ModelBuffers {
-    pub fn new(context: &RenderContext, assets: &Assets) -> Self {
+    pub fn new(context: &RenderContext, assets: &Assets, animations: &ModelAnimations) -> Self {
         Self {
             mice_marines: DynamicBuffer::new(
                 &context.device,
@@ -507,6 +906,7 @@ impl ModelBuffers {
                 "Cheese mice marines joints buffer",
                 "Cheese mice marines joints bind group",
                 &assets.mouse_model,
+                &animations.mouse,
             ),
             mice_engineers: DynamicBuffer::new(
                 &context.device,
@@ -520,6 +920,63 @@ impl ModelBuffers {
                 "Cheese mice engineers joints buffer",
                 "Cheese mice engineers joints bind group",
                 &assets.mouse_model,
+                &animations.mouse,
+            ),
+            distant_mice_marines: LOD_CELL_SIZES
+                .iter()
+                .map(|_| {
+                    DynamicBuffer::new(
+                        &context.device,
+                        50,
+                        "Cheese distant mice marines instance buffer",
+                        wgpu::BufferUsage::VERTEX,
+                    )
+                })
+                .collect(),
+            distant_mice_engineers: LOD_CELL_SIZES
+                .iter()
+                .map(|_| {
+                    DynamicBuffer::new(
+                        &context.device,
+                        50,
+                        "Cheese distant mice engineers instance buffer",
+                        wgpu::BufferUsage::VERTEX,
+                    )
+                })
+                .collect(),
+            mice_corpses: DynamicBuffer::new(
+                &context.device,
+                20,
+                "Cheese mice corpses instance buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            mice_corpses_joints: JointBuffer::new(
+                context,
+                160,
+                "Cheese mice corpses joints buffer",
+                "Cheese mice corpses joints bind group",
+                &assets.mouse_model,
+                &animations.mouse,
+            ),
+            mice_outline_masks: DynamicBuffer::new(
+                &context.device,
+                50,
+                "Cheese mice outline masks buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            mice_outlines: DynamicBuffer::new(
+                &context.device,
+                50,
+                "Cheese mice outlines buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            mice_outlines_joints: JointBuffer::new(
+                context,
+                400,
+                "Cheese mice outlines joints buffer",
+                "Cheese mice outlines joints bind group",
+                &assets.mouse_model,
+                &animations.mouse,
             ),
             pumps: DynamicBuffer::new(
                 &context.device,
@@ -533,6 +990,7 @@ impl ModelBuffers {
                 "Cheese pump joints buffer",
                 "Cheese pump joints bind group",
                 &assets.pump_model,
+                &animations.pump,
             ),
 
             bullets: DynamicBuffer::new(
@@ -559,6 +1017,18 @@ impl ModelBuffers {
                 "Cheese armoury buffer",
                 wgpu::BufferUsage::VERTEX,
             ),
+            rocks: DynamicBuffer::new(
+                &context.device,
+                50,
+                "Cheese rocks buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            walls: DynamicBuffer::new(
+                &context.device,
+                50,
+                "Cheese walls buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
             cheese_droplets: DynamicBuffer::new(
                 &context.device,
                 5000,
@@ -571,6 +1041,20 @@ impl ModelBuffers {
                 "Cheese explosions buffer",
                 wgpu::BufferUsage::VERTEX,
             ),
+            particles: DynamicBuffer::new(
+                &context.device,
+                200,
+                "Cheese particles buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            shields: DynamicBuffer::new(
+                &context.device,
+                10,
+                "Cheese shields buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
+            #[cfg(feature = "material-maps")]
+            point_lights: Vec::new(),
             building_plan: BuildingPlan {
                 building: None,
                 buffer: StaticBuffer::new(
@@ -580,6 +1064,12 @@ impl ModelBuffers {
                     wgpu::BufferUsage::VERTEX,
                 ),
             },
+            wall_plan: DynamicBuffer::new(
+                &context.device,
+                16,
+                "Cheese wall plan buffer",
+                wgpu::BufferUsage::VERTEX,
+            ),
         }
     }
 
@@ -588,18 +1078,85 @@ impl ModelBuffers {
         self.command_indicators.upload(context);
         self.command_paths.upload(context);
         self.armouries.upload(context);
+        self.rocks.upload(context);
+        self.walls.upload(context);
         self.cheese_droplets.upload(context);
         self.pumps.upload(context);
         self.building_plan.upload(context);
+        self.wall_plan.upload(context);
         self.mice_marines.upload(context);
         self.mice_engineers.upload(context);
+        self.mice_corpses.upload(context);
+        for buffer in self
+            .distant_mice_marines
+            .iter_mut()
+            .chain(&mut self.distant_mice_engineers)
+        {
+            buffer.upload(context);
+        }
+        self.mice_outline_masks.upload(context);
+        self.mice_outlines.upload(context);
         self.explosions.upload(context);
+        self.particles.upload(context);
+        self.shields.upload(context);
+        #[cfg(feature = "material-maps")]
+        {
+            context.update_lighting(&self.point_lights);
+            self.point_lights.clear();
+        }
+        #[cfg(not(feature = "material-maps"))]
+        context.update_lighting();
         self.mice_marines_joints
             .upload(context, &assets.mouse_model);
         self.mice_engineers_joints
             .upload(context, &assets.mouse_model);
+        self.mice_corpses_joints
+            .upload(context, &assets.mouse_model);
+        self.mice_outlines_joints
+            .upload(context, &assets.mouse_model);
         self.pump_joints.upload(context, &assets.pump_model);
     }
+
+    pub fn stats(&self) -> Vec<BufferStats> {
+        let mut stats: Vec<BufferStats> = [
+            &self.mice_marines,
+            &self.mice_engineers,
+            &self.mice_corpses,
+            &self.mice_outline_masks,
+            &self.mice_outlines,
+            &self.pumps,
+            &self.bullets,
+            &self.command_indicators,
+            &self.command_paths,
+            &self.armouries,
+            &self.rocks,
+            &self.walls,
+            &self.cheese_droplets,
+            &self.explosions,
+            &self.shields,
+            &self.wall_plan,
+        ]
+        .iter()
+        .chain(&self.distant_mice_marines.iter().collect::<Vec<_>>())
+        .chain(&self.distant_mice_engineers.iter().collect::<Vec<_>>())
+        .map(|buffer| buffer.stats())
+        .collect();
+
+        stats.push(self.particles.stats());
+        stats.extend(
+            [
+                &self.mice_marines_joints,
+                &self.mice_engineers_joints,
+                &self.mice_corpses_joints,
+                &self.mice_outlines_joints,
+                &self.pump_joints,
+            ]
+            .iter()
+            .map(|joints| joints.buffer.stats()),
+        );
+
+        stats
+    }
 }
 
 pub struct TitlescreenBuffer {
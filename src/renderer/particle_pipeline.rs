@@ -0,0 +1,151 @@
+use super::{
+    additive_colour_state_descriptor, draw_model, DynamicBuffer, RenderContext, Vertex,
+    DEPTH_FORMAT, INDEX_FORMAT,
+};
+use crate::assets::Model;
+use std::sync::Arc;
+use ultraviolet::{Mat4, Vec2, Vec3, Vec4};
+use wgpu::util::DeviceExt;
+
+// Particles are little octahedrons (so that they look the same from every angle without having to
+// billboard them) drawn with additive blending. The instance format is the same as `ModelInstance`,
+// so we can get away with reusing the transparent shaders.
+
+pub struct ParticlePipeline {
+    pipeline: wgpu::RenderPipeline,
+    main_bind_group: Arc<wgpu::BindGroup>,
+    model: Model,
+}
+
+impl ParticlePipeline {
+    pub fn new(context: &RenderContext) -> Self {
+        let pipeline_layout =
+            context
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Cheese particle pipeline layout"),
+                    bind_group_layouts: &[&context.main_bind_group_layout],
+                    push_constant_ranges: &[],
+                });
+
+        let pipeline = context
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Cheese particle pipeline"),
+                layout: Some(&pipeline_layout),
+                vertex_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &context.vs_transparent_module,
+                    entry_point: "main",
+                },
+                fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                    module: &context.fs_transparent_module,
+                    entry_point: "main",
+                }),
+                rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                    cull_mode: wgpu::CullMode::Back,
+                    ..Default::default()
+                }),
+                primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+                // The transparent fragment shader doesn't write anything for bloom, so leave it alone.
+                color_states: &[
+                    additive_colour_state_descriptor(),
+                    wgpu::ColorStateDescriptor {
+                        write_mask: wgpu::ColorWrite::empty(),
+                        ..additive_colour_state_descriptor()
+                    },
+                ],
+                // Particles overlap each other a lot, so don't let them hide one another.
+                depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                    format: DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: wgpu::StencilStateDescriptor::default(),
+                }),
+                vertex_state: wgpu::VertexStateDescriptor {
+                    index_format: INDEX_FORMAT,
+                    vertex_buffers: &[
+                        wgpu::VertexBufferDescriptor {
+                            stride: std::mem::size_of::<Vertex>() as u64,
+                            step_mode: wgpu::InputStepMode::Vertex,
+                            attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float3, 2 => Float2],
+                        },
+                        wgpu::VertexBufferDescriptor {
+                            stride: std::mem::size_of::<ParticleInstance>() as u64,
+                            step_mode: wgpu::InputStepMode::Instance,
+                            attributes: &wgpu::vertex_attr_array![3 => Float4, 4 => Float4, 5 => Float4, 6 => Float4, 7 => Float4],
+                        },
+                    ],
+                },
+                sample_count: context.sample_count(),
+                sample_mask: !0,
+                alpha_to_coverage_enabled: false,
+            });
+
+        Self {
+            pipeline,
+            main_bind_group: context.main_bind_group.clone(),
+            model: create_octahedron(&context.device),
+        }
+    }
+
+    pub fn render<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        particles: &'a DynamicBuffer<ParticleInstance>,
+    ) {
+        if let Some((slice, num)) = particles.get() {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+            draw_model(render_pass, &self.model, slice, num);
+        }
+    }
+}
+
+fn create_octahedron(device: &wgpu::Device) -> Model {
+    let points = [
+        Vec3::unit_x(),
+        -Vec3::unit_x(),
+        Vec3::unit_y(),
+        -Vec3::unit_y(),
+        Vec3::unit_z(),
+        -Vec3::unit_z(),
+    ];
+
+    let vertices: Vec<_> = points
+        .iter()
+        .map(|&position| Vertex {
+            position,
+            normal: position,
+            uv: Vec2::zero(),
+            // Particles aren't textured, so this doesn't matter.
+            tangent: Vec4::zero(),
+        })
+        .collect();
+
+    // Counter-clockwise when looking at each face from the outside.
+    let indices: [u32; 24] = [
+        0, 2, 4, 4, 2, 1, 1, 2, 5, 5, 2, 0, 0, 4, 3, 4, 1, 3, 1, 5, 3, 5, 0, 3,
+    ];
+
+    Model {
+        vertices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese particle vertices"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        }),
+        indices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese particle indices"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsage::INDEX,
+        }),
+        num_indices: indices.len() as u32,
+        lods: Vec::new(),
+    }
+}
+
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Clone, Copy)]
+pub struct ParticleInstance {
+    pub colour: Vec4,
+    pub transform: Mat4,
+}
@@ -0,0 +1,327 @@
+use super::{
+    additive_colour_state_descriptor, colour_state_descriptor, RenderContext, DISPLAY_FORMAT,
+    INDEX_FORMAT,
+};
+use crate::hot_reload::shader;
+use crate::resources::Settings;
+#[cfg(feature = "hdr")]
+use wgpu::util::DeviceExt;
+
+// How much the scene is brightened before tone mapping.
+#[cfg(feature = "hdr")]
+const EXPOSURE: f32 = 1.2;
+// How much darker the corners are than the middle of the screen. Kept low so it isn't distracting.
+#[cfg(feature = "hdr")]
+const VIGNETTE_STRENGTH: f32 = 0.25;
+
+// The effects that are applied to the scene after it's been rendered, in the order they happen.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Effect {
+    Bloom,
+    ToneMapping,
+    Vignette,
+}
+
+impl Effect {
+    // Tone mapping and the vignette happen in the final composite shader, which needs the `hdr`
+    // feature. Without it the framebuffer is just copied to the screen.
+    fn supported(self) -> bool {
+        cfg!(feature = "hdr") || self == Self::Bloom
+    }
+}
+
+// The effects that are turned on in the settings and work in this build.
+pub fn effect_chain(settings: &Settings) -> Vec<Effect> {
+    vec![
+        (Effect::Bloom, settings.bloom),
+        (Effect::ToneMapping, settings.tone_mapping),
+        (Effect::Vignette, settings.vignette),
+    ]
+    .into_iter()
+    .filter(|(effect, enabled)| *enabled && effect.supported())
+    .map(|(effect, _)| effect)
+    .collect()
+}
+
+#[cfg(feature = "hdr")]
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct CompositeUniforms {
+    exposure: f32,
+    vignette_strength: f32,
+    tone_mapping: i32,
+    padding: i32,
+}
+
+#[cfg(feature = "hdr")]
+impl CompositeUniforms {
+    fn new(effects: &[Effect]) -> Self {
+        Self {
+            exposure: EXPOSURE,
+            vignette_strength: if effects.contains(&Effect::Vignette) {
+                VIGNETTE_STRENGTH
+            } else {
+                0.0
+            },
+            tone_mapping: effects.contains(&Effect::ToneMapping) as i32,
+            padding: 0,
+        }
+    }
+}
+
+// Takes the framebuffer once the scene has been drawn into it, runs the effects over it and
+// composites the result onto the swap chain texture.
+pub struct PostProcessing {
+    effects: Vec<Effect>,
+    bloom_blur_pipeline: wgpu::RenderPipeline,
+    composite_pipeline: wgpu::RenderPipeline,
+    #[cfg(feature = "hdr")]
+    composite_uniform_buffer: wgpu::Buffer,
+    #[cfg(feature = "hdr")]
+    composite_bind_group: wgpu::BindGroup,
+}
+
+impl PostProcessing {
+    pub fn new(context: &RenderContext, settings: &Settings) -> Self {
+        let device = &context.device;
+        let effects = effect_chain(settings);
+
+        let vs_full_screen_quad = shader!("full_screen_quad.vert.spv");
+        let vs_full_screen_quad_module = device.create_shader_module(vs_full_screen_quad);
+
+        // Bloom
+
+        let fs_blur = shader!("blur.frag.spv");
+        let fs_blur_module = device.create_shader_module(fs_blur);
+
+        let bloom_blur_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Cheese bloom blur pipeline layout"),
+                bind_group_layouts: &[&context.bloom_bind_group_layout],
+                push_constant_ranges: &[],
+            });
+
+        let bloom_blur_pipeline = create_full_screen_pipeline(
+            device,
+            "Cheese bloom blur pipeline",
+            &bloom_blur_pipeline_layout,
+            &vs_full_screen_quad_module,
+            &fs_blur_module,
+            additive_colour_state_descriptor(),
+        );
+
+        // Composite
+
+        #[cfg(feature = "hdr")]
+        let composite_uniform_buffer =
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Cheese composite uniform buffer"),
+                contents: bytemuck::bytes_of(&CompositeUniforms::new(&effects)),
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+            });
+
+        #[cfg(feature = "hdr")]
+        let composite_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Cheese composite bind group layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        #[cfg(feature = "hdr")]
+        let composite_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Cheese composite bind group"),
+            layout: &composite_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(composite_uniform_buffer.slice(..)),
+            }],
+        });
+
+        #[cfg(feature = "hdr")]
+        let (composite_layouts, fs_composite): (&[&wgpu::BindGroupLayout], _) = (
+            &[
+                &context.framebuffer_bind_group_layout,
+                &composite_bind_group_layout,
+            ],
+            shader!("composite.frag.spv"),
+        );
+        #[cfg(not(feature = "hdr"))]
+        let (composite_layouts, fs_composite): (&[&wgpu::BindGroupLayout], _) = (
+            &[&context.framebuffer_bind_group_layout],
+            shader!("post_processing.frag.spv"),
+        );
+
+        let fs_composite_module = device.create_shader_module(fs_composite);
+
+        let composite_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Cheese composite pipeline layout"),
+                bind_group_layouts: composite_layouts,
+                push_constant_ranges: &[],
+            });
+
+        let composite_pipeline = create_full_screen_pipeline(
+            device,
+            "Cheese composite pipeline",
+            &composite_pipeline_layout,
+            &vs_full_screen_quad_module,
+            &fs_composite_module,
+            // This is the only thing (other than the text) that draws to the swap chain.
+            wgpu::ColorStateDescriptor {
+                format: DISPLAY_FORMAT,
+                ..colour_state_descriptor(false)
+            },
+        );
+
+        Self {
+            effects,
+            bloom_blur_pipeline,
+            composite_pipeline,
+            #[cfg(feature = "hdr")]
+            composite_uniform_buffer,
+            #[cfg(feature = "hdr")]
+            composite_bind_group,
+        }
+    }
+
+    #[cfg_attr(not(feature = "hdr"), allow(unused_variables))]
+    pub fn configure(&mut self, context: &RenderContext, settings: &Settings) {
+        self.effects = effect_chain(settings);
+
+        #[cfg(feature = "hdr")]
+        context.queue.write_buffer(
+            &self.composite_uniform_buffer,
+            0,
+            bytemuck::bytes_of(&CompositeUniforms::new(&self.effects)),
+        );
+    }
+
+    // Blurs the bloombuffer and adds it back onto the framebuffer. This needs to happen before
+    // anything else is drawn over the scene, like the darkening behind menus.
+    pub fn render_bloom(&self, encoder: &mut wgpu::CommandEncoder, context: &RenderContext) {
+        if !self.effects.contains(&Effect::Bloom) {
+            return;
+        }
+
+        // Vertical blur into a second texture.
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &context.bloombuffer_after_vertical,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(&self.bloom_blur_pipeline);
+        render_pass.set_bind_group(0, &context.bloom_first_pass_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+
+        drop(render_pass);
+
+        // Horizontal blur, composited straight onto the framebuffer.
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: &context.framebuffer,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(&self.bloom_blur_pipeline);
+        render_pass.set_bind_group(0, &context.bloom_second_pass_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    // Draws the framebuffer to the screen, applying the tone mapping and vignette on the way. This
+    // also stretches it to fit the window when the render scale isn't 100%.
+    pub fn render_composite(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        context: &RenderContext,
+        output: &wgpu::TextureView,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(&self.composite_pipeline);
+        render_pass.set_bind_group(0, &context.framebuffer_bind_group, &[]);
+        #[cfg(feature = "hdr")]
+        render_pass.set_bind_group(1, &self.composite_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+pub(super) fn create_full_screen_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    layout: &wgpu::PipelineLayout,
+    vs_module: &wgpu::ShaderModule,
+    fs_module: &wgpu::ShaderModule,
+    colour_state: wgpu::ColorStateDescriptor,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex_stage: wgpu::ProgrammableStageDescriptor {
+            module: vs_module,
+            entry_point: "main",
+        },
+        fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+            module: fs_module,
+            entry_point: "main",
+        }),
+        rasterization_state: Some(wgpu::RasterizationStateDescriptor::default()),
+        primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+        color_states: &[colour_state],
+        depth_stencil_state: None,
+        vertex_state: wgpu::VertexStateDescriptor {
+            index_format: INDEX_FORMAT,
+            vertex_buffers: &[],
+        },
+        sample_count: 1,
+        sample_mask: !0,
+        alpha_to_coverage_enabled: false,
+    })
+}
+
+#[test]
+fn effects_that_need_the_hdr_feature_are_left_out_without_it() {
+    let settings = Settings {
+        bloom: false,
+        ..Settings::default()
+    };
+
+    let expected = if cfg!(feature = "hdr") {
+        vec![Effect::ToneMapping, Effect::Vignette]
+    } else {
+        Vec::new()
+    };
+
+    assert_eq!(effect_chain(&settings), expected);
+    assert_eq!(effect_chain(&Settings::default())[0], Effect::Bloom);
+}
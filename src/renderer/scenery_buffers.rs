@@ -0,0 +1,122 @@
+use super::{ModelInstance, ModelPipelines, RenderContext, ShadowPipeline};
+use crate::assets::{Assets, Model};
+use crate::scenery::{Prop, Scenery};
+use crate::terrain::Terrain;
+use ultraviolet::{Mat4, Vec4};
+use wgpu::util::DeviceExt;
+
+// A buffer of instances that's written once and then drawn for as long as it's needed, instead of
+// being refilled every frame like a `DynamicBuffer`.
+pub struct PersistentBuffer {
+    pub(super) buffer: wgpu::Buffer,
+    pub(super) len: u32,
+}
+
+impl PersistentBuffer {
+    fn new(device: &wgpu::Device, instances: &[ModelInstance], label: &str) -> Self {
+        Self {
+            buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::cast_slice(instances),
+                usage: wgpu::BufferUsage::VERTEX,
+            }),
+            len: instances.len() as u32,
+        }
+    }
+}
+
+// One buffer per prop, so each kind of prop is drawn with a single call. See `scenery`.
+#[derive(Default)]
+pub struct SceneryBuffers {
+    buffers: Vec<(Prop, PersistentBuffer)>,
+    // Which version of the scenery is in the buffers.
+    generation: Option<u32>,
+}
+
+impl SceneryBuffers {
+    // Only does anything when the scenery has changed since the last time.
+    pub fn upload(&mut self, context: &RenderContext, scenery: &Scenery, terrain: &Terrain) {
+        if self.generation == Some(scenery.generation()) {
+            return;
+        }
+        self.generation = Some(scenery.generation());
+
+        self.buffers = Prop::ALL
+            .iter()
+            .filter_map(|&prop| {
+                let instances: Vec<ModelInstance> = scenery
+                    .props()
+                    .iter()
+                    .filter(|instance| instance.prop == prop)
+                    .map(|instance| ModelInstance {
+                        transform: Mat4::from_translation(terrain.ground(instance.position.into()))
+                            * Mat4::from_rotation_y(instance.facing)
+                            * Mat4::from_scale(instance.scale * prop_scale(prop)),
+                        flat_colour: Vec4::one(),
+                    })
+                    .collect();
+
+                if instances.is_empty() {
+                    return None;
+                }
+
+                let buffer = PersistentBuffer::new(
+                    &context.device,
+                    &instances,
+                    &format!("Cheese {:?} scenery buffer", prop),
+                );
+                Some((prop, buffer))
+            })
+            .collect();
+    }
+
+    pub fn render<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        model_pipelines: &'a ModelPipelines,
+        assets: &'a Assets,
+    ) {
+        for (prop, buffer) in &self.buffers {
+            // Rocks are squashed cheese moons, like the obstacles.
+            let texture = match prop {
+                Prop::Rock => &assets.surface_texture,
+                Prop::CheeseWheel | Prop::GrassTuft => &assets.props_texture,
+            };
+
+            model_pipelines.render_static_instances(
+                render_pass,
+                buffer,
+                texture,
+                prop_model(*prop, assets),
+            );
+        }
+    }
+
+    pub fn render_shadows<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        shadow_pipeline: &'a ShadowPipeline,
+        assets: &'a Assets,
+    ) {
+        for (prop, buffer) in &self.buffers {
+            shadow_pipeline.render_static_instances(render_pass, prop_model(*prop, assets), buffer);
+        }
+    }
+}
+
+fn prop_model(prop: Prop, assets: &Assets) -> &Model {
+    match prop {
+        Prop::Rock => &assets.cheese_moon_model,
+        Prop::CheeseWheel => &assets.cheese_wheel_model,
+        Prop::GrassTuft => &assets.grass_tuft_model,
+    }
+}
+
+// How big each prop is at a `scale` of 1. The cheese moon is a lot bigger than a pebble.
+fn prop_scale(prop: Prop) -> f32 {
+    match prop {
+        Prop::Rock => 0.4,
+        Prop::CheeseWheel => 0.8,
+        Prop::GrassTuft => 1.0,
+    }
+}
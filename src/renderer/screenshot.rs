@@ -0,0 +1,409 @@
+// Saving what's on the screen as pngs: one at a time, or every frame while recording (for turning a
+// battle into a gif). The swap chain texture can't be copied from, so frames that are being captured
+// get composited into an offscreen texture instead, which is then copied into a buffer and drawn onto
+// the swap chain as normal. The buffers are read back once the gpu has finished with them, and the
+// pngs are written out on another thread so that capturing doesn't stall the game.
+//
+// Everything goes in `screenshots/`, named after when it was taken (in UTC).
+
+use super::post_processing::create_full_screen_pipeline;
+use super::{colour_state_descriptor, RenderContext, DISPLAY_FORMAT};
+use crate::hot_reload::shader;
+use futures::FutureExt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DIRECTORY: &str = "screenshots";
+// Recording stops by itself after this many frames, so it can't fill up the disk if it's forgotten
+// about.
+const MAX_RECORDED_FRAMES: u32 = 1200;
+
+type Mapping = Pin<Box<dyn Future<Output = Result<(), wgpu::BufferAsyncError>> + Send>>;
+
+// A frame that's been copied into a buffer, waiting to be read back.
+struct Capture {
+    buffer: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    // Rows of the copy have to be a multiple of 256 bytes long.
+    padded_bytes_per_row: u32,
+    path: PathBuf,
+    // Only set once the commands doing the copy have been submitted.
+    mapping: Option<Mapping>,
+}
+
+struct Recording {
+    directory: PathBuf,
+    frames: u32,
+}
+
+// What the frame is composited into when it's being captured.
+struct Target {
+    texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    bind_group: wgpu::BindGroup,
+    width: u32,
+    height: u32,
+}
+
+pub struct Screenshots {
+    // Draws the captured frame onto the swap chain.
+    blit_pipeline: wgpu::RenderPipeline,
+    target: Option<Target>,
+    screenshot_requested: bool,
+    recording: Option<Recording>,
+    // The path the current frame is being captured to, if it is.
+    capturing: Option<PathBuf>,
+    captures: Vec<Capture>,
+}
+
+impl Screenshots {
+    pub fn new(context: &RenderContext) -> Self {
+        let device = &context.device;
+
+        let vs_module = device.create_shader_module(shader!("full_screen_quad.vert.spv"));
+        let fs_module = device.create_shader_module(shader!("post_processing.frag.spv"));
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Cheese screenshot blit pipeline layout"),
+            bind_group_layouts: &[&context.framebuffer_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let blit_pipeline = create_full_screen_pipeline(
+            device,
+            "Cheese screenshot blit pipeline",
+            &pipeline_layout,
+            &vs_module,
+            &fs_module,
+            wgpu::ColorStateDescriptor {
+                format: DISPLAY_FORMAT,
+                ..colour_state_descriptor(false)
+            },
+        );
+
+        Self {
+            blit_pipeline,
+            target: None,
+            screenshot_requested: false,
+            recording: None,
+            capturing: None,
+            captures: Vec::new(),
+        }
+    }
+
+    pub fn take_screenshot(&mut self) {
+        self.screenshot_requested = true;
+    }
+
+    pub fn toggle_recording(&mut self) {
+        self.recording = match self.recording.take() {
+            Some(recording) => {
+                log::info!(
+                    "Recorded {} frames to {}",
+                    recording.frames,
+                    recording.directory.display()
+                );
+                None
+            }
+            None => {
+                let directory = Path::new(DIRECTORY).join(format!("recording_{}", timestamp()));
+                log::info!("Recording frames to {}", directory.display());
+                Some(Recording {
+                    directory,
+                    frames: 0,
+                })
+            }
+        };
+    }
+
+    // Where the frame should be composited to: the swap chain, or the offscreen texture if the frame
+    // is being captured. Call `end_frame` once everything has been drawn.
+    pub fn begin_frame<'a>(
+        &'a mut self,
+        context: &RenderContext,
+        swap_chain_view: &'a wgpu::TextureView,
+    ) -> &'a wgpu::TextureView {
+        self.capturing = if std::mem::take(&mut self.screenshot_requested) {
+            Some(Path::new(DIRECTORY).join(format!("screenshot_{}.png", timestamp())))
+        } else if let Some(recording) = &mut self.recording {
+            recording.frames += 1;
+            Some(
+                recording
+                    .directory
+                    .join(format!("frame_{:05}.png", recording.frames)),
+            )
+        } else {
+            None
+        };
+
+        if self
+            .recording
+            .as_ref()
+            .is_some_and(|recording| recording.frames >= MAX_RECORDED_FRAMES)
+        {
+            self.toggle_recording();
+        }
+
+        if self.capturing.is_none() {
+            return swap_chain_view;
+        }
+
+        let dimensions = context.screen_dimensions();
+        let (width, height) = (dimensions.width, dimensions.height);
+
+        if !matches!(&self.target, Some(target) if (target.width, target.height) == (width, height))
+        {
+            self.target = Some(create_target(context, width, height));
+        }
+
+        &self.target.as_ref().unwrap().view
+    }
+
+    // Copies the captured frame into a buffer and draws it onto the swap chain.
+    pub fn end_frame(
+        &mut self,
+        context: &RenderContext,
+        encoder: &mut wgpu::CommandEncoder,
+        swap_chain_view: &wgpu::TextureView,
+    ) {
+        let path = match self.capturing.take() {
+            Some(path) => path,
+            None => return,
+        };
+        let target = self.target.as_ref().unwrap();
+
+        let padded_bytes_per_row = padded_bytes_per_row(target.width);
+
+        let buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cheese screenshot buffer"),
+            size: (padded_bytes_per_row * target.height) as u64,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: &target.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::BufferCopyView {
+                buffer: &buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: padded_bytes_per_row,
+                    rows_per_image: target.height,
+                },
+            },
+            wgpu::Extent3d {
+                width: target.width,
+                height: target.height,
+                depth: 1,
+            },
+        );
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: swap_chain_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+
+        render_pass.set_pipeline(&self.blit_pipeline);
+        render_pass.set_bind_group(0, &target.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+        drop(render_pass);
+
+        self.captures.push(Capture {
+            buffer,
+            width: target.width,
+            height: target.height,
+            padded_bytes_per_row,
+            path,
+            mapping: None,
+        });
+    }
+
+    // Call after submitting the frame. Starts reading back the frames that have just been submitted
+    // and saves the ones that are ready.
+    pub fn save_finished(&mut self, context: &RenderContext) {
+        for capture in &mut self.captures {
+            if capture.mapping.is_none() {
+                capture.mapping = Some(Box::pin(
+                    capture.buffer.slice(..).map_async(wgpu::MapMode::Read),
+                ));
+            }
+        }
+
+        context.device.poll(wgpu::Maintain::Poll);
+
+        let mut index = 0;
+        while index < self.captures.len() {
+            let ready = match self.captures[index]
+                .mapping
+                .as_mut()
+                .unwrap()
+                .now_or_never()
+            {
+                Some(result) => result,
+                None => {
+                    index += 1;
+                    continue;
+                }
+            };
+
+            let capture = self.captures.remove(index);
+
+            match ready {
+                Ok(()) => save(capture),
+                Err(_) => log::error!("Failed to read back {}", capture.path.display()),
+            }
+        }
+    }
+}
+
+fn create_target(context: &RenderContext, width: u32, height: u32) -> Target {
+    let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Cheese screenshot texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: DISPLAY_FORMAT,
+        usage: wgpu::TextureUsage::OUTPUT_ATTACHMENT
+            | wgpu::TextureUsage::SAMPLED
+            | wgpu::TextureUsage::COPY_SRC,
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+    let bind_group = context
+        .device
+        .create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Cheese screenshot bind group"),
+            layout: &context.framebuffer_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&context.framebuffer_sampler),
+                },
+            ],
+        });
+
+    Target {
+        texture,
+        view,
+        bind_group,
+        width,
+        height,
+    }
+}
+
+fn padded_bytes_per_row(width: u32) -> u32 {
+    let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let bytes_per_row = width * 4;
+    bytes_per_row.div_ceil(alignment) * alignment
+}
+
+// Takes the pixels out of the buffer and writes them out on another thread.
+fn save(capture: Capture) {
+    let unpadded_bytes_per_row = (capture.width * 4) as usize;
+
+    let mut pixels = Vec::with_capacity(unpadded_bytes_per_row * capture.height as usize);
+    {
+        let mapped = capture.buffer.slice(..).get_mapped_range();
+        for row in mapped.chunks(capture.padded_bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..unpadded_bytes_per_row]);
+        }
+    }
+    capture.buffer.unmap();
+
+    std::thread::spawn(move || {
+        // The swap chain format is BGRA.
+        for pixel in pixels.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+
+        let result = capture
+            .path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .map_err(anyhow::Error::from)
+            .and_then(|()| {
+                image::save_buffer(
+                    &capture.path,
+                    &pixels,
+                    capture.width,
+                    capture.height,
+                    image::ColorType::Rgba8,
+                )
+                .map_err(anyhow::Error::from)
+            });
+
+        match result {
+            Ok(()) => log::info!("Saved {}", capture.path.display()),
+            Err(error) => log::error!("Failed to save {}: {}", capture.path.display(), error),
+        }
+    });
+}
+
+// E.g. `2020-11-05_18-30-02`, so that the files sort by when they were taken.
+fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+
+    format_timestamp(seconds)
+}
+
+fn format_timestamp(seconds: u64) -> String {
+    let days = (seconds / 86_400) as i64;
+    let time = seconds % 86_400;
+
+    // Days since 1970 to a date, from http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{}-{:02}-{:02}_{:02}-{:02}-{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+#[test]
+fn timestamps_are_readable_dates() {
+    assert_eq!(format_timestamp(0), "1970-01-01_00-00-00");
+    assert_eq!(format_timestamp(951_782_400 + 3661), "2000-02-29_01-01-01");
+    assert_eq!(format_timestamp(1_604_601_002), "2020-11-05_18-30-02");
+    assert_eq!(padded_bytes_per_row(1280), 5120);
+    assert_eq!(padded_bytes_per_row(1), 256);
+}
@@ -1,8 +1,9 @@
 use super::{
-    draw_model, AnimatedVertex, DynamicBuffer, ModelInstance, RenderContext, Vertex, DEPTH_FORMAT,
-    INDEX_FORMAT,
+    draw_model, AnimatedVertex, DynamicBuffer, ModelInstance, PersistentBuffer, RenderContext,
+    Vertex, INDEX_FORMAT, SHADOW_FORMAT,
 };
 use crate::assets::{AnimatedModel, Model};
+use crate::hot_reload::shader;
 use std::sync::Arc;
 
 pub struct ShadowPipeline {
@@ -14,12 +15,10 @@ pub struct ShadowPipeline {
 
 impl ShadowPipeline {
     pub fn new(context: &RenderContext) -> Self {
-        let vs_shadow_static =
-            wgpu::include_spirv!("../../shaders/compiled/shadow_static.vert.spv");
+        let vs_shadow_static = shader!("shadow_static.vert.spv");
         let vs_shadow_static_module = context.device.create_shader_module(vs_shadow_static);
 
-        let vs_shadow_animated =
-            wgpu::include_spirv!("../../shaders/compiled/shadow_animated.vert.spv");
+        let vs_shadow_animated = shader!("shadow_animated.vert.spv");
         let vs_shadow_animated_module = context.device.create_shader_module(vs_shadow_animated);
 
         let static_pipeline_layout =
@@ -47,7 +46,7 @@ impl ShadowPipeline {
                 primitive_topology: wgpu::PrimitiveTopology::TriangleList,
                 color_states: &[],
                 depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
-                    format: DEPTH_FORMAT,
+                    format: SHADOW_FORMAT,
                     depth_write_enabled: true,
                     depth_compare: wgpu::CompareFunction::Less,
                     stencil: wgpu::StencilStateDescriptor::default(),
@@ -100,7 +99,7 @@ impl ShadowPipeline {
                 primitive_topology: wgpu::PrimitiveTopology::TriangleList,
                 color_states: &[],
                 depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
-                    format: DEPTH_FORMAT,
+                    format: SHADOW_FORMAT,
                     depth_write_enabled: true,
                     depth_compare: wgpu::CompareFunction::Less,
                     stencil: wgpu::StencilStateDescriptor::default(),
@@ -146,6 +145,22 @@ impl ShadowPipeline {
         }
     }
 
+    pub(super) fn render_static_instances<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        model: &'a Model,
+        instances: &'a PersistentBuffer,
+    ) {
+        render_pass.set_pipeline(&self.static_pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        draw_model(
+            render_pass,
+            model,
+            instances.buffer.slice(..),
+            instances.len,
+        );
+    }
+
     pub fn render_animated<'a>(
         &'a self,
         render_pass: &mut wgpu::RenderPass<'a>,
@@ -0,0 +1,198 @@
+use super::model_pipelines::JointBuffer;
+use super::{DynamicBuffer, RenderContext};
+use crate::animation::{PackedAnimations, SkinningInstance, SkinningUniforms};
+use crate::assets::AnimationInfo;
+use crate::hot_reload::shader;
+use ultraviolet::Mat4;
+use wgpu::util::DeviceExt;
+
+// Has to match `local_size_x` in shaders/skinning.comp.
+const WORKGROUP_SIZE: u32 = 64;
+
+// Computes joint matrices straight into the joint buffers of animated models, so that the cpu only
+// has to upload which clip each instance is playing and how far through it is.
+
+pub struct SkinningPipeline {
+    pipeline: wgpu::ComputePipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+}
+
+impl SkinningPipeline {
+    pub fn new(context: &RenderContext) -> Self {
+        let storage_buffer = |binding, readonly| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::COMPUTE,
+            ty: wgpu::BindingType::StorageBuffer {
+                dynamic: false,
+                min_binding_size: None,
+                readonly,
+            },
+            count: None,
+        };
+
+        let bind_group_layout =
+            context
+                .device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    label: Some("Cheese skinning bind group layout"),
+                    entries: &[
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStage::COMPUTE,
+                            ty: wgpu::BindingType::UniformBuffer {
+                                dynamic: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        // Instances.
+                        storage_buffer(1, true),
+                        // Packed skeleton and animation data.
+                        storage_buffer(2, true),
+                        // Joint matrices.
+                        storage_buffer(3, false),
+                    ],
+                });
+
+        let pipeline_layout =
+            context
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Cheese skinning pipeline layout"),
+                    bind_group_layouts: &[&bind_group_layout],
+                    push_constant_ranges: &[],
+                });
+
+        let cs = shader!("skinning.comp.spv");
+        let cs_module = context.device.create_shader_module(cs);
+
+        let pipeline = context
+            .device
+            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Cheese skinning pipeline"),
+                layout: Some(&pipeline_layout),
+                compute_stage: wgpu::ProgrammableStageDescriptor {
+                    module: &cs_module,
+                    entry_point: "main",
+                },
+            });
+
+        Self {
+            pipeline,
+            bind_group_layout,
+        }
+    }
+
+    // Needs to happen after the joint buffers have been uploaded but before anything is rendered.
+    pub fn compute(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        device: &wgpu::Device,
+        joint_buffers: &[&JointBuffer],
+    ) {
+        let bind_groups: Vec<_> = joint_buffers
+            .iter()
+            .filter(|joint_buffer| joint_buffer.skinning.instances.len > 0)
+            .map(|joint_buffer| {
+                let skinning = &joint_buffer.skinning;
+
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("Cheese skinning bind group"),
+                    layout: &self.bind_group_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::Buffer(
+                                skinning.uniform_buffer.slice(..),
+                            ),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Buffer(
+                                skinning.instances.buffer.slice(..),
+                            ),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: wgpu::BindingResource::Buffer(skinning.data.slice(..)),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: wgpu::BindingResource::Buffer(
+                                joint_buffer.buffer.buffer.slice(..),
+                            ),
+                        },
+                    ],
+                });
+
+                (bind_group, skinning.instances.len as u32)
+            })
+            .collect();
+
+        let mut compute_pass = encoder.begin_compute_pass();
+        compute_pass.set_pipeline(&self.pipeline);
+
+        for (bind_group, num_instances) in &bind_groups {
+            compute_pass.set_bind_group(0, bind_group, &[]);
+            compute_pass.dispatch(num_instances.div_ceil(WORKGROUP_SIZE), 1, 1);
+        }
+    }
+}
+
+// The gpu side of an animated model's skeleton and clips, plus the instances to compute joints for.
+pub struct SkinnedModel {
+    uniforms: SkinningUniforms,
+    uniform_buffer: wgpu::Buffer,
+    data: wgpu::Buffer,
+    instances: DynamicBuffer<SkinningInstance>,
+}
+
+impl SkinnedModel {
+    pub fn new(context: &RenderContext, capacity: usize, animation_info: &AnimationInfo) -> Self {
+        let packed = PackedAnimations::new(&animation_info.skin, &animation_info.animations);
+
+        Self {
+            uniforms: packed.uniforms,
+            uniform_buffer: context
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Cheese skinning uniform buffer"),
+                    contents: bytemuck::bytes_of(&packed.uniforms),
+                    usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                }),
+            data: context
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Cheese skinning data buffer"),
+                    contents: bytemuck::cast_slice(&packed.data),
+                    usage: wgpu::BufferUsage::STORAGE,
+                }),
+            instances: DynamicBuffer::new(
+                &context.device,
+                capacity,
+                "Cheese skinning instances buffer",
+                wgpu::BufferUsage::STORAGE,
+            ),
+        }
+    }
+
+    pub fn push(&mut self, instance: SkinningInstance) {
+        self.instances.push(instance);
+    }
+
+    // Upload the instances and make room for their joint matrices. Returns whether the joint
+    // buffer was resized.
+    pub fn upload(&mut self, context: &RenderContext, joints: &mut DynamicBuffer<Mat4>) -> bool {
+        self.instances.upload(context);
+
+        self.uniforms.num_instances = self.instances.len as u32;
+        context
+            .queue
+            .write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&self.uniforms));
+
+        joints.reserve(
+            &context.device,
+            self.instances.len * self.uniforms.num_joints as usize,
+        )
+    }
+}
@@ -0,0 +1,299 @@
+use super::model_pipelines::create_render_pipeline;
+use super::{draw_model, ModelInstance, RenderContext, Sunlight, Vertex};
+use crate::assets::{Assets, Material, Model};
+use crate::hot_reload::shader;
+use crate::resources::TimeOfDay;
+use image::RgbaImage;
+use std::sync::Arc;
+use ultraviolet::{Mat4, Vec2, Vec3, Vec4};
+use wgpu::util::DeviceExt;
+
+// The sky is a big cube that follows the camera around, drawn before anything else without writing
+// any depth so that the whole scene ends up in front of it. Its texture is a cubemap laid out as a
+// strip of six square faces (+x, -x, +y, -y, +z, -z), which starts out as the starry
+// `textures/skybox.png`. With `dynamic_sky` on, a glow around the horizon and a disc for the sun (or
+// the moon) are painted over the stars on the cpu, and repainted as the day goes by.
+
+// Has to fit inside the far plane, even at the corners.
+const SKY_DISTANCE: f32 = 120.0;
+
+// The sky gets repainted this many times a day.
+const REPAINTS_PER_DAY: f32 = 400.0;
+
+// How big the sun looks, as the cosine of its angular radius.
+const SUN_SIZE: f32 = 0.9985;
+
+const HORIZON_COLOUR: Vec3 = Vec3::new(0.35, 0.4, 0.5);
+const DUSK_HORIZON_COLOUR: Vec3 = Vec3::new(0.6, 0.3, 0.2);
+const GROUND_COLOUR: Vec3 = Vec3::new(0.08, 0.07, 0.06);
+
+pub struct SkyPipeline {
+    pipeline: wgpu::RenderPipeline,
+    main_bind_group: Arc<wgpu::BindGroup>,
+    model: Model,
+    instance_buffer: wgpu::Buffer,
+    stars: RgbaImage,
+    texture: wgpu::BindGroup,
+    // Which time of day the texture was painted for, or `None` if it's just the stars.
+    painted_for: Option<i32>,
+}
+
+impl SkyPipeline {
+    pub fn new(context: &RenderContext, assets: &Assets) -> Self {
+        let device = &context.device;
+
+        // The sky doesn't glow, as the instance's alpha is 0.
+        let fs_module = device.create_shader_module(shader!("transparent_textured_bloom.frag.spv"));
+
+        let pipeline = create_render_pipeline(
+            device,
+            &[
+                &context.main_bind_group_layout,
+                &assets.material_bind_group_layout,
+            ],
+            "Cheese sky pipeline",
+            &context.vs_transparent_module,
+            &fs_module,
+            false,
+            false,
+            context.sample_count(),
+        );
+
+        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese sky instance buffer"),
+            contents: bytemuck::bytes_of(&ModelInstance::default()),
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+        });
+
+        Self {
+            pipeline,
+            main_bind_group: context.main_bind_group.clone(),
+            model: create_sky_cube(device),
+            instance_buffer,
+            texture: create_sky_texture(context, assets, &assets.skybox),
+            stars: assets.skybox.clone(),
+            painted_for: None,
+        }
+    }
+
+    // Moves the sky to where the camera is and repaints it if the sun has moved far enough.
+    pub fn update(
+        &mut self,
+        context: &RenderContext,
+        assets: &Assets,
+        time_of_day: &TimeOfDay,
+        dynamic_sky: bool,
+    ) {
+        let camera_position = context.view().inversed().cols[3].xyz();
+
+        context.queue.write_buffer(
+            &self.instance_buffer,
+            0,
+            bytemuck::bytes_of(&ModelInstance {
+                flat_colour: Vec4::new(1.0, 1.0, 1.0, 0.0),
+                transform: Mat4::from_translation(camera_position) * Mat4::from_scale(SKY_DISTANCE),
+            }),
+        );
+
+        let paint_for = if dynamic_sky {
+            // Always day when the day/night cycle is off.
+            Some(
+                time_of_day
+                    .0
+                    .map_or(-1, |time| (time * REPAINTS_PER_DAY) as i32),
+            )
+        } else {
+            None
+        };
+
+        if paint_for == self.painted_for {
+            return;
+        }
+        self.painted_for = paint_for;
+
+        let image = match paint_for {
+            Some(_) => paint_sky(&self.stars, &Sunlight::at(time_of_day), time_of_day),
+            None => self.stars.clone(),
+        };
+        self.texture = create_sky_texture(context, assets, &image);
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.texture, &[]);
+        draw_model(render_pass, &self.model, self.instance_buffer.slice(..), 1);
+    }
+}
+
+fn create_sky_texture(
+    context: &RenderContext,
+    assets: &Assets,
+    image: &RgbaImage,
+) -> wgpu::BindGroup {
+    let mut encoder = context
+        .device
+        .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Cheese sky texture encoder"),
+        });
+
+    let bind_group = Material::new(image.clone()).create_bind_group(
+        "Cheese sky texture",
+        &assets.material_bind_group_layout,
+        &context.device,
+        &mut encoder,
+    );
+
+    context.queue.submit(Some(encoder.finish()));
+
+    bind_group
+}
+
+// Which way a point on one of the faces of the cube is, with `point` going from -1 to 1 across the
+// face. `x` goes to the right and `y` goes down the face in the texture, the same as a cubemap.
+fn face_direction(face: usize, point: Vec2) -> Vec3 {
+    let Vec2 { x: s, y: t } = point;
+
+    match face {
+        0 => Vec3::new(1.0, -t, -s),
+        1 => Vec3::new(-1.0, -t, s),
+        2 => Vec3::new(s, 1.0, t),
+        3 => Vec3::new(s, -1.0, -t),
+        4 => Vec3::new(s, -t, 1.0),
+        _ => Vec3::new(-s, -t, -1.0),
+    }
+}
+
+fn create_sky_cube(device: &wgpu::Device) -> Model {
+    let corners = [
+        Vec2::new(-1.0, -1.0),
+        Vec2::new(1.0, -1.0),
+        Vec2::new(1.0, 1.0),
+        Vec2::new(-1.0, 1.0),
+    ];
+
+    let vertices: Vec<Vertex> = (0..6)
+        .flat_map(|face| {
+            corners.iter().map(move |&corner| {
+                let position = face_direction(face, corner);
+
+                Vertex {
+                    position,
+                    normal: -face_direction(face, Vec2::zero()),
+                    uv: Vec2::new(
+                        (face as f32 + (corner.x + 1.0) / 2.0) / 6.0,
+                        (corner.y + 1.0) / 2.0,
+                    ),
+                    // The sky isn't lit, so this doesn't matter.
+                    tangent: Vec4::zero(),
+                }
+            })
+        })
+        .collect();
+
+    // The faces need to be counter-clockwise when seen from inside the cube, and which way round
+    // that is depends on the face.
+    let indices: Vec<u32> = (0..6)
+        .flat_map(|face| {
+            let first = face as u32 * 4;
+            let position = |i: u32| vertices[(first + i) as usize].position;
+            let (a, b, c) = (position(0), position(1), position(2));
+            let facing_inwards = (b - a).cross(c - a).dot(a) < 0.0;
+
+            if facing_inwards {
+                vec![first, first + 1, first + 2, first, first + 2, first + 3]
+            } else {
+                vec![first, first + 2, first + 1, first, first + 3, first + 2]
+            }
+        })
+        .collect();
+
+    Model {
+        vertices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese sky vertices"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        }),
+        indices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese sky indices"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsage::INDEX,
+        }),
+        num_indices: indices.len() as u32,
+        lods: Vec::new(),
+    }
+}
+
+// There's no air on the moon, so the stars are always out, but they're harder to see during the day
+// over the glow of the horizon.
+fn paint_sky(stars: &RgbaImage, sunlight: &Sunlight, time_of_day: &TimeOfDay) -> RgbaImage {
+    let face_size = stars.height();
+    let sun_direction = sunlight.direction.normalized();
+    let darkness = time_of_day.darkness();
+    let sunset = 1.0 - (time_of_day.sun_height().abs() / 0.4).min(1.0);
+
+    let horizon =
+        (HORIZON_COLOUR + (DUSK_HORIZON_COLOUR - HORIZON_COLOUR) * sunset) * (1.0 - darkness * 0.8);
+    let star_brightness = 0.3 + darkness * 0.7;
+
+    RgbaImage::from_fn(stars.width(), face_size, |x, y| {
+        let face = (x / face_size) as usize;
+        let point = Vec2::new(
+            ((x % face_size) as f32 + 0.5) / face_size as f32,
+            (y as f32 + 0.5) / face_size as f32,
+        ) * 2.0
+            - Vec2::one();
+        let direction = face_direction(face, point).normalized();
+
+        let star = stars.get_pixel(x, y).0;
+        let star = Vec3::new(star[0] as f32, star[1] as f32, star[2] as f32) / 255.0;
+
+        let colour = if direction.y < 0.0 {
+            // Below the horizon, where there's nothing but the ground past the edge of the map.
+            GROUND_COLOUR + (horizon - GROUND_COLOUR) * (1.0 + direction.y * 8.0).max(0.0)
+        } else {
+            let glow = (1.0 - direction.y).powi(6);
+            let alignment = direction.dot(sun_direction);
+            let disc = if alignment > SUN_SIZE { 1.0 } else { 0.0 };
+            let halo = alignment.max(0.0).powi(200) * 0.5;
+
+            star * star_brightness * (1.0 - glow) + horizon * glow + sunlight.colour * (disc + halo)
+        };
+
+        let encode = |value: f32| (value.min(1.0) * 255.0) as u8;
+        image::Rgba([encode(colour.x), encode(colour.y), encode(colour.z), 255])
+    })
+}
+
+#[test]
+fn sky_cube_faces_point_inwards() {
+    for face in 0..6 {
+        let center = face_direction(face, Vec2::zero());
+        let right = face_direction(face, Vec2::new(1.0, 0.0)) - center;
+        let down = face_direction(face, Vec2::new(0.0, 1.0)) - center;
+
+        // Each face is a flat square one unit out from the middle of the cube.
+        assert_eq!(center.mag(), 1.0);
+        assert_eq!(right.dot(center), 0.0);
+        assert_eq!(down.dot(center), 0.0);
+        assert_eq!(right.cross(down).mag(), 1.0);
+    }
+}
+
+#[test]
+fn the_sun_is_painted_where_it_is() {
+    let stars = RgbaImage::new(6 * 64, 64);
+    let time_of_day = TimeOfDay(Some(0.5));
+    let sunlight = Sunlight::at(&time_of_day);
+    let sky = paint_sky(&stars, &sunlight, &time_of_day);
+
+    // At noon the sun is high up in the sky, so it shows up on the top face.
+    let brightest = sky
+        .enumerate_pixels()
+        .max_by_key(|(_, _, pixel)| pixel.0[0] as u32 + pixel.0[1] as u32 + pixel.0[2] as u32)
+        .unwrap();
+    assert_eq!(brightest.0 / 64, 2);
+
+    let below = *sky.get_pixel(3 * 64 + 32, 32);
+    assert!(below.0[0] < brightest.2 .0[0]);
+}
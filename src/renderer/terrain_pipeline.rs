@@ -0,0 +1,65 @@
+use super::model_pipelines::create_render_pipeline;
+use super::{draw_model, RenderContext};
+use crate::assets::Assets;
+use crate::hot_reload::shader;
+use std::sync::Arc;
+
+// The terrain is a single big mesh with a texture that's been splatted together on the cpu (see
+// `Terrain::splat_texture`), so it can be lit and shadowed the same way as any other static model.
+
+pub struct TerrainPipeline {
+    pipeline: wgpu::RenderPipeline,
+    main_bind_group: Arc<wgpu::BindGroup>,
+    shadow_uniform_bind_group: Arc<wgpu::BindGroup>,
+    identity_instance_buffer: Arc<wgpu::Buffer>,
+}
+
+impl TerrainPipeline {
+    pub fn new(context: &RenderContext, assets: &Assets) -> Self {
+        // Lit the same way as the other static models, see `ModelPipelines`.
+        #[cfg(feature = "material-maps")]
+        let (vs, fs) = (shader!("material.vert.spv"), shader!("material.frag.spv"));
+        #[cfg(not(feature = "material-maps"))]
+        let (vs, fs) = (
+            shader!("static_model.vert.spv"),
+            shader!("textured.frag.spv"),
+        );
+        let vs_module = context.device.create_shader_module(vs);
+        let fs_module = context.device.create_shader_module(fs);
+
+        let pipeline = create_render_pipeline(
+            &context.device,
+            &[
+                &context.main_bind_group_layout,
+                &assets.material_bind_group_layout,
+                &context.shadow_uniform_bind_group_layout,
+            ],
+            "Cheese terrain pipeline",
+            &vs_module,
+            &fs_module,
+            false,
+            true,
+            context.sample_count(),
+        );
+
+        Self {
+            pipeline,
+            main_bind_group: context.main_bind_group.clone(),
+            shadow_uniform_bind_group: context.shadow_uniform_bind_group.clone(),
+            identity_instance_buffer: context.identity_instance_buffer.clone(),
+        }
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, assets: &'a Assets) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+        render_pass.set_bind_group(1, &assets.terrain_texture, &[]);
+        render_pass.set_bind_group(2, &self.shadow_uniform_bind_group, &[]);
+        draw_model(
+            render_pass,
+            &assets.terrain_model,
+            self.identity_instance_buffer.slice(..),
+            1,
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use super::{RenderContext, DISPLAY_FORMAT};
+use ultraviolet::{Vec2, Vec4};
+use wgpu_glyph::ab_glyph::FontRef;
+use wgpu_glyph::{
+    BuiltInLineBreaker, FontId, GlyphBrush, GlyphBrushBuilder, HorizontalAlign, Layout, Section,
+    Text, VerticalAlign,
+};
+
+// All the text in the game goes through here. `wgpu_glyph` rasterizes the glyphs of the fonts into
+// an atlas texture as they're needed and batches up a quad per glyph, so all the text queued in a
+// frame gets drawn in a single pass at the end.
+pub struct TextBuffer {
+    glyph_brush: GlyphBrush<(), FontRef<'static>>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Font {
+    Ui = 0,
+    Title = 1,
+}
+
+impl Font {
+    pub fn scale(&self) -> f32 {
+        match self {
+            Self::Ui => 24.0,
+            Self::Title => 64.0,
+        }
+    }
+}
+
+pub enum TextAlignment {
+    Default,
+    Center,
+    HorizontalRight,
+    CenterLeft,
+}
+
+impl TextAlignment {
+    fn layout(&self) -> Layout<BuiltInLineBreaker> {
+        match self {
+            Self::Default => Layout::default(),
+            Self::Center => Layout::default()
+                .h_align(HorizontalAlign::Center)
+                .v_align(VerticalAlign::Center),
+            Self::HorizontalRight => Layout::default().h_align(HorizontalAlign::Right),
+            Self::CenterLeft => Layout::default().v_align(VerticalAlign::Center),
+        }
+    }
+}
+
+impl TextBuffer {
+    pub fn new(device: &wgpu::Device) -> anyhow::Result<Self> {
+        let fonts = vec![
+            FontRef::try_from_slice(include_bytes!(
+                "../../fonts/Roboto_Mono/RobotoMono-Bold.ttf"
+            ))?,
+            FontRef::try_from_slice(include_bytes!("../../fonts/Chewy/Chewy-Regular.ttf"))?,
+        ];
+
+        let glyph_brush = GlyphBrushBuilder::using_fonts(fonts).build(device, DISPLAY_FORMAT);
+
+        Ok(Self { glyph_brush })
+    }
+
+    // The simple version of `render_text`: UI text of a given size in pixels, centered on a point.
+    // This is what most things want, e.g. labels on buttons or numbers floating above units.
+    pub fn draw_text(&mut self, screen_position: Vec2, text: &str, size: f32, colour: Vec4) {
+        self.queue(
+            screen_position,
+            text,
+            Font::Ui,
+            size,
+            TextAlignment::Center,
+            colour,
+        );
+    }
+
+    pub fn render_text(
+        &mut self,
+        screen_position: Vec2,
+        text: &str,
+        font: Font,
+        scale_multiplier: f32,
+        dpi_scaling: f32,
+        alignment: TextAlignment,
+        colour: Vec4,
+    ) {
+        self.queue(
+            screen_position,
+            text,
+            font,
+            font.scale() * scale_multiplier * dpi_scaling,
+            alignment,
+            colour,
+        );
+    }
+
+    fn queue(
+        &mut self,
+        screen_position: Vec2,
+        text: &str,
+        font: Font,
+        size: f32,
+        alignment: TextAlignment,
+        colour: Vec4,
+    ) {
+        let colour: [f32; 4] = colour.into();
+
+        self.glyph_brush.queue(
+            Section::new()
+                .with_screen_position((screen_position.x, screen_position.y))
+                .with_layout(alignment.layout())
+                .add_text(
+                    Text::new(text)
+                        .with_color(colour)
+                        .with_font_id(FontId(font as usize))
+                        .with_scale(size),
+                ),
+        );
+    }
+
+    // Draw all the text queued this frame on top of whatever's in `view`.
+    pub fn render(
+        &mut self,
+        context: &RenderContext,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+    ) {
+        let dimensions = context.screen_dimensions();
+        let mut staging_belt = wgpu::util::StagingBelt::new(10);
+
+        self.glyph_brush
+            .draw_queued(
+                &context.device,
+                &mut staging_belt,
+                encoder,
+                view,
+                dimensions.width,
+                dimensions.height,
+            )
+            .unwrap();
+
+        staging_belt.finish();
+    }
+}
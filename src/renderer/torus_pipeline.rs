@@ -3,6 +3,7 @@ use super::{
     INDEX_FORMAT,
 };
 use crate::assets::Model;
+use crate::hot_reload::shader;
 use std::sync::Arc;
 use ultraviolet::Vec3;
 
@@ -27,10 +28,10 @@ impl TorusPipeline {
                     push_constant_ranges: &[],
                 });
 
-        let vs = wgpu::include_spirv!("../../shaders/compiled/torus.vert.spv");
+        let vs = shader!("torus.vert.spv");
         let vs_module = context.device.create_shader_module(vs);
 
-        let fs = wgpu::include_spirv!("../../shaders/compiled/torus.frag.spv");
+        let fs = shader!("torus.frag.spv");
         let fs_module = context.device.create_shader_module(fs);
 
         let pipeline = context.device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
@@ -71,7 +72,7 @@ impl TorusPipeline {
                     },
                 ],
             },
-            sample_count: 1,
+            sample_count: context.sample_count(),
             sample_mask: !0,
             alpha_to_coverage_enabled: false,
         });
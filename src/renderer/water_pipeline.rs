@@ -0,0 +1,405 @@
+use super::{RenderContext, Vertex};
+use crate::assets::Model;
+#[cfg(feature = "water-shader")]
+use crate::hot_reload::shader;
+use crate::water::{Water, WATER_LEVEL};
+use std::sync::Arc;
+use ultraviolet::{Vec2, Vec3, Vec4};
+use wgpu::util::DeviceExt;
+
+#[cfg(not(feature = "water-shader"))]
+use super::{draw_model, model_pipelines::create_render_pipeline, ModelInstance};
+
+#[cfg(feature = "water-shader")]
+use super::{colour_state_descriptor, create_texture, DEPTH_FORMAT, INDEX_FORMAT, SCENE_FORMAT};
+#[cfg(feature = "water-shader")]
+use ultraviolet::Mat4;
+
+// With the `water-shader` feature, the units and buildings are first rendered upside down into an
+// offscreen texture (see `begin_reflection_pass`), which the water shader samples with a bit of
+// wobble from an animated normal map. Without it, the water is just a flat see-through plane.
+
+#[cfg(not(feature = "water-shader"))]
+const WATER_COLOUR: Vec4 = Vec4::new(0.05, 0.2, 0.3, 0.8);
+
+// The reflection gets squished into a square texture, but it's distorted by the ripples anyway.
+#[cfg(feature = "water-shader")]
+const REFLECTION_RESOLUTION: u32 = 1024;
+
+pub struct WaterPipeline {
+    pipeline: wgpu::RenderPipeline,
+    main_bind_group: Arc<wgpu::BindGroup>,
+    model: Model,
+    #[cfg(not(feature = "water-shader"))]
+    instance_buffer: wgpu::Buffer,
+    #[cfg(feature = "water-shader")]
+    reflection: Reflection,
+}
+
+#[cfg(feature = "water-shader")]
+struct Reflection {
+    colour: wgpu::TextureView,
+    // The model pipelines all write out bloom as well, so it needs to go somewhere.
+    bloom: wgpu::TextureView,
+    depth: wgpu::TextureView,
+    time_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl WaterPipeline {
+    #[cfg(not(feature = "water-shader"))]
+    pub fn new(context: &RenderContext, water: &[Water]) -> Self {
+        let pipeline = create_render_pipeline(
+            &context.device,
+            &[&context.main_bind_group_layout],
+            "Cheese water pipeline",
+            &context.vs_transparent_module,
+            &context.fs_transparent_module,
+            true,
+            false,
+            context.sample_count(),
+        );
+
+        let instance_buffer =
+            context
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Cheese water instance buffer"),
+                    contents: bytemuck::bytes_of(&ModelInstance {
+                        flat_colour: WATER_COLOUR,
+                        ..Default::default()
+                    }),
+                    usage: wgpu::BufferUsage::VERTEX,
+                });
+
+        Self {
+            pipeline,
+            main_bind_group: context.main_bind_group.clone(),
+            model: create_water_model(&context.device, water),
+            instance_buffer,
+        }
+    }
+
+    #[cfg(feature = "water-shader")]
+    pub fn new(context: &RenderContext, water: &[Water]) -> Self {
+        let device = &context.device;
+
+        let vs = shader!("water.vert.spv");
+        let vs_module = device.create_shader_module(vs);
+
+        let fs = shader!("water.frag.spv");
+        let fs_module = device.create_shader_module(fs);
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::FRAGMENT,
+            ty: wgpu::BindingType::SampledTexture {
+                multisampled: false,
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+            },
+            count: None,
+        };
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Cheese water bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::UniformBuffer {
+                        dynamic: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
+                texture_entry(2),
+                texture_entry(3),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Cheese water pipeline layout"),
+            bind_group_layouts: &[&context.main_bind_group_layout, &bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Cheese water pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex_stage: wgpu::ProgrammableStageDescriptor {
+                module: &vs_module,
+                entry_point: "main",
+            },
+            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                module: &fs_module,
+                entry_point: "main",
+            }),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor {
+                cull_mode: wgpu::CullMode::Back,
+                ..Default::default()
+            }),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            // Water doesn't glow.
+            color_states: &[
+                colour_state_descriptor(true),
+                wgpu::ColorStateDescriptor {
+                    write_mask: wgpu::ColorWrite::empty(),
+                    ..colour_state_descriptor(true)
+                },
+            ],
+            depth_stencil_state: Some(wgpu::DepthStencilStateDescriptor {
+                format: DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilStateDescriptor::default(),
+            }),
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: INDEX_FORMAT,
+                vertex_buffers: &[wgpu::VertexBufferDescriptor {
+                    stride: std::mem::size_of::<Vertex>() as u64,
+                    step_mode: wgpu::InputStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![0 => Float3, 1 => Float3, 2 => Float2],
+                }],
+            },
+            sample_count: context.sample_count(),
+            sample_mask: !0,
+            alpha_to_coverage_enabled: false,
+        });
+
+        // The normal map isn't srgb, so it can't go through `assets::create_texture`.
+        let normal_map = crate::water::normal_map();
+        let normal_map_extent = wgpu::Extent3d {
+            width: normal_map.width(),
+            height: normal_map.height(),
+            depth: 1,
+        };
+        let normal_map_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Cheese water normal map"),
+            size: normal_map_extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+        context.queue.write_texture(
+            wgpu::TextureCopyView {
+                texture: &normal_map_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &normal_map,
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 4 * normal_map.width(),
+                rows_per_image: 0,
+            },
+            normal_map_extent,
+        );
+        let normal_map = normal_map_texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let colour = create_texture(
+            device,
+            "Cheese reflection texture",
+            REFLECTION_RESOLUTION,
+            REFLECTION_RESOLUTION,
+            SCENE_FORMAT,
+            wgpu::TextureUsage::OUTPUT_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        );
+        let bloom = create_texture(
+            device,
+            "Cheese reflection bloom texture",
+            REFLECTION_RESOLUTION,
+            REFLECTION_RESOLUTION,
+            SCENE_FORMAT,
+            wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        );
+        let depth = create_texture(
+            device,
+            "Cheese reflection depth texture",
+            REFLECTION_RESOLUTION,
+            REFLECTION_RESOLUTION,
+            DEPTH_FORMAT,
+            wgpu::TextureUsage::OUTPUT_ATTACHMENT,
+        );
+
+        let time_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese water time buffer"),
+            contents: bytemuck::bytes_of(&0.0_f32),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Cheese water sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            ..Default::default()
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Cheese water bind group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(time_buffer.slice(..)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&normal_map),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&colour),
+                },
+            ],
+        });
+
+        Self {
+            pipeline,
+            main_bind_group: context.main_bind_group.clone(),
+            model: create_water_model(device, water),
+            reflection: Reflection {
+                colour,
+                bloom,
+                depth,
+                time_buffer,
+                bind_group,
+            },
+        }
+    }
+
+    // Used to scroll the ripples.
+    #[cfg(feature = "water-shader")]
+    pub fn update_time(&self, context: &RenderContext, time: f32) {
+        context
+            .queue
+            .write_buffer(&self.reflection.time_buffer, 0, bytemuck::bytes_of(&time));
+    }
+
+    // Anything rendered into this pass with `ModelPipelines::new_for_reflections` shows up in the
+    // water.
+    #[cfg(feature = "water-shader")]
+    pub fn begin_reflection_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+    ) -> wgpu::RenderPass<'a> {
+        let clear = |colour| wgpu::Operations {
+            load: wgpu::LoadOp::Clear(colour),
+            store: true,
+        };
+
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            color_attachments: &[
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &self.reflection.colour,
+                    resolve_target: None,
+                    // The alpha channel marks where there's something to reflect.
+                    ops: clear(wgpu::Color::TRANSPARENT),
+                },
+                wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: &self.reflection.bloom,
+                    resolve_target: None,
+                    ops: clear(wgpu::Color::BLACK),
+                },
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+                attachment: &self.reflection.depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        })
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.main_bind_group, &[]);
+
+        #[cfg(not(feature = "water-shader"))]
+        draw_model(render_pass, &self.model, self.instance_buffer.slice(..), 1);
+
+        #[cfg(feature = "water-shader")]
+        {
+            render_pass.set_bind_group(1, &self.reflection.bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.model.vertices.slice(..));
+            render_pass.set_index_buffer(self.model.indices.slice(..));
+            render_pass.draw_indexed(0..self.model.num_indices, 0, 0..1);
+        }
+    }
+}
+
+// Mirror the world in the water, then mirror the result horizontally. Doing both means that
+// triangles keep their winding order, so back-face culling still works. The water shader flips the
+// reflection back when sampling it.
+#[cfg(feature = "water-shader")]
+pub(super) fn reflection_view(view: Mat4) -> Mat4 {
+    let flip_horizontally = Mat4::from_nonuniform_scale(Vec3::new(-1.0, 1.0, 1.0));
+    let mirror = Mat4::from_translation(Vec3::new(0.0, WATER_LEVEL, 0.0))
+        * Mat4::from_nonuniform_scale(Vec3::new(1.0, -1.0, 1.0))
+        * Mat4::from_translation(Vec3::new(0.0, -WATER_LEVEL, 0.0));
+
+    flip_horizontally * view * mirror
+}
+
+// A flat rectangle for each area of water.
+fn create_water_model(device: &wgpu::Device, water: &[Water]) -> Model {
+    let vertices: Vec<_> = water
+        .iter()
+        .flat_map(|water| {
+            let min = water.center - water.dimensions / 2.0;
+            let max = water.center + water.dimensions / 2.0;
+
+            let corners = [Vec2::new(min.x, max.y), max, Vec2::new(max.x, min.y), min];
+
+            corners
+                .iter()
+                .map(|corner| Vertex {
+                    position: Vec3::new(corner.x, WATER_LEVEL, corner.y),
+                    normal: Vec3::unit_y(),
+                    uv: (*corner - min) / water.dimensions,
+                    // The same as the decals.
+                    tangent: Vec4::new(1.0, 0.0, 0.0, -1.0),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    // Counter-clockwise when looking down from above.
+    let indices: Vec<u32> = (0..water.len() as u32)
+        .flat_map(|i| {
+            let first = i * 4;
+            vec![first, first + 1, first + 2, first, first + 2, first + 3]
+        })
+        .collect();
+
+    Model {
+        vertices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese water vertices"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsage::VERTEX,
+        }),
+        indices: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cheese water indices"),
+            contents: bytemuck::cast_slice(&indices),
+            usage: wgpu::BufferUsage::INDEX,
+        }),
+        num_indices: indices.len() as u32,
+        lods: Vec::new(),
+    }
+}
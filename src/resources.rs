@@ -1,25 +1,23 @@
 use crate::ecs;
+use crate::input::InputMap;
 use legion::Entity;
 use std::collections::BTreeMap;
-use ultraviolet::{Mat4, Vec2, Vec3, Vec4};
+use ultraviolet::{Vec2, Vec3};
 use winit::event::VirtualKeyCode;
 
-#[derive(Default)]
-pub struct CameraControls {
-    pub up: bool,
-    pub down: bool,
-    pub left: bool,
-    pub right: bool,
-    pub zoom_delta: f32,
-}
-
 #[derive(Default, Debug)]
 pub struct RtsControls {
     pub shift_held: bool,
     pub control_held: bool,
     pub stop_pressed: bool,
+    pub hold_position_pressed: bool,
+    pub cycle_stance_pressed: bool,
+    pub select_idle_worker_pressed: bool,
+    pub select_idle_army_pressed: bool,
+    pub jump_to_alert_pressed: bool,
     pub mode: CommandMode,
     pub control_group_key_pressed: [bool; 10],
+    pub formation: Formation,
 }
 
 #[derive(Default, Debug)]
@@ -27,14 +25,27 @@ pub struct DebugControls {
     pub spawn_building_pressed: bool,
     pub pathfinding_start: Vec2,
     pub set_pathfinding_start_pressed: bool,
+    // Switched on and off with the `debug` console command.
+    pub show_unit_paths: bool,
+    pub show_navmesh: bool,
+    pub show_funnels: bool,
+    pub show_flow_fields: bool,
 }
 
 #[derive(PartialEq, Debug)]
 pub enum CommandMode {
     Normal,
+    Move,
     AttackMove,
+    Patrol,
+    Guard,
     Construct { building: ecs::Building },
     SetRecruitmentWaypoint,
+    // Picking a target for an active ability.
+    Cast { ability: ecs::ActiveAbility },
+    // Picking where a silo's strike should land. The camera is locked while this is going on, and
+    // the minimap can be used to aim anywhere.
+    TargetStrike,
 }
 
 impl Default for CommandMode {
@@ -43,68 +54,27 @@ impl Default for CommandMode {
     }
 }
 
-pub struct Camera {
-    pub distance: f32,
-    pub looking_at: Vec2,
+// How a group of units spreads out around the target of a move command.
+#[derive(PartialEq, Clone, Copy, Debug, derive_more::Display)]
+pub enum Formation {
+    Line,
+    Box,
+    Wedge,
 }
 
-impl Default for Camera {
-    fn default() -> Self {
-        Self {
-            distance: Self::ANGLE.mag(),
-            looking_at: Vec2::new(0.0, 0.0),
+impl Formation {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Line => Self::Box,
+            Self::Box => Self::Wedge,
+            Self::Wedge => Self::Line,
         }
     }
 }
 
-impl Camera {
-    const ANGLE: Vec3 = Vec3::new(0.0, 20.0, 10.0);
-
-    fn looking_at_3(&self) -> Vec3 {
-        Vec3::new(self.looking_at.x, 0.0, self.looking_at.y)
-    }
-
-    fn position(&self) -> Vec3 {
-        self.looking_at_3() + Self::ANGLE.normalized() * self.distance
-    }
-
-    pub fn to_matrix(&self) -> Mat4 {
-        Mat4::look_at(self.position(), self.looking_at_3(), Vec3::unit_y())
-    }
-
-    pub fn cast_ray(&self, mouse_position: Vec2, screen_dimensions: &ScreenDimensions) -> Vec2 {
-        let &ScreenDimensions { width, height } = screen_dimensions;
-
-        let x = (mouse_position.x / width as f32 * 2.0) - 1.0;
-        let y = 1.0 - (mouse_position.y / height as f32 * 2.0);
-
-        let clip = Vec4::new(x, y, -1.0, 1.0);
-
-        let eye = crate::renderer::create_perspective_mat4(width, height).inversed() * clip;
-        let eye = Vec4::new(eye.x, eye.y, -1.0, 0.0);
-        let direction = (self.to_matrix().inversed() * eye).truncated().normalized() * 1.0;
-
-        let position = self.position();
-        let ray = ncollide3d::query::Ray::new(
-            ncollide3d::math::Point::new(position.x, position.y, position.z),
-            ncollide3d::math::Vector::new(direction.x, direction.y, direction.z),
-        );
-
-        let toi = ncollide3d::query::ray_toi_with_plane(
-            &ncollide3d::math::Point::new(0.0, 0.0, 0.0),
-            &ncollide3d::math::Vector::new(0.0, 1.0, 0.0),
-            &ray,
-        );
-
-        match toi {
-            Some(toi) => {
-                let contact = self.position() + direction * toi;
-                Vec2::new(contact.x, contact.z)
-            }
-            // The above ray cast can fail in odd cases such as where the window is minimized,
-            // So let's just return the point the camera is centered on.
-            None => self.looking_at,
-        }
+impl Default for Formation {
+    fn default() -> Self {
+        Self::Box
     }
 }
 
@@ -209,6 +179,14 @@ impl MouseButtonState {
         }
     }
 
+    // Returns where the button was first pressed if it's currently being held down.
+    pub fn is_held(&self) -> Option<Vec2> {
+        match *self {
+            Self::Down(_, start) | Self::Dragging(start) => Some(start),
+            _ => None,
+        }
+    }
+
     pub fn was_dragged(&self) -> Option<Vec2> {
         if let Self::Dragged(start) = self {
             Some(*start)
@@ -219,7 +197,72 @@ impl MouseButtonState {
 }
 
 pub struct PlayerSide(pub ecs::Side);
+
+// The colours that can be picked for each side in the multiplayer lobby, out of 255.
+pub const TEAM_COLOURS: [(&str, Vec3); 4] = [
+    ("Green", Vec3::new(43.0, 140.0, 0.0)),
+    ("Purple", Vec3::new(196.0, 0.0, 109.0)),
+    ("Blue", Vec3::new(0.0, 90.0, 200.0)),
+    ("Orange", Vec3::new(230.0, 110.0, 0.0)),
+];
+
+// Which of `TEAM_COLOURS` each side is drawn in. Green is green and purple is purple unless they've
+// been changed in the lobby.
+pub struct TeamColours(pub [usize; 2]);
+
+impl Default for TeamColours {
+    fn default() -> Self {
+        Self([0, 1])
+    }
+}
+
+impl TeamColours {
+    pub fn get(&self, side: ecs::Side) -> Vec3 {
+        TEAM_COLOURS[self.0[side as usize]].1 / 255.0
+    }
+}
 pub struct DeltaTime(pub f32);
+// How many times faster than real time single player games run, and whether they're paused. Changed
+// with the buttons under the cheese counter, their hotkeys or `set_speed` in the console.
+pub struct GameSpeed {
+    pub speed: f32,
+    pub paused: bool,
+}
+
+impl Default for GameSpeed {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            paused: false,
+        }
+    }
+}
+
+impl GameSpeed {
+    // What the speed buttons and hotkeys step through. The console can go outside of these.
+    const STEPS: [f32; 6] = [0.5, 1.0, 1.5, 2.0, 3.0, 4.0];
+
+    pub fn faster(&mut self) {
+        let fastest = Self::STEPS[Self::STEPS.len() - 1];
+        self.speed = Self::STEPS
+            .iter()
+            .copied()
+            .find(|step| *step > self.speed)
+            .unwrap_or_else(|| self.speed.max(fastest));
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = Self::STEPS
+            .iter()
+            .rev()
+            .copied()
+            .find(|step| *step < self.speed)
+            .unwrap_or_else(|| self.speed.min(Self::STEPS[0]));
+    }
+}
+
+// How long the last frame took in real time, whatever the game speed is.
+pub struct UnscaledDeltaTime(pub f32);
 pub struct CursorIcon(pub winit::window::CursorIcon);
 #[derive(Default, Debug)]
 pub struct RayCastLocation {
@@ -238,23 +281,25 @@ pub enum Mode {
     PlayingMenu,
     Quit,
     StartScenario(u8),
-    ScenarioWon,
-    ScenarioLost,
+    // Load the quicksave at the start of the next frame, e.g. after clicking 'Load' in the main menu.
+    LoadGame,
+    // The game has been won or lost, and the stats screen is showing.
+    GameOver(Outcome),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Won,
+    Lost,
 }
 
 impl Mode {
     pub fn should_render(&self) -> bool {
-        matches!(
-            self,
-            Self::Playing | Self::PlayingMenu | Self::ScenarioWon | Self::ScenarioLost
-        )
+        matches!(self, Self::Playing | Self::PlayingMenu | Self::GameOver(_))
     }
 
     pub fn is_playing_menu(&self) -> bool {
-        matches!(
-            self,
-            Self::PlayingMenu | Self::ScenarioWon | Self::ScenarioLost
-        )
+        matches!(self, Self::PlayingMenu | Self::GameOver(_))
     }
 }
 
@@ -272,42 +317,174 @@ pub struct Keypress {
 #[derive(Default)]
 pub struct Keypresses(pub Vec<Keypress>);
 
-#[derive(derive_more::Display)]
+#[derive(derive_more::Display, Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
 pub enum WinCondition {
     #[display(fmt = "Build {} {}", _0, "_1.maybe_plural(*_0)")]
     BuildN(u8, ecs::Building),
     #[display(fmt = "Destroy all enemies")]
     DestroyAll,
+    #[display(fmt = "Destroy all enemy buildings")]
+    DestroyAllBuildings,
+    #[display(fmt = "Collect {} cheese", _0)]
+    CollectCheese(u32),
+    #[display(fmt = "Survive for {} minutes", _0)]
+    SurviveMinutes(u32),
+    // Destroy everything with a mission's `ScriptTag`.
+    #[display(fmt = "{}", description)]
+    Destroy { tag: String, description: String },
 }
 
-#[derive(derive_more::Display)]
+#[derive(derive_more::Display, Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
 pub enum LoseCondition {
     #[display(fmt = "Keep at least one unit alive")]
     LetAllUnitsDie,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Objectives {
     pub win_conditions: Vec<WinCondition>,
     pub lose_conditions: Vec<LoseCondition>,
 }
 
+#[derive(Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct Settings {
+    // The size of the window when the game starts, in logical pixels. This is also the size it goes
+    // back to when leaving fullscreen.
+    pub resolution: [u32; 2],
+    // Borderless fullscreen on whichever monitor the window is on. Alt+Enter toggles it.
+    pub fullscreen: bool,
+    // How big the HUD and text are, on top of the monitor's own scaling. It gets shrunk if the HUD
+    // wouldn't fit in the window, see `ecs::ui_scale`.
+    pub ui_scale: f32,
+    pub vsync: bool,
     pub bloom: bool,
+    // These two only do anything with the `hdr` feature, see `renderer::post_processing`.
+    pub tone_mapping: bool,
+    pub vignette: bool,
+    // When disabled, the shadow map is still cleared each frame so nothing ends up in shadow.
+    pub shadows: bool,
+    // This one is only read at startup, as the shadow pipelines would need to be recreated.
     pub shadow_resolution: u32,
+    // Samples per pixel for anti-aliasing: 1 (off), 2, 4 or 8.
+    pub msaa: u32,
+    // How big the scene is rendered relative to the window, from 0.25 to 2.0. The UI text is
+    // always drawn at the window's size.
+    pub render_scale: f32,
+    // Volumes go from 0.0 to 1.0. Effects and music are both scaled by the master volume.
+    pub master_volume: f32,
+    pub effects_volume: f32,
+    pub music_volume: f32,
+    // How fast the camera pans with the arrow keys or edge scrolling, in world units per second.
+    pub camera_speed: f32,
+    // Show how much damage things take (and when they die) as numbers floating above them.
+    pub damage_numbers: bool,
+    // Shake the camera when there are explosions or something big dies nearby.
+    pub camera_shake: bool,
+    // Draw units that are far from the camera with simplified models and without animating them.
+    pub unit_lod: bool,
+    // How many minutes of game time go by between autosaves, or 0 to turn them off.
+    pub autosave_interval: f32,
+    // How many autosave files to cycle through.
+    pub autosave_slots: u32,
+    // Read the models, textures and shaders from the disk and reload them when they change. See
+    // `hot_reload`.
+    pub hot_reload: bool,
+    // How many minutes of game time a whole day and night take, or 0 for it to always be day.
+    pub day_length: f32,
+    // Whether units can't see as far at night.
+    pub dark_nights: bool,
+    // Paint the sun and the glow of the horizon over the stars, following the day/night cycle.
+    pub dynamic_sky: bool,
+    // These two are only read at startup. See `renderer::adapter`.
+    pub backend: GraphicsBackend,
+    // Use the integrated gpu instead of a discrete one, to save battery.
+    pub low_power_gpu: bool,
+    // This needs to be last, as tables have to come after plain values in toml.
+    pub input_map: InputMap,
+}
+
+// Which graphics api to draw with. With `Auto`, wgpu picks whichever of Vulkan, DX12 or Metal works.
+#[derive(Clone, Copy, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphicsBackend {
+    Auto,
+    Vulkan,
+    Dx12,
+    Dx11,
+    Metal,
+    Gl,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
+            resolution: [1280, 720],
+            fullscreen: false,
+            ui_scale: 1.0,
+            vsync: true,
             bloom: true,
+            tone_mapping: true,
+            vignette: true,
+            shadows: true,
             shadow_resolution: 1024,
+            msaa: 1,
+            render_scale: 1.0,
+            master_volume: 1.0,
+            effects_volume: 1.0,
+            music_volume: 0.5,
+            camera_speed: 45.0,
+            damage_numbers: true,
+            camera_shake: true,
+            unit_lod: true,
+            autosave_interval: 5.0,
+            autosave_slots: 3,
+            hot_reload: false,
+            day_length: 15.0,
+            dark_nights: false,
+            dynamic_sky: true,
+            backend: GraphicsBackend::Auto,
+            low_power_gpu: false,
+            input_map: InputMap::default(),
         }
     }
 }
 
 pub struct TotalTime(pub f32);
 
+// How far through the day it is, from 0.0 to 1.0. Midnight is at 0.0, sunrise at 0.25, noon at 0.5
+// and sunset at 0.75. This is `None` when the day/night cycle is turned off.
+#[derive(Default)]
+pub struct TimeOfDay(pub Option<f32>);
+
+impl TimeOfDay {
+    // Games start in the morning.
+    const START: f32 = 0.3;
+
+    pub fn at(total_time: f32, day_length_minutes: f32) -> Self {
+        if day_length_minutes <= 0.0 {
+            return Self(None);
+        }
+
+        Self(Some(
+            (Self::START + total_time / (day_length_minutes * 60.0)).fract(),
+        ))
+    }
+
+    // How high the sun is, from -1.0 at midnight to 1.0 at noon.
+    pub fn sun_height(&self) -> f32 {
+        match self.0 {
+            Some(time) => ((time - 0.25) * std::f32::consts::TAU).sin(),
+            None => 1.0,
+        }
+    }
+
+    // From 0.0 during the day to 1.0 at night, fading in and out around sunset and sunrise.
+    pub fn darkness(&self) -> f32 {
+        ((0.1 - self.sun_height()) / 0.3).clamp(0.0, 1.0)
+    }
+}
+
 #[derive(Debug)]
 pub enum AiBuildOrderItem {
     BuildPump(Entity),
@@ -320,11 +497,39 @@ pub enum AiBuildOrderItem {
 #[derive(Default)]
 pub struct AiBuildOrders(pub Vec<(f32, AiBuildOrderItem)>);
 
-#[derive(Default)]
+// Everything that's shown on the stats screen at the end of a game. Both sides are tracked the
+// same way, so the enemy's kills are just our losses.
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct GameStats {
+    pub green: SideStats,
+    pub purple: SideStats,
+}
+
+impl GameStats {
+    pub fn side(&self, side: ecs::Side) -> &SideStats {
+        match side {
+            ecs::Side::Green => &self.green,
+            ecs::Side::Purple => &self.purple,
+        }
+    }
+
+    pub fn side_mut(&mut self, side: ecs::Side) -> &mut SideStats {
+        match side {
+            ecs::Side::Green => &mut self.green,
+            ecs::Side::Purple => &mut self.purple,
+        }
+    }
+}
+
+#[derive(Default, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SideStats {
     pub units_recruited: u32,
     pub units_lost: u32,
     pub buildings_built: u32,
-    pub enemy_buildings_destroyed: u32,
-    pub enemy_units_killed: u32,
+    pub buildings_lost: u32,
+    pub damage_dealt: f32,
+    // Counted for every side, even though only the player actually has any coins to spend.
+    pub cheese_gathered: u32,
 }
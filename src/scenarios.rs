@@ -1,14 +1,184 @@
 use crate::assets::ModelAnimations;
+use crate::camera::Camera;
+use crate::deterministic;
 use crate::ecs;
+use crate::mapgen;
+use crate::mission::MissionScript;
 use crate::pathfinding::Map;
 use crate::resources::{
-    AiBuildOrderItem, AiBuildOrders, Camera, CheeseCoins, LoseCondition, Objectives, WinCondition,
+    AiBuildOrderItem, AiBuildOrders, CheeseCoins, LoseCondition, Objectives, WinCondition,
 };
+use crate::resources::{GameSpeed, GameStats, PlayerSide, TotalTime};
+use crate::scenery::Scenery;
 use legion::systems::CommandBuffer;
 use legion::*;
-use rand::Rng;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::path::Path;
 use ultraviolet::Vec2;
 
+pub const MISSION_SCENARIO: u8 = 5;
+pub const BENCH_SCENARIO: u8 = 253;
+pub const RANDOM_MULTIPLAYER_SCENARIO: u8 = 252;
+pub const MULTIPLAYER_SCENARIO: u8 = 254;
+
+// The maps that can be picked in the multiplayer lobby, along with their scenario numbers.
+pub const MULTIPLAYER_MAPS: &[(&str, u8)] = &[
+    ("Two bases", MULTIPLAYER_SCENARIO),
+    ("Random map", RANDOM_MULTIPLAYER_SCENARIO),
+];
+
+// Clear out whatever game was going on before and set up a new one. `bench_units` is only used by
+// `BENCH_SCENARIO`.
+pub fn start(
+    world: &mut World,
+    resources: &Resources,
+    scenario: u8,
+    network_seed: Option<u64>,
+    mission_path: &Path,
+    bench_units: u32,
+) {
+    let animations = resources.get::<ModelAnimations>().unwrap();
+    let mut map = resources.get_mut::<Map>().unwrap();
+    let mut rng = resources.get_mut::<SmallRng>().unwrap();
+    let mut objectives = resources.get_mut::<Objectives>().unwrap();
+    let mut camera = resources.get_mut::<Camera>().unwrap();
+    let mut cheese_coins = resources.get_mut::<CheeseCoins>().unwrap();
+    let mut ai_build_orders = resources.get_mut::<AiBuildOrders>().unwrap();
+    resources.get_mut::<TotalTime>().unwrap().0 = 0.0;
+    *resources.get_mut::<GameSpeed>().unwrap() = GameSpeed::default();
+    *resources.get_mut::<GameStats>().unwrap() = GameStats::default();
+    *map = Map::new();
+    *resources.get_mut::<ecs::FogOfWar>().unwrap() = ecs::FogOfWar::default();
+    *resources.get_mut::<ecs::Upgrades>().unwrap() = ecs::Upgrades::default();
+    *resources.get_mut::<ecs::PathRequests>().unwrap() = ecs::PathRequests::default();
+    let mut mission = resources.get_mut::<crate::mission::Mission>().unwrap();
+    *mission = crate::mission::Mission::default();
+    // Only missions have scenery so far.
+    let mut scenery = resources.get_mut::<Scenery>().unwrap();
+    scenery.set(Vec::new());
+    resources.get_mut::<crate::net::Lockstep>().unwrap().reset();
+    let player_side = resources.get::<PlayerSide>().unwrap().0;
+    // Both peers need to generate the same random numbers.
+    if let Some(seed) = network_seed {
+        *rng = SmallRng::seed_from_u64(seed);
+    }
+
+    world.clear();
+
+    match scenario {
+        1 => {
+            one(
+                world,
+                &animations,
+                &mut map,
+                &mut rng,
+                &mut objectives,
+                &mut camera,
+                &mut cheese_coins,
+                &mut ai_build_orders,
+            );
+        }
+        2 => {
+            two(
+                world,
+                &animations,
+                &mut map,
+                &mut rng,
+                &mut objectives,
+                &mut camera,
+                &mut cheese_coins,
+                &mut ai_build_orders,
+            );
+        }
+        3 => {
+            three(
+                world,
+                &animations,
+                &mut map,
+                &mut rng,
+                &mut objectives,
+                &mut camera,
+                &mut cheese_coins,
+                &mut ai_build_orders,
+            );
+        }
+        4 => {
+            random_map(
+                world,
+                &animations,
+                &mut map,
+                &mut rng,
+                &mut objectives,
+                &mut camera,
+                &mut cheese_coins,
+                &mut ai_build_orders,
+            );
+        }
+        BENCH_SCENARIO => bench(
+            world,
+            &animations,
+            &mut map,
+            &mut objectives,
+            &mut camera,
+            &mut cheese_coins,
+            &mut ai_build_orders,
+            bench_units,
+        ),
+        MULTIPLAYER_SCENARIO => multiplayer(
+            world,
+            &animations,
+            &mut map,
+            &mut rng,
+            &mut objectives,
+            &mut camera,
+            &mut cheese_coins,
+            &mut ai_build_orders,
+            player_side,
+        ),
+        RANDOM_MULTIPLAYER_SCENARIO => multiplayer_random_map(
+            world,
+            &animations,
+            &mut map,
+            &mut rng,
+            &mut objectives,
+            &mut camera,
+            &mut cheese_coins,
+            &mut ai_build_orders,
+            player_side,
+        ),
+        MISSION_SCENARIO => match crate::mission::load(mission_path) {
+            Ok(script) => {
+                self::mission(
+                    world,
+                    &animations,
+                    &mut map,
+                    &mut objectives,
+                    &mut camera,
+                    &mut cheese_coins,
+                    &mut ai_build_orders,
+                    &script,
+                );
+                *mission = crate::mission::Mission::new(&script);
+                scenery.set(crate::scenery::scatter_all(&script.scenery));
+            }
+            Err(error) => {
+                log::error!("Failed to load {}: {}", mission_path.display(), error)
+            }
+        },
+        255 => sandbox(
+            world,
+            &animations,
+            &mut map,
+            &mut rng,
+            &mut objectives,
+            &mut camera,
+            &mut cheese_coins,
+            &mut ai_build_orders,
+        ),
+        _ => {}
+    }
+}
+
 // Squad of 10 marines vs 5.
 pub fn one(
     world: &mut World,
@@ -110,10 +280,7 @@ pub fn one(
         lose_conditions: vec![LoseCondition::LetAllUnitsDie],
     };
 
-    *camera = Camera {
-        looking_at: unit_spawn_point,
-        distance: 15.0,
-    };
+    *camera = Camera::new(unit_spawn_point, 15.0);
     *cheese_coins = CheeseCoins(0);
     *ai_build_orders = AiBuildOrders::default();
 }
@@ -143,7 +310,7 @@ fn spawn_pump_over_guyser(
     <&mut ecs::AnimationState>::query()
         .get_mut(world, pump_entity)
         .unwrap()
-        .time = animation_offset;
+        .set_time(animation_offset);
 
     world.push((
         ecs::Position(position),
@@ -153,6 +320,17 @@ fn spawn_pump_over_guyser(
     ));
 }
 
+// A watchtower and a cheese store on each flank, the same distance from both bases. Generated maps
+// might have a rock in the way, in which case that structure is left out.
+fn spawn_structures(buffer: &mut CommandBuffer, green_base: Vec2, map: &mut Map) {
+    let across = Vec2::new(-green_base.y, green_base.x).normalized();
+
+    for &flip in &[1.0, -1.0] {
+        ecs::Structure::Watchtower.add_to_world(buffer, across * 30.0 * flip, map);
+        ecs::Structure::CheeseStore.add_to_world(buffer, across * 70.0 * flip, map);
+    }
+}
+
 fn spawn_units_in_circle(
     buffer: &mut CommandBuffer,
     animations: &ModelAnimations,
@@ -236,10 +414,7 @@ pub fn two(
     spawn_guyser(&mut world, Vec2::new(-27.52, -59.68));
     spawn_guyser(&mut world, Vec2::new(-42.69, -77.58));
 
-    *camera = Camera {
-        looking_at: engineer_pos,
-        distance: 30.0,
-    };
+    *camera = Camera::new(engineer_pos, 30.0);
 
     *objectives = Objectives {
         win_conditions: vec![
@@ -267,22 +442,20 @@ pub fn three(
 
     let mut command_buffer = legion::systems::CommandBuffer::new(&world);
 
-    ecs::Unit::Engineer.add_to_world(
+    ecs::spawn_unit(
         &mut command_buffer,
         Some(animations),
-        start,
-        ecs::Facing(0.0),
+        ecs::Unit::Engineer,
         ecs::Side::Green,
-        None,
+        start,
     );
 
-    ecs::Unit::Engineer.add_to_world(
+    ecs::spawn_unit(
         &mut command_buffer,
         Some(animations),
-        -start,
-        ecs::Facing(0.0),
+        ecs::Unit::Engineer,
         ecs::Side::Purple,
-        None,
+        -start,
     );
 
     command_buffer.flush(world);
@@ -308,10 +481,7 @@ pub fn three(
         lose_conditions: vec![LoseCondition::LetAllUnitsDie],
     };
 
-    *camera = Camera {
-        looking_at: start,
-        distance: 30.0,
-    };
+    *camera = Camera::new(start, 30.0);
 
     *cheese_coins = CheeseCoins(100);
 
@@ -423,6 +593,62 @@ pub fn three(
 150.26266: MoveTo { target: Vec2 { x: -46.487736, y: -79.067795 }, attack_move: true, path: [] }
 */
 
+// Sets up whatever a mission script says is on the map to start with. The triggers are run by
+// `ecs::run_mission_triggers`.
+pub fn mission(
+    world: &mut World,
+    animations: &ModelAnimations,
+    map: &mut Map,
+    objectives: &mut Objectives,
+    camera: &mut Camera,
+    cheese_coins: &mut CheeseCoins,
+    ai_build_orders: &mut AiBuildOrders,
+    script: &MissionScript,
+) {
+    let mut command_buffer = legion::systems::CommandBuffer::new(world);
+
+    for group in &script.units {
+        group.spawn(&mut command_buffer, animations);
+    }
+
+    command_buffer.flush(world);
+
+    for placement in &script.buildings {
+        let position = placement.position.into();
+        let entity = placement.building.add_to_world_fully_built(
+            world,
+            position,
+            placement.side,
+            animations,
+            map,
+        );
+
+        match (entity, &placement.tag) {
+            (Some(entity), Some(tag)) => {
+                if let Some(mut entry) = world.entry(entity) {
+                    entry.add_component(ecs::ScriptTag(tag.clone()));
+                }
+            }
+            (Some(_), None) => {}
+            (None, _) => log::warn!(
+                "Couldn't place a {:?} at {:?} in {}",
+                placement.building,
+                position,
+                script.name
+            ),
+        }
+    }
+
+    *objectives = Objectives {
+        win_conditions: script.win_conditions.clone(),
+        lose_conditions: script.lose_conditions.clone(),
+    };
+
+    *camera = Camera::new(script.camera.into(), 15.0);
+    *cheese_coins = CheeseCoins(script.cheese_coins);
+    *ai_build_orders = AiBuildOrders::default();
+}
+
 pub fn sandbox(
     world: &mut World,
     animations: &ModelAnimations,
@@ -435,13 +661,12 @@ pub fn sandbox(
 ) {
     let mut command_buffer = legion::systems::CommandBuffer::new(&world);
 
-    ecs::Unit::Engineer.add_to_world(
+    ecs::spawn_unit(
         &mut command_buffer,
         Some(animations),
-        Vec2::new(0.0, -90.0),
-        ecs::Facing(0.0),
+        ecs::Unit::Engineer,
         ecs::Side::Green,
-        None,
+        Vec2::new(0.0, -90.0),
     );
 
     spawn_units_in_circle(
@@ -494,10 +719,319 @@ pub fn sandbox(
         win_conditions: vec![],
         lose_conditions: vec![],
     };
-    *camera = Camera {
-        looking_at: Vec2::new(0.0, -90.0),
-        distance: 50.0,
-    };
+    *camera = Camera::new(Vec2::new(0.0, -90.0), 50.0);
     *cheese_coins = CheeseCoins(10_000_000);
     *ai_build_orders = AiBuildOrders::default();
 }
+
+// For `--bench`. Two big blocks of marines on either side of the middle, attack-moving into each
+// other.
+pub fn bench(
+    world: &mut World,
+    animations: &ModelAnimations,
+    map: &mut Map,
+    objectives: &mut Objectives,
+    camera: &mut Camera,
+    cheese_coins: &mut CheeseCoins,
+    ai_build_orders: &mut AiBuildOrders,
+    units: u32,
+) {
+    const COLUMNS: u32 = 50;
+    const SPACING: f32 = 2.5;
+
+    let mut command_buffer = legion::systems::CommandBuffer::new(world);
+
+    for &(side, flip) in &[(ecs::Side::Green, -1.0), (ecs::Side::Purple, 1.0)] {
+        let front = Vec2::new(0.0, 20.0 * flip);
+        let facing = (-flip * 90.0_f32).to_radians();
+        let per_side = units / 2
+            + if side == ecs::Side::Green {
+                units % 2
+            } else {
+                0
+            };
+
+        for i in 0..per_side {
+            let (column, row) = ((i % COLUMNS) as f32, (i / COLUMNS) as f32);
+            let offset = Vec2::new(
+                (column - (COLUMNS - 1) as f32 / 2.0) * SPACING,
+                row * SPACING,
+            );
+            let position = map.nearest_passable_point(front + offset * Vec2::new(1.0, flip), 1.0);
+
+            ecs::Unit::MouseMarine.add_to_world(
+                &mut command_buffer,
+                Some(animations),
+                position,
+                ecs::Facing(facing),
+                side,
+                Some(ecs::Command::MoveTo {
+                    target: Vec2::new(position.x, -position.y),
+                    attack_move: true,
+                    path: Vec::new(),
+                }),
+            );
+        }
+    }
+
+    command_buffer.flush(world);
+
+    // Nothing to win or lose, the bench just stops after enough ticks.
+    *objectives = Objectives::default();
+    *camera = Camera::new(Vec2::zero(), 80.0);
+    *cheese_coins = CheeseCoins(0);
+    *ai_build_orders = AiBuildOrders::default();
+}
+
+// Two mirrored bases, for playing against someone else over the network.
+pub fn multiplayer(
+    world: &mut World,
+    animations: &ModelAnimations,
+    map: &mut Map,
+    rng: &mut rand::rngs::SmallRng,
+    objectives: &mut Objectives,
+    camera: &mut Camera,
+    cheese_coins: &mut CheeseCoins,
+    ai_build_orders: &mut AiBuildOrders,
+    player_side: ecs::Side,
+) {
+    let mut command_buffer = legion::systems::CommandBuffer::new(world);
+
+    let base = |side| match side {
+        ecs::Side::Green => Vec2::new(0.0, 60.0),
+        ecs::Side::Purple => Vec2::new(0.0, -60.0),
+    };
+
+    for &side in &[ecs::Side::Green, ecs::Side::Purple] {
+        let towards_middle = -base(side).normalized();
+        let facing = deterministic::angle(towards_middle);
+
+        ecs::Unit::Engineer.add_to_world(
+            &mut command_buffer,
+            Some(animations),
+            base(side) + towards_middle * 10.0,
+            ecs::Facing(facing),
+            side,
+            None,
+        );
+
+        spawn_units_in_circle(
+            &mut command_buffer,
+            animations,
+            5,
+            ecs::Unit::MouseMarine,
+            base(side) + towards_middle * 15.0,
+            1.0,
+            facing.to_degrees(),
+            side,
+        );
+    }
+
+    command_buffer.flush(world);
+
+    for &side in &[ecs::Side::Green, ecs::Side::Purple] {
+        let flip = if base(side).y > 0.0 { 1.0 } else { -1.0 };
+
+        ecs::Building::Armoury
+            .add_to_world_fully_built(world, base(side), side, animations, map)
+            .unwrap();
+
+        spawn_pump_over_guyser(
+            base(side) + Vec2::new(-12.0, 6.0 * flip),
+            side,
+            world,
+            animations,
+            map,
+            rng,
+        );
+
+        spawn_guyser(world, base(side) + Vec2::new(12.0, 6.0 * flip));
+        spawn_guyser(world, base(side) + Vec2::new(30.0, -10.0 * flip));
+    }
+
+    spawn_structures(&mut command_buffer, base(ecs::Side::Green), map);
+    command_buffer.flush(world);
+
+    // Chasing down every last mouse isn't much fun against another person.
+    *objectives = Objectives {
+        win_conditions: vec![WinCondition::DestroyAllBuildings],
+        lose_conditions: vec![LoseCondition::LetAllUnitsDie],
+    };
+
+    *camera = Camera::new(base(player_side), 30.0);
+    *cheese_coins = CheeseCoins(100);
+    *ai_build_orders = AiBuildOrders::default();
+}
+
+// `multiplayer`, but on a generated map. Both peers have the same seed, so they generate the same
+// one.
+pub fn multiplayer_random_map(
+    world: &mut World,
+    animations: &ModelAnimations,
+    map: &mut Map,
+    rng: &mut rand::rngs::SmallRng,
+    objectives: &mut Objectives,
+    camera: &mut Camera,
+    cheese_coins: &mut CheeseCoins,
+    ai_build_orders: &mut AiBuildOrders,
+    player_side: ecs::Side,
+) {
+    let generated = mapgen::generate(rng.gen());
+
+    let mut command_buffer = legion::systems::CommandBuffer::new(world);
+
+    for &(position, dimensions) in &generated.obstacles {
+        ecs::Obstacle::add_to_world(&mut command_buffer, position, dimensions, map);
+    }
+
+    let sides = [ecs::Side::Green, ecs::Side::Purple];
+
+    for (&side, &start) in sides.iter().zip(&generated.start_locations) {
+        let towards_middle = -start.normalized();
+        let facing = deterministic::angle(towards_middle);
+
+        ecs::Unit::Engineer.add_to_world(
+            &mut command_buffer,
+            Some(animations),
+            start,
+            ecs::Facing(facing),
+            side,
+            None,
+        );
+
+        spawn_units_in_circle(
+            &mut command_buffer,
+            animations,
+            5,
+            ecs::Unit::MouseMarine,
+            start + towards_middle * 5.0,
+            1.0,
+            facing.to_degrees(),
+            side,
+        );
+    }
+
+    command_buffer.flush(world);
+
+    for (i, &side) in sides.iter().enumerate() {
+        ecs::Building::Armoury
+            .add_to_world_fully_built(world, generated.armoury_locations[i], side, animations, map)
+            .unwrap();
+
+        let mut guysers = generated.base_guysers[i].iter();
+
+        if let Some(&guyser) = guysers.next() {
+            spawn_pump_over_guyser(guyser, side, world, animations, map, rng);
+        }
+
+        for &guyser in guysers {
+            spawn_guyser(world, guyser);
+        }
+    }
+
+    for &guyser in &generated.expansion_guysers {
+        spawn_guyser(world, guyser);
+    }
+
+    spawn_structures(&mut command_buffer, generated.start_locations[0], map);
+    command_buffer.flush(world);
+
+    *objectives = Objectives {
+        win_conditions: vec![WinCondition::DestroyAllBuildings],
+        lose_conditions: vec![LoseCondition::LetAllUnitsDie],
+    };
+
+    let player_start = match player_side {
+        ecs::Side::Green => generated.start_locations[0],
+        ecs::Side::Purple => generated.start_locations[1],
+    };
+    *camera = Camera::new(player_start, 30.0);
+    *cheese_coins = CheeseCoins(100);
+    *ai_build_orders = AiBuildOrders::default();
+}
+
+// A skirmish like `three`, but on a freshly generated map. The AI's build order is pretty much the
+// opening from `three`, just moved to wherever its base ended up.
+pub fn random_map(
+    world: &mut World,
+    animations: &ModelAnimations,
+    map: &mut Map,
+    rng: &mut rand::rngs::SmallRng,
+    objectives: &mut Objectives,
+    camera: &mut Camera,
+    cheese_coins: &mut CheeseCoins,
+    ai_build_orders: &mut AiBuildOrders,
+) {
+    let seed = rng.gen();
+    log::info!("Generating a map with seed {}", seed);
+    let generated = mapgen::generate(seed);
+
+    let [start, enemy_start] = generated.start_locations;
+
+    let mut command_buffer = legion::systems::CommandBuffer::new(world);
+
+    for &(position, dimensions) in &generated.obstacles {
+        ecs::Obstacle::add_to_world(&mut command_buffer, position, dimensions, map);
+    }
+
+    ecs::spawn_unit(
+        &mut command_buffer,
+        Some(animations),
+        ecs::Unit::Engineer,
+        ecs::Side::Green,
+        start,
+    );
+
+    ecs::spawn_unit(
+        &mut command_buffer,
+        Some(animations),
+        ecs::Unit::Engineer,
+        ecs::Side::Purple,
+        enemy_start,
+    );
+
+    command_buffer.flush(world);
+
+    for guyser in generated.base_guysers[0]
+        .iter()
+        .chain(&generated.expansion_guysers)
+    {
+        spawn_guyser(world, *guyser);
+    }
+
+    let enemy_guyser_entities: Vec<Entity> = generated.base_guysers[1]
+        .iter()
+        .map(|guyser| spawn_guyser(world, *guyser))
+        .collect();
+
+    *objectives = Objectives {
+        win_conditions: vec![WinCondition::DestroyAll],
+        lose_conditions: vec![LoseCondition::LetAllUnitsDie],
+    };
+
+    *camera = Camera::new(start, 30.0);
+
+    *cheese_coins = CheeseCoins(100);
+
+    let mut build_order: Vec<_> = enemy_guyser_entities
+        .iter()
+        .zip(&[5.36, 12.74, 23.95, 39.6])
+        .map(|(guyser, time)| (*time, AiBuildOrderItem::BuildPump(*guyser)))
+        .collect();
+
+    let enemy_armoury = generated.armoury_locations[1];
+    let towards_middle = -enemy_start.normalized();
+
+    build_order.extend(vec![
+        (49.39, AiBuildOrderItem::BuildArmoury(enemy_armoury)),
+        (
+            60.0,
+            AiBuildOrderItem::SetWaypoint(enemy_armoury + towards_middle * 10.0),
+        ),
+        (60.0, AiBuildOrderItem::RecruitMarine(100)),
+        (103.63, AiBuildOrderItem::AttackMove(Vec2::zero())),
+        (134.98, AiBuildOrderItem::AttackMove(start)),
+    ]);
+
+    *ai_build_orders = AiBuildOrders(build_order);
+}
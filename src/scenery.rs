@@ -0,0 +1,125 @@
+// Decoration that's placed on the map when it's loaded and then never changes: rocks, wheels of
+// cheese and tufts of grass. None of it gets in anyone's way, so unlike obstacles and buildings it
+// isn't made out of entities. Maps can have thousands of props, so instead of being pushed into the
+// instance buffers every frame like everything else, they're uploaded once whenever the scenery
+// changes (see `renderer::SceneryBuffers`).
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use ultraviolet::Vec2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Prop {
+    Rock,
+    CheeseWheel,
+    GrassTuft,
+}
+
+impl Prop {
+    pub const ALL: [Prop; 3] = [Prop::Rock, Prop::CheeseWheel, Prop::GrassTuft];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PropInstance {
+    pub prop: Prop,
+    pub position: [f32; 2],
+    // In radians.
+    pub facing: f32,
+    pub scale: f32,
+}
+
+#[derive(Default)]
+pub struct Scenery {
+    props: Vec<PropInstance>,
+    // Bumped every time the props change, so that the renderer knows to upload them again.
+    generation: u32,
+}
+
+impl Scenery {
+    pub fn set(&mut self, props: Vec<PropInstance>) {
+        self.props = props;
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    pub fn props(&self) -> &[PropInstance] {
+        &self.props
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+// A `[[scenery]]` entry in a mission script. Big patches of grass can be scattered with a single
+// entry by giving it a `count` and a `spread`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct SceneryGroup {
+    pub prop: Prop,
+    pub position: [f32; 2],
+    #[serde(default = "one")]
+    pub count: u32,
+    // The props are scattered randomly within a circle of this radius.
+    #[serde(default)]
+    pub spread: f32,
+    // Each prop's size is varied by up to a quarter of this either way, so they don't all look the
+    // same.
+    #[serde(default = "one_f32")]
+    pub scale: f32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+fn one_f32() -> f32 {
+    1.0
+}
+
+impl SceneryGroup {
+    // The props are scattered the same way every time the map is loaded.
+    pub fn scatter(&self, seed: u64) -> impl Iterator<Item = PropInstance> + '_ {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let center = Vec2::from(self.position);
+
+        (0..self.count).map(move |_| {
+            // Square rooting the distance spreads them evenly over the circle instead of bunching
+            // them up in the middle.
+            let angle = rng.gen_range(0.0, std::f32::consts::TAU);
+            let distance = rng.gen_range(0.0_f32, 1.0).sqrt() * self.spread;
+            let position = center + crate::deterministic::direction(angle) * distance;
+
+            PropInstance {
+                prop: self.prop,
+                position: position.into(),
+                facing: rng.gen_range(0.0, std::f32::consts::TAU),
+                scale: self.scale * rng.gen_range(0.75, 1.25),
+            }
+        })
+    }
+}
+
+pub fn scatter_all(groups: &[SceneryGroup]) -> Vec<PropInstance> {
+    groups
+        .iter()
+        .enumerate()
+        .flat_map(|(i, group)| group.scatter(i as u64))
+        .collect()
+}
+
+#[test]
+fn scenery_is_scattered_the_same_way_every_time() {
+    let groups = [SceneryGroup {
+        prop: Prop::GrassTuft,
+        position: [10.0, -20.0],
+        count: 500,
+        spread: 15.0,
+        scale: 1.0,
+    }];
+
+    let props = scatter_all(&groups);
+    assert_eq!(props.len(), 500);
+    assert_eq!(props, scatter_all(&groups));
+    assert!(props
+        .iter()
+        .all(|prop| (Vec2::from(prop.position) - Vec2::new(10.0, -20.0)).mag() <= 15.0 + 1e-3));
+}
@@ -0,0 +1,292 @@
+// The whole game simulation (everything in `ecs::add_gameplay_systems`, including pathfinding),
+// but without a window, a gpu or any sound. The tick is always `net::TICK_LENGTH` long, and
+// commands are given the same way as they are over the network, so this is for integration tests
+// and for bots playing against each other on CI.
+//
+// Run with `--headless <scenario> [ticks] [seed]` to play out a scenario like this and print out
+// how it went.
+
+use crate::assets::ModelAnimations;
+use crate::audio::SoundEvents;
+use crate::camera::{Camera, CameraControls, CameraShake};
+use crate::ecs::{self, NetworkId, Side, Unit};
+use crate::net::{self, Lockstep, PlayerCommand};
+use crate::pathfinding::Map;
+use crate::profiler::{self, Profiler};
+use crate::resources::{
+    AiBuildOrders, CheeseCoins, ControlGroups, CursorIcon, DebugControls, DeltaTime, DpiScaling,
+    GameSpeed, GameStats, Gravity, Keypresses, Mode, MouseState, Objectives, Outcome, PlayerSide,
+    RayCastLocation, RtsControls, ScreenDimensions, SelectedUnitsAbilities, Settings, TimeOfDay,
+    TotalTime, UnscaledDeltaTime,
+};
+use crate::terrain::Terrain;
+use crate::{mission, scenarios};
+use legion::*;
+use rand::{rngs::SmallRng, SeedableRng};
+use std::path::Path;
+use std::time::Instant;
+
+// 10 minutes of game time.
+const DEFAULT_TICKS: u32 = 36_000;
+
+pub struct Simulation {
+    pub world: World,
+    pub resources: Resources,
+    schedule: Schedule,
+    seed: u64,
+}
+
+impl Simulation {
+    // The same seed gives the same game every time, as long as the same commands are issued on the
+    // same ticks.
+    pub fn new(seed: u64) -> anyhow::Result<Self> {
+        let mut resources = Resources::default();
+        // Nothing ever looks at the screen, but the controls still need some dimensions to work with.
+        let screen_dimensions = ScreenDimensions {
+            width: 1280,
+            height: 720,
+        };
+        resources.insert(MouseState::new(&screen_dimensions));
+        resources.insert(screen_dimensions);
+        resources.insert(DpiScaling(1.0));
+        resources.insert(CursorIcon(winit::window::CursorIcon::default()));
+        resources.insert(Camera::default());
+        resources.insert(CameraControls::default());
+        resources.insert(SoundEvents::default());
+        resources.insert(CameraShake::default());
+        resources.insert(ecs::Alerts::default());
+        resources.insert(ecs::Upgrades::default());
+        resources.insert(RtsControls::default());
+        resources.insert(RayCastLocation::default());
+        resources.insert(ControlGroups::default());
+        resources.insert(Keypresses::default());
+        resources.insert(DebugControls::default());
+        resources.insert(SelectedUnitsAbilities::default());
+        resources.insert(PlayerSide(Side::Green));
+        resources.insert(DeltaTime(net::TICK_LENGTH));
+        resources.insert(UnscaledDeltaTime(net::TICK_LENGTH));
+        resources.insert(TotalTime(0.0));
+        resources.insert(TimeOfDay::default());
+        resources.insert(Gravity(5.0));
+        resources.insert(CheeseCoins(0));
+        resources.insert(AiBuildOrders::default());
+        resources.insert(GameStats::default());
+        resources.insert(GameSpeed::default());
+        resources.insert(Objectives::default());
+        resources.insert(Mode::Playing);
+        resources.insert(Map::new());
+        resources.insert(Terrain::generate());
+        resources.insert(Settings::default());
+        resources.insert(ModelAnimations::load()?);
+        resources.insert(SmallRng::seed_from_u64(seed));
+        resources.insert(Lockstep::default());
+        resources.insert(Profiler::default());
+        resources.insert(mission::Mission::default());
+        resources.insert(crate::scenery::Scenery::default());
+        resources.insert(ecs::FogOfWar::default());
+        resources.insert(ecs::PathRequests::default());
+        resources.insert(ecs::Inspector::default());
+        resources.insert(ecs::IdleUnits::default());
+        resources.insert(ecs::WorldChecksum::default());
+        resources.insert(ecs::Console::default());
+        resources.insert(ecs::SpatialHash::default());
+
+        let mut builder = Schedule::builder();
+        profiler::mark_stage(&mut builder, Some("Gameplay"));
+        ecs::add_gameplay_systems(&mut builder);
+        profiler::mark_stage(&mut builder, Some("Cleanup"));
+        ecs::add_cleanup_systems(&mut builder);
+        profiler::mark_stage(&mut builder, None);
+
+        Ok(Self {
+            world: World::default(),
+            resources,
+            schedule: builder.build(),
+            seed,
+        })
+    }
+
+    // Takes the same numbers as `Mode::StartScenario`. `bench_units` is only used by
+    // `scenarios::BENCH_SCENARIO`.
+    pub fn start_scenario(&mut self, scenario: u8, bench_units: u32) {
+        scenarios::start(
+            &mut self.world,
+            &self.resources,
+            scenario,
+            Some(self.seed),
+            Path::new(mission::DEFAULT_MISSION_PATH),
+            bench_units,
+        );
+        *self.resources.get_mut::<Mode>().unwrap() = Mode::Playing;
+    }
+
+    // Does nothing once the game is over.
+    pub fn tick(&mut self) {
+        if *self.resources.get::<Mode>().unwrap() != Mode::Playing {
+            return;
+        }
+
+        self.resources.insert(DeltaTime(net::TICK_LENGTH));
+        self.resources.insert(UnscaledDeltaTime(net::TICK_LENGTH));
+        self.schedule.execute(&mut self.world, &mut self.resources);
+        // Not networked, so this can't fail.
+        let _ = self.resources.get_mut::<Lockstep>().unwrap().end_tick();
+        self.resources.get_mut::<TotalTime>().unwrap().0 += net::TICK_LENGTH;
+        self.resources.get_mut::<SoundEvents>().unwrap().clear();
+        self.resources.get_mut::<CameraShake>().unwrap().clear();
+    }
+
+    // Either side can be given commands, and they get carried out on the next tick. Nothing in the
+    // game itself needs this, it's for tests and bots driving the simulation from outside.
+    #[allow(dead_code)]
+    pub fn issue(&mut self, side: Side, command: PlayerCommand) {
+        self.resources
+            .get_mut::<Lockstep>()
+            .unwrap()
+            .issue(side, command);
+    }
+
+    // The same on every machine for the same game, as long as they're all built with the
+    // `deterministic` feature.
+    pub fn checksum(&self) -> u64 {
+        self.resources.get::<ecs::WorldChecksum>().unwrap().0
+    }
+
+    // From the point of view of `PlayerSide`, which is green.
+    pub fn outcome(&self) -> Option<Outcome> {
+        match *self.resources.get::<Mode>().unwrap() {
+            Mode::GameOver(outcome) => Some(outcome),
+            _ => None,
+        }
+    }
+
+    // The ids of all the units on a side, for giving them commands. Ids are handed out at the start
+    // of each tick, so anything that's only just been spawned won't be in here yet.
+    pub fn units(&self, side: Side) -> Vec<NetworkId> {
+        <(&NetworkId, &Side)>::query()
+            .filter(component::<Unit>())
+            .iter(&self.world)
+            .filter(|(_, unit_side)| **unit_side == side)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+pub struct HeadlessOptions {
+    pub scenario: u8,
+    pub ticks: u32,
+    pub seed: u64,
+}
+
+pub fn options() -> Option<HeadlessOptions> {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.get(1).map(|arg| arg.as_str()) != Some("--headless") {
+        return None;
+    }
+
+    Some(HeadlessOptions {
+        scenario: args
+            .get(2)
+            .and_then(|arg| arg.parse().ok())
+            .unwrap_or(scenarios::MULTIPLAYER_SCENARIO),
+        ticks: args
+            .get(3)
+            .and_then(|arg| arg.parse().ok())
+            .unwrap_or(DEFAULT_TICKS),
+        seed: args.get(4).and_then(|arg| arg.parse().ok()).unwrap_or(0),
+    })
+}
+
+pub fn run(options: HeadlessOptions) -> anyhow::Result<()> {
+    let start = Instant::now();
+
+    let mut simulation = Simulation::new(options.seed)?;
+    simulation.start_scenario(options.scenario, 0);
+
+    let mut ticks = 0;
+    while ticks < options.ticks && simulation.outcome().is_none() {
+        simulation.tick();
+        ticks += 1;
+    }
+
+    let outcome = match simulation.outcome() {
+        Some(Outcome::Won) => "Green won",
+        Some(Outcome::Lost) => "Purple won",
+        None => "Nobody won",
+    };
+
+    println!(
+        "{} after {} ticks ({:.0} seconds of game time), which took {:.2} seconds",
+        outcome,
+        ticks,
+        simulation.resources.get::<TotalTime>().unwrap().0,
+        start.elapsed().as_secs_f32()
+    );
+    println!(
+        "Units left: {} green, {} purple",
+        simulation.units(Side::Green).len(),
+        simulation.units(Side::Purple).len()
+    );
+    // For comparing runs on different machines.
+    println!("Checksum: {:016x}", simulation.checksum());
+
+    Ok(())
+}
+
+#[test]
+fn commands_are_carried_out_headlessly() {
+    let mut simulation = Simulation::new(0).unwrap();
+    // Just one marine on each side, attack-moving towards each other.
+    simulation.start_scenario(scenarios::BENCH_SCENARIO, 2);
+
+    let positions = |simulation: &Simulation| -> Vec<ultraviolet::Vec2> {
+        let mut positions: Vec<_> = <(&Side, &ecs::Position)>::query()
+            .iter(&simulation.world)
+            .map(|(side, position)| (*side, position.0))
+            .collect();
+        positions.sort_by_key(|(side, _)| *side != Side::Green);
+        positions
+            .into_iter()
+            .map(|(_, position)| position)
+            .collect()
+    };
+
+    simulation.tick();
+    let before = positions(&simulation);
+    let green = simulation.units(Side::Green);
+    simulation.issue(Side::Green, PlayerCommand::Stop { units: green });
+
+    for _ in 0..30 {
+        simulation.tick();
+    }
+
+    let after = positions(&simulation);
+    assert_eq!(before.len(), 2);
+    assert!((after[0] - before[0]).mag() < 0.01);
+    assert!((after[1] - before[1]).mag() > 0.1);
+}
+
+#[test]
+fn the_same_game_gives_the_same_checksums() {
+    let run = |stop: bool| -> Vec<u64> {
+        let mut simulation = Simulation::new(1).unwrap();
+        simulation.start_scenario(scenarios::BENCH_SCENARIO, 20);
+        simulation.tick();
+
+        if stop {
+            let green = simulation.units(Side::Green);
+            simulation.issue(Side::Green, PlayerCommand::Stop { units: green });
+        }
+
+        (0..60)
+            .map(|_| {
+                simulation.tick();
+                simulation.checksum()
+            })
+            .collect()
+    };
+
+    assert_eq!(run(false), run(false));
+    assert_ne!(run(false).last(), run(true).last());
+}
@@ -0,0 +1,282 @@
+use crate::assets::generate_tangents;
+use crate::high_ground;
+use crate::pathfinding::MAP_SIZE;
+use crate::renderer::Vertex;
+use crate::water::{LAKES, WATER_LEVEL};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use ultraviolet::{Vec2, Vec3, Vec4};
+
+// Every match is played on the same map for now, so the hills are always generated from the same
+// seed.
+const SEED: u64 = 0xC4EE5E;
+
+// The map is split into 2 x 2 cells, with a height for each corner.
+const CELL_SIZE: f32 = 2.0;
+const CELLS: usize = (MAP_SIZE / CELL_SIZE) as usize;
+const VERTICES: usize = CELLS + 1;
+
+const MAX_HEIGHT: f32 = 4.0;
+
+// The ground is raised up into a bank around each lake so that the edges of the water are hidden,
+// then it slopes down into the lakebed.
+const BANK_WIDTH: f32 = 6.0;
+const SHORE_WIDTH: f32 = 4.0;
+const LAKE_DEPTH: f32 = 1.5;
+
+// How many times the surface texture repeats across the map.
+const TEXTURE_REPEATS: u32 = 10;
+
+pub struct Terrain {
+    heights: Vec<f32>,
+}
+
+// Completely flat, which is handy for tests.
+impl Default for Terrain {
+    fn default() -> Self {
+        Self {
+            heights: vec![0.0; VERTICES * VERTICES],
+        }
+    }
+}
+
+impl Terrain {
+    // Rolling hills made out of a couple of octaves of value noise.
+    pub fn generate() -> Self {
+        let mut rng = SmallRng::seed_from_u64(SEED);
+
+        let octaves = [
+            ValueNoise::new(&mut rng, 40.0, 1.0),
+            ValueNoise::new(&mut rng, 15.0, 0.35),
+        ];
+        let total_amplitude: f32 = octaves.iter().map(|octave| octave.amplitude).sum();
+
+        let heights = (0..VERTICES * VERTICES)
+            .map(|i| {
+                let point = vertex_position(i % VERTICES, i / VERTICES);
+                let noise: f32 = octaves.iter().map(|octave| octave.sample(point)).sum();
+                let height = carve_lakes(noise / total_amplitude * MAX_HEIGHT, point);
+                height + high_ground::elevation_at(point)
+            })
+            .collect();
+
+        Self { heights }
+    }
+
+    fn height(&self, x: usize, z: usize) -> f32 {
+        self.heights[z.min(CELLS) * VERTICES + x.min(CELLS)]
+    }
+
+    // Bilinearly interpolated, so that it matches the mesh closely enough.
+    pub fn height_at(&self, point: Vec2) -> f32 {
+        let cell = (point + Vec2::broadcast(MAP_SIZE / 2.0)) / CELL_SIZE;
+        let cell = cell.clamped(Vec2::zero(), Vec2::broadcast(CELLS as f32));
+
+        let x = (cell.x as usize).min(CELLS - 1);
+        let z = (cell.y as usize).min(CELLS - 1);
+        let x_factor = cell.x - x as f32;
+        let z_factor = cell.y - z as f32;
+
+        let top = lerp(self.height(x, z), self.height(x + 1, z), x_factor);
+        let bottom = lerp(self.height(x, z + 1), self.height(x + 1, z + 1), x_factor);
+        lerp(top, bottom, z_factor)
+    }
+
+    // A point on the map lifted up onto the surface.
+    pub fn ground(&self, point: Vec2) -> Vec3 {
+        Vec3::new(point.x, self.height_at(point), point.y)
+    }
+
+    fn slope_at(&self, point: Vec2) -> Vec2 {
+        let x = Vec2::new(CELL_SIZE, 0.0);
+        let z = Vec2::new(0.0, CELL_SIZE);
+
+        Vec2::new(
+            self.height_at(point + x) - self.height_at(point - x),
+            self.height_at(point + z) - self.height_at(point - z),
+        ) / (2.0 * CELL_SIZE)
+    }
+
+    pub fn normal_at(&self, point: Vec2) -> Vec3 {
+        let slope = self.slope_at(point);
+        Vec3::new(-slope.x, 1.0, -slope.y).normalized()
+    }
+
+    pub fn mesh(&self) -> (Vec<Vertex>, Vec<u32>) {
+        let vertices = (0..VERTICES * VERTICES)
+            .map(|i| {
+                let (x, z) = (i % VERTICES, i / VERTICES);
+                let point = vertex_position(x, z);
+
+                Vertex {
+                    position: Vec3::new(point.x, self.height(x, z), point.y),
+                    normal: self.normal_at(point),
+                    // The splat texture covers the whole map.
+                    uv: Vec2::new(x as f32, z as f32) / CELLS as f32,
+                    tangent: Vec4::zero(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let index = |x: usize, z: usize| (z * VERTICES + x) as u32;
+
+        let indices = (0..CELLS * CELLS)
+            .flat_map(|i| {
+                let (x, z) = (i % CELLS, i / CELLS);
+                let (a, b, c, d) = (
+                    index(x, z + 1),
+                    index(x + 1, z + 1),
+                    index(x + 1, z),
+                    index(x, z),
+                );
+                // Counter-clockwise when looking down from above.
+                vec![a, b, c, a, c, d]
+            })
+            .collect::<Vec<_>>();
+
+        let tangents = generate_tangents(
+            &vertices
+                .iter()
+                .map(|vertex| vertex.position)
+                .collect::<Vec<_>>(),
+            &vertices
+                .iter()
+                .map(|vertex| vertex.normal)
+                .collect::<Vec<_>>(),
+            &vertices.iter().map(|vertex| vertex.uv).collect::<Vec<_>>(),
+            &indices,
+        );
+
+        let vertices = vertices
+            .into_iter()
+            .zip(tangents)
+            .map(|(vertex, tangent)| Vertex { tangent, ..vertex })
+            .collect();
+
+        (vertices, indices)
+    }
+
+    // Bake a texture for the whole map by blending layers made from the surface texture: dry,
+    // crumbly rock on the slopes, paler crust on the hilltops, soggy cheese by the water and regular
+    // cheese everywhere else.
+    pub fn splat_texture(&self, surface: &image::RgbaImage) -> image::RgbaImage {
+        let size = surface.width() * TEXTURE_REPEATS;
+
+        image::RgbaImage::from_fn(size, size, |x, y| {
+            let point = Vec2::new(x as f32, y as f32) / size as f32 * MAP_SIZE
+                - Vec2::broadcast(MAP_SIZE / 2.0);
+
+            let texel = surface
+                .get_pixel(x % surface.width(), y % surface.height())
+                .0;
+            let cheese = Vec4::new(
+                texel[0] as f32,
+                texel[1] as f32,
+                texel[2] as f32,
+                texel[3] as f32,
+            ) / 255.0;
+
+            let grey = (cheese.x + cheese.y + cheese.z) / 3.0;
+            let rock = Vec4::new(grey * 0.7, grey * 0.6, grey * 0.5, cheese.w);
+            let crust = cheese * 0.7 + Vec4::one() * 0.3;
+            let soggy = Vec4::new(cheese.x * 0.5, cheese.y * 0.5, cheese.z * 0.4, cheese.w);
+
+            let steepness = self.slope_at(point).mag();
+            let rock_weight = smoothstep(0.1, 0.25, steepness);
+            let height = self.height_at(point);
+            let crust_weight = smoothstep(0.6, 0.9, height / MAX_HEIGHT);
+            let soggy_weight = smoothstep(WATER_LEVEL + 0.5, WATER_LEVEL, height);
+
+            let colour = cheese * (1.0 - crust_weight) + crust * crust_weight;
+            let colour = colour * (1.0 - rock_weight) + rock * rock_weight;
+            let colour = colour * (1.0 - soggy_weight) + soggy * soggy_weight;
+
+            image::Rgba([
+                (colour.x * 255.0) as u8,
+                (colour.y * 255.0) as u8,
+                (colour.z * 255.0) as u8,
+                (colour.w * 255.0) as u8,
+            ])
+        })
+    }
+}
+
+fn carve_lakes(height: f32, point: Vec2) -> f32 {
+    LAKES.iter().fold(height, |height, lake| {
+        let distance = lake.signed_distance(point);
+        let bank = height.max(WATER_LEVEL + 0.3);
+
+        if distance > 0.0 {
+            lerp(bank, height, smoothstep(0.0, BANK_WIDTH, distance))
+        } else {
+            let lakebed = WATER_LEVEL - LAKE_DEPTH;
+            lerp(bank, lakebed, smoothstep(0.0, SHORE_WIDTH, -distance))
+        }
+    })
+}
+
+fn vertex_position(x: usize, z: usize) -> Vec2 {
+    Vec2::new(x as f32, z as f32) * CELL_SIZE - Vec2::broadcast(MAP_SIZE / 2.0)
+}
+
+fn lerp(a: f32, b: f32, factor: f32) -> f32 {
+    a + (b - a) * factor
+}
+
+pub fn smoothstep(edge_a: f32, edge_b: f32, value: f32) -> f32 {
+    let t = ((value - edge_a) / (edge_b - edge_a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Random values on a grid, smoothly interpolated between.
+struct ValueNoise {
+    spacing: f32,
+    amplitude: f32,
+    points: usize,
+    values: Vec<f32>,
+}
+
+impl ValueNoise {
+    fn new(rng: &mut SmallRng, spacing: f32, amplitude: f32) -> Self {
+        let points = (MAP_SIZE / spacing).ceil() as usize + 2;
+
+        Self {
+            spacing,
+            amplitude,
+            points,
+            values: (0..points * points)
+                .map(|_| rng.gen_range(0.0, 1.0))
+                .collect(),
+        }
+    }
+
+    fn sample(&self, point: Vec2) -> f32 {
+        let point = (point + Vec2::broadcast(MAP_SIZE / 2.0)) / self.spacing;
+        let x = (point.x.max(0.0) as usize).min(self.points - 2);
+        let z = (point.y.max(0.0) as usize).min(self.points - 2);
+        let x_factor = smoothstep(0.0, 1.0, point.x - x as f32);
+        let z_factor = smoothstep(0.0, 1.0, point.y - z as f32);
+
+        let value = |x: usize, z: usize| self.values[z * self.points + x];
+
+        let top = lerp(value(x, z), value(x + 1, z), x_factor);
+        let bottom = lerp(value(x, z + 1), value(x + 1, z + 1), x_factor);
+        lerp(top, bottom, z_factor) * self.amplitude
+    }
+}
+
+#[test]
+fn height_at_matches_the_corners_of_cells() {
+    let terrain = Terrain::generate();
+
+    for &(x, z) in &[(0, 0), (10, 20), (CELLS, CELLS), (CELLS / 2, 3)] {
+        let point = vertex_position(x, z);
+        assert!((terrain.height_at(point) - terrain.height(x, z)).abs() < 0.001);
+    }
+
+    // Points off the edge of the map are clamped to it.
+    let corner = Vec2::broadcast(MAP_SIZE / 2.0);
+    assert_eq!(
+        terrain.height_at(corner + Vec2::new(10.0, 10.0)),
+        terrain.height(CELLS, CELLS)
+    );
+}
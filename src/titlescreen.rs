@@ -1,9 +1,16 @@
+use crate::input::{Action, Input};
+use crate::lobby::{self, Lobby};
 use crate::renderer::{
     Font, LineBuffers, ModelInstance, TextAlignment, TextBuffer, TitlescreenBuffer,
 };
-use crate::resources::{CursorIcon, DeltaTime, DpiScaling, Mode, MouseState, ScreenDimensions};
+use crate::resources::{
+    CursorIcon, DeltaTime, DpiScaling, Keypress, Keypresses, Mode, MouseState, ScreenDimensions,
+    Settings,
+};
 use legion::*;
+use std::borrow::Cow;
 use ultraviolet::{Mat4, Rotor3, Vec2, Vec3, Vec4};
+use winit::event::VirtualKeyCode;
 
 pub fn camera_view() -> Mat4 {
     // The camera is at zero and looks towards the z axis.
@@ -14,18 +21,58 @@ const TITLE_POSITION: Vec2 = Vec2::new(0.3, 1.0 / 4.0);
 const MOON_POSITION: Vec3 = Vec3::new(-1.5, 0.0, 3.0);
 
 const MAIN_MENU: &'static [(&'static str, Vec2)] = &[
-    ("Scenarios", Vec2::new(0.3, 3.5 / 6.0)),
-    ("Quit", Vec2::new(0.3, 4.0 / 6.0)),
+    ("New Game", Vec2::new(0.3, 3.0 / 6.0)),
+    ("Multiplayer", Vec2::new(0.3, 3.5 / 6.0)),
+    ("Load", Vec2::new(0.3, 4.0 / 6.0)),
+    ("Settings", Vec2::new(0.3, 4.5 / 6.0)),
+    ("Quit", Vec2::new(0.3, 5.0 / 6.0)),
 ];
 
 const SCENARIOS_MENU: &'static [(&'static str, Vec2)] = &[
-    ("Training 1: Attacking", Vec2::new(0.3, 3.5 / 6.0)),
-    ("Training 2: Base Building", Vec2::new(0.3, 4.0 / 6.0)),
-    ("Skirmish", Vec2::new(0.3, 4.5 / 6.0)),
+    ("Training 1: Attacking", Vec2::new(0.3, 2.5 / 6.0)),
+    ("Training 2: Base Building", Vec2::new(0.3, 3.0 / 6.0)),
+    ("Skirmish", Vec2::new(0.3, 3.5 / 6.0)),
+    ("Random Map", Vec2::new(0.3, 4.0 / 6.0)),
+    ("Mission", Vec2::new(0.3, 4.5 / 6.0)),
     ("Sandbox", Vec2::new(0.3, 5.0 / 6.0)),
     ("Back", Vec2::new(0.3, 5.5 / 6.0)),
 ];
 
+const SETTINGS_MENU: &'static [(&'static str, Vec2)] = &[
+    ("Bloom", Vec2::new(0.3, 2.0 / 6.0)),
+    ("Tone mapping", Vec2::new(0.3, 2.4 / 6.0)),
+    ("Vignette", Vec2::new(0.3, 2.8 / 6.0)),
+    ("Shadows", Vec2::new(0.3, 3.2 / 6.0)),
+    ("Anti-aliasing", Vec2::new(0.3, 3.6 / 6.0)),
+    ("Render scale", Vec2::new(0.3, 4.0 / 6.0)),
+    ("Damage numbers", Vec2::new(0.3, 4.4 / 6.0)),
+    ("Camera shake", Vec2::new(0.3, 4.8 / 6.0)),
+    ("Controls", Vec2::new(0.3, 5.2 / 6.0)),
+    ("Back", Vec2::new(0.3, 5.6 / 6.0)),
+];
+
+// Clicking on the address lets you type in a new one.
+const MULTIPLAYER_MENU: &'static [(&'static str, Vec2)] = &[
+    ("Address", Vec2::new(0.3, 3.5 / 6.0)),
+    ("Host", Vec2::new(0.3, 4.0 / 6.0)),
+    ("Join", Vec2::new(0.3, 4.5 / 6.0)),
+    ("Back", Vec2::new(0.3, 5.5 / 6.0)),
+];
+
+const LOBBY_MENU: &'static [(&'static str, Vec2)] = &[
+    ("Side", Vec2::new(0.3, 2.5 / 6.0)),
+    ("Colour", Vec2::new(0.3, 3.0 / 6.0)),
+    ("Map", Vec2::new(0.3, 3.5 / 6.0)),
+    ("Ready", Vec2::new(0.3, 4.0 / 6.0)),
+    ("Leave", Vec2::new(0.3, 5.5 / 6.0)),
+];
+
+// Where `Lobby::status_text` goes.
+const STATUS_POSITION: Vec2 = Vec2::new(0.3, 4.75 / 6.0);
+
+// The bindings themselves are listed above this, see `control_items`.
+const CONTROLS_MENU: &'static [(&'static str, Vec2)] = &[("Back", Vec2::new(0.3, 5.5 / 6.0))];
+
 pub const TEXT_COLOUR: Vec4 = Vec4::new(0.867, 0.675, 0.086, 1.0);
 
 pub fn selected_colour() -> Vec4 {
@@ -35,6 +82,11 @@ pub fn selected_colour() -> Vec4 {
 pub enum Menu {
     Main,
     Scenarios,
+    Settings,
+    Multiplayer,
+    Lobby,
+    // Clicking on an action waits for the next key press and binds the action to that key.
+    Controls { rebinding: Option<Action> },
 }
 
 impl Menu {
@@ -42,16 +94,101 @@ impl Menu {
         match self {
             Self::Main => MAIN_MENU,
             Self::Scenarios => SCENARIOS_MENU,
+            Self::Settings => SETTINGS_MENU,
+            Self::Multiplayer => MULTIPLAYER_MENU,
+            Self::Lobby => LOBBY_MENU,
+            Self::Controls { .. } => CONTROLS_MENU,
         }
     }
 }
 
+// There are too many actions to fit in one column with the big font, so they're in three columns
+// with the UI font.
+fn control_items() -> impl Iterator<Item = (Action, Vec2)> {
+    const ROWS: usize = 10;
+
+    Action::ALL
+        .iter()
+        .copied()
+        .filter(|action| !action.is_pointer())
+        .enumerate()
+        .map(|(i, action)| {
+            let column = (i / ROWS) as f32;
+            let row = (i % ROWS) as f32;
+            (action, Vec2::new(0.2 + column * 0.3, 0.42 + row * 0.045))
+        })
+}
+
+fn control_label(action: Action, settings: &Settings, rebinding: Option<Action>) -> String {
+    if rebinding == Some(action) {
+        return format!("{}: press a key", action.name());
+    }
+
+    let bindings = settings
+        .input_map
+        .bindings(action)
+        .iter()
+        .map(|input| input.to_string())
+        .collect::<Vec<_>>();
+
+    if bindings.is_empty() {
+        format!("{}: unbound", action.name())
+    } else {
+        format!("{}: {}", action.name(), bindings.join(" / "))
+    }
+}
+
+// Clicking on these cycles through the values.
+const MSAA_OPTIONS: &[u32] = &[1, 2, 4, 8];
+const RENDER_SCALE_OPTIONS: &[f32] = &[0.5, 0.75, 1.0, 1.5, 2.0];
+
+// The option after `current`, wrapping back around to the first one.
+fn next_option<T: Copy + PartialOrd>(options: &[T], current: T) -> T {
+    options
+        .iter()
+        .copied()
+        .find(|option| *option > current)
+        .unwrap_or(options[0])
+}
+
+// The settings are shown along with whether they're on or off, e.g. 'Bloom: On'.
+fn label(text: &'static str, settings: &Settings, lobby: &Lobby) -> Cow<'static, str> {
+    if let Some(label) = lobby.label(text) {
+        return Cow::Owned(label);
+    }
+
+    let setting = match text {
+        "Anti-aliasing" if settings.msaa > 1 => {
+            return Cow::Owned(format!("{}: {}x", text, settings.msaa))
+        }
+        "Anti-aliasing" => false,
+        "Render scale" => {
+            return Cow::Owned(format!(
+                "{}: {}%",
+                text,
+                (settings.render_scale * 100.0).round()
+            ))
+        }
+        "Bloom" => settings.bloom,
+        "Tone mapping" => settings.tone_mapping,
+        "Vignette" => settings.vignette,
+        "Shadows" => settings.shadows,
+        "Damage numbers" => settings.damage_numbers,
+        "Camera shake" => settings.camera_shake,
+        _ => return Cow::Borrowed(text),
+    };
+
+    Cow::Owned(format!("{}: {}", text, if setting { "On" } else { "Off" }))
+}
+
 pub fn titlescreen_schedule() -> Schedule {
     let mut builder = Schedule::builder();
 
     builder
         .add_system(update_system())
+        .add_system(lobby::update_lobby_system())
         .add_system(handle_clicks_system())
+        .add_system(handle_rebinding_system())
         .add_system(render_text_system());
     //.(render_click_regions_system())
 
@@ -85,6 +222,8 @@ fn render_text(
     #[resource] mouse_state: &MouseState,
     #[resource] cursor_icon: &mut CursorIcon,
     #[resource] menu: &Menu,
+    #[resource] settings: &Settings,
+    #[resource] lobby: &Lobby,
 ) {
     let screen_dimensions = screen_dimensions.as_vec();
 
@@ -98,10 +237,11 @@ fn render_text(
         TEXT_COLOUR,
     );
 
-    for (text, position) in menu.list().iter() {
-        let center = *position * screen_dimensions;
+    for &(text, position) in menu.list().iter() {
+        let center = position * screen_dimensions;
+        let text = label(text, settings, lobby);
 
-        let (top_left, bottom_right) = text_selection_area(center, text, dpi_scaling.0);
+        let (top_left, bottom_right) = text_selection_area(center, &text, dpi_scaling.0);
         let selected = point_in_area(mouse_state.position, top_left, bottom_right);
 
         if selected {
@@ -110,7 +250,7 @@ fn render_text(
 
         text_buffer.render_text(
             center,
-            text,
+            &text,
             Font::Title,
             1.0,
             dpi_scaling.0,
@@ -122,6 +262,46 @@ fn render_text(
             },
         );
     }
+
+    if let (Menu::Multiplayer | Menu::Lobby, Some(status)) = (menu, lobby.status_text()) {
+        text_buffer.render_text(
+            STATUS_POSITION * screen_dimensions,
+            &status,
+            Font::Ui,
+            1.0,
+            dpi_scaling.0,
+            TextAlignment::Center,
+            TEXT_COLOUR,
+        );
+    }
+
+    if let Menu::Controls { rebinding } = menu {
+        for (action, position) in control_items() {
+            let center = position * screen_dimensions;
+            let text = control_label(action, settings, *rebinding);
+
+            let (top_left, bottom_right) = ui_text_selection_area(center, &text, dpi_scaling.0);
+            let selected = point_in_area(mouse_state.position, top_left, bottom_right);
+
+            if selected {
+                cursor_icon.0 = winit::window::CursorIcon::Hand;
+            }
+
+            text_buffer.render_text(
+                center,
+                &text,
+                Font::Ui,
+                1.0,
+                dpi_scaling.0,
+                TextAlignment::Center,
+                if selected || *rebinding == Some(action) {
+                    selected_colour()
+                } else {
+                    TEXT_COLOUR
+                },
+            );
+        }
+    }
 }
 
 #[legion::system]
@@ -130,15 +310,27 @@ fn render_click_regions(
     #[resource] screen_dimensions: &ScreenDimensions,
     #[resource] dpi_scaling: &DpiScaling,
     #[resource] menu: &Menu,
+    #[resource] settings: &Settings,
+    #[resource] lobby: &Lobby,
 ) {
     let screen_dimensions = screen_dimensions.as_vec();
 
-    for (text, position) in menu.list().iter() {
-        let center = *position * screen_dimensions;
+    for &(text, position) in menu.list().iter() {
+        let center = position * screen_dimensions;
 
-        let (top_left, bottom_right) = text_selection_area(center, text, dpi_scaling.0);
+        let (top_left, bottom_right) =
+            text_selection_area(center, &label(text, settings, lobby), dpi_scaling.0);
         line_buffers.draw_rect(top_left, bottom_right, dpi_scaling.0);
     }
+
+    if let Menu::Controls { rebinding } = menu {
+        for (action, position) in control_items() {
+            let text = control_label(action, settings, *rebinding);
+            let (top_left, bottom_right) =
+                ui_text_selection_area(position * screen_dimensions, &text, dpi_scaling.0);
+            line_buffers.draw_rect(top_left, bottom_right, dpi_scaling.0);
+        }
+    }
 }
 
 #[legion::system]
@@ -148,21 +340,91 @@ fn handle_clicks(
     #[resource] mouse_state: &MouseState,
     #[resource] mode: &mut Mode,
     #[resource] menu: &mut Menu,
+    #[resource] settings: &mut Settings,
+    #[resource] lobby: &mut Lobby,
 ) {
     if !mouse_state.left_state.was_clicked() {
         return;
     }
 
+    // Clicking anywhere stops typing in the address.
+    lobby.editing_address = false;
+
     let screen_dimensions = screen_dimensions.as_vec();
 
     for &(text, position) in menu.list().iter() {
         let center = position * screen_dimensions;
-        let (top_left, bottom_right) = text_selection_area(center, text, dpi_scaling.0);
+        let (top_left, bottom_right) =
+            text_selection_area(center, &label(text, settings, lobby), dpi_scaling.0);
         if point_in_area(mouse_state.position, top_left, bottom_right) {
             match text {
-                "Scenarios" => {
+                "New Game" => {
                     *menu = Menu::Scenarios;
                 }
+                "Multiplayer" => {
+                    *menu = Menu::Multiplayer;
+                }
+                "Address" => {
+                    lobby.editing_address = true;
+                }
+                "Host" => {
+                    lobby.host();
+                    *menu = Menu::Lobby;
+                }
+                "Join" => {
+                    lobby.join();
+                    *menu = Menu::Lobby;
+                }
+                "Side" => {
+                    lobby.cycle_side();
+                }
+                "Colour" => {
+                    lobby.cycle_colour();
+                }
+                "Map" => {
+                    lobby.cycle_map();
+                }
+                "Ready" => {
+                    lobby.toggle_ready();
+                }
+                "Leave" => {
+                    lobby.leave();
+                    *menu = Menu::Multiplayer;
+                }
+                "Load" => {
+                    *mode = Mode::LoadGame;
+                }
+                "Settings" => {
+                    *menu = Menu::Settings;
+                }
+                "Bloom" => {
+                    settings.bloom = !settings.bloom;
+                }
+                "Tone mapping" => {
+                    settings.tone_mapping = !settings.tone_mapping;
+                }
+                "Vignette" => {
+                    settings.vignette = !settings.vignette;
+                }
+                "Shadows" => {
+                    settings.shadows = !settings.shadows;
+                }
+                "Anti-aliasing" => {
+                    settings.msaa = next_option(MSAA_OPTIONS, settings.msaa);
+                }
+                "Render scale" => {
+                    settings.render_scale =
+                        next_option(RENDER_SCALE_OPTIONS, settings.render_scale);
+                }
+                "Damage numbers" => {
+                    settings.damage_numbers = !settings.damage_numbers;
+                }
+                "Camera shake" => {
+                    settings.camera_shake = !settings.camera_shake;
+                }
+                "Controls" => {
+                    *menu = Menu::Controls { rebinding: None };
+                }
                 "Training 1: Attacking" => {
                     *mode = Mode::StartScenario(1);
                 }
@@ -172,11 +434,20 @@ fn handle_clicks(
                 "Skirmish" => {
                     *mode = Mode::StartScenario(3);
                 }
+                "Random Map" => {
+                    *mode = Mode::StartScenario(4);
+                }
+                "Mission" => {
+                    *mode = Mode::StartScenario(5);
+                }
                 "Sandbox" => {
                     *mode = Mode::StartScenario(255);
                 }
                 "Back" => {
-                    *menu = Menu::Main;
+                    *menu = match menu {
+                        Menu::Controls { .. } => Menu::Settings,
+                        _ => Menu::Main,
+                    };
                 }
                 "Quit" => {
                     *mode = Mode::Quit;
@@ -186,6 +457,50 @@ fn handle_clicks(
             return;
         }
     }
+
+    if let Menu::Controls { rebinding } = menu {
+        for (action, position) in control_items() {
+            let text = control_label(action, settings, *rebinding);
+            let (top_left, bottom_right) =
+                ui_text_selection_area(position * screen_dimensions, &text, dpi_scaling.0);
+            if point_in_area(mouse_state.position, top_left, bottom_right) {
+                *rebinding = Some(action);
+                return;
+            }
+        }
+    }
+}
+
+#[legion::system]
+fn handle_rebinding(
+    #[resource] keypresses: &mut Keypresses,
+    #[resource] menu: &mut Menu,
+    #[resource] settings: &mut Settings,
+    #[resource] lobby: &mut Lobby,
+) {
+    // This needs to drain the keypresses even when nothing is being rebound, otherwise they'd all
+    // get handled at once when a game starts.
+    for Keypress { code, pressed, .. } in keypresses.0.drain(..) {
+        // The characters themselves come through `WindowEvent::ReceivedCharacter`.
+        if lobby.editing_address && pressed {
+            match code {
+                Some(VirtualKeyCode::Back) => {
+                    lobby.address.pop();
+                }
+                Some(VirtualKeyCode::Return) | Some(VirtualKeyCode::Escape) => {
+                    lobby.editing_address = false;
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        if let (Menu::Controls { rebinding }, Some(code), true) = (&mut *menu, code, pressed) {
+            if let Some(action) = rebinding.take() {
+                settings.input_map.rebind(action, Input::Key(code));
+            }
+        }
+    }
 }
 
 // Kinda hacky code to get a selection box around some text. Works well enough though.
@@ -198,6 +513,13 @@ pub fn text_selection_area(center: Vec2, text: &str, dpi_scaling: f32) -> (Vec2,
     (center - dimensions / 2.0, center + dimensions / 2.0)
 }
 
+// Roboto Mono is monospaced, with each character being 0.6 times as wide as the font size.
+fn ui_text_selection_area(center: Vec2, text: &str, dpi_scaling: f32) -> (Vec2, Vec2) {
+    let dimensions = Vec2::new(text.len() as f32 * 0.6, 1.0) * Font::Ui.scale() * dpi_scaling;
+
+    (center - dimensions / 2.0, center + dimensions / 2.0)
+}
+
 pub fn point_in_area(point: Vec2, top_left: Vec2, bottom_right: Vec2) -> bool {
     point.x >= top_left.x
         && point.y >= top_left.y
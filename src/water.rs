@@ -0,0 +1,94 @@
+use ultraviolet::Vec2;
+
+// All the lakes are at the same level, so that they can share a single planar reflection.
+pub const WATER_LEVEL: f32 = 0.5;
+
+// Like the hills, the lakes are the same in every match for now. They're placed in the two corners
+// that none of the scenarios use.
+pub const LAKES: [Water; 2] = [
+    Water {
+        center: Vec2::new(60.0, -60.0),
+        dimensions: Vec2::new(24.0, 18.0),
+    },
+    Water {
+        center: Vec2::new(-60.0, 60.0),
+        dimensions: Vec2::new(18.0, 24.0),
+    },
+];
+
+// A rectangle of water on the map. Land units can't walk through it (it's inserted into the map
+// like a building), and the terrain is carved out underneath it.
+#[derive(Clone, Copy, Debug)]
+pub struct Water {
+    pub center: Vec2,
+    pub dimensions: Vec2,
+}
+
+impl Water {
+    // Negative inside the rectangle and positive outside of it.
+    pub fn signed_distance(&self, point: Vec2) -> f32 {
+        let offset = (point - self.center).abs() - self.dimensions / 2.0;
+        let outside = offset.max_by_component(Vec2::zero()).mag();
+        let inside = offset.x.max(offset.y).min(0.0);
+        outside + inside
+    }
+
+    pub fn overlaps(&self, center: Vec2, dimensions: Vec2) -> bool {
+        let offset = (center - self.center).abs();
+        let max_offset = (self.dimensions + dimensions) / 2.0;
+        offset.x < max_offset.x && offset.y < max_offset.y
+    }
+}
+
+// A tiling normal map made out of a few sine waves with whole-number frequencies (so that they wrap
+// around at the edges). The water shader scrolls two copies of it across each other to animate it.
+#[cfg(feature = "water-shader")]
+pub fn normal_map() -> image::RgbaImage {
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    const SIZE: u32 = 128;
+    const NUM_WAVES: usize = 6;
+
+    let mut rng = SmallRng::seed_from_u64(0x3A7E5);
+
+    let waves: Vec<(Vec2, f32, f32)> = (0..NUM_WAVES)
+        .map(|_| {
+            let frequency = Vec2::new(rng.gen_range(-4, 5) as f32, rng.gen_range(1, 5) as f32);
+            let amplitude = 0.4 / frequency.mag();
+            let phase = rng.gen_range(0.0, std::f32::consts::TAU);
+            (frequency, amplitude, phase)
+        })
+        .collect();
+
+    image::RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+        let point = Vec2::new(x as f32, y as f32) / SIZE as f32;
+
+        let slope = waves
+            .iter()
+            .fold(Vec2::zero(), |slope, &(frequency, amplitude, phase)| {
+                let angle = frequency.dot(point) * std::f32::consts::TAU + phase;
+                slope + frequency * amplitude * angle.cos()
+            });
+
+        // Stored with z pointing up out of the surface.
+        let normal = ultraviolet::Vec3::new(-slope.x, -slope.y, 1.0).normalized();
+        let encode = |value: f32| ((value * 0.5 + 0.5) * 255.0) as u8;
+
+        image::Rgba([encode(normal.x), encode(normal.y), encode(normal.z), 255])
+    })
+}
+
+#[test]
+fn signed_distances_to_water() {
+    let water = Water {
+        center: Vec2::new(10.0, 0.0),
+        dimensions: Vec2::new(4.0, 2.0),
+    };
+
+    assert_eq!(water.signed_distance(Vec2::new(10.0, 0.0)), -1.0);
+    assert_eq!(water.signed_distance(Vec2::new(15.0, 0.0)), 3.0);
+    assert_eq!(water.signed_distance(Vec2::new(15.0, 5.0)), 5.0);
+
+    assert!(water.overlaps(Vec2::new(13.0, 0.0), Vec2::new(4.0, 4.0)));
+    assert!(!water.overlaps(Vec2::new(15.0, 0.0), Vec2::new(4.0, 4.0)));
+}